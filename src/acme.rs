@@ -0,0 +1,427 @@
+use openssl::ec::{EcGroup, EcKey};
+use openssl::ecdsa::EcdsaSig;
+use openssl::error::ErrorStack;
+use openssl::hash::{hash, MessageDigest};
+use openssl::nid::Nid;
+use openssl::pkey::{PKey, Private};
+use openssl::sign::Signer;
+use openssl::stack::Stack;
+use openssl::x509::{X509, X509NameBuilder, X509Req};
+use openssl::x509::extension::SubjectAlternativeName;
+use openssl::asn1::Asn1Time;
+use warp::{path, Filter, Reply};
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::{Database, SocketOptions, TlsConfig};
+use crate::error::lock;
+use crate::runtime;
+use crate::store::StoreError;
+
+/// The default `ACME_DIRECTORY`. Let's Encrypt's staging one, for trying things out
+/// without its rate limits, is <https://acme-staging-v02.api.letsencrypt.org/directory>.
+const LETS_ENCRYPT: &str = "https://acme-v02.api.letsencrypt.org/directory";
+
+/// Certificates expiring sooner than this are renewed
+const RENEW_BEFORE_DAYS: u32 = 30;
+
+/// Times an authorization or order is polled, `POLL_INTERVAL` apart, before giving up
+const MAX_POLLS: usize = 30;
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+const ACCOUNT_KEY: &str = "acme_account_key";
+const DOMAINS: &str = "acme_domains";
+const CERTIFICATE: &str = "acme_certificate";
+const CERTIFICATE_KEY: &str = "acme_certificate_key";
+
+/// Failures of obtaining a certificate
+#[derive(Debug, thiserror::Error)]
+enum AcmeError {
+    #[error("ACME request failed: {0}")]
+    Http(#[from] reqwest::Error),
+    #[error("{0}")]
+    Openssl(#[from] ErrorStack),
+    #[error("{0}")]
+    Store(#[from] StoreError),
+    #[error("invalid ACME response: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("{0}")]
+    Protocol(String),
+}
+
+/// Where the root server gets its certificate from, set with `ACME_DOMAINS`
+pub struct AcmeConfig {
+    domains: Vec<String>,
+    email: Option<String>,
+    directory: String,
+    /// Port answering the HTTP-01 challenges. The CA asks on port 80 of every domain, which
+    /// has to be forwarded here as this listens on 127.0.0.1 like every server does.
+    http_port: u16,
+}
+
+impl AcmeConfig {
+    /// `ACME_DOMAINS`, comma separated, has the root server obtain a certificate for them,
+    /// with `ACME_EMAIL` as the account's contact, from the CA of `ACME_DIRECTORY`. The
+    /// challenges are answered on `ACME_HTTP_PORT`, 80 by default.
+    pub fn from_env() -> Result<Option<AcmeConfig>, String> {
+        let domains: Vec<String> = match std::env::var("ACME_DOMAINS") {
+            Ok(domains) => domains.split(',').map(str::trim).filter(|domain| !domain.is_empty()).map(str::to_string).collect(),
+            Err(_) => return Ok(None),
+        };
+        if domains.is_empty() {
+            return Err("ACME_DOMAINS names no domain".to_string());
+        }
+        let http_port = match std::env::var("ACME_HTTP_PORT") {
+            Ok(port) => port.parse().map_err(|_| format!("invalid ACME_HTTP_PORT {:?}", port))?,
+            Err(_) => 80,
+        };
+
+        Ok(Some(AcmeConfig {
+            domains,
+            email: std::env::var("ACME_EMAIL").ok(),
+            directory: std::env::var("ACME_DIRECTORY").unwrap_or_else(|_| LETS_ENCRYPT.to_string()),
+            http_port,
+        }))
+    }
+
+    /// TLS of the root server, self-signed until the certificate is obtained
+    pub fn tls_config(&self) -> TlsConfig {
+        TlsConfig {
+            self_signed: true,
+            hostnames: self.domains.clone(),
+            cert_file: None,
+            key_file: None,
+            client_ca_file: None,
+            client_cert_optional: false,
+            sni: BTreeMap::new(),
+            http3: false,
+        }
+    }
+}
+
+/// Key authorizations of the HTTP-01 challenges pending, by token
+type Challenges = Arc<Mutex<HashMap<String, String>>>;
+
+/// Answer the challenges on `config.http_port` and keep the root server on `port` served
+/// with a certificate for `config.domains`, on a thread of its own. The account key and
+/// certificate are kept in the registry's store, to be reused on the next start.
+pub fn start(database: Database, port: u16, config: AcmeConfig) -> std::io::Result<()> {
+    let challenges = Challenges::default();
+
+    let answers = challenges.clone();
+    let challenge = warp::get2()
+        .and(path!(".well-known" / "acme-challenge" / String))
+        .and(warp::path::end())
+        .map(move |token: String| match lock(&answers).get(&token) {
+            Some(key_authorization) => key_authorization.clone().into_response(),
+            None => warp::http::StatusCode::NOT_FOUND.into_response(),
+        });
+    let incoming = crate::tcp::bind(config.http_port, Vec::new(), &SocketOptions::default())?;
+    runtime::spawn(warp::serve(challenge).serve_incoming(incoming));
+
+    std::thread::Builder::new().name("acme".to_string()).spawn(move || loop {
+        let wait = match renew(&database, port, &config, &challenges) {
+            Ok(()) => Duration::from_secs(12 * 60 * 60),
+            Err(e) => {
+                eprintln!("failed to obtain a certificate for {}: {}", config.domains.join(", "), e);
+                Duration::from_secs(60 * 60)
+            }
+        };
+        std::thread::sleep(wait);
+    })?;
+    Ok(())
+}
+
+/// Serve the stored certificate, obtaining a new one first if it's missing, for other
+/// domains or about to expire
+fn renew(database: &Database, port: u16, config: &AcmeConfig, challenges: &Challenges) -> Result<(), AcmeError> {
+    let load = |name| lock(database).store.load_secret(name);
+    let domains = config.domains.join(",");
+
+    let stored = match (load(DOMAINS)?, load(CERTIFICATE)?, load(CERTIFICATE_KEY)?) {
+        (Some(stored_domains), Some(chain), Some(key)) if stored_domains == domains => Some((chain, key)),
+        _ => None,
+    };
+    let (chain, key) = match stored.filter(|(chain, _)| !expiring(chain)) {
+        Some(stored) => stored,
+        None => {
+            let account_key = match load(ACCOUNT_KEY)? {
+                Some(pem) => PKey::private_key_from_pem(pem.as_bytes())?,
+                None => {
+                    let key = new_key()?;
+                    let pem = String::from_utf8_lossy(&key.private_key_to_pem_pkcs8()?).into_owned();
+                    lock(database).store.save_secret(ACCOUNT_KEY, &pem)?;
+                    key
+                }
+            };
+            let mut client = Client::new(&config.directory, account_key)?;
+            client.register(config.email.as_deref())?;
+            let (chain, key) = client.issue(&config.domains, challenges)?;
+
+            let mut registry = lock(database);
+            registry.store.save_secret(CERTIFICATE, &chain)?;
+            registry.store.save_secret(CERTIFICATE_KEY, &key)?;
+            registry.store.save_secret(DOMAINS, &domains)?;
+            (chain, key)
+        }
+    };
+
+    let acceptor = lock(database).servers.get(&port)
+        .and_then(|server| server.tls.as_ref())
+        .map(|tls| tls.acceptor.clone());
+    match acceptor {
+        Some(acceptor) => Ok(acceptor.replace_certificate(&chain, &key)?),
+        None => Err(AcmeError::Protocol(format!("server {} doesn't serve TLS", port))),
+    }
+}
+
+/// Whether the leaf certificate of `chain` expires within `RENEW_BEFORE_DAYS`
+fn expiring(chain: &str) -> bool {
+    let renew_at = match Asn1Time::days_from_now(RENEW_BEFORE_DAYS) {
+        Ok(renew_at) => renew_at,
+        Err(_) => return true,
+    };
+    match X509::stack_from_pem(chain.as_bytes()) {
+        Ok(certificates) => certificates.first().is_none_or(|leaf| leaf.not_after() < renew_at),
+        Err(_) => true,
+    }
+}
+
+fn new_key() -> Result<PKey<Private>, ErrorStack> {
+    let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)?;
+    PKey::from_ec_key(EcKey::generate(&group)?)
+}
+
+/// Base64url without padding, as JWS uses
+fn base64url(bytes: &[u8]) -> String {
+    openssl::base64::encode_block(bytes)
+        .trim_end_matches('=')
+        .replace('+', "-")
+        .replace('/', "_")
+}
+
+#[derive(Debug, serde_derive::Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Directory {
+    new_nonce: String,
+    new_account: String,
+    new_order: String,
+}
+
+#[derive(Debug, serde_derive::Deserialize)]
+struct Order {
+    status: String,
+    #[serde(default)]
+    authorizations: Vec<String>,
+    finalize: String,
+    #[serde(default)]
+    certificate: Option<String>,
+}
+
+#[derive(Debug, serde_derive::Deserialize)]
+struct Authorization {
+    status: String,
+    #[serde(default)]
+    challenges: Vec<Challenge>,
+}
+
+#[derive(Debug, serde_derive::Deserialize)]
+struct Challenge {
+    #[serde(rename = "type")]
+    kind: String,
+    url: String,
+    #[serde(default)]
+    token: String,
+    #[serde(default)]
+    error: Option<serde_json::Value>,
+}
+
+/// An ACME account, see RFC 8555. Requests are blocking, so this runs off the runtime.
+struct Client {
+    http: reqwest::Client,
+    directory: Directory,
+    key: PKey<Private>,
+    jwk: serde_json::Value,
+    /// The account's URL, once registered
+    kid: Option<String>,
+    nonce: Option<String>,
+}
+
+impl Client {
+    fn new(directory: &str, key: PKey<Private>) -> Result<Client, AcmeError> {
+        let http = reqwest::Client::builder().timeout(Duration::from_secs(30)).build()?;
+        let directory = http.get(directory).send()?.error_for_status()?.json()?;
+
+        let ec = key.ec_key()?;
+        let (mut x, mut y) = (openssl::bn::BigNum::new()?, openssl::bn::BigNum::new()?);
+        let mut context = openssl::bn::BigNumContext::new()?;
+        ec.public_key().affine_coordinates(ec.group(), &mut x, &mut y, &mut context)?;
+        // The members in lexicographic order, as the thumbprint needs them
+        let jwk = serde_json::json!({
+            "crv": "P-256",
+            "kty": "EC",
+            "x": base64url(&x.to_vec_padded(32)?),
+            "y": base64url(&y.to_vec_padded(32)?),
+        });
+
+        Ok(Client { http, directory, key, jwk, kid: None, nonce: None })
+    }
+
+    fn thumbprint(&self) -> Result<String, AcmeError> {
+        Ok(base64url(&hash(MessageDigest::sha256(), self.jwk.to_string().as_bytes())?))
+    }
+
+    fn nonce(&mut self) -> Result<String, AcmeError> {
+        if let Some(nonce) = self.nonce.take() {
+            return Ok(nonce);
+        }
+        let response = self.http.head(&self.directory.new_nonce).send()?;
+        replay_nonce(&response).ok_or_else(|| AcmeError::Protocol("no nonce from the CA".to_string()))
+    }
+
+    /// POST `payload` signed with the account key, or an empty one to GET the resource
+    fn post(&mut self, url: &str, payload: Option<&serde_json::Value>) -> Result<reqwest::Response, AcmeError> {
+        let mut retried = false;
+        loop {
+            let mut protected = serde_json::json!({ "alg": "ES256", "nonce": self.nonce()?, "url": url });
+            match &self.kid {
+                Some(kid) => protected["kid"] = serde_json::Value::String(kid.clone()),
+                None => protected["jwk"] = self.jwk.clone(),
+            }
+            let protected = base64url(protected.to_string().as_bytes());
+            let payload = payload.map_or_else(String::new, |payload| base64url(payload.to_string().as_bytes()));
+
+            let mut signer = Signer::new(MessageDigest::sha256(), &self.key)?;
+            signer.update(format!("{}.{}", protected, payload).as_bytes())?;
+            let signature = EcdsaSig::from_der(&signer.sign_to_vec()?)?;
+            let mut raw = signature.r().to_vec_padded(32)?;
+            raw.extend(signature.s().to_vec_padded(32)?);
+
+            let body = serde_json::json!({ "protected": protected, "payload": payload, "signature": base64url(&raw) });
+            let mut response = self.http.post(url)
+                .header(reqwest::header::CONTENT_TYPE, "application/jose+json")
+                .body(body.to_string())
+                .send()?;
+            self.nonce = replay_nonce(&response);
+            if response.status().is_success() {
+                return Ok(response);
+            }
+
+            let error = response.text().unwrap_or_default();
+            // Nonces can expire, and a fresh one comes with the error
+            if !retried && error.contains("urn:ietf:params:acme:error:badNonce") {
+                retried = true;
+                continue;
+            }
+            return Err(AcmeError::Protocol(format!("{} answered {}: {}", url, response.status(), error)));
+        }
+    }
+
+    /// Register the account, or look it up if the key already has one
+    fn register(&mut self, email: Option<&str>) -> Result<(), AcmeError> {
+        let contact: Vec<String> = email.into_iter().map(|email| format!("mailto:{}", email)).collect();
+        let payload = serde_json::json!({ "termsOfServiceAgreed": true, "contact": contact });
+        let new_account = self.directory.new_account.clone();
+        let response = self.post(&new_account, Some(&payload))?;
+        self.kid = Some(location(&response)?);
+        Ok(())
+    }
+
+    /// Order a certificate for `domains`, answering their HTTP-01 challenges through
+    /// `challenges`. Returns the PEM certificate chain and the PEM key it certifies.
+    fn issue(&mut self, domains: &[String], challenges: &Challenges) -> Result<(String, String), AcmeError> {
+        let identifiers: Vec<serde_json::Value> = domains.iter()
+            .map(|domain| serde_json::json!({ "type": "dns", "value": domain }))
+            .collect();
+        let new_order = self.directory.new_order.clone();
+        let mut response = self.post(&new_order, Some(&serde_json::json!({ "identifiers": identifiers })))?;
+        let order_url = location(&response)?;
+        let order: Order = response.json()?;
+
+        let key_authorization_suffix = self.thumbprint()?;
+        for authorization_url in &order.authorizations {
+            let authorization: Authorization = self.post(authorization_url, None)?.json()?;
+            if authorization.status == "valid" {
+                continue;
+            }
+            let challenge = authorization.challenges.into_iter()
+                .find(|challenge| challenge.kind == "http-01")
+                .ok_or_else(|| AcmeError::Protocol("the CA offers no HTTP-01 challenge".to_string()))?;
+
+            let key_authorization = format!("{}.{}", challenge.token, key_authorization_suffix);
+            lock(challenges).insert(challenge.token.clone(), key_authorization);
+            let answered = self.post(&challenge.url, Some(&serde_json::json!({})))
+                .and_then(|_| self.poll_authorization(authorization_url));
+            lock(challenges).remove(&challenge.token);
+            answered?;
+        }
+
+        let key = new_key()?;
+        let mut request = X509Req::builder()?;
+        let mut name = X509NameBuilder::new()?;
+        name.append_entry_by_nid(Nid::COMMONNAME, &domains[0])?;
+        request.set_subject_name(&name.build())?;
+        request.set_pubkey(&key)?;
+        let mut names = SubjectAlternativeName::new();
+        for domain in domains {
+            names.dns(domain);
+        }
+        let mut extensions = Stack::new()?;
+        extensions.push(names.build(&request.x509v3_context(None))?)?;
+        request.add_extensions(&extensions)?;
+        request.sign(&key, MessageDigest::sha256())?;
+        let csr = base64url(&request.build().to_der()?);
+
+        let finalize = order.finalize.clone();
+        self.post(&finalize, Some(&serde_json::json!({ "csr": csr })))?;
+        let certificate_url = self.poll_order(&order_url)?;
+        let chain = self.post(&certificate_url, None)?.text()?;
+        let key = String::from_utf8_lossy(&key.private_key_to_pem_pkcs8()?).into_owned();
+        Ok((chain, key))
+    }
+
+    fn poll_authorization(&mut self, url: &str) -> Result<(), AcmeError> {
+        for _ in 0..MAX_POLLS {
+            let authorization: Authorization = self.post(url, None)?.json()?;
+            match authorization.status.as_str() {
+                "valid" => return Ok(()),
+                "pending" | "processing" => std::thread::sleep(POLL_INTERVAL),
+                status => {
+                    let error = authorization.challenges.iter()
+                        .find_map(|challenge| challenge.error.as_ref())
+                        .map_or_else(String::new, |error| format!(": {}", error));
+                    return Err(AcmeError::Protocol(format!("authorization {}{}", status, error)));
+                }
+            }
+        }
+        Err(AcmeError::Protocol("authorization still pending".to_string()))
+    }
+
+    /// The URL of the certificate once the order is valid
+    fn poll_order(&mut self, url: &str) -> Result<String, AcmeError> {
+        for _ in 0..MAX_POLLS {
+            let order: Order = self.post(url, None)?.json()?;
+            match (order.status.as_str(), order.certificate) {
+                ("valid", Some(certificate)) => return Ok(certificate),
+                ("pending" | "ready" | "processing" | "valid", _) => std::thread::sleep(POLL_INTERVAL),
+                (status, _) => return Err(AcmeError::Protocol(format!("order {}", status))),
+            }
+        }
+        Err(AcmeError::Protocol("order still processing".to_string()))
+    }
+}
+
+fn replay_nonce(response: &reqwest::Response) -> Option<String> {
+    response.headers().get("replay-nonce")
+        .and_then(|nonce| nonce.to_str().ok())
+        .map(str::to_string)
+}
+
+fn location(response: &reqwest::Response) -> Result<String, AcmeError> {
+    response.headers().get(reqwest::header::LOCATION)
+        .and_then(|location| location.to_str().ok())
+        .map(str::to_string)
+        .ok_or_else(|| AcmeError::Protocol("no Location from the CA".to_string()))
+}
@@ -0,0 +1,36 @@
+use std::collections::HashMap;
+use std::net::TcpListener;
+use std::os::unix::io::{FromRawFd, RawFd};
+
+/// The first file descriptor passed by systemd, see `sd_listen_fds(3)`
+const LISTEN_FDS_START: RawFd = 3;
+
+/// Take the listening sockets systemd passed to this process, by port. Only stream
+/// sockets are supported. The environment variables are cleared, so child processes
+/// don't mistake the sockets for their own.
+pub fn inherited_listeners() -> HashMap<u16, TcpListener> {
+    let for_this_process = std::env::var("LISTEN_PID").ok()
+        .and_then(|pid| pid.parse::<u32>().ok())
+        .is_some_and(|pid| pid == std::process::id());
+    let count = std::env::var("LISTEN_FDS").ok()
+        .and_then(|count| count.parse::<RawFd>().ok())
+        .filter(|_| for_this_process)
+        .unwrap_or(0);
+
+    std::env::remove_var("LISTEN_PID");
+    std::env::remove_var("LISTEN_FDS");
+    std::env::remove_var("LISTEN_FDNAMES");
+
+    let mut listeners = HashMap::new();
+    for fd in LISTEN_FDS_START..LISTEN_FDS_START + count {
+        // systemd hands these descriptors over to this process, and nothing else owns them
+        let listener = unsafe { TcpListener::from_raw_fd(fd) };
+        match listener.local_addr() {
+            Ok(addr) => {
+                listeners.insert(addr.port(), listener);
+            }
+            Err(e) => eprintln!("ignoring inherited socket {}: {}", fd, e),
+        }
+    }
+    listeners
+}
@@ -0,0 +1,41 @@
+use std::io;
+
+/// When a stub takes requests, by its server's clock, see `clock::Clock`: from
+/// `active_from` until `active_until`, Unix times, or for `ttl_secs` from `active_from` or
+/// else from when the server (re)started. Outside of it the stub is passed over, as if it
+/// weren't there, so that an outage window can end without another call to the admin API.
+#[derive(Clone, Copy, Debug, Default, PartialEq, serde_derive::Deserialize, serde_derive::Serialize)]
+pub struct Activity {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub active_from: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub active_until: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ttl_secs: Option<u64>,
+}
+
+impl Activity {
+    /// Whether the stub is always active
+    pub fn is_default(&self) -> bool {
+        *self == Activity::default()
+    }
+
+    pub fn check(&self) -> io::Result<()> {
+        let invalid = |error: &str| Err(io::Error::new(io::ErrorKind::InvalidInput, error.to_string()));
+        match (self.active_from, self.active_until, self.ttl_secs) {
+            (_, Some(_), Some(_)) => invalid("a stub is active until a time or for a TTL, not both"),
+            (Some(from), Some(until), None) if until <= from => invalid("a stub's active_until isn't after its active_from"),
+            _ => Ok(()),
+        }
+    }
+
+    /// Whether the stub is active at `now_ms` of a server that (re)started at `started_ms`
+    pub fn is_active(&self, started_ms: u64, now_ms: u64) -> bool {
+        let from_ms = self.active_from.map(|from| from.saturating_mul(1000));
+        let until_ms = match self.ttl_secs {
+            Some(ttl) => Some(from_ms.unwrap_or(started_ms).saturating_add(ttl.saturating_mul(1000))),
+            None => self.active_until.map(|until| until.saturating_mul(1000)),
+        };
+        from_ms.is_none_or(|from_ms| now_ms >= from_ms) && until_ms.is_none_or(|until_ms| now_ms < until_ms)
+    }
+}
@@ -0,0 +1,193 @@
+use futures::Future;
+use tokio::timer::Delay;
+use warp::http::{Method, StatusCode};
+
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::chaos::Rng;
+use crate::error::{lock, Error};
+use crate::{error_reply, shared};
+
+/// The latency and the jitter of the faults at most, an hour each, well within what the
+/// timer can hold a request back for
+const MAX_DELAY_MS: u64 = 60 * 60 * 1000;
+
+/// An operation of the admin API faults can be injected into
+#[derive(Clone, Copy, Debug, PartialEq, serde_derive::Deserialize, serde_derive::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AdminOperation {
+    /// `GET /`
+    List,
+    /// `GET /{port}`
+    Get,
+    /// `POST /`
+    Create,
+    /// `PUT /{port}`
+    Update,
+    /// `DELETE /{port}` and `DELETE /?label=`
+    Delete,
+}
+
+impl AdminOperation {
+    /// The operation a request stands for, if it's one of them
+    fn of(method: &Method, path: &str) -> Option<AdminOperation> {
+        let port = path.strip_prefix('/').is_some_and(|port| port.parse::<u16>().is_ok());
+        match (method, path == "/") {
+            (&Method::GET, true) => Some(AdminOperation::List),
+            (&Method::POST, true) => Some(AdminOperation::Create),
+            (&Method::DELETE, true) => Some(AdminOperation::Delete),
+            (&Method::GET, false) if port => Some(AdminOperation::Get),
+            (&Method::PUT, false) if port => Some(AdminOperation::Update),
+            (&Method::DELETE, false) if port => Some(AdminOperation::Delete),
+            _ => None,
+        }
+    }
+}
+
+/// JSON body of `PUT /admin/faults`: latency and errors injected into the admin API
+/// itself, for the retries of whatever drives it to be tested. Off until it's set.
+#[derive(Clone, Debug, serde_derive::Deserialize, serde_derive::Serialize)]
+pub struct AdminFaults {
+    /// The operations faults are injected into, by default listing, creating and deleting
+    #[serde(default = "default_operations")]
+    operations: Vec<AdminOperation>,
+    /// How long every one of the operations is held back before it's handled, up to an
+    /// hour
+    #[serde(default)]
+    latency_ms: u64,
+    /// Up to how much longer, at random, up to an hour too
+    #[serde(default)]
+    jitter_ms: u64,
+    /// The share of the operations failed without being handled, within 0 and 1
+    #[serde(default)]
+    error_rate: f64,
+    #[serde(default = "default_error_status")]
+    error_status: u16,
+    /// `Retry-After` of the failures
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    retry_after_secs: Option<u64>,
+    /// Seed of the random choices, to repeat a run. By default taken from the clock.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    seed: Option<u64>,
+}
+
+fn default_operations() -> Vec<AdminOperation> {
+    vec![AdminOperation::List, AdminOperation::Create, AdminOperation::Delete]
+}
+
+fn default_error_status() -> u16 {
+    503
+}
+
+/// The faults being injected, and how many have been
+#[derive(Debug)]
+pub struct Injector {
+    config: AdminFaults,
+    rng: Rng,
+    delayed: u64,
+    failed: u64,
+}
+
+/// What `GET /admin/faults` tells
+#[derive(serde_derive::Serialize)]
+struct InjectorStatus<'a> {
+    #[serde(flatten)]
+    config: &'a AdminFaults,
+    /// Operations held back and failed so far
+    delayed: u64,
+    failed: u64,
+}
+
+/// Hold back or fail the admin API request as the faults set with `PUT /admin/faults`
+/// have it, if it's one of the operations they're injected into
+pub fn inject(
+    shared: Arc<shared::Shared>,
+    method: Method,
+    path: warp::path::FullPath
+) -> Box<dyn Future<Item = (), Error = warp::Rejection> + Send> {
+    let operation = AdminOperation::of(&method, path.as_str());
+    let (delay_ms, failure) = {
+        let mut injector = lock(&shared.admin_faults);
+        let injector = match (injector.as_mut(), operation) {
+            (Some(injector), Some(operation)) if injector.config.operations.contains(&operation) => injector,
+            _ => return Box::new(futures::future::ok(())),
+        };
+        let config = &injector.config;
+        let jitter_ms = match config.jitter_ms {
+            0 => 0,
+            jitter_ms => injector.rng.next() % jitter_ms.saturating_add(1),
+        };
+        let delay_ms = config.latency_ms.saturating_add(jitter_ms);
+        let failure = (injector.rng.fraction() < config.error_rate).then(|| Error::InjectedFault {
+            status: StatusCode::from_u16(config.error_status).unwrap_or(StatusCode::SERVICE_UNAVAILABLE),
+            retry_after_secs: config.retry_after_secs,
+        });
+        injector.delayed += u64::from(delay_ms > 0);
+        injector.failed += u64::from(failure.is_some());
+        (delay_ms, failure)
+    };
+
+    let outcome = move || match failure {
+        Some(failure) => Err(warp::reject::custom(failure)),
+        None => Ok(()),
+    };
+    if delay_ms == 0 {
+        return Box::new(futures::future::result(outcome()));
+    }
+    Box::new(Delay::new(Instant::now() + Duration::from_millis(delay_ms))
+        .then(move |delayed| {
+            if let Err(e) = delayed {
+                eprintln!("admin fault timer error: {}", e);
+            }
+            outcome()
+        }))
+}
+
+fn status(injector: &Injector) -> warp::reply::Response {
+    let status = InjectorStatus { config: &injector.config, delayed: injector.delayed, failed: injector.failed };
+    warp::reply::Reply::into_response(warp::reply::json(&status))
+}
+
+fn not_injecting() -> warp::reply::Response {
+    error_reply(StatusCode::NOT_FOUND, "no faults are injected into the admin API")
+}
+
+/// `GET /admin/faults`
+pub fn get_faults(shared: Arc<shared::Shared>) -> warp::reply::Response {
+    match &*lock(&shared.admin_faults) {
+        Some(injector) => status(injector),
+        None => not_injecting(),
+    }
+}
+
+/// `PUT /admin/faults`: inject faults into the admin API, replacing those injected already
+/// and starting their counts over
+pub fn put_faults(shared: Arc<shared::Shared>, config: AdminFaults) -> warp::reply::Response {
+    let valid_status = (400..600).contains(&config.error_status);
+    if !(0.0..=1.0).contains(&config.error_rate) || !valid_status || config.operations.is_empty() {
+        let error = "error_rate must be within 0 and 1, error_status an error status and operations non-empty";
+        return error_reply(StatusCode::UNPROCESSABLE_ENTITY, error);
+    }
+    if config.latency_ms > MAX_DELAY_MS || config.jitter_ms > MAX_DELAY_MS {
+        let error = format!("latency_ms and jitter_ms must be at most {} each", MAX_DELAY_MS);
+        return error_reply(StatusCode::UNPROCESSABLE_ENTITY, &error);
+    }
+
+    eprintln!("injecting faults into the admin API");
+    let rng = Rng::new(config.seed.unwrap_or_else(Rng::clock_seed));
+    let mut injector = lock(&shared.admin_faults);
+    status(injector.insert(Injector { config, rng, delayed: 0, failed: 0 }))
+}
+
+/// `DELETE /admin/faults`: stop injecting faults into the admin API. Requests held back
+/// already are answered as they were to be.
+pub fn delete_faults(shared: Arc<shared::Shared>) -> warp::reply::Response {
+    match lock(&shared.admin_faults).take() {
+        Some(_) => {
+            eprintln!("no longer injecting faults into the admin API");
+            warp::reply::Reply::into_response(StatusCode::NO_CONTENT)
+        }
+        None => not_injecting(),
+    }
+}
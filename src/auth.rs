@@ -0,0 +1,62 @@
+use std::io::Read;
+use std::sync::Arc;
+
+use crate::{shared, Database};
+use crate::error::{lock, Error};
+
+/// The credential of the admin API, from `ADMIN_TOKEN`. Left unset, the API is open.
+pub fn admin_token_from_env() -> Option<String> {
+    std::env::var("ADMIN_TOKEN").ok().filter(|token| !token.is_empty())
+}
+
+/// A fresh random token, for `POST /` to hand out as the `deletion_token` of the server it
+/// created
+pub fn new_token() -> std::io::Result<String> {
+    let mut bytes = [0u8; 16];
+    std::fs::File::open("/dev/urandom")?.read_exact(&mut bytes)?;
+    Ok(bytes.iter().map(|byte| format!("{:02x}", byte)).collect())
+}
+
+/// Let a request through if the admin API is open, if it carries the admin token as
+/// `Authorization: Bearer`, or if it's `DELETE /{port}` carrying the deletion token of
+/// that server. `GET /healthz` is always let through, for probes.
+pub fn authorize(
+    database: Database,
+    shared: Arc<shared::Shared>,
+    authorization: Option<String>,
+    method: warp::http::Method,
+    path: warp::path::FullPath
+) -> Result<(), warp::Rejection> {
+    let admin_token = match &shared.admin_token {
+        Some(token) => token,
+        None => return Ok(()),
+    };
+    if method == warp::http::Method::GET && path.as_str() == "/healthz" {
+        return Ok(());
+    }
+
+    let bearer = authorization.as_deref().and_then(|authorization| authorization.strip_prefix("Bearer "));
+    let bearer = match bearer {
+        Some(bearer) => bearer.trim(),
+        None => return Err(warp::reject::custom(Error::Unauthorized)),
+    };
+    if tokens_equal(bearer, admin_token) {
+        return Ok(());
+    }
+
+    let deleted_port = path.as_str().strip_prefix('/').and_then(|port| port.parse::<u16>().ok());
+    if method == warp::http::Method::DELETE {
+        let registry = lock(&database);
+        if let Some(token) = deleted_port.and_then(|port| registry.deletion_tokens.get(&port)) {
+            if tokens_equal(bearer, token) {
+                return Ok(());
+            }
+        }
+    }
+    Err(warp::reject::custom(Error::Unauthorized))
+}
+
+/// Compare tokens in time independent of where they differ
+pub fn tokens_equal(a: &str, b: &str) -> bool {
+    a.len() == b.len() && a.bytes().zip(b.bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
@@ -0,0 +1,138 @@
+use futures::future::{self, Either, Loop};
+use futures::sync::oneshot;
+use futures::{Future, Stream};
+use tokio::timer::{Delay, Interval};
+
+use std::time::{Duration, Instant};
+
+use crate::{runtime, Database, MemoryStore, ServerStatus};
+use crate::error::lock;
+use crate::watch::ChangeType;
+
+// Restart backoff doubles per restart, up to 2^6 = 64 seconds, like a server's respawn
+const MAX_BACKOFF_EXPONENT: u32 = 6;
+
+/// How long the servers are given to let go of their ports once the process is stopped
+pub const DRAIN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The tasks that run in the background for as long as the process does, like the lease
+/// reaper and the scheduler.
+///
+/// Each is supervised: should it end before being stopped, it's started anew after a
+/// backoff. They're stopped in the reverse of the order they were started in, so a task
+/// may rely on those started before it for as long as it runs. Dropping the instance
+/// stops them all without waiting for them to end.
+#[derive(Default)]
+pub struct Background {
+    tasks: Vec<Task>,
+}
+
+struct Task {
+    name: &'static str,
+    // Dropped to stop the task
+    stop: oneshot::Sender<()>,
+    ended: oneshot::Receiver<()>,
+}
+
+impl Background {
+    /// Run the future `task` makes as `name` until stopped, making another whenever it
+    /// ends before that
+    pub fn start<T, F>(&mut self, name: &'static str, task: T)
+    where
+        T: Fn() -> F + Send + 'static,
+        F: Future<Item = (), Error = ()> + Send + 'static,
+    {
+        let (stop, stopped) = oneshot::channel::<()>();
+        let (end, ended) = oneshot::channel();
+        let stopped = stopped.shared();
+
+        let supervised = future::loop_fn(0, move |restarts: u32| {
+            task().select2(stopped.clone()).then(move |ended| match ended {
+                Ok(Either::B(_)) | Err(Either::B(_)) => Either::A(future::ok::<_, ()>(Loop::Break(()))),
+                Ok(Either::A(_)) | Err(Either::A(_)) => {
+                    let backoff = Duration::from_secs(1 << restarts.min(MAX_BACKOFF_EXPONENT));
+                    eprintln!("{} ended, restarting it in {}s", name, backoff.as_secs());
+                    Either::B(Delay::new(Instant::now() + backoff)
+                        .then(move |_| Ok(Loop::Continue(restarts + 1))))
+                }
+            })
+        });
+        runtime::spawn(supervised.then(move |_| {
+            let _ = end.send(());
+            Ok(())
+        }));
+
+        self.tasks.push(Task { name, stop, ended });
+    }
+
+    /// Stop every task, the last started first, each once the one started after it has
+    /// ended. Resolves once they all have.
+    pub fn stop(&mut self) -> impl Future<Item = (), Error = ()> {
+        let tasks = std::mem::take(&mut self.tasks);
+        futures::stream::iter_ok(tasks.into_iter().rev())
+            .for_each(|Task { name, stop, ended }| {
+                drop(stop);
+                ended.then(move |_| {
+                    eprintln!("stopped {}", name);
+                    Ok(())
+                })
+            })
+    }
+}
+
+impl Drop for Background {
+    fn drop(&mut self) {
+        // In the order `stop` would have
+        while self.tasks.pop().is_some() {}
+    }
+}
+
+/// Stop the background tasks, then every server, resolving once they've all closed their
+/// listeners, or `DRAIN_TIMEOUT` has passed. The tasks go first so none acts on the
+/// servers while they drain. Requests in flight are answered meanwhile. The store is let
+/// go of before the servers are stopped, so it keeps them as they were.
+pub fn shut_down(database: Database) -> impl Future<Item = (), Error = ()> {
+    let stopped = lock(&database).background.stop();
+    stopped.and_then(move |()| drain(database))
+}
+
+fn drain(database: Database) -> impl Future<Item = (), Error = ()> {
+    let mut registry = lock(&database);
+    registry.store = Box::new(MemoryStore);
+
+    let ports: Vec<u16> = registry.servers.iter()
+        .filter(|(_, server)| server.status.can_become(ServerStatus::Draining))
+        .map(|(port, _)| *port)
+        .collect();
+    for port in ports {
+        let server = registry.servers.get_mut(&port).unwrap();
+        server.status = ServerStatus::Draining;
+        server.signal_shutdown();
+        registry.record_change(port, ChangeType::Modified);
+    }
+    drop(registry);
+
+    let deadline = Instant::now() + DRAIN_TIMEOUT;
+    Interval::new_interval(Duration::from_millis(100))
+        .map_err(|e| eprintln!("drain timer error: {}", e))
+        .take_while(move |_| {
+            let draining = lock(&database).servers.values().any(|server| server.status == ServerStatus::Draining);
+            Ok(draining && Instant::now() < deadline)
+        })
+        .for_each(|_| Ok(()))
+}
+
+/// Shut down and exit once the process gets `SIGTERM`, see `shut_down`
+#[cfg(unix)]
+pub fn exit_on_sigterm(database: &Database) {
+    let database = database.clone();
+    runtime::spawn(tokio_signal::unix::Signal::new(tokio_signal::unix::SIGTERM)
+        .flatten_stream()
+        .into_future()
+        .map_err(|(e, _)| eprintln!("failed to watch for SIGTERM: {}", e))
+        .and_then(move |_| {
+            eprintln!("shutting down on SIGTERM");
+            shut_down(database)
+        })
+        .map(|()| std::process::exit(0)));
+}
@@ -0,0 +1,268 @@
+use futures::Future;
+use openssl::error::ErrorStack;
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use openssl::sign::Signer;
+use warp::http::StatusCode;
+
+use std::time::Duration;
+
+use crate::{error_reply, har, snapshot, unix_time, Database};
+use crate::error::lock;
+
+/// How often the registry is backed up unless `BACKUP_INTERVAL_SECS` says otherwise
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// The snapshot most recently backed up, under the prefix of `BACKUP_URL`
+const LATEST: &str = "latest.json";
+
+const REGION: &str = "us-east-1";
+
+/// Failures of backing up to or restoring from S3-compatible storage
+#[derive(Debug, thiserror::Error)]
+pub enum BackupError {
+    #[error("S3 request failed: {0}")]
+    Http(#[from] reqwest::Error),
+    #[error("{0}")]
+    Openssl(#[from] ErrorStack),
+    #[error("invalid snapshot: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("{method} {url} answered {status}: {body}")]
+    Status { method: &'static str, url: String, status: reqwest::StatusCode, body: String },
+    #[error("{0}")]
+    Config(String),
+}
+
+/// An object, or a prefix of objects, in S3-compatible storage, like `s3://{bucket}/{key}`
+#[derive(Clone, Debug, PartialEq)]
+pub struct S3Url {
+    pub bucket: String,
+    pub key: String,
+}
+
+impl S3Url {
+    pub fn parse(url: &str) -> Result<S3Url, BackupError> {
+        let invalid = || BackupError::Config(format!("invalid S3 URL {:?}, expected s3://{{bucket}}/{{key}}", url));
+        let path = url.strip_prefix("s3://").ok_or_else(invalid)?;
+        let (bucket, key) = path.split_once('/').unwrap_or((path, ""));
+        if bucket.is_empty() || bucket.contains(':') {
+            return Err(invalid());
+        }
+        Ok(S3Url { bucket: bucket.to_string(), key: key.to_string() })
+    }
+
+    /// The object `name` under this as a prefix
+    fn join(&self, name: &str) -> S3Url {
+        let key = match self.key.as_str() {
+            "" => name.to_string(),
+            prefix if prefix.ends_with('/') => format!("{}{}", prefix, name),
+            prefix => format!("{}/{}", prefix, name),
+        };
+        S3Url { bucket: self.bucket.clone(), key }
+    }
+}
+
+/// How to reach S3-compatible storage: `S3_ENDPOINT`, by default the AWS endpoint of
+/// `AWS_REGION`, itself `us-east-1` by default, with the credentials of `AWS_ACCESS_KEY_ID`,
+/// `AWS_SECRET_ACCESS_KEY` and `AWS_SESSION_TOKEN` if it's set. Buckets are addressed by
+/// path, like MinIO and other stores besides AWS expect.
+pub struct S3 {
+    endpoint: reqwest::Url,
+    region: String,
+    access_key: String,
+    secret_key: String,
+    session_token: Option<String>,
+    http: reqwest::Client,
+}
+
+impl S3 {
+    pub fn from_env() -> Result<S3, BackupError> {
+        let region = std::env::var("AWS_REGION").unwrap_or_else(|_| REGION.to_string());
+        let endpoint = std::env::var("S3_ENDPOINT").unwrap_or_else(|_| format!("https://s3.{}.amazonaws.com", region));
+        let endpoint = reqwest::Url::parse(&endpoint)
+            .ok()
+            .filter(|endpoint| endpoint.has_host())
+            .ok_or_else(|| BackupError::Config(format!("invalid S3_ENDPOINT {:?}", endpoint)))?;
+        let credential = |var: &str| std::env::var(var)
+            .map_err(|_| BackupError::Config(format!("S3 storage needs {}", var)));
+
+        Ok(S3 {
+            endpoint,
+            region,
+            access_key: credential("AWS_ACCESS_KEY_ID")?,
+            secret_key: credential("AWS_SECRET_ACCESS_KEY")?,
+            session_token: std::env::var("AWS_SESSION_TOKEN").ok(),
+            http: reqwest::Client::builder().timeout(Duration::from_secs(30)).build()?,
+        })
+    }
+
+    pub fn put(&self, object: &S3Url, body: Vec<u8>) -> Result<(), BackupError> {
+        self.send(reqwest::Method::PUT, object, body).map(|_| ())
+    }
+
+    pub fn get(&self, object: &S3Url) -> Result<Vec<u8>, BackupError> {
+        self.send(reqwest::Method::GET, object, Vec::new())
+    }
+
+    /// Send a request signed with AWS Signature Version 4, answering with its body
+    fn send(&self, method: reqwest::Method, object: &S3Url, body: Vec<u8>) -> Result<Vec<u8>, BackupError> {
+        let path = format!("/{}/{}", object.bucket, object.key);
+        let path: String = path.bytes().map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => (byte as char).to_string(),
+            _ => format!("%{:02X}", byte),
+        }).collect();
+        let mut url = self.endpoint.clone();
+        url.set_path(&path);
+        let host = match url.port() {
+            Some(port) => format!("{}:{}", url.host_str().unwrap_or_default(), port),
+            None => url.host_str().unwrap_or_default().to_string(),
+        };
+
+        // Like `20190601T120000Z`
+        let now = har::iso8601(unix_time() * 1000);
+        let timestamp = format!("{}Z", now[..19].replace(['-', ':'], ""));
+        let date = &timestamp[..8];
+        let payload = hex(&openssl::sha::sha256(&body));
+
+        let mut headers = vec![
+            ("host", host),
+            ("x-amz-content-sha256", payload.clone()),
+            ("x-amz-date", timestamp.clone()),
+        ];
+        if let Some(token) = &self.session_token {
+            headers.push(("x-amz-security-token", token.clone()));
+        }
+        let signed: Vec<&str> = headers.iter().map(|(name, _)| *name).collect();
+        let signed = signed.join(";");
+        let canonical: String = headers.iter().map(|(name, value)| format!("{}:{}\n", name, value.trim())).collect();
+        let request = format!("{}\n{}\n\n{}\n{}\n{}", method, path, canonical, signed, payload);
+
+        let scope = format!("{}/{}/s3/aws4_request", date, self.region);
+        let to_sign = format!("AWS4-HMAC-SHA256\n{}\n{}\n{}", timestamp, scope, hex(&openssl::sha::sha256(request.as_bytes())));
+        let mut key = hmac(format!("AWS4{}", self.secret_key).as_bytes(), date.as_bytes())?;
+        for part in [self.region.as_str(), "s3", "aws4_request"] {
+            key = hmac(&key, part.as_bytes())?;
+        }
+        let signature = hex(&hmac(&key, to_sign.as_bytes())?);
+        let authorization = format!(
+            "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+            self.access_key, scope, signed, signature
+        );
+
+        let method_name = if method == reqwest::Method::PUT { "PUT" } else { "GET" };
+        let mut request = self.http.request(method, url.clone())
+            .header(reqwest::header::AUTHORIZATION, authorization)
+            .body(body);
+        // reqwest sets `Host` itself, to the same value
+        for (name, value) in headers.into_iter().skip(1) {
+            request = request.header(name, value);
+        }
+        let mut response = request.send()?;
+        let mut answered = Vec::new();
+        response.copy_to(&mut answered)?;
+        if !response.status().is_success() {
+            return Err(BackupError::Status {
+                method: method_name,
+                url: url.to_string(),
+                status: response.status(),
+                body: String::from_utf8_lossy(&answered).trim().to_string(),
+            });
+        }
+        Ok(answered)
+    }
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> Result<Vec<u8>, ErrorStack> {
+    let key = PKey::hmac(key)?;
+    Signer::new(MessageDigest::sha256(), &key)?.sign_oneshot_to_vec(data)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Where and how often the registry is backed up, from `BACKUP_URL` and
+/// `BACKUP_INTERVAL_SECS`, an hour by default. Each backup is the body of `GET /snapshot`,
+/// the servers with their routes and stubs and the templates, uploaded under the prefix of
+/// `BACKUP_URL` like `s3://{bucket}/{prefix}/snapshot-{taken_at}.json` and again as
+/// `latest.json`, for `POST /restore?from=` to rebuild the fleet from. Old backups are left
+/// to the bucket's lifecycle rules to expire. The storage is reached as `S3` says.
+pub struct BackupConfig {
+    target: S3Url,
+    interval: Duration,
+    s3: S3,
+}
+
+impl BackupConfig {
+    pub fn from_env() -> Result<Option<BackupConfig>, String> {
+        let target = match std::env::var("BACKUP_URL") {
+            Ok(url) => S3Url::parse(&url).map_err(|e| e.to_string())?,
+            Err(_) => return Ok(None),
+        };
+        let interval = match std::env::var("BACKUP_INTERVAL_SECS") {
+            Ok(secs) => match secs.parse::<u64>() {
+                Ok(secs) if secs > 0 => Duration::from_secs(secs),
+                _ => return Err(format!("invalid BACKUP_INTERVAL_SECS {:?}, expected a number of seconds", secs)),
+            },
+            Err(_) => DEFAULT_INTERVAL,
+        };
+        let s3 = S3::from_env().map_err(|e| e.to_string())?;
+        Ok(Some(BackupConfig { target, interval, s3 }))
+    }
+}
+
+/// Back up the registry of the admin API on `own_port` every interval, on a thread of its
+/// own, starting with one right away
+pub fn start(database: Database, own_port: u16, config: BackupConfig) -> std::io::Result<()> {
+    std::thread::Builder::new().name("backup".to_string()).spawn(move || loop {
+        let uploaded = back_up(&database, own_port, &config);
+        if let Err(e) = uploaded {
+            eprintln!("failed to back up to s3://{}/{}: {}", config.target.bucket, config.target.key, e);
+        }
+        std::thread::sleep(config.interval);
+    })?;
+    Ok(())
+}
+
+fn back_up(database: &Database, own_port: u16, config: &BackupConfig) -> Result<(), BackupError> {
+    let snapshot = snapshot::capture(&lock(database), own_port);
+    let json = serde_json::to_vec(&snapshot)?;
+    config.s3.put(&config.target.join(&format!("snapshot-{}.json", snapshot.taken_at)), json.clone())?;
+    config.s3.put(&config.target.join(LATEST), json)
+}
+
+/// The query of `POST /restore?from=s3://{bucket}/{key}`
+#[derive(Debug, serde_derive::Deserialize)]
+pub struct RestoreFrom {
+    from: String,
+}
+
+/// Rebuild the fleet from the snapshot at `from`, like `POST /restore` with it as the body.
+/// It's fetched on a thread of its own.
+pub fn restore_from(
+    database: Database,
+    own_port: u16,
+    query: RestoreFrom
+) -> impl Future<Item = warp::reply::Response, Error = warp::Rejection> {
+    let object = match S3Url::parse(&query.from) {
+        Ok(object) => object,
+        Err(e) => return futures::future::Either::A(futures::future::ok(error_reply(StatusCode::BAD_REQUEST, &e.to_string()))),
+    };
+    let (tx, rx) = futures::sync::oneshot::channel();
+    let fetching = std::thread::Builder::new().name("restore".to_string()).spawn(move || {
+        let _ = tx.send(S3::from_env().and_then(|s3| s3.get(&object)));
+    });
+    if let Err(e) = fetching {
+        eprintln!("failed to fetch a snapshot: {}", e);
+    }
+
+    futures::future::Either::B(rx.then(move |fetched| Ok(match fetched {
+        Ok(Ok(json)) => match serde_json::from_slice(&json) {
+            Ok(captured) => snapshot::restore(database, own_port, captured),
+            Err(e) => error_reply(StatusCode::UNPROCESSABLE_ENTITY, &BackupError::from(e).to_string()),
+        },
+        Ok(Err(e @ BackupError::Config(_))) => error_reply(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string()),
+        Ok(Err(e)) => error_reply(StatusCode::BAD_GATEWAY, &e.to_string()),
+        Err(_) => error_reply(StatusCode::INTERNAL_SERVER_ERROR, "failed to fetch the snapshot"),
+    })))
+}
@@ -0,0 +1,362 @@
+use futures::{Future, Stream};
+use hyper::body::Payload;
+use warp::{Filter, Reply};
+use warp::http::header::{self, HeaderMap, HeaderName, HeaderValue};
+use warp::http::{Method, StatusCode};
+
+use std::collections::HashMap;
+use std::io;
+use std::sync::{Arc, Mutex};
+
+use crate::{clock, error_reply, objects, Database};
+use crate::clock::SharedClock;
+use crate::error::lock;
+
+/// Paths a server remembers the bodies of before it forgets them all
+const MAX_PATHS: usize = 1024;
+
+fn invalid(error: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, error)
+}
+
+/// How an in-process HTTP server has clients revalidate what it answers with: each `200`
+/// to a `GET` or `HEAD` gets an `ETag` made of its body and a `Last-Modified` of when the
+/// server first answered the path with the body, by its clock, unless the route answering
+/// it gave them. A request whose `If-None-Match` has the tag, or whose
+/// `If-Modified-Since` isn't before the body changed, is answered with `304`. Responses
+/// streamed without a length are left as they are.
+#[derive(Clone, Debug, Default, PartialEq, serde_derive::Deserialize, serde_derive::Serialize)]
+pub struct ConditionalRequests {
+    #[serde(default)]
+    pub etag: EtagStyle,
+    /// Leave out `Last-Modified`, for clients to revalidate by `ETag` alone
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub no_last_modified: bool,
+    /// Tag every response by the server's content version in place of its body, and date
+    /// it by when the version was bumped, so that what clients and caches in between
+    /// have only goes stale on `POST /{port}/content-version`, however the bodies change
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub versioned: bool,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, serde_derive::Deserialize, serde_derive::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EtagStyle {
+    /// The MD5 digest of the body, like `"9e107d9d372bb6826bd81d3542a419d6"`
+    #[default]
+    Strong,
+    /// The same tag, marked weak, like `W/"9e107d9d372bb6826bd81d3542a419d6"`
+    Weak,
+    /// No `ETag`, for clients to revalidate by `Last-Modified` alone
+    None,
+}
+
+/// A rule of an HTTP server giving the responses to the requests it matches a
+/// `Cache-Control`, of a preset or its own, unless the route answering them gave one.
+/// The first rule matching a request gives it.
+#[derive(Clone, Debug, PartialEq, serde_derive::Deserialize, serde_derive::Serialize)]
+pub struct CacheRule {
+    /// The method of the requests matched, any by default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub method: Option<String>,
+    /// What the path of the requests matched starts with, any by default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path_prefix: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub preset: Option<CachePreset>,
+    /// Directives of its own, like `public, max-age=600, s-maxage=3600`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub directives: Option<String>,
+    /// The request headers the responses vary by, for caches to key them by, like
+    /// `Accept-Encoding`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub vary: Vec<String>,
+}
+
+/// The `Cache-Control` of the usual caching scenarios
+#[derive(Clone, Copy, Debug, PartialEq, serde_derive::Deserialize, serde_derive::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CachePreset {
+    /// Never kept, like a page with a user's secrets
+    NoStore,
+    /// Kept, but revalidated before every use
+    NoCache,
+    /// Kept by the client for a minute, but not by shared caches
+    Private,
+    /// Fresh for a minute, and revalidated once stale
+    Short,
+    /// Fresh for a minute, and used stale for five more while revalidated in the
+    /// background
+    StaleWhileRevalidate,
+    /// Fresh for a year and never revalidated, like a fingerprinted asset
+    Immutable,
+}
+
+impl CachePreset {
+    fn directives(self) -> &'static str {
+        match self {
+            CachePreset::NoStore => "no-store",
+            CachePreset::NoCache => "no-cache",
+            CachePreset::Private => "private, max-age=60",
+            CachePreset::Short => "public, max-age=60, must-revalidate",
+            CachePreset::StaleWhileRevalidate => "public, max-age=60, stale-while-revalidate=300",
+            CachePreset::Immutable => "public, max-age=31536000, immutable",
+        }
+    }
+}
+
+impl CacheRule {
+    fn matches(&self, method: &Method, path: &str) -> bool {
+        self.method.as_ref().is_none_or(|expected| expected.eq_ignore_ascii_case(method.as_str()))
+            && self.path_prefix.as_ref().is_none_or(|prefix| path.starts_with(prefix.as_str()))
+    }
+
+    /// The `Cache-Control` and `Vary` the rule gives, checked to be valid
+    fn headers(&self) -> io::Result<(HeaderValue, Option<HeaderValue>)> {
+        let directives = match (self.preset, &self.directives) {
+            (Some(preset), None) => preset.directives(),
+            (None, Some(directives)) => directives,
+            _ => return Err(invalid("a cache rule needs either a preset or directives".to_string())),
+        };
+        let cache_control = HeaderValue::from_str(directives)
+            .map_err(|_| invalid(format!("invalid cache directives {:?}", directives)))?;
+        for name in &self.vary {
+            HeaderName::from_bytes(name.as_bytes()).map_err(|_| invalid(format!("invalid header {:?} to vary by", name)))?;
+        }
+        let vary = Some(self.vary.join(", "))
+            .filter(|vary| !vary.is_empty())
+            .map(|vary| HeaderValue::from_str(&vary).map_err(|_| invalid(format!("invalid headers {:?} to vary by", vary))))
+            .transpose()?;
+        Ok((cache_control, vary))
+    }
+}
+
+/// The version of what an in-process HTTP server serves, kept across restarts, that its
+/// responses are tagged by if its conditional requests are `versioned`
+#[derive(Clone, Copy, Debug, Default, serde_derive::Serialize)]
+pub struct ContentVersion {
+    pub version: u64,
+    /// Unix time in milliseconds by the server's clock it was bumped to `version`, or
+    /// the server was created at
+    pub modified_ms: u64,
+}
+
+pub type SharedContentVersion = Arc<Mutex<ContentVersion>>;
+
+pub fn content_version(now_ms: u64) -> SharedContentVersion {
+    Arc::new(Mutex::new(ContentVersion { version: 1, modified_ms: now_ms }))
+}
+
+/// The tag and the time of the body a path was last answered with
+#[derive(Clone, Debug)]
+struct Version {
+    etag: String,
+    modified_ms: u64,
+}
+
+/// The conditional requests and cache rules of a server, with the bodies of its paths
+/// it answered with, see `Conditions`
+#[derive(Clone)]
+pub struct Revalidation {
+    config: Option<Arc<ConditionalRequests>>,
+    rules: Arc<Vec<(CacheRule, HeaderValue, Option<HeaderValue>)>>,
+    versions: Arc<Mutex<HashMap<String, Version>>>,
+    content: SharedContentVersion,
+    clock: SharedClock,
+}
+
+/// Check `rules`, to give the responses of a server revalidated by `config`
+/// `Cache-Control` with
+pub fn revalidation(
+    config: Option<&ConditionalRequests>,
+    rules: &[CacheRule],
+    content: SharedContentVersion,
+    clock: SharedClock
+) -> io::Result<Revalidation> {
+    let rules = rules.iter()
+        .map(|rule| rule.headers().map(|(cache_control, vary)| (rule.clone(), cache_control, vary)))
+        .collect::<io::Result<_>>()?;
+    Ok(Revalidation {
+        config: config.cloned().map(Arc::new),
+        rules: Arc::new(rules),
+        versions: Arc::default(),
+        content,
+        clock,
+    })
+}
+
+/// What of a request its response is revalidated by
+pub struct Conditions {
+    revalidation: Revalidation,
+    /// Whether the response is given validators, for a `GET` or `HEAD` of a server with
+    /// conditional requests
+    validated: bool,
+    /// The path with the query string
+    target: String,
+    if_none_match: Option<String>,
+    if_modified_since: Option<u64>,
+    cache_control: Option<HeaderValue>,
+    vary: Option<HeaderValue>,
+}
+
+/// Whether `etag` is one of the tags of `If-None-Match`, compared weakly
+fn none_match(if_none_match: &str, etag: &str) -> bool {
+    let etag = etag.trim_start_matches("W/");
+    if_none_match.split(',').map(str::trim).any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+}
+
+impl Revalidation {
+    /// The conditions of a request, if the server revalidates its response or has a
+    /// cache rule for it
+    pub fn conditions(&self) -> impl Filter<Extract = (Option<Conditions>,), Error = warp::Rejection> + Clone {
+        let revalidation = self.clone();
+        warp::method()
+            .and(warp::path::full())
+            .and(warp::query::raw().or(warp::any().map(String::new)).unify())
+            .and(warp::header::headers_cloned())
+            .map(move |method: Method, path: warp::path::FullPath, query: String, headers: HeaderMap| {
+                let validated = revalidation.config.is_some() && (method == Method::GET || method == Method::HEAD);
+                let rule = revalidation.rules.iter().find(|(rule, _, _)| rule.matches(&method, path.as_str()));
+                if !validated && rule.is_none() {
+                    return None;
+                }
+                let header = |name| headers.get(name).and_then(|value: &HeaderValue| value.to_str().ok());
+                let target = match query.as_str() {
+                    "" => path.as_str().to_string(),
+                    query => format!("{}?{}", path.as_str(), query),
+                };
+                Some(Conditions {
+                    revalidation: revalidation.clone(),
+                    validated,
+                    target,
+                    if_none_match: header(header::IF_NONE_MATCH).map(str::to_string),
+                    if_modified_since: header(header::IF_MODIFIED_SINCE).and_then(clock::parse_http_date),
+                    cache_control: rule.map(|(_, cache_control, _)| cache_control.clone()),
+                    vary: rule.and_then(|(_, _, vary)| vary.clone()),
+                })
+            })
+    }
+}
+
+impl Conditions {
+    /// `response` with the cache directives and validators of its body, or `304` if the
+    /// request has them
+    pub fn respond(
+        conditions: Option<Conditions>,
+        mut response: warp::reply::Response
+    ) -> Box<dyn Future<Item = warp::reply::Response, Error = warp::Rejection> + Send> {
+        let conditions = match conditions {
+            Some(conditions) => conditions,
+            None => return Box::new(futures::future::ok(response)),
+        };
+        let headers = response.headers_mut();
+        let given = [(header::CACHE_CONTROL, &conditions.cache_control), (header::VARY, &conditions.vary)];
+        for (name, value) in given {
+            if let (Some(value), false) = (value, headers.contains_key(&name)) {
+                headers.insert(name, value.clone());
+            }
+        }
+        if !conditions.validated || response.status() != StatusCode::OK || response.body().content_length().is_none() {
+            return Box::new(futures::future::ok(response));
+        }
+        let (parts, body) = response.into_parts();
+        Box::new(body.concat2()
+            .map(move |body| conditions.validate(warp::http::Response::from_parts(parts, body.to_vec())))
+            .or_else(|_| Ok(error_reply(StatusCode::BAD_GATEWAY, "failed to read the response"))))
+    }
+
+    /// The tag and time of the body of `response`, or of the content version
+    fn version(&self, config: &ConditionalRequests, body: &[u8]) -> Version {
+        if config.versioned {
+            let content = *lock(&self.revalidation.content);
+            return Version { etag: format!("v{}", content.version), modified_ms: content.modified_ms };
+        }
+        let digest: String = objects::md5(body).iter().map(|byte| format!("{:02x}", byte)).collect();
+        let now_ms = lock(&self.revalidation.clock).now_ms();
+        let mut versions = lock(&self.revalidation.versions);
+        if versions.len() >= MAX_PATHS && !versions.contains_key(&self.target) {
+            versions.clear();
+        }
+        let version = versions.entry(self.target.clone()).or_insert_with(|| Version { etag: digest.clone(), modified_ms: now_ms });
+        if version.etag != digest {
+            *version = Version { etag: digest, modified_ms: now_ms };
+        }
+        version.clone()
+    }
+
+    fn validate(self, response: warp::http::Response<Vec<u8>>) -> warp::reply::Response {
+        let config = match &self.revalidation.config {
+            Some(config) => config.clone(),
+            None => return response.map(Into::into),
+        };
+        let version = self.version(&config, response.body());
+
+        let (mut parts, body) = response.into_parts();
+        let etag = match config.etag {
+            EtagStyle::Strong => Some(format!("\"{}\"", version.etag)),
+            EtagStyle::Weak => Some(format!("W/\"{}\"", version.etag)),
+            EtagStyle::None => None,
+        };
+        if let Some(etag) = etag.and_then(|etag| HeaderValue::from_str(&etag).ok()) {
+            if !parts.headers.contains_key(header::ETAG) {
+                parts.headers.insert(header::ETAG, etag);
+            }
+        }
+        if !config.no_last_modified && !parts.headers.contains_key(header::LAST_MODIFIED) {
+            if let Ok(modified) = HeaderValue::from_str(&clock::http_date(version.modified_ms)) {
+                parts.headers.insert(header::LAST_MODIFIED, modified);
+            }
+        }
+
+        // `If-Modified-Since` is only looked at without `If-None-Match`, and by the second
+        let etag = parts.headers.get(header::ETAG).and_then(|etag| etag.to_str().ok());
+        let modified_ms = parts.headers.get(header::LAST_MODIFIED)
+            .and_then(|modified| modified.to_str().ok())
+            .and_then(clock::parse_http_date);
+        let not_modified = match (&self.if_none_match, self.if_modified_since) {
+            (Some(if_none_match), _) => etag.is_some_and(|etag| none_match(if_none_match, etag)),
+            (None, Some(since_ms)) => modified_ms.is_some_and(|modified_ms| modified_ms / 1000 <= since_ms / 1000),
+            (None, None) => false,
+        };
+        if !not_modified {
+            return warp::http::Response::from_parts(parts, body.into());
+        }
+        parts.status = StatusCode::NOT_MODIFIED;
+        for name in [header::CONTENT_LENGTH, header::CONTENT_TYPE, header::CONTENT_ENCODING, header::TRANSFER_ENCODING] {
+            parts.headers.remove(name);
+        }
+        warp::http::Response::from_parts(parts, hyper::Body::empty())
+    }
+}
+
+/// The content version of the server on `port`, or the status and error to answer with
+/// if it has none
+fn find_content_version(database: &Database, port: u16) -> Result<SharedContentVersion, (StatusCode, String)> {
+    let registry = lock(database);
+    let server = registry.servers.get(&port)
+        .ok_or_else(|| (StatusCode::NOT_FOUND, format!("no server on port {}", port)))?;
+    server.content_version.clone()
+        .ok_or_else(|| (StatusCode::CONFLICT, "only in-process HTTP servers have a content version".to_string()))
+}
+
+/// `GET /{port}/content-version`: the version of what the server on `port` serves
+pub fn get_content_version(database: Database, port: u16) -> warp::reply::Response {
+    match find_content_version(&database, port) {
+        Ok(content) => warp::reply::json(&*lock(&content)).into_response(),
+        Err((status, error)) => error_reply(status, &error),
+    }
+}
+
+/// `POST /{port}/content-version`: bump the version of what the server on `port` serves,
+/// as of now by its clock, for what clients have of it to go stale if it's `versioned`
+pub fn bump_content_version(database: Database, port: u16) -> warp::reply::Response {
+    let content = match find_content_version(&database, port) {
+        Ok(content) => content,
+        Err((status, error)) => return error_reply(status, &error),
+    };
+    let clock = lock(&database).servers.get(&port).and_then(|server| server.clock.clone()).unwrap_or_default();
+    let now_ms = lock(&clock).now_ms();
+    let mut content = lock(&content);
+    *content = ContentVersion { version: content.version + 1, modified_ms: now_ms };
+    warp::reply::json(&*content).into_response()
+}
@@ -0,0 +1,300 @@
+use warp::Reply;
+
+use std::fs::File;
+use std::io::Write;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::{error_reply, Database, ServerKind};
+use crate::error::lock;
+
+/// `LINKTYPE_RAW`: packets start with their IPv4 or IPv6 header
+const LINKTYPE_RAW: u32 = 101;
+
+/// Payload bytes per synthesized packet
+const MAX_SEGMENT_BYTES: usize = 65_000;
+
+/// JSON body of `POST /{port}/capture`
+#[derive(Debug, serde_derive::Deserialize)]
+pub struct CaptureJsonBody {
+    /// Whether to capture from now on. Stopping keeps the files written.
+    #[serde(default = "default_enabled")]
+    enabled: bool,
+    /// Directory of the capture files, by default the system's temporary one
+    #[serde(default)]
+    dir: Option<PathBuf>,
+    /// Size a file grows to before the capture moves on to the next
+    #[serde(default = "default_max_file_bytes")]
+    max_file_bytes: u64,
+    /// Files kept, the oldest being deleted as the capture moves on
+    #[serde(default = "default_max_files")]
+    max_files: usize,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+fn default_max_file_bytes() -> u64 {
+    10 * 1024 * 1024
+}
+
+fn default_max_files() -> usize {
+    5
+}
+
+/// What `GET` and `POST /{port}/capture` report
+#[derive(Debug, Default, serde_derive::Serialize)]
+struct CaptureStatus {
+    enabled: bool,
+    /// The files kept, oldest first
+    files: Vec<PathBuf>,
+    packets: u64,
+    bytes: u64,
+}
+
+/// Wire traffic of an HTTP server being written to rotating pcap files, which Wireshark
+/// and tcpdump read. The IP and TCP headers are made up from the connection's addresses,
+/// without handshakes or TCP checksums; the payloads are exactly what went over the wire.
+pub struct Capture {
+    port: u16,
+    dir: PathBuf,
+    max_file_bytes: u64,
+    max_files: usize,
+    /// Numbers the files, so their names sort in the order they were written
+    next_file: u64,
+    files: Vec<PathBuf>,
+    file: File,
+    file_bytes: u64,
+    packets: u64,
+    bytes: u64,
+}
+
+impl Capture {
+    fn start(port: u16, body: &CaptureJsonBody) -> std::io::Result<Capture> {
+        let dir = body.dir.clone().unwrap_or_else(std::env::temp_dir);
+        std::fs::create_dir_all(&dir)?;
+        let (path, file) = Capture::create_file(&dir, port, 0)?;
+
+        Ok(Capture {
+            port,
+            dir,
+            max_file_bytes: body.max_file_bytes,
+            max_files: body.max_files.max(1),
+            next_file: 1,
+            files: vec![path],
+            file,
+            file_bytes: PCAP_HEADER_BYTES,
+            packets: 0,
+            bytes: 0,
+        })
+    }
+
+    fn create_file(dir: &std::path::Path, port: u16, number: u64) -> std::io::Result<(PathBuf, File)> {
+        let started = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_secs());
+        let path = dir.join(format!("capture-{}-{}-{:04}.pcap", port, started, number));
+        let mut file = File::create(&path)?;
+        file.write_all(&pcap_header())?;
+        Ok((path, file))
+    }
+
+    /// Write a packet of `payload` sent from `from` to `to`, numbered `seq` in its
+    /// direction and acknowledging `ack`
+    fn packet(&mut self, from: SocketAddr, to: SocketAddr, seq: u32, ack: u32, payload: &[u8]) -> std::io::Result<()> {
+        let packet = ip_packet(from, to, seq, ack, payload);
+        let record = 16 + packet.len() as u64;
+
+        if self.file_bytes + record > self.max_file_bytes && self.file_bytes > PCAP_HEADER_BYTES {
+            let (path, file) = Capture::create_file(&self.dir, self.port, self.next_file)?;
+            self.next_file += 1;
+            self.file = file;
+            self.file_bytes = PCAP_HEADER_BYTES;
+            self.files.push(path);
+            while self.files.len() > self.max_files {
+                let oldest = self.files.remove(0);
+                if let Err(e) = std::fs::remove_file(&oldest) {
+                    eprintln!("failed to remove capture file {}: {}", oldest.display(), e);
+                }
+            }
+        }
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let mut bytes = Vec::with_capacity(record as usize);
+        bytes.extend_from_slice(&(now.as_secs() as u32).to_le_bytes());
+        bytes.extend_from_slice(&now.subsec_micros().to_le_bytes());
+        bytes.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&packet);
+        self.file.write_all(&bytes)?;
+
+        self.file_bytes += record;
+        self.packets += 1;
+        self.bytes += payload.len() as u64;
+        Ok(())
+    }
+
+    fn status(&self) -> CaptureStatus {
+        CaptureStatus { enabled: true, files: self.files.clone(), packets: self.packets, bytes: self.bytes }
+    }
+}
+
+const PCAP_HEADER_BYTES: u64 = 24;
+
+fn pcap_header() -> Vec<u8> {
+    let mut header = Vec::with_capacity(PCAP_HEADER_BYTES as usize);
+    header.extend_from_slice(&0xa1b2_c3d4u32.to_le_bytes());
+    header.extend_from_slice(&2u16.to_le_bytes());
+    header.extend_from_slice(&4u16.to_le_bytes());
+    header.extend_from_slice(&0i32.to_le_bytes());
+    header.extend_from_slice(&0u32.to_le_bytes());
+    header.extend_from_slice(&(MAX_SEGMENT_BYTES as u32 + 60).to_le_bytes());
+    header.extend_from_slice(&LINKTYPE_RAW.to_le_bytes());
+    header
+}
+
+/// An IP packet carrying `payload` in a TCP segment with `PSH` and `ACK` set
+fn ip_packet(from: SocketAddr, to: SocketAddr, seq: u32, ack: u32, payload: &[u8]) -> Vec<u8> {
+    let mut tcp = Vec::with_capacity(20 + payload.len());
+    tcp.extend_from_slice(&from.port().to_be_bytes());
+    tcp.extend_from_slice(&to.port().to_be_bytes());
+    tcp.extend_from_slice(&seq.to_be_bytes());
+    tcp.extend_from_slice(&ack.to_be_bytes());
+    tcp.push(5 << 4);
+    tcp.push(0x18);
+    tcp.extend_from_slice(&u16::MAX.to_be_bytes());
+    tcp.extend_from_slice(&[0, 0, 0, 0]);
+    tcp.extend_from_slice(payload);
+
+    let mut packet = Vec::with_capacity(40 + tcp.len());
+    match (from.ip(), to.ip()) {
+        (IpAddr::V4(from), IpAddr::V4(to)) => {
+            packet.extend_from_slice(&[0x45, 0]);
+            packet.extend_from_slice(&(20 + tcp.len() as u16).to_be_bytes());
+            packet.extend_from_slice(&[0, 0, 0x40, 0, 64, 6, 0, 0]);
+            packet.extend_from_slice(&from.octets());
+            packet.extend_from_slice(&to.octets());
+            let sum: u32 = packet.chunks(2).map(|word| u32::from(u16::from_be_bytes([word[0], word[1]]))).sum();
+            let sum = (sum & 0xffff) + (sum >> 16);
+            let checksum = !((sum & 0xffff) + (sum >> 16)) as u16;
+            packet[10..12].copy_from_slice(&checksum.to_be_bytes());
+        }
+        (from, to) => {
+            let v6 = |ip: IpAddr| match ip {
+                IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+                IpAddr::V6(ip) => ip,
+            };
+            packet.extend_from_slice(&[0x60, 0, 0, 0]);
+            packet.extend_from_slice(&(tcp.len() as u16).to_be_bytes());
+            packet.extend_from_slice(&[6, 64]);
+            packet.extend_from_slice(&v6(from).octets());
+            packet.extend_from_slice(&v6(to).octets());
+        }
+    }
+    packet.extend_from_slice(&tcp);
+    packet
+}
+
+/// The capture of a server's traffic, shared with its connections
+pub type SharedCapture = Arc<Mutex<Option<Capture>>>;
+
+/// One connection's side of a capture, numbering the bytes going each way like TCP does
+pub struct Flow {
+    capture: SharedCapture,
+    client: SocketAddr,
+    server: SocketAddr,
+    client_seq: u32,
+    server_seq: u32,
+}
+
+impl Flow {
+    pub fn new(capture: SharedCapture, client: Option<SocketAddr>, port: u16) -> Flow {
+        let unknown = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0);
+        let client = client.unwrap_or(unknown);
+        let server = SocketAddr::new(if client.is_ipv4() { IpAddr::V4(Ipv4Addr::LOCALHOST) } else { IpAddr::V6(Ipv6Addr::LOCALHOST) }, port);
+        Flow { capture, client, server, client_seq: 1, server_seq: 1 }
+    }
+
+    /// Capture bytes read from the client if `received`, or else written to it
+    pub fn record(&mut self, received: bool, bytes: &[u8]) {
+        let mut shared = lock(&self.capture);
+        let capture = match shared.as_mut() {
+            Some(capture) => capture,
+            None => return,
+        };
+
+        for segment in bytes.chunks(MAX_SEGMENT_BYTES) {
+            let written = if received {
+                capture.packet(self.client, self.server, self.client_seq, self.server_seq, segment)
+            } else {
+                capture.packet(self.server, self.client, self.server_seq, self.client_seq, segment)
+            };
+            if let Err(e) = written {
+                eprintln!("failed to capture traffic of server {}, stopping: {}", self.server.port(), e);
+                *shared = None;
+                return;
+            }
+            let seq = if received { &mut self.client_seq } else { &mut self.server_seq };
+            *seq = seq.wrapping_add(segment.len() as u32);
+        }
+    }
+}
+
+fn find_capture(database: &Database, port: u16) -> Result<SharedCapture, (warp::http::StatusCode, String)> {
+    let registry = lock(database);
+    let server = registry.servers.get(&port)
+        .ok_or_else(|| (warp::http::StatusCode::NOT_FOUND, format!("no server on port {}", port)))?;
+
+    match &server.capture {
+        Some(capture) => Ok(capture.clone()),
+        None if server.config.kind == ServerKind::Http => {
+            Err((warp::http::StatusCode::CONFLICT, "only in-process servers can be captured".to_string()))
+        }
+        None => {
+            let error = format!("a {} server can't be captured", server.config.kind.name());
+            Err((warp::http::StatusCode::CONFLICT, error))
+        }
+    }
+}
+
+/// Start or stop capturing the traffic of the HTTP server on `port`. Starting anew moves
+/// on to fresh files.
+pub fn toggle_capture(
+    database: Database,
+    port: u16,
+    body: CaptureJsonBody
+) -> warp::reply::Response {
+    let capture = match find_capture(&database, port) {
+        Ok(capture) => capture,
+        Err((status, error)) => return error_reply(status, &error),
+    };
+    let mut capture = lock(&capture);
+
+    if !body.enabled {
+        let status = capture.take().map(|capture| CaptureStatus { enabled: false, ..capture.status() });
+        return warp::reply::json(&status.unwrap_or_default()).into_response();
+    }
+    match Capture::start(port, &body) {
+        Ok(started) => {
+            let status = started.status();
+            *capture = Some(started);
+            warp::reply::json(&status).into_response()
+        }
+        Err(e) => {
+            let error = format!("failed to start capture: {}", e);
+            error_reply(warp::http::StatusCode::UNPROCESSABLE_ENTITY, &error)
+        }
+    }
+}
+
+/// Whether the traffic of the HTTP server on `port` is being captured, and where to
+pub fn get_capture(database: Database, port: u16) -> warp::reply::Response {
+    match find_capture(&database, port) {
+        Ok(capture) => {
+            let status = lock(&capture).as_ref().map(Capture::status).unwrap_or_default();
+            warp::reply::json(&status).into_response()
+        }
+        Err((status, error)) => error_reply(status, &error),
+    }
+}
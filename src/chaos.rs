@@ -0,0 +1,238 @@
+use futures::{Future, Stream};
+use futures::sync::oneshot;
+use tokio::timer::{Delay, Interval};
+
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::{error_reply, Database, Isolation, ServerKind, ServerStatus};
+use crate::error::lock;
+use crate::runtime;
+use crate::watch::ChangeType;
+
+/// The interval, duration and delay of a schedule at most, an hour each, well within what
+/// the timer can wait for
+const MAX_MS: u64 = 60 * 60 * 1000;
+
+/// Something chaos can do to a server
+#[derive(Clone, Copy, Debug, PartialEq, serde_derive::Deserialize, serde_derive::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChaosAction {
+    /// Pause the server for `duration_ms`
+    Pause,
+    /// Hold every response of an in-process HTTP server back by `delay_ms`, for
+    /// `duration_ms`
+    Delay,
+    /// Shut the server down as if it crashed, respawning it if it's configured to
+    Kill,
+}
+
+/// JSON body of `PUT /chaos`
+#[derive(Clone, Debug, serde_derive::Deserialize, serde_derive::Serialize)]
+pub struct ChaosConfig {
+    /// Time between rounds, in each of which every target is hit with `probability`. This,
+    /// `duration_ms` and `delay_ms` are an hour at most.
+    #[serde(default = "default_interval_ms")]
+    interval_ms: u64,
+    #[serde(default = "default_probability")]
+    probability: f64,
+    /// What may happen to a server that is hit, picked at random
+    #[serde(default = "default_actions")]
+    actions: Vec<ChaosAction>,
+    /// How long a pause or delay lasts
+    #[serde(default = "default_duration_ms")]
+    duration_ms: u64,
+    #[serde(default = "default_delay_ms")]
+    delay_ms: u64,
+    /// Ports of the servers that may be hit, by default every one but the admin API's
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    ports: Vec<u16>,
+    /// Seed of the random choices, to repeat a run. By default taken from the clock.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    seed: Option<u64>,
+}
+
+fn default_interval_ms() -> u64 {
+    1000
+}
+
+fn default_probability() -> f64 {
+    0.1
+}
+
+fn default_actions() -> Vec<ChaosAction> {
+    vec![ChaosAction::Pause, ChaosAction::Delay, ChaosAction::Kill]
+}
+
+fn default_duration_ms() -> u64 {
+    1000
+}
+
+fn default_delay_ms() -> u64 {
+    500
+}
+
+/// A running chaos schedule
+pub struct Chaos {
+    config: ChaosConfig,
+    // Dropped to stop the schedule
+    _stop: oneshot::Sender<()>,
+}
+
+/// A xorshift64* generator, good enough for picking victims reproducibly
+#[derive(Debug)]
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Rng {
+        Rng(seed.max(1))
+    }
+
+    /// A seed taken from the clock
+    pub fn clock_seed() -> u64 {
+        SystemTime::now().duration_since(UNIX_EPOCH).map_or(1, |elapsed| elapsed.as_nanos() as u64)
+    }
+
+    pub fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    /// Uniform in `[0, 1)`
+    pub fn fraction(&mut self) -> f64 {
+        (self.next() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+/// Start injecting faults into the fleet according to `config`, replacing any schedule
+/// already running. Every fault is published on the `GET /watch` feed with its `chaos`
+/// action. The admin API on `own_port` is never hit.
+pub fn start_chaos(
+    database: Database,
+    own_port: u16,
+    config: ChaosConfig
+) -> warp::reply::Response {
+    if !(0.0..=1.0).contains(&config.probability) || config.interval_ms == 0 || config.actions.is_empty() {
+        let error = "probability must be within 0 and 1, and interval_ms and actions non-empty";
+        return error_reply(warp::http::StatusCode::UNPROCESSABLE_ENTITY, error);
+    }
+    if [config.interval_ms, config.duration_ms, config.delay_ms].iter().any(|ms| *ms > MAX_MS) {
+        let error = format!("interval_ms, duration_ms and delay_ms must be at most {} each", MAX_MS);
+        return error_reply(warp::http::StatusCode::UNPROCESSABLE_ENTITY, &error);
+    }
+
+    let seed = config.seed.unwrap_or_else(Rng::clock_seed);
+    let mut rng = Rng::new(seed);
+    let (stop_tx, stop) = oneshot::channel();
+
+    let round_database = database.clone();
+    let round_config = config.clone();
+    let period = Duration::from_millis(config.interval_ms);
+    let rounds = Interval::new(Instant::now() + period, period)
+        .map_err(|e| eprintln!("chaos timer error: {}", e))
+        .for_each(move |_| {
+            round(&round_database, own_port, &round_config, &mut rng);
+            Ok(())
+        });
+    runtime::spawn(rounds.select2(stop).then(|_| Ok(())));
+
+    let reply = warp::reply::json(&config);
+    lock(&database).chaos = Some(Chaos { config, _stop: stop_tx });
+    warp::reply::Reply::into_response(reply)
+}
+
+/// The chaos schedule running, if any
+pub fn get_chaos(database: Database) -> warp::reply::Response {
+    match &lock(&database).chaos {
+        Some(chaos) => warp::reply::Reply::into_response(warp::reply::json(&chaos.config)),
+        None => not_running(),
+    }
+}
+
+/// Stop the chaos schedule. Pauses and delays already injected run their course.
+pub fn stop_chaos(database: Database) -> warp::reply::Response {
+    match lock(&database).chaos.take() {
+        Some(_) => warp::reply::Reply::into_response(warp::http::StatusCode::NO_CONTENT),
+        None => not_running(),
+    }
+}
+
+fn not_running() -> warp::reply::Response {
+    error_reply(warp::http::StatusCode::NOT_FOUND, "no chaos schedule is running")
+}
+
+fn round(database: &Database, own_port: u16, config: &ChaosConfig, rng: &mut Rng) {
+    let mut registry = lock(database);
+
+    let mut targets: Vec<u16> = registry.servers.values()
+        .filter(|server| server.status == ServerStatus::Running)
+        .map(|server| server.config.port)
+        .filter(|port| *port != own_port && (config.ports.is_empty() || config.ports.contains(port)))
+        .collect();
+    targets.sort();
+
+    for port in targets {
+        if rng.fraction() >= config.probability {
+            continue;
+        }
+        let server = &registry.servers[&port];
+        let possible: Vec<ChaosAction> = config.actions.iter().cloned()
+            .filter(|action| match action {
+                ChaosAction::Pause => server.config.pausable(),
+                ChaosAction::Delay => {
+                    server.config.kind == ServerKind::Http && server.config.isolation == Isolation::InProcess
+                }
+                ChaosAction::Kill => true,
+            })
+            .collect();
+        if possible.is_empty() {
+            continue;
+        }
+        let action = possible[(rng.next() % possible.len() as u64) as usize];
+
+        let server = registry.servers.get_mut(&port).unwrap();
+        let id = server.id;
+        match action {
+            ChaosAction::Pause => {
+                server.paused.store(true, Ordering::SeqCst);
+                server.status = ServerStatus::Paused;
+            }
+            ChaosAction::Delay => server.delay_ms.store(config.delay_ms, Ordering::SeqCst),
+            ChaosAction::Kill => {}
+        }
+        registry.record_chaos(port, action);
+
+        match action {
+            ChaosAction::Pause | ChaosAction::Delay => {
+                let database = database.clone();
+                runtime::spawn(Delay::new(Instant::now() + Duration::from_millis(config.duration_ms))
+                    .map_err(|e| eprintln!("chaos timer error: {}", e))
+                    .map(move |_| recover(&database, port, id, action)));
+            }
+            // The supervisor takes a server that finishes without draining for crashed
+            ChaosAction::Kill => registry.servers.get_mut(&port).unwrap().signal_shutdown(),
+        }
+    }
+}
+
+/// Undo a pause or delay of server `id` on `port`, unless it has been replaced or its
+/// state changed in the meantime
+fn recover(database: &Database, port: u16, id: usize, action: ChaosAction) {
+    let mut registry = lock(database);
+    let server = match registry.servers.get_mut(&port) {
+        Some(server) if server.id == id => server,
+        _ => return,
+    };
+
+    match action {
+        ChaosAction::Pause if server.status == ServerStatus::Paused => {
+            server.paused.store(false, Ordering::SeqCst);
+            server.status = ServerStatus::Running;
+            registry.record_change(port, ChangeType::Modified);
+        }
+        ChaosAction::Delay => server.delay_ms.store(0, Ordering::SeqCst),
+        _ => {}
+    }
+}
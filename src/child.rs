@@ -0,0 +1,81 @@
+use futures::Future;
+use futures::future::Either;
+use futures::sync::oneshot;
+use tokio_process::CommandExt;
+
+use std::process::Command;
+use std::sync::{Arc, Mutex};
+
+use crate::{runtime, start_server, Isolation, Registry, ServerJsonBody};
+use crate::error::lock;
+use crate::server_logs::ServerLogs;
+
+/// The flag that makes the binary serve a single server described by the JSON argument
+/// following it, instead of the admin API on 8080
+pub const CHILD_FLAG: &str = "--child";
+
+/// Re-exec this binary to run the server described by `config` in a child process. The
+/// returned future ends when the child exits, and kills it when `shutdown` fires.
+///
+/// The child binds its listener on its own, so a port that's in use shows up as the
+/// child exiting straight away rather than as an error here.
+pub fn spawn_child(
+    config: &ServerJsonBody,
+    shutdown: oneshot::Receiver<()>
+) -> std::io::Result<impl Future<Item = (), Error = ()>> {
+    let config = serde_json::to_string(config)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+    let child = Command::new(std::env::current_exe()?)
+        .arg(CHILD_FLAG)
+        .arg(config)
+        .spawn_async()?;
+
+    Ok(child.select2(shutdown).then(|result| -> Box<dyn Future<Item = (), Error = ()> + Send> {
+        match result {
+            Ok(Either::A((status, _))) => {
+                eprintln!("server child exited with {}", status);
+                Box::new(futures::future::ok(()))
+            }
+            Err(Either::A((e, _))) => {
+                eprintln!("server child error: {}", e);
+                Box::new(futures::future::ok(()))
+            }
+            // A dropped shutdown sender (the server was replaced) kills the child too
+            Ok(Either::B((_, mut child))) | Err(Either::B((_, mut child))) => {
+                if let Err(e) = child.kill() {
+                    eprintln!("failed to kill server child: {}", e);
+                }
+                Box::new(child.map(|_| ()).map_err(|e| eprintln!("server child error: {}", e)))
+            }
+        }
+    }))
+}
+
+/// Entry point of a child process: run the server described by the JSON `config` in
+/// process, with a registry of its own, until it finishes.
+pub fn run_child(config: &str) {
+    let mut config: ServerJsonBody = match serde_json::from_str(config) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("invalid child server configuration: {}", e);
+            std::process::exit(2);
+        }
+    };
+    config.isolation = Isolation::InProcess;
+    // Checked by the parent already. The child writes its server's log itself.
+    let server_logs = ServerLogs::from_env().unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(2);
+    });
+
+    let database = Arc::new(Mutex::new(Registry { server_logs, ..Registry::default() }));
+
+    runtime::run(futures::future::lazy(move || {
+        let mut registry = lock(&database);
+        if let Err(e) = start_server(&database, &mut registry, config, 0) {
+            eprintln!("failed to start child server: {}", e);
+            std::process::exit(1);
+        }
+        Ok(())
+    }));
+}
@@ -0,0 +1,153 @@
+use crate::{ServerJsonBody, StubSet, StubsReport};
+
+/// Failures of `Client` calls
+#[derive(Debug, thiserror::Error)]
+pub enum ClientError {
+    #[error("request failed: {0}")]
+    Http(#[from] reqwest::Error),
+    /// The admin API answered with an error status
+    #[error("{status}: {error}")]
+    Api {
+        status: reqwest::StatusCode,
+        error: String,
+    },
+}
+
+/// JSON body of error responses, as sent by the admin API
+#[derive(serde_derive::Deserialize)]
+struct ErrorJsonBody {
+    error: String,
+}
+
+/// A blocking client for the admin API
+#[derive(Clone, Debug)]
+pub struct Client {
+    base_url: String,
+    http: reqwest::Client,
+    /// Sent as `Authorization: Bearer`, see `with_token`
+    token: Option<String>,
+}
+
+impl Client {
+    /// A client for the admin API at `base_url`, such as `http://127.0.0.1:8080`
+    pub fn new(base_url: impl Into<String>) -> Client {
+        Client {
+            base_url: base_url.into().trim_end_matches('/').to_string(),
+            http: reqwest::Client::new(),
+            token: None,
+        }
+    }
+
+    /// The client sending `token` as `Authorization: Bearer` with every request: the
+    /// admin token `ADMIN_TOKEN` sets, or the `deletion_token` `create` returns, which
+    /// only `delete` of the server created takes
+    pub fn with_token(self, token: impl Into<String>) -> Client {
+        Client { token: Some(token.into()), ..self }
+    }
+
+    /// `GET /`
+    pub fn list(&self) -> Result<Vec<ServerJsonBody>, ClientError> {
+        let response = self.request(reqwest::Method::GET, "/", None).send()?;
+        Ok(check(response)?.json()?)
+    }
+
+    /// `GET /{port}`
+    pub fn get(&self, port: u16) -> Result<ServerJsonBody, ClientError> {
+        let response = self.request(reqwest::Method::GET, &format!("/{}", port), None).send()?;
+        Ok(check(response)?.json()?)
+    }
+
+    /// `POST /`, returning the created server
+    pub fn create(&self, server: &ServerJsonBody) -> Result<ServerJsonBody, ClientError> {
+        let response = self.request(reqwest::Method::POST, "/", None).json(server).send()?;
+        Ok(check(response)?.json()?)
+    }
+
+    /// `PUT /{port}`, returning the updated server. With `if_match`, only if the server's
+    /// `resource_version` still is that.
+    pub fn update(&self, server: &ServerJsonBody, if_match: Option<u64>) -> Result<ServerJsonBody, ClientError> {
+        let response = self.request(reqwest::Method::PUT, &format!("/{}", server.port), if_match).json(server).send()?;
+        Ok(check(response)?.json()?)
+    }
+
+    /// `DELETE /{port}`. With `if_match`, only if the server's `resource_version` still is
+    /// that.
+    pub fn delete(&self, port: u16, if_match: Option<u64>) -> Result<(), ClientError> {
+        let response = self.request(reqwest::Method::DELETE, &format!("/{}", port), if_match).send()?;
+        check(response)?;
+        Ok(())
+    }
+
+    /// `GET /{port}/stubs`, the stubs of an HTTP server in the order they're tried, with
+    /// how many requests each matched
+    pub fn stubs(&self, port: u16) -> Result<StubsReport, ClientError> {
+        let response = self.request(reqwest::Method::GET, &format!("/{}/stubs", port), None).send()?;
+        Ok(check(response)?.json()?)
+    }
+
+    /// `PUT /{port}/stubs`, returning the stubs as `stubs` lists them. With `if_match`,
+    /// only if the server's `resource_version` still is that.
+    pub fn replace_stubs(&self, port: u16, stubs: &StubSet, if_match: Option<u64>) -> Result<StubsReport, ClientError> {
+        let response = self.request(reqwest::Method::PUT, &format!("/{}/stubs", port), if_match).json(stubs).send()?;
+        Ok(check(response)?.json()?)
+    }
+
+    /// `PATCH /{port}/stubs`, replacing the kinds of stub `patch` has, like
+    /// `header_routes`, and clearing those it has as `null`. Returns the stubs as `stubs`
+    /// lists them. With `if_match`, only if the server's `resource_version` still is that.
+    pub fn patch_stubs(
+        &self,
+        port: u16,
+        patch: &serde_json::Map<String, serde_json::Value>,
+        if_match: Option<u64>
+    ) -> Result<StubsReport, ClientError> {
+        let response = self.request(reqwest::Method::PATCH, &format!("/{}/stubs", port), if_match).json(patch).send()?;
+        Ok(check(response)?.json()?)
+    }
+
+    /// `POST /{port}/stubs/{id}/reset`, `id` being that of `ListedStub::id`, like
+    /// `download:/files/big.bin`
+    pub fn reset_stub(&self, port: u16, id: &str) -> Result<(), ClientError> {
+        let path = format!("/{}/stubs/{}/reset", port, percent_encode(id));
+        let response = self.request(reqwest::Method::POST, &path, None).send()?;
+        check(response)?;
+        Ok(())
+    }
+
+    /// A request for `path` carrying the token, if any, and the ETag of `if_match` as
+    /// `If-Match`, see `RunningServer::etag`
+    fn request(&self, method: reqwest::Method, path: &str, if_match: Option<u64>) -> reqwest::RequestBuilder {
+        let mut request = self.http.request(method, &format!("{}{}", self.base_url, path));
+        if let Some(token) = &self.token {
+            request = request.bearer_auth(token);
+        }
+        if let Some(resource_version) = if_match {
+            request = request.header(reqwest::header::IF_MATCH, format!("\"{}\"", resource_version));
+        }
+        request
+    }
+}
+
+/// `segment` with what can't be in a path segment as is escaped as `%XX`, like the `/` of
+/// the id of a download
+fn percent_encode(segment: &str) -> String {
+    segment.bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b':' => (byte as char).to_string(),
+            byte => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+/// Turn an error status into a `ClientError`, with the error message the API sent if any
+fn check(mut response: reqwest::Response) -> Result<reqwest::Response, ClientError> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+
+    let error = response.json::<ErrorJsonBody>()
+        .map(|body| body.error)
+        .unwrap_or_else(|_| status.canonical_reason().unwrap_or("").to_string());
+    Err(ClientError::Api { status, error })
+}
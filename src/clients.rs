@@ -0,0 +1,74 @@
+use std::convert::TryFrom;
+use std::net::{IpAddr, SocketAddr};
+
+/// Clients told by their address: one address like `10.1.2.3` or `::1`, a CIDR block
+/// like `10.0.0.0/8`, or an address and the port the client connects from, like
+/// `10.1.2.3:5000` or `[::1]:5000`
+#[derive(Clone, Debug, PartialEq, serde_derive::Deserialize, serde_derive::Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct ClientPattern {
+    /// As it was given
+    text: String,
+    network: IpAddr,
+    /// Leading bits of `network` a client's address must share
+    prefix: u32,
+    port: Option<u16>,
+}
+
+impl TryFrom<String> for ClientPattern {
+    type Error = String;
+
+    fn try_from(text: String) -> Result<ClientPattern, String> {
+        let invalid = || format!("invalid client {:?}, expected an address, a CIDR block or an address and port", text);
+        let (network, prefix, port) = if let Some((network, prefix)) = text.split_once('/') {
+            let network: IpAddr = network.parse().map_err(|_| invalid())?;
+            let prefix: u32 = prefix.parse().map_err(|_| invalid())?;
+            (network, prefix, None)
+        } else if let Ok(address) = text.parse::<SocketAddr>() {
+            (address.ip(), bits(address.ip()), Some(address.port()))
+        } else {
+            let address: IpAddr = text.parse().map_err(|_| invalid())?;
+            (address, bits(address), None)
+        };
+        if prefix > bits(network) {
+            return Err(invalid());
+        }
+        Ok(ClientPattern { network: network.to_canonical(), prefix, port, text })
+    }
+}
+
+impl From<ClientPattern> for String {
+    fn from(pattern: ClientPattern) -> String {
+        pattern.text
+    }
+}
+
+fn bits(address: IpAddr) -> u32 {
+    match address {
+        IpAddr::V4(_) => 32,
+        IpAddr::V6(_) => 128,
+    }
+}
+
+impl ClientPattern {
+    fn matches(&self, client: SocketAddr) -> bool {
+        // Left as many bits as the prefix, of the address and the network
+        let shift = bits(self.network) - self.prefix;
+        let in_network = match (self.network, client.ip().to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(address)) => {
+                u64::from(u32::from(network)) >> shift == u64::from(u32::from(address)) >> shift
+            }
+            (IpAddr::V6(network), IpAddr::V6(address)) => {
+                u128::from(network).checked_shr(shift).unwrap_or(0) == u128::from(address).checked_shr(shift).unwrap_or(0)
+            }
+            _ => false,
+        };
+        in_network && self.port.is_none_or(|port| port == client.port())
+    }
+}
+
+/// Whether `client` is one of those `patterns` tell, any client if there are none. A
+/// client whose address isn't known is only taken by no patterns.
+pub fn matches(patterns: &[ClientPattern], client: Option<SocketAddr>) -> bool {
+    patterns.is_empty() || client.is_some_and(|client| patterns.iter().any(|pattern| pattern.matches(client)))
+}
@@ -0,0 +1,171 @@
+use warp::Reply;
+use warp::http::StatusCode;
+
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::{error_reply, har, Database};
+use crate::error::lock;
+
+const WEEKDAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+
+const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+/// JSON body of `PUT /{port}/clock`: the time an in-process HTTP server tells, set to
+/// `at` or shifted from the real time by `offset_secs`, and stopped there if `frozen`
+#[derive(Clone, Debug, Default, PartialEq, serde_derive::Deserialize, serde_derive::Serialize)]
+pub struct ClockConfig {
+    /// Unix time the clock is set to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub at: Option<u64>,
+    /// Seconds the clock is ahead of the real time, behind if negative
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub offset_secs: Option<i64>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub frozen: bool,
+}
+
+/// What `GET /{port}/clock` tells
+#[derive(serde_derive::Serialize)]
+struct ClockStatus {
+    /// ISO 8601, in UTC
+    now: String,
+    unix_time: u64,
+    offset_secs: i64,
+    frozen: bool,
+}
+
+/// The time a server tells, in templates and `Date` headers: the real time unless it's
+/// been bent with `PUT /{port}/clock`
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Clock {
+    offset_ms: i64,
+    frozen_at_ms: Option<u64>,
+}
+
+fn real_ms() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_millis() as u64)
+}
+
+impl Clock {
+    /// Milliseconds since the epoch
+    pub fn now_ms(&self) -> u64 {
+        self.frozen_at_ms.unwrap_or_else(|| real_ms().saturating_add_signed(self.offset_ms))
+    }
+
+    fn bent(&self) -> bool {
+        self.offset_ms != 0 || self.frozen_at_ms.is_some()
+    }
+
+    fn status(&self) -> ClockStatus {
+        let now_ms = self.now_ms();
+        ClockStatus {
+            now: har::iso8601(now_ms),
+            unix_time: now_ms / 1000,
+            offset_secs: (now_ms as i64 - real_ms() as i64) / 1000,
+            frozen: self.frozen_at_ms.is_some(),
+        }
+    }
+}
+
+/// The clock of an in-process HTTP server, kept across restarts
+pub type SharedClock = Arc<Mutex<Clock>>;
+
+/// `unix_ms` as the date of an HTTP header, like `Sat, 01 Jun 2019 12:00:00 GMT`
+pub fn http_date(unix_ms: u64) -> String {
+    // Taken apart from `2019-06-01T12:00:00.000Z`
+    let iso = har::iso8601(unix_ms);
+    let month: usize = iso[5..7].parse().unwrap_or(1);
+    let weekday = WEEKDAYS[(unix_ms / 1000 / 86400 % 7) as usize];
+    format!("{}, {} {} {} {} GMT", weekday, &iso[8..10], MONTHS[month - 1], &iso[..4], &iso[11..19])
+}
+
+/// The Unix time in milliseconds of the date of an HTTP header, like
+/// `Sat, 01 Jun 2019 12:00:00 GMT`, if it's one
+pub fn parse_http_date(date: &str) -> Option<u64> {
+    let fields: Vec<&str> = date.split_whitespace().collect();
+    let (day, month, year, time) = match fields[..] {
+        [_, day, month, year, time, "GMT"] => (day, month, year, time),
+        _ => return None,
+    };
+    let day: i64 = day.parse().ok()?;
+    let month = MONTHS.iter().position(|name| *name == month)? as i64 + 1;
+    let year: i64 = year.parse().ok()?;
+    let mut time = time.split(':').map(|field| field.parse::<u64>().ok());
+    let (hours, minutes, seconds) = (time.next()??, time.next()??, time.next()??);
+
+    // Days from civil, see <http://howardhinnant.github.io/date_algorithms.html>
+    let year = year - i64::from(month <= 2);
+    let era = year.div_euclid(400);
+    let year_of_era = year.rem_euclid(400);
+    let day_of_year = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146_097 + day_of_era - 719_468;
+    if days < 0 {
+        return None;
+    }
+    Some((days as u64 * 86400 + hours * 3600 + minutes * 60 + seconds) * 1000)
+}
+
+/// `response` with the `Date` the clock tells, if it's been bent
+pub fn dated(clock: &SharedClock, mut response: warp::reply::Response) -> warp::reply::Response {
+    let clock = *lock(clock);
+    if clock.bent() {
+        if let Ok(date) = warp::http::header::HeaderValue::from_str(&http_date(clock.now_ms())) {
+            response.headers_mut().insert(warp::http::header::DATE, date);
+        }
+    }
+    response
+}
+
+/// The clock of the server on `port`, or the status and error to answer with if it has none
+fn find_clock(database: &Database, port: u16) -> Result<SharedClock, (StatusCode, String)> {
+    let registry = lock(database);
+    let server = registry.servers.get(&port)
+        .ok_or_else(|| (StatusCode::NOT_FOUND, format!("no server on port {}", port)))?;
+    server.clock.clone()
+        .ok_or_else(|| (StatusCode::CONFLICT, "only in-process HTTP servers have a clock".to_string()))
+}
+
+/// `GET /{port}/clock`: the time the server on `port` tells
+pub fn get_clock(database: Database, port: u16) -> warp::reply::Response {
+    match find_clock(&database, port) {
+        Ok(clock) => warp::reply::json(&lock(&clock).status()).into_response(),
+        Err((status, error)) => error_reply(status, &error),
+    }
+}
+
+/// `PUT /{port}/clock`: set or shift the clock of the server on `port`, freezing it if
+/// `config` has it. Left without a time, a frozen clock stops at the time it tells.
+pub fn put_clock(database: Database, port: u16, config: ClockConfig) -> warp::reply::Response {
+    let shared = match find_clock(&database, port) {
+        Ok(clock) => clock,
+        Err((status, error)) => return error_reply(status, &error),
+    };
+    let mut clock = lock(&shared);
+    let now_ms = match (config.at, config.offset_secs) {
+        (Some(_), Some(_)) => {
+            return error_reply(StatusCode::UNPROCESSABLE_ENTITY, "a clock is set with at or offset_secs, not both");
+        }
+        (Some(at), None) => at.saturating_mul(1000),
+        (None, Some(offset)) => real_ms().saturating_add_signed(offset.saturating_mul(1000)),
+        (None, None) => clock.now_ms(),
+    };
+    *clock = if config.frozen {
+        Clock { offset_ms: 0, frozen_at_ms: Some(now_ms) }
+    } else {
+        Clock { offset_ms: now_ms as i64 - real_ms() as i64, frozen_at_ms: None }
+    };
+    warp::reply::json(&clock.status()).into_response()
+}
+
+/// `DELETE /{port}/clock`: have the server on `port` tell the real time again
+pub fn delete_clock(database: Database, port: u16) -> warp::reply::Response {
+    match find_clock(&database, port) {
+        Ok(clock) => {
+            *lock(&clock) = Clock::default();
+            StatusCode::NO_CONTENT.into_response()
+        }
+        Err((status, error)) => error_reply(status, &error),
+    }
+}
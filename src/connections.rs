@@ -0,0 +1,207 @@
+use futures::{Async, Future, Poll};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::timer::Delay;
+use warp::Reply;
+
+use std::io::{self, Read, Write};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+/// How an in-process HTTP server keeps its connections open, in place of always letting
+/// clients reuse them for as long as they like
+#[derive(Clone, Debug, Default, PartialEq, serde_derive::Deserialize, serde_derive::Serialize)]
+#[serde(default)]
+pub struct ConnectionOptions {
+    /// Close a connection idle this long after answering a request
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub keep_alive_timeout_ms: Option<u64>,
+    /// Answer this many requests on a connection, closing it after the last
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_requests_per_connection: Option<u64>,
+    /// Close a connection whose request headers don't arrive this soon after it's opened
+    /// or its request starts
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub header_read_timeout_ms: Option<u64>,
+    /// Answer every request with `Connection: close`
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub force_close: bool,
+}
+
+impl ConnectionOptions {
+    pub fn is_default(&self) -> bool {
+        *self == ConnectionOptions::default()
+    }
+
+    pub fn check(&self) -> io::Result<()> {
+        let zero = [
+            ("keep_alive_timeout_ms", self.keep_alive_timeout_ms),
+            ("max_requests_per_connection", self.max_requests_per_connection),
+            ("header_read_timeout_ms", self.header_read_timeout_ms),
+        ];
+        match zero.iter().find(|(_, value)| *value == Some(0)) {
+            Some((name, _)) => Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{} can't be 0", name))),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Requests of a connection whose headers were read, and of those the ones answered
+#[derive(Default)]
+struct Progress {
+    started: AtomicU64,
+    answered: AtomicU64,
+}
+
+/// A connection of an HTTP server, which its requests and the stream it's read from
+/// keep track of together
+#[derive(Clone)]
+pub struct Connection {
+    options: ConnectionOptions,
+    progress: Arc<Progress>,
+}
+
+impl Connection {
+    pub fn new(options: &ConnectionOptions) -> Connection {
+        Connection { options: options.clone(), progress: Arc::default() }
+    }
+
+    /// `stream` read from and written to by this connection, ending when it times out
+    pub fn stream<T>(&self, stream: T) -> Managed<T> {
+        Managed {
+            inner: stream,
+            options: self.options.clone(),
+            progress: self.progress.clone(),
+            opened: Instant::now(),
+            last_active: None,
+            request_since: None,
+            seen: 0,
+            timer: None,
+        }
+    }
+
+    /// Count a request whose headers were read
+    pub fn start(&self) -> Request {
+        let count = self.progress.started.fetch_add(1, Ordering::SeqCst) + 1;
+        let last = self.options.max_requests_per_connection.is_some_and(|max| count >= max);
+        Request { progress: self.progress.clone(), close: self.options.force_close || last }
+    }
+}
+
+/// A request of a `Connection`, being answered
+pub struct Request {
+    progress: Arc<Progress>,
+    close: bool,
+}
+
+impl Request {
+    /// `reply`, telling the client the connection closes after it if it's to
+    pub fn finish(self, reply: impl Reply) -> warp::reply::Response {
+        self.progress.answered.fetch_add(1, Ordering::SeqCst);
+        if self.close {
+            warp::reply::with_header(reply, "connection", "close").into_response()
+        } else {
+            reply.into_response()
+        }
+    }
+}
+
+/// The stream of a `Connection`, which ends as if the client closed it once it's been
+/// idle or reading request headers for too long
+pub struct Managed<T> {
+    inner: T,
+    options: ConnectionOptions,
+    progress: Arc<Progress>,
+    opened: Instant,
+    /// When the last response was written, if any was
+    last_active: Option<Instant>,
+    /// When the first bytes of a request whose headers aren't read yet arrived
+    request_since: Option<Instant>,
+    /// `Progress::started` as of the last read
+    seen: u64,
+    timer: Option<Delay>,
+}
+
+impl<T> Managed<T> {
+    /// When the connection times out, unless something happens before
+    fn deadline(&self) -> Option<Instant> {
+        let answered = self.progress.answered.load(Ordering::SeqCst);
+        if self.progress.started.load(Ordering::SeqCst) > answered {
+            return None;
+        }
+        let header_read_timeout = self.options.header_read_timeout_ms.map(Duration::from_millis);
+        match (self.request_since, self.last_active) {
+            (Some(since), _) => header_read_timeout.map(|timeout| since + timeout),
+            (None, None) => header_read_timeout.map(|timeout| self.opened + timeout),
+            (None, Some(active)) => self.options.keep_alive_timeout_ms.map(|timeout| active + Duration::from_millis(timeout)),
+        }
+    }
+
+    /// Whether the connection timed out, waking the task when it will otherwise
+    fn timed_out(&mut self) -> io::Result<bool> {
+        let deadline = match self.deadline() {
+            Some(deadline) => deadline,
+            None => {
+                self.timer = None;
+                return Ok(false);
+            }
+        };
+        let timer = self.timer.get_or_insert_with(|| Delay::new(deadline));
+        if timer.deadline() != deadline {
+            timer.reset(deadline);
+        }
+        match timer.poll() {
+            Ok(Async::Ready(())) => Ok(true),
+            Ok(Async::NotReady) => Ok(false),
+            Err(e) => Err(io::Error::other(e)),
+        }
+    }
+}
+
+impl<T: Read> Read for Managed<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let started = self.progress.started.load(Ordering::SeqCst);
+        if started != self.seen {
+            self.seen = started;
+            self.request_since = None;
+        }
+
+        match self.inner.read(buf) {
+            Ok(length) => {
+                let busy = started > self.progress.answered.load(Ordering::SeqCst);
+                if length > 0 && !busy && self.request_since.is_none() {
+                    self.request_since = Some(Instant::now());
+                }
+                Ok(length)
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                if self.timed_out()? {
+                    Ok(0)
+                } else {
+                    Err(e)
+                }
+            }
+            Err(e) => Err(e),
+        }
+    }
+}
+
+impl<T: Write> Write for Managed<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let length = self.inner.write(buf)?;
+        self.last_active = Some(Instant::now());
+        Ok(length)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<T: AsyncRead> AsyncRead for Managed<T> {}
+
+impl<T: AsyncWrite> AsyncWrite for Managed<T> {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        self.inner.shutdown()
+    }
+}
@@ -0,0 +1,171 @@
+use warp::Filter;
+use warp::http::header::{HeaderMap, HeaderName, HeaderValue, WWW_AUTHENTICATE};
+use warp::http::StatusCode;
+
+use std::io;
+use std::sync::Arc;
+
+use crate::{auth, error_reply};
+use crate::error_pages::{ErrorPage, Page};
+
+/// The credentials an in-process HTTP server requires of every request, as a service
+/// behind authentication would, for exercising how clients handle failing it. Requests
+/// presenting none are answered with 401 and a `WWW-Authenticate` challenge, those
+/// presenting others with 403. What each request presented is kept in the journal, see
+/// `journal::Entry::credentials`.
+#[derive(Clone, Debug, PartialEq, serde_derive::Deserialize, serde_derive::Serialize)]
+pub struct AuthSimulation {
+    #[serde(flatten)]
+    pub credential: Credential,
+    /// The challenge of the 401, by default one of the scheme, like `Bearer realm="mock"`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub www_authenticate: Option<String>,
+    /// What the 401 answers with in place of a JSON error
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub unauthorized: Option<ErrorPage>,
+    /// What the 403 answers with in place of a JSON error
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub forbidden: Option<ErrorPage>,
+}
+
+/// A credential, and where a request presents it
+#[derive(Clone, Debug, PartialEq, serde_derive::Deserialize, serde_derive::Serialize)]
+#[serde(tag = "scheme", rename_all = "snake_case")]
+pub enum Credential {
+    /// `Authorization: Bearer {token}`
+    Bearer { token: String },
+    /// `Authorization: Basic` of `username` and `password`
+    Basic { username: String, password: String },
+    /// `key` as the value of `header`
+    ApiKey {
+        #[serde(default = "default_api_key_header")]
+        header: String,
+        key: String,
+    },
+}
+
+fn default_api_key_header() -> String {
+    "x-api-key".to_string()
+}
+
+/// What a request presented for the credential of its server, as the journal keeps it
+#[derive(Clone, Debug, PartialEq, serde_derive::Serialize)]
+pub struct Attempt {
+    /// `bearer`, `basic` or `api_key`
+    pub scheme: &'static str,
+    /// The token or key, or the `username:password` of basic authentication. Left out if
+    /// the request presented none.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub presented: Option<String>,
+    pub accepted: bool,
+}
+
+impl Credential {
+    fn scheme(&self) -> &'static str {
+        match self {
+            Credential::Bearer { .. } => "bearer",
+            Credential::Basic { .. } => "basic",
+            Credential::ApiKey { .. } => "api_key",
+        }
+    }
+
+    /// What a request whose headers `header` looks up by name presented for the credential
+    pub fn attempt<'a>(&self, header: impl Fn(&str) -> Option<&'a str>) -> Attempt {
+        let authorization = |scheme: &str| header("authorization")
+            .and_then(|authorization| authorization.split_once(' '))
+            .filter(|(presented, _)| presented.eq_ignore_ascii_case(scheme))
+            .map(|(_, credential)| credential.trim());
+        let (presented, expected) = match self {
+            Credential::Bearer { token } => (authorization("bearer").map(str::to_string), token.clone()),
+            Credential::Basic { username, password } => (
+                authorization("basic").map(|encoded| base64_decode(encoded).unwrap_or_else(|| encoded.to_string())),
+                format!("{}:{}", username, password),
+            ),
+            Credential::ApiKey { header: name, key } => (header(name).map(str::to_string), key.clone()),
+        };
+        let accepted = presented.as_ref().is_some_and(|presented| auth::tokens_equal(presented, &expected));
+        Attempt { scheme: self.scheme(), presented, accepted }
+    }
+}
+
+/// Standard base64, as of basic authentication, if `encoded` is base64 of UTF-8
+fn base64_decode(encoded: &str) -> Option<String> {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut decoded = Vec::with_capacity(encoded.len() / 4 * 3);
+    let (mut group, mut bits) = (0u32, 0);
+    for byte in encoded.trim_end_matches('=').bytes() {
+        let sextet = ALPHABET.iter().position(|letter| *letter == byte)? as u32;
+        group = group << 6 | sextet;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            decoded.push((group >> bits) as u8);
+            group &= (1 << bits) - 1;
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+fn invalid(error: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, error)
+}
+
+/// The authentication a server simulates, checked to be valid, see `Authentication::filter`
+#[derive(Clone, Default)]
+pub struct Authentication(Option<Arc<Checked>>);
+
+struct Checked {
+    credential: Credential,
+    challenge: HeaderValue,
+    unauthorized: Option<Page>,
+    forbidden: Option<Page>,
+}
+
+pub fn authentication(auth: Option<&AuthSimulation>) -> io::Result<Authentication> {
+    let auth = match auth {
+        Some(auth) => auth,
+        None => return Ok(Authentication(None)),
+    };
+    if let Credential::ApiKey { header, .. } = &auth.credential {
+        HeaderName::from_bytes(header.as_bytes())
+            .map_err(|_| invalid(format!("invalid API key header name {:?}", header)))?;
+    }
+    let challenge = match (&auth.www_authenticate, &auth.credential) {
+        (Some(challenge), _) => challenge.clone(),
+        (None, Credential::Bearer { .. }) => "Bearer realm=\"mock\"".to_string(),
+        (None, Credential::Basic { .. }) => "Basic realm=\"mock\"".to_string(),
+        (None, Credential::ApiKey { header, .. }) => format!("ApiKey realm=\"mock\", header=\"{}\"", header),
+    };
+    let challenge = HeaderValue::from_str(&challenge)
+        .map_err(|_| invalid(format!("invalid WWW-Authenticate challenge {:?}", challenge)))?;
+    let unauthorized = auth.unauthorized.as_ref().map(|page| page.checked(StatusCode::UNAUTHORIZED)).transpose()?;
+    let forbidden = auth.forbidden.as_ref().map(|page| page.checked(StatusCode::FORBIDDEN)).transpose()?;
+    Ok(Authentication(Some(Arc::new(Checked { credential: auth.credential.clone(), challenge, unauthorized, forbidden }))))
+}
+
+impl Authentication {
+    /// Answer the requests that don't present the credential with 401 or 403. Others are
+    /// rejected to be routed as usual.
+    pub fn filter(&self) -> impl Filter<Extract = (warp::reply::Response,), Error = warp::Rejection> + Clone {
+        let checked = self.0.clone();
+        warp::header::headers_cloned().and_then(move |headers: HeaderMap| {
+            let checked = checked.as_ref().ok_or_else(warp::reject::not_found)?;
+            let attempt = checked.credential.attempt(|name| headers.get(name).and_then(|value| value.to_str().ok()));
+            match attempt {
+                Attempt { accepted: true, .. } => Err(warp::reject::not_found()),
+                Attempt { presented: None, .. } => {
+                    let mut reply = match &checked.unauthorized {
+                        Some(page) => page.reply(),
+                        None => error_reply(StatusCode::UNAUTHORIZED, "missing credentials"),
+                    };
+                    reply.headers_mut().insert(WWW_AUTHENTICATE, checked.challenge.clone());
+                    Ok(reply)
+                }
+                Attempt { presented: Some(_), .. } => Ok(match &checked.forbidden {
+                    Some(page) => page.reply(),
+                    None => error_reply(StatusCode::FORBIDDEN, "invalid credentials"),
+                }),
+            }
+        })
+    }
+}
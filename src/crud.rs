@@ -0,0 +1,196 @@
+use serde_json::Value;
+use warp::{Buf, Filter, Reply};
+use warp::http::{Method, StatusCode};
+
+use std::collections::BTreeMap;
+use std::io;
+use std::sync::Arc;
+
+use crate::error_reply;
+use crate::error::lock;
+use crate::state::SharedState;
+use crate::stubs::Hits;
+
+fn invalid(error: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, error)
+}
+
+/// Generic REST over named collections of JSON objects an HTTP server keeps in its state,
+/// each under its name, see `state::SharedState`. For a collection `things`:
+///
+/// - `GET /things` lists its items, those whose fields equal the query's, like `?done=true`
+/// - `POST /things` adds an item, with the next numeric `id` unless it has one
+/// - `GET|PUT|PATCH|DELETE /things/{id}` reads, replaces, updates the fields of, or
+///   removes the item with that `id`
+///
+/// Tried after OpenAPI operations and before the fallback, so a collection takes its
+/// paths from the usual routes.
+#[derive(Clone, Debug, Default, PartialEq, serde_derive::Deserialize, serde_derive::Serialize)]
+pub struct Crud {
+    /// Put in front of the paths of the collections, like `/api`
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub prefix: String,
+    /// The collections by name, with the items each starts with
+    pub collections: BTreeMap<String, Vec<Value>>,
+}
+
+impl Crud {
+    fn check(&self) -> io::Result<()> {
+        if !self.prefix.is_empty() && (!self.prefix.starts_with('/') || self.prefix.ends_with('/')) {
+            return Err(invalid(format!("CRUD prefix {:?} doesn't start with / or ends with it", self.prefix)));
+        }
+        for (name, items) in &self.collections {
+            if name.is_empty() || name.contains('/') {
+                return Err(invalid(format!("invalid CRUD collection name {:?}", name)));
+            }
+            if items.iter().any(|item| !item.is_object()) {
+                return Err(invalid(format!("the items of CRUD collection {} aren't all objects", name)));
+            }
+        }
+        Ok(())
+    }
+
+    /// The collection and the id of the item a request for `path` is for, if it's for one
+    pub fn route(&self, path: &str) -> Option<(String, Option<String>)> {
+        let path = path.split_once('?').map_or(path, |(path, _)| path);
+        let path = path.strip_prefix(self.prefix.as_str()).filter(|path| path.starts_with('/'))?;
+        let segments: Vec<&str> = path.split('/').filter(|segment| !segment.is_empty()).collect();
+        match segments[..] {
+            [name] if self.collections.contains_key(name) => Some((name.to_string(), None)),
+            [name, id] if self.collections.contains_key(name) => Some((name.to_string(), Some(id.to_string()))),
+            _ => None,
+        }
+    }
+}
+
+/// The `id` of `item` as it's told by in paths
+fn id_of(item: &Value) -> Option<String> {
+    match item.get("id")? {
+        Value::String(id) => Some(id.clone()),
+        Value::Null => None,
+        id => Some(id.to_string()),
+    }
+}
+
+/// Whether `item` has the field `name` equal to `value` of a query, which is JSON unless
+/// the field is a string, like `done=true`
+fn has_field(item: &Value, name: &str, value: &str) -> bool {
+    match item.get(name) {
+        Some(Value::String(field)) => field == value,
+        Some(field) => serde_json::from_str::<Value>(value).ok().as_ref() == Some(field),
+        None => false,
+    }
+}
+
+/// Answer a request with `method` for the item `id` of the collection `name`, or for the
+/// collection, with `query` and `body`
+fn handle(
+    items: &mut Vec<Value>,
+    method: &Method,
+    name: &str,
+    id: Option<&str>,
+    query: &[(String, String)],
+    body: &[u8]
+) -> warp::reply::Response {
+    let parse = || match serde_json::from_slice::<Value>(body) {
+        Ok(item @ Value::Object(_)) => Ok(item),
+        Ok(_) => Err("an item is a JSON object".to_string()),
+        Err(e) => Err(format!("invalid JSON: {}", e)),
+    };
+    let position = id.and_then(|id| items.iter().position(|item| id_of(item).as_deref() == Some(id)));
+    let not_found = || error_reply(StatusCode::NOT_FOUND, &format!("{} has no item {}", name, id.unwrap_or("")));
+
+    match (method, id, position) {
+        (&Method::GET, None, _) => {
+            let matching: Vec<&Value> = items.iter()
+                .filter(|item| query.iter().all(|(field, value)| has_field(item, field, value)))
+                .collect();
+            warp::reply::json(&matching).into_response()
+        }
+        (&Method::POST, None, _) => {
+            let mut item = match parse() {
+                Ok(item) => item,
+                Err(error) => return error_reply(StatusCode::BAD_REQUEST, &error),
+            };
+            match id_of(&item) {
+                Some(id) if items.iter().any(|existing| id_of(existing).as_deref() == Some(id.as_str())) => {
+                    return error_reply(StatusCode::CONFLICT, &format!("{} already has an item {}", name, id));
+                }
+                Some(_) => {}
+                None => {
+                    let next = items.iter().filter_map(|item| item.get("id")?.as_u64()).max().map_or(1, |last| last + 1);
+                    item["id"] = next.into();
+                }
+            }
+            items.push(item.clone());
+            warp::reply::with_status(warp::reply::json(&item), StatusCode::CREATED).into_response()
+        }
+        (&Method::GET, Some(_), Some(position)) => warp::reply::json(&items[position]).into_response(),
+        (&Method::PUT | &Method::PATCH, Some(_), Some(position)) => {
+            let update = match parse() {
+                Ok(update) => update,
+                Err(error) => return error_reply(StatusCode::BAD_REQUEST, &error),
+            };
+            let item = &mut items[position];
+            // The item keeps its id whatever the body has
+            let id = item["id"].clone();
+            match (method, update) {
+                (&Method::PUT, update) => *item = update,
+                (_, Value::Object(fields)) => {
+                    if let Some(item) = item.as_object_mut() {
+                        item.extend(fields);
+                    }
+                }
+                _ => {}
+            }
+            item["id"] = id;
+            warp::reply::json(item).into_response()
+        }
+        (&Method::DELETE, Some(_), Some(position)) => {
+            items.remove(position);
+            StatusCode::NO_CONTENT.into_response()
+        }
+        (&Method::GET | &Method::PUT | &Method::PATCH | &Method::DELETE, Some(_), None) => not_found(),
+        _ => error_reply(StatusCode::METHOD_NOT_ALLOWED, &format!("{} isn't allowed here", method)),
+    }
+}
+
+/// Answer the requests for the collections of `crud`, if there are any, keeping them in
+/// `state`, which starts with their items, and counting them in `hits`, see `Crud`.
+/// Other requests are rejected to be handled as usual.
+pub fn crud(
+    crud: Option<&Crud>,
+    state: SharedState,
+    hits: Arc<Hits>
+) -> io::Result<impl Filter<Extract = (warp::reply::Response,), Error = warp::Rejection> + Clone> {
+    if let Some(crud) = crud {
+        crud.check()?;
+        let mut state = lock(&state);
+        for (name, items) in &crud.collections {
+            state.insert(name.clone(), Value::Array(items.clone()));
+        }
+    }
+    let crud = crud.cloned().map(Arc::new);
+
+    // Finding the collection first leaves the body to the usual routes if there's none
+    let taken = warp::path::full().and_then(move |path: warp::path::FullPath| {
+        crud.as_ref().and_then(|crud| crud.route(path.as_str())).ok_or_else(warp::reject::not_found)
+    });
+
+    Ok(taken
+        .and(warp::method())
+        .and(warp::query::<Vec<(String, String)>>())
+        .and(warp::body::concat())
+        .map(move |(name, id): (String, Option<String>), method: Method, query: Vec<(String, String)>, body: warp::body::FullBody| {
+            hits.collection(&name);
+            let mut state = lock(&state);
+            // What was put in place of the collection with `PUT /{port}/state` counts as none
+            let mut items = match state.remove(&name) {
+                Some(Value::Array(items)) => items,
+                _ => Vec::new(),
+            };
+            let reply = handle(&mut items, &method, &name, id.as_deref(), &query, body.bytes());
+            state.insert(name, Value::Array(items));
+            reply
+        }))
+}
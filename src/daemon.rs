@@ -0,0 +1,307 @@
+use std::fs::File;
+use std::io::{self, Write};
+#[cfg(unix)]
+use std::io::Read;
+#[cfg(unix)]
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::path::PathBuf;
+
+/// What `Options::parse` takes
+#[cfg(unix)]
+const EXPECTED: &str = "--daemon, --pid-file, --log-file, --log, --ready-file, --tui or --self-test";
+#[cfg(windows)]
+const EXPECTED: &str = "--service, --install-service, --uninstall-service, --pid-file, --log, --ready-file, --tui or --self-test";
+
+/// How the process runs as a service, from its command line
+#[derive(Debug, Default)]
+pub struct Options {
+    /// `--daemon`: detach from the terminal, into the background
+    daemon: bool,
+    /// `--pid-file {path}`: where the process id is written once it's known
+    pid_file: Option<PathBuf>,
+    /// `--log-file {path}`: where a daemon's output goes, rather than nowhere
+    log_file: Option<PathBuf>,
+    /// `--ready-file {path}`: where the `Banner` is written once the process is ready
+    ready_file: Option<PathBuf>,
+    /// `--log {sink}`, as many as wanted: where the process's output goes, rather than
+    /// stderr, see `logging::Sink`
+    pub log: Vec<crate::logging::Sink>,
+    /// `--tui`: show a dashboard of the servers in the terminal, see `tui::run`
+    pub tui: bool,
+    /// `--self-test`: check the environment and run a server through its paces, rather
+    /// than serving, see `selftest::run`
+    pub self_test: bool,
+    /// `--service`, `--install-service` or `--uninstall-service`, see `service::Command`
+    #[cfg(windows)]
+    pub service: Option<crate::service::Command>,
+}
+
+impl Options {
+    pub fn parse(args: &[String]) -> Result<Options, String> {
+        let mut options = Options::default();
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let (name, inline) = match arg.split_once('=') {
+                Some((name, value)) => (name, Some(value.to_string())),
+                None => (arg.as_str(), None),
+            };
+            let mut value = |what: &str| inline.clone()
+                .or_else(|| args.next().cloned())
+                .filter(|value| !value.is_empty())
+                .ok_or_else(|| format!("{} needs {}", name, what));
+            match name {
+                #[cfg(unix)]
+                "--daemon" if inline.is_none() => options.daemon = true,
+                #[cfg(windows)]
+                "--daemon" | "--log-file" => {
+                    return Err(format!("{} is only supported on Unix, run as a Windows service with --install-service instead", name));
+                }
+                #[cfg(windows)]
+                "--service" if inline.is_none() => options.service = Some(crate::service::Command::Run),
+                #[cfg(windows)]
+                "--install-service" if inline.is_none() => options.service = Some(crate::service::Command::Install),
+                #[cfg(windows)]
+                "--uninstall-service" if inline.is_none() => options.service = Some(crate::service::Command::Uninstall),
+                "--pid-file" => options.pid_file = Some(PathBuf::from(value("a path")?)),
+                "--ready-file" => options.ready_file = Some(PathBuf::from(value("a path")?)),
+                "--tui" if inline.is_none() => options.tui = true,
+                "--self-test" if inline.is_none() => options.self_test = true,
+                #[cfg(unix)]
+                "--log-file" => options.log_file = Some(PathBuf::from(value("a path")?)),
+                "--log" => options.log.push(crate::logging::Sink::parse(&value("a sink")?)?),
+                _ => return Err(format!("unknown option {:?}, expected {}", arg, EXPECTED)),
+            }
+        }
+        if options.log_file.is_some() && !options.daemon {
+            return Err("--log-file only applies with --daemon".to_string());
+        }
+        if options.self_test && (options.daemon || options.tui) {
+            return Err("--self-test runs on its own, in the foreground".to_string());
+        }
+        if options.tui && options.daemon {
+            return Err("--tui needs a terminal, which a daemon detaches from".to_string());
+        }
+        #[cfg(windows)]
+        if options.tui && options.service.is_some() {
+            return Err("--tui needs a terminal, which a service has none of".to_string());
+        }
+        Ok(options)
+    }
+}
+
+/// Where the admin API is served, printed as JSON once it's ready for scripts to wait
+/// on rather than sleeping
+#[derive(Debug, serde_derive::Serialize)]
+pub struct Banner {
+    pub url: String,
+    /// The port bound
+    pub port: u16,
+    pub pid: u32,
+}
+
+impl Banner {
+    pub fn new(scheme: &str, address: std::net::SocketAddr) -> Banner {
+        Banner { url: format!("{}://{}/", scheme, address), port: address.port(), pid: std::process::id() }
+    }
+}
+
+/// Tells whoever started the process once it's serving the admin API
+pub struct Readiness {
+    /// The end of a pipe the process that daemonized waits at
+    started_by: Option<File>,
+    ready_file: Option<PathBuf>,
+}
+
+impl Readiness {
+    /// Print the banner to stdout and write it to `--ready-file`, let the process that
+    /// daemonized this one exit, and tell systemd, if it's watching for `NOTIFY_SOCKET`, or
+    /// the service control manager, if it started the process, that the service is ready
+    pub fn ready(self, banner: &Banner) {
+        let json = serde_json::to_string(banner).unwrap_or_default();
+        println!("{}", json);
+        let _ = io::stdout().flush();
+        if let Some(ready_file) = &self.ready_file {
+            if let Err(e) = write_whole(ready_file, format!("{}\n", json).as_bytes()) {
+                eprintln!("failed to write {}: {}", ready_file.display(), e);
+            }
+        }
+        if let Some(mut started_by) = self.started_by {
+            let _ = started_by.write_all(b"1");
+        }
+        #[cfg(unix)]
+        if let Err(e) = sd_notify(&format!("READY=1\nMAINPID={}", std::process::id())) {
+            eprintln!("failed to notify systemd of readiness: {}", e);
+        }
+        #[cfg(windows)]
+        crate::service::running();
+    }
+}
+
+/// Daemonize with `--daemon` unless `upgraded`, as a process that's been upgraded into is
+/// daemonized already, and write the pid file. A daemon is detached by forking twice and
+/// starting a session, and keeps the working directory so relative paths in the
+/// configuration still hold. The command returns once the daemon is ready, failing if it
+/// isn't.
+pub fn start(options: &Options, upgraded: bool) -> Result<Readiness, String> {
+    if let Some(pid_file) = &options.pid_file {
+        check_not_running(pid_file)?;
+    }
+    // A ready file left by an earlier run would tell of a process that's gone
+    if let Some(ready_file) = &options.ready_file {
+        match std::fs::remove_file(ready_file) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                return Err(format!("failed to remove {}: {}", ready_file.display(), e));
+            }
+            _ => {}
+        }
+    }
+
+    let started_by = if options.daemon && !upgraded {
+        Some(daemonize(options).map_err(|e| format!("failed to daemonize: {}", e))?)
+    } else {
+        None
+    };
+
+    if let Some(pid_file) = &options.pid_file {
+        std::fs::write(pid_file, format!("{}\n", std::process::id()))
+            .map_err(|e| format!("failed to write {}: {}", pid_file.display(), e))?;
+    }
+    Ok(Readiness { started_by, ready_file: options.ready_file.clone() })
+}
+
+/// Write `contents` to `path` by way of a file beside it, so it's never seen half written
+fn write_whole(path: &PathBuf, contents: &[u8]) -> io::Result<()> {
+    let mut partial = path.clone().into_os_string();
+    partial.push(".partial");
+    std::fs::write(&partial, contents)?;
+    std::fs::rename(&partial, path)
+}
+
+/// Refuse to start while the process named by the pid file is still alive
+fn check_not_running(pid_file: &PathBuf) -> Result<(), String> {
+    let pid = match std::fs::read_to_string(pid_file) {
+        Ok(pid) => pid.trim().parse::<u32>().ok(),
+        Err(_) => return Ok(()),
+    };
+    match pid {
+        Some(pid) if pid > 0 && pid != std::process::id() && alive(pid) => {
+            Err(format!("already running as process {}, see {}", pid, pid_file.display()))
+        }
+        _ => Ok(()),
+    }
+}
+
+#[cfg(unix)]
+fn alive(pid: u32) -> bool {
+    use std::convert::TryFrom;
+
+    libc::pid_t::try_from(pid).is_ok_and(|pid| unsafe { libc::kill(pid, 0) } == 0)
+}
+
+#[cfg(windows)]
+fn alive(pid: u32) -> bool {
+    use windows_sys::Win32::Foundation::{CloseHandle, STILL_ACTIVE};
+    use windows_sys::Win32::System::Threading::{GetExitCodeProcess, OpenProcess, PROCESS_QUERY_LIMITED_INFORMATION};
+
+    // The handle is closed before returning, whatever the process turns out to be
+    unsafe {
+        let process = OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, 0, pid);
+        if process == 0 {
+            return false;
+        }
+        let mut code = 0;
+        let running = GetExitCodeProcess(process, &mut code) != 0 && code == STILL_ACTIVE as u32;
+        CloseHandle(process);
+        running
+    }
+}
+
+/// Fork into the background, returning the pipe to report readiness on. The foreground
+/// process waits on the pipe and exits.
+#[cfg(unix)]
+fn daemonize(options: &Options) -> io::Result<File> {
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // Neither end is inherited by subprocesses, nor across an upgrade
+    for fd in fds {
+        unsafe {
+            libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC);
+        }
+    }
+    // Both descriptors were just opened, and nothing else owns them
+    let (mut waiting, started_by) = unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+
+    match unsafe { libc::fork() } {
+        -1 => return Err(io::Error::last_os_error()),
+        0 => {}
+        _ => {
+            drop(started_by);
+            let mut ready = [0];
+            let code = match waiting.read(&mut ready) {
+                Ok(1) => 0,
+                _ => {
+                    eprintln!("the daemon exited before it was ready");
+                    1
+                }
+            };
+            unsafe { libc::_exit(code) }
+        }
+    }
+    drop(waiting);
+
+    if unsafe { libc::setsid() } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    // The session leader exits, so the daemon can never acquire a terminal again
+    match unsafe { libc::fork() } {
+        -1 => return Err(io::Error::last_os_error()),
+        0 => {}
+        _ => unsafe { libc::_exit(0) },
+    }
+
+    let null = File::open("/dev/null")?;
+    let output = match &options.log_file {
+        Some(log_file) => std::fs::OpenOptions::new().create(true).append(true).open(log_file)?,
+        None => std::fs::OpenOptions::new().write(true).open("/dev/null")?,
+    };
+    unsafe {
+        libc::dup2(null.as_raw_fd(), libc::STDIN_FILENO);
+        libc::dup2(output.as_raw_fd(), libc::STDOUT_FILENO);
+        libc::dup2(output.as_raw_fd(), libc::STDERR_FILENO);
+    }
+    Ok(started_by)
+}
+
+/// There's no forking on Windows, where the service control manager runs the process in
+/// the background, see `service::run`
+#[cfg(windows)]
+fn daemonize(_options: &Options) -> io::Result<File> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "only supported on Unix"))
+}
+
+/// Send `state` to the socket systemd named in `NOTIFY_SOCKET`, if it did, see
+/// `sd_notify(3)`
+#[cfg(unix)]
+fn sd_notify(state: &str) -> io::Result<()> {
+    let path = match std::env::var("NOTIFY_SOCKET") {
+        Ok(path) => path,
+        Err(_) => return Ok(()),
+    };
+    let socket = std::os::unix::net::UnixDatagram::unbound()?;
+    match path.strip_prefix('@') {
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt;
+            let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+            socket.send_to_addr(state.as_bytes(), &addr)?;
+        }
+        #[cfg(not(target_os = "linux"))]
+        Some(_) => return Err(io::Error::other("abstract sockets are only supported on Linux")),
+        None => {
+            socket.send_to(state.as_bytes(), &path)?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,84 @@
+use serde_json::Value;
+use warp::Reply;
+
+use crate::{error_reply, Database};
+use crate::error::lock;
+
+/// Query parameters of `GET /diff`
+#[derive(Debug, serde_derive::Deserialize)]
+pub struct DiffQuery {
+    a: u16,
+    b: u16,
+}
+
+/// A configuration value that differs between the two servers. A side missing the
+/// value altogether is left out.
+#[derive(Debug, serde_derive::Serialize)]
+struct Difference {
+    /// JSON pointer to the value
+    path: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    a: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    b: Option<Value>,
+}
+
+#[derive(Debug, serde_derive::Serialize)]
+struct DiffJsonBody {
+    a: u16,
+    b: u16,
+    differences: Vec<Difference>,
+}
+
+/// Compare the configurations of servers `a` and `b`, down to the individual values
+/// that differ. Their ports are left out, as those always do.
+pub fn diff_servers(
+    database: Database,
+    query: DiffQuery
+) -> warp::reply::Response {
+    let registry = lock(&database);
+
+    let config = |port: u16| {
+        registry.servers.get(&port)
+            .and_then(|server| serde_json::to_value(&server.config).ok())
+            .map(|mut config| {
+                if let Some(config) = config.as_object_mut() {
+                    config.remove("port");
+                }
+                config
+            })
+    };
+
+    let (a, b) = match (config(query.a), config(query.b)) {
+        (Some(a), Some(b)) => (a, b),
+        (None, _) | (_, None) => {
+            let missing = if registry.servers.contains_key(&query.a) { query.b } else { query.a };
+            let error = format!("no server on port {}", missing);
+            return error_reply(warp::http::StatusCode::NOT_FOUND, &error);
+        }
+    };
+
+    let mut differences = Vec::new();
+    compare(String::new(), Some(&a), Some(&b), &mut differences);
+
+    warp::reply::json(&DiffJsonBody { a: query.a, b: query.b, differences }).into_response()
+}
+
+/// Collect the differences between `a` and `b` at `path`, descending into objects.
+/// Arrays are compared as a whole.
+fn compare(path: String, a: Option<&Value>, b: Option<&Value>, differences: &mut Vec<Difference>) {
+    match (a, b) {
+        (Some(Value::Object(a)), Some(Value::Object(b))) => {
+            let mut keys: Vec<&String> = a.keys().chain(b.keys()).collect();
+            keys.sort();
+            keys.dedup();
+
+            for key in keys {
+                let pointer = format!("{}/{}", path, key.replace('~', "~0").replace('/', "~1"));
+                compare(pointer, a.get(key), b.get(key), differences);
+            }
+        }
+        (a, b) if a != b => differences.push(Difference { path, a: a.cloned(), b: b.cloned() }),
+        _ => {}
+    }
+}
@@ -0,0 +1,308 @@
+use futures::{Future, Stream};
+use futures::sync::mpsc;
+use tokio::timer::Interval;
+
+use std::collections::{BTreeMap, HashMap};
+use std::time::Duration;
+
+use crate::{ServerJsonBody, ServerStatus};
+
+/// How often Consul checks a server, and etcd leases are renewed
+const CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// An etcd lease lapses this long after it was last renewed
+const LEASE_TTL_SECS: u64 = 30;
+
+/// Where servers are registered as services
+#[derive(Clone, Debug, PartialEq)]
+pub enum Backend {
+    /// The HTTP API of a Consul agent, which checks the health of the services itself
+    Consul { url: String, token: Option<String> },
+    /// The JSON gateway of etcd, which keeps a key under `prefix` for as long as the
+    /// server is up to renew its lease
+    Etcd { url: String, prefix: String },
+}
+
+/// Services a server is registered as, see `Discovery`
+#[derive(Clone, Debug)]
+pub struct DiscoveryConfig {
+    backend: Backend,
+    /// The address services are found at
+    address: String,
+}
+
+impl DiscoveryConfig {
+    /// `SERVICE_REGISTRY` registers the servers with `consul:{url}`, authorized by
+    /// `CONSUL_HTTP_TOKEN` if set, or with `etcd:{url}` under the `ETCD_PREFIX` key prefix,
+    /// `/services/mock` by default. `SERVICE_ADDRESS` is the address services are found
+    /// at, `127.0.0.1` by default.
+    pub fn from_env() -> Result<Option<DiscoveryConfig>, String> {
+        let spec = match std::env::var("SERVICE_REGISTRY") {
+            Ok(spec) => spec,
+            Err(_) => return Ok(None),
+        };
+        let backend = match spec.split_once(':') {
+            Some(("consul", url)) => Backend::Consul {
+                url: url.trim_end_matches('/').to_string(),
+                token: std::env::var("CONSUL_HTTP_TOKEN").ok(),
+            },
+            Some(("etcd", url)) => Backend::Etcd {
+                url: url.trim_end_matches('/').to_string(),
+                prefix: std::env::var("ETCD_PREFIX").unwrap_or_else(|_| "/services/mock".to_string()),
+            },
+            _ => return Err(format!("invalid SERVICE_REGISTRY {:?}, expected consul:{{url}} or etcd:{{url}}", spec)),
+        };
+        let url = match &backend {
+            Backend::Consul { url, .. } | Backend::Etcd { url, .. } => url,
+        };
+        if url.parse::<hyper::Uri>().map_or(true, |uri| uri.scheme_part().map(|scheme| scheme.as_str()) != Some("http")) {
+            return Err(format!("invalid SERVICE_REGISTRY {:?}, expected an http URL", spec));
+        }
+        let address = std::env::var("SERVICE_ADDRESS").unwrap_or_else(|_| "127.0.0.1".to_string());
+        Ok(Some(DiscoveryConfig { backend, address }))
+    }
+}
+
+/// A server as registered
+#[derive(Clone, Debug, PartialEq)]
+struct Service {
+    port: u16,
+    name: String,
+    kind: &'static str,
+    labels: BTreeMap<String, String>,
+}
+
+impl Service {
+    fn id(&self) -> String {
+        format!("mock-{}", self.port)
+    }
+}
+
+/// What the worker is asked to do
+enum Command {
+    Register(Service),
+    Deregister(u16),
+    /// Whether the server on the port is up, keeping its etcd lease alive
+    Up(u16, bool),
+    Renew,
+}
+
+/// Registers servers as services as they're created, and deregisters them as they're
+/// deleted, keeping the requests to the registry in order on a worker of its own
+pub struct Discovery {
+    commands: mpsc::UnboundedSender<Command>,
+    registered: HashMap<u16, (Service, bool)>,
+}
+
+impl Discovery {
+    /// A `Discovery`, with the worker making its requests to be spawned on the runtime
+    pub fn new(config: DiscoveryConfig) -> (Discovery, impl Future<Item = (), Error = ()>) {
+        let (commands, received) = mpsc::unbounded();
+        let renew = Interval::new_interval(CHECK_INTERVAL)
+            .map(|_| Command::Renew)
+            .map_err(|e| eprintln!("service registry timer error: {}", e));
+        let worker = Worker { config, client: hyper::Client::new(), leases: HashMap::new() };
+        let work = received.select(renew)
+            .fold(worker, |worker, command| worker.run(command))
+            .map(|_| ());
+        (Discovery { commands, registered: HashMap::new() }, work)
+    }
+
+    /// Register `server` if it's new, its name or labels changed or it came back up
+    pub fn update(&mut self, server: &ServerJsonBody) {
+        let service = Service {
+            port: server.port,
+            name: server.name.clone().unwrap_or_else(|| "mock".to_string()),
+            kind: server.kind.name(),
+            labels: server.labels.clone(),
+        };
+        let up = matches!(server.status, ServerStatus::Starting | ServerStatus::Running | ServerStatus::Paused);
+        let previous = self.registered.insert(server.port, (service.clone(), up));
+        let came_up = up && previous.as_ref().is_some_and(|(_, was_up)| !was_up);
+        if came_up || previous.as_ref().is_none_or(|(previous, _)| *previous != service) {
+            self.send(Command::Register(service));
+        }
+        if previous.is_none_or(|(_, was_up)| was_up != up) {
+            self.send(Command::Up(server.port, up));
+        }
+    }
+
+    pub fn deregister(&mut self, port: u16) {
+        if self.registered.remove(&port).is_some() {
+            self.send(Command::Deregister(port));
+        }
+    }
+
+    fn send(&self, command: Command) {
+        if self.commands.unbounded_send(command).is_err() {
+            eprintln!("the service registry worker stopped");
+        }
+    }
+}
+
+/// An etcd lease of a server's key
+struct Lease {
+    id: Option<String>,
+    up: bool,
+}
+
+struct Worker {
+    config: DiscoveryConfig,
+    client: hyper::Client<hyper::client::HttpConnector>,
+    leases: HashMap<u16, Lease>,
+}
+
+type WorkerFuture = Box<dyn Future<Item = Worker, Error = ()> + Send>;
+type CallFuture = Box<dyn Future<Item = (Worker, Option<serde_json::Value>), Error = ()> + Send>;
+
+impl Worker {
+    /// Carry out `command`, logging what fails
+    fn run(mut self, command: Command) -> WorkerFuture {
+        let backend = self.config.backend.clone();
+        match (backend, command) {
+            (Backend::Consul { url, .. }, Command::Register(service)) => {
+                let mut registration = serde_json::json!({
+                    "ID": service.id(),
+                    "Name": service.name,
+                    "Address": self.config.address,
+                    "Port": service.port,
+                    "Tags": [service.kind],
+                    "Meta": service.labels,
+                });
+                if matches!(service.kind, "http" | "tcp") {
+                    registration["Check"] = serde_json::json!({
+                        "Name": format!("port {} accepts connections", service.port),
+                        "TCP": format!("{}:{}", self.config.address, service.port),
+                        "Interval": format!("{}s", CHECK_INTERVAL.as_secs()),
+                    });
+                }
+                let uri = format!("{}/v1/agent/service/register", url);
+                self.request("PUT", uri, Some(registration), service.port)
+            }
+            (Backend::Consul { url, .. }, Command::Deregister(port)) => {
+                let uri = format!("{}/v1/agent/service/deregister/mock-{}", url, port);
+                self.request("PUT", uri, None, port)
+            }
+            (Backend::Consul { .. }, Command::Up(..)) | (Backend::Consul { .. }, Command::Renew) => {
+                Box::new(futures::future::ok(self))
+            }
+            (Backend::Etcd { url, prefix }, Command::Register(service)) => {
+                let value = serde_json::json!({
+                    "name": service.name,
+                    "address": self.config.address,
+                    "port": service.port,
+                    "kind": service.kind,
+                    "labels": service.labels,
+                });
+                let key = format!("{}/{}", prefix, service.id());
+                let port = service.port;
+                let lease = self.leases.remove(&port);
+                let up = lease.as_ref().is_some_and(|lease| lease.up);
+                // A lease not renewed while the server was down may have lapsed
+                let grant: CallFuture = match lease.filter(|lease| lease.up).and_then(|lease| lease.id) {
+                    Some(id) => Box::new(futures::future::ok((self, Some(serde_json::json!({ "ID": id }))))),
+                    None => {
+                        let ttl = serde_json::json!({ "TTL": LEASE_TTL_SECS.to_string() });
+                        self.call("POST", format!("{}/v3/lease/grant", url), Some(ttl), port)
+                    }
+                };
+                Box::new(grant.and_then(move |(mut worker, granted)| {
+                    let id = granted.and_then(|granted| Some(granted.get("ID")?.as_str()?.to_string()));
+                    worker.leases.insert(port, Lease { id: id.clone(), up });
+                    let put = serde_json::json!({
+                        "key": base64(key.as_bytes()),
+                        "value": base64(value.to_string().as_bytes()),
+                        "lease": id,
+                    });
+                    worker.request("POST", format!("{}/v3/kv/put", url), Some(put), port)
+                }))
+            }
+            (Backend::Etcd { url, .. }, Command::Deregister(port)) => {
+                match self.leases.remove(&port).and_then(|lease| lease.id) {
+                    Some(id) => {
+                        let revoke = serde_json::json!({ "ID": id });
+                        self.request("POST", format!("{}/v3/lease/revoke", url), Some(revoke), port)
+                    }
+                    None => Box::new(futures::future::ok(self)),
+                }
+            }
+            (Backend::Etcd { .. }, Command::Up(port, up)) => {
+                self.leases.entry(port).or_insert(Lease { id: None, up }).up = up;
+                Box::new(futures::future::ok(self))
+            }
+            (Backend::Etcd { url, .. }, Command::Renew) => {
+                let renewals: Vec<(u16, String)> = self.leases.iter()
+                    .filter(|(_, lease)| lease.up)
+                    .filter_map(|(port, lease)| Some((*port, lease.id.clone()?)))
+                    .collect();
+                Box::new(futures::stream::iter_ok(renewals).fold(self, move |worker, (port, id)| {
+                    let keepalive = serde_json::json!({ "ID": id });
+                    worker.request("POST", format!("{}/v3/lease/keepalive", url), Some(keepalive), port)
+                }))
+            }
+        }
+    }
+
+    /// Make a request on behalf of the server on `port`, logging whether it fails
+    fn request(self, method: &str, uri: String, body: Option<serde_json::Value>, port: u16) -> WorkerFuture {
+        Box::new(self.call(method, uri, body, port).map(|(worker, _)| worker))
+    }
+
+    /// Make a request on behalf of the server on `port`, with the JSON it's answered with
+    /// unless it fails
+    fn call(
+        self,
+        method: &str,
+        uri: String,
+        body: Option<serde_json::Value>,
+        port: u16
+    ) -> CallFuture {
+        let mut request = hyper::Request::builder();
+        request.method(method).uri(uri.as_str()).header(hyper::header::CONTENT_TYPE, "application/json");
+        if let Backend::Consul { token: Some(token), .. } = &self.config.backend {
+            request.header("x-consul-token", token.as_str());
+        }
+        let request = request.body(hyper::Body::from(body.map_or_else(String::new, |body| body.to_string())));
+
+        let response = futures::future::result(request)
+            .map_err(|e| e.to_string())
+            .and_then({
+                let client = self.client.clone();
+                move |request| client.request(request).map_err(|e| e.to_string())
+            })
+            .and_then(|response| {
+                let status = response.status();
+                response.into_body().concat2().map_err(|e| e.to_string()).and_then(move |body| {
+                    if status.is_success() {
+                        Ok(serde_json::from_slice(&body).ok())
+                    } else {
+                        Err(format!("answered {}: {}", status, String::from_utf8_lossy(&body)))
+                    }
+                })
+            });
+        Box::new(response.then(move |response| {
+            let answer = response.unwrap_or_else(|e| {
+                eprintln!("service registry request {} for server {} failed: {}", uri, port, e);
+                None
+            });
+            Ok((self, answer))
+        }))
+    }
+}
+
+/// Standard base64 with padding, as the etcd gateway takes keys and values in
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let group = chunk.iter().enumerate().fold(0u32, |group, (i, byte)| group | u32::from(*byte) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[(group >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
@@ -0,0 +1,286 @@
+use futures::{Future, Stream};
+use futures::sync::oneshot;
+use tokio::codec::BytesCodec;
+use tokio::net::{UdpFramed, UdpSocket};
+
+use std::collections::BTreeMap;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+/// Records served by a DNS server, keyed by domain name
+pub type Zone = BTreeMap<String, Vec<Record>>;
+
+/// A resource record in a `Zone`
+#[derive(Clone, Debug, PartialEq, serde_derive::Deserialize, serde_derive::Serialize)]
+#[serde(tag = "type", rename_all = "UPPERCASE")]
+pub enum Record {
+    A { address: Ipv4Addr },
+    Aaaa { address: Ipv6Addr },
+    Cname { target: String },
+    Srv { priority: u16, weight: u16, port: u16, target: String },
+}
+
+const TYPE_A: u16 = 1;
+const TYPE_CNAME: u16 = 5;
+const TYPE_AAAA: u16 = 28;
+const TYPE_SRV: u16 = 33;
+const TYPE_ANY: u16 = 255;
+
+const CLASS_IN: u16 = 1;
+const TTL: u32 = 60;
+
+// How many CNAMEs to follow before giving up on a (possibly cyclic) chain
+const MAX_CNAME_CHAIN: usize = 8;
+
+/// How long `resolve` waits for the answer to its query
+const QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Ids of the queries `resolve` makes, told apart from those of other queries
+static QUERY_ID: AtomicUsize = AtomicUsize::new(1);
+
+impl Record {
+    fn type_code(&self) -> u16 {
+        match self {
+            Record::A { .. } => TYPE_A,
+            Record::Aaaa { .. } => TYPE_AAAA,
+            Record::Cname { .. } => TYPE_CNAME,
+            Record::Srv { .. } => TYPE_SRV,
+        }
+    }
+
+    fn write_rdata(&self, out: &mut Vec<u8>) {
+        match self {
+            Record::A { address } => out.extend_from_slice(&address.octets()),
+            Record::Aaaa { address } => out.extend_from_slice(&address.octets()),
+            Record::Cname { target } => write_name(out, target),
+            Record::Srv { priority, weight, port, target } => {
+                out.extend_from_slice(&priority.to_be_bytes());
+                out.extend_from_slice(&weight.to_be_bytes());
+                out.extend_from_slice(&port.to_be_bytes());
+                write_name(out, target);
+            }
+        }
+    }
+}
+
+/// Bind a DNS server on `port`, answering queries from `zone` until `shutdown` fires.
+/// Queries received while `paused` go unanswered.
+pub fn create_dns_server(
+    zone: Zone,
+    port: u16,
+    paused: Arc<AtomicBool>,
+    shutdown: oneshot::Receiver<()>
+) -> std::io::Result<impl Future<Item = (), Error = ()>> {
+    let socket = UdpSocket::bind(&([127, 0, 0, 1], port).into())?;
+    let (sink, stream) = UdpFramed::new(socket, BytesCodec::new()).split();
+
+    let replies = stream.filter_map(move |(query, addr)| {
+        if paused.load(Ordering::SeqCst) {
+            return None;
+        }
+        answer(&zone, &query).map(|reply| (reply.into(), addr))
+    });
+
+    let server = replies.forward(sink)
+        .map_err(|e| eprintln!("dns server error: {}", e));
+
+    Ok(server.select2(shutdown).then(|_| Ok(())))
+}
+
+/// Build the response to a single-question DNS query. Names missing from the
+/// zone get NXDOMAIN; packets that aren't well-formed queries get no answer.
+fn answer(zone: &Zone, query: &[u8]) -> Option<Vec<u8>> {
+    let header = query.get(..12)?;
+    let is_response = header[2] & 0x80 != 0;
+    let question_count = u16::from_be_bytes([header[4], header[5]]);
+    if is_response || question_count != 1 {
+        return None;
+    }
+
+    let (name, end) = read_name(query, 12)?;
+    let question = query.get(12..end + 4)?;
+    let query_type = u16::from_be_bytes([query[end], query[end + 1]]);
+
+    let known = find(zone, &name).is_some();
+    let answers = lookup(zone, &name, query_type);
+
+    let mut response = Vec::with_capacity(512);
+    response.extend_from_slice(&header[..2]);
+    // QR and AA set, opcode and RD copied from the query
+    response.push(0x84 | (header[2] & 0x79));
+    response.push(if known { 0 } else { 3 });
+    response.extend_from_slice(&1u16.to_be_bytes());
+    response.extend_from_slice(&(answers.len() as u16).to_be_bytes());
+    response.extend_from_slice(&[0, 0, 0, 0]);
+    response.extend_from_slice(question);
+
+    for (owner, record) in answers {
+        write_name(&mut response, &owner);
+        response.extend_from_slice(&record.type_code().to_be_bytes());
+        response.extend_from_slice(&CLASS_IN.to_be_bytes());
+        response.extend_from_slice(&TTL.to_be_bytes());
+
+        let mut rdata = Vec::new();
+        record.write_rdata(&mut rdata);
+        response.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+        response.extend_from_slice(&rdata);
+    }
+
+    Some(response)
+}
+
+/// Records answering `query_type` for `name`, following CNAMEs like a resolver would
+fn lookup<'a>(zone: &'a Zone, name: &str, query_type: u16) -> Vec<(String, &'a Record)> {
+    let mut answers = Vec::new();
+    let mut name = name.to_string();
+
+    for _ in 0..MAX_CNAME_CHAIN {
+        let records = match find(zone, &name) {
+            Some(records) => records,
+            None => break,
+        };
+
+        let direct: Vec<&Record> = records.iter()
+            .filter(|record| query_type == TYPE_ANY || record.type_code() == query_type)
+            .collect();
+
+        if !direct.is_empty() {
+            answers.extend(direct.into_iter().map(|record| (name.clone(), record)));
+            break;
+        }
+
+        match records.iter().find(|record| record.type_code() == TYPE_CNAME) {
+            Some(record @ Record::Cname { target }) => {
+                answers.push((name.clone(), record));
+                name = normalize(target);
+            }
+            _ => break,
+        }
+    }
+
+    answers
+}
+
+fn find<'a>(zone: &'a Zone, name: &str) -> Option<&'a Vec<Record>> {
+    zone.iter()
+        .find(|(zone_name, _)| normalize(zone_name) == name)
+        .map(|(_, records)| records)
+}
+
+/// Lowercase a domain name and strip its trailing dot
+fn normalize(name: &str) -> String {
+    name.trim_end_matches('.').to_lowercase()
+}
+
+/// Read an uncompressed domain name starting at `pos`, returning the
+/// normalized name and the position just past it
+fn read_name(packet: &[u8], mut pos: usize) -> Option<(String, usize)> {
+    let mut labels = Vec::new();
+
+    loop {
+        let len = *packet.get(pos)? as usize;
+        pos += 1;
+        if len == 0 {
+            break;
+        }
+        if len > 63 {
+            return None;
+        }
+        labels.push(String::from_utf8_lossy(packet.get(pos..pos + len)?).to_lowercase());
+        pos += len;
+    }
+
+    Some((labels.join("."), pos))
+}
+
+fn write_name(out: &mut Vec<u8>, name: &str) {
+    for label in name.split('.').filter(|label| !label.is_empty()) {
+        let label = &label.as_bytes()[..label.len().min(63)];
+        out.push(label.len() as u8);
+        out.extend_from_slice(label);
+    }
+    out.push(0);
+}
+
+/// The addresses of the A and AAAA records the DNS server at `server` answers a query
+/// for `name`'s A records with, asked over UDP, like a stub resolver would
+pub fn resolve(server: SocketAddr, name: &str) -> impl Future<Item = Vec<IpAddr>, Error = io::Error> {
+    let id = QUERY_ID.fetch_add(1, Ordering::SeqCst) as u16;
+    let mut query = Vec::with_capacity(512);
+    query.extend_from_slice(&id.to_be_bytes());
+    // RD set, one question
+    query.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+    write_name(&mut query, name);
+    query.extend_from_slice(&TYPE_A.to_be_bytes());
+    query.extend_from_slice(&CLASS_IN.to_be_bytes());
+
+    let local: SocketAddr = match server {
+        SocketAddr::V4(_) => ([0, 0, 0, 0], 0).into(),
+        SocketAddr::V6(_) => ([0u16; 8], 0).into(),
+    };
+    let name = name.to_string();
+    let answered = futures::future::result(UdpSocket::bind(&local))
+        .and_then(move |socket| socket.send_dgram(query, &server))
+        .and_then(|(socket, _)| socket.recv_dgram(vec![0; 512]))
+        .and_then(move |(_, response, length, _)| addresses(id, &response[..length])
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "invalid DNS response"))?
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("no such host {}", name))));
+    tokio::timer::Timeout::new_at(answered, Instant::now() + QUERY_TIMEOUT).map_err(|e| {
+        e.into_inner().unwrap_or_else(|| io::Error::new(io::ErrorKind::TimedOut, "DNS query timed out"))
+    })
+}
+
+/// The addresses answered by `response` to the query `id`, `None` within if the name
+/// doesn't exist, or `None` if it isn't a response to the query
+fn addresses(id: u16, response: &[u8]) -> Option<Option<Vec<IpAddr>>> {
+    let header = response.get(..12)?;
+    if u16::from_be_bytes([header[0], header[1]]) != id || header[2] & 0x80 == 0 {
+        return None;
+    }
+    if header[3] & 0x0f == 3 {
+        return Some(None);
+    }
+    let question_count = u16::from_be_bytes([header[4], header[5]]);
+    let answer_count = u16::from_be_bytes([header[6], header[7]]);
+
+    let mut pos = 12;
+    for _ in 0..question_count {
+        pos = skip_name(response, pos)? + 4;
+    }
+    let mut addresses = Vec::new();
+    for _ in 0..answer_count {
+        pos = skip_name(response, pos)?;
+        let fixed = response.get(pos..pos + 10)?;
+        let record_type = u16::from_be_bytes([fixed[0], fixed[1]]);
+        let length = u16::from_be_bytes([fixed[8], fixed[9]]) as usize;
+        let rdata = response.get(pos + 10..pos + 10 + length)?;
+        match (record_type, rdata.len()) {
+            (TYPE_A, 4) => addresses.push(IpAddr::from([rdata[0], rdata[1], rdata[2], rdata[3]])),
+            (TYPE_AAAA, 16) => {
+                let mut octets = [0; 16];
+                octets.copy_from_slice(rdata);
+                addresses.push(IpAddr::from(octets));
+            }
+            _ => {}
+        }
+        pos += 10 + length;
+    }
+    Some(Some(addresses))
+}
+
+/// The position just past the possibly compressed domain name at `pos`
+fn skip_name(packet: &[u8], mut pos: usize) -> Option<usize> {
+    loop {
+        let len = *packet.get(pos)? as usize;
+        match len {
+            0 => return Some(pos + 1),
+            // A pointer ends the name
+            _ if len & 0xc0 == 0xc0 => return Some(pos + 2),
+            _ if len > 63 => return None,
+            _ => pos += 1 + len,
+        }
+    }
+}
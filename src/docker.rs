@@ -0,0 +1,85 @@
+use futures::Future;
+use futures::future::Either;
+use futures::sync::oneshot;
+use tokio_process::CommandExt;
+
+use std::process::Command;
+
+use crate::{Database, HttpState, ServerBackend, ServerFuture, ServerJsonBody, ServerKind};
+
+/// A container run with the Docker CLI, with its `container_port` published on the
+/// server's port
+#[derive(Clone, Debug, PartialEq, serde_derive::Deserialize, serde_derive::Serialize)]
+pub struct Container {
+    pub image: String,
+    pub container_port: u16,
+    /// Arguments passed to the container's entrypoint
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub args: Vec<String>,
+}
+
+/// Runs `ServerKind::Docker` servers as containers, see `create_docker_server`. They
+/// bind their port themselves, so they neither take listeners nor count what they serve.
+pub struct Docker;
+
+impl ServerBackend for Docker {
+    fn create(
+        &self,
+        _database: Database,
+        body: &ServerJsonBody,
+        _listeners: Vec<std::net::TcpListener>,
+        _state: HttpState,
+        shutdown: oneshot::Receiver<()>
+    ) -> std::io::Result<ServerFuture> {
+        match body.kind {
+            ServerKind::Docker(ref container) => Ok(Box::new(create_docker_server(container, body.port, shutdown)?)),
+            _ => {
+                let error = "only containers are run by docker::Docker";
+                Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, error))
+            }
+        }
+    }
+
+    fn in_process(&self) -> bool {
+        false
+    }
+}
+
+/// Run `container` with `docker run`, publishing it on `port`. The returned future ends
+/// when the container exits. When `shutdown` fires the container is stopped with
+/// `docker stop`, as killing the CLI would leave it running.
+pub fn create_docker_server(
+    container: &Container,
+    port: u16,
+    shutdown: oneshot::Receiver<()>
+) -> std::io::Result<impl Future<Item = (), Error = ()>> {
+    let name = format!("warp-self-replicating-{}", port);
+    let run = Command::new("docker")
+        .args(["run", "--rm", "--name", &name])
+        .arg("--publish")
+        .arg(format!("127.0.0.1:{}:{}", port, container.container_port))
+        .arg(&container.image)
+        .args(&container.args)
+        .spawn_async()?;
+
+    Ok(run.select2(shutdown).then(move |result| -> Box<dyn Future<Item = (), Error = ()> + Send> {
+        match result {
+            Ok(Either::A((status, _))) => {
+                eprintln!("container {} exited with {}", name, status);
+                Box::new(futures::future::ok(()))
+            }
+            Err(Either::A((e, _))) => {
+                eprintln!("container {} error: {}", name, e);
+                Box::new(futures::future::ok(()))
+            }
+            Ok(Either::B((_, run))) | Err(Either::B((_, run))) => {
+                let stopped = Command::new("docker")
+                    .args(["stop", &name])
+                    .output_async()
+                    .map(|_| ())
+                    .map_err(|e| eprintln!("docker stop error: {}", e));
+                Box::new(stopped.and_then(|()| run.map(|_| ()).map_err(|e| eprintln!("docker run error: {}", e))))
+            }
+        }
+    }))
+}
@@ -0,0 +1,325 @@
+use futures::{Future, Stream};
+use warp::Filter;
+use warp::http::header::{self, HeaderMap, HeaderValue};
+use warp::http::{Method, StatusCode};
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+use crate::clock;
+
+/// Bytes sent at once, read from a file or made up
+const CHUNK_BYTES: u64 = 64 * 1024;
+
+/// How long a download cut short waits for what was sent to be flushed before closing
+const CUT_DELAY: Duration = Duration::from_millis(100);
+
+fn invalid(error: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, error)
+}
+
+/// A large body an HTTP server serves at a path, to `GET` and `HEAD`, whole or in the
+/// byte range of a `Range` header, as `206 Partial Content`, for clients to resume
+/// downloads with. A range is only honored if `If-Range`, when given, has the body's
+/// `ETag` or `Last-Modified`. Requests for several ranges at once get the whole body.
+/// Tried after the header routes and before the operations of the OpenAPI document.
+#[derive(Clone, Debug, PartialEq, serde_derive::Deserialize, serde_derive::Serialize)]
+pub struct Download {
+    /// A file to serve, read as it's sent
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file: Option<PathBuf>,
+    /// Bytes to make up in place of a file, the same for every request
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size_bytes: Option<u64>,
+    #[serde(default = "default_content_type")]
+    pub content_type: String,
+    /// Close the connection once this many bytes of a body have been sent, to have
+    /// clients resume from where they got to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cut_after_bytes: Option<u64>,
+    /// Get ranges wrong, like servers clients have to cope with
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub range_fault: Option<RangeFault>,
+}
+
+fn default_content_type() -> String {
+    "application/octet-stream".to_string()
+}
+
+/// A way of getting ranges wrong
+#[derive(Clone, Copy, Debug, PartialEq, serde_derive::Deserialize, serde_derive::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RangeFault {
+    /// Answer every range with `200` and the whole body, as servers without range support
+    /// do, though they're advertised with `Accept-Ranges`
+    Ignore,
+    /// Send the bytes starting one after those asked for, under the `Content-Range` of
+    /// those asked for
+    OffByOne,
+    /// Send half of the bytes of the range, and close the connection
+    Truncated,
+    /// Leave out `Content-Range`
+    NoContentRange,
+    /// Answer every range with `416 Range Not Satisfiable`
+    Unsatisfiable,
+}
+
+/// Where the bytes of a download come from
+#[derive(Debug)]
+enum Source {
+    File {
+        path: PathBuf,
+        size: u64,
+        modified_ms: u64,
+    },
+    Generated {
+        size: u64,
+    },
+}
+
+impl Source {
+    fn size(&self) -> u64 {
+        match self {
+            Source::File { size, .. } | Source::Generated { size } => *size,
+        }
+    }
+
+    /// Tells the bytes apart, for `ETag` and `If-Range`
+    fn etag(&self) -> String {
+        match self {
+            Source::File { size, modified_ms, .. } => format!("\"{:x}-{:x}\"", size, modified_ms),
+            Source::Generated { size } => format!("\"generated-{:x}\"", size),
+        }
+    }
+
+    fn modified_ms(&self) -> Option<u64> {
+        match self {
+            Source::File { modified_ms, .. } => Some(*modified_ms),
+            Source::Generated { .. } => None,
+        }
+    }
+
+    /// The `length` bytes from `start`, as they're sent, failing after `cut_after` of
+    /// them
+    fn stream(
+        &self,
+        start: u64,
+        length: u64,
+        cut_after: Option<u64>
+    ) -> io::Result<Box<dyn Stream<Item = Vec<u8>, Error = io::Error> + Send>> {
+        let mut file = match self {
+            Source::File { path, .. } => {
+                let mut file = File::open(path)?;
+                file.seek(SeekFrom::Start(start))?;
+                Some(file)
+            }
+            Source::Generated { .. } => None,
+        };
+        let end = start + length.min(cut_after.unwrap_or(u64::MAX));
+        let chunks = futures::stream::unfold(start, move |offset| {
+            if offset >= end {
+                return None;
+            }
+            let next = (offset + CHUNK_BYTES).min(end);
+            let chunk = match &mut file {
+                Some(file) => {
+                    let mut chunk = vec![0; (next - offset) as usize];
+                    file.read_exact(&mut chunk).map(|()| chunk)
+                }
+                None => Ok((offset..next).map(generated_byte).collect()),
+            };
+            Some(chunk.map(|chunk| (chunk, next)))
+        });
+        if cut_after.is_none_or(|cut_after| cut_after >= length) {
+            return Ok(Box::new(chunks));
+        }
+        // Failing the body closes the connection, once what was sent is flushed
+        let cut = tokio::timer::Delay::new(Instant::now() + CUT_DELAY)
+            .then(|_| Err(io::Error::new(io::ErrorKind::ConnectionAborted, "cut the download short")));
+        Ok(Box::new(chunks.chain(cut.into_stream())))
+    }
+}
+
+/// The byte at `offset` of a made-up body, to tell from the bytes around it, so that a
+/// body stitched together from the wrong ranges differs from the whole
+fn generated_byte(offset: u64) -> u8 {
+    (offset ^ (offset >> 8) ^ (offset >> 16) ^ (offset >> 24)).wrapping_mul(131) as u8
+}
+
+impl Download {
+    fn source(&self, path: &str) -> io::Result<Source> {
+        match (&self.file, self.size_bytes) {
+            (Some(file), None) => {
+                let metadata = std::fs::metadata(file)
+                    .map_err(|e| invalid(format!("can't serve {} at {}: {}", file.display(), path, e)))?;
+                let modified_ms = metadata.modified().ok()
+                    .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+                    .map_or(0, |modified| modified.as_millis() as u64);
+                Ok(Source::File { path: file.clone(), size: metadata.len(), modified_ms })
+            }
+            (None, Some(size)) => Ok(Source::Generated { size }),
+            _ => Err(invalid(format!("the download at {} needs either a file or size_bytes", path))),
+        }
+    }
+}
+
+pub fn check(downloads: &BTreeMap<String, Download>) -> io::Result<()> {
+    for (path, download) in downloads {
+        if !path.starts_with('/') {
+            return Err(invalid(format!("download path {:?} doesn't start with /", path)));
+        }
+        HeaderValue::from_str(&download.content_type)
+            .map_err(|_| invalid(format!("invalid content type {:?} of the download at {}", download.content_type, path)))?;
+        download.source(path)?;
+    }
+    Ok(())
+}
+
+/// The range of `Range`, as the first byte and the number of bytes, if it's a single
+/// range of bytes, or `Err` if it's one that's out of the body
+fn range(range: &str, size: u64) -> Option<Result<(u64, u64), ()>> {
+    let range = range.trim().strip_prefix("bytes=")?;
+    if range.contains(',') {
+        return None;
+    }
+    let (first, last) = range.split_once('-')?;
+    let (first, last) = (first.trim(), last.trim());
+    let (start, end) = match (first.parse::<u64>(), last.parse::<u64>()) {
+        (Ok(first), Ok(last)) if first <= last => (first, last.min(size.saturating_sub(1))),
+        (Ok(first), Err(_)) if last.is_empty() => (first, size.saturating_sub(1)),
+        (Err(_), Ok(suffix)) if first.is_empty() && suffix > 0 => (size.saturating_sub(suffix), size.saturating_sub(1)),
+        _ => return None,
+    };
+    if start >= size {
+        return Some(Err(()));
+    }
+    Some(Ok((start, end - start + 1)))
+}
+
+/// Whether `If-Range` has the validator of `source`
+fn if_range_holds(if_range: &str, source: &Source) -> bool {
+    match clock::parse_http_date(if_range) {
+        Some(since_ms) => source.modified_ms().is_some_and(|modified_ms| modified_ms / 1000 == since_ms / 1000),
+        None => !if_range.starts_with("W/") && if_range == source.etag(),
+    }
+}
+
+/// The file at `path` as a body read as it's sent, rather than held in memory, with its
+/// size
+pub fn file_body(path: &Path) -> io::Result<(u64, hyper::Body)> {
+    let size = std::fs::metadata(path)?.len();
+    let source = Source::File { path: path.to_path_buf(), size, modified_ms: 0 };
+    Ok((size, hyper::Body::wrap_stream(source.stream(0, size, None)?)))
+}
+
+/// The content type of the file at `path`, told by its extension
+pub fn content_type_of(path: &Path) -> &'static str {
+    let extension = path.extension().and_then(|extension| extension.to_str()).unwrap_or_default();
+    match extension.to_ascii_lowercase().as_str() {
+        "json" => "application/json",
+        "html" | "htm" => "text/html; charset=utf-8",
+        "txt" | "log" => "text/plain; charset=utf-8",
+        "csv" => "text/csv; charset=utf-8",
+        "xml" => "application/xml",
+        "js" | "mjs" => "text/javascript",
+        "css" => "text/css",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "pdf" => "application/pdf",
+        "zip" => "application/zip",
+        "gz" | "tgz" => "application/gzip",
+        "tar" => "application/x-tar",
+        "wasm" => "application/wasm",
+        "mp4" => "video/mp4",
+        "mp3" => "audio/mpeg",
+        _ => "application/octet-stream",
+    }
+}
+
+fn header_value(value: impl ToString) -> HeaderValue {
+    HeaderValue::from_str(&value.to_string()).expect("made of valid characters")
+}
+
+fn serve(download: &Download, path: &str, method: &Method, headers: &HeaderMap) -> warp::reply::Response {
+    // Checked when the server started, but the file may have changed since
+    let source = match download.source(path) {
+        Ok(source) => source,
+        Err(e) => return crate::error_reply(StatusCode::NOT_FOUND, &e.to_string()),
+    };
+    let size = source.size();
+    let header = |name| headers.get(name).and_then(|value: &HeaderValue| value.to_str().ok());
+    let requested = header(header::RANGE)
+        .filter(|_| header(header::IF_RANGE).is_none_or(|if_range| if_range_holds(if_range, &source)))
+        .and_then(|requested| range(requested, size))
+        .filter(|_| download.range_fault != Some(RangeFault::Ignore));
+
+    let mut response = warp::http::Response::new(hyper::Body::empty());
+    let response_headers = response.headers_mut();
+    response_headers.insert(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+    response_headers.insert(header::ETAG, header_value(source.etag()));
+    if let Some(modified_ms) = source.modified_ms() {
+        response_headers.insert(header::LAST_MODIFIED, header_value(clock::http_date(modified_ms)));
+    }
+    let (status, start, length, cut_after) = match requested {
+        Some(Err(())) => {
+            response_headers.insert(header::CONTENT_RANGE, header_value(format!("bytes */{}", size)));
+            *response.status_mut() = StatusCode::RANGE_NOT_SATISFIABLE;
+            return response;
+        }
+        Some(Ok(_)) if download.range_fault == Some(RangeFault::Unsatisfiable) => {
+            response_headers.insert(header::CONTENT_RANGE, header_value(format!("bytes */{}", size)));
+            *response.status_mut() = StatusCode::RANGE_NOT_SATISFIABLE;
+            return response;
+        }
+        Some(Ok((start, length))) => {
+            if download.range_fault != Some(RangeFault::NoContentRange) {
+                let content_range = format!("bytes {}-{}/{}", start, start + length - 1, size);
+                response_headers.insert(header::CONTENT_RANGE, header_value(content_range));
+            }
+            match download.range_fault {
+                Some(RangeFault::OffByOne) => (StatusCode::PARTIAL_CONTENT, start + 1, length.min(size - start - 1), download.cut_after_bytes),
+                Some(RangeFault::Truncated) => (StatusCode::PARTIAL_CONTENT, start, length, Some(length / 2)),
+                _ => (StatusCode::PARTIAL_CONTENT, start, length, download.cut_after_bytes),
+            }
+        }
+        None => (StatusCode::OK, 0, size, download.cut_after_bytes),
+    };
+    response_headers.insert(header::CONTENT_TYPE, header_value(&download.content_type));
+    response_headers.insert(header::CONTENT_LENGTH, header_value(length));
+    *response.status_mut() = status;
+    if method == Method::HEAD {
+        return response;
+    }
+    match source.stream(start, length, cut_after) {
+        Ok(chunks) => {
+            *response.body_mut() = hyper::Body::wrap_stream(chunks);
+            response
+        }
+        Err(e) => crate::error_reply(StatusCode::INTERNAL_SERVER_ERROR, &format!("failed to read the download at {}: {}", path, e)),
+    }
+}
+
+/// Serve the downloads of a server at their paths, checked to be valid, leaving other
+/// requests to the routes after it
+pub fn filter(
+    downloads: &BTreeMap<String, Download>
+) -> io::Result<impl Filter<Extract = (warp::reply::Response,), Error = warp::Rejection> + Clone> {
+    check(downloads)?;
+    let downloads = Arc::new(downloads.clone());
+    Ok(warp::method()
+        .and(warp::path::full())
+        .and(warp::header::headers_cloned())
+        .and_then(move |method: Method, path: warp::path::FullPath, headers: HeaderMap| {
+            let download = downloads.get(path.as_str())
+                .filter(|_| method == Method::GET || method == Method::HEAD)
+                .ok_or_else(warp::reject::not_found)?;
+            Ok::<_, warp::Rejection>(serve(download, path.as_str(), &method, &headers))
+        }))
+}
@@ -0,0 +1,85 @@
+use warp::Reply;
+use warp::http::StatusCode;
+
+use std::collections::BTreeMap;
+
+use crate::{check_config, error_reply, port_taken, start_server, ApplyFailure, Database, ServerJsonBody, ServerStatus};
+use crate::error::lock;
+
+/// Query of `POST /duplicate`
+#[derive(Debug, serde_derive::Deserialize)]
+pub struct DuplicateQuery {
+    /// Added to the port of each server for that of its duplicate
+    offset: u16,
+}
+
+/// What `POST /duplicate` started
+#[derive(Debug, serde_derive::Serialize)]
+struct Duplicated {
+    /// The port of each server duplicated, and that of its duplicate
+    created: BTreeMap<u16, u16>,
+    failed: Vec<ApplyFailure>,
+}
+
+/// `config` of a duplicate on `port`, its header routes, virtual hosts and SNI routes
+/// forwarding to the duplicates of servers that are duplicated along with it
+fn duplicate_config(config: &ServerJsonBody, port: u16, ports: &BTreeMap<u16, u16>) -> ServerJsonBody {
+    let moved = |port: &mut u16| if let Some(target) = ports.get(port) {
+        *port = *target;
+    };
+    let mut config = ServerJsonBody { port, ..config.clone() };
+    config.header_routes.iter_mut().for_each(|route| moved(&mut route.port));
+    config.virtual_hosts.values_mut().for_each(|host| moved(&mut host.port));
+    if let Some(tls) = &mut config.tls {
+        tls.sni.values_mut().filter_map(|route| route.port.as_mut()).for_each(moved);
+    }
+    config
+}
+
+/// `POST /duplicate?offset={offset}`: start a duplicate of every server that's up on its
+/// port plus `offset`, with the same config, for a second environment alongside the first.
+/// Routes of a server to another are taken to the other's duplicate. The admin API on
+/// `own_port` and the root server aren't duplicated. Nothing is started unless every
+/// duplicate's port is free and its config checks out.
+pub fn duplicate(database: Database, own_port: u16, query: DuplicateQuery) -> warp::reply::Response {
+    if query.offset == 0 {
+        return error_reply(StatusCode::BAD_REQUEST, "the offset can't be 0");
+    }
+    let mut registry = lock(&database);
+
+    let up = |status| matches!(status, ServerStatus::Starting | ServerStatus::Running | ServerStatus::Paused);
+    let mut sources: Vec<&ServerJsonBody> = registry.servers.values()
+        .filter(|server| up(server.status) && server.config.port != own_port && Some(server.config.port) != registry.root_port)
+        .map(|server| &server.config)
+        .collect();
+    sources.sort_by_key(|config| config.port);
+    let mut ports = BTreeMap::new();
+    for config in &sources {
+        match config.port.checked_add(query.offset) {
+            Some(port) if registry.servers.contains_key(&port) => return port_taken(port),
+            Some(port) => ports.insert(config.port, port),
+            None => {
+                let error = format!("server {} has no port {} past it", config.port, query.offset);
+                return error_reply(StatusCode::UNPROCESSABLE_ENTITY, &error);
+            }
+        };
+    }
+    let configs: Vec<(u16, ServerJsonBody)> = sources.iter()
+        .map(|config| (config.port, duplicate_config(config, ports[&config.port], &ports)))
+        .collect();
+    if let Some(error) = configs.iter().find_map(|(_, config)| check_config(&registry, config).err()) {
+        return error_reply(StatusCode::UNPROCESSABLE_ENTITY, &error.to_string());
+    }
+
+    let mut duplicated = Duplicated { created: BTreeMap::new(), failed: Vec::new() };
+    for (source, config) in configs {
+        let port = config.port;
+        match start_server(&database, &mut registry, config, 0) {
+            Ok(()) => {
+                duplicated.created.insert(source, port);
+            }
+            Err(e) => duplicated.failed.push(ApplyFailure { port, error: e.to_string() }),
+        }
+    }
+    warp::reply::json(&duplicated).into_response()
+}
@@ -0,0 +1,170 @@
+use std::fs::File;
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// How long a leader's claim on Redis lasts unless it's renewed, by default
+const DEFAULT_LEASE: Duration = Duration::from_secs(10);
+
+/// The key an instance's leader claims in Redis, followed by the instance
+#[cfg(feature = "redis")]
+const LEADER_KEY_PREFIX: &str = "warp-self-replicating:leader:";
+
+/// Renews the claim of a leader if it still holds it, telling whether it does
+#[cfg(feature = "redis")]
+const RENEW_SCRIPT: &str = r"
+if redis.call('get', KEYS[1]) == ARGV[1] then
+    return redis.call('pexpire', KEYS[1], ARGV[2])
+end
+return 0
+";
+
+/// What the root server of an active/standby pair elects its leader with, from
+/// `LEADER_LOCK`: `file:{path}` for a lock on a file both can see, or `redis` for a
+/// lease in the Redis of `REDIS_URL`, which `LEADER_LEASE_SECS` sets the length of.
+/// A standby waits until it leads before it loads the registry from the store the pair
+/// share, which with Redis takes them both having the same `INSTANCE_ID`. Started with
+/// `--daemon`, a standby's command returns once it's taken over.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Election {
+    File(PathBuf),
+    Redis { url: String, lease: Duration },
+}
+
+impl Election {
+    pub fn from_env() -> Result<Option<Election>, String> {
+        let lock = match std::env::var("LEADER_LOCK") {
+            Ok(lock) => lock,
+            Err(_) => return Ok(None),
+        };
+        let lease = match std::env::var("LEADER_LEASE_SECS") {
+            Ok(secs) => match secs.parse::<u64>() {
+                Ok(secs) if secs > 0 => Duration::from_secs(secs),
+                _ => return Err(format!("invalid LEADER_LEASE_SECS {:?}, expected a number of seconds", secs)),
+            },
+            Err(_) => DEFAULT_LEASE,
+        };
+        match lock.split_once(':') {
+            Some(("file", path)) if !path.is_empty() => Ok(Some(Election::File(PathBuf::from(path)))),
+            None if lock == "redis" => {
+                let url = std::env::var("REDIS_URL")
+                    .map_err(|_| "LEADER_LOCK=redis needs REDIS_URL".to_string())?;
+                Ok(Some(Election::Redis { url, lease }))
+            }
+            _ => Err(format!("invalid LEADER_LOCK {:?}, expected file:{{path}} or redis", lock)),
+        }
+    }
+
+    /// Wait until this process leads, standing by while another does, and keep leading
+    /// for as long as it runs. A process that's been `upgraded` into leads already. A
+    /// leader that loses its lease to the standby exits, as both would serve otherwise.
+    pub fn lead(&self, upgraded: bool) -> Result<(), String> {
+        match self {
+            Election::File(path) => lock_file(path, upgraded),
+            Election::Redis { url, lease } => claim_lease(url, *lease),
+        }
+    }
+}
+
+/// Hold a lock on the file at `path`, which the system lets go of when the process dies.
+/// It's held by a descriptor kept across upgrades, so the process upgraded into holds it
+/// from the start.
+fn lock_file(path: &PathBuf, upgraded: bool) -> Result<(), String> {
+    if upgraded {
+        return Ok(());
+    }
+    let file = File::options().create(true).truncate(false).write(true).open(path)
+        .map_err(|e| format!("failed to open {}: {}", path.display(), e))?;
+    match file.try_lock() {
+        Ok(()) => {}
+        Err(std::fs::TryLockError::WouldBlock) => {
+            eprintln!("standing by while another process holds {}", path.display());
+            file.lock().map_err(|e| format!("failed to lock {}: {}", path.display(), e))?;
+            eprintln!("took over as leader, holding {}", path.display());
+        }
+        Err(std::fs::TryLockError::Error(e)) => return Err(format!("failed to lock {}: {}", path.display(), e)),
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::io::AsRawFd;
+
+        // The descriptor is open, and only loses the flag that closes it on exec
+        unsafe {
+            let flags = libc::fcntl(file.as_raw_fd(), libc::F_GETFD);
+            libc::fcntl(file.as_raw_fd(), libc::F_SETFD, flags & !libc::FD_CLOEXEC);
+        }
+    }
+    // Leading lasts until the process exits
+    std::mem::forget(file);
+    Ok(())
+}
+
+/// Claim the leader's key of this instance in Redis for `lease`, renewing it at a third
+/// of that on a thread of its own. The process is named by its host and pid, which an
+/// upgrade keeps, so the process upgraded into picks up the claim where it was left.
+#[cfg(feature = "redis")]
+fn claim_lease(url: &str, lease: Duration) -> Result<(), String> {
+    let instance = std::env::var("INSTANCE_ID").unwrap_or_else(|_| crate::store::host_name());
+    let key = format!("{}{}", LEADER_KEY_PREFIX, instance);
+    let holder = format!("{}:{}", crate::store::host_name(), std::process::id());
+    let lease_ms = lease.as_millis() as u64;
+    let mut connection = redis::Client::open(url)
+        .and_then(|client| client.get_connection())
+        .map_err(|e| format!("failed to connect to {}: {}", url, e))?;
+
+    let mut standing_by = false;
+    loop {
+        let claimed: Option<String> = redis::cmd("SET").arg(&key).arg(&holder).arg("NX").arg("PX").arg(lease_ms)
+            .query(&mut connection)
+            .map_err(|e| format!("failed to claim {}: {}", key, e))?;
+        let renewed: u64 = match claimed {
+            Some(_) => 1,
+            None => renew(&mut connection, &key, &holder, lease_ms)
+                .map_err(|e| format!("failed to claim {}: {}", key, e))?,
+        };
+        if renewed == 1 {
+            break;
+        }
+        if !standing_by {
+            let leader: Option<String> = redis::cmd("GET").arg(&key).query(&mut connection).unwrap_or_default();
+            eprintln!("standing by while {} leads {}", leader.as_deref().unwrap_or("another process"), instance);
+            standing_by = true;
+        }
+        std::thread::sleep(lease / 3);
+    }
+    if standing_by {
+        eprintln!("took over as leader of {}", instance);
+    }
+
+    std::thread::Builder::new()
+        .name("leader".to_string())
+        .spawn(move || {
+            let mut renewed_at = std::time::Instant::now();
+            loop {
+                std::thread::sleep(lease / 3);
+                match renew(&mut connection, &key, &holder, lease_ms) {
+                    Ok(1) => renewed_at = std::time::Instant::now(),
+                    Ok(_) => {
+                        eprintln!("lost the lead of {} to another process, exiting", instance);
+                        std::process::exit(1);
+                    }
+                    Err(e) if renewed_at.elapsed() >= lease => {
+                        eprintln!("couldn't renew the lead of {} in time, exiting: {}", instance, e);
+                        std::process::exit(1);
+                    }
+                    Err(e) => eprintln!("failed to renew the lead of {}: {}", instance, e),
+                }
+            }
+        })
+        .map_err(|e| format!("failed to start renewing the lead: {}", e))?;
+    Ok(())
+}
+
+#[cfg(feature = "redis")]
+fn renew(connection: &mut redis::Connection, key: &str, holder: &str, lease_ms: u64) -> redis::RedisResult<u64> {
+    redis::cmd("EVAL").arg(RENEW_SCRIPT).arg(1).arg(key).arg(holder).arg(lease_ms).query(connection)
+}
+
+#[cfg(not(feature = "redis"))]
+fn claim_lease(url: &str, _lease: Duration) -> Result<(), String> {
+    Err(format!("can't lead through {}: built without the redis feature", url))
+}
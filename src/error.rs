@@ -0,0 +1,126 @@
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+use crate::error_reply;
+
+/// Failures of admin API handlers, answered with a JSON error body by `recover`
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("failed to start server on port {port}: {source}")]
+    StartServer {
+        port: u16,
+        #[source]
+        source: std::io::Error,
+    },
+    #[error("failed to allocate a port: {0}")]
+    AllocatePort(#[source] std::io::Error),
+    #[error("already handling the maximum of {0} concurrent requests")]
+    ConcurrentRequests(usize),
+    #[error("failed to generate a deletion token: {0}")]
+    DeletionToken(#[source] std::io::Error),
+    #[error("missing or invalid bearer token")]
+    Unauthorized,
+    #[error("too many requests, retry after {} seconds", retry_after_secs(*.0))]
+    RateLimited(std::time::Duration),
+    #[error("{0}")]
+    QuotaExceeded(crate::quota::Exceeded),
+    #[error("the admin API is read-only, see PUT /admin/readonly")]
+    ReadOnly,
+    #[error("rate limit of {limit} requests every {window_secs} seconds of rule {rule} exceeded")]
+    LimitExceeded {
+        rule: usize,
+        limit: u64,
+        window_secs: u64,
+        /// The rate-limit headers to answer with, see `throttling::RateLimitRule`
+        headers: warp::http::HeaderMap,
+    },
+    #[error("failed by the {} profile", .preset.as_deref().unwrap_or("custom"))]
+    Profiled {
+        preset: Option<String>,
+        status: warp::http::StatusCode,
+    },
+    #[error("fault injected into the admin API, see PUT /admin/faults")]
+    InjectedFault {
+        status: warp::http::StatusCode,
+        retry_after_secs: Option<u64>,
+    },
+    /// The body of a request was read to match the XPath of a header route, see
+    /// `headers::Forward::filter`
+    #[error("the request body matches none of the header routes its headers do")]
+    UnmatchedBody,
+    /// The body of a request was read to match the XPath of a fallback, see
+    /// `fallback::Unrouted::filter`
+    #[error("the request body doesn't match the XPath of the fallback")]
+    UnmatchedFallback,
+}
+
+/// The JSON error body of `Error::QuotaExceeded`, telling which quota it was
+#[derive(serde_derive::Serialize)]
+struct QuotaErrorBody<'a> {
+    error: String,
+    quota: &'a crate::quota::Exceeded,
+}
+
+/// Whole seconds of `Retry-After` to wait at least `wait`
+fn retry_after_secs(wait: std::time::Duration) -> u64 {
+    wait.as_secs() + u64::from(wait.subsec_nanos() > 0)
+}
+
+impl Error {
+    fn status(&self) -> warp::http::StatusCode {
+        match self {
+            Error::StartServer { source, .. } if source.kind() == std::io::ErrorKind::AddrInUse => {
+                warp::http::StatusCode::CONFLICT
+            }
+            Error::StartServer { source, .. } if source.kind() == std::io::ErrorKind::InvalidInput => {
+                warp::http::StatusCode::UNPROCESSABLE_ENTITY
+            }
+            Error::ConcurrentRequests(_) | Error::ReadOnly => warp::http::StatusCode::SERVICE_UNAVAILABLE,
+            Error::Unauthorized => warp::http::StatusCode::UNAUTHORIZED,
+            Error::RateLimited(_) | Error::LimitExceeded { .. } => warp::http::StatusCode::TOO_MANY_REQUESTS,
+            Error::QuotaExceeded(_) => warp::http::StatusCode::FORBIDDEN,
+            Error::UnmatchedBody | Error::UnmatchedFallback => warp::http::StatusCode::NOT_FOUND,
+            Error::Profiled { status, .. } | Error::InjectedFault { status, .. } => *status,
+            _ => warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+/// Answer rejections caused by an `Error`, passing on every other rejection
+pub fn recover(rejection: warp::Rejection) -> Result<warp::reply::Response, warp::Rejection> {
+    match rejection.find_cause::<Error>() {
+        Some(Error::RateLimited(wait)) => {
+            let reply = error_reply(warp::http::StatusCode::TOO_MANY_REQUESTS, &Error::RateLimited(*wait).to_string());
+            Ok(warp::reply::Reply::into_response(
+                warp::reply::with_header(reply, "retry-after", retry_after_secs(*wait).to_string())
+            ))
+        }
+        Some(e @ Error::LimitExceeded { headers, .. }) => {
+            let mut reply = error_reply(e.status(), &e.to_string());
+            reply.headers_mut().extend(headers.clone());
+            Ok(reply)
+        }
+        Some(e @ Error::QuotaExceeded(quota)) => {
+            let body = QuotaErrorBody { error: e.to_string(), quota };
+            Ok(warp::reply::Reply::into_response(warp::reply::with_status(warp::reply::json(&body), e.status())))
+        }
+        Some(e @ Error::InjectedFault { retry_after_secs: Some(retry_after_secs), .. }) => {
+            let reply = error_reply(e.status(), &e.to_string());
+            Ok(warp::reply::Reply::into_response(
+                warp::reply::with_header(reply, "retry-after", retry_after_secs.to_string())
+            ))
+        }
+        Some(e) => Ok(error_reply(e.status(), &e.to_string())),
+        None => Err(rejection),
+    }
+}
+
+/// Lock `mutex`, even if another thread panicked while holding it. Every change to
+/// the registry is made in steps that leave it consistent, so a handler that panicked
+/// halfway through leaves it usable.
+pub fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|poisoned: PoisonError<_>| {
+        eprintln!("recovering from a panic while the lock was held");
+        mutex.clear_poison();
+        poisoned.into_inner()
+    })
+}
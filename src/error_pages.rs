@@ -0,0 +1,94 @@
+use warp::http::header::{HeaderValue, CONTENT_TYPE};
+use warp::http::StatusCode;
+
+use std::collections::BTreeMap;
+use std::io;
+use std::sync::Arc;
+
+/// The statuses an HTTP server can be given pages of its own for: 404 for the requests
+/// none of its routes take, 405 for those none takes with their method, and 500 for
+/// those it fails on
+const STATUSES: [StatusCode; 3] = [
+    StatusCode::NOT_FOUND,
+    StatusCode::METHOD_NOT_ALLOWED,
+    StatusCode::INTERNAL_SERVER_ERROR,
+];
+
+/// A body an HTTP server answers with in place of its own for one of `STATUSES`, for
+/// clients that tell errors apart by their bodies, like an HTML page of a web server or
+/// the JSON error of an API gateway
+#[derive(Clone, Debug, PartialEq, serde_derive::Deserialize, serde_derive::Serialize)]
+pub struct ErrorPage {
+    #[serde(default = "default_content_type")]
+    pub content_type: String,
+    #[serde(default)]
+    pub body: String,
+}
+
+fn default_content_type() -> String {
+    "text/html; charset=utf-8".to_string()
+}
+
+impl ErrorPage {
+    /// The page as answered for `status`, checked to have a valid content type, and a body
+    /// of JSON if that's what it's said to be
+    pub fn checked(&self, status: StatusCode) -> io::Result<Page> {
+        let content_type = HeaderValue::from_str(&self.content_type)
+            .map_err(|_| invalid(format!("invalid content type {:?} of the {} error page", self.content_type, status.as_u16())))?;
+        let json = self.content_type.split(';').next().is_some_and(|mime| mime.trim().ends_with("json"));
+        if json {
+            serde_json::from_str::<serde_json::Value>(&self.body)
+                .map_err(|e| invalid(format!("the {} error page isn't JSON: {}", status.as_u16(), e)))?;
+        }
+        Ok(Page { status, content_type, body: self.body.clone() })
+    }
+}
+
+/// An `ErrorPage` checked to be valid
+#[derive(Clone, Debug)]
+pub struct Page {
+    status: StatusCode,
+    content_type: HeaderValue,
+    body: String,
+}
+
+impl Page {
+    pub fn reply(&self) -> warp::reply::Response {
+        let mut reply = warp::http::Response::new(self.body.clone().into());
+        *reply.status_mut() = self.status;
+        reply.headers_mut().insert(CONTENT_TYPE, self.content_type.clone());
+        reply
+    }
+}
+
+fn invalid(error: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, error)
+}
+
+/// The pages of an HTTP server by their status, see `ErrorPages::recover`
+#[derive(Clone, Default)]
+pub struct ErrorPages(Arc<BTreeMap<StatusCode, Page>>);
+
+/// Check `pages` are of `STATUSES` only, and valid, see `ErrorPage::checked`
+pub fn error_pages(pages: &BTreeMap<u16, ErrorPage>) -> io::Result<ErrorPages> {
+    let pages = pages.iter()
+        .map(|(status, page)| {
+            let status = StatusCode::from_u16(*status).ok()
+                .filter(|status| STATUSES.contains(status))
+                .ok_or_else(|| invalid(format!("no error page can be set for status {}, only 404, 405 and 500", status)))?;
+            Ok((status, page.checked(status)?))
+        })
+        .collect::<io::Result<_>>()?;
+    Ok(ErrorPages(Arc::new(pages)))
+}
+
+impl ErrorPages {
+    /// Answer `rejection` with the page of the status it'd be answered with, if there's
+    /// one. Responses the routes make, like a stub answering 404, are left as they are.
+    pub fn recover(&self, rejection: warp::Rejection) -> Result<warp::reply::Response, warp::Rejection> {
+        match self.0.get(&rejection.status()) {
+            Some(page) => Ok(page.reply()),
+            None => Err(rejection),
+        }
+    }
+}
@@ -0,0 +1,118 @@
+use warp::Filter;
+
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+
+use crate::activity::Activity;
+use crate::clients::{self, ClientPattern};
+use crate::clock::SharedClock;
+use crate::error::lock;
+use crate::error_reply;
+use crate::stubs::Hits;
+
+/// A rule of an HTTP server failing some of the requests it matches, counting them from
+/// when the server (re)started, to exercise how clients retry and back off
+#[derive(Clone, Debug, PartialEq, serde_derive::Deserialize, serde_derive::Serialize)]
+pub struct FailureRule {
+    /// The method of the requests counted, any by default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub method: Option<String>,
+    /// The path of the requests counted, without the query string, any by default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    /// The clients whose requests are counted, any by default
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub clients: Vec<ClientPattern>,
+    /// Requests answered as usual before the rule starts failing them
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub after: Option<u64>,
+    /// Fail only every `every`-th request counted, after those let through by `after`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub every: Option<u64>,
+    #[serde(default = "default_status")]
+    pub status: u16,
+    #[serde(flatten)]
+    pub activity: Activity,
+}
+
+fn default_status() -> u16 {
+    503
+}
+
+impl FailureRule {
+    pub fn matches(&self, method: &warp::http::Method, path: &str, client: Option<SocketAddr>) -> bool {
+        self.method.as_ref().is_none_or(|expected| expected.eq_ignore_ascii_case(method.as_str()))
+            && self.path.as_ref().is_none_or(|expected| expected == path)
+            && clients::matches(&self.clients, client)
+    }
+
+    /// Whether the `count`-th request matched, counting from 1, fails
+    fn fails(&self, count: u64) -> bool {
+        let after = self.after.unwrap_or(0);
+        count > after && self.every.is_none_or(|every| (count - after).is_multiple_of(every))
+    }
+}
+
+/// The failure rules of a server, checked to be valid, see `Failures::filter`
+#[derive(Clone)]
+pub struct Failures {
+    rules: Arc<Vec<FailureRule>>,
+    clock: SharedClock,
+    hits: Arc<Hits>,
+}
+
+/// Check `rules`, to fail requests with once they're served, while they're active by
+/// `clock`, counting them in `hits`
+pub fn fail(rules: &[FailureRule], clock: SharedClock, hits: Arc<Hits>) -> io::Result<Failures> {
+    for rule in rules {
+        if rule.after.is_none() && rule.every.is_none() || rule.every == Some(0) {
+            let error = "a failure rule needs after or a non-zero every";
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, error));
+        }
+        if !(400..600).contains(&rule.status) {
+            let error = format!("failure status {} isn't an error", rule.status);
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, error));
+        }
+        rule.activity.check()?;
+    }
+    Ok(Failures { rules: Arc::new(rules.to_vec()), clock, hits })
+}
+
+impl Failures {
+    /// Answer the requests of the connection from `client` the rules fail with their
+    /// `status`, every rule a request matches counting it. Other requests are rejected to
+    /// be handled as usual.
+    pub fn filter(
+        &self,
+        client: Option<SocketAddr>
+    ) -> impl Filter<Extract = (warp::reply::Response,), Error = warp::Rejection> + Clone {
+        let Failures { rules, clock, hits } = self.clone();
+        warp::method()
+            .and(warp::path::full())
+            .and_then(move |method: warp::http::Method, path: warp::path::FullPath| {
+                let mut failure = None;
+                let now_ms = lock(&clock).now_ms();
+                for (index, rule) in rules.iter().enumerate() {
+                    if !rule.activity.is_active(hits.started_ms, now_ms) || !rule.matches(&method, path.as_str(), client) {
+                        continue;
+                    }
+                    let count = hits.failure_rules[index].fetch_add(1, Ordering::SeqCst) + 1;
+                    if failure.is_none() && rule.fails(count) {
+                        failure = Some((index, rule.status, count));
+                    }
+                }
+
+                match failure {
+                    Some((index, status, count)) => {
+                        let status = warp::http::StatusCode::from_u16(status)
+                            .unwrap_or(warp::http::StatusCode::SERVICE_UNAVAILABLE);
+                        let error = format!("failed by rule {} at matching request {}", index, count);
+                        Ok(error_reply(status, &error))
+                    }
+                    None => Err(warp::reject::not_found()),
+                }
+            })
+    }
+}
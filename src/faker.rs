@@ -0,0 +1,169 @@
+use std::collections::BTreeMap;
+use std::io;
+use std::net::SocketAddr;
+
+use crate::har;
+use crate::synth::Synthesizer;
+
+/// Words of `{{faker.lorem}}` without a count
+const LOREM_WORDS: usize = 8;
+
+fn invalid(error: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, error)
+}
+
+/// What a placeholder of a body template makes up
+#[derive(Clone, Debug, PartialEq)]
+enum Helper {
+    Name,
+    FirstName,
+    LastName,
+    Email,
+    Uuid,
+    Timestamp,
+    Word,
+    Lorem(usize),
+    Integer(i64, i64),
+    /// The value the server keeps under a key, see `state::SharedState`
+    State(String),
+    /// The value the server keeps under a key, counted up by one first
+    Increment(String),
+    /// The time the server's clock tells, see `clock::Clock`
+    Now,
+    UnixTime,
+    /// The address of the client the request came from, see `proxy_protocol`
+    ClientAddress,
+    ClientPort,
+}
+
+impl Helper {
+    /// The helper of a placeholder, like `faker.integer 1 6`
+    fn parse(placeholder: &str) -> io::Result<Helper> {
+        let mut words = placeholder.split_whitespace();
+        let name = words.next().unwrap_or("");
+        let args: Vec<&str> = words.collect();
+        let numbers = || args.iter()
+            .map(|arg| arg.parse::<i64>().map_err(|_| invalid(format!("{{{{{}}}}} takes numbers, not {:?}", name, arg))))
+            .collect::<io::Result<Vec<i64>>>();
+        let helper = match (name, args.len()) {
+            ("faker.name", 0) => Helper::Name,
+            ("faker.first_name", 0) => Helper::FirstName,
+            ("faker.last_name", 0) => Helper::LastName,
+            ("faker.email", 0) => Helper::Email,
+            ("faker.uuid", 0) => Helper::Uuid,
+            ("faker.timestamp", 0) => Helper::Timestamp,
+            ("faker.word", 0) => Helper::Word,
+            ("faker.lorem", 0) => Helper::Lorem(LOREM_WORDS),
+            ("faker.lorem", 1) => Helper::Lorem(numbers()?[0].clamp(0, 1000) as usize),
+            ("faker.integer", 2) => match numbers()?[..] {
+                [min, max] if min <= max => Helper::Integer(min, max),
+                _ => return Err(invalid(format!("{{{{{}}}}} takes a minimum no greater than its maximum", placeholder))),
+            },
+            ("clock.now", 0) => Helper::Now,
+            ("clock.unix", 0) => Helper::UnixTime,
+            ("client.address", 0) => Helper::ClientAddress,
+            ("client.port", 0) => Helper::ClientPort,
+            ("state.incr", 1) => Helper::Increment(args[0].to_string()),
+            (key, 0) if key.starts_with("state.") => Helper::State(key["state.".len()..].to_string()),
+            _ => return Err(invalid(format!("unknown helper {{{{{}}}}}", placeholder))),
+        };
+        Ok(helper)
+    }
+
+    fn render(&self, synthesizer: &mut Synthesizer, state: &mut BTreeMap<String, serde_json::Value>, request: &Rendered, out: &mut String) {
+        match self {
+            Helper::Name => {
+                out.push_str(synthesizer.first_name());
+                out.push(' ');
+                out.push_str(synthesizer.last_name());
+            }
+            Helper::FirstName => out.push_str(synthesizer.first_name()),
+            Helper::LastName => out.push_str(synthesizer.last_name()),
+            Helper::Email => out.push_str(&synthesizer.email()),
+            Helper::Uuid => out.push_str(&synthesizer.uuid()),
+            Helper::Timestamp => out.push_str(&synthesizer.timestamp()),
+            Helper::Word => out.push_str(synthesizer.word()),
+            Helper::Lorem(words) => out.push_str(&synthesizer.lorem(*words)),
+            Helper::Integer(min, max) => out.push_str(&synthesizer.integer_within(*min, *max).to_string()),
+            Helper::State(key) => match state.get(key) {
+                // Strings go in as they are, to be quoted or not by the template
+                Some(serde_json::Value::String(value)) => out.push_str(value),
+                Some(value) => out.push_str(&value.to_string()),
+                None => out.push_str("null"),
+            },
+            Helper::Increment(key) => {
+                let count = state.get(key).and_then(serde_json::Value::as_i64).unwrap_or(0).saturating_add(1);
+                state.insert(key.clone(), count.into());
+                out.push_str(&count.to_string());
+            }
+            Helper::Now => out.push_str(&har::iso8601(request.now_ms)),
+            Helper::UnixTime => out.push_str(&(request.now_ms / 1000).to_string()),
+            // Like `state.` helpers, `null` if there's none
+            Helper::ClientAddress => out.push_str(&request.client.map_or("null".to_string(), |client| client.ip().to_string())),
+            Helper::ClientPort => out.push_str(&request.client.map_or("null".to_string(), |client| client.port().to_string())),
+        }
+    }
+}
+
+/// What a body is rendered for: the time the server's clock tells, and the client
+pub struct Rendered {
+    pub now_ms: u64,
+    pub client: Option<SocketAddr>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Part {
+    Text(String),
+    Helper(Helper),
+}
+
+/// A response body with placeholders made up anew for every request, like
+/// `{"id": "{{faker.uuid}}", "name": "{{faker.name}}"}`. The helpers are `faker.name`,
+/// `faker.first_name`, `faker.last_name`, `faker.email`, `faker.uuid`, `faker.timestamp`
+/// (ISO 8601), `faker.word`, `faker.lorem` with an optional count of words, and
+/// `faker.integer` with a minimum and maximum, like `{{faker.integer 1 6}}`. What the
+/// server keeps is read with `{{state.cart}}`, a string as it is and anything else as
+/// JSON, `null` if there's nothing, and counted up with `{{state.incr orders}}`. The
+/// time the server's clock tells is `{{clock.now}}` (ISO 8601) or `{{clock.unix}}`, and
+/// the client's address and port `{{client.address}}` and `{{client.port}}`. Braces not
+/// around a `faker.`, `state.`, `clock.` or `client.` helper are left as they are.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BodyTemplate(Vec<Part>);
+
+impl BodyTemplate {
+    pub fn parse(template: &str) -> io::Result<BodyTemplate> {
+        let mut parts = Vec::new();
+        let mut text = String::new();
+        let mut rest = template;
+        while let Some(start) = rest.find("{{") {
+            let placeholder = rest[start + 2..].find("}}").map(|end| &rest[start + 2..start + 2 + end]);
+            match placeholder {
+                Some(placeholder) if ["faker.", "state.", "clock.", "client."].iter().any(|prefix| placeholder.trim().starts_with(prefix)) => {
+                    text.push_str(&rest[..start]);
+                    parts.push(Part::Text(std::mem::take(&mut text)));
+                    parts.push(Part::Helper(Helper::parse(placeholder.trim())?));
+                    rest = &rest[start + 2 + placeholder.len() + 2..];
+                }
+                _ => {
+                    text.push_str(&rest[..start + 2]);
+                    rest = &rest[start + 2..];
+                }
+            }
+        }
+        text.push_str(rest);
+        parts.push(Part::Text(text));
+        parts.retain(|part| *part != Part::Text(String::new()));
+        Ok(BodyTemplate(parts))
+    }
+
+    pub fn render(&self, synthesizer: &mut Synthesizer, state: &mut BTreeMap<String, serde_json::Value>, request: &Rendered) -> String {
+        let mut out = String::new();
+        for part in &self.0 {
+            match part {
+                Part::Text(text) => out.push_str(text),
+                Part::Helper(helper) => helper.render(synthesizer, state, request, &mut out),
+            }
+        }
+        out
+    }
+}
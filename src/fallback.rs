@@ -0,0 +1,418 @@
+use warp::{Buf, Filter};
+use warp::http::HeaderMap;
+
+use std::collections::BTreeMap;
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use crate::{downloads, headers, interim, schema, unmatched, xml};
+use crate::activity::Activity;
+use crate::clock::SharedClock;
+use crate::error::{lock, Error};
+use crate::faker::{BodyTemplate, Rendered};
+use crate::headers::Target;
+use crate::interim::{Informational, Interim};
+use crate::journal::SharedJournal;
+use crate::resolver::{self, Resolver, UpstreamDns};
+use crate::seed::{Reseeded, SharedSeed};
+use crate::state::SharedState;
+use crate::stubs::Hits;
+use crate::synth::Synthesizer;
+use crate::xml::{Matcher, XPathMatchers};
+
+/// What an HTTP server answers the requests none of its routes take with, in place of a
+/// 404 or 405, see `unmatched::is_routed`. Either a response of its own, or whatever
+/// `upstream` answers, for a mock standing in for part of a real service. An XML body is
+/// answered as such, and a SOAP envelope as SOAP, unless `headers` has a content type, see
+/// `xml::content_type`.
+#[derive(Clone, Debug, PartialEq, serde_derive::Deserialize, serde_derive::Serialize)]
+pub struct Fallback {
+    #[serde(default = "default_status")]
+    pub status: u16,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub headers: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub body: String,
+    /// A file the body is read from as it's sent, in place of `body`, for bodies too large
+    /// to inline, like those of download mocks. Its extension tells the content type,
+    /// unless `headers` has one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub body_file: Option<PathBuf>,
+    /// A JSON Schema to make up a JSON body from when the server starts, in place of
+    /// `body`, see `synth::Synthesizer`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub response_schema: Option<serde_json::Value>,
+    /// Sent ahead of the response, in order, over HTTP/1
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub informational: Vec<Informational>,
+    /// Sent after the body, which is then chunked, over HTTP/1. Announced with a
+    /// `Trailer` header.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub trailers: BTreeMap<String, String>,
+    /// Responses answered in turn in place of `status`, `headers` and `body`: the first
+    /// to the first request the fallback takes, the second to the next and so on, like
+    /// the pages of a listing or the answers to a poll. Requests answered with 422 for
+    /// not conforming to `schema` don't count. They start over when the fallback is reset
+    /// with `POST /{port}/stubs/fallback/reset`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub sequence: Vec<SequencedResponse>,
+    /// What's answered once the last response of `sequence` has been
+    #[serde(default, skip_serializing_if = "AfterSequence::is_default")]
+    pub after_sequence: AfterSequence,
+    /// Fill in the placeholders of `body`, or those of `sequence`, anew for every request,
+    /// like `{{faker.name}}` or `{{state.cart}}`, see `faker::BodyTemplate`
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub templated: bool,
+    /// An `http://` URL without a path, like `http://10.0.0.7:8000`, to forward the
+    /// requests to, keeping their path and query
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub upstream: Option<String>,
+    /// How the host name of `upstream` is resolved, by default by the system
+    #[serde(default, skip_serializing_if = "UpstreamDns::is_default")]
+    pub upstream_dns: UpstreamDns,
+    /// A JSON Schema the bodies of the requests must conform to, see `schema::Schema`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub schema: Option<serde_json::Value>,
+    /// What the XML bodies of the requests must match for the fallback to take them, see
+    /// `xml::XPathMatchers`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub xpath: Option<XPathMatchers>,
+    #[serde(flatten)]
+    pub activity: Activity,
+}
+
+fn default_status() -> u16 {
+    404
+}
+
+/// A response of a fallback's `sequence`
+#[derive(Clone, Debug, PartialEq, serde_derive::Deserialize, serde_derive::Serialize)]
+pub struct SequencedResponse {
+    #[serde(default = "default_sequenced_status")]
+    pub status: u16,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub headers: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub body: String,
+    /// Like the fallback's own
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub body_file: Option<PathBuf>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub informational: Vec<Informational>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub trailers: BTreeMap<String, String>,
+}
+
+fn default_sequenced_status() -> u16 {
+    200
+}
+
+/// What a fallback answers after the last response of its `sequence`
+#[derive(Clone, Copy, Debug, Default, PartialEq, serde_derive::Deserialize, serde_derive::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AfterSequence {
+    /// The last response again, to every request after, like a poll that's done
+    #[default]
+    StickOnLast,
+    /// The first response, going through them all again
+    Repeat,
+}
+
+impl AfterSequence {
+    fn is_default(&self) -> bool {
+        *self == AfterSequence::default()
+    }
+}
+
+fn invalid(error: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, error)
+}
+
+/// The responses of a fallback's own
+struct Canned {
+    /// Answered in turn, see `AfterSequence`. Just the one unless the fallback has a
+    /// sequence.
+    responses: Vec<CannedResponse>,
+    after_sequence: AfterSequence,
+    /// What makes up the bodies of templated responses
+    synthesizer: Mutex<Reseeded<Synthesizer>>,
+    state: SharedState,
+    clock: SharedClock,
+}
+
+struct CannedResponse {
+    status: warp::http::StatusCode,
+    headers: HeaderMap,
+    body: String,
+    /// What makes up the body of each response instead, if it's templated
+    template: Option<BodyTemplate>,
+    /// The file the body is read from instead, anew for every response
+    file: Option<PathBuf>,
+    /// The informational responses sent ahead, see `interim::informational`
+    informational: Vec<u8>,
+    trailers: Option<Vec<u8>>,
+}
+
+impl Canned {
+    /// Answer the request the fallback takes after having taken `taken` others, sending
+    /// what goes along with the response on `interim`. Responses to `HEAD` have no body to
+    /// end with trailers.
+    fn reply(&self, client: Option<SocketAddr>, head: bool, interim: &Interim, taken: u64) -> warp::reply::Response {
+        let last = self.responses.len() - 1;
+        let index = match self.after_sequence {
+            AfterSequence::StickOnLast => taken.min(last as u64) as usize,
+            AfterSequence::Repeat => (taken % self.responses.len() as u64) as usize,
+        };
+        let response = &self.responses[index];
+        let trailers = response.trailers.as_deref().filter(|_| !head);
+        // Checked when the server started, but the file may have changed since
+        let file = match &response.file {
+            Some(file) => match downloads::file_body(file) {
+                Ok(file) => Some(file),
+                Err(e) => {
+                    let error = format!("failed to read the body file {} of the fallback: {}", file.display(), e);
+                    return crate::error_reply(warp::http::StatusCode::INTERNAL_SERVER_ERROR, &error);
+                }
+            },
+            None => None,
+        };
+        interim.send(&response.informational, trailers);
+        let length = file.as_ref().map(|(size, _)| *size);
+        let body = match (file, &response.template) {
+            (Some(_), _) if head => hyper::Body::empty(),
+            (Some((_, body)), _) => body,
+            (None, Some(template)) => {
+                let request = Rendered { now_ms: lock(&self.clock).now_ms(), client };
+                template.render(lock(&self.synthesizer).get(), &mut lock(&self.state), &request).into()
+            },
+            (None, None) => response.body.clone().into(),
+        };
+        let body = match trailers {
+            // Of unknown length, for hyper to chunk it
+            Some(_) => hyper::Body::wrap_stream(body),
+            None => body,
+        };
+        let mut reply = warp::http::Response::new(body);
+        *reply.status_mut() = response.status;
+        reply.headers_mut().extend(response.headers.clone());
+        // A file is streamed, of a length told up front unless it's chunked for trailers
+        if let Some(length) = length.filter(|_| trailers.is_none()) {
+            reply.headers_mut().insert(warp::http::header::CONTENT_LENGTH, length.into());
+        }
+        reply
+    }
+}
+
+/// A response of `status`, `headers` and `body`, or `body_file`, or one made up from
+/// `response_schema` with `seed`, checked to be valid
+#[allow(clippy::too_many_arguments)]
+fn canned_response(
+    status: u16,
+    headers: &BTreeMap<String, String>,
+    body: &str,
+    body_file: Option<&Path>,
+    informational: &[Informational],
+    trailers: &BTreeMap<String, String>,
+    response_schema: Option<&serde_json::Value>,
+    templated: bool,
+    seed: u64
+) -> io::Result<CannedResponse> {
+    let status = warp::http::StatusCode::from_u16(status)
+        .map_err(|_| invalid(format!("invalid fallback status {}", status)))?;
+    let mut headers = headers::response_headers(headers)?;
+    let informational = interim::informational(informational)?;
+    let trailers = match interim::trailers(trailers)? {
+        Some((trailers, names)) => {
+            headers.insert(warp::http::header::TRAILER, names);
+            Some(trailers)
+        }
+        None => None,
+    };
+    if let Some(file) = body_file {
+        if !body.is_empty() || response_schema.is_some() || templated {
+            return Err(invalid("a fallback with a body file has no body or response schema, and isn't templated".to_string()));
+        }
+        let metadata = std::fs::metadata(file)
+            .map_err(|e| invalid(format!("can't answer with the body file {}: {}", file.display(), e)))?;
+        if !metadata.is_file() {
+            return Err(invalid(format!("the body file {} isn't a file", file.display())));
+        }
+        if !headers.contains_key(warp::http::header::CONTENT_TYPE) {
+            let content_type = warp::http::header::HeaderValue::from_static(downloads::content_type_of(file));
+            headers.insert(warp::http::header::CONTENT_TYPE, content_type);
+        }
+    }
+    let body = match response_schema {
+        Some(_) if !body.is_empty() => return Err(invalid("a fallback has a body or a response schema, not both".to_string())),
+        Some(schema) => {
+            if !headers.contains_key(warp::http::header::CONTENT_TYPE) {
+                headers.insert(warp::http::header::CONTENT_TYPE, warp::http::header::HeaderValue::from_static("application/json"));
+            }
+            Synthesizer::new(seed, "fallback").value(schema).to_string()
+        }
+        None => {
+            let content_type = xml::content_type(body).filter(|_| !headers.contains_key(warp::http::header::CONTENT_TYPE));
+            if let Some(content_type) = content_type {
+                headers.insert(warp::http::header::CONTENT_TYPE, warp::http::header::HeaderValue::from_static(content_type));
+            }
+            body.to_string()
+        }
+    };
+    let template = match templated {
+        true if response_schema.is_some() => return Err(invalid("a fallback with a response schema isn't templated".to_string())),
+        true => Some(BodyTemplate::parse(&body)?),
+        false => None,
+    };
+    let file = body_file.map(Path::to_path_buf);
+    Ok(CannedResponse { status, headers, body, template, file, informational, trailers })
+}
+
+/// Answer the requests no route takes as `fallback` has it, if there's one, making up
+/// its bodies from `seed`, `state` and `clock` and counting them in `hits`, see
+/// `Unrouted::filter`. Templated bodies follow `seed` as it's set later on.
+pub fn fallback(
+    fallback: Option<&Fallback>,
+    seed: &SharedSeed,
+    state: SharedState,
+    clock: SharedClock,
+    hits: Arc<Hits>
+) -> io::Result<Unrouted> {
+    let (response, upstream) = match fallback {
+        Some(Fallback { upstream: Some(upstream), upstream_dns, status, headers, body, body_file, response_schema, informational, trailers, sequence, templated, .. }) => {
+            let own = !headers.is_empty() || !body.is_empty() || body_file.is_some() || response_schema.is_some() || !informational.is_empty() || !trailers.is_empty();
+            if *status != default_status() || own || !sequence.is_empty() || *templated {
+                return Err(invalid("a fallback forwarding to an upstream has no status, headers, body or sequence".to_string()));
+            }
+            let uri: warp::http::Uri = upstream.parse()
+                .map_err(|_| invalid(format!("invalid fallback upstream {:?}", upstream)))?;
+            if uri.scheme_str() != Some("http") || uri.authority_part().is_none() || !matches!(uri.path(), "" | "/") || uri.query().is_some() {
+                return Err(invalid(format!("fallback upstream {:?} isn't an http:// URL without a path", upstream)));
+            }
+            (None, Some((upstream.trim_end_matches('/').to_string(), resolver::resolver(upstream_dns)?)))
+        }
+        Some(Fallback { upstream_dns, .. }) if !upstream_dns.is_default() => {
+            return Err(invalid("only a fallback forwarding to an upstream resolves its host name".to_string()));
+        }
+        Some(Fallback { status, headers, body, body_file, response_schema, informational, trailers, sequence, after_sequence, templated, .. }) => {
+            let responses = if sequence.is_empty() {
+                vec![canned_response(
+                    *status, headers, body, body_file.as_deref(), informational, trailers, response_schema.as_ref(), *templated, seed.get()
+                )?]
+            } else if *status != default_status() || !headers.is_empty() || !body.is_empty() || body_file.is_some() || response_schema.is_some() || !informational.is_empty() || !trailers.is_empty() {
+                return Err(invalid("a fallback has a sequence or a status, headers and body, not both".to_string()));
+            } else {
+                sequence.iter()
+                    .map(|response| canned_response(
+                        response.status, &response.headers, &response.body, response.body_file.as_deref(), &response.informational, &response.trailers, None, *templated, seed.get()
+                    ))
+                    .collect::<io::Result<_>>()?
+            };
+            let canned = Canned {
+                responses,
+                after_sequence: *after_sequence,
+                synthesizer: Mutex::new(Reseeded::new(seed.clone(), |seed| Synthesizer::new(seed, "fallback"))),
+                state: state.clone(),
+                clock: clock.clone(),
+            };
+            (Some(Arc::new(canned)), None)
+        }
+        None => (None, None),
+    };
+    let activity = fallback.map(|fallback| fallback.activity);
+    activity.as_ref().map(Activity::check).transpose()?;
+    let schema = schema::compile(fallback.and_then(|fallback| fallback.schema.as_ref()))?;
+    let xpath = xml::compile(fallback.and_then(|fallback| fallback.xpath.as_ref()))?;
+    Ok(Unrouted { activity, response, upstream, schema, xpath, clock, hits })
+}
+
+/// The fallback of a server, checked to be valid
+#[derive(Clone)]
+pub struct Unrouted {
+    /// That of the fallback, if there's one
+    activity: Option<Activity>,
+    response: Option<Arc<Canned>>,
+    /// With the resolver of its host name
+    upstream: Option<(String, Resolver)>,
+    schema: Option<schema::Schema>,
+    xpath: Option<Matcher>,
+    clock: SharedClock,
+    hits: Arc<Hits>,
+}
+
+impl Unrouted {
+    /// Answer the requests no route takes of the connection from `client`, sending what
+    /// goes along with the fallback's responses on `interim`, and keeping those forwarded
+    /// in `journal`. Other requests are rejected to be handled as usual. Those whose bodies
+    /// don't match the fallback's XPath are answered with 404, as the usual routes can't
+    /// have the body anymore.
+    pub fn filter(
+        &self,
+        client: Option<SocketAddr>,
+        interim: Interim,
+        journal: Option<SharedJournal>
+    ) -> impl Filter<Extract = (warp::reply::Response,), Error = warp::Rejection> + Clone {
+        let Unrouted { activity, response, upstream, schema, xpath, clock, hits } = self.clone();
+        let started_ms = hits.started_ms;
+        let unrouted = warp::method()
+            .and(warp::path::full())
+            .and_then(move |method: warp::http::Method, path: warp::path::FullPath| {
+                let active = activity.is_some_and(|activity| activity.is_active(started_ms, lock(&clock).now_ms()));
+                if !active || unmatched::is_routed(method.as_str(), path.as_str()) {
+                    Err(warp::reject::not_found())
+                } else {
+                    Ok(())
+                }
+            })
+            .untuple_one();
+
+        let (responded, checked, matched) = (hits.clone(), schema.clone(), xpath.clone());
+        let respond = unrouted.clone()
+            .and_then(move || response.clone().ok_or_else(warp::reject::not_found))
+            .and(warp::method())
+            .and(warp::body::concat())
+            .and_then(move |response: Arc<Canned>, method: warp::http::Method, request: warp::body::FullBody| {
+                if matched.as_ref().is_some_and(|xpath| !xpath.matches(request.bytes())) {
+                    return Err(warp::reject::custom(Error::UnmatchedFallback));
+                }
+                if let Some(violation) = checked.as_ref().and_then(|schema| schema.check(request.bytes())) {
+                    return Ok(violation);
+                }
+                let taken = responded.fallback();
+                Ok(response.reply(client, method == warp::http::Method::HEAD, &interim, taken))
+            });
+        let resolver = upstream.as_ref().map(|(_, resolver)| resolver.clone()).unwrap_or_default();
+        let forward = headers::relay_resolving(unrouted.and(matched_body(xpath)).and_then(move |body: Option<Vec<u8>>| {
+            let (upstream, _) = upstream.clone().ok_or_else(warp::reject::not_found)?;
+            hits.fallback();
+            Ok::<_, warp::Rejection>(Target::upstream(upstream).checked(schema.clone()).with_body(body))
+        }), resolver, journal);
+
+        respond.or(forward).unify()
+    }
+}
+
+/// The body of a request if `xpath` is there to match it, which is only read then. Those
+/// it doesn't match are answered with 404, as the usual routes can't have the body anymore.
+fn matched_body(xpath: Option<Matcher>) -> impl Filter<Extract = (Option<Vec<u8>>,), Error = warp::Rejection> + Clone {
+    let unread = xpath.is_none();
+    let read = warp::any()
+        .and_then(move || match unread {
+            true => Err(warp::reject::not_found()),
+            false => Ok(()),
+        })
+        .untuple_one()
+        .and(warp::body::concat())
+        .and_then(move |body: warp::body::FullBody| match &xpath {
+            Some(xpath) if xpath.matches(body.bytes()) => Ok(Some(body.bytes().to_vec())),
+            _ => Err(warp::reject::custom(Error::UnmatchedFallback)),
+        });
+    warp::any()
+        .and_then(move || match unread {
+            true => Ok(None),
+            false => Err(warp::reject::not_found()),
+        })
+        .or(read)
+        .unify()
+}
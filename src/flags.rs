@@ -0,0 +1,352 @@
+use serde_json::{json, Map, Value};
+use warp::{Buf, Filter, Reply};
+use warp::http::StatusCode;
+
+use std::collections::BTreeMap;
+use std::io;
+use std::sync::{Arc, Mutex};
+
+use crate::{error_reply, quota, Database, Registry};
+use crate::error::lock;
+use crate::watch::ChangeType;
+
+fn invalid(error: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, error)
+}
+
+/// An HTTP server acting as a feature flag provider, evaluating its flags for the
+/// context of each request over the APIs OpenFeature providers use:
+///
+/// - `POST /ofrep/v1/evaluate/flags/{key}` and `POST /ofrep/v1/evaluate/flags`, the
+///   OpenFeature Remote Evaluation Protocol, taking `{"context": {...}}`
+/// - `POST /flagd.evaluation.v1.Service/Resolve{Boolean,String,Int,Float,Object,All}`,
+///   flagd's evaluation service in Connect's JSON, taking `{"flagKey": ..., "context": {...}}`
+///
+/// The flags can change while it runs, see `PUT /{port}/flags/{key}`. Tried after CRUD
+/// collections and before the fallback.
+#[derive(Clone, Debug, Default, PartialEq, serde_derive::Deserialize, serde_derive::Serialize)]
+pub struct FeatureFlags {
+    /// Put in front of the paths of the APIs, like `/flags`
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub prefix: String,
+    /// The flags by key
+    #[serde(default)]
+    pub flags: BTreeMap<String, Flag>,
+}
+
+/// A flag, as in flagd's flag definitions: its variants, the one it evaluates to by
+/// default and the rules picking another for some contexts. flagd's JSON Logic
+/// `targeting` isn't evaluated.
+#[derive(Clone, Debug, PartialEq, serde_derive::Deserialize, serde_derive::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Flag {
+    #[serde(default)]
+    pub state: FlagState,
+    /// The values the flag can take by name, all of the same type
+    pub variants: BTreeMap<String, Value>,
+    pub default_variant: String,
+    /// Tried in turn, the first matching the context picking the variant
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub rules: Vec<Rule>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, serde_derive::Deserialize, serde_derive::Serialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum FlagState {
+    #[default]
+    Enabled,
+    /// Evaluated as not found, leaving clients to their defaults
+    Disabled,
+}
+
+/// A variant picked for the contexts having every field of `when` equal to its value,
+/// like `{"when": {"plan": "pro"}, "variant": "on"}`
+#[derive(Clone, Debug, PartialEq, serde_derive::Deserialize, serde_derive::Serialize)]
+pub struct Rule {
+    pub when: BTreeMap<String, Value>,
+    pub variant: String,
+}
+
+/// The flags of an HTTP server by key, shared with its listener so they can change while
+/// it runs
+pub type SharedFlags = Arc<Mutex<BTreeMap<String, Flag>>>;
+
+impl FeatureFlags {
+    fn check(&self) -> io::Result<()> {
+        if !self.prefix.is_empty() && (!self.prefix.starts_with('/') || self.prefix.ends_with('/')) {
+            return Err(invalid(format!("feature flags prefix {:?} doesn't start with / or ends with it", self.prefix)));
+        }
+        for (key, flag) in &self.flags {
+            check(key, flag).map_err(invalid)?;
+        }
+        Ok(())
+    }
+}
+
+/// The JSON type of the values of a flag, as the flagd resolver of the same name takes it
+fn value_type(value: &Value) -> &'static str {
+    match value {
+        Value::Bool(_) => "Boolean",
+        Value::String(_) => "String",
+        Value::Number(number) if number.is_i64() => "Int",
+        Value::Number(_) => "Float",
+        Value::Object(_) => "Object",
+        Value::Null | Value::Array(_) => "",
+    }
+}
+
+fn check(key: &str, flag: &Flag) -> Result<(), String> {
+    if key.is_empty() || key.contains('/') {
+        return Err(format!("invalid feature flag key {:?}", key));
+    }
+    let mut types = flag.variants.values().map(|value| match value_type(value) {
+        "Int" | "Float" => "number",
+        other => other,
+    });
+    match types.next() {
+        None => return Err(format!("feature flag {} has no variants", key)),
+        Some("") => return Err(format!("the variants of feature flag {} aren't booleans, strings, numbers or objects", key)),
+        Some(first) if types.any(|other| other != first) => {
+            return Err(format!("the variants of feature flag {} aren't all of the same type", key));
+        }
+        Some(_) => {}
+    }
+    let mut variants = std::iter::once(&flag.default_variant).chain(flag.rules.iter().map(|rule| &rule.variant));
+    if let Some(variant) = variants.find(|variant| !flag.variants.contains_key(*variant)) {
+        return Err(format!("feature flag {} has no variant {:?}", key, variant));
+    }
+    Ok(())
+}
+
+/// The flags of `flags` to share with the listener of a server, checked to be valid
+pub fn share(flags: &FeatureFlags) -> io::Result<SharedFlags> {
+    flags.check()?;
+    Ok(Arc::new(Mutex::new(flags.flags.clone())))
+}
+
+/// A flag evaluated for a context
+struct Evaluation {
+    value: Value,
+    variant: String,
+    reason: &'static str,
+}
+
+/// Why a flag couldn't be evaluated
+enum EvaluationError {
+    NotFound(String),
+    TypeMismatch(String),
+}
+
+fn evaluate(flags: &BTreeMap<String, Flag>, key: &str, context: &Map<String, Value>) -> Result<Evaluation, EvaluationError> {
+    let flag = flags.get(key)
+        .ok_or_else(|| EvaluationError::NotFound(format!("flag {} not found", key)))?;
+    if flag.state == FlagState::Disabled {
+        return Err(EvaluationError::NotFound(format!("flag {} is disabled", key)));
+    }
+    let matched = flag.rules.iter()
+        .find(|rule| rule.when.iter().all(|(field, value)| context.get(field) == Some(value)));
+    let (variant, reason) = match matched {
+        Some(rule) => (&rule.variant, "TARGETING_MATCH"),
+        None if flag.rules.is_empty() => (&flag.default_variant, "STATIC"),
+        None => (&flag.default_variant, "DEFAULT"),
+    };
+    Ok(Evaluation { value: flag.variants[variant].clone(), variant: variant.clone(), reason })
+}
+
+/// The API a request for `path` is for, and the flag, if it names one
+enum Api {
+    Ofrep(Option<String>),
+    Flagd(String),
+}
+
+fn route(prefix: &str, path: &str) -> Option<Api> {
+    let path = path.strip_prefix(prefix)?;
+    if let Some(rest) = path.strip_prefix("/ofrep/v1/evaluate/flags") {
+        return match rest.strip_prefix('/') {
+            None if rest.is_empty() => Some(Api::Ofrep(None)),
+            Some(key) if !key.is_empty() && !key.contains('/') => Some(Api::Ofrep(Some(key.to_string()))),
+            _ => None,
+        };
+    }
+    path.strip_prefix("/flagd.evaluation.v1.Service/Resolve")
+        .filter(|method| ["Boolean", "String", "Int", "Float", "Object", "All"].contains(method))
+        .map(|method| Api::Flagd(method.to_string()))
+}
+
+/// The JSON reply of the flag provider APIs
+fn reply(status: StatusCode, body: Value) -> warp::reply::Response {
+    warp::reply::with_status(warp::reply::json(&body), status).into_response()
+}
+
+fn ofrep(flags: &BTreeMap<String, Flag>, key: Option<String>, context: &Map<String, Value>) -> warp::reply::Response {
+    let evaluated = |key: &str, evaluation: Evaluation| json!({
+        "key": key,
+        "value": evaluation.value,
+        "variant": evaluation.variant,
+        "reason": evaluation.reason,
+        "metadata": {},
+    });
+    match key {
+        Some(key) => match evaluate(flags, &key, context) {
+            Ok(evaluation) => reply(StatusCode::OK, evaluated(&key, evaluation)),
+            Err(EvaluationError::NotFound(details) | EvaluationError::TypeMismatch(details)) => {
+                reply(StatusCode::NOT_FOUND, json!({ "key": key, "errorCode": "FLAG_NOT_FOUND", "errorDetails": details }))
+            }
+        },
+        None => {
+            let evaluations: Vec<Value> = flags.keys()
+                .filter_map(|key| Some(evaluated(key, evaluate(flags, key, context).ok()?)))
+                .collect();
+            reply(StatusCode::OK, json!({ "flags": evaluations }))
+        }
+    }
+}
+
+fn flagd(flags: &BTreeMap<String, Flag>, method: &str, key: Option<&str>, context: &Map<String, Value>) -> warp::reply::Response {
+    // Connect's errors, with flagd's error codes
+    let error = |error| match error {
+        EvaluationError::NotFound(message) => {
+            reply(StatusCode::NOT_FOUND, json!({ "code": "not_found", "message": format!("FLAG_NOT_FOUND: {}", message) }))
+        }
+        EvaluationError::TypeMismatch(message) => {
+            reply(StatusCode::BAD_REQUEST, json!({ "code": "invalid_argument", "message": format!("TYPE_MISMATCH: {}", message) }))
+        }
+    };
+    if method == "All" {
+        // Values by type, as in flagd's `AnyFlag`
+        let evaluations: Map<String, Value> = flags.keys()
+            .filter_map(|key| {
+                let evaluation = evaluate(flags, key, context).ok()?;
+                let field = match value_type(&evaluation.value) {
+                    "Boolean" => "boolValue",
+                    "String" => "stringValue",
+                    "Object" => "objectValue",
+                    _ => "doubleValue",
+                };
+                let evaluated = json!({ "reason": evaluation.reason, "variant": evaluation.variant, field: evaluation.value, "metadata": {} });
+                Some((key.clone(), evaluated))
+            })
+            .collect();
+        return reply(StatusCode::OK, json!({ "flags": evaluations }));
+    }
+
+    let key = match key {
+        Some(key) => key,
+        None => return reply(StatusCode::BAD_REQUEST, json!({ "code": "invalid_argument", "message": "flagKey is missing" })),
+    };
+    let evaluation = match evaluate(flags, key, context) {
+        Ok(evaluation) => evaluation,
+        Err(e) => return error(e),
+    };
+    // An int answers a float's resolver too, as flagd's do
+    let value = match (method, value_type(&evaluation.value)) {
+        ("Float", "Int" | "Float") => evaluation.value.clone(),
+        // An int64 is a string in proto3's JSON
+        ("Int", "Int") => Value::String(evaluation.value.to_string()),
+        (method, found) if method == found => evaluation.value.clone(),
+        (method, found) => {
+            return error(EvaluationError::TypeMismatch(format!("flag {} is of type {}, not {}", key, found, method)));
+        }
+    };
+    reply(StatusCode::OK, json!({ "value": value, "variant": evaluation.variant, "reason": evaluation.reason, "metadata": {} }))
+}
+
+/// Evaluate the flags of `shared` as the flag provider APIs of `flags` have it, if the
+/// server acts as a provider. Other requests are rejected to be handled as usual.
+pub fn filter(
+    flags: Option<&FeatureFlags>,
+    shared: SharedFlags
+) -> impl Filter<Extract = (warp::reply::Response,), Error = warp::Rejection> + Clone {
+    let prefix = flags.map(|flags| flags.prefix.clone());
+
+    // Finding the API first leaves the body to the usual routes if it's for none
+    let taken = warp::post2()
+        .and(warp::path::full())
+        .and_then(move |path: warp::path::FullPath| {
+            prefix.as_deref().and_then(|prefix| route(prefix, path.as_str())).ok_or_else(warp::reject::not_found)
+        });
+
+    taken
+        .and(warp::body::concat())
+        .map(move |api: Api, body: warp::body::FullBody| {
+            let body = match body.bytes() {
+                [] => Value::Object(Map::new()),
+                bytes => match serde_json::from_slice::<Value>(bytes) {
+                    Ok(body @ Value::Object(_)) => body,
+                    _ => return reply(StatusCode::BAD_REQUEST, json!({ "errorCode": "PARSE_ERROR", "errorDetails": "the body isn't a JSON object" })),
+                },
+            };
+            let context = match body.get("context") {
+                Some(Value::Object(context)) => context.clone(),
+                _ => Map::new(),
+            };
+            let flags = lock(&shared);
+            match api {
+                Api::Ofrep(key) => ofrep(&flags, key, &context),
+                Api::Flagd(method) => flagd(&flags, &method, body.get("flagKey").and_then(Value::as_str), &context),
+            }
+        })
+}
+
+fn find_flags(registry: &Registry, port: u16) -> Result<SharedFlags, (StatusCode, String)> {
+    let server = registry.servers.get(&port)
+        .ok_or_else(|| (StatusCode::NOT_FOUND, format!("no server on port {}", port)))?;
+    server.flags.clone()
+        .ok_or_else(|| (StatusCode::CONFLICT, format!("server {} doesn't serve feature flags", port)))
+}
+
+/// The feature flags the HTTP server on `port` serves
+pub fn list_flags(database: Database, port: u16) -> warp::reply::Response {
+    match find_flags(&lock(&database), port) {
+        Ok(flags) => warp::reply::json(&*lock(&flags)).into_response(),
+        Err((status, error)) => error_reply(status, &error),
+    }
+}
+
+/// Add or replace the feature flag `key` of the HTTP server on `port`, taking effect on
+/// its next evaluation. Refused if the server would exceed its memory quota.
+pub fn put_flag(
+    database: Database,
+    port: u16,
+    key: String,
+    flag: Flag
+) -> Result<warp::reply::Response, warp::Rejection> {
+    if let Err(error) = check(&key, &flag) {
+        return Ok(error_reply(StatusCode::UNPROCESSABLE_ENTITY, &error));
+    }
+    let mut registry = lock(&database);
+    let flags = match find_flags(&registry, port) {
+        Ok(flags) => flags,
+        Err((status, error)) => return Ok(error_reply(status, &error)),
+    };
+    if let Some(server) = registry.servers.get(&port) {
+        let mut config = server.config.clone();
+        config.feature_flags.get_or_insert_with(FeatureFlags::default).flags.insert(key.clone(), flag.clone());
+        quota::check_memory(&registry, &config).map_err(warp::reject::custom)?;
+    }
+    let replaced = lock(&flags).insert(key.clone(), flag.clone()).is_some();
+    if let Some(feature_flags) = registry.servers.get_mut(&port).and_then(|server| server.config.feature_flags.as_mut()) {
+        feature_flags.flags.insert(key, flag.clone());
+    }
+    registry.record_change(port, ChangeType::Modified);
+
+    let status = if replaced { StatusCode::OK } else { StatusCode::CREATED };
+    Ok(warp::reply::with_status(warp::reply::json(&flag), status).into_response())
+}
+
+/// Remove the feature flag `key` of the HTTP server on `port`
+pub fn delete_flag(database: Database, port: u16, key: String) -> warp::reply::Response {
+    let mut registry = lock(&database);
+    let flags = match find_flags(&registry, port) {
+        Ok(flags) => flags,
+        Err((status, error)) => return error_reply(status, &error),
+    };
+    if lock(&flags).remove(&key).is_none() {
+        return error_reply(StatusCode::NOT_FOUND, &format!("server {} has no feature flag {}", port, key));
+    }
+    if let Some(feature_flags) = registry.servers.get_mut(&port).and_then(|server| server.config.feature_flags.as_mut()) {
+        feature_flags.flags.remove(&key);
+    }
+    registry.record_change(port, ChangeType::Modified);
+    StatusCode::NO_CONTENT.into_response()
+}
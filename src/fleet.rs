@@ -0,0 +1,54 @@
+use futures::Stream;
+use warp::Reply;
+
+use crate::{error_reply, Database, ServerJsonBody};
+use crate::error::lock;
+
+/// The servers of one instance sharing the registry
+#[derive(Debug, serde_derive::Serialize)]
+struct InstanceJsonBody {
+    instance: String,
+    servers: Vec<ServerJsonBody>,
+}
+
+/// List the servers of every instance sharing the store, as last saved by each
+pub fn list_fleet(database: Database) -> warp::reply::Response {
+    let fleet = match lock(&database).store.fleet() {
+        Ok(Some(fleet)) => fleet,
+        Ok(None) => return not_shared(),
+        Err(e) => return error_reply(warp::http::StatusCode::BAD_GATEWAY, &e.to_string()),
+    };
+
+    let instances: Vec<InstanceJsonBody> = fleet.into_iter()
+        .map(|(instance, servers)| InstanceJsonBody { instance, servers })
+        .collect();
+    warp::reply::json(&instances).into_response()
+}
+
+/// Stream the changes to the servers of every instance sharing the store as newline
+/// delimited JSON, each naming the instance it happened on
+pub fn watch_fleet(database: Database) -> warp::reply::Response {
+    let changes = match lock(&database).store.subscribe() {
+        Ok(Some(changes)) => changes,
+        Ok(None) => return not_shared(),
+        Err(e) => return error_reply(warp::http::StatusCode::BAD_GATEWAY, &e.to_string()),
+    };
+
+    let body = changes
+        .map(|mut change| {
+            change.push('\n');
+            change
+        })
+        .map_err(|()| -> std::io::Error { unreachable!("unbounded receivers never fail") });
+
+    let mut response = warp::http::Response::new(hyper::Body::wrap_stream(body));
+    response.headers_mut().insert(
+        warp::http::header::CONTENT_TYPE,
+        warp::http::header::HeaderValue::from_static("application/x-ndjson"),
+    );
+    response
+}
+
+fn not_shared() -> warp::reply::Response {
+    error_reply(warp::http::StatusCode::NOT_FOUND, "the registry isn't shared with other instances")
+}
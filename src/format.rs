@@ -0,0 +1,142 @@
+use warp::Reply;
+
+use crate::ServerJsonBody;
+
+/// Representations the admin API can respond with, picked by `Accept` or, for
+/// `GET /`, by `?format=` overriding it
+#[derive(Clone, Copy, Debug, PartialEq, serde_derive::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Format {
+    Json,
+    Yaml,
+    Csv,
+    /// Aligned columns for reading in a terminal, `?format=table` or `?format=text`
+    #[serde(alias = "table")]
+    Text,
+}
+
+impl Format {
+    /// Pick the most preferred supported media type of an `Accept` header, falling back
+    /// to JSON when there is no header or nothing in it is supported.
+    pub fn from_accept(accept: Option<&str>) -> Format {
+        let mut ranges: Vec<(&str, f32)> = accept.unwrap_or("")
+            .split(',')
+            .map(|range| {
+                let mut params = range.split(';').map(str::trim);
+                let media_type = params.next().unwrap_or("");
+                let quality = params
+                    .filter_map(|param| param.strip_prefix("q="))
+                    .find_map(|q| q.parse().ok())
+                    .unwrap_or(1.0);
+                (media_type, quality)
+            })
+            .collect();
+
+        // Stable, so equally preferred types keep the client's order
+        ranges.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal));
+
+        ranges.into_iter()
+            .filter(|(_, quality)| *quality > 0.0)
+            .find_map(|(media_type, _)| Format::from_media_type(media_type))
+            .unwrap_or(Format::Json)
+    }
+
+    fn from_media_type(media_type: &str) -> Option<Format> {
+        match media_type.to_ascii_lowercase().as_str() {
+            "application/json" | "application/*" | "*/*" => Some(Format::Json),
+            "application/yaml" | "application/x-yaml" | "text/yaml" => Some(Format::Yaml),
+            "text/csv" => Some(Format::Csv),
+            "text/plain" | "text/*" => Some(Format::Text),
+            _ => None,
+        }
+    }
+
+    fn content_type(self) -> &'static str {
+        match self {
+            Format::Json => "application/json",
+            Format::Yaml => "application/yaml",
+            Format::Csv => "text/csv",
+            Format::Text => "text/plain; charset=utf-8",
+        }
+    }
+}
+
+/// Render a list of servers
+pub fn servers(format: Format, servers: &[ServerJsonBody]) -> warp::reply::Response {
+    match format {
+        Format::Json => warp::reply::json(&servers).into_response(),
+        Format::Yaml => yaml(&servers),
+        Format::Csv | Format::Text => table(format, servers),
+    }
+}
+
+/// Render a single server
+pub fn server(format: Format, server: &ServerJsonBody) -> warp::reply::Response {
+    match format {
+        Format::Json => warp::reply::json(server).into_response(),
+        Format::Yaml => yaml(server),
+        Format::Csv | Format::Text => table(format, std::slice::from_ref(server)),
+    }
+}
+
+fn yaml<T: serde::Serialize>(value: &T) -> warp::reply::Response {
+    match serde_yaml::to_string(value) {
+        Ok(body) => with_content_type(Format::Yaml, body),
+        Err(e) => {
+            eprintln!("yaml serialization error: {}", e);
+            warp::http::StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+const COLUMNS: [&str; 7] = ["port", "kind", "status", "restarts", "created_at", "uptime_secs", "requests"];
+
+/// Render the scalar fields of `servers` as CSV or as aligned text columns
+fn table(format: Format, servers: &[ServerJsonBody]) -> warp::reply::Response {
+    let rows: Vec<[String; 7]> = servers.iter()
+        .map(|server| [
+            server.port.to_string(),
+            server.kind.name().to_string(),
+            server.status.name().to_string(),
+            server.restarts.to_string(),
+            server.created_at.to_string(),
+            server.uptime_secs.map(|uptime| uptime.to_string()).unwrap_or_default(),
+            // Answered since the server was (re)started, as HTTP servers count them
+            server.latency.as_ref().map(|latency| latency.requests.to_string()).unwrap_or_default(),
+        ])
+        .collect();
+
+    let mut body = String::new();
+
+    if format == Format::Csv {
+        body.push_str(&COLUMNS.join(","));
+        body.push('\n');
+        for row in &rows {
+            body.push_str(&row.join(","));
+            body.push('\n');
+        }
+    } else {
+        let mut widths = COLUMNS.map(str::len);
+        for row in &rows {
+            for (width, cell) in widths.iter_mut().zip(row) {
+                *width = (*width).max(cell.len());
+            }
+        }
+
+        let header = COLUMNS.map(str::to_uppercase);
+        for row in std::iter::once(&header).chain(&rows) {
+            let cells: Vec<String> = row.iter()
+                .zip(&widths)
+                .map(|(cell, width)| format!("{:<width$}", cell, width = width))
+                .collect();
+            body.push_str(cells.join("  ").trim_end());
+            body.push('\n');
+        }
+    }
+
+    with_content_type(format, body)
+}
+
+fn with_content_type(format: Format, body: String) -> warp::reply::Response {
+    warp::reply::with_header(body, "content-type", format.content_type()).into_response()
+}
@@ -0,0 +1,273 @@
+use futures::Future;
+use warp::Reply;
+use warp::http::StatusCode;
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::{act_on_ports, delete_ports, error_reply, schedule, unix_time, ApplyFailure, Database, ServerAction};
+use crate::error::lock;
+use crate::limits::UsageSummary;
+use crate::metrics::{LatencyHistogram, LatencySummary};
+
+/// Body of `POST /groups`
+#[derive(Debug, serde_derive::Deserialize)]
+pub struct GroupJsonBody {
+    name: String,
+    /// The servers in the group to begin with
+    #[serde(default)]
+    ports: BTreeSet<u16>,
+}
+
+/// A named set of servers, paused, restarted, deleted and summed up together, like the
+/// mocks of a stage of a test suite. Unlike labels, membership is managed explicitly,
+/// with `PUT` and `DELETE /groups/{name}/members/{port}`. A server may be in several
+/// groups, and leaves them when it's deleted.
+#[derive(Clone, Debug, serde_derive::Serialize)]
+pub struct Group {
+    name: String,
+    ports: BTreeSet<u16>,
+    /// Unix time the group was created
+    created_at: u64,
+}
+
+impl Group {
+    /// Take a server that's been deleted out of the group
+    pub fn leave(&mut self, port: u16) {
+        self.ports.remove(&port);
+    }
+}
+
+/// Query parameters of `DELETE /groups/{name}`
+#[derive(Debug, serde_derive::Deserialize)]
+pub struct DeleteGroupQuery {
+    /// Only delete the group, leaving its servers be
+    #[serde(default)]
+    keep_servers: bool,
+    /// Delete the root server too, if it's in the group
+    #[serde(default)]
+    force: bool,
+}
+
+/// What's done to every server of a group with `POST /groups/{name}/{action}`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GroupAction {
+    Server(ServerAction),
+    /// Start the servers anew, see `schedule::restart`, or start those that are stopped
+    Restart,
+}
+
+impl std::str::FromStr for GroupAction {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, ()> {
+        match s {
+            "restart" => Ok(GroupAction::Restart),
+            action => action.parse().map(GroupAction::Server),
+        }
+    }
+}
+
+/// What `GET /groups/{name}/stats` sums up
+#[derive(Debug, serde_derive::Serialize)]
+struct GroupStats {
+    name: String,
+    servers: usize,
+    /// How many of the servers are in each status
+    statuses: BTreeMap<String, usize>,
+    /// Connections and requests the in-process HTTP and TCP servers are serving
+    usage: UsageSummary,
+    /// Response times of the HTTP servers since they were (re)started, as if they were one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    latency: Option<LatencySummary>,
+    members: Vec<MemberStats>,
+}
+
+#[derive(Debug, serde_derive::Serialize)]
+struct MemberStats {
+    port: u16,
+    status: crate::ServerStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    uptime_secs: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    usage: Option<UsageSummary>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    latency: Option<LatencySummary>,
+}
+
+fn no_group(name: &str) -> warp::reply::Response {
+    error_reply(StatusCode::NOT_FOUND, &format!("no group named {}", name))
+}
+
+/// `GET /groups`: the groups by name
+pub fn list_groups(database: Database) -> warp::reply::Response {
+    let registry = lock(&database);
+    let groups: Vec<&Group> = registry.groups.values().collect();
+    warp::reply::json(&groups).into_response()
+}
+
+/// `POST /groups`: create a group of servers that exist, answering 409 if there's one
+/// with the name already
+pub fn create_group(database: Database, body: GroupJsonBody) -> warp::reply::Response {
+    let valid_name = !body.name.is_empty()
+        && body.name.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+    if !valid_name {
+        let error = format!("invalid group name {:?}, expected letters, digits, '-', '_' and '.'", body.name);
+        return error_reply(StatusCode::UNPROCESSABLE_ENTITY, &error);
+    }
+
+    let mut registry = lock(&database);
+    if registry.groups.contains_key(&body.name) {
+        return error_reply(StatusCode::CONFLICT, &format!("there's a group named {} already", body.name));
+    }
+    if let Some(port) = body.ports.iter().find(|port| !registry.servers.contains_key(port)) {
+        return error_reply(StatusCode::UNPROCESSABLE_ENTITY, &format!("no server on port {}", port));
+    }
+
+    let group = Group { name: body.name, ports: body.ports, created_at: unix_time() };
+    let reply = warp::reply::with_status(warp::reply::json(&group), StatusCode::CREATED).into_response();
+    registry.groups.insert(group.name.clone(), group);
+    reply
+}
+
+/// `GET /groups/{name}`
+pub fn get_group(database: Database, name: String) -> warp::reply::Response {
+    match lock(&database).groups.get(&name) {
+        Some(group) => warp::reply::json(group).into_response(),
+        None => no_group(&name),
+    }
+}
+
+/// `DELETE /groups/{name}`: delete the servers of a group, see `delete_ports`, and the
+/// group once it has none left, or only the group with `keep_servers`
+pub fn delete_group(
+    database: Database,
+    name: String,
+    own_port: u16,
+    query: DeleteGroupQuery
+) -> Box<dyn Future<Item = warp::reply::Response, Error = warp::Rejection> + Send> {
+    let ports: Vec<u16> = {
+        let mut registry = lock(&database);
+        let ports = match registry.groups.get(&name) {
+            Some(group) => group.ports.iter().cloned().collect(),
+            None => return Box::new(futures::future::ok(no_group(&name))),
+        };
+        if query.keep_servers {
+            registry.groups.remove(&name);
+            return Box::new(futures::future::ok(StatusCode::NO_CONTENT.into_response()));
+        }
+        ports
+    };
+
+    // The servers deleted leave the group, which is kept with those that weren't
+    Box::new(delete_ports(&database, &ports, own_port, query.force).map(move |summary| {
+        if summary.failed.is_empty() {
+            lock(&database).groups.remove(&name);
+        }
+        warp::reply::json(&summary).into_response()
+    }))
+}
+
+/// `PUT /groups/{name}/members/{port}`: add the server on `port` to a group
+pub fn add_member(database: Database, name: String, port: u16) -> warp::reply::Response {
+    let mut registry = lock(&database);
+    if !registry.servers.contains_key(&port) {
+        return error_reply(StatusCode::NOT_FOUND, &format!("no server on port {}", port));
+    }
+    match registry.groups.get_mut(&name) {
+        Some(group) => {
+            group.ports.insert(port);
+            warp::reply::json(group).into_response()
+        }
+        None => no_group(&name),
+    }
+}
+
+/// `DELETE /groups/{name}/members/{port}`: take the server on `port` out of a group,
+/// leaving it be
+pub fn remove_member(database: Database, name: String, port: u16) -> warp::reply::Response {
+    let mut registry = lock(&database);
+    let group = match registry.groups.get_mut(&name) {
+        Some(group) => group,
+        None => return no_group(&name),
+    };
+    if !group.ports.remove(&port) {
+        return error_reply(StatusCode::NOT_FOUND, &format!("server {} isn't in group {}", port, name));
+    }
+    warp::reply::json(group).into_response()
+}
+
+/// `POST /groups/{name}/{action}`: take `action` on every server of a group, listing
+/// those it couldn't be taken on with the reason
+pub fn group_action(database: Database, name: String, action: GroupAction) -> warp::reply::Response {
+    let mut registry = lock(&database);
+    let ports: Vec<u16> = match registry.groups.get(&name) {
+        Some(group) => group.ports.iter().cloned().collect(),
+        None => return no_group(&name),
+    };
+
+    let summary = match action {
+        GroupAction::Server(action) => act_on_ports(&database, &mut registry, &ports, action),
+        GroupAction::Restart => {
+            let (stopped, up): (Vec<u16>, Vec<u16>) = ports.iter()
+                .partition(|port| !registry.servers[port].status.can_become(crate::ServerStatus::Draining));
+            let mut summary = act_on_ports(&database, &mut registry, &stopped, ServerAction::Start);
+            for port in up {
+                match schedule::restart(&database, &mut registry, port) {
+                    Ok(()) => summary.affected.push(port),
+                    Err(e) => summary.failed.push(ApplyFailure { port, error: e.to_string() }),
+                }
+            }
+            summary.affected.sort();
+            summary
+        }
+    };
+    warp::reply::json(&summary).into_response()
+}
+
+/// `GET /groups/{name}/stats`: what the servers of a group are serving, summed up and
+/// server by server
+pub fn group_stats(database: Database, name: String) -> warp::reply::Response {
+    let registry = lock(&database);
+    let group = match registry.groups.get(&name) {
+        Some(group) => group,
+        None => return no_group(&name),
+    };
+
+    let mut statuses = BTreeMap::new();
+    let mut usage = UsageSummary::default();
+    let mut latency = LatencyHistogram::default();
+    let mut members = Vec::new();
+    for port in &group.ports {
+        let server = &registry.servers[port];
+        let body = server.json_body();
+        let status = serde_json::to_value(body.status).ok()
+            .and_then(|status| status.as_str().map(str::to_string))
+            .unwrap_or_default();
+        *statuses.entry(status).or_insert(0) += 1;
+        if let Some(member) = &body.usage {
+            usage.connections += member.connections;
+            usage.requests += member.requests;
+            usage.queued += member.queued;
+        }
+        if let Some(member) = &server.latency {
+            latency.merge(&lock(member));
+        }
+        members.push(MemberStats {
+            port: *port,
+            status: body.status,
+            uptime_secs: body.uptime_secs,
+            usage: body.usage,
+            latency: body.latency,
+        });
+    }
+
+    warp::reply::json(&GroupStats {
+        name: group.name.clone(),
+        servers: members.len(),
+        statuses,
+        usage,
+        latency: latency.summary(),
+        members,
+    })
+    .into_response()
+}
@@ -0,0 +1,250 @@
+use warp::Reply;
+
+use crate::{error_reply, Database};
+use crate::error::lock;
+use crate::journal::{self, Entry, Header};
+
+/// Query parameters of `GET /{port}/requests/export`
+#[derive(Debug, serde_derive::Deserialize)]
+pub struct ExportQuery {
+    #[serde(default = "default_format")]
+    format: String,
+}
+
+fn default_format() -> String {
+    "har".to_string()
+}
+
+/// An HTTP Archive, see <http://www.softwareishard.com/blog/har-12-spec/>
+#[derive(Debug, serde_derive::Serialize)]
+struct Har {
+    log: HarLog,
+}
+
+#[derive(Debug, serde_derive::Serialize)]
+struct HarLog {
+    version: &'static str,
+    creator: HarCreator,
+    entries: Vec<HarEntry>,
+}
+
+#[derive(Debug, serde_derive::Serialize)]
+struct HarCreator {
+    name: &'static str,
+    version: &'static str,
+}
+
+#[derive(Debug, serde_derive::Serialize)]
+#[serde(rename_all = "camelCase")]
+struct HarEntry {
+    started_date_time: String,
+    time: f64,
+    request: HarRequest,
+    response: HarResponse,
+    cache: HarCache,
+    timings: HarTimings,
+    #[serde(rename = "_id")]
+    id: u64,
+}
+
+#[derive(Debug, serde_derive::Serialize)]
+#[serde(rename_all = "camelCase")]
+struct HarRequest {
+    method: String,
+    url: String,
+    http_version: String,
+    cookies: Vec<HarNameValue>,
+    headers: Vec<HarNameValue>,
+    query_string: Vec<HarNameValue>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    post_data: Option<HarPostData>,
+    headers_size: i64,
+    body_size: i64,
+}
+
+#[derive(Debug, serde_derive::Serialize)]
+#[serde(rename_all = "camelCase")]
+struct HarResponse {
+    status: u16,
+    status_text: String,
+    http_version: String,
+    cookies: Vec<HarNameValue>,
+    headers: Vec<HarNameValue>,
+    content: HarContent,
+    #[serde(rename = "redirectURL")]
+    redirect_url: String,
+    headers_size: i64,
+    body_size: i64,
+}
+
+#[derive(Debug, serde_derive::Serialize)]
+struct HarNameValue {
+    name: String,
+    value: String,
+}
+
+#[derive(Debug, serde_derive::Serialize)]
+#[serde(rename_all = "camelCase")]
+struct HarPostData {
+    mime_type: String,
+    text: String,
+}
+
+#[derive(Debug, serde_derive::Serialize)]
+#[serde(rename_all = "camelCase")]
+struct HarContent {
+    size: usize,
+    mime_type: String,
+    text: String,
+    /// Whether `text` is cut short at the journal's `max_body_bytes`
+    #[serde(rename = "_truncated", skip_serializing_if = "std::ops::Not::not")]
+    truncated: bool,
+}
+
+#[derive(Debug, serde_derive::Serialize)]
+struct HarCache {}
+
+/// The journal only knows how long a request took, so that's all counted as waiting
+#[derive(Debug, serde_derive::Serialize)]
+struct HarTimings {
+    send: f64,
+    wait: f64,
+    receive: f64,
+}
+
+fn name_values(headers: &[Header]) -> Vec<HarNameValue> {
+    headers.iter()
+        .map(|header| HarNameValue { name: header.name.clone(), value: header.value.clone() })
+        .collect()
+}
+
+fn header<'a>(headers: &'a [Header], name: &str) -> Option<&'a str> {
+    headers.iter()
+        .find(|header| header.name.eq_ignore_ascii_case(name))
+        .map(|header| header.value.as_str())
+}
+
+/// `unix_ms` as an ISO 8601 date in UTC, like `2019-06-01T12:00:00.000Z`
+pub fn iso8601(unix_ms: u64) -> String {
+    let secs = unix_ms / 1000;
+    let days = (secs / 86400) as i64;
+    let time = secs % 86400;
+
+    // Civil from days, see <http://howardhinnant.github.io/date_algorithms.html>
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year, month, day, time / 3600, time % 3600 / 60, time % 60, unix_ms % 1000
+    )
+}
+
+fn har_entry(port: u16, entry: &Entry) -> HarEntry {
+    let request = &entry.request;
+    let host = header(&request.headers, "host").map_or_else(|| format!("127.0.0.1:{}", port), str::to_string);
+    let query_string = request.path.split_once('?')
+        .map(|(_, query)| {
+            query.split('&')
+                .filter(|pair| !pair.is_empty())
+                .map(|pair| {
+                    let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+                    HarNameValue { name: name.to_string(), value: value.to_string() }
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    let post_data = Some(()).filter(|_| request.body.size > 0).map(|_| HarPostData {
+        mime_type: header(&request.headers, "content-type").unwrap_or_default().to_string(),
+        text: request.body.text.clone(),
+    });
+
+    // Requests the connection closed on before they were answered have no response yet
+    let response = match &entry.response {
+        Some(response) => HarResponse {
+            status: response.status,
+            status_text: warp::http::StatusCode::from_u16(response.status).ok()
+                .and_then(|status| status.canonical_reason())
+                .unwrap_or_default()
+                .to_string(),
+            http_version: response.version.clone(),
+            cookies: Vec::new(),
+            headers: name_values(&response.headers),
+            content: HarContent {
+                size: response.body.size,
+                mime_type: header(&response.headers, "content-type").unwrap_or_default().to_string(),
+                text: response.body.text.clone(),
+                truncated: response.body.truncated,
+            },
+            redirect_url: header(&response.headers, "location").unwrap_or_default().to_string(),
+            headers_size: -1,
+            body_size: response.body.size as i64,
+        },
+        None => HarResponse {
+            status: 0,
+            status_text: String::new(),
+            http_version: request.version.clone(),
+            cookies: Vec::new(),
+            headers: Vec::new(),
+            content: HarContent { size: 0, mime_type: String::new(), text: String::new(), truncated: false },
+            redirect_url: String::new(),
+            headers_size: -1,
+            body_size: -1,
+        },
+    };
+
+    HarEntry {
+        started_date_time: iso8601(entry.started_at_ms),
+        time: entry.duration_ms,
+        request: HarRequest {
+            method: request.method.clone(),
+            url: format!("http://{}{}", host, request.path),
+            http_version: request.version.clone(),
+            cookies: Vec::new(),
+            headers: name_values(&request.headers),
+            query_string,
+            post_data,
+            headers_size: -1,
+            body_size: request.body.size as i64,
+        },
+        response,
+        cache: HarCache {},
+        timings: HarTimings { send: 0.0, wait: entry.duration_ms, receive: 0.0 },
+        id: entry.id,
+    }
+}
+
+/// The requests recorded by the HTTP server on `port` as a file to download. HAR is the
+/// only format. Bodies cut short by the journal are marked `_truncated`.
+pub fn export_requests(
+    database: Database,
+    port: u16,
+    query: ExportQuery
+) -> warp::reply::Response {
+    if query.format != "har" {
+        let error = format!("unsupported export format {:?}, only \"har\" is", query.format);
+        return error_reply(warp::http::StatusCode::UNPROCESSABLE_ENTITY, &error);
+    }
+    let journal = match journal::find_journal(&database, port) {
+        Ok(journal) => journal,
+        Err((status, error)) => return error_reply(status, &error),
+    };
+
+    let har = Har {
+        log: HarLog {
+            version: "1.2",
+            creator: HarCreator { name: env!("CARGO_PKG_NAME"), version: env!("CARGO_PKG_VERSION") },
+            entries: lock(&journal).entries().map(|entry| har_entry(port, entry)).collect(),
+        },
+    };
+    let reply = warp::reply::with_header(warp::reply::json(&har), "content-type", "application/json");
+    let disposition = format!("attachment; filename=\"requests-{}.har\"", port);
+    warp::reply::with_header(reply, "content-disposition", disposition).into_response()
+}
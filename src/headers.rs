@@ -0,0 +1,347 @@
+use futures::Future;
+use futures::future::Either;
+use warp::{Buf, Filter};
+use warp::http::header::{HeaderMap, HeaderName, HeaderValue};
+
+use std::collections::BTreeMap;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Instant;
+
+use crate::{error_reply, vhosts};
+use crate::activity::Activity;
+use crate::clients::{self, ClientPattern};
+use crate::clock::SharedClock;
+use crate::resolver::Resolver;
+use crate::error::{lock, Error};
+use crate::journal::{SharedJournal, UpstreamEntry};
+use crate::schema::{self, Schema};
+use crate::stubs::Hits;
+use crate::xml::{self, Matcher, XPathMatchers};
+
+/// A rule of an HTTP server forwarding the requests that carry a header to another server.
+/// Of the routes matching a request, the one with the highest `priority` takes it, then
+/// the most specific: one naming a value before one with a `*.` wildcard, before one
+/// taking any value. Routes as specific as each other are taken in order. Of those with
+/// `xpath` matchers, only the ones matching the request's body are.
+#[derive(Clone, Debug, PartialEq, serde_derive::Deserialize, serde_derive::Serialize)]
+pub struct HeaderRoute {
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub priority: i32,
+    /// Name of the header, case insensitive. `:authority`, as HTTP/2 names it, stands for
+    /// `Host`.
+    pub header: String,
+    /// The value the header must have, any by default. A `Host` header is matched without
+    /// case or port, and `*.api.test` has it match any subdomain of `api.test`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub value: Option<String>,
+    /// The clients whose requests are forwarded, any by default
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub clients: Vec<ClientPattern>,
+    /// Port of the server on this host answering the requests
+    pub port: u16,
+    /// A JSON Schema the bodies of the requests must conform to, see `schema::Schema`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub schema: Option<serde_json::Value>,
+    /// What the XML bodies of the requests must match, see `xml::XPathMatchers`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub xpath: Option<XPathMatchers>,
+    #[serde(flatten)]
+    pub activity: Activity,
+}
+
+fn is_zero(priority: &i32) -> bool {
+    *priority == 0
+}
+
+impl HeaderRoute {
+    /// Ranks routes of the same priority, the more specific above
+    fn specificity(&self) -> u8 {
+        match &self.value {
+            Some(value) if value.starts_with("*.") => 1,
+            Some(_) => 2,
+            None => 0,
+        }
+    }
+
+    /// Name of the header matched, `Host` for `:authority`
+    fn header_name(&self) -> &str {
+        match self.header.as_str() {
+            ":authority" => "host",
+            header => header,
+        }
+    }
+
+    pub fn matches(&self, headers: &HeaderMap, client: Option<SocketAddr>) -> bool {
+        let host = self.header_name().eq_ignore_ascii_case("host");
+        clients::matches(&self.clients, client) && headers.get_all(self.header_name()).iter()
+            .any(|value| self.value.as_ref().is_none_or(|expected| match value.to_str() {
+                Ok(value) if host => vhosts::host_matches(expected, value),
+                _ => value.as_bytes() == expected.as_bytes(),
+            }))
+    }
+}
+
+fn invalid(error: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, error)
+}
+
+/// The `response_headers` of a server, checked to be valid
+pub fn response_headers(headers: &BTreeMap<String, String>) -> io::Result<HeaderMap> {
+    let mut map = HeaderMap::new();
+    for (name, value) in headers {
+        let name = HeaderName::from_bytes(name.as_bytes())
+            .map_err(|_| invalid(format!("invalid response header name {:?}", name)))?;
+        let value = HeaderValue::from_str(value)
+            .map_err(|_| invalid(format!("invalid value of response header {}", name)))?;
+        map.insert(name, value);
+    }
+    Ok(map)
+}
+
+/// Forward requests matching one of `routes` active by `clock`, the first that does, and
+/// answer with what the server it names does. Other requests are rejected to be handled
+/// as usual.
+pub fn forward(port: u16, routes: &[HeaderRoute], clock: SharedClock, hits: Arc<Hits>) -> io::Result<Forward> {
+    for route in routes {
+        HeaderName::from_bytes(route.header_name().as_bytes())
+            .map_err(|_| invalid(format!("invalid header name {:?} of a header route", route.header)))?;
+        if route.port == port {
+            return Err(invalid("a header route can't forward to its own server".to_string()));
+        }
+        route.activity.check()?;
+    }
+    let routes: Arc<Vec<CompiledRoute>> = Arc::new(ordered(routes).into_iter()
+        .map(|(index, route)| Ok(CompiledRoute {
+            index,
+            schema: schema::compile(route.schema.as_ref())?,
+            xpath: xml::compile(route.xpath.as_ref())?,
+            route: route.clone(),
+        }))
+        .collect::<io::Result<_>>()?);
+
+    Ok(Forward { routes, clock, hits })
+}
+
+/// A header route with its position in the server's routes, and what it checks bodies with
+struct CompiledRoute {
+    index: usize,
+    route: HeaderRoute,
+    schema: Option<Schema>,
+    xpath: Option<Matcher>,
+}
+
+/// The header routes of a server, checked to be valid and in the order they're tried,
+/// see `Forward::filter`
+#[derive(Clone)]
+pub struct Forward {
+    routes: Arc<Vec<CompiledRoute>>,
+    clock: SharedClock,
+    hits: Arc<Hits>,
+}
+
+impl Forward {
+    /// Forward the requests of the connection from `client` a route takes to its server,
+    /// counting them, and keeping them in `journal`. Other requests are rejected to be
+    /// handled as usual.
+    ///
+    /// The body of a request is only read when the first route matching its headers has
+    /// XPath matchers. If then no route matching its headers matches its body either, it's
+    /// answered with 404, as the usual routes can't have the body anymore.
+    pub fn filter(
+        &self,
+        client: Option<SocketAddr>,
+        journal: Option<SharedJournal>
+    ) -> impl Filter<Extract = (warp::reply::Response,), Error = warp::Rejection> + Clone {
+        let Forward { routes, clock, hits } = self.clone();
+        // Looking at the headers first leaves the body to the usual routes if nothing matches
+        let (matching_routes, plain_routes, xml_routes) = (routes.clone(), routes.clone(), routes.clone());
+        let started_ms = hits.started_ms;
+        let matching = warp::header::headers_cloned().and_then(move |headers: HeaderMap| {
+            let now_ms = lock(&clock).now_ms();
+            let matching: Vec<usize> = matching_routes.iter().enumerate()
+                .filter(|(_, compiled)| compiled.route.activity.is_active(started_ms, now_ms) && compiled.route.matches(&headers, client))
+                .map(|(position, _)| position)
+                .collect();
+            match matching.is_empty() {
+                true => Err(warp::reject::not_found()),
+                false => Ok(matching),
+            }
+        });
+        let plain = matching.clone().and_then(move |matching: Vec<usize>| match plain_routes[matching[0]].xpath {
+            Some(_) => Err(warp::reject::not_found()),
+            None => Ok((matching[0], None)),
+        });
+        let xml = matching.and(warp::body::concat()).and_then(move |matching: Vec<usize>, body: warp::body::FullBody| {
+            let body = body.bytes().to_vec();
+            let position = matching.into_iter()
+                .find(|position| xml_routes[*position].xpath.as_ref().is_none_or(|xpath| xpath.matches(&body)))
+                .ok_or_else(|| warp::reject::custom(Error::UnmatchedBody))?;
+            Ok::<_, warp::Rejection>((position, Some(body)))
+        });
+        relay(plain.or(xml).unify().map(move |(position, body): (usize, Option<Vec<u8>>)| {
+            let compiled = &routes[position];
+            hits.header_route(compiled.index);
+            Target::server(compiled.route.port).checked(compiled.schema.clone()).with_body(body)
+        }), journal)
+    }
+}
+
+/// `routes` with their positions, in the order they're tried: by priority, then
+/// specificity, then position
+pub fn ordered(routes: &[HeaderRoute]) -> Vec<(usize, &HeaderRoute)> {
+    let mut ordered: Vec<(usize, &HeaderRoute)> = routes.iter().enumerate().collect();
+    ordered.sort_by_key(|(index, route)| (std::cmp::Reverse(route.priority), std::cmp::Reverse(route.specificity()), *index));
+    ordered
+}
+
+/// Where `relay` forwards a request to
+pub struct Target {
+    /// The URL the request's path is appended to
+    base: String,
+    /// What the target is told by in errors
+    name: String,
+    /// What the request's body must conform to, if anything
+    schema: Option<Schema>,
+    /// A prefix of the request's path left out of the one forwarded
+    prefix: Option<String>,
+    /// The request's body, if it was read to pick the target
+    body: Option<Vec<u8>>,
+}
+
+impl Target {
+    /// The server on `port` on this host
+    pub fn server(port: u16) -> Target {
+        Target { base: format!("http://127.0.0.1:{}", port), name: format!("server {}", port), schema: None, prefix: None, body: None }
+    }
+
+    /// `upstream`, an `http://` URL without a path
+    pub fn upstream(upstream: String) -> Target {
+        Target { name: upstream.clone(), base: upstream, schema: None, prefix: None, body: None }
+    }
+
+    /// Only forward request bodies conforming to `schema`
+    pub fn checked(self, schema: Option<Schema>) -> Target {
+        Target { schema, ..self }
+    }
+
+    /// Forward `body` as that of the request, if it was read already
+    pub fn with_body(self, body: Option<Vec<u8>>) -> Target {
+        Target { body, ..self }
+    }
+
+    /// Forward requests without `prefix`, a path like `/s/9000`, leading their paths.
+    /// It's passed on as `X-Forwarded-Prefix`.
+    pub fn stripping(self, prefix: String) -> Target {
+        Target { prefix: Some(prefix), ..self }
+    }
+}
+
+/// Forward requests to the port on this host `target` picks, answering with what the
+/// server there does. The original `Host` is passed on as `X-Forwarded-Host`.
+pub fn proxy<F>(
+    target: F,
+    journal: Option<SharedJournal>
+) -> impl Filter<Extract = (warp::reply::Response,), Error = warp::Rejection> + Clone
+where
+    F: Filter<Extract = (u16,), Error = warp::Rejection> + Clone + Send + Sync + 'static,
+{
+    relay(target.map(Target::server), journal)
+}
+
+/// Forward requests to the `Target` `target` picks, answering with what it does, or with
+/// 422 if the body doesn't conform to its schema. The original `Host` is passed on as
+/// `X-Forwarded-Host`, and any prefix stripped as `X-Forwarded-Prefix`. The requests
+/// are kept in `journal` as they're sent on, see `journal::UpstreamEntry`.
+pub fn relay<F>(
+    target: F,
+    journal: Option<SharedJournal>
+) -> impl Filter<Extract = (warp::reply::Response,), Error = warp::Rejection> + Clone
+where
+    F: Filter<Extract = (Target,), Error = warp::Rejection> + Clone + Send + Sync + 'static,
+{
+    relay_resolving(target, Resolver::default(), journal)
+}
+
+/// Like `relay`, resolving the host names of targets with `resolver`
+pub fn relay_resolving<F>(
+    target: F,
+    resolver: Resolver,
+    journal: Option<SharedJournal>
+) -> impl Filter<Extract = (warp::reply::Response,), Error = warp::Rejection> + Clone
+where
+    F: Filter<Extract = (Target,), Error = warp::Rejection> + Clone + Send + Sync + 'static,
+{
+    let client = hyper::Client::builder().build(hyper::client::HttpConnector::new_with_resolver(resolver));
+    let query = warp::query::raw()
+        .or(warp::any().map(String::new))
+        .unify();
+
+    target
+        .and(warp::method())
+        .and(warp::path::full())
+        .and(query)
+        .and(warp::header::headers_cloned())
+        .and(unread_body())
+        .and_then(move |mut target: Target, method: warp::http::Method, path: warp::path::FullPath, query: String, mut headers: HeaderMap, body: Option<Vec<u8>>| {
+            let body = target.body.take().or(body).unwrap_or_default();
+            if let Some(violation) = target.schema.as_ref().and_then(|schema| schema.check(&body)) {
+                return Either::A(futures::future::ok(violation));
+            }
+            let query = if query.is_empty() { String::new() } else { format!("?{}", query) };
+            if let Some(host) = headers.remove(hyper::header::HOST) {
+                headers.insert("x-forwarded-host", host);
+            }
+            headers.remove(hyper::header::TRANSFER_ENCODING);
+            headers.remove(hyper::header::CONNECTION);
+            let mut path = path.as_str();
+            if let Some(prefix) = &target.prefix {
+                path = path.strip_prefix(prefix.as_str()).unwrap_or(path);
+                if let Ok(prefix) = HeaderValue::from_str(prefix) {
+                    headers.insert("x-forwarded-prefix", prefix);
+                }
+            }
+            let path = if path.starts_with('/') { path.to_string() } else { format!("/{}", path) };
+            // As hyper would, so the journal has it
+            if let Ok(host) = HeaderValue::from_str(target.base.trim_start_matches("http://")) {
+                headers.insert(hyper::header::HOST, host);
+            }
+
+            let url = format!("{}{}{}", target.base, path, query);
+            let sent = journal.clone().map(|journal| {
+                (journal, UpstreamEntry::sent(&target.name, method.as_str(), &url, &headers), Instant::now())
+            });
+            let mut request = hyper::Request::builder();
+            request.method(method).uri(url);
+            let request = request.body(hyper::Body::from(body.clone()));
+            let response = futures::future::result(request)
+                .map_err(|e| e.to_string())
+                .and_then({
+                    let client = client.clone();
+                    move |mut request| {
+                        *request.headers_mut() = headers;
+                        client.request(request).map_err(|e| e.to_string())
+                    }
+                });
+            let name = target.name;
+            Either::B(response.then(move |response| {
+                if let Some((journal, entry, started)) = sent {
+                    let answer = response.as_ref().map(|response| response.status().as_u16()).map_err(String::clone);
+                    lock(&journal).record_upstream(entry.answered(started, answer), &body);
+                }
+                Ok::<_, warp::Rejection>(response.unwrap_or_else(|e| {
+                    let error = format!("failed to forward to {}: {}", name, e);
+                    error_reply(warp::http::StatusCode::BAD_GATEWAY, &error)
+                }))
+            }))
+        })
+}
+
+/// The body of a request, unless it was read already to pick its target
+fn unread_body() -> impl Filter<Extract = (Option<Vec<u8>>,), Error = warp::Rejection> + Clone {
+    warp::body::concat()
+        .map(|body: warp::body::FullBody| Some(body.bytes().to_vec()))
+        .or(warp::any().map(|| None))
+        .unify()
+}
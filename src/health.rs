@@ -0,0 +1,227 @@
+use futures::{Future, Stream};
+use tokio::timer::{Interval, Timeout};
+
+use std::time::{Duration, Instant};
+
+use crate::{Database, ServerKind, ServerStatus};
+use crate::error::lock;
+
+/// Query parameters of `GET /healthz`
+#[derive(Debug, serde_derive::Deserialize)]
+pub struct HealthQuery {
+    /// Path requested from HTTP servers
+    #[serde(default = "default_path")]
+    path: String,
+    /// How many servers are probed at a time
+    #[serde(default = "default_concurrency")]
+    concurrency: usize,
+    #[serde(default = "default_timeout_ms")]
+    timeout_ms: u64,
+}
+
+fn default_path() -> String {
+    "/".to_string()
+}
+
+fn default_concurrency() -> usize {
+    8
+}
+
+fn default_timeout_ms() -> u64 {
+    1000
+}
+
+/// Health of a single server in the `GET /healthz` report
+#[derive(Debug, serde_derive::Serialize)]
+struct ServerHealth {
+    port: u16,
+    kind: &'static str,
+    status: ServerStatus,
+    healthy: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    latency_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+#[derive(Debug, serde_derive::Serialize)]
+struct HealthReport {
+    healthy: bool,
+    servers: Vec<ServerHealth>,
+}
+
+/// How a server is checked
+enum Probe {
+    /// `GET` the probe path, expecting a success status
+    Http,
+    /// Open a connection
+    Tcp,
+    /// Nothing to check beyond the server being up
+    None,
+}
+
+/// Probe every server and report their health, with 200 if all of them are healthy and
+/// 503 otherwise, see `probe_all`
+pub fn healthz(
+    database: Database,
+    query: HealthQuery
+) -> impl Future<Item = warp::reply::Response, Error = warp::Rejection> {
+    let timeout = Duration::from_millis(query.timeout_ms);
+    probe_all(&database, query.path, query.concurrency, timeout)
+        .map(|servers| {
+            let healthy = servers.iter().all(|server| server.healthy);
+            let status = if healthy {
+                warp::http::StatusCode::OK
+            } else {
+                warp::http::StatusCode::SERVICE_UNAVAILABLE
+            };
+            let report = HealthReport { healthy, servers };
+            warp::reply::Reply::into_response(warp::reply::with_status(warp::reply::json(&report), status))
+        })
+        .map_err(|()| warp::reject::not_found())
+}
+
+/// Probe every server, `concurrency` at a time, by port. HTTP servers must answer `path`
+/// with a success status and TCP listeners, including containers, must accept a
+/// connection. UDP and DNS servers are healthy when running. Only running servers are
+/// probed; others are unhealthy.
+fn probe_all(
+    database: &Database,
+    path: String,
+    concurrency: usize,
+    timeout: Duration
+) -> impl Future<Item = Vec<ServerHealth>, Error = ()> {
+    let registry = lock(database);
+    let mut targets: Vec<(u16, &'static str, ServerStatus, Probe)> = registry
+        .servers.values()
+        .map(|server| {
+            let probe = match server.config.kind {
+                // Probing over TLS isn't supported, so those servers are just connected to
+                ServerKind::Http if server.config.tls.is_some() => Probe::Tcp,
+                ServerKind::Http => Probe::Http,
+                ServerKind::Tcp(_) | ServerKind::Smtp(_) | ServerKind::Docker(_) => Probe::Tcp,
+                ServerKind::Udp(_) | ServerKind::Dns(_) => Probe::None,
+            };
+            (server.config.port, server.config.kind.name(), server.status, probe)
+        })
+        .collect();
+    targets.sort_by_key(|(port, ..)| *port);
+    // In-process HTTP servers serve the admin API, which may require its token
+    let authorization = registry.shared.admin_token.as_ref().map(|token| format!("Bearer {}", token));
+    drop(registry);
+
+    futures::stream::iter_ok(targets)
+        .map(move |(port, kind, status, probe)| {
+            let started = Instant::now();
+            let result: Box<dyn Future<Item = (), Error = String> + Send> = if status != ServerStatus::Running {
+                Box::new(futures::future::err(format!("server is {}", status.name())))
+            } else {
+                match probe {
+                    Probe::Http => Box::new(probe_http(port, &path, authorization.as_deref(), timeout)),
+                    Probe::Tcp => Box::new(probe_tcp(port, timeout)),
+                    Probe::None => Box::new(futures::future::ok(())),
+                }
+            };
+
+            result.then(move |result| -> Result<ServerHealth, ()> {
+                let latency_ms = Some(started.elapsed().as_millis() as u64)
+                    .filter(|_| status == ServerStatus::Running);
+                Ok(ServerHealth {
+                    port,
+                    kind,
+                    status,
+                    healthy: result.is_ok(),
+                    latency_ms,
+                    error: result.err(),
+                })
+            })
+        })
+        .buffered(concurrency.max(1))
+        .collect()
+}
+
+/// How often the servers are probed in the background, from `HEALTH_CHECK_INTERVAL_SECS`.
+/// Left unset, they're only probed by `GET /healthz`, as the probes of HTTP servers are
+/// recorded like any other request.
+pub fn interval_from_env() -> Result<Option<Duration>, String> {
+    match std::env::var("HEALTH_CHECK_INTERVAL_SECS") {
+        Ok(secs) => secs.parse::<u64>().ok()
+            .filter(|secs| *secs > 0)
+            .map(|secs| Some(Duration::from_secs(secs)))
+            .ok_or_else(|| format!("invalid HEALTH_CHECK_INTERVAL_SECS {:?}", secs)),
+        Err(_) => Ok(None),
+    }
+}
+
+/// Probe every server each `interval`, like `GET /healthz` with its defaults, keeping
+/// whether each was healthy in `Registry::health` and logging those that became
+/// unhealthy or recovered, run as a `background::Background` task
+pub fn check_periodically(database: Database, interval: Duration) -> impl Future<Item = (), Error = ()> {
+    Interval::new_interval(interval)
+        .map_err(|e| eprintln!("health checker timer error: {}", e))
+        .for_each(move |_| {
+            let database = database.clone();
+            probe_all(&database, default_path(), default_concurrency(), Duration::from_millis(default_timeout_ms()))
+                .map(move |servers| {
+                    let mut registry = lock(&database);
+                    registry.health.retain(|port, _| servers.iter().any(|server| server.port == *port));
+                    for server in servers {
+                        let was_healthy = registry.health.insert(server.port, server.healthy);
+                        match (was_healthy, server.healthy) {
+                            (Some(true) | None, false) => eprintln!(
+                                "server {} is unhealthy: {}",
+                                server.port,
+                                server.error.as_deref().unwrap_or_default()
+                            ),
+                            (Some(false), true) => eprintln!("server {} is healthy again", server.port),
+                            _ => {}
+                        }
+                    }
+                })
+        })
+}
+
+fn probe_http(
+    port: u16,
+    path: &str,
+    authorization: Option<&str>,
+    timeout: Duration
+) -> impl Future<Item = (), Error = String> {
+    let mut request = hyper::Request::get(format!("http://127.0.0.1:{}{}", port, path));
+    if let Some(authorization) = authorization {
+        request.header(hyper::header::AUTHORIZATION, authorization);
+    }
+    let request = futures::future::result(request.body(hyper::Body::empty()))
+        .map_err(|e| format!("invalid probe path: {}", e))
+        .and_then(|request| hyper::Client::new().request(request).map_err(|e| e.to_string()))
+        .and_then(|response| {
+            if response.status().is_success() {
+                Ok(())
+            } else {
+                Err(format!("probe answered {}", response.status()))
+            }
+        });
+
+    with_timeout(request, timeout)
+}
+
+fn probe_tcp(port: u16, timeout: Duration) -> impl Future<Item = (), Error = String> {
+    let connect = tokio::net::TcpStream::connect(&([127, 0, 0, 1], port).into())
+        .map(|_| ())
+        .map_err(|e| e.to_string());
+
+    with_timeout(connect, timeout)
+}
+
+fn with_timeout<F>(probe: F, timeout: Duration) -> impl Future<Item = (), Error = String>
+where
+    F: Future<Item = (), Error = String>,
+{
+    Timeout::new(probe, timeout).map_err(|e| {
+        if e.is_elapsed() {
+            "probe timed out".to_string()
+        } else {
+            e.into_inner().unwrap_or_else(|| "probe timer failed".to_string())
+        }
+    })
+}
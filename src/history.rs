@@ -0,0 +1,86 @@
+use warp::Reply;
+
+use std::collections::BTreeMap;
+
+use crate::{error_reply, Database, ServerJsonBody};
+use crate::error::lock;
+use crate::watch::{Change, ChangeType};
+
+/// Every change published to the feed, oldest first. Unlike the feed's own history it's
+/// never trimmed, so the state of the registry at any revision can be derived from it.
+#[derive(Default)]
+pub struct EventLog {
+    events: Vec<Change>,
+}
+
+impl EventLog {
+    pub fn append(&mut self, change: Change) {
+        self.events.push(change);
+    }
+
+    /// The servers registered as of `revision`, by folding the changes up to it
+    pub fn fold(&self, revision: u64) -> BTreeMap<u16, ServerJsonBody> {
+        let mut servers = BTreeMap::new();
+        for change in self.events.iter().take_while(|change| change.revision <= revision) {
+            match change.change {
+                ChangeType::Added | ChangeType::Modified => {
+                    servers.insert(change.server.port, change.server.clone());
+                }
+                ChangeType::Deleted => {
+                    servers.remove(&change.server.port);
+                }
+            }
+        }
+        servers
+    }
+}
+
+/// Query parameters of `GET /history`
+#[derive(Debug, serde_derive::Deserialize)]
+pub struct HistoryQuery {
+    /// The revision to go back to, by default the latest
+    at: Option<u64>,
+}
+
+#[derive(Debug, serde_derive::Serialize)]
+struct FleetHistoryJsonBody {
+    revision: u64,
+    servers: Vec<ServerJsonBody>,
+}
+
+#[derive(Debug, serde_derive::Serialize)]
+struct ServerHistoryJsonBody<'a> {
+    port: u16,
+    events: Vec<&'a Change>,
+}
+
+/// The servers as they were at revision `at`
+pub fn fleet_history(
+    database: Database,
+    query: HistoryQuery
+) -> warp::reply::Response {
+    let registry = lock(&database);
+    let revision = query.at.unwrap_or_else(|| registry.feed.revision());
+
+    let servers = registry.feed.log().fold(revision).into_values().collect();
+    warp::reply::json(&FleetHistoryJsonBody { revision, servers }).into_response()
+}
+
+/// Every change of whatever servers were ever registered on `port`, oldest first,
+/// including those of servers since deleted
+pub fn server_history(
+    database: Database,
+    port: u16
+) -> warp::reply::Response {
+    let registry = lock(&database);
+
+    let events: Vec<&Change> = registry.feed.log().events.iter()
+        .filter(|change| change.server.port == port)
+        .collect();
+    if events.is_empty() {
+        let error = format!("no server was ever registered on port {}", port);
+        return error_reply(warp::http::StatusCode::NOT_FOUND, &error);
+    }
+
+    warp::reply::json(&ServerHistoryJsonBody { port, events }).into_response()
+}
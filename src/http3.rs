@@ -0,0 +1,181 @@
+use bytes1::{Buf, Bytes};
+use openssl::pkey::PKey;
+use openssl::x509::X509;
+use quinn::crypto::rustls::QuicServerConfig;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+use warp::http::header::{HeaderMap, HeaderValue, ALT_SVC};
+
+use std::convert::TryFrom;
+use std::io;
+use std::sync::{Arc, OnceLock};
+
+use crate::ErrorJsonBody;
+use crate::tls::Tls;
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// What an HTTP/3 request is answered with
+type Forwarded = (u16, Vec<(String, Vec<u8>)>, Vec<u8>);
+
+/// Headers of HTTP/1.1 responses that mean nothing, and aren't allowed, in HTTP/3
+const CONNECTION_HEADERS: &[&str] = &["connection", "keep-alive", "proxy-connection", "transfer-encoding", "upgrade"];
+
+/// The runtime every HTTP/3 listener runs on, as quinn needs tokio 1
+fn runtime() -> &'static tokio1::runtime::Runtime {
+    static RUNTIME: OnceLock<tokio1::runtime::Runtime> = OnceLock::new();
+    RUNTIME.get_or_init(|| {
+        tokio1::runtime::Builder::new_multi_thread()
+            .worker_threads(2)
+            .thread_name("http3")
+            .enable_all()
+            .build()
+            .expect("failed to start the HTTP/3 runtime")
+    })
+}
+
+/// An HTTP/3 listener, which stops when dropped along with the server it serves beside
+pub struct Listener {
+    endpoint: quinn::Endpoint,
+    port: u16,
+}
+
+impl Listener {
+    /// `headers` to respond over TCP with, telling clients of this listener unless they
+    /// already say something else
+    pub fn advertise(&self, mut headers: HeaderMap) -> HeaderMap {
+        if let Ok(alt_svc) = HeaderValue::from_str(&format!("h3=\":{}\"; ma=86400", self.port)) {
+            headers.entry(ALT_SVC).map(|entry| entry.or_insert(alt_svc)).ok();
+        }
+        headers
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        self.endpoint.close(0u32.into(), b"server stopped");
+    }
+}
+
+fn crypto(tls: &Tls) -> Result<rustls::ServerConfig, BoxError> {
+    let (chain, key) = &tls.identity_pem;
+    let chain = X509::stack_from_pem(chain.as_bytes())?
+        .iter()
+        .map(|certificate| Ok(CertificateDer::from(certificate.to_der()?)))
+        .collect::<Result<Vec<_>, openssl::error::ErrorStack>>()?;
+    let key = PrivatePkcs8KeyDer::from(PKey::private_key_from_pem(key.as_bytes())?.private_key_to_pkcs8()?);
+
+    let mut config = rustls::ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+        .with_protocol_versions(&[&rustls::version::TLS13])?
+        .with_no_client_auth()
+        .with_single_cert(chain, PrivateKeyDer::Pkcs8(key))?;
+    config.alpn_protocols = vec![b"h3".to_vec()];
+    Ok(config)
+}
+
+/// Serve HTTP/3 on the UDP `port` with the certificate of `tls`, answering requests with
+/// what the server on the TCP `port` does. Requests are forwarded to it over TLS, so its
+/// journal records them with `X-Forwarded-Proto: h3`.
+pub fn listen(port: u16, tls: &Tls) -> io::Result<Listener> {
+    let crypto = crypto(tls).map_err(io::Error::other)?;
+    let crypto = QuicServerConfig::try_from(crypto).map_err(io::Error::other)?;
+    let config = quinn::ServerConfig::with_crypto(Arc::new(crypto));
+
+    // The connection is to this very server, whose certificate may be one no CA signed
+    let client = reqwest::Client::builder()
+        .danger_accept_invalid_certs(true)
+        .redirect(reqwest::RedirectPolicy::none())
+        .gzip(false)
+        .build()
+        .map_err(io::Error::other)?;
+
+    let _runtime = runtime().enter();
+    let endpoint = quinn::Endpoint::server(config, ([127, 0, 0, 1], port).into())?;
+    runtime().spawn(accept(endpoint.clone(), port, client));
+    Ok(Listener { endpoint, port })
+}
+
+async fn accept(endpoint: quinn::Endpoint, port: u16, client: reqwest::Client) {
+    while let Some(incoming) = endpoint.accept().await {
+        let client = client.clone();
+        tokio1::spawn(async move {
+            if let Err(e) = serve_connection(incoming, port, client).await {
+                eprintln!("HTTP/3 connection of server {} failed: {}", port, e);
+            }
+        });
+    }
+}
+
+async fn serve_connection(incoming: quinn::Incoming, port: u16, client: reqwest::Client) -> Result<(), BoxError> {
+    let connection = h3_quinn::Connection::new(incoming.await?);
+    let mut connection = h3::server::Connection::<_, Bytes>::new(connection).await?;
+
+    loop {
+        let resolver = match connection.accept().await {
+            Ok(Some(resolver)) => resolver,
+            Ok(None) => return Ok(()),
+            Err(e) if e.is_h3_no_error() => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+        let client = client.clone();
+        tokio1::spawn(async move {
+            let served = async {
+                let (request, mut stream) = resolver.resolve_request().await?;
+
+                let mut body = Vec::new();
+                while let Some(mut chunk) = stream.recv_data().await? {
+                    while chunk.has_remaining() {
+                        let part = chunk.chunk();
+                        body.extend_from_slice(part);
+                        let read = part.len();
+                        chunk.advance(read);
+                    }
+                }
+
+                let forwarded = tokio1::task::spawn_blocking(move || forward(&client, port, &request, body)).await?;
+                let (status, headers, body) = forwarded.unwrap_or_else(|e| {
+                    let error = ErrorJsonBody { error: format!("failed to forward to server {}: {}", port, e) };
+                    let headers = vec![("content-type".to_string(), b"application/json".to_vec())];
+                    (502, headers, serde_json::to_vec(&error).unwrap_or_default())
+                });
+
+                let mut response = http1::Response::builder().status(status);
+                for (name, value) in &headers {
+                    if !CONNECTION_HEADERS.contains(&name.as_str()) {
+                        response = response.header(name.as_str(), value.as_slice());
+                    }
+                }
+                stream.send_response(response.body(())?).await?;
+                if !body.is_empty() {
+                    stream.send_data(Bytes::from(body)).await?;
+                }
+                stream.finish().await?;
+                Ok::<_, BoxError>(())
+            };
+            if let Err(e) = served.await {
+                eprintln!("HTTP/3 request to server {} failed: {}", port, e);
+            }
+        });
+    }
+}
+
+/// Have the server on `port` answer `request`, blocking until it has
+fn forward(client: &reqwest::Client, port: u16, request: &http1::Request<()>, body: Vec<u8>) -> Result<Forwarded, BoxError> {
+    let method = reqwest::Method::from_bytes(request.method().as_str().as_bytes())?;
+    let path = request.uri().path_and_query().map_or("/", |path| path.as_str());
+
+    let mut forwarded = client.request(method, &format!("https://127.0.0.1:{}{}", port, path));
+    for (name, value) in request.headers() {
+        forwarded = forwarded.header(name.as_str(), value.as_bytes());
+    }
+    if let Some(authority) = request.uri().authority() {
+        forwarded = forwarded.header("host", authority.as_str());
+    }
+    let mut response = forwarded.header("x-forwarded-proto", "h3").body(body).send()?;
+
+    let mut body = Vec::new();
+    response.copy_to(&mut body)?;
+    let headers = response.headers().iter()
+        .map(|(name, value)| (name.as_str().to_string(), value.as_bytes().to_vec()))
+        .collect();
+    Ok((response.status().as_u16(), headers, body))
+}
@@ -0,0 +1,252 @@
+use futures::Poll;
+use tokio::io::{AsyncRead, AsyncWrite};
+use warp::http::header::{HeaderMap, HeaderName, HeaderValue};
+use warp::http::StatusCode;
+
+use std::collections::BTreeMap;
+use std::io::{self, Read, Write};
+use std::sync::{Arc, Mutex};
+
+use crate::error::lock;
+use crate::headers;
+
+/// Headers a response's trailers can't have, as they frame or route its messages
+const NOT_TRAILERS: [&str; 5] = ["content-length", "transfer-encoding", "trailer", "connection", "host"];
+
+/// An informational response an HTTP server sends ahead of the response to a request,
+/// like `103 Early Hints` with `Link` headers, or an unasked for `100 Continue`
+#[derive(Clone, Debug, PartialEq, serde_derive::Deserialize, serde_derive::Serialize)]
+pub struct Informational {
+    pub status: u16,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub headers: BTreeMap<String, String>,
+}
+
+fn invalid(error: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, error)
+}
+
+/// The bytes of `informational`, checked to be of a 1xx status other than 101, which
+/// would switch the connection to another protocol
+pub fn informational(informational: &[Informational]) -> io::Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    for response in informational {
+        let status = StatusCode::from_u16(response.status).ok()
+            .filter(|status| status.is_informational() && *status != StatusCode::SWITCHING_PROTOCOLS)
+            .ok_or_else(|| invalid(format!("invalid informational status {}, only 1xx other than 101", response.status)))?;
+        // hyper's `http` predates 103
+        let reason = status.canonical_reason().or(Some("Early Hints").filter(|_| status.as_u16() == 103));
+        let line = format!("HTTP/1.1 {} {}\r\n", status.as_u16(), reason.unwrap_or_default());
+        bytes.extend_from_slice(line.as_bytes());
+        header_lines(&mut bytes, &headers::response_headers(&response.headers)?);
+        bytes.extend_from_slice(b"\r\n");
+    }
+    Ok(bytes)
+}
+
+/// The bytes of `trailers` ending a chunked body, along with the `Trailer` header
+/// announcing them, checked to be neither framing nor routing headers
+pub fn trailers(trailers: &BTreeMap<String, String>) -> io::Result<Option<(Vec<u8>, HeaderValue)>> {
+    if trailers.is_empty() {
+        return Ok(None);
+    }
+    let trailers = headers::response_headers(trailers)?;
+    if let Some(name) = trailers.keys().find(|name| NOT_TRAILERS.contains(&name.as_str())) {
+        return Err(invalid(format!("{} can't be a trailer", name)));
+    }
+    let names = trailers.keys().map(HeaderName::as_str).collect::<Vec<_>>().join(", ");
+    let mut bytes = Vec::new();
+    header_lines(&mut bytes, &trailers);
+    Ok(Some((bytes, HeaderValue::from_str(&names).expect("header names are valid values"))))
+}
+
+fn header_lines(bytes: &mut Vec<u8>, headers: &HeaderMap) {
+    for (name, value) in headers {
+        bytes.extend_from_slice(name.as_str().as_bytes());
+        bytes.extend_from_slice(b": ");
+        bytes.extend_from_slice(value.as_bytes());
+        bytes.extend_from_slice(b"\r\n");
+    }
+}
+
+/// What a connection sends along with the next response hyper writes on it, which hyper
+/// can't send itself: informational responses ahead of it, and trailers after its body.
+/// Handed to the routes of the connection, see `Wire`.
+#[derive(Clone, Default)]
+pub struct Interim(Arc<Mutex<Pending>>);
+
+#[derive(Default)]
+struct Pending {
+    ahead: Vec<u8>,
+    trailers: Option<Vec<u8>>,
+}
+
+impl Interim {
+    /// Send the informational responses of `ahead` before the next response, and end its
+    /// body with `trailers`, if it's chunked
+    pub fn send(&self, ahead: &[u8], trailers: Option<&[u8]>) {
+        let mut pending = lock(&self.0);
+        pending.ahead.extend_from_slice(ahead);
+        pending.trailers = trailers.map(<[u8]>::to_vec);
+    }
+
+    pub fn wire<T>(&self, inner: T) -> Wire<T> {
+        Wire { inner, interim: self.clone(), framing: Framing::Passing, trailers: Vec::new(), line: Vec::new(), out: Vec::new() }
+    }
+}
+
+/// Where a connection is in a response it ends with trailers
+#[derive(Clone, Copy, Debug)]
+enum Framing {
+    /// Not in one; what's written is passed on as it is
+    Passing,
+    Head,
+    ChunkSize,
+    ChunkData { remaining: usize },
+    ChunkEnd,
+    /// Dropping the empty trailers of hyper's last chunk, having written those of its own
+    LastChunkEnd,
+}
+
+/// A server's side of an HTTP/1 connection sending what its `Interim` has along with
+/// the responses written to it. Responses of other protocols are passed on as they are.
+pub struct Wire<T> {
+    inner: T,
+    interim: Interim,
+    framing: Framing,
+    trailers: Vec<u8>,
+    /// A partial head or chunk line written and not yet passed on
+    line: Vec<u8>,
+    /// Passed on and not yet written to the connection
+    out: Vec<u8>,
+}
+
+impl<T: Write> Wire<T> {
+    /// Write what's passed on to the connection, for as long as it takes it
+    fn drain(&mut self) -> io::Result<()> {
+        while !self.out.is_empty() {
+            match self.inner.write(&self.out)? {
+                0 => return Err(io::ErrorKind::WriteZero.into()),
+                written => {
+                    self.out.drain(..written);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn feed(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            match self.framing {
+                Framing::Passing => {
+                    self.out.extend_from_slice(data);
+                    data = &[];
+                }
+                Framing::ChunkData { remaining } => {
+                    let length = remaining.min(data.len());
+                    self.out.extend_from_slice(&data[..length]);
+                    data = &data[length..];
+                    self.framing = match remaining - length {
+                        0 => Framing::ChunkEnd,
+                        remaining => Framing::ChunkData { remaining },
+                    };
+                }
+                Framing::Head | Framing::ChunkSize | Framing::ChunkEnd | Framing::LastChunkEnd => {
+                    let terminator: &[u8] = match self.framing {
+                        Framing::Head => b"\r\n\r\n",
+                        _ => b"\n",
+                    };
+                    let before = self.line.len();
+                    self.line.extend_from_slice(data);
+                    let end = match self.line.windows(terminator.len()).position(|window| window == terminator) {
+                        Some(end) => end + terminator.len(),
+                        None => return,
+                    };
+                    data = &data[end - before..];
+                    self.line.truncate(end);
+                    let line = std::mem::take(&mut self.line);
+                    self.framing = match self.framing {
+                        Framing::Head => {
+                            let head = String::from_utf8_lossy(&line).to_ascii_lowercase();
+                            self.out.extend_from_slice(&line);
+                            // Only a chunked body can end with trailers
+                            match head.contains("\r\ntransfer-encoding: chunked\r\n") {
+                                true => Framing::ChunkSize,
+                                false => Framing::Passing,
+                            }
+                        }
+                        Framing::ChunkSize => {
+                            let text = String::from_utf8_lossy(&line);
+                            match text.trim().split(';').next().and_then(|size| usize::from_str_radix(size.trim(), 16).ok()) {
+                                Some(0) => {
+                                    self.out.extend_from_slice(b"0\r\n");
+                                    self.out.append(&mut self.trailers);
+                                    self.out.extend_from_slice(b"\r\n");
+                                    Framing::LastChunkEnd
+                                }
+                                Some(remaining) => {
+                                    self.out.extend_from_slice(&line);
+                                    Framing::ChunkData { remaining }
+                                }
+                                None => {
+                                    self.out.extend_from_slice(&line);
+                                    Framing::Passing
+                                }
+                            }
+                        }
+                        Framing::ChunkEnd => {
+                            self.out.extend_from_slice(&line);
+                            Framing::ChunkSize
+                        }
+                        _ => Framing::Passing,
+                    };
+                }
+            }
+        }
+    }
+}
+
+impl<T: Read> Read for Wire<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+impl<T: Write> Write for Wire<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.drain()?;
+        if let Framing::Passing = self.framing {
+            // Between responses, so what's pending goes along with this one. HTTP/2 is
+            // left alone.
+            let mut pending = lock(&self.interim.0);
+            let ahead = std::mem::take(&mut pending.ahead);
+            let trailers = pending.trailers.take();
+            if buf.starts_with(b"HTTP/1.") {
+                self.out.extend_from_slice(&ahead);
+                if let Some(trailers) = trailers {
+                    self.trailers = trailers;
+                    self.framing = Framing::Head;
+                }
+            }
+        }
+        self.feed(buf);
+        match self.drain() {
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(buf.len()),
+            result => result.map(|()| buf.len()),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.drain()?;
+        self.inner.flush()
+    }
+}
+
+impl<T: AsyncRead> AsyncRead for Wire<T> {}
+
+impl<T: AsyncWrite> AsyncWrite for Wire<T> {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        self.drain()?;
+        self.inner.shutdown()
+    }
+}
@@ -0,0 +1,84 @@
+use serde_json::Value;
+use warp::Reply;
+use warp::http::StatusCode;
+
+use std::io;
+
+use crate::{error_reply, Database, ServerJsonBody};
+use crate::error::lock;
+
+/// Query of `GET /{port}/config`
+#[derive(Debug, Default, serde_derive::Deserialize)]
+pub struct ConfigQuery {
+    /// Expand environment variables as the server was started with them
+    #[serde(default)]
+    resolved: bool,
+}
+
+/// `config` with `${VAR}` expanded from the environment in every string it has, stub
+/// bodies and upstream URLs alike, or `${VAR:-default}` to fall back on `default` if `VAR`
+/// isn't set. `$${` stands for a literal `${`. A config is kept as it was given, and
+/// expanded anew each time its server starts.
+pub fn resolve(config: &ServerJsonBody) -> io::Result<ServerJsonBody> {
+    let mut value = serde_json::to_value(config)?;
+    expand_value(&mut value).map_err(|error| io::Error::new(io::ErrorKind::InvalidInput, error))?;
+    Ok(serde_json::from_value(value)?)
+}
+
+fn expand_value(value: &mut Value) -> Result<(), String> {
+    match value {
+        Value::String(text) if text.contains("${") => *text = expand(text)?,
+        Value::Array(values) => values.iter_mut().try_for_each(expand_value)?,
+        Value::Object(values) => values.values_mut().try_for_each(expand_value)?,
+        _ => {}
+    }
+    Ok(())
+}
+
+fn expand(text: &str) -> Result<String, String> {
+    let mut expanded = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find("${") {
+        if rest[..start].ends_with('$') {
+            expanded.push_str(&rest[..start - 1]);
+            expanded.push_str("${");
+            rest = &rest[start + 2..];
+            continue;
+        }
+        expanded.push_str(&rest[..start]);
+        let end = rest[start..].find('}')
+            .ok_or_else(|| format!("unclosed ${{ in {:?}", text))?;
+        let reference = &rest[start + 2..start + end];
+        let (name, default) = match reference.split_once(":-") {
+            Some((name, default)) => (name, Some(default)),
+            None => (reference, None),
+        };
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            return Err(format!("invalid environment variable name {:?}", name));
+        }
+        match (std::env::var(name), default) {
+            (Ok(value), _) => expanded.push_str(&value),
+            (Err(_), Some(default)) => expanded.push_str(default),
+            (Err(_), None) => return Err(format!("environment variable {} isn't set", name)),
+        }
+        rest = &rest[start + end + 1..];
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+/// `GET /{port}/config`: the config of the server on `port` as it was given, or with its
+/// environment variables expanded if `query` asks for it
+pub fn get_config(database: Database, port: u16, query: ConfigQuery) -> warp::reply::Response {
+    let config = match lock(&database).servers.get(&port) {
+        Some(server) => server.config.clone(),
+        None => return error_reply(StatusCode::NOT_FOUND, &format!("no server on port {}", port)),
+    };
+    if !query.resolved {
+        return warp::reply::json(&config).into_response();
+    }
+    match resolve(&config) {
+        Ok(resolved) => warp::reply::json(&resolved).into_response(),
+        Err(e) => error_reply(StatusCode::UNPROCESSABLE_ENTITY, &e.to_string()),
+    }
+}
@@ -0,0 +1,1053 @@
+use tokio::io::{AsyncRead, AsyncWrite};
+use futures::{Future, Poll, Sink, Stream};
+use futures::sync::mpsc;
+use warp::Reply;
+use warp::ws::{Message as WsMessage, Ws2};
+
+use std::collections::{BTreeMap, VecDeque};
+use std::io::{Read, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use crate::{credentials, error_reply, quota, Database, ServerKind};
+use crate::capture::Flow;
+use crate::error::lock;
+use crate::schema;
+use crate::unmatched;
+
+/// A request head longer than this isn't recorded, nor anything after it on the connection
+const MAX_HEAD_BYTES: usize = 64 * 1024;
+
+/// Headers parsed per request or response head, at most
+const MAX_HEADERS: usize = 100;
+
+/// Bookkeeping an entry is counted for in `Journal::bytes`, on top of what it captured
+const ENTRY_OVERHEAD_BYTES: usize = 256;
+
+/// How much of what an in-process HTTP server serves its journal keeps. Every limit left
+/// out is taken from `JOURNAL_CAPACITY`, `JOURNAL_MAX_BYTES` and `JOURNAL_MAX_BODY_BYTES`,
+/// or else a built-in default.
+#[derive(Clone, Debug, Default, PartialEq, serde_derive::Deserialize, serde_derive::Serialize)]
+pub struct JournalLimits {
+    /// Entries kept, the oldest being evicted first. 0 turns recording off.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub capacity: Option<usize>,
+    /// Memory the entries may take, roughly, before the oldest are evicted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_bytes: Option<usize>,
+    /// Bytes of each request and response body captured, the rest being counted only
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_body_bytes: Option<usize>,
+}
+
+impl JournalLimits {
+    pub fn is_default(&self) -> bool {
+        *self == JournalLimits::default()
+    }
+
+    pub fn from_env() -> Result<JournalLimits, String> {
+        let limit = |var: &str| match std::env::var(var) {
+            Ok(limit) => limit.parse().map(Some).map_err(|_| format!("invalid {} {:?}", var, limit)),
+            Err(_) => Ok(None),
+        };
+
+        Ok(JournalLimits {
+            capacity: limit("JOURNAL_CAPACITY")?,
+            max_bytes: limit("JOURNAL_MAX_BYTES")?,
+            max_body_bytes: limit("JOURNAL_MAX_BODY_BYTES")?,
+        })
+    }
+
+    /// These limits, with those left out taken from `defaults` and then the built-in ones
+    pub fn resolve(&self, defaults: &JournalLimits) -> Retention {
+        let builtin = Retention::default();
+        Retention {
+            capacity: self.capacity.or(defaults.capacity).unwrap_or(builtin.capacity),
+            max_bytes: self.max_bytes.or(defaults.max_bytes).unwrap_or(builtin.max_bytes),
+            max_body_bytes: self.max_body_bytes.or(defaults.max_body_bytes).unwrap_or(builtin.max_body_bytes),
+        }
+    }
+}
+
+/// `JournalLimits` in effect
+#[derive(Clone, Copy, Debug, PartialEq, serde_derive::Serialize)]
+pub struct Retention {
+    pub capacity: usize,
+    pub max_bytes: usize,
+    pub max_body_bytes: usize,
+}
+
+impl Default for Retention {
+    fn default() -> Self {
+        Retention {
+            capacity: 1000,
+            max_bytes: 16 * 1024 * 1024,
+            max_body_bytes: 64 * 1024,
+        }
+    }
+}
+
+#[derive(Clone, Debug, serde_derive::Serialize)]
+pub struct Header {
+    pub name: String,
+    pub value: String,
+}
+
+/// A captured request or response body
+#[derive(Clone, Debug, Default, serde_derive::Serialize)]
+pub struct Body {
+    /// The captured bytes, with invalid UTF-8 replaced
+    pub text: String,
+    /// Length of the whole body
+    pub size: usize,
+    /// Whether `text` is cut short at `max_body_bytes`
+    pub truncated: bool,
+}
+
+#[derive(Clone, Debug, serde_derive::Serialize)]
+pub struct RecordedRequest {
+    pub method: String,
+    /// The request target, with the query string
+    pub path: String,
+    pub version: String,
+    pub headers: Vec<Header>,
+    pub body: Body,
+    /// Those of a chunked body, as gRPC's status is sent in
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub trailers: Vec<Header>,
+}
+
+#[derive(Clone, Debug, serde_derive::Serialize)]
+pub struct RecordedResponse {
+    pub status: u16,
+    pub version: String,
+    pub headers: Vec<Header>,
+    pub body: Body,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub trailers: Vec<Header>,
+}
+
+/// A request an HTTP server forwarded, as it sent it on, see `headers::relay`. Kept
+/// apart from the requests it served, within the same capacity, with its body cut
+/// short at the same `max_body_bytes`.
+#[derive(Clone, Debug, serde_derive::Serialize)]
+pub struct UpstreamEntry {
+    /// Numbers the server's forwarded requests from 1, without gaps
+    pub id: u64,
+    pub started_at_ms: u64,
+    pub duration_ms: f64,
+    /// Where it was forwarded, like `server 9001` or `http://api.example.com`
+    pub target: String,
+    pub method: String,
+    /// The URL it was sent to
+    pub url: String,
+    pub headers: Vec<Header>,
+    pub body: Body,
+    /// Of the response, left out if forwarding failed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status: Option<u16>,
+    /// Why forwarding failed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl UpstreamEntry {
+    /// A request sent to `target` now, not yet answered
+    pub fn sent(target: &str, method: &str, url: &str, headers: &warp::http::HeaderMap) -> UpstreamEntry {
+        UpstreamEntry {
+            id: 0,
+            started_at_ms: SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_millis() as u64),
+            duration_ms: 0.0,
+            target: target.to_string(),
+            method: method.to_string(),
+            url: url.to_string(),
+            headers: headers.iter()
+                .map(|(name, value)| Header { name: name.to_string(), value: String::from_utf8_lossy(value.as_bytes()).into_owned() })
+                .collect(),
+            body: Body::default(),
+            status: None,
+            error: None,
+        }
+    }
+
+    /// The request sent at `started`, answered with a status, or failed
+    pub fn answered(self, started: Instant, answer: Result<u16, String>) -> UpstreamEntry {
+        let duration_ms = (started.elapsed().as_secs_f64() * 1000.0 * 100.0).round() / 100.0;
+        let (status, error) = match answer {
+            Ok(status) => (Some(status), None),
+            Err(error) => (None, Some(error)),
+        };
+        UpstreamEntry { duration_ms, status, error, ..self }
+    }
+}
+
+/// The certificate a client of an HTTP server presented over mutual TLS, see
+/// `tls::TlsConfig::client_ca_file`
+#[derive(Clone, Debug, PartialEq, serde_derive::Serialize)]
+pub struct ClientCert {
+    /// Like `CN=client,O=Example`
+    pub subject: String,
+    pub issuer: String,
+    /// In hex
+    pub serial: String,
+    /// In hex, of the DER encoding
+    pub sha256: String,
+}
+
+/// A request served by an HTTP server, with its response
+#[derive(Clone, Debug, serde_derive::Serialize)]
+pub struct Entry {
+    /// Numbers the server's requests from 1, without gaps, evicted ones included
+    pub id: u64,
+    /// Unix time in milliseconds the request started arriving
+    pub started_at_ms: u64,
+    /// From the request starting to arrive until the response was sent
+    pub duration_ms: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client: Option<IpAddr>,
+    /// The port the client connected from
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_port: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_cert: Option<ClientCert>,
+    pub request: RecordedRequest,
+    /// Left out if the connection closed before a response was sent. A response cut off
+    /// by the connection closing, like that of `GET /watch`, has what was sent.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub response: Option<RecordedResponse>,
+    /// What the request violated, like `schema` for a body not conforming to the schema
+    /// of the stub taking it, see `schema::Schema`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub violation: Option<String>,
+    /// What the request presented for the credential its server requires, if it requires
+    /// one, see `credentials::AuthSimulation`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub credentials: Option<credentials::Attempt>,
+}
+
+impl Entry {
+    /// Memory the entry is counted for against `max_bytes`
+    fn bytes(&self) -> usize {
+        let headers = |headers: &[Header]| headers.iter().map(|header| header.name.len() + header.value.len()).sum::<usize>();
+        let response = self.response.as_ref()
+            .map_or(0, |response| headers(&response.headers) + headers(&response.trailers) + response.body.text.len());
+        ENTRY_OVERHEAD_BYTES + self.request.path.len() + headers(&self.request.headers) + headers(&self.request.trailers) + self.request.body.text.len() + response
+    }
+}
+
+/// Counters of a journal, reported by `GET /{port}/requests` and `GET /metrics`
+#[derive(Clone, Copy, Debug, serde_derive::Serialize)]
+pub struct JournalStats {
+    pub stored: usize,
+    pub bytes: usize,
+    pub recorded: u64,
+    pub evicted: u64,
+    /// Bodies cut short at `max_body_bytes`
+    pub truncated: u64,
+    pub retention: Retention,
+}
+
+/// What the requests a journal kept were answered with, reported by `DELETE /{port}?stats=true`
+#[derive(Debug, serde_derive::Serialize)]
+pub struct Summary {
+    #[serde(flatten)]
+    pub stats: JournalStats,
+    /// Requests by the status they were answered with
+    pub statuses: BTreeMap<u16, usize>,
+    /// Requests no route took, see `unmatched::is_unmatched`, most frequent first
+    pub unmatched: Vec<Unmatched>,
+}
+
+/// Requests for a path no route matched
+#[derive(Debug, serde_derive::Serialize)]
+pub struct Unmatched {
+    pub method: String,
+    pub path: String,
+    pub count: usize,
+}
+
+/// A server's journal, shared by its connections and the admin API
+pub type SharedJournal = Arc<Mutex<Journal>>;
+
+/// The requests an HTTP server has served, oldest first, within its `Retention`, the
+/// recorded bytes quotas of its namespace, see `quota::Limits`, and its server's memory
+/// quota
+#[derive(Debug, Default)]
+pub struct Journal {
+    retention: Retention,
+    entries: VecDeque<Entry>,
+    bytes: quota::Charge,
+    /// What the server's memory quota leaves the journal, see `quota::ServerLimits`
+    room: Option<usize>,
+    recorded: u64,
+    evicted: u64,
+    truncated: u64,
+    /// Those streaming the requests as they're recorded, sent their ids and JSON, see
+    /// `stream_requests`
+    subscribers: Vec<mpsc::UnboundedSender<(u64, String)>>,
+    /// The credential the server requires, whose attempts are kept with the requests
+    credential: Option<credentials::Credential>,
+    /// The requests the server forwarded, oldest first
+    upstream: VecDeque<UpstreamEntry>,
+    upstream_recorded: u64,
+}
+
+impl Journal {
+    pub fn new(retention: Retention) -> Journal {
+        Journal { retention, ..Journal::default() }
+    }
+
+    pub fn retention(&self) -> Retention {
+        self.retention
+    }
+
+    /// Apply new limits, evicting what no longer fits
+    pub fn set_retention(&mut self, retention: Retention) {
+        self.retention = retention;
+        self.evict();
+    }
+
+    /// Keep what the requests present for `credential` from now on
+    pub fn set_credential(&mut self, credential: Option<credentials::Credential>) {
+        self.credential = credential;
+    }
+
+    /// Count the bytes kept against `budgets`, and keep no more than `room`, evicting what
+    /// no longer fits them
+    pub fn charge_to(&mut self, budgets: Vec<Arc<quota::Budget>>, room: Option<usize>) {
+        self.bytes.move_to(budgets);
+        self.room = room;
+        self.evict();
+    }
+
+    fn record(&mut self, mut entry: Entry) {
+        entry.violation = entry.response.as_ref()
+            .and_then(|response| response.headers.iter().find(|header| header.name.eq_ignore_ascii_case(schema::VIOLATION_HEADER)))
+            .map(|header| header.value.clone());
+        let headers = &entry.request.headers;
+        entry.credentials = self.credential.as_ref().map(|credential| credential.attempt(|name| {
+            headers.iter().find(|header| header.name.eq_ignore_ascii_case(name)).map(|header| header.value.as_str())
+        }));
+        self.recorded += 1;
+        entry.id = self.recorded;
+        self.truncated += u64::from(entry.request.body.truncated)
+            + u64::from(entry.response.as_ref().is_some_and(|response| response.body.truncated));
+        self.bytes.add(entry.bytes());
+        if !self.subscribers.is_empty() {
+            let json = serde_json::to_string(&entry).unwrap_or_default();
+            self.subscribers.retain(|subscriber| subscriber.unbounded_send((entry.id, json.clone())).is_ok());
+        }
+        self.entries.push_back(entry);
+        self.evict();
+    }
+
+    /// Keep `entry`, a request the server forwarded with `body`, unless recording is off
+    pub fn record_upstream(&mut self, mut entry: UpstreamEntry, body: &[u8]) {
+        if self.retention.capacity == 0 {
+            return;
+        }
+        self.upstream_recorded += 1;
+        entry.id = self.upstream_recorded;
+        let captured = &body[..body.len().min(self.retention.max_body_bytes)];
+        entry.body = Body {
+            text: String::from_utf8_lossy(captured).into_owned(),
+            size: body.len(),
+            truncated: captured.len() < body.len(),
+        };
+        self.upstream.push_back(entry);
+        self.evict();
+    }
+
+    /// Receive the id and JSON of every entry recorded from now on
+    fn subscribe(&mut self) -> mpsc::UnboundedReceiver<(u64, String)> {
+        let (subscriber, entries) = mpsc::unbounded();
+        self.subscribers.push(subscriber);
+        entries
+    }
+
+    fn evict(&mut self) {
+        let over = |journal: &Journal| {
+            let bytes = journal.bytes.bytes();
+            bytes > journal.retention.max_bytes || journal.room.is_some_and(|room| bytes > room) || journal.bytes.exceeded()
+        };
+        while self.entries.len() > self.retention.capacity || (!self.entries.is_empty() && over(self)) {
+            if let Some(entry) = self.entries.pop_front() {
+                self.bytes.remove(entry.bytes());
+                self.evicted += 1;
+            }
+        }
+        while self.upstream.len() > self.retention.capacity {
+            self.upstream.pop_front();
+        }
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = &Entry> {
+        self.entries.iter()
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        let bytes = self.bytes.bytes();
+        self.bytes.remove(bytes);
+    }
+
+    pub fn stats(&self) -> JournalStats {
+        JournalStats {
+            stored: self.entries.len(),
+            bytes: self.bytes.bytes(),
+            recorded: self.recorded,
+            evicted: self.evicted,
+            truncated: self.truncated,
+            retention: self.retention,
+        }
+    }
+
+    /// Tally what the kept requests were answered with
+    pub fn summary(&self) -> Summary {
+        let mut statuses = BTreeMap::new();
+        let mut unmatched = BTreeMap::new();
+        for entry in &self.entries {
+            let status = match &entry.response {
+                Some(response) => response.status,
+                None => continue,
+            };
+            *statuses.entry(status).or_insert(0) += 1;
+            if unmatched::is_unmatched(entry) {
+                *unmatched.entry((entry.request.method.as_str(), entry.request.path.as_str())).or_insert(0) += 1;
+            }
+        }
+        let mut unmatched: Vec<Unmatched> = unmatched.into_iter()
+            .map(|((method, path), count)| Unmatched { method: method.to_string(), path: path.to_string(), count })
+            .collect();
+        unmatched.sort_by_key(|unmatched| std::cmp::Reverse(unmatched.count));
+        Summary { stats: self.stats(), statuses, unmatched }
+    }
+}
+
+/// The first line of a message
+#[derive(Debug)]
+enum StartLine {
+    Request { method: String, path: String },
+    Response { status: u16 },
+}
+
+/// A request or response read off a connection
+#[derive(Debug)]
+struct Message {
+    start: StartLine,
+    version: String,
+    headers: Vec<Header>,
+    body: Vec<u8>,
+    size: usize,
+    truncated: bool,
+    trailers: Vec<Header>,
+    started: Instant,
+    started_at: SystemTime,
+}
+
+impl Message {
+    fn body(&self) -> Body {
+        Body {
+            text: String::from_utf8_lossy(&self.body).into_owned(),
+            size: self.size,
+            truncated: self.truncated,
+        }
+    }
+}
+
+/// Where a parser is in the message it's reading
+#[derive(Clone, Copy, Debug)]
+enum State {
+    Head,
+    Body { remaining: usize },
+    ChunkSize,
+    ChunkData { remaining: usize },
+    ChunkEnd,
+    Trailers,
+    UntilClose,
+    /// Lost track of the framing, or not recording; nothing more is parsed
+    Broken,
+}
+
+/// Reads the messages going one way on an HTTP/1 connection as bytes pass by
+struct Parser {
+    requests: bool,
+    max_body_bytes: usize,
+    state: State,
+    buf: Vec<u8>,
+    message: Option<Message>,
+}
+
+impl Parser {
+    fn new(requests: bool, max_body_bytes: usize) -> Parser {
+        Parser { requests, max_body_bytes, state: State::Head, buf: Vec::new(), message: None }
+    }
+
+    fn off(requests: bool) -> Parser {
+        Parser { state: State::Broken, ..Parser::new(requests, 0) }
+    }
+
+    /// Read `data`, adding the messages it completes to `done`. Responses for which
+    /// `bodyless` holds have no body, whatever their headers say.
+    fn feed(&mut self, mut data: &[u8], bodyless: &dyn Fn(&Message) -> bool, done: &mut Vec<Message>) {
+        while !data.is_empty() {
+            match self.state {
+                State::Head => {
+                    self.buf.extend_from_slice(data);
+                    let rest = match self.parse_head(bodyless) {
+                        Some(rest) => rest,
+                        None => return,
+                    };
+                    if matches!(self.state, State::Head) {
+                        done.extend(self.message.take());
+                    }
+                    return self.feed(&rest, bodyless, done);
+                }
+                State::Body { remaining } | State::ChunkData { remaining } => {
+                    let length = remaining.min(data.len());
+                    self.capture(&data[..length]);
+                    data = &data[length..];
+                    let remaining = remaining - length;
+                    self.state = match self.state {
+                        State::Body { .. } if remaining == 0 => {
+                            done.extend(self.message.take());
+                            State::Head
+                        }
+                        State::Body { .. } => State::Body { remaining },
+                        _ if remaining == 0 => State::ChunkEnd,
+                        _ => State::ChunkData { remaining },
+                    };
+                }
+                State::ChunkSize | State::ChunkEnd | State::Trailers => {
+                    let line = match self.take_line(&mut data) {
+                        Some(line) => line,
+                        None => return,
+                    };
+                    let line = String::from_utf8_lossy(&line);
+                    let line = line.trim();
+                    self.state = match self.state {
+                        State::ChunkSize => {
+                            let size = line.split(';').next().and_then(|size| usize::from_str_radix(size.trim(), 16).ok());
+                            match size {
+                                Some(0) => State::Trailers,
+                                Some(remaining) => State::ChunkData { remaining },
+                                None => State::Broken,
+                            }
+                        }
+                        State::ChunkEnd => State::ChunkSize,
+                        _ if line.is_empty() => {
+                            done.extend(self.message.take());
+                            State::Head
+                        }
+                        _ => {
+                            if let (Some(message), Some((name, value))) = (&mut self.message, line.split_once(':')) {
+                                message.trailers.push(Header { name: name.trim().to_string(), value: value.trim().to_string() });
+                            }
+                            State::Trailers
+                        }
+                    };
+                }
+                State::UntilClose => {
+                    self.capture(data);
+                    data = &[];
+                }
+                State::Broken => return,
+            }
+        }
+    }
+
+    /// Parse the head in `buf` if it's complete, returning the bytes after it
+    fn parse_head(&mut self, bodyless: &dyn Fn(&Message) -> bool) -> Option<Vec<u8>> {
+        let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+        let parsed = if self.requests {
+            let mut request = httparse::Request::new(&mut headers);
+            match request.parse(&self.buf) {
+                Ok(httparse::Status::Complete(length)) => {
+                    let start = StartLine::Request {
+                        method: request.method.unwrap_or_default().to_string(),
+                        path: request.path.unwrap_or_default().to_string(),
+                    };
+                    Ok(Some((length, start, request.version, collect_headers(request.headers))))
+                }
+                Ok(httparse::Status::Partial) => Ok(None),
+                Err(e) => Err(e),
+            }
+        } else {
+            let mut response = httparse::Response::new(&mut headers);
+            match response.parse(&self.buf) {
+                Ok(httparse::Status::Complete(length)) => {
+                    let start = StartLine::Response { status: response.code.unwrap_or_default() };
+                    Ok(Some((length, start, response.version, collect_headers(response.headers))))
+                }
+                Ok(httparse::Status::Partial) => Ok(None),
+                Err(e) => Err(e),
+            }
+        };
+
+        let (length, start, version, headers) = match parsed {
+            Ok(Some(head)) => head,
+            Ok(None) if self.buf.len() <= MAX_HEAD_BYTES => return None,
+            _ => {
+                self.state = State::Broken;
+                self.buf = Vec::new();
+                return None;
+            }
+        };
+
+        let header = |name: &str| headers.iter().find(|header| header.name.eq_ignore_ascii_case(name)).map(|header| header.value.as_str());
+        let chunked = header("transfer-encoding").is_some_and(|encoding| encoding.to_ascii_lowercase().contains("chunked"));
+        let content_length = header("content-length").and_then(|length| length.trim().parse::<usize>().ok());
+
+        let message = Message {
+            start,
+            version: format!("HTTP/1.{}", version.unwrap_or(1)),
+            headers,
+            body: Vec::new(),
+            size: 0,
+            truncated: false,
+            trailers: Vec::new(),
+            started: Instant::now(),
+            started_at: SystemTime::now(),
+        };
+        self.state = if !self.requests && bodyless(&message) {
+            State::Head
+        } else if chunked {
+            State::ChunkSize
+        } else {
+            match content_length {
+                Some(0) => State::Head,
+                Some(remaining) => State::Body { remaining },
+                None if self.requests => State::Head,
+                None => State::UntilClose,
+            }
+        };
+        self.message = Some(message);
+
+        let rest = self.buf.split_off(length);
+        self.buf.clear();
+        Some(rest)
+    }
+
+    /// Take a line ending in `\n` off `data`, buffering a partial one
+    fn take_line(&mut self, data: &mut &[u8]) -> Option<Vec<u8>> {
+        match data.iter().position(|byte| *byte == b'\n') {
+            Some(end) => {
+                self.buf.extend_from_slice(&data[..end]);
+                *data = &data[end + 1..];
+                Some(std::mem::take(&mut self.buf))
+            }
+            None => {
+                self.buf.extend_from_slice(data);
+                *data = &[];
+                if self.buf.len() > MAX_HEAD_BYTES {
+                    self.state = State::Broken;
+                    self.buf = Vec::new();
+                }
+                None
+            }
+        }
+    }
+
+    fn capture(&mut self, data: &[u8]) {
+        let max_body_bytes = self.max_body_bytes;
+        if let Some(message) = &mut self.message {
+            message.size += data.len();
+            let room = max_body_bytes.saturating_sub(message.body.len());
+            message.body.extend_from_slice(&data[..room.min(data.len())]);
+            message.truncated |= data.len() > room;
+        }
+    }
+
+    /// The message being read when the connection closed, if its head was complete
+    fn finish(&mut self) -> Option<Message> {
+        self.message.take()
+    }
+}
+
+fn collect_headers(headers: &[httparse::Header<'_>]) -> Vec<Header> {
+    headers.iter()
+        .map(|header| Header {
+            name: header.name.to_string(),
+            value: String::from_utf8_lossy(header.value).into_owned(),
+        })
+        .collect()
+}
+
+/// A connection of an HTTP server, recording the requests read from it and the responses
+/// written to it in `journal`, and its traffic in `flow` while it's captured
+pub struct Recorded<T> {
+    inner: T,
+    journal: Arc<Mutex<Journal>>,
+    flow: Flow,
+    client: Option<SocketAddr>,
+    client_cert: Option<ClientCert>,
+    requests: Parser,
+    responses: Parser,
+    /// Requests read and not yet answered, oldest first
+    pending: VecDeque<Message>,
+}
+
+impl<T> Recorded<T> {
+    pub fn new(
+        inner: T,
+        journal: Arc<Mutex<Journal>>,
+        flow: Flow,
+        client: Option<SocketAddr>,
+        client_cert: Option<ClientCert>,
+        record: bool
+    ) -> Recorded<T> {
+        let retention = lock(&journal).retention();
+        let (requests, responses) = if !record || retention.capacity == 0 {
+            // Nothing would be kept, so nothing is parsed
+            (Parser::off(true), Parser::off(false))
+        } else {
+            (Parser::new(true, retention.max_body_bytes), Parser::new(false, retention.max_body_bytes))
+        };
+        Recorded { inner, journal, flow, client, client_cert, requests, responses, pending: VecDeque::new() }
+    }
+
+    fn record(&self, request: Message, response: Option<Message>) {
+        let (method, path) = match request.start {
+            StartLine::Request { ref method, ref path } => (method.clone(), path.clone()),
+            StartLine::Response { .. } => return,
+        };
+        let finished = Instant::now();
+
+        let entry = Entry {
+            id: 0,
+            started_at_ms: request.started_at.duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_millis() as u64),
+            duration_ms: (finished.duration_since(request.started).as_secs_f64() * 1000.0 * 100.0).round() / 100.0,
+            client: self.client.map(|client| client.ip()),
+            client_port: self.client.map(|client| client.port()),
+            client_cert: self.client_cert.clone(),
+            request: RecordedRequest {
+                method,
+                path,
+                version: request.version.clone(),
+                body: request.body(),
+                headers: request.headers,
+                trailers: request.trailers,
+            },
+            response: response.map(|response| RecordedResponse {
+                status: match response.start {
+                    StartLine::Response { status } => status,
+                    StartLine::Request { .. } => 0,
+                },
+                version: response.version.clone(),
+                body: response.body(),
+                headers: response.headers,
+                trailers: response.trailers,
+            }),
+            violation: None,
+            credentials: None,
+        };
+        lock(&self.journal).record(entry);
+    }
+}
+
+impl<T: Read> Read for Recorded<T> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let length = self.inner.read(buf)?;
+        self.flow.record(true, &buf[..length]);
+        let mut done = Vec::new();
+        self.requests.feed(&buf[..length], &|_| false, &mut done);
+        self.pending.extend(done);
+        Ok(length)
+    }
+}
+
+impl<T: Write> Write for Recorded<T> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let length = self.inner.write(buf)?;
+        self.flow.record(false, &buf[..length]);
+
+        let head = self.pending.front().is_some_and(|request| {
+            matches!(&request.start, StartLine::Request { method, .. } if method == "HEAD")
+        });
+        let bodyless = move |response: &Message| match response.start {
+            StartLine::Response { status } => head || status < 200 || status == 204 || status == 304,
+            StartLine::Request { .. } => false,
+        };
+        let mut done = Vec::new();
+        self.responses.feed(&buf[..length], &bodyless, &mut done);
+
+        for response in done {
+            // Informational responses like `100 Continue` precede the actual one
+            if matches!(response.start, StartLine::Response { status } if status < 200) {
+                continue;
+            }
+            if let Some(request) = self.pending.pop_front() {
+                self.record(request, Some(response));
+            }
+        }
+        Ok(length)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<T: AsyncRead> AsyncRead for Recorded<T> {}
+
+impl<T: AsyncWrite> AsyncWrite for Recorded<T> {
+    fn shutdown(&mut self) -> Poll<(), std::io::Error> {
+        self.inner.shutdown()
+    }
+}
+
+impl<T> Drop for Recorded<T> {
+    fn drop(&mut self) {
+        let mut response = self.responses.finish();
+        while let Some(request) = self.pending.pop_front() {
+            self.record(request, response.take());
+        }
+    }
+}
+
+/// JSON body of `GET /{port}/requests`
+#[derive(Debug, serde_derive::Serialize)]
+struct JournalJsonBody<'a> {
+    #[serde(flatten)]
+    stats: JournalStats,
+    requests: Vec<&'a Entry>,
+}
+
+#[derive(serde_derive::Serialize)]
+struct UpstreamJsonBody<'a> {
+    stored: usize,
+    recorded: u64,
+    requests: &'a VecDeque<UpstreamEntry>,
+}
+
+/// The journal of the server on `port`, or the status and error to answer without one
+pub fn find_journal(
+    database: &Database,
+    port: u16
+) -> Result<Arc<Mutex<Journal>>, (warp::http::StatusCode, String)> {
+    let registry = lock(database);
+    let server = registry.servers.get(&port)
+        .ok_or_else(|| (warp::http::StatusCode::NOT_FOUND, format!("no server on port {}", port)))?;
+
+    match &server.journal {
+        Some(journal) => Ok(journal.clone()),
+        None if server.config.kind == ServerKind::Http => {
+            Err((warp::http::StatusCode::CONFLICT, "only in-process servers record requests".to_string()))
+        }
+        None => {
+            let error = format!("a {} server doesn't record requests", server.config.kind.name());
+            Err((warp::http::StatusCode::CONFLICT, error))
+        }
+    }
+}
+
+/// Every request recorded by the HTTP server on `port`, oldest first
+pub fn list_requests(database: Database, port: u16) -> warp::reply::Response {
+    let journal = match find_journal(&database, port) {
+        Ok(journal) => journal,
+        Err((status, error)) => return error_reply(status, &error),
+    };
+    let journal = lock(&journal);
+
+    let body = JournalJsonBody { stats: journal.stats(), requests: journal.entries().collect() };
+    warp::reply::json(&body).into_response()
+}
+
+/// Every request the HTTP server on `port` forwarded, oldest first, as it sent it on
+pub fn list_upstream_requests(database: Database, port: u16) -> warp::reply::Response {
+    let journal = match find_journal(&database, port) {
+        Ok(journal) => journal,
+        Err((status, error)) => return error_reply(status, &error),
+    };
+    let journal = lock(&journal);
+
+    let body = UpstreamJsonBody { stored: journal.upstream.len(), recorded: journal.upstream_recorded, requests: &journal.upstream };
+    warp::reply::json(&body).into_response()
+}
+
+/// Forget the requests the HTTP server on `port` forwarded
+pub fn clear_upstream_requests(database: Database, port: u16) -> warp::reply::Response {
+    match find_journal(&database, port) {
+        Ok(journal) => {
+            lock(&journal).upstream.clear();
+            warp::http::StatusCode::NO_CONTENT.into_response()
+        }
+        Err((status, error)) => error_reply(status, &error),
+    }
+}
+
+/// Forget the requests recorded by the HTTP server on `port`. Its counters keep counting.
+pub fn clear_requests(database: Database, port: u16) -> warp::reply::Response {
+    match find_journal(&database, port) {
+        Ok(journal) => {
+            lock(&journal).clear();
+            warp::http::StatusCode::NO_CONTENT.into_response()
+        }
+        Err((status, error)) => error_reply(status, &error),
+    }
+}
+
+/// Stream the requests the HTTP server on `port` records from now on as server-sent
+/// events, each a JSON entry with its id as the event's, or over a WebSocket if the
+/// request upgrades to one, a text message per entry
+pub fn stream_requests(database: Database, port: u16, ws: Option<Ws2>) -> warp::reply::Response {
+    let entries = match find_journal(&database, port) {
+        Ok(journal) => lock(&journal).subscribe(),
+        Err((status, error)) => return error_reply(status, &error),
+    };
+
+    if let Some(ws) = ws {
+        return ws.on_upgrade(move |socket| {
+            let (sink, incoming) = socket.split();
+            // Ends once the subscriber closes the socket, or it fails
+            let closed = incoming.for_each(|_| Ok(())).then(|_| Ok::<_, ()>(()));
+            let send = entries
+                .map(|(_, json)| WsMessage::text(json))
+                .forward(sink.sink_map_err(|e| eprintln!("request stream socket error: {}", e)))
+                .map(|_| ());
+            send.select(closed).then(|_| Ok(()))
+        }).into_response();
+    }
+
+    let events = entries
+        .map(|(id, json)| format!("id: {}\nevent: request\ndata: {}\n\n", id, json))
+        .map_err(|()| -> std::io::Error { unreachable!("unbounded receivers never fail") });
+    let mut response = warp::http::Response::new(hyper::Body::wrap_stream(events));
+    let headers = response.headers_mut();
+    headers.insert(warp::http::header::CONTENT_TYPE, warp::http::header::HeaderValue::from_static("text/event-stream"));
+    headers.insert(warp::http::header::CACHE_CONTROL, warp::http::header::HeaderValue::from_static("no-cache"));
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Cursor;
+
+    /// The messages `parser` reads off `data` fed to it in pieces of `piece` bytes
+    fn parse(parser: &mut Parser, data: &[u8], piece: usize) -> Vec<Message> {
+        let mut done = Vec::new();
+        for piece in data.chunks(piece) {
+            parser.feed(piece, &|_| false, &mut done);
+        }
+        done
+    }
+
+    fn path(message: &Message) -> &str {
+        match &message.start {
+            StartLine::Request { path, .. } => path,
+            StartLine::Response { .. } => "",
+        }
+    }
+
+    #[test]
+    fn reads_chunked_bodies_fed_in_any_pieces() {
+        let request = b"POST /upload HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n6;name=value\r\n world\r\n0\r\n\r\n";
+        for piece in [1, 2, 7, request.len()] {
+            let done = parse(&mut Parser::new(true, 1024), request, piece);
+            assert_eq!(done.len(), 1, "in pieces of {}", piece);
+            assert_eq!(done[0].body().text, "hello world");
+            assert_eq!(done[0].size, 11);
+            assert!(done[0].trailers.is_empty());
+        }
+    }
+
+    #[test]
+    fn reads_the_trailers_of_chunked_bodies() {
+        let response = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\nTrailer: grpc-status\r\n\r\n3\r\nabc\r\n0\r\ngrpc-status: 0\r\ngrpc-message: ok\r\n\r\n";
+        let done = parse(&mut Parser::new(false, 1024), response, 4);
+        assert_eq!(done.len(), 1);
+        assert_eq!(done[0].body().text, "abc");
+        let trailers: Vec<(&str, &str)> = done[0].trailers.iter().map(|header| (header.name.as_str(), header.value.as_str())).collect();
+        assert_eq!(trailers, [("grpc-status", "0"), ("grpc-message", "ok")]);
+    }
+
+    #[test]
+    fn reads_pipelined_requests_in_order() {
+        let requests = b"GET /a HTTP/1.1\r\nHost: test\r\n\r\nPOST /b HTTP/1.1\r\nContent-Length: 3\r\n\r\nabcPUT /c HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n1\r\nx\r\n0\r\n\r\nDELETE /d HTTP/1.1\r\n\r\n";
+        for piece in [1, 5, requests.len()] {
+            let done = parse(&mut Parser::new(true, 1024), requests, piece);
+            let paths: Vec<&str> = done.iter().map(path).collect();
+            assert_eq!(paths, ["/a", "/b", "/c", "/d"], "in pieces of {}", piece);
+            let bodies: Vec<String> = done.iter().map(|message| message.body().text).collect();
+            assert_eq!(bodies, ["", "abc", "x", ""]);
+        }
+    }
+
+    #[test]
+    fn keeps_a_body_shorter_than_its_content_length_once_the_connection_closes() {
+        let mut parser = Parser::new(true, 1024);
+        assert!(parse(&mut parser, b"POST /short HTTP/1.1\r\nContent-Length: 10\r\n\r\nabc", 4).is_empty());
+        let message = parser.finish().unwrap();
+        assert_eq!(path(&message), "/short");
+        assert_eq!(message.body().text, "abc");
+        assert_eq!(message.size, 3);
+    }
+
+    #[test]
+    fn stops_reading_once_a_body_is_longer_than_its_content_length() {
+        let mut parser = Parser::new(true, 1024);
+        let done = parse(&mut parser, b"POST /long HTTP/1.1\r\nContent-Length: 3\r\n\r\nabcdef\r\n\r\nGET /next HTTP/1.1\r\n\r\n", 64);
+        let paths: Vec<&str> = done.iter().map(path).collect();
+        assert_eq!(paths, ["/long"]);
+        assert_eq!(done[0].body().text, "abc");
+        assert!(matches!(parser.state, State::Broken));
+        assert!(parse(&mut parser, b"GET /after HTTP/1.1\r\n\r\n", 64).is_empty());
+    }
+
+    #[test]
+    fn truncates_bodies_at_max_body_bytes() {
+        let done = parse(&mut Parser::new(true, 4), b"POST / HTTP/1.1\r\nContent-Length: 10\r\n\r\n0123456789", 3);
+        let body = done[0].body();
+        assert_eq!((body.text.as_str(), body.size, body.truncated), ("0123", 10, true));
+    }
+
+    /// A connection reading `input` and keeping what's written to it
+    struct Connection {
+        input: Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl Read for Connection {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for Connection {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn pairs_the_responses_of_a_keep_alive_connection_with_its_requests() {
+        let requests = b"HEAD /head HTTP/1.1\r\n\r\nPOST /continue HTTP/1.1\r\nExpect: 100-continue\r\nContent-Length: 2\r\n\r\nhiGET /unanswered HTTP/1.1\r\n\r\n";
+        let journal = Arc::new(Mutex::new(Journal::new(Retention::default())));
+        let connection = Connection { input: Cursor::new(requests.to_vec()), output: Vec::new() };
+        let flow = Flow::new(Arc::default(), None, 80);
+        let mut recorded = Recorded::new(connection, journal.clone(), flow, None, None, true);
+
+        let mut read = Vec::new();
+        recorded.read_to_end(&mut read).unwrap();
+        // Answering HEAD with the length a GET's body would have, and sending no body
+        recorded.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\n").unwrap();
+        recorded.write_all(b"HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 201 Created\r\nContent-Length: 2\r\n\r\nok").unwrap();
+        drop(recorded);
+
+        let journal = lock(&journal);
+        let entries: Vec<(&str, Option<u16>, &str)> = journal.entries()
+            .map(|entry| {
+                let response = entry.response.as_ref();
+                (entry.request.path.as_str(), response.map(|response| response.status), response.map_or("", |response| response.body.text.as_str()))
+            })
+            .collect();
+        assert_eq!(entries, [("/head", Some(200), ""), ("/continue", Some(201), "ok"), ("/unanswered", None, "")]);
+    }
+}
@@ -0,0 +1,293 @@
+use futures::{Future, Sink, Stream};
+use futures::sync::mpsc;
+use serde_json::Value;
+use warp::{Buf, Reply};
+use warp::ws::{Message, WebSocket, Ws2};
+
+use crate::{apply, delete_server, get_server, list_servers, post_new_server, server_action, update_server};
+use crate::{Database, DeleteQuery, DesiredState, DryRunQuery, ListQuery, ServerAction, ServerJsonBody};
+use crate::error::{self, lock};
+use crate::{readonly, runtime};
+
+/// The request wasn't valid JSON
+const PARSE_ERROR: i64 = -32700;
+/// The request wasn't a JSON-RPC request
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+/// The admin API answered the call with an error, its status being in `data`
+const CALL_FAILED: i64 = -32000;
+
+/// The error of a failed call
+#[derive(Debug, serde_derive::Serialize)]
+struct RpcError {
+    code: i64,
+    message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    data: Option<Value>,
+}
+
+impl RpcError {
+    fn new(code: i64, message: impl Into<String>) -> RpcError {
+        RpcError { code, message: message.into(), data: None }
+    }
+}
+
+/// Params of the methods taking a server's port
+#[derive(Debug, serde_derive::Deserialize)]
+struct PortParams {
+    port: u16,
+    /// The server's ETag, which it must still have, like `If-Match`
+    #[serde(default)]
+    if_match: Option<String>,
+}
+
+/// Params of `delete`
+#[derive(Debug, serde_derive::Deserialize)]
+struct DeleteParams {
+    port: u16,
+    #[serde(default)]
+    if_match: Option<String>,
+    /// Answer with the server's final stats, like `DELETE /{port}?stats=true`
+    #[serde(default)]
+    stats: bool,
+    /// Delete the root server too, like `DELETE /{port}?force=true`
+    #[serde(default)]
+    force: bool,
+}
+
+/// Params of `update`
+#[derive(Debug, serde_derive::Deserialize)]
+struct UpdateParams {
+    port: u16,
+    #[serde(default)]
+    if_match: Option<String>,
+    server: ServerJsonBody,
+}
+
+type CallFuture = Box<dyn Future<Item = Option<Value>, Error = ()> + Send>;
+
+/// `POST /rpc`: a JSON-RPC 2.0 call or batch of calls, answered as the admin API routes
+/// they stand for would be. Calls in a batch are made in order, so that a batch can
+/// create a server and then one depending on it. A batch of notifications only is
+/// answered with 204.
+pub fn rpc(
+    database: Database,
+    own_port: u16,
+    body: warp::body::FullBody
+) -> impl Future<Item = warp::reply::Response, Error = warp::Rejection> {
+    handle(database, own_port, body.bytes()).map(|answer| match answer {
+        Some(answer) => warp::reply::json(&answer).into_response(),
+        None => warp::http::StatusCode::NO_CONTENT.into_response(),
+    })
+    .map_err(|()| warp::reject::not_found())
+}
+
+/// `GET /rpc` upgraded to a WebSocket: every text message is a call or batch, like the
+/// body of `POST /rpc`, answered in order by a message of its own. `watch` subscribes
+/// the socket to `change` notifications of every change to the servers, with the lines
+/// of `GET /watch` as their params.
+pub fn rpc_socket(database: Database, own_port: u16, ws: Ws2) -> impl Reply {
+    ws.on_upgrade(move |socket| session(database, own_port, socket))
+}
+
+fn session(database: Database, own_port: u16, socket: WebSocket) -> impl Future<Item = (), Error = ()> {
+    let (sink, stream) = socket.split();
+    let (outgoing, queued) = mpsc::unbounded::<String>();
+
+    let send = queued
+        .map(Message::text)
+        .forward(sink.sink_map_err(|e| eprintln!("JSON-RPC socket error: {}", e)))
+        .map(|_| ());
+    let receive = stream
+        .map_err(|e| eprintln!("JSON-RPC socket error: {}", e))
+        .take_while(|message| Ok(!message.is_close()))
+        .filter_map(|message| message.to_str().ok().map(str::to_string))
+        .for_each(move |text| {
+            let outgoing = outgoing.clone();
+            let answer: CallFuture = match serde_json::from_str::<Value>(&text) {
+                Ok(call) if call.get("method").and_then(Value::as_str) == Some("watch") => {
+                    Box::new(futures::future::ok(watch(&database, call, outgoing.clone())))
+                }
+                _ => handle(database.clone(), own_port, text.as_bytes()),
+            };
+            answer.map(move |answer| {
+                if let Some(answer) = answer {
+                    let _ = outgoing.unbounded_send(answer.to_string());
+                }
+            })
+        });
+
+    receive.select(send).then(|_| Ok(()))
+}
+
+/// Subscribe to the changes of the servers, answering with the revision they start after
+fn watch(database: &Database, call: Value, outgoing: mpsc::UnboundedSender<String>) -> Option<Value> {
+    let id = call.get("id").cloned();
+    let mut registry = lock(database);
+    let changes = registry.feed.subscribe();
+    let revision = registry.feed.revision();
+    drop(registry);
+
+    // Queued before any change is, so the answer comes first
+    let answer = id.map(|id| answer(id, Ok(serde_json::json!({ "revision": revision }))));
+    if let Some(answer) = &answer {
+        let _ = outgoing.unbounded_send(answer.to_string());
+    }
+    runtime::spawn(changes.for_each(move |line| {
+        let notification = format!(r#"{{"jsonrpc":"2.0","method":"change","params":{}}}"#, line.trim_end());
+        outgoing.unbounded_send(notification).map_err(|_| ())
+    }));
+    None
+}
+
+/// Answer a call or batch of calls, if any of them are answered
+fn handle(database: Database, own_port: u16, body: &[u8]) -> CallFuture {
+    let parsed: Value = match serde_json::from_slice(body) {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            let error = RpcError::new(PARSE_ERROR, format!("invalid JSON: {}", e));
+            return Box::new(futures::future::ok(Some(answer(Value::Null, Err(error)))));
+        }
+    };
+    match parsed {
+        Value::Array(calls) if calls.is_empty() => {
+            let error = RpcError::new(INVALID_REQUEST, "a batch has at least one call");
+            Box::new(futures::future::ok(Some(answer(Value::Null, Err(error)))))
+        }
+        Value::Array(calls) => Box::new(futures::stream::iter_ok(calls)
+            .and_then(move |call| handle_call(database.clone(), own_port, call))
+            .filter_map(|answer| answer)
+            .collect()
+            .map(|answers| Some(answers).filter(|answers| !answers.is_empty()).map(Value::Array))),
+        call => handle_call(database, own_port, call),
+    }
+}
+
+/// Answer a single call, unless it's a notification
+fn handle_call(database: Database, own_port: u16, call: Value) -> CallFuture {
+    let id = call.get("id").cloned();
+    let invalid = |message: &str| {
+        let error = RpcError::new(INVALID_REQUEST, message);
+        Box::new(futures::future::ok(Some(answer(id.clone().unwrap_or_default(), Err(error))))) as CallFuture
+    };
+    if call.get("jsonrpc").and_then(Value::as_str) != Some("2.0") {
+        return invalid("a call has \"jsonrpc\": \"2.0\"");
+    }
+    let method = match call.get("method").and_then(Value::as_str) {
+        Some(method) => method.to_string(),
+        None => return invalid("a call has a method"),
+    };
+    let params = call.get("params").cloned().unwrap_or(Value::Null);
+
+    let result: Box<dyn Future<Item = Value, Error = RpcError> + Send> = match dispatch(&database, own_port, &method, params) {
+        Ok(response) => Box::new(response.and_then(result)),
+        Err(error) => Box::new(futures::future::err(error)),
+    };
+    Box::new(result.then(move |result| Ok(id.map(|id| answer(id, result)))))
+}
+
+/// Make a single call of `method` with `params`, like `handle` does, to the result the
+/// route answered with, or else its error
+#[cfg(feature = "tui")]
+pub fn call(database: &Database, own_port: u16, method: &str, params: Value) -> impl Future<Item = Value, Error = String> {
+    let response = match dispatch(database, own_port, method, params) {
+        Ok(response) => futures::future::Either::A(response.and_then(result)),
+        Err(error) => futures::future::Either::B(futures::future::err(error)),
+    };
+    response.map_err(|error| error.message)
+}
+
+/// The response of the route a call was made through, as it's answered
+type ResponseFuture = Box<dyn Future<Item = warp::reply::Response, Error = RpcError> + Send>;
+
+/// Make the call through the handler of the route it stands for
+fn dispatch(database: &Database, own_port: u16, method: &str, params: Value) -> Result<ResponseFuture, RpcError> {
+    if let Some(refused) = readonly::check_call(database, method) {
+        return Ok(Box::new(futures::future::ok(refused)));
+    }
+    let database = database.clone();
+    let reply = match method {
+        "list" => {
+            let query: ListQuery = params_of(null_as_empty(params))?;
+            // A call is answered with JSON, whatever format it asks for
+            let query = ListQuery { format: None, ..query };
+            let shared = lock(&database).shared.clone();
+            Ok(list_servers(shared, crate::format::Format::Json, query).into_response())
+        }
+        "get" => {
+            let PortParams { port, .. } = params_of(params)?;
+            get_server(database, crate::format::Format::Json, port).map(Reply::into_response)
+        }
+        "create" => {
+            let server: ServerJsonBody = params_of(params)?;
+            post_new_server(database, DryRunQuery::default(), None, server.into_config()).map(Reply::into_response)
+        }
+        "update" => {
+            let UpdateParams { port, if_match, server } = params_of(params)?;
+            update_server(database, port, if_match, server.into_config()).map(Reply::into_response)
+        }
+        "delete" => {
+            let DeleteParams { port, if_match, stats, force } = params_of(params)?;
+            // Answered once the server's task has ended
+            let deleted = delete_server(database, port, own_port, if_match, DeleteQuery { stats, force });
+            return Ok(Box::new(deleted.then(|reply| Ok(recovered(reply)))));
+        }
+        "pause" | "resume" | "stop" | "start" => {
+            let PortParams { port, if_match } = params_of(params)?;
+            let action: ServerAction = method.parse()
+                .map_err(|()| RpcError::new(METHOD_NOT_FOUND, format!("no method {:?}", method)))?;
+            server_action(database, port, action, if_match).map(Reply::into_response)
+        }
+        "apply" => {
+            let desired: DesiredState = params_of(params)?;
+            apply(database, own_port, DryRunQuery::default(), desired).map(Reply::into_response)
+        }
+        "watch" => return Err(RpcError::new(INVALID_REQUEST, "watch is only available over a WebSocket")),
+        method => return Err(RpcError::new(METHOD_NOT_FOUND, format!("no method {:?}", method))),
+    };
+    Ok(Box::new(futures::future::ok(recovered(reply))))
+}
+
+/// Answer rejections the routes leave to warp as it would
+fn recovered(reply: Result<warp::reply::Response, warp::Rejection>) -> warp::reply::Response {
+    reply.unwrap_or_else(|rejection| error::recover(rejection).unwrap_or_else(|rejection| {
+        let status = rejection.status();
+        crate::error_reply(status, status.canonical_reason().unwrap_or_default())
+    }))
+}
+
+fn null_as_empty(params: Value) -> Value {
+    if params.is_null() { serde_json::json!({}) } else { params }
+}
+
+fn params_of<T: serde::de::DeserializeOwned>(params: Value) -> Result<T, RpcError> {
+    serde_json::from_value(params).map_err(|e| RpcError::new(INVALID_PARAMS, e.to_string()))
+}
+
+/// The result of a call: the JSON body the route answered with, or an error with its
+/// status and body as data
+fn result(response: warp::reply::Response) -> impl Future<Item = Value, Error = RpcError> {
+    let status = response.status();
+    response.into_body().concat2()
+        .map_err(|e| RpcError::new(CALL_FAILED, e.to_string()))
+        .and_then(move |body| {
+            let body: Value = serde_json::from_slice(&body).unwrap_or(Value::Null);
+            if status.is_success() {
+                return Ok(body);
+            }
+            let message = body.get("error").and_then(Value::as_str)
+                .or_else(|| status.canonical_reason())
+                .unwrap_or_default()
+                .to_string();
+            let data = serde_json::json!({ "status": status.as_u16(), "body": body });
+            Err(RpcError { code: CALL_FAILED, message, data: Some(data) })
+        })
+}
+
+fn answer(id: Value, result: Result<Value, RpcError>) -> Value {
+    match result {
+        Ok(result) => serde_json::json!({ "jsonrpc": "2.0", "result": result, "id": id }),
+        Err(error) => serde_json::json!({ "jsonrpc": "2.0", "error": error, "id": id }),
+    }
+}
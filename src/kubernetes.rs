@@ -0,0 +1,253 @@
+use futures::{Future, Sink, Stream};
+use futures::sync::{mpsc, oneshot};
+
+use std::collections::{BTreeMap, HashMap};
+use std::time::Duration;
+
+use crate::{converge, ApplySummary, Database, ServerJsonBody};
+use crate::error::lock;
+
+/// Where a pod finds the credentials of its service account
+const SERVICE_ACCOUNT: &str = "/var/run/secrets/kubernetes.io/serviceaccount";
+
+/// The API group and version of `MockServer` resources
+const API_VERSION: &str = "mock.warp.dev/v1";
+
+/// Annotation of a ConfigMap that the status of its servers, and those that failed to
+/// start, are reported in
+const STATUS_ANNOTATION: &str = "mock.warp.dev/status";
+
+/// Failures of reconciling the servers with their resources
+#[derive(Debug, thiserror::Error)]
+enum KubernetesError {
+    #[error("Kubernetes API request failed: {0}")]
+    Http(#[from] reqwest::Error),
+    #[error("invalid servers in {resource}: {error}")]
+    Servers { resource: String, error: String },
+    #[error("{0}")]
+    Converge(String),
+}
+
+/// The resources the servers are declared by
+#[derive(Clone, Debug, PartialEq)]
+pub enum Source {
+    /// The `servers` key of a ConfigMap, a YAML or JSON list of servers
+    ConfigMap(String),
+    /// `MockServer` resources, whose spec is a server
+    MockServers,
+}
+
+/// Reconciles the servers with resources in a Kubernetes namespace, set with
+/// `KUBERNETES_MOCKS`: every server there should be is started, those that differ are
+/// updated and those that shouldn't be are deleted, as `POST /apply` does. The outcome
+/// is reported back to the resources.
+pub struct KubernetesConfig {
+    source: Source,
+    api: String,
+    namespace: String,
+    token: Option<String>,
+    ca: Option<Vec<u8>>,
+    poll: Duration,
+}
+
+impl KubernetesConfig {
+    /// `KUBERNETES_MOCKS` is `configmap/{name}` or `mockservers`. The namespace is
+    /// `KUBERNETES_NAMESPACE`, by default that of the pod, and resources are polled
+    /// every `KUBERNETES_POLL_SECS`, 5 by default. The API server and its credentials
+    /// are those of the pod's service account, unless `KUBERNETES_API` names another
+    /// one, like `kubectl proxy` at `http://127.0.0.1:8001`.
+    pub fn from_env() -> Result<Option<KubernetesConfig>, String> {
+        let mocks = match std::env::var("KUBERNETES_MOCKS") {
+            Ok(mocks) => mocks,
+            Err(_) => return Ok(None),
+        };
+        let source = match mocks.split_once('/') {
+            Some(("configmap", name)) if !name.is_empty() => Source::ConfigMap(name.to_string()),
+            None if mocks == "mockservers" => Source::MockServers,
+            _ => return Err(format!("invalid KUBERNETES_MOCKS {:?}, expected configmap/{{name}} or mockservers", mocks)),
+        };
+
+        let read = |file: &str| std::fs::read(format!("{}/{}", SERVICE_ACCOUNT, file));
+        let (api, token, ca) = match std::env::var("KUBERNETES_API") {
+            Ok(api) => (api.trim_end_matches('/').to_string(), None, None),
+            Err(_) => {
+                let host = std::env::var("KUBERNETES_SERVICE_HOST")
+                    .map_err(|_| "KUBERNETES_MOCKS needs KUBERNETES_API outside of a pod".to_string())?;
+                let port = std::env::var("KUBERNETES_SERVICE_PORT").unwrap_or_else(|_| "443".to_string());
+                let token = read("token").map_err(|e| format!("failed to read the service account token: {}", e))?;
+                let ca = read("ca.crt").map_err(|e| format!("failed to read the cluster CA: {}", e))?;
+                let token = String::from_utf8_lossy(&token).trim().to_string();
+                (format!("https://{}:{}", host, port), Some(token), Some(ca))
+            }
+        };
+        let namespace = match std::env::var("KUBERNETES_NAMESPACE") {
+            Ok(namespace) => namespace,
+            Err(_) => read("namespace")
+                .map(|namespace| String::from_utf8_lossy(&namespace).trim().to_string())
+                .map_err(|_| "KUBERNETES_MOCKS needs KUBERNETES_NAMESPACE outside of a pod".to_string())?,
+        };
+        let poll = match std::env::var("KUBERNETES_POLL_SECS") {
+            Ok(secs) => secs.parse().ok()
+                .filter(|secs| *secs > 0)
+                .map(Duration::from_secs)
+                .ok_or_else(|| format!("invalid KUBERNETES_POLL_SECS {:?}", secs))?,
+            Err(_) => Duration::from_secs(5),
+        };
+
+        Ok(Some(KubernetesConfig { source, api, namespace, token, ca, poll }))
+    }
+}
+
+/// A desired state handed to the runtime, with where to send the outcome of converging
+/// on it and the servers as they are after
+type Reconciliation = (Vec<ServerJsonBody>, oneshot::Sender<Result<(ApplySummary, HashMap<u16, ServerJsonBody>), String>>);
+
+/// Reconcile the servers, but the admin API on `own_port`, on a thread of its own. The
+/// returned future converges on what the thread read, and is to be run on the runtime
+/// the servers are started on.
+pub fn start(
+    database: Database,
+    own_port: u16,
+    config: KubernetesConfig
+) -> std::io::Result<impl Future<Item = (), Error = ()>> {
+    let mut client = reqwest::Client::builder().timeout(Duration::from_secs(30));
+    if let Some(ca) = &config.ca {
+        let ca = reqwest::Certificate::from_pem(ca).map_err(std::io::Error::other)?;
+        client = client.add_root_certificate(ca);
+    }
+    let client = client.build().map_err(std::io::Error::other)?;
+
+    let (reconciliations, received) = mpsc::channel::<Reconciliation>(0);
+    std::thread::Builder::new().name("kubernetes".to_string()).spawn(move || {
+        let mut operator = Operator { client, config, reconciliations, reported: None };
+        loop {
+            if let Err(e) = operator.reconcile() {
+                eprintln!("failed to reconcile servers with Kubernetes: {}", e);
+            }
+            std::thread::sleep(operator.config.poll);
+        }
+    })?;
+
+    Ok(received.for_each(move |(desired, outcome)| {
+        let mut registry = lock(&database);
+        let result = converge(&database, &mut registry, own_port, desired, false).map(|summary| {
+            let servers = registry.servers.iter().map(|(port, server)| (*port, server.json_body())).collect();
+            (summary, servers)
+        });
+        let _ = outcome.send(result);
+        Ok(())
+    }))
+}
+
+struct Operator {
+    client: reqwest::Client,
+    config: KubernetesConfig,
+    reconciliations: mpsc::Sender<Reconciliation>,
+    /// What was last reported to a ConfigMap
+    reported: Option<serde_json::Value>,
+}
+
+impl Operator {
+    fn url(&self, path: &str) -> String {
+        format!("{}{}", self.config.api, path)
+    }
+
+    fn get(&self, path: &str) -> Result<serde_json::Value, KubernetesError> {
+        let mut request = self.client.get(&self.url(path));
+        if let Some(token) = &self.config.token {
+            request = request.bearer_auth(token);
+        }
+        Ok(request.send()?.error_for_status()?.json()?)
+    }
+
+    fn patch(&self, path: &str, patch: &serde_json::Value) -> Result<(), KubernetesError> {
+        let mut request = self.client.patch(&self.url(path))
+            .header(reqwest::header::CONTENT_TYPE, "application/merge-patch+json")
+            .body(patch.to_string());
+        if let Some(token) = &self.config.token {
+            request = request.bearer_auth(token);
+        }
+        request.send()?.error_for_status()?;
+        Ok(())
+    }
+
+    /// Converge on the servers the resources declare, and report what came of it
+    fn reconcile(&mut self) -> Result<(), KubernetesError> {
+        let namespace = self.config.namespace.clone();
+        match self.config.source.clone() {
+            Source::ConfigMap(name) => {
+                let path = format!("/api/v1/namespaces/{}/configmaps/{}", namespace, name);
+                let config_map = self.get(&path)?;
+                let servers = config_map["data"]["servers"].as_str().unwrap_or("[]");
+                let desired: Vec<ServerJsonBody> = serde_yaml::from_str(servers).map_err(|e| KubernetesError::Servers {
+                    resource: format!("configmap/{}", name),
+                    error: e.to_string(),
+                })?;
+                let ports: Vec<u16> = desired.iter().map(|server| server.port).collect();
+                let (summary, servers) = self.converge(desired)?;
+
+                // The status of each server declared rather than what changed, so that
+                // the annotation settles along with the servers
+                let statuses: BTreeMap<u16, String> = ports.iter()
+                    .filter_map(|port| Some((*port, phase(servers.get(port)?))))
+                    .collect();
+                let report = serde_json::json!({ "servers": statuses, "failed": summary.failed });
+                if self.reported.as_ref() != Some(&report) {
+                    let patch = serde_json::json!({
+                        "metadata": { "annotations": { STATUS_ANNOTATION: report.to_string() } },
+                    });
+                    self.patch(&path, &patch)?;
+                    self.reported = Some(report);
+                }
+            }
+            Source::MockServers => {
+                let path = format!("/apis/{}/namespaces/{}/mockservers", API_VERSION, namespace);
+                let list = self.get(&path)?;
+                let items = list["items"].as_array().cloned().unwrap_or_default();
+                let mut desired = Vec::new();
+                for item in &items {
+                    let server = serde_json::from_value(item["spec"].clone()).map_err(|e| KubernetesError::Servers {
+                        resource: format!("mockserver/{}", item["metadata"]["name"].as_str().unwrap_or_default()),
+                        error: e.to_string(),
+                    })?;
+                    desired.push(server);
+                }
+                let (summary, servers) = self.converge(desired)?;
+
+                for item in &items {
+                    let port = item["spec"]["port"].as_u64().unwrap_or(0) as u16;
+                    let failure = summary.failed.iter().find(|failure| failure.port == port);
+                    let status = serde_json::json!({
+                        "observedGeneration": item["metadata"]["generation"],
+                        "port": port,
+                        "phase": match (failure, servers.get(&port)) {
+                            (None, Some(server)) => phase(server),
+                            _ => "failed".to_string(),
+                        },
+                        "error": failure.map(|failure| failure.error.clone()),
+                    });
+                    if item["status"] != status {
+                        let name = item["metadata"]["name"].as_str().unwrap_or_default();
+                        self.patch(&format!("{}/{}/status", path, name), &serde_json::json!({ "status": status }))?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Have the runtime converge on `desired`, waiting for it to
+    fn converge(&mut self, desired: Vec<ServerJsonBody>) -> Result<(ApplySummary, HashMap<u16, ServerJsonBody>), KubernetesError> {
+        let (outcome, result) = oneshot::channel();
+        let stopped = || KubernetesError::Converge("the runtime stopped".to_string());
+        self.reconciliations.clone().send((desired, outcome)).wait().map_err(|_| stopped())?;
+        result.wait().map_err(|_| stopped())?.map_err(KubernetesError::Converge)
+    }
+}
+
+/// The status of `server`, as it's serialized
+fn phase(server: &ServerJsonBody) -> String {
+    serde_json::to_value(server.status).ok()
+        .and_then(|status| status.as_str().map(str::to_string))
+        .unwrap_or_default()
+}
@@ -0,0 +1,3385 @@
+// The admin API is one filter chain, whose type nests deeper than the default limit
+#![recursion_limit = "512"]
+
+use futures::{Future, Stream};
+use warp::{self, path, Filter, Reply};
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::clone::Clone;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::error::lock;
+
+pub use crate::connections::ConnectionOptions;
+pub use crate::dns::{Record, Zone};
+pub use crate::docker::Container;
+pub use crate::error::Error;
+pub use crate::limits::UsageSummary;
+pub use crate::metrics::LatencySummary;
+pub use crate::ports::{EphemeralPorts, PortAllocator, PortPool, SequentialPorts};
+pub use crate::journal::JournalLimits;
+pub use crate::sockets::SocketOptions;
+pub use crate::store::{MemoryStore, Store, StoreError};
+pub use crate::stubs::{ListedStub, Resolution, ResolutionStep, Stub, StubSet, StubsReport};
+pub use crate::tcp::TcpMode;
+pub use crate::testing::TestInstance;
+pub use crate::tls::TlsConfig;
+pub use crate::udp::UdpMode;
+
+#[cfg(feature = "acme")]
+mod acme;
+#[cfg(unix)]
+mod activation;
+mod activity;
+mod admin_faults;
+mod auth;
+mod background;
+#[cfg(feature = "s3")]
+mod backup;
+mod caching;
+mod capture;
+mod chaos;
+mod child;
+mod clients;
+#[cfg(feature = "client")]
+pub mod client;
+mod clock;
+mod credentials;
+mod connections;
+mod crud;
+mod daemon;
+mod diff;
+mod discovery;
+mod dns;
+mod docker;
+mod downloads;
+mod duplicate;
+mod election;
+mod error;
+mod error_pages;
+mod failures;
+mod faker;
+mod fallback;
+mod flags;
+mod fleet;
+mod format;
+mod groups;
+mod har;
+mod headers;
+mod health;
+mod history;
+#[cfg(feature = "http3")]
+mod http3;
+mod interim;
+mod interpolate;
+mod journal;
+mod jsonrpc;
+#[cfg(feature = "kubernetes")]
+mod kubernetes;
+mod limits;
+mod load;
+mod logging;
+mod maintenance;
+#[cfg(feature = "mdns")]
+mod mdns;
+mod metrics;
+mod middleware;
+mod mqtt;
+mod multiplex;
+mod objects;
+mod openapi;
+mod ports;
+mod profile;
+mod proxy_protocol;
+mod quota;
+#[cfg(feature = "raft")]
+mod raft;
+mod ratelimit;
+mod readonly;
+mod reaper;
+#[cfg(feature = "redis")]
+mod redis_store;
+mod requestbin;
+mod resolver;
+mod rewrite;
+mod runtime;
+mod schedule;
+mod schema;
+mod seed;
+mod selftest;
+mod server_logs;
+#[cfg(windows)]
+mod service;
+mod shared;
+mod shutdown;
+mod smtp;
+mod snapshot;
+mod sockets;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+mod state;
+mod statsd;
+mod store;
+mod stubs;
+mod supervisor;
+mod synth;
+mod system;
+mod tcp;
+mod templates;
+mod testing;
+mod throttling;
+mod tls;
+mod tombstones;
+#[cfg(feature = "tui")]
+mod tui;
+mod tunnel;
+mod udp;
+mod unmatched;
+mod upgrade;
+mod verify;
+mod vhosts;
+mod watch;
+mod xml;
+
+/// JSON representation of a server instance
+#[derive(Clone, Debug, Default, PartialEq, serde_derive::Deserialize, serde_derive::Serialize)]
+pub struct ServerJsonBody {
+    /// 0, or left out, to have one allocated, see `ports::PortAllocator`
+    #[serde(default)]
+    pub port: u16,
+    #[serde(default)]
+    pub kind: ServerKind,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// Free-form labels, selectable with `GET /?label=`
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub labels: BTreeMap<String, String>,
+    /// The namespace whose quotas the server counts against, see `quota::QuotaConfig`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub namespace: Option<String>,
+    /// Respawn the server with backoff if it crashes
+    #[serde(default)]
+    pub respawn: bool,
+    #[serde(default)]
+    pub isolation: Isolation,
+    /// Delete the server unless `POST /{port}/heartbeat` renews its lease within this many
+    /// seconds
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lease_secs: Option<u64>,
+    /// Ports of servers this one needs. `POST /apply` starts those first, and they can't
+    /// be deleted while this one is up.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub depends_on: Vec<u16>,
+    /// Actions taken on the server at set times, like restarting it every night, see
+    /// `schedule::Schedule`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub schedules: Vec<schedule::Schedule>,
+    /// The template the server was instantiated from, see `templates::Template`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub template: Option<String>,
+    /// Connections an HTTP or TCP server serves at a time. Further ones wait in the
+    /// listen backlog until one closes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_connections: Option<usize>,
+    /// Requests an HTTP server handles at a time. Further ones are answered with 503.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_concurrent_requests: Option<usize>,
+    /// Have the requests an HTTP server can't handle yet wait rather than be answered
+    /// with 503
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub admission_queue: Option<limits::AdmissionQueue>,
+    /// Run on a dedicated thread shared by the servers naming the same runtime, rather
+    /// than on the shared runtime
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub runtime: Option<String>,
+    #[serde(default, skip_serializing_if = "SocketOptions::is_default")]
+    pub socket: SocketOptions,
+    /// How long and for how many requests an in-process HTTP server keeps connections open
+    #[serde(default, skip_serializing_if = "ConnectionOptions::is_default")]
+    pub connection: ConnectionOptions,
+    /// Take every connection of an in-process HTTP server to start with a PROXY protocol
+    /// header, v1 or v2, as from haproxy, and the client it tells for the one the requests
+    /// come from, see `proxy_protocol::accept`. Connections without one are closed.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub proxy_protocol: bool,
+    /// How much an in-process HTTP server keeps of the requests it serves, see
+    /// `GET /{port}/requests`
+    #[serde(default, skip_serializing_if = "JournalLimits::is_default")]
+    pub journal: JournalLimits,
+    /// Layers an in-process HTTP server applies to every request, in order, see
+    /// `middleware::Layer`. Left out, those `MIDDLEWARE` sets or else the built-in ones.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub middleware: Option<Vec<middleware::Layer>>,
+    /// Headers an in-process HTTP server sets on every response it answers, like `Server`
+    /// or `X-Env: mock`, in place of its own
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub response_headers: BTreeMap<String, String>,
+    /// Requests an in-process HTTP server forwards to another server by their headers,
+    /// the first route matching a request taking it
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub header_routes: Vec<headers::HeaderRoute>,
+    /// Large bodies an in-process HTTP server serves by path, in byte ranges if asked
+    /// for, see `downloads::Download`
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub downloads: BTreeMap<String, downloads::Download>,
+    /// Headers an in-process HTTP server adds to, sets on or strips from every request
+    /// before its routes see it, or every response it answers, as a gateway in front of it
+    /// would, see `rewrite::HeaderRule`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub header_rules: Vec<rewrite::HeaderRule>,
+    /// Requests an in-process HTTP server fails on purpose, like all after the first few
+    /// or every third, answered before any header route
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub failure_rules: Vec<failures::FailureRule>,
+    /// Requests an in-process HTTP server limits for each client, answering with
+    /// rate-limit headers and 429 once a client has none left, before any failure rule
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub rate_limit_rules: Vec<throttling::RateLimitRule>,
+    /// How an in-process HTTP server tags what it answers with for clients to revalidate,
+    /// answering `304` to the requests for what they have, see `caching::ConditionalRequests`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub conditional_requests: Option<caching::ConditionalRequests>,
+    /// The `Cache-Control` an in-process HTTP server gives its responses, by request, see
+    /// `caching::CacheRule`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub cache_rules: Vec<caching::CacheRule>,
+    /// The clients an in-process HTTP server answers, by their address, any by default.
+    /// Others get a 403, as from a service behind an allowlist of addresses.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub allowed_clients: Vec<clients::ClientPattern>,
+    /// The credential an in-process HTTP server requires of every request, answering
+    /// others with 401 or 403, see `credentials::AuthSimulation`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub auth: Option<credentials::AuthSimulation>,
+    /// What an in-process HTTP server answers the requests none of its routes take with,
+    /// in place of a 404 or 405, like a canned response or a real service to pass them on to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fallback: Option<fallback::Fallback>,
+    /// What an in-process HTTP server answers its 404s, 405s and 500s with in place of
+    /// its own bodies, by status, see `error_pages::ErrorPage`
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub error_pages: BTreeMap<u16, error_pages::ErrorPage>,
+    /// An OpenAPI 3 document, in JSON, whose operations an in-process HTTP server answers
+    /// with their examples, checking the requests they take against it, see
+    /// `openapi::Contract`. Tried after header routes and before the fallback.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub openapi: Option<serde_json::Value>,
+    /// Collections an in-process HTTP server serves generic REST over, kept in its state,
+    /// see `crud::Crud`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub crud: Option<crud::Crud>,
+    /// The feature flags an in-process HTTP server evaluates as a flag provider, see
+    /// `flags::FeatureFlags`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub feature_flags: Option<flags::FeatureFlags>,
+    /// The buckets of an in-process HTTP server acting as S3-compatible object storage,
+    /// see `objects::ObjectStorage`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub object_storage: Option<objects::ObjectStorage>,
+    /// What an in-process HTTP server acting as a request bin answers every request
+    /// with, see `requestbin::RequestBin`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_bin: Option<requestbin::RequestBin>,
+    /// Seed of the response bodies an in-process HTTP server makes up from schemas and
+    /// templates, to have them the same every time it starts. By default `seed`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub example_seed: Option<u64>,
+    /// Seed of every random choice of an in-process HTTP server, to repeat a run exactly,
+    /// see `seed::SharedSeed`. By default taken from the clock, see `GET /{port}/seed`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seed: Option<u64>,
+    /// Logical servers sharing an in-process HTTP server's listener, by the host name
+    /// they're dispatched by, see `GET /{port}/hosts`
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub virtual_hosts: BTreeMap<String, vhosts::VirtualHost>,
+    /// Serve an in-process HTTP server over TLS
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tls: Option<TlsConfig>,
+    // Runtime state, reported by the registry and cleared on input by `into_config`
+    #[serde(default)]
+    pub status: ServerStatus,
+    #[serde(default)]
+    pub restarts: u32,
+    /// Unix time the server was first created, kept across restarts
+    #[serde(default)]
+    pub created_at: u64,
+    /// Seconds since the server was last (re)started, while it's up
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub uptime_secs: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lease_remaining_secs: Option<u64>,
+    /// Response times of an HTTP server since it was (re)started
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub latency: Option<metrics::LatencySummary>,
+    /// Connections and requests an in-process HTTP or TCP server is serving
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub usage: Option<limits::UsageSummary>,
+    /// The addresses an in-process HTTP, TCP or SMTP server listens on, `socket.bind_addr`
+    /// as it was resolved when the server last started
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub addresses: Vec<std::net::SocketAddr>,
+    /// Changes whenever the server does, see `RunningServer::etag`
+    #[serde(default)]
+    pub resource_version: u64,
+    /// Authorizes deleting this server and nothing else, see `auth::authorize`. Only
+    /// reported by the `POST /` that created it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deletion_token: Option<String>,
+}
+
+impl ServerJsonBody {
+    /// The configuration part of a server received as input, without the runtime state
+    fn into_config(self) -> ServerJsonBody {
+        ServerJsonBody {
+            status: ServerStatus::default(),
+            restarts: 0,
+            created_at: 0,
+            uptime_secs: None,
+            lease_remaining_secs: None,
+            latency: None,
+            usage: None,
+            addresses: Vec::new(),
+            resource_version: 0,
+            deletion_token: None,
+            ..self
+        }
+    }
+
+    /// Whether the server shares its `RunningServer::paused` flag with the listener
+    fn pausable(&self) -> bool {
+        self.isolation == Isolation::InProcess && self.kind.backend().in_process()
+    }
+}
+
+/// JSON body of error responses
+#[derive(Debug, serde_derive::Serialize)]
+struct ErrorJsonBody {
+    error: String,
+}
+
+/// The protocol spoken by a server
+#[derive(Clone, Debug, Default, PartialEq, serde_derive::Deserialize, serde_derive::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ServerKind {
+    /// A replica of this app's HTTP admin API
+    #[default]
+    Http,
+    /// A raw TCP listener, see `tcp::TcpMode`
+    Tcp(tcp::TcpMode),
+    /// A UDP listener, see `udp::UdpMode`
+    Udp(udp::UdpMode),
+    /// A DNS server answering from a zone map, see `dns::Zone`
+    Dns(dns::Zone),
+    /// An SMTP server keeping the messages it's sent, see `smtp::Smtp`
+    Smtp(smtp::Smtp),
+    /// A Docker container, see `docker::Container`
+    Docker(docker::Container),
+}
+
+impl ServerKind {
+    /// What runs servers of this kind
+    fn backend(&self) -> &'static dyn ServerBackend {
+        match self {
+            ServerKind::Docker(_) => &docker::Docker,
+            _ => &InProcess,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            ServerKind::Http => "http",
+            ServerKind::Tcp(_) => "tcp",
+            ServerKind::Udp(_) => "udp",
+            ServerKind::Dns(_) => "dns",
+            ServerKind::Smtp(_) => "smtp",
+            ServerKind::Docker(_) => "docker",
+        }
+    }
+}
+
+/// Where a server runs
+#[derive(Clone, Copy, Debug, Default, PartialEq, serde_derive::Deserialize, serde_derive::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Isolation {
+    /// As a task on the runtime of the process that created it
+    #[default]
+    InProcess,
+    /// In a child process of its own, so a panic only takes down that server
+    Subprocess,
+}
+
+/// Lifecycle state of a server
+#[derive(Clone, Copy, Debug, Default, PartialEq, serde_derive::Deserialize, serde_derive::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ServerStatus {
+    /// Bound, but not yet picked up by the runtime
+    #[default]
+    Starting,
+    Running,
+    /// Bound, but refusing to serve until resumed
+    Paused,
+    /// Shutdown signalled, waiting for the server to finish
+    Draining,
+    /// Shut down, can be started again
+    Stopped,
+    /// The server stopped without being shut down
+    Crashed,
+}
+
+impl ServerStatus {
+    fn name(self) -> &'static str {
+        match self {
+            ServerStatus::Starting => "starting",
+            ServerStatus::Running => "running",
+            ServerStatus::Paused => "paused",
+            ServerStatus::Draining => "draining",
+            ServerStatus::Stopped => "stopped",
+            ServerStatus::Crashed => "crashed",
+        }
+    }
+
+    /// Whether a handler may move a server from this state to `next`. Leaving `starting`
+    /// and `draining` is up to the supervisor.
+    fn can_become(self, next: ServerStatus) -> bool {
+        use ServerStatus::*;
+
+        matches!(
+            (self, next),
+            (Running, Paused) | (Paused, Running)
+                | (Starting, Draining) | (Running, Draining) | (Paused, Draining)
+                | (Stopped, Starting) | (Crashed, Starting)
+        )
+    }
+}
+
+/// A state change requested through `POST /{port}/{action}`
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ServerAction {
+    Pause,
+    Resume,
+    Stop,
+    Start,
+}
+
+impl ServerAction {
+    fn target_status(self) -> ServerStatus {
+        match self {
+            ServerAction::Pause => ServerStatus::Paused,
+            ServerAction::Resume => ServerStatus::Running,
+            ServerAction::Stop => ServerStatus::Draining,
+            ServerAction::Start => ServerStatus::Starting,
+        }
+    }
+}
+
+impl std::str::FromStr for ServerAction {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, ()> {
+        match s {
+            "pause" => Ok(ServerAction::Pause),
+            "resume" => Ok(ServerAction::Resume),
+            "stop" => Ok(ServerAction::Stop),
+            "start" => Ok(ServerAction::Start),
+            _ => Err(()),
+        }
+    }
+}
+
+/// `?dry_run=true` of `POST /` and `POST /apply`, checking what they'd do without doing it
+#[derive(Debug, Default, serde_derive::Deserialize)]
+struct DryRunQuery {
+    #[serde(default)]
+    dry_run: bool,
+}
+
+/// Body of `POST /apply`: every server that should exist, and nothing else
+#[derive(Debug, serde_derive::Deserialize)]
+struct DesiredState {
+    servers: Vec<ServerJsonBody>,
+}
+
+/// What `POST /apply` did to each port
+#[derive(Debug, Default, serde_derive::Serialize)]
+struct ApplySummary {
+    created: Vec<u16>,
+    updated: Vec<u16>,
+    deleted: Vec<u16>,
+    unchanged: Vec<u16>,
+    failed: Vec<ApplyFailure>,
+}
+
+#[derive(Debug, serde_derive::Serialize)]
+struct ApplyFailure {
+    port: u16,
+    error: String,
+}
+
+/// In memory representation of a running server.
+struct RunningServer {
+    // Signal for shutting down the server, taken once it's been sent
+    shutdown: Option<futures::sync::oneshot::Sender<()>>,
+    // The task running the server's future, to tell whether it ended once told to
+    task: Arc<shutdown::Handle>,
+    // Makes the listener refuse service while the server is paused
+    paused: Arc<AtomicBool>,
+    // Milliseconds an in-process HTTP server holds back its responses, set by chaos
+    delay_ms: Arc<AtomicU64>,
+    // A handle on the server's TCP listener while it's up, to hand over on upgrade
+    listener: Option<std::net::TcpListener>,
+    // Handles on the listeners of the addresses after the first, while it's up, kept
+    // across restarts
+    extra_listeners: Vec<std::net::TcpListener>,
+    // The addresses the server's TCP listeners are bound to
+    addresses: Vec<std::net::SocketAddr>,
+    // Response times recorded by an in-process HTTP server
+    latency: Option<Arc<Mutex<metrics::LatencyHistogram>>>,
+    // Connections and requests being served by an in-process HTTP or TCP server
+    usage: Option<Arc<limits::Usage>>,
+    // The requests served by an in-process HTTP server, kept across restarts
+    journal: Option<Arc<Mutex<journal::Journal>>>,
+    // The tasks spawned for the server and its connections, kept across restarts
+    tasks: Arc<runtime::Tasks>,
+    // The capture of an in-process HTTP server's traffic, if one is running
+    capture: Option<capture::SharedCapture>,
+    // What an HTTP server serving TLS is set up with
+    tls: Option<tls::Tls>,
+    // The virtual hosts an in-process HTTP server dispatches to
+    virtual_hosts: Option<vhosts::SharedHosts>,
+    // The feature flags an in-process HTTP server evaluates, if it's a flag provider
+    flags: Option<flags::SharedFlags>,
+    // The buckets of an in-process HTTP server acting as object storage, kept across restarts
+    objects: Option<objects::SharedStore>,
+    // The messages an in-process SMTP server accepted, kept across restarts
+    emails: Option<smtp::SharedMailbox>,
+    // How many requests each stub of an in-process HTTP server matched
+    stub_hits: Option<Arc<stubs::Hits>>,
+    // What an in-process HTTP server keeps between requests, while it's up
+    state: Option<state::SharedState>,
+    // The latency and error profile applied to an in-process HTTP server, kept across restarts
+    profile: Option<profile::SharedProfile>,
+    // The time an in-process HTTP server tells, kept across restarts
+    clock: Option<clock::SharedClock>,
+    // The seed of an in-process HTTP server's random choices, kept across restarts
+    seed: Option<seed::SharedSeed>,
+    // The version of what an in-process HTTP server serves, kept across restarts
+    content_version: Option<caching::SharedContentVersion>,
+    // Distinguishes this spawn from earlier or later servers on the same port
+    id: usize,
+    // The configuration the server was created from
+    config: ServerJsonBody,
+    status: ServerStatus,
+    restarts: u32,
+    created_at: u64,
+    started_at: Instant,
+    lease_renewed_at: Instant,
+    resource_version: u64,
+}
+
+impl RunningServer {
+    /// JSON representation of the server, including its runtime state
+    fn json_body(&self) -> ServerJsonBody {
+        ServerJsonBody {
+            status: self.status,
+            restarts: self.restarts,
+            created_at: self.created_at,
+            uptime_secs: Some(self.started_at.elapsed().as_secs()).filter(|_| self.is_up()),
+            lease_remaining_secs: self.config.lease_secs
+                .map(|lease| lease.saturating_sub(self.lease_renewed_at.elapsed().as_secs())),
+            latency: self.latency.as_ref().and_then(|latency| lock(latency).summary()),
+            usage: self.usage.as_ref().map(|usage| usage.summary()),
+            addresses: self.addresses.clone(),
+            resource_version: self.resource_version,
+            ..self.config.clone()
+        }
+    }
+
+    /// Whether the server has an uptime
+    fn is_up(&self) -> bool {
+        !matches!(self.status, ServerStatus::Stopped | ServerStatus::Crashed)
+    }
+
+    fn final_stats(&self) -> FinalStats {
+        FinalStats {
+            server: self.json_body(),
+            requests: self.journal.as_ref().map(|journal| lock(journal).summary()),
+            shutdown: None,
+        }
+    }
+
+    fn etag(&self) -> String {
+        format!("\"{}\"", self.resource_version)
+    }
+
+    /// Whether an `If-Match` header value allows modifying the server as it is now
+    fn matches_etag(&self, if_match: &str) -> bool {
+        let etag = self.etag();
+        if_match.split(',')
+            .map(str::trim)
+            .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+    }
+
+    /// The answer to a change of the server conditional on `if_match`, if it's refused:
+    /// 412 with the server as it is if the tag doesn't match, or 428 if there's none and
+    /// one is `required`, see `Registry::require_if_match`
+    fn precondition(&self, if_match: Option<&str>, required: bool) -> Option<warp::reply::Response> {
+        match if_match {
+            Some(if_match) if !self.matches_etag(if_match) => {
+                Some(server_reply(self, warp::http::StatusCode::PRECONDITION_FAILED))
+            }
+            None if required => {
+                let error = "changes to a server need an If-Match header with its ETag";
+                Some(error_reply(warp::http::StatusCode::PRECONDITION_REQUIRED, error))
+            }
+            _ => None,
+        }
+    }
+
+    fn lease_expired(&self) -> bool {
+        self.config.lease_secs
+            .is_some_and(|lease| self.lease_renewed_at.elapsed().as_secs() >= lease)
+    }
+
+    fn signal_shutdown(&mut self) {
+        if let Some(shutdown) = self.shutdown.take() {
+            // A crashed server has already dropped its end of the channel, having ended
+            let delivered = shutdown.send(()).is_ok();
+            self.task.signalled(delivered);
+        }
+    }
+}
+
+/// A spawnable server future, whatever its kind
+type ServerFuture = Box<dyn futures::future::Future<Item = (), Error = ()> + Send>;
+
+/// Query parameters of `GET /`
+#[derive(Debug, serde_derive::Deserialize)]
+struct ListQuery {
+    #[serde(default)]
+    sort: SortKey,
+    #[serde(default)]
+    desc: bool,
+    port_min: Option<u16>,
+    port_max: Option<u16>,
+    status: Option<ServerStatus>,
+    /// Label selector, see `labels_match`
+    label: Option<String>,
+    /// Case insensitive substring of the server name
+    name_contains: Option<String>,
+    min_uptime_secs: Option<u64>,
+    max_uptime_secs: Option<u64>,
+    created_after: Option<u64>,
+    created_before: Option<u64>,
+    /// How to render the list, in place of what `Accept` asks for
+    format: Option<format::Format>,
+}
+
+/// What `GET /` orders servers by
+#[derive(Clone, Copy, Debug, Default, serde_derive::Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum SortKey {
+    #[default]
+    Port,
+    CreatedAt,
+    Uptime,
+}
+
+impl ListQuery {
+    fn matches(&self, server: &ServerJsonBody) -> bool {
+        let uptime_in_range = match server.uptime_secs {
+            Some(uptime) => {
+                self.min_uptime_secs.is_none_or(|min| uptime >= min)
+                    && self.max_uptime_secs.is_none_or(|max| uptime <= max)
+            }
+            None => self.min_uptime_secs.is_none() && self.max_uptime_secs.is_none(),
+        };
+
+        let name_matches = match (&self.name_contains, &server.name) {
+            (Some(needle), Some(name)) => name.to_lowercase().contains(&needle.to_lowercase()),
+            (Some(_), None) => false,
+            (None, _) => true,
+        };
+
+        uptime_in_range
+            && name_matches
+            && self.port_min.is_none_or(|min| server.port >= min)
+            && self.port_max.is_none_or(|max| server.port <= max)
+            && self.status.is_none_or(|status| server.status == status)
+            && self.label.as_ref().is_none_or(|selector| labels_match(selector, &server.labels))
+            && self.created_after.is_none_or(|after| server.created_at > after)
+            && self.created_before.is_none_or(|before| server.created_at < before)
+    }
+
+    fn sort(&self, servers: &mut [ServerJsonBody]) {
+        servers.sort_by_key(|server| server.port);
+
+        match self.sort {
+            SortKey::Port => {}
+            SortKey::CreatedAt => servers.sort_by_key(|server| server.created_at),
+            SortKey::Uptime => servers.sort_by_key(|server| server.uptime_secs),
+        }
+
+        if self.desc {
+            servers.reverse();
+        }
+    }
+}
+
+/// Order the ports of `servers` so that each comes after the servers it depends on,
+/// or fail with a port on a dependency cycle
+fn startup_order(servers: &BTreeMap<u16, ServerJsonBody>) -> Result<Vec<u16>, u16> {
+    let mut order = Vec::with_capacity(servers.len());
+    let mut pending: Vec<u16> = servers.keys().cloned().collect();
+
+    while !pending.is_empty() {
+        let (ready, blocked): (Vec<u16>, Vec<u16>) = pending.iter().partition(|port| {
+            servers[port].depends_on.iter()
+                .all(|dependency| !servers.contains_key(dependency) || order.contains(dependency))
+        });
+        if ready.is_empty() {
+            return Err(blocked[0]);
+        }
+        order.extend(ready);
+        pending = blocked;
+    }
+
+    Ok(order)
+}
+
+/// Whether `labels` satisfy a comma separated selector of `key=value` terms, which
+/// require a label with that value, and bare `key` terms, which require the label to exist
+fn labels_match(selector: &str, labels: &BTreeMap<String, String>) -> bool {
+    selector.split(',')
+        .map(str::trim)
+        .filter(|term| !term.is_empty())
+        .all(|term| match term.split_once('=') {
+            Some((key, value)) => labels.get(key.trim()).is_some_and(|label| label == value.trim()),
+            None => labels.contains_key(term),
+        })
+}
+
+/// The application state
+#[derive(Default)]
+struct Registry {
+    /// Each running server is keyed by its listening port
+    servers: HashMap<u16, RunningServer>,
+    /// Port of the root server the process started, which the admin plane is managed
+    /// through. It's only deleted when forced, as every other server would be left
+    /// without it.
+    root_port: Option<u16>,
+    /// Creates made with an `Idempotency-Key`, by key
+    idempotent_creates: HashMap<String, IdempotentCreate>,
+    /// The ports `POST /` is creating servers on, with the `Idempotency-Key` of the create
+    /// if any, reserved while their listeners are bound with the registry let go of
+    reserved_ports: HashMap<u16, Option<String>>,
+    feed: watch::Feed,
+    templates: BTreeMap<String, templates::Template>,
+    /// Servers managed together, by name, see `groups::Group`
+    groups: BTreeMap<String, groups::Group>,
+    /// Picks the port of servers created without one
+    port_allocator: Box<dyn PortAllocator>,
+    /// Dedicated runtimes by name, started by the first server naming them
+    runtimes: HashMap<String, tokio::runtime::current_thread::Handle>,
+    /// Listening sockets opened before their server was started, such as those passed
+    /// by systemd, by port
+    inherited_listeners: HashMap<u16, std::net::TcpListener>,
+    /// TLS set up before the registry was locked to start the server on the port, see
+    /// `prepare_tls`
+    prepared_tls: HashMap<u16, tls::Tls>,
+    /// The fault injection schedule set with `PUT /chaos`
+    chaos: Option<chaos::Chaos>,
+    /// The lease reaper, the scheduler and the like, see `background::Background`
+    background: background::Background,
+    /// The maintenance window set with `PUT /maintenance`
+    maintenance: maintenance::SharedWindow,
+    /// Keeps the servers beyond the process, see `store::from_env`
+    store: Box<dyn Store>,
+    /// What the admin API reads without taking the registry's lock, see `shared::Shared`
+    shared: Arc<shared::Shared>,
+    /// The servers deleted lately, see `tombstones::Tombstones`
+    tombstones: tombstones::Tombstones,
+    /// The tasks of the server futures yet to end, see `shutdown::Shutdowns`
+    shutdowns: shutdown::Shutdowns,
+    /// The tokens handed out by `POST /` for deleting the server it created, by port
+    deletion_tokens: HashMap<u16, String>,
+    /// Journal limits of servers that leave them out, see `journal::JournalLimits`
+    journal_defaults: JournalLimits,
+    /// Middleware of servers that leave it out, see `middleware::from_env`
+    middleware: Option<Vec<middleware::Layer>>,
+    /// Limits of servers and recorded bytes, see `quota::QuotaConfig`
+    quotas: quota::Quotas,
+    /// Registers the servers as services, see `discovery::DiscoveryConfig`
+    discovery: Option<discovery::Discovery>,
+    /// Advertises the servers on the local network, see `mdns::Advertiser`
+    #[cfg(feature = "mdns")]
+    mdns: Option<mdns::Advertiser>,
+    /// Where the servers push their metrics, see `statsd::Statsd`
+    statsd: Option<Arc<statsd::Statsd>>,
+    /// Publishes changes and requests to an MQTT broker, see `mqtt::Publisher`
+    mqtt: Option<mqtt::Publisher>,
+    /// Where the servers log their requests and errors, see `server_logs::ServerLogs`
+    server_logs: Option<Arc<server_logs::ServerLogs>>,
+    /// Whether the root server exposes the others at `/s/{port}/`, see `multiplex::from_env`
+    multiplexed: bool,
+    /// Whether each server was healthy when last probed in the background, see
+    /// `health::check_periodically`
+    health: BTreeMap<u16, bool>,
+    /// Whether updates, deletes and actions of a server are refused without an `If-Match`,
+    /// from `REQUIRE_IF_MATCH`, so that no client changes a server it hasn't seen as it is
+    require_if_match: bool,
+}
+
+impl Registry {
+    /// Give the server on `port` a new resource version and publish it to watchers.
+    /// Every change to a registered server must be followed by this.
+    fn record_change(&mut self, port: u16, change: watch::ChangeType) {
+        if let Some(server) = self.servers.get_mut(&port) {
+            if let Some(journal) = &server.journal {
+                let room = self.quotas.journal_room(&server.config);
+                lock(journal).charge_to(self.quotas.budgets(server.config.namespace.as_deref()), room);
+            }
+            server.resource_version = self.feed.publish(change, server.json_body());
+            self.shared.list(port, Some(server));
+            if let Some(discovery) = &mut self.discovery {
+                discovery.update(&server.json_body());
+            }
+            #[cfg(feature = "mdns")]
+            if let Some(mdns) = &mut self.mdns {
+                mdns.update(&server.json_body());
+            }
+            self.save(port);
+        }
+    }
+
+    fn save(&mut self, port: u16) {
+        if let Some(server) = self.servers.get(&port) {
+            if let Err(e) = self.store.save(&server.json_body()) {
+                eprintln!("failed to store server {}: {}", port, e);
+            }
+        }
+    }
+
+    /// Like `record_change`, for a fault injected by the chaos schedule
+    fn record_chaos(&mut self, port: u16, action: chaos::ChaosAction) {
+        if let Some(server) = self.servers.get_mut(&port) {
+            server.resource_version = self.feed.publish_chaos(action, server.json_body());
+            self.shared.list(port, Some(server));
+            self.save(port);
+        }
+    }
+
+    fn dedicated_runtime(&mut self, name: &str) -> std::io::Result<tokio::runtime::current_thread::Handle> {
+        if let Some(handle) = self.runtimes.get(name) {
+            return Ok(handle.clone());
+        }
+        let handle = runtime::spawn_dedicated(name)?;
+        self.runtimes.insert(name.to_string(), handle.clone());
+        Ok(handle)
+    }
+
+    /// Whether a server is registered on `port`, or one is being created on it
+    fn is_taken(&self, port: u16) -> bool {
+        self.servers.contains_key(&port) || self.reserved_ports.contains_key(&port)
+    }
+
+    fn allocate_port(&mut self) -> Result<u16, error::Error> {
+        let (servers, reserved_ports) = (&self.servers, &self.reserved_ports);
+        self.port_allocator.allocate(&|port| servers.contains_key(&port) || reserved_ports.contains_key(&port))
+            .map_err(error::Error::AllocatePort)
+    }
+
+    /// Unregister the server on `port`, leaving a tombstone, and signal its shutdown
+    fn remove_server(&mut self, port: u16) -> Option<RunningServer> {
+        let mut server = self.servers.remove(&port)?;
+        self.tombstones.bury(server.final_stats());
+        self.deletion_tokens.remove(&port);
+        for group in self.groups.values_mut() {
+            group.leave(port);
+        }
+        server.signal_shutdown();
+        server.resource_version = self.feed.publish(watch::ChangeType::Deleted, server.json_body());
+        self.shared.list(port, None);
+        if let Some(discovery) = &mut self.discovery {
+            discovery.deregister(port);
+        }
+        #[cfg(feature = "mdns")]
+        if let Some(mdns) = &mut self.mdns {
+            mdns.withdraw(port);
+        }
+        if let Err(e) = self.store.delete(port) {
+            eprintln!("failed to delete stored server {}: {}", port, e);
+        }
+        Some(server)
+    }
+}
+
+/// The "Database"
+type Database = Arc<Mutex<Registry>>;
+
+/// How long an `Idempotency-Key` is remembered
+const IDEMPOTENCY_KEY_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// How long a server deleted through its own admin API drains for, answering the delete
+/// in the meantime, before it's shut down
+const SELF_DELETE_GRACE: Duration = Duration::from_millis(500);
+
+/// How long a delete waits for the server's task to end once it's told to shut down,
+/// before aborting it
+const DELETE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The outcome of a `POST /` made with an `Idempotency-Key`
+struct IdempotentCreate {
+    request: ServerJsonBody,
+    response: ServerJsonBody,
+    created_at: Instant,
+}
+
+/// Source of `RunningServer::id`
+static NEXT_SERVER_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// JSON representation of a server with its ETag
+fn server_reply(server: &RunningServer, status: warp::http::StatusCode) -> warp::reply::Response {
+    let reply = warp::reply::with_status(warp::reply::json(&server.json_body()), status);
+    warp::reply::with_header(reply, "etag", server.etag()).into_response()
+}
+
+fn error_reply(status: warp::http::StatusCode, error: &str) -> warp::reply::Response {
+    let body = ErrorJsonBody { error: error.to_string() };
+    warp::reply::with_status(warp::reply::json(&body), status).into_response()
+}
+
+fn port_taken(port: u16) -> warp::reply::Response {
+    error_reply(warp::http::StatusCode::CONFLICT, &format!("port {} is already taken", port))
+}
+
+/// List all running servers matching the query, in the requested order, as of the last
+/// change to any of them, so it never waits on changes being made
+fn list_servers(
+    shared: Arc<shared::Shared>,
+    format: format::Format,
+    query: ListQuery
+) -> impl warp::Reply {
+    let mut json_array: Vec<ServerJsonBody> = shared.servers().values()
+        .map(shared::Listed::json_body)
+        .filter(|server| query.matches(server))
+        .collect();
+
+    query.sort(&mut json_array);
+
+    format::servers(query.format.unwrap_or(format), &json_array)
+}
+
+/// Get a single server by port
+fn get_server(
+    database: Database,
+    format: format::Format,
+    port: u16
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let registry = lock(&database);
+
+    match registry.servers.get(&port) {
+        Some(server) => {
+            let reply = format::server(format, &server.json_body());
+            Ok(warp::reply::with_header(reply, "etag", server.etag()))
+        }
+        None => Err(warp::reject::not_found())
+    }
+}
+
+/// Create a new server described by ServerJsonBody, on an allocated port if it has none.
+///
+/// Repeating a create with the same `Idempotency-Key` returns the original result, even
+/// if the server has changed or been deleted since. Reusing a key for a different
+/// request is an error.
+///
+/// The port is reserved while the registry is locked, so of creates racing for a port
+/// one wins and the others are answered with 409. Its listener is bound and its TLS set
+/// up with the registry let go of, and once it's locked again the server is registered
+/// as `starting`, or the reservation dropped if it can't be started. The supervisor has
+/// it `running` once the runtime picks it up.
+fn post_new_server(
+    database: Database,
+    query: DryRunQuery,
+    idempotency_key: Option<String>,
+    request: ServerJsonBody
+) -> Result<impl warp::Reply, warp::Rejection> {
+    // Whether the server's listener is to be bound, unless one was inherited
+    let (body, bind) = {
+        let mut registry = lock(&database);
+        let registry = &mut *registry;
+        let mut body = request.clone();
+
+        // Answered with the config that would be started, its port left 0 if it was to be
+        // allocated
+        if query.dry_run {
+            if body.port != 0 && registry.is_taken(body.port) {
+                return Ok(port_taken(body.port));
+            }
+            check_config(registry, &body).map_err(warp::reject::custom)?;
+            return Ok(warp::reply::json(&body).into_response());
+        }
+
+        registry.idempotent_creates.retain(|_, create| create.created_at.elapsed() < IDEMPOTENCY_KEY_TTL);
+
+        if let Some(create) = idempotency_key.as_ref().and_then(|key| registry.idempotent_creates.get(key)) {
+            if create.request != request {
+                let error = "Idempotency-Key was already used for a different request";
+                return Ok(error_reply(warp::http::StatusCode::UNPROCESSABLE_ENTITY, error));
+            }
+            return Ok(warp::reply::json(&create.response).into_response());
+        }
+        if idempotency_key.is_some() && registry.reserved_ports.values().any(|key| *key == idempotency_key) {
+            let error = "a create with this Idempotency-Key is in progress";
+            return Ok(error_reply(warp::http::StatusCode::CONFLICT, error));
+        }
+
+        if body.port == 0 {
+            body.port = registry.allocate_port().map_err(warp::reject::custom)?;
+        } else if registry.is_taken(body.port) {
+            return Ok(port_taken(body.port));
+        }
+        registry.reserved_ports.insert(body.port, idempotency_key.clone());
+        let bind = !registry.inherited_listeners.contains_key(&body.port);
+        (body, bind)
+    };
+    let port = body.port;
+    let listener = if bind { bind_first(&body) } else { Ok(None) };
+    let prepared_tls = listener.as_ref().ok().and_then(|_| prepare_tls(port, &body));
+
+    let mut registry = lock(&database);
+    let registry = &mut *registry;
+    registry.reserved_ports.remove(&port);
+    let listener = listener.map_err(|source| warp::reject::custom(error::Error::StartServer { port, source }))?;
+    if registry.servers.contains_key(&port) {
+        return Ok(port_taken(port));
+    }
+    let deletion_token = auth::new_token()
+        .map_err(|e| warp::reject::custom(error::Error::DeletionToken(e)))?;
+    if let Some(listener) = listener {
+        registry.inherited_listeners.insert(port, listener);
+    }
+    if let Some(tls) = prepared_tls {
+        registry.prepared_tls.insert(port, tls);
+    }
+    if let Err(e) = start_server(&database, registry, body, 0) {
+        if bind {
+            registry.inherited_listeners.remove(&port);
+        }
+        return Err(warp::reject::custom(e));
+    }
+    registry.deletion_tokens.insert(port, deletion_token.clone());
+
+    let server = &registry.servers[&port];
+    let response = ServerJsonBody { deletion_token: Some(deletion_token), ..server.json_body() };
+    if let Some(key) = idempotency_key {
+        registry.idempotent_creates.insert(key, IdempotentCreate {
+            request,
+            response: response.clone(),
+            created_at: Instant::now(),
+        });
+    }
+
+    let reply = warp::reply::json(&response);
+    Ok(warp::reply::with_header(reply, "etag", server.etag()).into_response())
+}
+
+/// Update the configuration of a server in place. Its port, kind and isolation can't
+/// change, as that would take a different listener.
+fn update_server(
+    database: Database,
+    port: u16,
+    if_match: Option<String>,
+    body: ServerJsonBody
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let mut registry = lock(&database);
+    let registry = &mut *registry;
+    let required = registry.require_if_match;
+    let server = registry.servers.get_mut(&port).ok_or_else(warp::reject::not_found)?;
+
+    if let Some(refused) = server.precondition(if_match.as_deref(), required) {
+        return Ok(refused);
+    }
+
+    if body.port != port || body.kind != server.config.kind || body.isolation != server.config.isolation {
+        let error = "the port, kind and isolation of a server can't be changed";
+        return Ok(error_reply(warp::http::StatusCode::CONFLICT, error));
+    }
+
+    if let Err(e) = schedule::check(&body.schedules) {
+        return Ok(error_reply(warp::http::StatusCode::UNPROCESSABLE_ENTITY, &e.to_string()));
+    }
+
+    if body.namespace != server.config.namespace {
+        quota::check_servers(registry, port, body.namespace.as_deref()).map_err(warp::reject::custom)?;
+    }
+    quota::check_memory(registry, &body).map_err(warp::reject::custom)?;
+
+    let server = registry.servers.get_mut(&port).ok_or_else(warp::reject::not_found)?;
+    if let (Some(seed), Some(value)) = (&server.seed, body.seed) {
+        if server.config.seed != body.seed {
+            seed.set(value);
+        }
+    }
+    server.config = body;
+    if let Some(journal) = &server.journal {
+        lock(journal).set_retention(server.config.journal.resolve(&registry.journal_defaults));
+    }
+    registry.record_change(port, watch::ChangeType::Modified);
+    Ok(server_reply(&registry.servers[&port], warp::http::StatusCode::OK))
+}
+
+/// Kill a server by port. A server that others which are up depend on can't be deleted.
+/// Query parameters of `DELETE /{port}`
+#[derive(Debug, serde_derive::Deserialize)]
+struct DeleteQuery {
+    /// Answer with the server's `FinalStats` rather than 204
+    #[serde(default)]
+    stats: bool,
+    /// Delete the root server too, and abort the server's task rather than wait for it
+    /// to shut down, along with those of earlier servers on the port that never did
+    #[serde(default)]
+    force: bool,
+}
+
+/// Body of `DELETE /{port}?stats=true`: the server as it was just before it was deleted
+#[derive(Debug, serde_derive::Serialize)]
+struct FinalStats {
+    /// With its uptime, response times and what it was serving
+    server: ServerJsonBody,
+    /// What the requests an in-process HTTP server still had in its journal were
+    /// answered with
+    #[serde(skip_serializing_if = "Option::is_none")]
+    requests: Option<journal::Summary>,
+    /// How the server's task ended, when it's been waited for
+    #[serde(skip_serializing_if = "Option::is_none")]
+    shutdown: Option<shutdown::Outcome>,
+}
+
+/// Delete the server on `port`, unless it's the root server and the delete isn't forced.
+/// The server drains until its task has ended, or `DELETE_TIMEOUT` is up and it's
+/// aborted, then it's removed, the answer telling which with `x-shutdown: graceful` or
+/// `forced`. A forced delete aborts the task at once, as it does the tasks left over of
+/// servers deleted on the port before, see `shutdown::Leak`.
+///
+/// A server asked to delete itself, `port` being `own_port`, is left draining until its
+/// answer is sent, and removed once `SELF_DELETE_GRACE` is up.
+fn delete_server(
+    database: Database,
+    port: u16,
+    own_port: u16,
+    if_match: Option<String>,
+    query: DeleteQuery
+) -> Box<dyn futures::Future<Item = warp::reply::Response, Error = warp::Rejection> + Send> {
+    let answer = |reply: warp::reply::Response| -> Box<dyn futures::Future<Item = _, Error = _> + Send> {
+        Box::new(futures::future::ok(reply))
+    };
+    let (id, shut_down) = {
+        let mut registry = lock(&database);
+        if !registry.servers.contains_key(&port) && query.force && registry.shutdowns.abort(port, None) > 0 {
+            return answer(warp::http::StatusCode::NO_CONTENT.into_response());
+        }
+        let server = match registry.servers.get(&port) {
+            Some(server) => server,
+            None => return Box::new(futures::future::err(warp::reject::not_found())),
+        };
+
+        if let Some(refused) = server.precondition(if_match.as_deref(), registry.require_if_match) {
+            return answer(refused);
+        }
+
+        if registry.root_port == Some(port) && !query.force {
+            let error = "this is the root server, which is only deleted with ?force=true";
+            return answer(error_reply(warp::http::StatusCode::CONFLICT, error));
+        }
+
+        let dependent = registry.servers.values()
+            .filter(|dependent| !matches!(dependent.status, ServerStatus::Stopped | ServerStatus::Crashed))
+            .find(|dependent| dependent.config.depends_on.contains(&port));
+        if let Some(dependent) = dependent {
+            let error = format!("server {} depends on this server", dependent.config.port);
+            return answer(error_reply(warp::http::StatusCode::CONFLICT, &error));
+        }
+
+        let id = drain(&mut registry, port);
+        if port == own_port {
+            // Taken under the same lock as the server is removed, so nothing it serves is missed
+            let stats = query.stats.then(|| registry.servers[&port].final_stats());
+            let database = database.clone();
+            runtime::spawn(tokio::timer::Delay::new(Instant::now() + SELF_DELETE_GRACE)
+                .map_err(|e| eprintln!("delete timer error: {}", e))
+                .map(move |_| {
+                    let mut registry = lock(&database);
+                    if registry.servers.get(&port).is_some_and(|server| server.id == id) {
+                        registry.remove_server(port);
+                    }
+                }));
+            return answer(match stats {
+                Some(stats) => warp::reply::json(&stats).into_response(),
+                None => warp::http::StatusCode::NO_CONTENT.into_response(),
+            });
+        }
+        (id, shut_down(&mut registry, port, query.force))
+    };
+
+    Box::new(shut_down.and_then(move |outcome| {
+        let mut registry = lock(&database);
+        match registry.servers.get(&port) {
+            Some(server) if server.id == id => {}
+            Some(_) => {
+                let error = "the server was started again while it was being deleted";
+                return Ok(error_reply(warp::http::StatusCode::CONFLICT, error));
+            }
+            None => return Err(warp::reject::not_found()),
+        }
+
+        // Taken under the same lock as the server is removed, so nothing it serves is missed
+        let stats = query.stats.then(|| registry.servers[&port].final_stats());
+        remove_shut_down(&mut registry, port, outcome, query.force);
+        let reply = match stats {
+            Some(stats) => warp::reply::json(&FinalStats { shutdown: Some(outcome), ..stats }).into_response(),
+            None => warp::http::StatusCode::NO_CONTENT.into_response(),
+        };
+        Ok(warp::reply::with_header(reply, "x-shutdown", outcome.as_str()).into_response())
+    }))
+}
+
+/// Mark the server on `port` draining, as it's being deleted, answering the id it was
+/// spawned as
+fn drain(registry: &mut Registry, port: u16) -> usize {
+    let server = registry.servers.get_mut(&port).unwrap();
+    let id = server.id;
+    if server.status.can_become(ServerStatus::Draining) {
+        server.status = ServerStatus::Draining;
+        registry.record_change(port, watch::ChangeType::Modified);
+    }
+    id
+}
+
+/// Tell the draining server on `port` to shut down, and wait for its task to end, up to
+/// `DELETE_TIMEOUT`, or not at all if `force`d, resolving to whether it did
+fn shut_down(
+    registry: &mut Registry,
+    port: u16,
+    force: bool
+) -> impl Future<Item = shutdown::Outcome, Error = warp::Rejection> + Send {
+    let server = registry.servers.get_mut(&port).unwrap();
+    server.signal_shutdown();
+    let task = server.task.clone();
+    let timeout = if force { Duration::ZERO } else { DELETE_TIMEOUT };
+    task.ended_within(timeout).then(move |ended| {
+        if ended.unwrap_or(false) || task.ended() {
+            return Ok(shutdown::Outcome::Graceful);
+        }
+        if !force {
+            eprintln!("server {} didn't shut down within {}s, aborting it", port, DELETE_TIMEOUT.as_secs());
+        }
+        Ok(shutdown::Outcome::Forced)
+    })
+}
+
+/// Remove the server on `port`, its task having shut down with `outcome`. The task is
+/// aborted unless it ended, as are the tasks left over of servers deleted on the port
+/// before if the delete is `force`d.
+fn remove_shut_down(registry: &mut Registry, port: u16, outcome: shutdown::Outcome, force: bool) {
+    registry.remove_server(port);
+    if force || outcome == shutdown::Outcome::Forced {
+        registry.shutdowns.abort(port, None);
+    }
+}
+
+/// Query parameters of `POST /{port}/clone`
+#[derive(Debug, serde_derive::Deserialize)]
+struct CloneQuery {
+    /// Port of the clone, allocated if left out
+    port: Option<u16>,
+}
+
+/// Create a server with the same configuration as the one on `port`
+fn clone_server(
+    database: Database,
+    port: u16,
+    query: CloneQuery
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let mut registry = lock(&database);
+    let config = registry.servers.get(&port).ok_or_else(warp::reject::not_found)?.config.clone();
+
+    let target = match query.port {
+        Some(target) => target,
+        None => registry.allocate_port().map_err(warp::reject::custom)?,
+    };
+    if registry.servers.contains_key(&target) {
+        return Ok(port_taken(target));
+    }
+
+    let config = ServerJsonBody { port: target, ..config };
+    start_server(&database, &mut registry, config, 0)
+        .map_err(warp::reject::custom)?;
+
+    Ok(server_reply(&registry.servers[&target], warp::http::StatusCode::OK))
+}
+
+/// Renew the lease of a server
+fn heartbeat(
+    database: Database,
+    port: u16
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let mut registry = lock(&database);
+
+    match registry.servers.get_mut(&port) {
+        Some(server) => {
+            server.lease_renewed_at = Instant::now();
+            let reply = server_reply(server, warp::http::StatusCode::OK);
+            registry.shared.list(port, registry.servers.get(&port));
+            Ok(reply)
+        }
+        None => Err(warp::reject::not_found())
+    }
+}
+
+/// Why `act` left a server as it was
+enum Refusal {
+    /// Its status doesn't allow the transition
+    Transition,
+    Conflict(&'static str),
+    Failed(error::Error),
+}
+
+/// Take `action` on the server on `port`
+fn act(database: &Database, registry: &mut Registry, port: u16, action: ServerAction) -> Result<(), Refusal> {
+    let server = registry.servers.get_mut(&port).expect("acted on servers exist");
+
+    let pausing = matches!(action, ServerAction::Pause | ServerAction::Resume);
+    if pausing && !server.config.pausable() {
+        return Err(Refusal::Conflict("servers running in a subprocess or container can't be paused"));
+    }
+
+    if !server.status.can_become(action.target_status()) {
+        return Err(Refusal::Transition);
+    }
+
+    match action {
+        ServerAction::Pause | ServerAction::Resume => {
+            server.paused.store(action == ServerAction::Pause, Ordering::SeqCst);
+            server.status = action.target_status();
+            registry.record_change(port, watch::ChangeType::Modified);
+        }
+        ServerAction::Stop => {
+            server.status = ServerStatus::Draining;
+            server.signal_shutdown();
+            registry.record_change(port, watch::ChangeType::Modified);
+        }
+        ServerAction::Start => {
+            let config = server.config.clone();
+            let restarts = server.restarts;
+            start_server(database, registry, config, restarts).map_err(Refusal::Failed)?;
+        }
+    }
+    Ok(())
+}
+
+/// Move a server through its lifecycle. Transitions not allowed from the server's
+/// current state are answered with 409 and the unchanged server.
+fn server_action(
+    database: Database,
+    port: u16,
+    action: ServerAction,
+    if_match: Option<String>
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let mut registry = lock(&database);
+    let server = registry.servers.get(&port).ok_or_else(warp::reject::not_found)?;
+
+    if let Some(refused) = server.precondition(if_match.as_deref(), registry.require_if_match) {
+        return Ok(refused);
+    }
+
+    match act(&database, &mut registry, port, action) {
+        Ok(()) => Ok(server_reply(&registry.servers[&port], warp::http::StatusCode::OK)),
+        Err(Refusal::Transition) => Ok(server_reply(&registry.servers[&port], warp::http::StatusCode::CONFLICT)),
+        Err(Refusal::Conflict(error)) => Ok(error_reply(warp::http::StatusCode::CONFLICT, error)),
+        Err(Refusal::Failed(e)) => Err(warp::reject::custom(e)),
+    }
+}
+
+/// Query parameters of `DELETE /` and `POST /{action}`
+#[derive(Debug, serde_derive::Deserialize)]
+struct BulkQuery {
+    /// Label selector of the servers acted on, see `labels_match`
+    label: Option<String>,
+    /// Delete the root server too, if it's selected
+    #[serde(default)]
+    force: bool,
+}
+
+/// What `DELETE /` and `POST /{action}` did to the servers selected
+#[derive(Debug, Default, serde_derive::Serialize)]
+struct BulkSummary {
+    affected: Vec<u16>,
+    failed: Vec<ApplyFailure>,
+    /// How the tasks of the servers deleted shut down
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    shutdown: BTreeMap<u16, shutdown::Outcome>,
+}
+
+/// The ports of the servers `query` selects, or an error if it selects none by label, so
+/// as not to act on every server by mistake
+fn select(registry: &Registry, query: &BulkQuery) -> Result<Vec<u16>, &'static str> {
+    let selector = query.label.as_deref().map(str::trim).unwrap_or_default();
+    if selector.is_empty() {
+        return Err("servers are selected by label, like ?label=suite%3Dcheckout");
+    }
+    Ok(registry.servers.iter()
+        .filter(|(_, server)| labels_match(selector, &server.config.labels))
+        .map(|(port, _)| *port)
+        .collect())
+}
+
+/// `DELETE /?label=`: delete every server the selector matches, see `delete_ports`
+fn delete_servers(
+    database: Database,
+    own_port: u16,
+    query: BulkQuery
+) -> Box<dyn futures::Future<Item = warp::reply::Response, Error = warp::Rejection> + Send> {
+    let ports = match select(&lock(&database), &query) {
+        Ok(ports) => ports,
+        Err(error) => return Box::new(futures::future::ok(error_reply(warp::http::StatusCode::BAD_REQUEST, error))),
+    };
+    Box::new(delete_ports(&database, &ports, own_port, query.force)
+        .map(|summary| warp::reply::json(&summary).into_response()))
+}
+
+/// Delete the servers on `ports`, like `DELETE /{port}`, except the server answering the
+/// request, on `own_port`, the root server unless `force`d, and servers that others
+/// which are up and not deleted with them depend on. They're all told to shut down at
+/// once, and removed once their tasks have ended or been aborted, `shutdown` telling
+/// which.
+fn delete_ports(
+    database: &Database,
+    ports: &[u16],
+    own_port: u16,
+    force: bool
+) -> impl Future<Item = BulkSummary, Error = warp::Rejection> + Send {
+    let mut registry = lock(database);
+    let mut summary = BulkSummary::default();
+    let mut deleting = Vec::new();
+    for &port in ports {
+        let dependent = registry.servers.values()
+            .filter(|dependent| !matches!(dependent.status, ServerStatus::Stopped | ServerStatus::Crashed))
+            .find(|dependent| !ports.contains(&dependent.config.port) && dependent.config.depends_on.contains(&port));
+        let error = if port == own_port {
+            Some("this server is answering the request".to_string())
+        } else if registry.root_port == Some(port) && !force {
+            Some("this is the root server, which is only deleted with ?force=true".to_string())
+        } else {
+            dependent.map(|dependent| format!("server {} depends on this server", dependent.config.port))
+        };
+        match error {
+            Some(error) => summary.failed.push(ApplyFailure { port, error }),
+            None => deleting.push(port),
+        }
+    }
+    let shut_downs: Vec<_> = deleting.into_iter()
+        .map(|port| {
+            let id = drain(&mut registry, port);
+            shut_down(&mut registry, port, force).map(move |outcome| (port, id, outcome))
+        })
+        .collect();
+    drop(registry);
+
+    let database = database.clone();
+    futures::future::join_all(shut_downs).map(move |outcomes| {
+        let mut registry = lock(&database);
+        for (port, id, outcome) in outcomes {
+            if registry.servers.get(&port).is_some_and(|server| server.id == id) {
+                remove_shut_down(&mut registry, port, outcome, force);
+                summary.affected.push(port);
+                summary.shutdown.insert(port, outcome);
+            } else {
+                let error = "the server was deleted or started again while it was being deleted".to_string();
+                summary.failed.push(ApplyFailure { port, error });
+            }
+        }
+        summary
+    })
+}
+
+/// `POST /{action}?label=`: take `action` on every server the selector matches, see
+/// `act_on_ports`
+fn bulk_action(database: Database, action: ServerAction, query: BulkQuery) -> warp::reply::Response {
+    let mut registry = lock(&database);
+    match select(&registry, &query) {
+        Ok(ports) => warp::reply::json(&act_on_ports(&database, &mut registry, &ports, action)).into_response(),
+        Err(error) => error_reply(warp::http::StatusCode::BAD_REQUEST, error),
+    }
+}
+
+/// Take `action` on the servers on `ports`, like `POST /{port}/{action}`, listing those it
+/// couldn't be taken on with the reason
+fn act_on_ports(database: &Database, registry: &mut Registry, ports: &[u16], action: ServerAction) -> BulkSummary {
+    let mut summary = BulkSummary::default();
+    for &port in ports {
+        match act(database, registry, port, action) {
+            Ok(()) => summary.affected.push(port),
+            Err(refusal) => {
+                let error = match refusal {
+                    Refusal::Transition => {
+                        let (status, target) = (registry.servers[&port].status, action.target_status());
+                        format!("a {:?} server can't become {:?}", status, target).to_lowercase()
+                    }
+                    Refusal::Conflict(error) => error.to_string(),
+                    Refusal::Failed(e) => e.to_string(),
+                };
+                summary.failed.push(ApplyFailure { port, error });
+            }
+        }
+    }
+    summary
+}
+
+/// `POST /apply`, see `converge`
+fn apply(
+    database: Database,
+    own_port: u16,
+    query: DryRunQuery,
+    desired: DesiredState
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let mut registry = lock(&database);
+    Ok(match converge(&database, &mut registry, own_port, desired.servers, query.dry_run) {
+        Ok(summary) => warp::reply::json(&summary).into_response(),
+        Err(error) => error_reply(warp::http::StatusCode::UNPROCESSABLE_ENTITY, &error),
+    })
+}
+
+/// Converge the registry on a desired state: create servers missing from it, update the
+/// configuration of those that differ and delete those not in it. The server on
+/// `own_port`, which is answering the request, and the root server are never deleted.
+///
+/// Servers are converged in dependency order, and every server's dependencies must be
+/// in the desired state as well. Every port is attempted; those that couldn't be
+/// converged, such as servers whose kind would have to change or whose dependency
+/// failed, are listed as failed in the summary. A desired state that can't be converged
+/// on at all is refused with the reason.
+///
+/// A `dry_run` changes nothing, summing up what converging would do instead, with the
+/// servers to create or update checked by `check_config`.
+fn converge(
+    database: &Database,
+    registry: &mut Registry,
+    own_port: u16,
+    servers: Vec<ServerJsonBody>,
+    dry_run: bool
+) -> Result<ApplySummary, String> {
+    let mut desired_by_port = BTreeMap::new();
+    for server in servers {
+        let server = server.into_config();
+        let port = server.port;
+        if port == 0 {
+            return Err("every desired server needs a port".to_string());
+        }
+        if desired_by_port.insert(port, server).is_some() {
+            return Err(format!("port {} is listed more than once", port));
+        }
+    }
+
+    let missing = desired_by_port.values()
+        .flat_map(|server| server.depends_on.iter().map(move |dependency| (server.port, *dependency)))
+        .find(|(_, dependency)| *dependency != own_port && !desired_by_port.contains_key(dependency));
+    if let Some((port, dependency)) = missing {
+        return Err(format!("port {} depends on {}, which isn't in the desired state", port, dependency));
+    }
+
+    let order = match startup_order(&desired_by_port) {
+        Ok(order) => order,
+        Err(port) => return Err(format!("port {} is part of a dependency cycle", port)),
+    };
+
+    let mut summary = ApplySummary::default();
+
+    let mut undesired: Vec<u16> = registry.servers.keys()
+        .filter(|port| **port != own_port && Some(**port) != registry.root_port)
+        .filter(|port| !desired_by_port.contains_key(port))
+        .cloned()
+        .collect();
+    undesired.sort();
+    for port in undesired {
+        if !dry_run {
+            registry.remove_server(port);
+        }
+        summary.deleted.push(port);
+    }
+
+    for port in order {
+        let config = desired_by_port.remove(&port).unwrap();
+
+        let failed_dependency = config.depends_on.iter()
+            .find(|dependency| summary.failed.iter().any(|failure| failure.port == **dependency));
+        if let Some(dependency) = failed_dependency {
+            let error = format!("dependency {} failed", dependency);
+            summary.failed.push(ApplyFailure { port, error });
+            continue;
+        }
+
+        let server = match registry.servers.get(&port) {
+            Some(server) => server,
+            None => {
+                let started = if dry_run {
+                    check_config(registry, &config)
+                } else {
+                    start_server(database, registry, config, 0)
+                };
+                match started {
+                    Ok(()) => summary.created.push(port),
+                    Err(e) => summary.failed.push(ApplyFailure { port, error: e.to_string() }),
+                }
+                continue;
+            }
+        };
+        let quota = if server.config.namespace == config.namespace {
+            Ok(())
+        } else {
+            quota::check_servers(registry, port, config.namespace.as_deref())
+        };
+        let quota = quota.and_then(|()| quota::check_memory(registry, &config));
+
+        if server.config == config {
+            summary.unchanged.push(port);
+        } else if server.config.kind != config.kind || server.config.isolation != config.isolation {
+            let error = "the kind and isolation of a server can't be changed".to_string();
+            summary.failed.push(ApplyFailure { port, error });
+        } else if let Err(e) = quota {
+            summary.failed.push(ApplyFailure { port, error: e.to_string() });
+        } else if dry_run {
+            match check_config(registry, &config) {
+                Ok(()) => summary.updated.push(port),
+                Err(e) => summary.failed.push(ApplyFailure { port, error: e.to_string() }),
+            }
+        } else {
+            if let Some(server) = registry.servers.get_mut(&port) {
+                server.config = config;
+            }
+            registry.record_change(port, watch::ChangeType::Modified);
+            summary.updated.push(port);
+        }
+    }
+
+    Ok(summary)
+}
+
+/// A server configuration in the request body
+fn config_body() -> impl Filter<Extract = (ServerJsonBody,), Error = warp::Rejection> + Copy {
+    warp::body::json().map(ServerJsonBody::into_config)
+}
+
+/// Create a warp filter representing the app's HTTP routes and handlers, as served on
+/// `port`
+fn app_filter(
+    database: Database,
+    shared: Arc<shared::Shared>,
+    port: u16
+) -> warp::filters::BoxedFilter<(impl warp::reply::Reply,)> {
+    // `/s/{port}/…` - the server on `port`, when the root server multiplexes the others
+    let multiplexed = multiplex::filter(database.clone(), port);
+    let db_arg = warp::any().map(move || database.clone());
+    let shared_arg = warp::any().map(move || shared.clone());
+    let if_match_arg = warp::header::optional::<String>("if-match");
+    let format_arg = warp::header::optional::<String>("accept")
+        .map(|accept: Option<String>| format::Format::from_accept(accept.as_deref()));
+
+    // `GET /?sort=&status=&label=&name_contains=&format=&...` - list mock servers
+    let get = shared_arg.clone()
+        .and(format_arg)
+        .and(warp::path::end())
+        .and(warp::get2())
+        .and(warp::query())
+        .map(list_servers);
+
+    // `POST /?template={name}&port={port}` - start mock server from a template
+    let instantiate = db_arg.clone()
+        .and(warp::path::end())
+        .and(warp::post2())
+        .and(warp::query())
+        .and_then(templates::instantiate);
+
+    // `POST /?dry_run=true` - start mock server, or only check it could be
+    let post = db_arg.clone()
+        .and(warp::path::end())
+        .and(warp::post2())
+        .and(warp::query())
+        .and(warp::header::optional::<String>("idempotency-key"))
+        .and(config_body())
+        .and_then(post_new_server);
+
+    // `GET /{port}` - get mock server
+    let get_one = db_arg.clone()
+        .and(format_arg)
+        .and(path!(u16))
+        .and(warp::get2())
+        .and_then(get_server);
+
+    // `PUT /{port}` - update mock server configuration
+    let put = db_arg.clone()
+        .and(path!(u16))
+        .and(warp::put2())
+        .and(warp::path::end())
+        .and(if_match_arg)
+        .and(config_body())
+        .and_then(update_server);
+
+    // 'DELETE /{port}?stats=true&force=true' - delete mock server, optionally answering with its
+    // final stats, or the root server if forced
+    let delete = db_arg.clone()
+        .and(path!(u16))
+        .and(warp::delete2())
+        .and(warp::any().map(move || port))
+        .and(if_match_arg)
+        .and(warp::query())
+        .and_then(delete_server);
+
+    // `DELETE /?label={selector}&force=true` - delete the mock servers matching a label selector
+    let delete_selected = db_arg.clone()
+        .and(warp::path::end())
+        .and(warp::delete2())
+        .and(warp::any().map(move || port))
+        .and(warp::query())
+        .and_then(delete_servers);
+
+    // `GET /watch?since={revision}` - stream registry changes
+    let watch = db_arg.clone()
+        .and(path!("watch"))
+        .and(warp::get2())
+        .and(warp::path::end())
+        .and(warp::query())
+        .map(watch::watch);
+
+    // `GET|POST /groups` - list groups of mock servers, or create one
+    let list_groups = db_arg.clone()
+        .and(path!("groups"))
+        .and(warp::get2())
+        .and(warp::path::end())
+        .map(groups::list_groups);
+    let create_group = db_arg.clone()
+        .and(path!("groups"))
+        .and(warp::post2())
+        .and(warp::path::end())
+        .and(warp::body::json())
+        .map(groups::create_group);
+
+    // `GET|DELETE /groups/{name}?keep_servers=true&force=true` - get a group, or delete it
+    // along with its mock servers
+    let get_group = db_arg.clone()
+        .and(path!("groups" / String))
+        .and(warp::get2())
+        .and(warp::path::end())
+        .map(groups::get_group);
+    let delete_group = db_arg.clone()
+        .and(path!("groups" / String))
+        .and(warp::delete2())
+        .and(warp::path::end())
+        .and(warp::any().map(move || port))
+        .and(warp::query())
+        .and_then(groups::delete_group);
+
+    // `PUT|DELETE /groups/{name}/members/{port}` - add a mock server to a group, or take it out
+    let add_group_member = db_arg.clone()
+        .and(path!("groups" / String / "members" / u16))
+        .and(warp::put2())
+        .and(warp::path::end())
+        .map(groups::add_member);
+    let remove_group_member = db_arg.clone()
+        .and(path!("groups" / String / "members" / u16))
+        .and(warp::delete2())
+        .and(warp::path::end())
+        .map(groups::remove_member);
+
+    // `GET /groups/{name}/stats` - sum up what the mock servers of a group are serving
+    let group_stats = db_arg.clone()
+        .and(path!("groups" / String / "stats"))
+        .and(warp::get2())
+        .and(warp::path::end())
+        .map(groups::group_stats);
+
+    // `POST /groups/{name}/{action}` - pause, resume, stop, start or restart the mock servers
+    // of a group
+    let group_action = db_arg.clone()
+        .and(path!("groups" / String))
+        .and(warp::path::param::<groups::GroupAction>())
+        .and(warp::post2())
+        .and(warp::path::end())
+        .map(groups::group_action);
+
+    // `GET /templates` - list server templates
+    let list_templates = db_arg.clone()
+        .and(path!("templates"))
+        .and(warp::get2())
+        .and(warp::path::end())
+        .map(templates::list_templates);
+
+    // `POST /templates` - register a server template
+    let register_template = db_arg.clone()
+        .and(path!("templates"))
+        .and(warp::post2())
+        .and(warp::path::end())
+        .and(warp::body::json())
+        .map(templates::register_template);
+
+    // `GET /diff?a={port}&b={port}` - compare two mock server configurations
+    let diff = db_arg.clone()
+        .and(path!("diff"))
+        .and(warp::get2())
+        .and(warp::path::end())
+        .and(warp::query())
+        .map(diff::diff_servers);
+
+    // `GET /quota` - usage of the global and per-namespace quotas
+    let quota = db_arg.clone()
+        .and(path!("quota"))
+        .and(warp::get2())
+        .and(warp::path::end())
+        .map(quota::quota);
+
+    // `GET /metrics` - latency histograms in the Prometheus text format
+    let metrics = db_arg.clone()
+        .and(path!("metrics"))
+        .and(warp::get2())
+        .and(warp::path::end())
+        .map(metrics::metrics);
+
+    // `GET /system` - the process's use of memory, file descriptors and tasks
+    let system = db_arg.clone()
+        .and(path!("system"))
+        .and(warp::get2())
+        .and(warp::path::end())
+        .map(system::system);
+
+    // `GET /healthz?path=&concurrency=&timeout_ms=` - probe every mock server
+    let healthz = db_arg.clone()
+        .and(path!("healthz"))
+        .and(warp::get2())
+        .and(warp::path::end())
+        .and(warp::query())
+        .and_then(health::healthz);
+
+    // `POST /apply` - converge on a desired set of mock servers
+    let apply = db_arg.clone()
+        .and(path!("apply"))
+        .and(warp::post2())
+        .and(warp::path::end())
+        .and(warp::any().map(move || port))
+        .and(warp::query())
+        .and(warp::body::json())
+        .and_then(apply);
+
+    // `POST /rpc` - the routes above as JSON-RPC 2.0 calls, also served over a WebSocket
+    // upgrading `GET /rpc`
+    let rpc = db_arg.clone()
+        .and(path!("rpc"))
+        .and(warp::post2())
+        .and(warp::path::end())
+        .and(warp::any().map(move || port))
+        .and(warp::body::concat())
+        .and_then(jsonrpc::rpc);
+    let rpc_socket = db_arg.clone()
+        .and(path!("rpc"))
+        .and(warp::get2())
+        .and(warp::path::end())
+        .and(warp::any().map(move || port))
+        .and(warp::ws2())
+        .map(jsonrpc::rpc_socket);
+
+    // `POST /upgrade?binary={path}` - re-exec into a new binary, keeping listeners open
+    let upgrade = db_arg.clone()
+        .and(path!("upgrade"))
+        .and(warp::post2())
+        .and(warp::path::end())
+        .and(warp::query())
+        .map(upgrade::upgrade);
+
+    // `GET /fleet` - list the mock servers of every instance sharing the registry
+    let list_fleet = db_arg.clone()
+        .and(path!("fleet"))
+        .and(warp::get2())
+        .and(warp::path::end())
+        .map(fleet::list_fleet);
+
+    // `GET /fleet/watch` - stream changes on every instance sharing the registry
+    let watch_fleet = db_arg.clone()
+        .and(path!("fleet" / "watch"))
+        .and(warp::get2())
+        .and(warp::path::end())
+        .map(fleet::watch_fleet);
+
+    // `GET /history?at={revision}` - the mock servers as of a past revision
+    let fleet_history = db_arg.clone()
+        .and(path!("history"))
+        .and(warp::get2())
+        .and(warp::path::end())
+        .and(warp::query())
+        .map(history::fleet_history);
+
+    // `GET /history/{port}` - every change of the mock servers on a port
+    let server_history = db_arg.clone()
+        .and(path!("history" / u16))
+        .and(warp::get2())
+        .and(warp::path::end())
+        .map(history::server_history);
+
+    // `GET /snapshot` - capture every mock server and template
+    let snapshot = db_arg.clone()
+        .and(path!("snapshot"))
+        .and(warp::get2())
+        .and(warp::path::end())
+        .and(warp::any().map(move || port))
+        .map(snapshot::snapshot);
+
+    // `POST /restore?from=s3://{bucket}/{key}` - rebuild the fleet from a backup
+    #[cfg(feature = "s3")]
+    let restore_backup = db_arg.clone()
+        .and(path!("restore"))
+        .and(warp::post2())
+        .and(warp::path::end())
+        .and(warp::any().map(move || port))
+        .and(warp::query())
+        .and_then(backup::restore_from);
+
+    // `POST /restore` - rebuild the fleet captured by `GET /snapshot`
+    let restore = db_arg.clone()
+        .and(path!("restore"))
+        .and(warp::post2())
+        .and(warp::path::end())
+        .and(warp::any().map(move || port))
+        .and(warp::body::json())
+        .map(snapshot::restore);
+    #[cfg(feature = "s3")]
+    let restore = restore_backup.or(restore).unify();
+
+    // `GET|PUT|DELETE /chaos` - inspect, set or stop the fault injection schedule
+    let get_chaos = db_arg.clone()
+        .and(path!("chaos"))
+        .and(warp::get2())
+        .and(warp::path::end())
+        .map(chaos::get_chaos);
+    let put_chaos = db_arg.clone()
+        .and(path!("chaos"))
+        .and(warp::put2())
+        .and(warp::path::end())
+        .and(warp::any().map(move || port))
+        .and(warp::body::json())
+        .map(chaos::start_chaos);
+    let delete_chaos = db_arg.clone()
+        .and(path!("chaos"))
+        .and(warp::delete2())
+        .and(warp::path::end())
+        .map(chaos::stop_chaos);
+
+    // `GET|PUT|DELETE /maintenance` - inspect, set or remove the maintenance window
+    let get_maintenance = db_arg.clone()
+        .and(path!("maintenance"))
+        .and(warp::get2())
+        .and(warp::path::end())
+        .map(maintenance::get_window);
+    let put_maintenance = db_arg.clone()
+        .and(path!("maintenance"))
+        .and(warp::put2())
+        .and(warp::path::end())
+        .and(warp::any().map(move || port))
+        .and(warp::body::json())
+        .map(maintenance::set_window);
+    let delete_maintenance = db_arg.clone()
+        .and(path!("maintenance"))
+        .and(warp::delete2())
+        .and(warp::path::end())
+        .map(maintenance::delete_window);
+
+    // `GET|PUT /admin/readonly` - inspect or toggle refusing changes to the fleet
+    let get_read_only = shared_arg.clone()
+        .and(path!("admin" / "readonly"))
+        .and(warp::get2())
+        .and(warp::path::end())
+        .map(readonly::get_mode);
+    let put_read_only = shared_arg.clone()
+        .and(path!("admin" / "readonly"))
+        .and(warp::put2())
+        .and(warp::path::end())
+        .and(warp::body::json())
+        .map(readonly::set_mode);
+
+    // `GET|PUT|DELETE /admin/faults` - inject latency and errors into the admin API itself
+    let get_admin_faults = shared_arg.clone()
+        .and(path!("admin" / "faults"))
+        .and(warp::get2())
+        .and(warp::path::end())
+        .map(admin_faults::get_faults);
+    let put_admin_faults = shared_arg.clone()
+        .and(path!("admin" / "faults"))
+        .and(warp::put2())
+        .and(warp::path::end())
+        .and(warp::body::json())
+        .map(admin_faults::put_faults);
+    let delete_admin_faults = shared_arg.clone()
+        .and(path!("admin" / "faults"))
+        .and(warp::delete2())
+        .and(warp::path::end())
+        .map(admin_faults::delete_faults);
+
+    // `POST /duplicate?offset={offset}` - duplicate the fleet on ports past its own
+    let duplicate = db_arg.clone()
+        .and(path!("duplicate"))
+        .and(warp::post2())
+        .and(warp::path::end())
+        .and(warp::any().map(move || port))
+        .and(warp::query())
+        .map(duplicate::duplicate);
+
+    // `GET /deleted?port={port}&at={unix time}` - list the mock servers deleted lately
+    let deleted = db_arg.clone()
+        .and(path!("deleted"))
+        .and(warp::get2())
+        .and(warp::path::end())
+        .and(warp::query())
+        .map(tombstones::list_deleted);
+
+    // `POST /{port}/heartbeat` - renew mock server lease
+    let heartbeat = db_arg.clone()
+        .and(path!(u16 / "heartbeat"))
+        .and(warp::post2())
+        .and_then(heartbeat);
+
+    // `POST /{port}/clone?port={port}` - copy mock server to another port
+    let clone = db_arg.clone()
+        .and(path!(u16 / "clone"))
+        .and(warp::post2())
+        .and(warp::path::end())
+        .and(warp::query())
+        .and_then(clone_server);
+
+    // `POST /{port}/load` - drive synthetic traffic at an HTTP mock server
+    let load = db_arg.clone()
+        .and(path!(u16 / "load"))
+        .and(warp::post2())
+        .and(warp::path::end())
+        .and(warp::body::json())
+        .and_then(load::load);
+
+    // `GET|DELETE /{port}/requests` - list or forget the requests an HTTP mock server served
+    let list_requests = db_arg.clone()
+        .and(path!(u16 / "requests"))
+        .and(warp::get2())
+        .and(warp::path::end())
+        .map(journal::list_requests);
+    let clear_requests = db_arg.clone()
+        .and(path!(u16 / "requests"))
+        .and(warp::delete2())
+        .and(warp::path::end())
+        .map(journal::clear_requests);
+
+    // `GET /{port}/requests/stream` - the requests as they're recorded, as server-sent
+    // events or over a WebSocket
+    let stream_requests = db_arg.clone()
+        .and(path!(u16 / "requests" / "stream"))
+        .and(warp::get2())
+        .and(warp::path::end())
+        .and(warp::ws2().map(Some).or(warp::any().map(|| None)).unify())
+        .map(journal::stream_requests);
+
+    // `GET /{port}/tunnel` - upgraded to a WebSocket, a TCP connection to the server
+    let tunnel = db_arg.clone()
+        .and(path!(u16 / "tunnel"))
+        .and(warp::get2())
+        .and(warp::path::end())
+        .and(warp::ws2())
+        .map(tunnel::tunnel);
+
+    // `GET /{port}/upstream-requests` - the requests the server forwarded, as it sent them on
+    let list_upstream_requests = db_arg.clone()
+        .and(path!(u16 / "upstream-requests"))
+        .and(warp::get2())
+        .and(warp::path::end())
+        .map(journal::list_upstream_requests);
+    let clear_upstream_requests = db_arg.clone()
+        .and(path!(u16 / "upstream-requests"))
+        .and(warp::delete2())
+        .and(warp::path::end())
+        .map(journal::clear_upstream_requests);
+
+    // `GET /{port}/emails?subject=&to=&from=` - the messages an SMTP mock server accepted
+    let list_emails = db_arg.clone()
+        .and(path!(u16 / "emails"))
+        .and(warp::get2())
+        .and(warp::path::end())
+        .and(warp::query())
+        .map(smtp::list_emails);
+    let clear_emails = db_arg.clone()
+        .and(path!(u16 / "emails"))
+        .and(warp::delete2())
+        .and(warp::path::end())
+        .map(smtp::clear_emails);
+
+    // `GET /{port}/bin?method=&path=&header=&q=&since=&limit=` - the requests a server
+    // recorded, taken apart, newest first, `GET /{port}/bin/{id}` one of them, and
+    // `GET /{port}/bin/ui` a page to look through them
+    let list_bin = db_arg.clone()
+        .and(path!(u16 / "bin"))
+        .and(warp::get2())
+        .and(warp::path::end())
+        .and(warp::query())
+        .map(requestbin::list_bin);
+    let bin_ui = db_arg.clone()
+        .and(path!(u16 / "bin" / "ui"))
+        .and(warp::get2())
+        .and(warp::path::end())
+        .map(requestbin::bin_ui);
+    let get_bin_request = db_arg.clone()
+        .and(path!(u16 / "bin" / u64))
+        .and(warp::get2())
+        .and(warp::path::end())
+        .map(requestbin::get_bin_request);
+
+    // `GET /{port}/requests/export?format=har` - download the requests as an HTTP Archive
+    let export_requests = db_arg.clone()
+        .and(path!(u16 / "requests" / "export"))
+        .and(warp::get2())
+        .and(warp::path::end())
+        .and(warp::query())
+        .map(har::export_requests);
+
+    // `GET /{port}/unmatched` - the requests no route took, with the routes closest to them
+    let unmatched = db_arg.clone()
+        .and(path!(u16 / "unmatched"))
+        .and(warp::get2())
+        .and(warp::path::end())
+        .map(unmatched::unmatched);
+
+    // `GET /{port}/stubs?resolve={path}&method=&header=` - what takes requests before the
+    // routes do, and which would take a request for the path
+    let stubs = db_arg.clone()
+        .and(path!(u16 / "stubs"))
+        .and(warp::get2())
+        .and(warp::path::end())
+        .and(warp::query())
+        .map(stubs::stubs);
+
+    // `PUT|PATCH /{port}/stubs` - replace every stub of an HTTP mock server at once, or some
+    // kinds of them
+    let put_stubs = db_arg.clone()
+        .and(path!(u16 / "stubs"))
+        .and(warp::put2())
+        .and(warp::path::end())
+        .and(if_match_arg)
+        .and(warp::body::json())
+        .and_then(stubs::replace_stubs);
+    let patch_stubs = db_arg.clone()
+        .and(path!(u16 / "stubs"))
+        .and(warp::patch())
+        .and(warp::path::end())
+        .and(if_match_arg)
+        .and(warp::body::json())
+        .and_then(stubs::patch_stubs);
+
+    // `POST /{port}/stubs/{id}/reset` - count what a stub matches from zero
+    let reset_stub = db_arg.clone()
+        .and(path!(u16 / "stubs" / String / "reset"))
+        .and(warp::post2())
+        .and(warp::path::end())
+        .map(stubs::reset);
+
+    // `GET|PUT /{port}/state` - read or replace what an HTTP mock server keeps between
+    // requests
+    let get_state = db_arg.clone()
+        .and(path!(u16 / "state"))
+        .and(warp::get2())
+        .and(warp::path::end())
+        .map(state::get_state);
+    let put_state = db_arg.clone()
+        .and(path!(u16 / "state"))
+        .and(warp::put2())
+        .and(warp::path::end())
+        .and(warp::body::json())
+        .map(state::put_state);
+
+    // `GET|PUT|DELETE /{port}/profile` - inspect, apply or remove the latency and error
+    // profile of an HTTP mock server
+    let get_profile = db_arg.clone()
+        .and(path!(u16 / "profile"))
+        .and(warp::get2())
+        .and(warp::path::end())
+        .map(profile::get_profile);
+    let put_profile = db_arg.clone()
+        .and(path!(u16 / "profile"))
+        .and(warp::put2())
+        .and(warp::path::end())
+        .and(warp::body::json())
+        .map(profile::put_profile);
+    let delete_profile = db_arg.clone()
+        .and(path!(u16 / "profile"))
+        .and(warp::delete2())
+        .and(warp::path::end())
+        .map(profile::delete_profile);
+
+    // `GET /{port}/config?resolved=true` - the config of a server, with its environment
+    // variables expanded if asked
+    let get_config = db_arg.clone()
+        .and(path!(u16 / "config"))
+        .and(warp::get2())
+        .and(warp::path::end())
+        .and(warp::query())
+        .map(interpolate::get_config);
+
+    // `GET|PUT|DELETE /{port}/clock` - inspect, set or reset the time an HTTP mock server tells
+    let get_clock = db_arg.clone()
+        .and(path!(u16 / "clock"))
+        .and(warp::get2())
+        .and(warp::path::end())
+        .map(clock::get_clock);
+    let put_clock = db_arg.clone()
+        .and(path!(u16 / "clock"))
+        .and(warp::put2())
+        .and(warp::path::end())
+        .and(warp::body::json())
+        .map(clock::put_clock);
+    let delete_clock = db_arg.clone()
+        .and(path!(u16 / "clock"))
+        .and(warp::delete2())
+        .and(warp::path::end())
+        .map(clock::delete_clock);
+
+    // `GET|POST /{port}/content-version` - inspect or bump the version of what an HTTP mock
+    // server serves
+    let get_content_version = db_arg.clone()
+        .and(path!(u16 / "content-version"))
+        .and(warp::get2())
+        .and(warp::path::end())
+        .map(caching::get_content_version);
+    let bump_content_version = db_arg.clone()
+        .and(path!(u16 / "content-version"))
+        .and(warp::post2())
+        .and(warp::path::end())
+        .map(caching::bump_content_version);
+
+    // `GET|PUT /{port}/seed` - inspect or set the seed of an HTTP mock server's random choices
+    let get_seed = db_arg.clone()
+        .and(path!(u16 / "seed"))
+        .and(warp::get2())
+        .and(warp::path::end())
+        .map(seed::get_seed);
+    let put_seed = db_arg.clone()
+        .and(path!(u16 / "seed"))
+        .and(warp::put2())
+        .and(warp::path::end())
+        .and(warp::body::json())
+        .map(seed::put_seed);
+
+    // `GET|POST /{port}/capture` - inspect, start or stop capturing an HTTP mock server's
+    // traffic to pcap files
+    let get_capture = db_arg.clone()
+        .and(path!(u16 / "capture"))
+        .and(warp::get2())
+        .and(warp::path::end())
+        .map(capture::get_capture);
+    let toggle_capture = db_arg.clone()
+        .and(path!(u16 / "capture"))
+        .and(warp::post2())
+        .and(warp::path::end())
+        .and(warp::body::json())
+        .map(capture::toggle_capture);
+
+    // `GET /{port}/hosts` and `PUT|DELETE /{port}/hosts/{host}` - manage the virtual hosts
+    // sharing an HTTP mock server's listener
+    let list_hosts = db_arg.clone()
+        .and(path!(u16 / "hosts"))
+        .and(warp::get2())
+        .and(warp::path::end())
+        .map(vhosts::list_hosts);
+    let put_host = db_arg.clone()
+        .and(path!(u16 / "hosts" / String))
+        .and(warp::put2())
+        .and(warp::path::end())
+        .and(warp::body::json())
+        .and_then(vhosts::put_host);
+    let delete_host = db_arg.clone()
+        .and(path!(u16 / "hosts" / String))
+        .and(warp::delete2())
+        .and(warp::path::end())
+        .map(vhosts::delete_host);
+
+    // `GET /{port}/flags`, `PUT|DELETE /{port}/flags/{key}` - the feature flags an HTTP
+    // mock server evaluates as a flag provider
+    let list_flags = db_arg.clone()
+        .and(path!(u16 / "flags"))
+        .and(warp::get2())
+        .and(warp::path::end())
+        .map(flags::list_flags);
+    let put_flag = db_arg.clone()
+        .and(path!(u16 / "flags" / String))
+        .and(warp::put2())
+        .and(warp::path::end())
+        .and(warp::body::json())
+        .and_then(flags::put_flag);
+    let delete_flag = db_arg.clone()
+        .and(path!(u16 / "flags" / String))
+        .and(warp::delete2())
+        .and(warp::path::end())
+        .map(flags::delete_flag);
+
+    // `GET /{port}/ca.pem` - the CA to trust for an HTTP server with a self-signed certificate
+    let ca_pem = db_arg.clone()
+        .and(path!(u16 / "ca.pem"))
+        .and(warp::get2())
+        .and(warp::path::end())
+        .map(tls::ca_pem);
+
+    // `POST /{port}/verify` - check the requests an HTTP mock server served
+    let verify = db_arg.clone()
+        .and(path!(u16 / "verify"))
+        .and(warp::post2())
+        .and(warp::path::end())
+        .and(warp::body::json())
+        .map(verify::verify);
+
+    // `POST /{port}/requests/diff` - compare the requests an HTTP mock server served with a sequence
+    let diff_requests = db_arg.clone()
+        .and(path!(u16 / "requests" / "diff"))
+        .and(warp::post2())
+        .and(warp::path::end())
+        .and(warp::body::json())
+        .map(verify::diff_sequence);
+
+    // `POST /{port}/{pause|resume|stop|start}` - change mock server state
+    let action = db_arg.clone()
+        .and(path!(u16 / ServerAction))
+        .and(warp::post2())
+        .and(if_match_arg)
+        .and_then(server_action);
+
+    // `POST /{action}?label={selector}` - take an action on the mock servers matching a label selector
+    let action_selected = db_arg.clone()
+        .and(path!(ServerAction))
+        .and(warp::post2())
+        .and(warp::path::end())
+        .and(warp::query())
+        .map(bulk_action);
+
+    // Every route requires the admin token if one is set
+    let authorized = db_arg.clone()
+        .and(shared_arg.clone())
+        .and(warp::header::optional::<String>("authorization"))
+        .and(warp::method())
+        .and(warp::path::full())
+        .and_then(auth::authorize)
+        .untuple_one();
+    // Authorized requests are refused once they'd change anything while read-only
+    let writable = shared_arg.clone()
+        .and(warp::method())
+        .and(warp::path::full())
+        .and_then(readonly::guard)
+        .untuple_one();
+    // Listing, creating and deleting may be held back or failed, see `PUT /admin/faults`
+    let faulty = shared_arg.clone()
+        .and(warp::method())
+        .and(warp::path::full())
+        .and_then(admin_faults::inject)
+        .untuple_one();
+
+    // Listing, creating and deleting servers, and watching them change
+    let registry_routes = get.or(instantiate).or(post).or(delete_selected).or(watch);
+    // Groups of servers
+    let group_routes = list_groups.or(create_group).or(get_group).or(delete_group).or(add_group_member)
+        .or(remove_group_member).or(group_stats).or(group_action);
+    // Templates servers are created from
+    let template_routes = list_templates.or(register_template).or(diff);
+    // Metrics and health
+    let observability_routes = metrics.or(system).or(quota).or(healthz);
+    // Applying manifests, JSON-RPC and upgrading in place
+    let operation_routes = apply.or(rpc).or(rpc_socket).or(upgrade);
+    // Every instance's servers, their history, snapshots and restores
+    let fleet_routes = list_fleet.or(watch_fleet).or(fleet_history).or(server_history).or(deleted)
+        .or(duplicate).or(snapshot).or(restore);
+    // Chaos, maintenance, read-only mode and faults of the admin API
+    let admin_routes = get_chaos.or(put_chaos).or(delete_chaos).or(get_maintenance).or(put_maintenance)
+        .or(delete_maintenance).or(get_read_only).or(put_read_only).or(get_admin_faults).or(put_admin_faults)
+        .or(delete_admin_faults);
+    // What the servers recorded
+    let recording_routes = list_requests.or(stream_requests).or(tunnel).or(export_requests)
+        .or(clear_requests).or(list_upstream_requests).or(clear_upstream_requests).or(list_emails)
+        .or(clear_emails).or(list_bin).or(bin_ui).or(get_bin_request).or(unmatched);
+    // Stubs, state, profiles, clocks, hosts and flags of a server
+    let server_state_routes = stubs.or(put_stubs).or(patch_stubs).or(reset_stub).or(get_state).or(put_state)
+        .or(get_profile).or(put_profile).or(delete_profile).or(get_config).or(get_clock).or(put_clock)
+        .or(delete_clock).or(get_seed).or(put_seed).or(get_content_version).or(bump_content_version)
+        .or(get_capture).or(ca_pem).or(list_hosts).or(put_host).or(delete_host).or(list_flags).or(put_flag)
+        .or(delete_flag);
+    // A server itself, its lifecycle and load
+    let server_routes = get_one.or(put).or(delete).or(heartbeat).or(clone).or(load).or(verify)
+        .or(diff_requests).or(toggle_capture).or(action).or(action_selected);
+
+    // Tried in this order, the first to match answering
+    let routes = registry_routes
+        .or(group_routes)
+        .or(template_routes)
+        .or(observability_routes)
+        .or(operation_routes)
+        .or(fleet_routes)
+        .or(admin_routes)
+        .or(recording_routes)
+        .or(server_state_routes)
+        .or(server_routes);
+    // Multiplexed servers are answered as they'd answer on their own ports
+    multiplexed.or(authorized.and(writable).and(faulty).and(routes)).recover(error::recover).boxed()
+}
+
+/// The listener of the first address of the server `config` describes, bound ahead of
+/// locking the registry to start it, if it listens itself. `start_server` takes it over
+/// as it would one inherited.
+fn bind_first(config: &ServerJsonBody) -> std::io::Result<Option<std::net::TcpListener>> {
+    match config.kind {
+        ServerKind::Http | ServerKind::Tcp(_) | ServerKind::Smtp(_) if config.isolation == Isolation::InProcess => {
+            let config = interpolate::resolve(config)?;
+            let addresses = config.socket.addresses(config.port)?;
+            config.socket.bind_address(addresses[0]).map(Some)
+        }
+        _ => Ok(None),
+    }
+}
+
+/// TLS for the server `config` describes, set up on `port` ahead of locking the registry
+/// to start it, as generating a self-signed certificate takes a while. `start_server`
+/// takes it over if it's for the config it starts the server with, and sets TLS up
+/// itself otherwise, reporting what's wrong with it.
+fn prepare_tls(port: u16, config: &ServerJsonBody) -> Option<tls::Tls> {
+    let config = interpolate::resolve(config).ok()?;
+    tls::setup(port, config.tls.as_ref()?).ok()
+}
+
+/// Create a server described by ServerJsonBody, register it and spawn it under supervision.
+/// A server already registered on the same port is replaced.
+fn start_server(
+    database: &Database,
+    registry: &mut Registry,
+    config: ServerJsonBody,
+    restarts: u32
+) -> Result<(), error::Error> {
+    let port = config.port;
+    let prepared_tls = registry.prepared_tls.remove(&port);
+    if !registry.servers.contains_key(&port) {
+        quota::check_servers(registry, port, config.namespace.as_deref())?;
+    }
+    quota::check_memory(registry, &config)?;
+    let start_error = |source| error::Error::StartServer { port, source };
+    // The server runs as its environment has it, and is kept as it was given
+    let given = config;
+    let config = interpolate::resolve(&given).map_err(start_error)?;
+    schedule::check(&config.schedules).map_err(start_error)?;
+    let id = NEXT_SERVER_ID.fetch_add(1, Ordering::Relaxed);
+    let paused = Arc::new(AtomicBool::new(false));
+    let delay_ms = Arc::new(AtomicU64::new(0));
+
+    // Servers with a TCP listener of their own take over one inherited from systemd or a
+    // previous process if there is one, in place of that of their first address. Those of
+    // the other addresses are kept across restarts.
+    let listeners = match config.kind {
+        ServerKind::Http | ServerKind::Tcp(_) | ServerKind::Smtp(_) if config.isolation == Isolation::InProcess => {
+            let addresses = config.socket.addresses(port).map_err(start_error)?;
+            let mut listeners = vec![match registry.inherited_listeners.remove(&port) {
+                Some(listener) => listener,
+                None => config.socket.bind_address(addresses[0]).map_err(start_error)?,
+            }];
+            for address in &addresses[1..] {
+                let kept = registry.servers.get(&port)
+                    .and_then(|previous| {
+                        previous.extra_listeners.iter().find(|listener| listener.local_addr().ok() == Some(*address))
+                    })
+                    .map(std::net::TcpListener::try_clone)
+                    .transpose()
+                    .map_err(start_error)?;
+                listeners.push(match kept {
+                    Some(listener) => listener,
+                    None => config.socket.bind_address(*address).map_err(start_error)?,
+                });
+            }
+            listeners
+        }
+        _ => Vec::new(),
+    };
+    let addresses = listeners.iter().filter_map(|listener| listener.local_addr().ok()).collect();
+    let mut listener_handles = listeners.iter()
+        .map(std::net::TcpListener::try_clone)
+        .collect::<std::io::Result<Vec<_>>>()
+        .map_err(start_error)?
+        .into_iter();
+    let listener_handle = listener_handles.next();
+    let extra_listeners = listener_handles.collect();
+    let recorded = config.kind == ServerKind::Http && config.isolation == Isolation::InProcess;
+    let latency = Some(Arc::default()).filter(|_| recorded);
+    let retention = config.journal.resolve(&registry.journal_defaults);
+    let journal = match registry.servers.get(&port).and_then(|previous| previous.journal.clone()) {
+        Some(journal) => {
+            lock(&journal).set_retention(retention);
+            Some(journal)
+        }
+        None => Some(Arc::new(Mutex::new(journal::Journal::new(retention)))),
+    }
+    .filter(|_| recorded);
+    if let Some(journal) = &journal {
+        lock(journal).set_credential(config.auth.as_ref().map(|auth| auth.credential.clone()));
+    }
+    let capture = registry.servers.get(&port)
+        .and_then(|previous| previous.capture.clone())
+        .or_else(|| Some(Arc::default()))
+        .filter(|_| recorded);
+    let tls = match &config.tls {
+        Some(_) if !recorded => {
+            let error = std::io::Error::new(std::io::ErrorKind::InvalidInput, "only in-process HTTP servers serve TLS");
+            return Err(start_error(error));
+        }
+        Some(tls_config) => {
+            let previous = registry.servers.get(&port).and_then(|previous| previous.tls.clone());
+            match previous.into_iter().chain(prepared_tls).find(|tls| tls.config == *tls_config) {
+                Some(tls) => Some(tls),
+                None => Some(tls::setup(port, tls_config).map_err(start_error)?),
+            }
+        }
+        None => None,
+    };
+    let virtual_hosts = Some(&config.virtual_hosts)
+        .filter(|_| recorded)
+        .map(|hosts| vhosts::share(port, hosts))
+        .transpose()
+        .map_err(start_error)?;
+    let flags = config.feature_flags.as_ref()
+        .filter(|_| recorded)
+        .map(flags::share)
+        .transpose()
+        .map_err(start_error)?;
+    let runtime = config.runtime.as_ref()
+        .map(|name| registry.dedicated_runtime(name))
+        .transpose()
+        .map_err(start_error)?;
+    let usage = Some(Arc::default()).filter(|_| !listeners.is_empty());
+    let kept_state = Some(state::SharedState::default()).filter(|_| recorded);
+    let profile = registry.servers.get(&port)
+        .and_then(|previous| previous.profile.clone())
+        .or_else(|| Some(Arc::default()))
+        .filter(|_| recorded);
+    let clock = registry.servers.get(&port)
+        .and_then(|previous| previous.clock.clone())
+        .or_else(|| Some(Arc::default()))
+        .filter(|_| recorded);
+    let stub_hits = clock.as_ref().map(|clock| Arc::new(stubs::Hits::new(&config, lock(clock).now_ms())));
+    let emails = registry.servers.get(&port)
+        .and_then(|previous| previous.emails.clone())
+        .or_else(|| Some(Arc::default()))
+        .filter(|_| matches!(config.kind, ServerKind::Smtp(_)) && config.isolation == Isolation::InProcess);
+    let objects = match (config.object_storage.as_ref().filter(|_| recorded), &clock) {
+        (Some(storage), Some(clock)) => match registry.servers.get(&port).and_then(|previous| previous.objects.clone()) {
+            Some(previous) => Some(previous),
+            None => Some(objects::share(port, storage, lock(clock).now_ms()).map_err(start_error)?),
+        },
+        _ => None,
+    };
+    // Set again on every start, starting over what's made from it
+    let seed = registry.servers.get(&port)
+        .and_then(|previous| previous.seed.clone())
+        .or_else(|| Some(seed::SharedSeed::default()))
+        .filter(|_| recorded);
+    if let Some(seed) = &seed {
+        seed.set(config.seed.unwrap_or_else(chaos::Rng::clock_seed));
+    }
+    let content_version = match (registry.servers.get(&port).and_then(|previous| previous.content_version.clone()), &clock) {
+        (Some(previous), _) => Some(previous),
+        (None, Some(clock)) => Some(caching::content_version(lock(clock).now_ms())),
+        (None, None) => None,
+    };
+    let tasks = registry.servers.get(&port)
+        .map(|previous| previous.tasks.clone())
+        .unwrap_or_default();
+    let middleware = config.middleware.clone()
+        .or_else(|| registry.middleware.clone())
+        .unwrap_or_else(middleware::default_layers);
+    let state = HttpState {
+        paused: paused.clone(),
+        delay_ms: delay_ms.clone(),
+        latency: latency.clone().unwrap_or_default(),
+        usage: usage.clone().unwrap_or_default(),
+        journal: journal.clone().unwrap_or_default(),
+        capture: capture.clone().unwrap_or_default(),
+        tls: tls.clone(),
+        virtual_hosts: virtual_hosts.clone().unwrap_or_default(),
+        flags: flags.clone().unwrap_or_default(),
+        objects: objects.clone(),
+        emails: emails.clone(),
+        stub_hits: stub_hits.clone().unwrap_or_default(),
+        state: kept_state.clone().unwrap_or_default(),
+        profile: profile.clone().unwrap_or_default(),
+        clock: clock.clone().unwrap_or_default(),
+        seed: seed.clone().unwrap_or_default(),
+        content_version: content_version.clone().unwrap_or_default(),
+        middleware,
+        maintenance: registry.maintenance.clone(),
+        statsd: registry.statsd.as_ref().map(|statsd| statsd.emitter(port, &config.labels)),
+        mqtt: registry.mqtt.clone(),
+        server_logs: registry.server_logs.clone(),
+        shared: registry.shared.clone(),
+        tasks: tasks.clone(),
+    };
+    let (shutdown, future) = create_server(database.clone(), &config, listeners, state)
+        .map_err(start_error)?;
+
+    // A restarted server keeps the creation time and lease of the one it replaces
+    let previous = registry.servers.get(&port);
+    let change = if previous.is_some() { watch::ChangeType::Modified } else { watch::ChangeType::Added };
+    let created_at = previous.map_or_else(unix_time, |previous| previous.created_at);
+    let lease_renewed_at = previous.map_or_else(Instant::now, |previous| previous.lease_renewed_at);
+
+    let (task, future) = shutdown::abortable(port, id, future);
+    registry.shutdowns.track(task.clone());
+    registry.servers.insert(port, RunningServer {
+        shutdown: Some(shutdown),
+        task,
+        paused,
+        delay_ms,
+        listener: listener_handle,
+        extra_listeners,
+        addresses,
+        latency,
+        usage,
+        journal,
+        tasks: tasks.clone(),
+        capture,
+        tls,
+        virtual_hosts,
+        flags,
+        objects,
+        emails,
+        stub_hits,
+        state: kept_state,
+        profile,
+        clock,
+        seed,
+        content_version,
+        id,
+        config: given,
+        status: ServerStatus::Starting,
+        restarts,
+        created_at,
+        started_at: Instant::now(),
+        lease_renewed_at,
+        resource_version: 0,
+    });
+    registry.record_change(port, change);
+
+    let supervise = supervisor::supervise(database.clone(), port, id, future);
+    match runtime {
+        Some(runtime) => runtime.spawn(runtime::counted(Some(tasks), supervise))
+            .unwrap_or_else(|e| eprintln!("failed to spawn server {}: {}", port, e)),
+        None => runtime::spawn_for(&tasks, supervise),
+    }
+    Ok(())
+}
+
+/// Start the in-process server on `config.port` over with `config`, on the listener it
+/// has. The server it replaces serves until then, and is left as it was if the new one
+/// can't be started.
+fn reconfigure(database: &Database, registry: &mut Registry, config: ServerJsonBody) -> Result<(), error::Error> {
+    let port = config.port;
+    let server = &registry.servers[&port];
+    let restarts = server.restarts;
+    let listener = server.listener.as_ref()
+        .map(std::net::TcpListener::try_clone)
+        .transpose()
+        .map_err(|source| error::Error::StartServer { port, source })?;
+    if let Some(listener) = listener {
+        registry.inherited_listeners.insert(port, listener);
+    }
+    let started = start_server(database, registry, config, restarts);
+    if started.is_err() {
+        registry.inherited_listeners.remove(&port);
+    }
+    started
+}
+
+/// Check `config` as `start_server` would, short of starting anything: the quotas, its
+/// environment variables and what an HTTP server builds its routes from. The port of a
+/// server that isn't registered yet is probed by binding it for a moment, unless a
+/// listener was inherited for it.
+fn check_config(registry: &Registry, config: &ServerJsonBody) -> Result<(), error::Error> {
+    let port = config.port;
+    let registered = registry.servers.contains_key(&port);
+    if !registered {
+        quota::check_servers(registry, port, config.namespace.as_deref())?;
+    }
+    quota::check_memory(registry, config)?;
+    let start_error = |source| error::Error::StartServer { port, source };
+    let config = interpolate::resolve(config).map_err(start_error)?;
+    schedule::check(&config.schedules).map_err(start_error)?;
+
+    let recorded = config.kind == ServerKind::Http && config.isolation == Isolation::InProcess;
+    if config.tls.is_some() && !recorded {
+        let error = std::io::Error::new(std::io::ErrorKind::InvalidInput, "only in-process HTTP servers serve TLS");
+        return Err(start_error(error));
+    }
+    if recorded {
+        let hits = Arc::new(stubs::Hits::new(&config, 0));
+        let routes = || -> std::io::Result<()> {
+            headers::response_headers(&config.response_headers)?;
+            rewrite::HeaderRules::new(&config.header_rules)?;
+            headers::forward(port, &config.header_routes, clock::SharedClock::default(), hits.clone())?;
+            downloads::check(&config.downloads)?;
+            throttling::throttle(&config.rate_limit_rules, clock::SharedClock::default(), hits.clone())?;
+            caching::revalidation(None, &config.cache_rules, caching::SharedContentVersion::default(), clock::SharedClock::default())?;
+            failures::fail(&config.failure_rules, clock::SharedClock::default(), hits.clone())?;
+            openapi::contract(config.openapi.as_ref(), 0, hits.clone())?;
+            crud::crud(config.crud.as_ref(), state::SharedState::default(), hits.clone())?;
+            config.feature_flags.as_ref().map(flags::share).transpose()?;
+            config.object_storage.as_ref().map(objects::ObjectStorage::check).transpose()?;
+            config.request_bin.as_ref().map(requestbin::RequestBin::check).transpose()?;
+            fallback::fallback(config.fallback.as_ref(), &seed::SharedSeed::default(), state::SharedState::default(), clock::SharedClock::default(), hits)?;
+            error_pages::error_pages(&config.error_pages)?;
+            credentials::authentication(config.auth.as_ref())?;
+            config.admission_queue.as_ref().map(|queue| queue.check(config.max_concurrent_requests)).transpose()?;
+            config.connection.check()
+        };
+        routes().map_err(start_error)?;
+    }
+
+    if port == 0 || registered || config.isolation != Isolation::InProcess {
+        return Ok(());
+    }
+    let probed = match config.kind {
+        ServerKind::Http | ServerKind::Tcp(_) | ServerKind::Smtp(_) if registry.inherited_listeners.contains_key(&port) => Ok(()),
+        ServerKind::Http | ServerKind::Tcp(_) | ServerKind::Smtp(_) => config.socket.bind(port).map(drop),
+        ServerKind::Udp(_) | ServerKind::Dns(_) => std::net::UdpSocket::bind(("127.0.0.1", port)).map(drop),
+        ServerKind::Docker(_) => Ok(()),
+    };
+    probed.map_err(start_error)
+}
+
+// Create an instance of the kind of server described by ServerJsonBody, listening on
+// `listeners` if given rather than binding new sockets. HTTP servers record response
+// times and requests in `state`, and HTTP and TCP servers count what they serve in it.
+fn create_server(
+    database: Database,
+    body: &ServerJsonBody,
+    listeners: Vec<std::net::TcpListener>,
+    state: HttpState
+) -> std::io::Result<(futures::sync::oneshot::Sender<()>, ServerFuture)> {
+    let (tx, rx) = futures::sync::oneshot::channel();
+
+    if body.isolation == Isolation::Subprocess {
+        return Ok((tx, Box::new(child::spawn_child(body, rx)?)));
+    }
+
+    let future = body.kind.backend().create(database, body, listeners, state, rx)?;
+    Ok((tx, future))
+}
+
+/// What runs the servers of some kinds, see `ServerKind::backend`
+trait ServerBackend: Sync {
+    /// Start the server `body` describes, its future ending once `shutdown` fires, see
+    /// `create_server`
+    fn create(
+        &self,
+        database: Database,
+        body: &ServerJsonBody,
+        listeners: Vec<std::net::TcpListener>,
+        state: HttpState,
+        shutdown: futures::sync::oneshot::Receiver<()>
+    ) -> std::io::Result<ServerFuture>;
+
+    /// Whether the servers run on this process's runtime, sharing its listeners and
+    /// `RunningServer::paused` flag, so they can be paused and handed over in an upgrade
+    fn in_process(&self) -> bool {
+        true
+    }
+}
+
+/// Runs HTTP, TCP, UDP, DNS and SMTP servers on this process's runtime
+struct InProcess;
+
+impl ServerBackend for InProcess {
+    fn create(
+        &self,
+        database: Database,
+        body: &ServerJsonBody,
+        listeners: Vec<std::net::TcpListener>,
+        state: HttpState,
+        shutdown: futures::sync::oneshot::Receiver<()>
+    ) -> std::io::Result<ServerFuture> {
+        let port = body.port;
+        Ok(match body.kind {
+            ServerKind::Http => create_warp_server(database, body, listeners, state, shutdown)?,
+            ServerKind::Tcp(ref mode) => {
+                let incoming = tcp::bind(port, listeners, &body.socket)?;
+                let incoming = limits::limit_connections(incoming, state.usage, body.max_connections);
+                Box::new(tcp::create_tcp_server(mode.clone(), incoming, state.paused, state.tasks, shutdown))
+            }
+            ServerKind::Udp(ref mode) => Box::new(udp::create_udp_server(mode.clone(), port, state.paused, shutdown)?),
+            ServerKind::Dns(ref zone) => Box::new(dns::create_dns_server(zone.clone(), port, state.paused, shutdown)?),
+            ServerKind::Smtp(ref smtp) => {
+                let incoming = tcp::bind(port, listeners, &body.socket)?;
+                let incoming = limits::limit_connections(incoming, state.usage, body.max_connections);
+                let mailbox = state.emails.unwrap_or_default();
+                Box::new(smtp::create_smtp_server(smtp.clone(), incoming, mailbox, state.paused, state.tasks, shutdown))
+            }
+            ServerKind::Docker(_) => {
+                let error = "containers are run by docker::Docker";
+                return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, error));
+            }
+        })
+    }
+}
+
+/// What an HTTP server shares with its `RunningServer`, of which other kinds of servers
+/// use some
+struct HttpState {
+    paused: Arc<AtomicBool>,
+    delay_ms: Arc<AtomicU64>,
+    latency: Arc<Mutex<metrics::LatencyHistogram>>,
+    usage: Arc<limits::Usage>,
+    journal: Arc<Mutex<journal::Journal>>,
+    capture: capture::SharedCapture,
+    tls: Option<tls::Tls>,
+    virtual_hosts: vhosts::SharedHosts,
+    flags: flags::SharedFlags,
+    objects: Option<objects::SharedStore>,
+    emails: Option<smtp::SharedMailbox>,
+    stub_hits: Arc<stubs::Hits>,
+    state: state::SharedState,
+    profile: profile::SharedProfile,
+    clock: clock::SharedClock,
+    seed: seed::SharedSeed,
+    content_version: caching::SharedContentVersion,
+    middleware: Vec<middleware::Layer>,
+    maintenance: maintenance::SharedWindow,
+    statsd: Option<statsd::Emitter>,
+    mqtt: Option<mqtt::Publisher>,
+    server_logs: Option<Arc<server_logs::ServerLogs>>,
+    shared: Arc<shared::Shared>,
+    tasks: Arc<runtime::Tasks>,
+}
+
+// Create an instance of HTTP server
+fn create_warp_server(
+    database: Database,
+    body: &ServerJsonBody,
+    listeners: Vec<std::net::TcpListener>,
+    state: HttpState,
+    shutdown: futures::sync::oneshot::Receiver<()>
+) -> std::io::Result<ServerFuture> {
+    let port = body.port;
+    let HttpState { paused, delay_ms, latency, usage, journal, capture, tls, virtual_hosts, flags, objects, emails: _, stub_hits, state, profile, clock, seed, content_version, middleware, maintenance, statsd, mqtt, server_logs, shared, tasks } = state;
+    let log = server_logs.map(|logs| logs.open(port)).transpose()?;
+
+    // Answers everything with 503 while paused, otherwise defers to the app
+    let unavailable = warp::any().and_then(move || {
+        if paused.load(Ordering::SeqCst) {
+            Ok(warp::http::StatusCode::SERVICE_UNAVAILABLE)
+        } else {
+            Err(warp::reject::not_found())
+        }
+    });
+
+    let maintenance = maintenance::unavailable(port, maintenance);
+
+    // Holds a request's place among the `max_concurrent_requests` until it's answered,
+    // waiting in the admission queue for one if there's one
+    let max_requests = body.max_concurrent_requests;
+    let admission_queue = body.admission_queue.clone();
+    admission_queue.as_ref().map(|queue| queue.check(max_requests)).transpose()?;
+    let request_usage = usage.clone();
+    let admit = warp::any().and_then(move || {
+        request_usage.admit(max_requests, admission_queue.as_ref())
+            .map_err(move |()| warp::reject::custom(error::Error::ConcurrentRequests(max_requests.unwrap_or_default())))
+    });
+
+    // Stop accepting when `shutdown` fires. Connections already accepted are served to
+    // completion in the background.
+    let stop = shutdown.then(|_| Ok::<_, std::io::Error>(None)).into_stream();
+    let incoming = limits::limit_connections(tcp::bind(port, listeners, &body.socket)?, usage, body.max_connections)
+        .map(Some)
+        .select(stop)
+        .take_while(|connection| Ok(connection.is_some()))
+        .filter_map(|connection| connection);
+
+    for layer in &middleware {
+        layer.check()?;
+    }
+    let pipeline = middleware::Pipeline::new(middleware, port, shared.clone(), delay_ms, latency, statsd, mqtt);
+    let before = pipeline.before();
+    let response_headers = headers::response_headers(&body.response_headers)?;
+    #[cfg(feature = "http3")]
+    let http3 = tls.as_ref()
+        .filter(|tls| tls.config.http3)
+        .map(|tls| http3::listen(port, tls))
+        .transpose()?;
+    #[cfg(feature = "http3")]
+    let response_headers = match &http3 {
+        Some(listener) => listener.advertise(response_headers),
+        None => response_headers,
+    };
+    let response_headers = warp::reply::with::headers(response_headers);
+    let header_rules = rewrite::HeaderRules::new(&body.header_rules)?;
+    let forward = headers::forward(port, &body.header_routes, clock.clone(), stub_hits.clone())?;
+    let limits = throttling::throttle(&body.rate_limit_rules, clock.clone(), stub_hits.clone())?;
+    let conditions = caching::revalidation(body.conditional_requests.as_ref(), &body.cache_rules, content_version, clock.clone())?.conditions();
+    let fail = failures::fail(&body.failure_rules, clock.clone(), stub_hits.clone())?;
+    let allowed_clients = Arc::new(body.allowed_clients.clone());
+    let seed = body.example_seed.map_or(seed, seed::SharedSeed::new);
+    let downloads = downloads::filter(&body.downloads)?;
+    let contract = openapi::contract(body.openapi.as_ref(), seed.get(), stub_hits.clone())?;
+    let crud = crud::crud(body.crud.as_ref(), state.clone(), stub_hits.clone())?;
+    let flags = flags::filter(body.feature_flags.as_ref(), flags);
+    let bin = requestbin::filter(body.request_bin.as_ref())?;
+    let objects = objects::filter(body.object_storage.as_ref(), objects, clock.clone());
+    let fallback = fallback::fallback(body.fallback.as_ref(), &seed, state, clock.clone(), stub_hits.clone())?;
+    let error_pages = error_pages::error_pages(&body.error_pages)?;
+    let authentication = credentials::authentication(body.auth.as_ref())?.filter();
+    // The requests forwarded are kept along with those served
+    let forwarded = Some(journal.clone()).filter(|_| pipeline.records());
+    let dispatch = vhosts::dispatch(virtual_hosts, stub_hits, forwarded.clone());
+    let shape = profile::shape(profile);
+    let app = app_filter(database.clone(), shared.clone(), port);
+    let tls_config = body.tls.clone();
+    body.connection.check()?;
+    let connection_options = body.connection.clone();
+
+    // Each connection is served on its own, as warp only tells the client's address to
+    // filters of servers it binds itself
+    let proxied = body.proxy_protocol;
+    let failed_log = log.clone();
+    let serve = incoming.for_each(move |connection| {
+        let peer = connection.get_ref().peer_addr().ok();
+        let flow = capture::Flow::new(capture.clone(), peer, port);
+        let (journal, shared) = (journal.clone(), shared.clone());
+        let (admit, shape, limits, conditions, fail, dispatch, forward) = (admit.clone(), shape.clone(), limits.clone(), conditions.clone(), fail.clone(), dispatch.clone(), forward.clone());
+        let (bin, downloads, contract, crud, flags, objects, fallback, app) = (bin.clone(), downloads.clone(), contract.clone(), crud.clone(), flags.clone(), objects.clone(), fallback.clone(), app.clone());
+        let (before, maintenance, unavailable, record) = (before.clone(), maintenance.clone(), unavailable.clone(), pipeline.records());
+        let (response_headers, pipeline, tls_config, connection_tasks) = (response_headers.clone(), pipeline.clone(), tls_config.clone(), tasks.clone());
+        let (error_pages, authentication, forwarded) = (error_pages.clone(), authentication.clone(), forwarded.clone());
+        let (dated, header_rules, allowed_clients, tls) = (clock.clone(), header_rules.clone(), allowed_clients.clone(), tls.clone());
+        let (log, accept_log) = (log.clone(), log.clone());
+        let managed = connections::Connection::new(&connection_options);
+        // Behind a load balancer, the client is the one its PROXY header tells
+        let connection = match proxied {
+            true => futures::future::Either::A(proxy_protocol::accept(connection, peer)),
+            false => futures::future::Either::B(futures::future::ok((peer, connection))),
+        };
+        runtime::spawn_for(&tasks, connection
+            .map_err(move |e| {
+                let error = format!("failed to accept a connection of server {}: {}", port, e);
+                server_logs::error(accept_log.as_ref(), &error);
+            })
+            .and_then(move |(client, connection)| {
+                let throttled_client = client.map(|addr| addr.ip());
+                let throttle = warp::any().and_then(move || {
+                    lock(&shared.rate_limiter).admit(throttled_client)
+                        .map_err(|wait| warp::reject::custom(error::Error::RateLimited(wait)))
+                });
+                let allowed = clients::matches(&allowed_clients, client);
+                let forbidden = warp::any().and_then(move || match allowed {
+                    true => Err(warp::reject::not_found()),
+                    false => Ok(error_reply(warp::http::StatusCode::FORBIDDEN, "client not allowed")),
+                });
+                let interim = interim::Interim::default();
+                let fallback = fallback.filter(client, interim.clone(), forwarded.clone());
+                let (limits, fail, forward) = (limits.filter(client), fail.filter(client), forward.filter(client, forwarded.clone()));
+                let connection = match &tls {
+                    Some(tls) => tls.acceptor.accept(connection),
+                    None => Box::new(futures::future::ok(tls::Stream::Plain(connection))),
+                };
+                let tls_log = log.clone();
+                connection.map_err(move |e| {
+                    let error = format!("TLS handshake with a client of server {} failed: {}", port, e);
+                    server_logs::error(tls_log.as_ref(), &error);
+                })
+                    .map(move |connection| (client, connection, throttle, limits, forbidden, fail, forward, fallback, interim, forwarded, log))
+            })
+            .and_then(move |(client, connection, throttle, limits, forbidden, fail, forward, fallback, interim, forwarded, log)| {
+                // Every request of a connection for a server name routed elsewhere is forwarded
+                let sni_port = connection.server_name()
+                    .and_then(|server_name| tls_config?.sni_port(&server_name));
+                let sni = headers::proxy(warp::any().and_then(move || sni_port.ok_or_else(warp::reject::not_found)), forwarded);
+
+                let app = throttle
+                    .and(admit)
+                    .and(shape)
+                    .and(limits)
+                    .and(conditions)
+                    .and(forbidden.or(authentication).or(fail).or(bin).or(sni).or(dispatch).or(forward).or(downloads).or(contract).or(crud).or(flags).or(objects).or(fallback).or(app))
+                    .and_then(|_throttled, _request, limits: throttling::Limits, conditions, reply| caching::Conditions::respond(conditions, limits.apply(reply)))
+                    .recover(error::recover);
+                let (start, rewrite) = (managed.clone(), header_rules.clone());
+                let after = warp::log::custom(move |info: warp::log::Info| {
+                    if let Some(log) = &log {
+                        log.access(client, &info);
+                    }
+                    pipeline.after(info)
+                });
+                let routes = warp::any()
+                    .map(move || start.start())
+                    .and(before.and(maintenance.or(unavailable).or(app)).recover(error::recover).recover(move |rejection| error_pages.recover(rejection)))
+                    .map(|request: connections::Request, reply| request.finish(reply))
+                    .map(move |response| clock::dated(&dated, response))
+                    .with(response_headers)
+                    .map(move |reply| rewrite.response(reply))
+                    .with(after);
+                let client_cert = connection.client_cert();
+                // What hyper can't send is added beneath the journal, which has the responses as hyper wrote them
+                let connection = interim.wire(connection);
+                // Rewritten before it's recorded, so the journal has the requests as the routes see them
+                let connection = rewrite::Rewritten::new(connection, header_rules);
+                let connection = journal::Recorded::new(connection, journal, flow, client, client_cert, record);
+                let connection = managed.stream(connection);
+                // hyper serves the connection on a task of its own, which holds it until it closes
+                let connection = runtime::counted(Some(connection_tasks), connection);
+                warp::serve(routes).serve_incoming(futures::stream::once(Ok::<_, std::io::Error>(connection)))
+            }));
+        Ok(())
+    });
+
+    // HTTP/3 stops along with the listener it's advertised by
+    #[cfg(feature = "http3")]
+    let serve = serve.then(move |result| {
+        drop(http3);
+        result
+    });
+
+    Ok(Box::new(serve.map_err(move |e| {
+        server_logs::error(failed_log.as_ref(), &format!("failed to accept connection: {}", e));
+    })))
+}
+
+/// Seconds since the Unix epoch
+fn unix_time() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or(0)
+}
+
+/// Run the admin API on port 8080 until the process is killed. This is the binary's
+/// `main`. It prints a `daemon::Banner` once it's ready.
+///
+/// Flags, on Unix:
+///
+/// - `--daemon`, `--pid-file`, `--log-file` and `--ready-file`: see `daemon::start`
+/// - `--log`: where the output goes, see `logging::Sink`
+/// - `--tui`: a dashboard of the servers, see `tui::run`
+/// - `--self-test`: check the environment rather than serving, see `selftest::run`
+///
+/// On Windows, `--log`, `--ready-file`, `--tui` and `--self-test` as well as:
+///
+/// - `--service`, `--install-service` and `--uninstall-service`: see `service::run`
+/// - `--pid-file`: see `daemon::start`
+///
+/// Environment variables:
+///
+/// - `PORT_ALLOCATOR`: how ports are allocated, see `ports::parse_allocator`
+/// - `WORKER_THREADS` and `BLOCKING_THREADS`: the size of the runtime, see
+///   `runtime::RuntimeConfig`
+/// - `SQLITE_PATH`, `REDIS_URL` or `RAFT_PEERS`: a store the servers are kept in, to be
+///   restored from on the next start, see `store::from_env`
+/// - `LEADER_LOCK`: elects the leader of an active/standby pair, see `election::Election`
+/// - `ADMIN_TOKEN`: guards the admin API, see `auth::authorize`
+/// - `RATE_LIMIT` and `CLIENT_RATE_LIMIT`: limit its request rate, see
+///   `ratelimit::RateLimitConfig`
+/// - `REQUIRE_IF_MATCH`: refuses changes of a server without an `If-Match` with 428, see
+///   `RunningServer::precondition`
+/// - `JOURNAL_CAPACITY`, `JOURNAL_MAX_BYTES` and `JOURNAL_MAX_BODY_BYTES`: bound the
+///   requests kept, see `journal::JournalLimits`
+/// - `TOMBSTONE_RETENTION_SECS`: how long deleted servers are kept, see
+///   `tombstones::Tombstones`
+/// - `HEALTH_CHECK_INTERVAL_SECS`: how often the servers are probed, see
+///   `health::check_periodically`
+/// - `MULTIPLEX`: exposes the servers on the root server, see `multiplex::from_env`
+/// - `ACME_DOMAINS`: serves the admin API over TLS with a certificate from Let's Encrypt,
+///   see `acme::AcmeConfig`
+/// - `MIDDLEWARE`: the layers it and the servers apply to requests, see
+///   `middleware::from_env`
+/// - `QUOTAS`: limits the servers and recorded bytes of each namespace and of all, see
+///   `quota::QuotaConfig`
+/// - `SERVICE_REGISTRY`: registers the servers with Consul or etcd, see
+///   `discovery::DiscoveryConfig`
+/// - `MDNS`: advertises the HTTP servers on the local network, see `mdns::Advertiser`
+/// - `STATSD_ADDR`: has them push request metrics to statsd, see `statsd::Statsd`
+/// - `MQTT_BROKER`: publishes their changes and requests, see `mqtt::MqttConfig`
+/// - `SERVER_LOG_DIR`: has each log its requests and errors to a file of its own, see
+///   `server_logs::ServerLogs`
+/// - `KUBERNETES_MOCKS`: has the servers be those a ConfigMap or `MockServer` resources
+///   declare, see `kubernetes::KubernetesConfig`
+/// - `BACKUP_URL`: backs them up to S3, see `backup::BackupConfig`
+pub fn run() {
+    let args: Vec<String> = std::env::args().collect();
+    if let [_, flag, config] = args.as_slice() {
+        if flag == child::CHILD_FLAG {
+            return child::run_child(config);
+        }
+    }
+
+    let options = daemon::Options::parse(&args[1..]).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(2);
+    });
+    if options.self_test {
+        return selftest::run();
+    }
+
+    #[cfg(windows)]
+    match options.service {
+        Some(service::Command::Run) => return service::run(options),
+        Some(command) => {
+            let managed = match command {
+                service::Command::Install => service::install(args[1..].iter()
+                    .filter(|arg| *arg != "--install-service")
+                    .map(Into::into)
+                    .collect()),
+                _ => service::uninstall(),
+            };
+            if let Err(e) = managed {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+            return;
+        }
+        None => {}
+    }
+    serve(options);
+}
+
+/// `run`, once the command line is parsed
+fn serve(options: daemon::Options) {
+    let port = 8080;
+    let handed_over = upgrade::handed_over_servers();
+    // Before any thread is started, as only the forking one would carry on
+    let readiness = daemon::start(&options, handed_over.is_some()).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    });
+    // Once the process has forked, as the thread taking the lines wouldn't carry on
+    if let Err(e) = logging::start(&options.log) {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+    let port_allocator = match std::env::var("PORT_ALLOCATOR") {
+        Ok(spec) => ports::parse_allocator(&spec).unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }),
+        Err(_) => Box::default(),
+    };
+    let rate_limits = ratelimit::RateLimitConfig::from_env().unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(2);
+    });
+    let journal_defaults = JournalLimits::from_env().unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(2);
+    });
+    let health_check_interval = health::interval_from_env().unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(2);
+    });
+    let tombstones = tombstones::Tombstones::from_env().unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(2);
+    });
+    let middleware = middleware::from_env().unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(2);
+    });
+    let quotas = quota::QuotaConfig::from_env().unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(2);
+    });
+    #[cfg(feature = "acme")]
+    let acme = acme::AcmeConfig::from_env().unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(2);
+    });
+    #[cfg(not(feature = "acme"))]
+    if std::env::var("ACME_DOMAINS").is_ok() {
+        eprintln!("ACME_DOMAINS needs this server to be built with the acme feature");
+        std::process::exit(2);
+    }
+    #[cfg(feature = "s3")]
+    let backup = backup::BackupConfig::from_env().unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(2);
+    });
+    #[cfg(not(feature = "s3"))]
+    if std::env::var("BACKUP_URL").is_ok() {
+        eprintln!("BACKUP_URL needs this server to be built with the s3 feature");
+        std::process::exit(2);
+    }
+    let discovery = discovery::DiscoveryConfig::from_env().unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(2);
+    });
+    let (discovery, discovery_worker) = discovery.map(discovery::Discovery::new).unzip();
+    #[cfg(feature = "mdns")]
+    let mdns = mdns::Advertiser::from_env().unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(2);
+    });
+    #[cfg(not(feature = "mdns"))]
+    if std::env::var("MDNS").is_ok() {
+        eprintln!("MDNS needs this server to be built with the mdns feature");
+        std::process::exit(2);
+    }
+    #[cfg(feature = "kubernetes")]
+    let kubernetes = kubernetes::KubernetesConfig::from_env().unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(2);
+    });
+    #[cfg(not(feature = "kubernetes"))]
+    if std::env::var("KUBERNETES_MOCKS").is_ok() {
+        eprintln!("KUBERNETES_MOCKS needs this server to be built with the kubernetes feature");
+        std::process::exit(2);
+    }
+    let statsd = statsd::Statsd::from_env().unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(2);
+    });
+    let mqtt = mqtt::MqttConfig::from_env()
+        .and_then(|config| config.map(mqtt::Publisher::start).transpose().map_err(|e| e.to_string()))
+        .unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        });
+    let server_logs = server_logs::ServerLogs::from_env().unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(2);
+    });
+    let election = election::Election::from_env().unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(2);
+    });
+    // A standby takes over the registry as the leader left it, so it loads it once it leads
+    if let Some(election) = &election {
+        if let Err(e) = election.lead(handed_over.is_some()) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    }
+    let mut store = store::from_env().unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(2);
+    });
+    let stored = store.load().unwrap_or_else(|e| {
+        eprintln!("failed to load stored servers: {}", e);
+        std::process::exit(1);
+    });
+    let mut registry = Registry {
+        root_port: Some(port),
+        #[cfg(unix)]
+        inherited_listeners: activation::inherited_listeners(),
+        port_allocator,
+        store,
+        shared: Arc::new(shared::Shared::new(auth::admin_token_from_env(), ratelimit::RateLimiter::new(rate_limits))),
+        journal_defaults,
+        tombstones,
+        middleware,
+        quotas: quota::Quotas::new(quotas),
+        discovery,
+        #[cfg(feature = "mdns")]
+        mdns,
+        statsd,
+        mqtt: mqtt.clone(),
+        server_logs,
+        multiplexed: multiplex::from_env(),
+        require_if_match: matches!(std::env::var("REQUIRE_IF_MATCH").as_deref(), Ok("1") | Ok("true")),
+        ..Registry::default()
+    };
+    if let Some(servers) = &handed_over {
+        registry.inherited_listeners.extend(upgrade::handed_over_listeners(servers));
+    }
+    #[cfg(not(feature = "tui"))]
+    if options.tui {
+        eprintln!("--tui needs this server to be built with the tui feature");
+        std::process::exit(2);
+    }
+    #[cfg(feature = "tui")]
+    let tui = options.tui;
+    let database: Database = Arc::new(Mutex::new(registry));
+    let body = ServerJsonBody { port, ..Default::default() };
+    #[cfg(feature = "acme")]
+    let body = ServerJsonBody { tls: acme.as_ref().map(acme::AcmeConfig::tls_config), ..body };
+
+    runtime::run(futures::future::lazy(move || {
+        if let Some(worker) = discovery_worker {
+            runtime::spawn(worker);
+        }
+        if let Some(mqtt) = mqtt {
+            runtime::spawn(mqtt.publish_changes(&database));
+        }
+        let mut registry = lock(&database);
+        match handed_over {
+            Some(servers) => upgrade::restore(&database, &mut registry, servers),
+            None => {
+                if let Err(e) = start_server(&database, &mut registry, body, 0) {
+                    eprintln!("{}", e);
+                    std::process::exit(1);
+                }
+                match snapshot::restore_servers(&database, &mut registry, port, stored) {
+                    Ok(summary) => {
+                        for failure in summary.failed {
+                            eprintln!("failed to restore server {}: {}", failure.port, failure.error);
+                        }
+                    }
+                    Err(e) => eprintln!("failed to restore stored servers: {}", e),
+                }
+            }
+        }
+        let root = registry.servers.get(&port);
+        let address = root
+            .and_then(|root| root.listener.as_ref())
+            .and_then(|listener| listener.local_addr().ok())
+            .unwrap_or_else(|| ([127, 0, 0, 1], port).into());
+        let scheme = if root.is_some_and(|root| root.config.tls.is_some()) { "https" } else { "http" };
+        readiness.ready(&daemon::Banner::new(scheme, address));
+        #[cfg(feature = "tui")]
+        if tui {
+            tui::run(database.clone(), &mut registry, port);
+        }
+        #[cfg(unix)]
+        background::exit_on_sigterm(&database);
+        #[cfg(windows)]
+        service::watch_for_stop(&database);
+        let reaped = database.clone();
+        registry.background.start("lease reaper", move || reaper::reap_expired_leases(reaped.clone()));
+        let scheduled = database.clone();
+        registry.background.start("scheduler", move || schedule::run_schedules(scheduled.clone()));
+        let swept = database.clone();
+        registry.background.start("shutdown sweeper", move || shutdown::sweep(swept.clone()));
+        if let Some(interval) = health_check_interval {
+            let checked = database.clone();
+            registry.background.start("health checker", move || health::check_periodically(checked.clone(), interval));
+        }
+        #[cfg(feature = "kubernetes")]
+        if let Some(kubernetes) = kubernetes {
+            match kubernetes::start(database.clone(), port, kubernetes) {
+                Ok(operator) => {
+                    runtime::spawn(operator);
+                }
+                Err(e) => {
+                    eprintln!("failed to reconcile servers with Kubernetes: {}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        #[cfg(feature = "s3")]
+        if let Some(backup) = backup {
+            if let Err(e) = backup::start(database.clone(), port, backup) {
+                eprintln!("failed to start backing up: {}", e);
+                std::process::exit(1);
+            }
+        }
+        #[cfg(feature = "acme")]
+        if let Some(acme) = acme {
+            drop(registry);
+            if let Err(e) = acme::start(database.clone(), port, acme) {
+                eprintln!("failed to serve ACME challenges: {}", e);
+                std::process::exit(1);
+            }
+        }
+        Ok(())
+    }));
+}
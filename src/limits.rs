@@ -0,0 +1,230 @@
+use futures::{Async, Future, Poll, Stream};
+use futures::sync::oneshot;
+use futures::task::AtomicTask;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::timer::Timeout;
+
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::error::lock;
+use crate::metrics::LatencyHistogram;
+
+/// Where the requests an HTTP server can't handle yet, as it handles its
+/// `max_concurrent_requests`, wait for one to be answered, like those of a server
+/// queueing under load, rather than being answered with 503 right away
+#[derive(Clone, Debug, PartialEq, serde_derive::Deserialize, serde_derive::Serialize)]
+pub struct AdmissionQueue {
+    /// Requests waiting at a time. Further ones are answered with 503.
+    pub depth: usize,
+    /// How long a request waits before it's answered with 503
+    pub timeout_ms: u64,
+}
+
+impl AdmissionQueue {
+    /// Check the queue is of a server handling at most `max_concurrent_requests`
+    pub fn check(&self, max_concurrent_requests: Option<usize>) -> io::Result<()> {
+        let invalid = |error: &str| Err(io::Error::new(io::ErrorKind::InvalidInput, error.to_string()));
+        if max_concurrent_requests.is_none() {
+            return invalid("only a server with max_concurrent_requests queues requests");
+        }
+        if self.depth == 0 || self.timeout_ms == 0 {
+            return invalid("an admission queue has a depth and timeout_ms above 0");
+        }
+        Ok(())
+    }
+}
+
+/// Connections and requests an in-process HTTP or TCP server is serving right now
+#[derive(Debug, Default)]
+pub struct Usage {
+    connections: AtomicUsize,
+    requests: AtomicUsize,
+    /// The accept loop, if it's waiting for a connection to close
+    accept_task: AtomicTask,
+    /// Requests waiting in the admission queue, oldest first, each handed the place of
+    /// a request once it's answered
+    waiting: Mutex<VecDeque<oneshot::Sender<Request>>>,
+    /// How long the requests let out of the admission queue waited in it
+    queue_wait: Mutex<LatencyHistogram>,
+    /// Requests answered with 503 after waiting for as long as the queue lets them
+    queue_timeouts: AtomicU64,
+}
+
+/// Current usage reported in a server's JSON representation
+#[derive(Clone, Debug, Default, PartialEq, serde_derive::Deserialize, serde_derive::Serialize)]
+pub struct UsageSummary {
+    pub connections: usize,
+    pub requests: usize,
+    /// Requests in the admission queue
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub queued: usize,
+}
+
+fn is_zero(queued: &usize) -> bool {
+    *queued == 0
+}
+
+impl Usage {
+    pub fn summary(&self) -> UsageSummary {
+        UsageSummary {
+            connections: self.connections.load(Ordering::SeqCst),
+            requests: self.requests.load(Ordering::SeqCst),
+            queued: lock(&self.waiting).iter().filter(|waiter| !waiter.is_canceled()).count(),
+        }
+    }
+
+    /// How long the requests let out of the admission queue waited in it, and how many
+    /// waited too long
+    pub fn queue_stats(&self) -> (LatencyHistogram, u64) {
+        (lock(&self.queue_wait).clone(), self.queue_timeouts.load(Ordering::SeqCst))
+    }
+
+    /// Count a request as being served, unless `max` of them already are
+    pub fn start_request(self: &Arc<Self>, max: Option<usize>) -> Option<Request> {
+        let max = max.unwrap_or(usize::MAX);
+        self.requests
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |requests| Some(requests + 1).filter(|_| requests < max))
+            .ok()?;
+        Some(Request(self.clone()))
+    }
+
+    /// Count a request as being served once fewer than `max` are, letting it wait in
+    /// `queue` for that if there's one, or fail if it's full or the request times out.
+    /// Requests are let out of the queue in the order they came.
+    pub fn admit(self: &Arc<Self>, max: Option<usize>, queue: Option<&AdmissionQueue>) -> Box<dyn Future<Item = Request, Error = ()> + Send> {
+        let queue = match queue {
+            Some(queue) if max.is_some() => queue,
+            _ => return Box::new(futures::future::result(self.start_request(max).ok_or(()))),
+        };
+        // Held until queued, so that no request is answered meanwhile with none to take its place
+        let mut waiting = lock(&self.waiting);
+        waiting.retain(|waiter| !waiter.is_canceled());
+        if waiting.is_empty() {
+            if let Some(request) = self.start_request(max) {
+                return Box::new(futures::future::ok(request));
+            }
+        }
+        if waiting.len() >= queue.depth {
+            return Box::new(futures::future::err(()));
+        }
+        let (waiter, admitted) = oneshot::channel();
+        waiting.push_back(waiter);
+        drop(waiting);
+
+        let (usage, queued) = (self.clone(), Instant::now());
+        Box::new(Timeout::new(admitted, Duration::from_millis(queue.timeout_ms)).then(move |admitted| match admitted {
+            Ok(request) => {
+                lock(&usage.queue_wait).record(queued.elapsed());
+                Ok(request)
+            }
+            Err(_) => {
+                usage.queue_timeouts.fetch_add(1, Ordering::SeqCst);
+                Err(())
+            }
+        }))
+    }
+}
+
+/// A request being served, counted in `Usage` until dropped, when its place goes to the
+/// request waiting longest in the admission queue, if any
+#[derive(Debug)]
+pub struct Request(Arc<Usage>);
+
+impl Drop for Request {
+    fn drop(&mut self) {
+        let mut waiting = lock(&self.0.waiting);
+        match waiting.pop_front() {
+            // Should it have timed out, the place is dropped along with the send, going
+            // to the next
+            Some(waiter) => {
+                drop(waiting);
+                let _ = waiter.send(Request(self.0.clone()));
+            }
+            None => {
+                self.0.requests.fetch_sub(1, Ordering::SeqCst);
+            }
+        }
+    }
+}
+
+/// A connection being served, counted in `Usage` until dropped
+pub struct Connection<T> {
+    inner: T,
+    usage: Arc<Usage>,
+}
+
+impl<T> Connection<T> {
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+}
+
+impl<T> Drop for Connection<T> {
+    fn drop(&mut self) {
+        self.usage.connections.fetch_sub(1, Ordering::SeqCst);
+        self.usage.accept_task.notify();
+    }
+}
+
+impl<T: Read> Read for Connection<T> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+impl<T: Write> Write for Connection<T> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<T: AsyncRead> AsyncRead for Connection<T> {}
+
+impl<T: AsyncWrite> AsyncWrite for Connection<T> {
+    fn shutdown(&mut self) -> Poll<(), std::io::Error> {
+        self.inner.shutdown()
+    }
+}
+
+/// Accepted connections, counted in `usage`. While `max` of them are open, no more are
+/// accepted, leaving them queued in the listen backlog until one closes.
+pub fn limit_connections<S: Stream>(incoming: S, usage: Arc<Usage>, max: Option<usize>) -> LimitConnections<S> {
+    LimitConnections { incoming, usage, max: max.unwrap_or(usize::MAX) }
+}
+
+pub struct LimitConnections<S> {
+    incoming: S,
+    usage: Arc<Usage>,
+    max: usize,
+}
+
+impl<S: Stream> Stream for LimitConnections<S> {
+    type Item = Connection<S::Item>;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, S::Error> {
+        if self.usage.connections.load(Ordering::SeqCst) >= self.max {
+            self.usage.accept_task.register();
+            // A connection may have closed before the task was registered
+            if self.usage.connections.load(Ordering::SeqCst) >= self.max {
+                return Ok(Async::NotReady);
+            }
+        }
+
+        let inner = match self.incoming.poll()? {
+            Async::Ready(Some(inner)) => inner,
+            Async::Ready(None) => return Ok(Async::Ready(None)),
+            Async::NotReady => return Ok(Async::NotReady),
+        };
+        self.usage.connections.fetch_add(1, Ordering::SeqCst);
+        Ok(Async::Ready(Some(Connection { inner, usage: self.usage.clone() })))
+    }
+}
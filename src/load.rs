@@ -0,0 +1,276 @@
+use futures::{Future, Stream};
+use futures::future::Either;
+use tokio::timer::{Interval, Timeout};
+
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+use crate::{error_reply, Database, ServerKind};
+use crate::error::lock;
+
+/// Requests started per second at most, one a nanosecond
+const MAX_RATE: u32 = 1_000_000_000;
+
+/// Requests a run makes at most, `rate` times `duration_secs`, as every one's outcome is
+/// kept until the report
+const MAX_REQUESTS: u64 = 1_000_000;
+
+/// JSON body of `POST /{port}/load`
+#[derive(Debug, serde_derive::Deserialize)]
+pub struct LoadJsonBody {
+    /// Requests started per second
+    #[serde(default = "default_rate")]
+    rate: u32,
+    #[serde(default = "default_duration_secs")]
+    duration_secs: u32,
+    /// Requests in flight at most. When they're all outstanding, the rate drops.
+    #[serde(default = "default_concurrency")]
+    concurrency: usize,
+    #[serde(default = "default_timeout_ms")]
+    timeout_ms: u64,
+    /// The requests to make, interleaved by weight
+    #[serde(default = "default_requests")]
+    requests: Vec<LoadTarget>,
+}
+
+#[derive(Debug, serde_derive::Deserialize)]
+pub struct LoadTarget {
+    #[serde(default = "default_method")]
+    method: String,
+    #[serde(default = "default_path")]
+    path: String,
+    #[serde(default = "default_weight")]
+    weight: u32,
+}
+
+fn default_rate() -> u32 {
+    10
+}
+
+fn default_duration_secs() -> u32 {
+    5
+}
+
+fn default_concurrency() -> usize {
+    64
+}
+
+fn default_timeout_ms() -> u64 {
+    1000
+}
+
+fn default_requests() -> Vec<LoadTarget> {
+    vec![LoadTarget { method: default_method(), path: default_path(), weight: default_weight() }]
+}
+
+fn default_method() -> String {
+    "GET".to_string()
+}
+
+fn default_path() -> String {
+    "/".to_string()
+}
+
+fn default_weight() -> u32 {
+    1
+}
+
+/// Results of a load run. Requests that failed to get a response, including those
+/// that timed out, count as errors and are left out of the latencies.
+#[derive(Debug, serde_derive::Serialize)]
+struct LoadReport {
+    requests: u64,
+    errors: u64,
+    /// Responses by status code
+    statuses: BTreeMap<u16, u64>,
+    duration_ms: u64,
+    /// Responses per second
+    throughput_rps: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    latency: Option<LatencyReport>,
+    targets: Vec<TargetReport>,
+}
+
+#[derive(Debug, serde_derive::Serialize)]
+struct LatencyReport {
+    min_ms: f64,
+    p50_ms: f64,
+    p95_ms: f64,
+    p99_ms: f64,
+    max_ms: f64,
+}
+
+#[derive(Debug, serde_derive::Serialize)]
+struct TargetReport {
+    method: String,
+    path: String,
+    requests: u64,
+    errors: u64,
+}
+
+/// The outcome of a single request
+struct Sample {
+    target: usize,
+    status: Option<u16>,
+    elapsed: Duration,
+}
+
+/// Drive `rate` requests per second for `duration_secs` at the HTTP server on `port`, and
+/// report how it coped once the last request is done.
+pub fn load(
+    database: Database,
+    port: u16,
+    body: LoadJsonBody
+) -> impl Future<Item = warp::reply::Response, Error = warp::Rejection> {
+    let registry = lock(&database);
+    let kind = registry.servers.get(&port).map(|server| server.config.kind.clone());
+    // In-process HTTP servers serve the admin API, which may require its token
+    let authorization = registry.shared.admin_token.as_ref().map(|token| format!("Bearer {}", token));
+    drop(registry);
+    match kind {
+        None => {
+            let error = format!("no server on port {}", port);
+            return Either::A(futures::future::ok(error_reply(warp::http::StatusCode::NOT_FOUND, &error)));
+        }
+        Some(ServerKind::Http) => {}
+        Some(kind) => {
+            let error = format!("can't load a {} server", kind.name());
+            return Either::A(futures::future::ok(error_reply(warp::http::StatusCode::CONFLICT, &error)));
+        }
+    }
+
+    let targets = match parse_targets(port, &body) {
+        Ok(targets) => targets,
+        Err(error) => {
+            return Either::A(futures::future::ok(error_reply(warp::http::StatusCode::UNPROCESSABLE_ENTITY, &error)));
+        }
+    };
+
+    let client = hyper::Client::new();
+    let timeout = Duration::from_millis(body.timeout_ms);
+    let total = u64::from(body.rate) * u64::from(body.duration_secs);
+    let weights: Vec<u32> = body.requests.iter().map(|target| target.weight).collect();
+    let mut sent = vec![0u64; targets.len()];
+    let started = Instant::now();
+
+    let samples = Interval::new(started, Duration::from_secs(1) / body.rate)
+        .take(total)
+        .map_err(|e| e.to_string())
+        .map(move |_| {
+            let target = next_target(&weights, &mut sent);
+            let (method, uri) = &targets[target];
+            let mut request = hyper::Request::builder();
+            request.method(method.clone()).uri(uri.clone());
+            if let Some(authorization) = &authorization {
+                request.header(hyper::header::AUTHORIZATION, authorization.as_str());
+            }
+            let request = request.body(hyper::Body::empty()).expect("method and uri were validated");
+
+            let sent_at = Instant::now();
+            Timeout::new(client.request(request), timeout).then(move |response| {
+                Ok(Sample {
+                    target,
+                    status: response.ok().map(|response| response.status().as_u16()),
+                    elapsed: sent_at.elapsed(),
+                })
+            })
+        })
+        .buffer_unordered(body.concurrency.max(1))
+        .collect();
+
+    Either::B(samples.then(move |samples| {
+        Ok(match samples {
+            Ok(samples) => {
+                let report = report(&body.requests, samples, started.elapsed());
+                warp::reply::Reply::into_response(warp::reply::json(&report))
+            }
+            Err(e) => error_reply(warp::http::StatusCode::INTERNAL_SERVER_ERROR, &format!("load timer failed: {}", e)),
+        })
+    }))
+}
+
+fn parse_targets(port: u16, body: &LoadJsonBody) -> Result<Vec<(hyper::Method, hyper::Uri)>, String> {
+    if body.rate == 0 || body.duration_secs == 0 {
+        return Err("rate and duration_secs must be positive".to_string());
+    }
+    if body.rate > MAX_RATE {
+        return Err(format!("rate must be at most {}", MAX_RATE));
+    }
+    if u64::from(body.rate) * u64::from(body.duration_secs) > MAX_REQUESTS {
+        return Err(format!("rate times duration_secs must be at most {} requests", MAX_REQUESTS));
+    }
+    if body.requests.iter().all(|target| target.weight == 0) {
+        return Err("at least one request needs a positive weight".to_string());
+    }
+
+    body.requests.iter()
+        .map(|target| {
+            let method = target.method.parse::<hyper::Method>()
+                .map_err(|_| format!("invalid method {:?}", target.method))?;
+            let uri = format!("http://127.0.0.1:{}{}", port, target.path).parse::<hyper::Uri>()
+                .map_err(|_| format!("invalid path {:?}", target.path))?;
+            Ok((method, uri))
+        })
+        .collect()
+}
+
+/// The target furthest behind its share of the requests sent so far
+fn next_target(weights: &[u32], sent: &mut [u64]) -> usize {
+    let target = (0..weights.len())
+        .filter(|target| weights[*target] > 0)
+        .min_by(|a, b| {
+            let share = |target: usize| (sent[target] + 1) as f64 / f64::from(weights[target]);
+            share(*a).total_cmp(&share(*b))
+        })
+        .unwrap_or(0);
+    sent[target] += 1;
+    target
+}
+
+fn report(targets: &[LoadTarget], samples: Vec<Sample>, elapsed: Duration) -> LoadReport {
+    let mut statuses = BTreeMap::new();
+    let mut report_targets: Vec<TargetReport> = targets.iter()
+        .map(|target| TargetReport {
+            method: target.method.clone(),
+            path: target.path.clone(),
+            requests: 0,
+            errors: 0,
+        })
+        .collect();
+    let mut latencies = Vec::new();
+
+    for sample in &samples {
+        report_targets[sample.target].requests += 1;
+        match sample.status {
+            Some(status) => {
+                *statuses.entry(status).or_insert(0) += 1;
+                latencies.push(sample.elapsed);
+            }
+            None => report_targets[sample.target].errors += 1,
+        }
+    }
+    latencies.sort();
+
+    let millis = |elapsed: Duration| (elapsed.as_secs_f64() * 1000.0 * 100.0).round() / 100.0;
+    let percentile = |q: f64| {
+        let rank = ((q * latencies.len() as f64).ceil() as usize).max(1);
+        millis(latencies[rank - 1])
+    };
+    let latency = Some(()).filter(|_| !latencies.is_empty()).map(|_| LatencyReport {
+        min_ms: millis(latencies[0]),
+        p50_ms: percentile(0.5),
+        p95_ms: percentile(0.95),
+        p99_ms: percentile(0.99),
+        max_ms: millis(latencies[latencies.len() - 1]),
+    });
+
+    LoadReport {
+        requests: samples.len() as u64,
+        errors: report_targets.iter().map(|target| target.errors).sum(),
+        statuses,
+        duration_ms: elapsed.as_millis() as u64,
+        throughput_rps: (latencies.len() as f64 / elapsed.as_secs_f64() * 100.0).round() / 100.0,
+        latency,
+        targets: report_targets,
+    }
+}
@@ -0,0 +1,270 @@
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{TcpStream, UdpSocket};
+#[cfg(unix)]
+use std::os::unix::io::{AsRawFd, FromRawFd};
+#[cfg(windows)]
+use std::os::windows::io::{AsHandle, AsRawHandle};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::sync::mpsc;
+use std::time::Duration;
+
+use crate::error::lock;
+use crate::{har, store};
+use crate::server_logs::{Rotating, DEFAULT_KEEP, DEFAULT_MAX_BYTES};
+
+/// How long the lines already written are given to reach the sinks when the process exits
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(1);
+
+const APP_NAME: &str = "warp_self_replicating_server";
+
+#[cfg(target_os = "linux")]
+const JOURNAL_SOCKET: &str = "/run/systemd/journal/socket";
+
+/// Where the lines the process writes to stderr go, given with `--log`, once each for as
+/// many as wanted: `stderr`, `journald` on Linux, `syslog://{host}:{port}` over UDP or
+/// `syslog+tcp://{host}:{port}` as RFC 5424, or
+/// `file:{path}?max_bytes={bytes}&keep={files}`, rotated to `{path}.1` and on once it's
+/// `max_bytes` long, 10 MiB by default, keeping 5 by default
+#[derive(Clone, Debug, PartialEq)]
+pub enum Sink {
+    Stderr,
+    #[cfg(target_os = "linux")]
+    Journald,
+    Syslog { address: String, tcp: bool },
+    File { path: PathBuf, max_bytes: u64, keep: usize },
+}
+
+impl Sink {
+    pub fn parse(spec: &str) -> Result<Sink, String> {
+        let invalid = |error: &str| format!("invalid --log {:?}: {}", spec, error);
+        if let Some(address) = spec.strip_prefix("syslog://") {
+            return Ok(Sink::Syslog { address: address.to_string(), tcp: false });
+        }
+        if let Some(address) = spec.strip_prefix("syslog+tcp://") {
+            return Ok(Sink::Syslog { address: address.to_string(), tcp: true });
+        }
+        if let Some(file) = spec.strip_prefix("file:") {
+            let (path, query) = file.split_once('?').unwrap_or((file, ""));
+            if path.is_empty() {
+                return Err(invalid("a file needs a path"));
+            }
+            let (mut max_bytes, mut keep) = (DEFAULT_MAX_BYTES, DEFAULT_KEEP);
+            for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+                match pair.split_once('=') {
+                    Some(("max_bytes", value)) => {
+                        max_bytes = value.parse().ok().filter(|bytes| *bytes > 0).ok_or_else(|| invalid("max_bytes isn't a positive number"))?;
+                    }
+                    Some(("keep", value)) => keep = value.parse().map_err(|_| invalid("keep isn't a number"))?,
+                    _ => return Err(invalid("a file takes max_bytes and keep")),
+                }
+            }
+            return Ok(Sink::File { path: PathBuf::from(path), max_bytes, keep });
+        }
+        match spec {
+            "stderr" => Ok(Sink::Stderr),
+            #[cfg(target_os = "linux")]
+            "journald" => Ok(Sink::Journald),
+            #[cfg(not(target_os = "linux"))]
+            "journald" => Err(invalid("journald is only supported on Linux")),
+            _ => Err(invalid("expected stderr, journald, syslog://{host}:{port}, syslog+tcp://{host}:{port} or file:{path}")),
+        }
+    }
+}
+
+/// How bad a line is, by the syslog severities: an error if it tells of a failure, an
+/// invalid setting or a panic, information otherwise
+fn severity(line: &str) -> u8 {
+    let line = line.to_ascii_lowercase();
+    if line.starts_with("failed") || line.starts_with("invalid") || line.contains("error") || line.contains("panicked") {
+        3
+    } else {
+        6
+    }
+}
+
+/// A sink opened
+enum Output {
+    Stderr(File),
+    #[cfg(target_os = "linux")]
+    Journald(std::os::unix::net::UnixDatagram),
+    SyslogUdp(UdpSocket),
+    /// Connected again after the connection fails
+    SyslogTcp { address: String, stream: Option<TcpStream> },
+    File(Rotating),
+}
+
+impl Output {
+    fn open(sink: &Sink, stderr: &File) -> io::Result<Output> {
+        Ok(match sink {
+            Sink::Stderr => Output::Stderr(stderr.try_clone()?),
+            #[cfg(target_os = "linux")]
+            Sink::Journald => {
+                let socket = std::os::unix::net::UnixDatagram::unbound()?;
+                socket.connect(JOURNAL_SOCKET)?;
+                Output::Journald(socket)
+            }
+            Sink::Syslog { address, tcp: false } => {
+                let socket = UdpSocket::bind(("0.0.0.0", 0))?;
+                socket.connect(address.as_str())?;
+                Output::SyslogUdp(socket)
+            }
+            Sink::Syslog { address, tcp: true } => {
+                Output::SyslogTcp { address: address.clone(), stream: Some(TcpStream::connect(address.as_str())?) }
+            }
+            Sink::File { path, max_bytes, keep } => Output::File(Rotating::open(path.clone(), *max_bytes, None, *keep)?),
+        })
+    }
+
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        match self {
+            Output::Stderr(stderr) => writeln!(stderr, "{}", line),
+            #[cfg(target_os = "linux")]
+            Output::Journald(socket) => {
+                let entry = format!("PRIORITY={}\nSYSLOG_IDENTIFIER={}\nMESSAGE={}\n", severity(line), APP_NAME, line);
+                socket.send(entry.as_bytes()).map(drop)
+            }
+            Output::SyslogUdp(socket) => socket.send(syslog_message(line).as_bytes()).map(drop),
+            Output::SyslogTcp { address, stream } => {
+                // Framed by octet counting, see RFC 6587
+                let message = syslog_message(line);
+                let framed = format!("{} {}", message.len(), message);
+                if let Some(connected) = stream {
+                    if connected.write_all(framed.as_bytes()).is_ok() {
+                        return Ok(());
+                    }
+                }
+                *stream = None;
+                let mut connected = TcpStream::connect(address.as_str())?;
+                connected.write_all(framed.as_bytes())?;
+                *stream = Some(connected);
+                Ok(())
+            }
+            Output::File(file) => file.write_line(line),
+        }
+    }
+}
+
+/// `line` as an RFC 5424 message from the user facility
+fn syslog_message(line: &str) -> String {
+    let priority = 8 + severity(line);
+    let now_ms = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_millis() as u64)
+        .unwrap_or(0);
+    format!("<{}>1 {} {} {} {} - - {}", priority, har::iso8601(now_ms), store::host_name(), APP_NAME, std::process::id(), line)
+}
+
+/// What the process is logging with, once it is
+struct Logging {
+    /// The stderr the process started with
+    stderr: File,
+    /// The write end of the pipe stderr points at instead, closed once it no longer does
+    writer: io::PipeWriter,
+    /// Word of the sinks having taken every line
+    finished: mpsc::Receiver<()>,
+}
+
+static LOGGING: Mutex<Option<Logging>> = Mutex::new(None);
+
+/// Send every line the process and its subprocesses write to stderr to `sinks` rather
+/// than stderr itself, on a thread of its own. A sink failing is told of on the stderr
+/// the process started with, once until it works again. Lines written as the process
+/// exits are given a moment to reach the sinks.
+pub fn start(sinks: &[Sink]) -> io::Result<()> {
+    if sinks.is_empty() {
+        return Ok(());
+    }
+    // The stderr the process started with, kept apart from the pipe taking its place
+    #[cfg(unix)]
+    let mut stderr = {
+        let stderr = unsafe { libc::fcntl(libc::STDERR_FILENO, libc::F_DUPFD_CLOEXEC, 0) };
+        if stderr == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        // A descriptor just duplicated, which nothing else owns
+        unsafe { File::from_raw_fd(stderr) }
+    };
+    #[cfg(windows)]
+    let mut stderr = File::from(io::stderr().as_handle().try_clone_to_owned()?);
+
+    let mut outputs = Vec::new();
+    for sink in sinks {
+        let output = Output::open(sink, &stderr)
+            .map_err(|e| io::Error::new(e.kind(), format!("failed to open log sink {:?}: {}", sink, e)))?;
+        // Whether the sink failed the last line it was given
+        outputs.push((sink.clone(), output, false));
+    }
+
+    let (lines, writer) = io::pipe()?;
+    redirect_stderr(&writer)?;
+
+    let (done, finished) = mpsc::channel();
+    let mut report = stderr.try_clone()?;
+    std::thread::Builder::new().name("log".to_string()).spawn(move || {
+        let mut lines = BufReader::new(lines);
+        let mut line = Vec::new();
+        while lines.read_until(b'\n', &mut line).is_ok_and(|read| read > 0) {
+            let text = String::from_utf8_lossy(&line);
+            let text = text.trim_end_matches(['\r', '\n']);
+            for (sink, output, failing) in &mut outputs {
+                match output.write_line(text) {
+                    Ok(()) => *failing = false,
+                    Err(e) if !*failing => {
+                        *failing = true;
+                        let _ = writeln!(report, "failed to log to {:?}: {}", sink, e);
+                    }
+                    Err(_) => {}
+                }
+            }
+            line.clear();
+        }
+        let _ = done.send(());
+    })?;
+
+    stderr.flush()?;
+    *lock(&LOGGING) = Some(Logging { stderr, writer, finished });
+    unsafe {
+        libc::atexit(finish_at_exit);
+    }
+    Ok(())
+}
+
+/// Point the process's stderr at `to`, a copy of it on Unix, and `to` itself on Windows,
+/// where it has to be kept open for as long as stderr points at it
+#[cfg(unix)]
+fn redirect_stderr(to: &impl AsRawFd) -> io::Result<()> {
+    if unsafe { libc::dup2(to.as_raw_fd(), libc::STDERR_FILENO) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(windows)]
+fn redirect_stderr(to: &impl AsRawHandle) -> io::Result<()> {
+    use windows_sys::Win32::System::Console::{SetStdHandle, STD_ERROR_HANDLE};
+
+    if unsafe { SetStdHandle(STD_ERROR_HANDLE, to.as_raw_handle() as isize) } == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+extern "C" fn finish_at_exit() {
+    finish();
+}
+
+/// Point stderr back at the one the process started with, giving the lines already
+/// written a moment to reach the sinks, before the process exits or execs another
+pub fn finish() {
+    let logging = match LOGGING.try_lock() {
+        Ok(mut logging) => logging.take(),
+        Err(_) => return,
+    };
+    if let Some(Logging { stderr, writer, finished }) = logging {
+        let _ = redirect_stderr(&stderr);
+        drop(writer);
+        // Subprocesses may still hold the pipe open, so this doesn't wait for it to close
+        let _ = finished.recv_timeout(FLUSH_TIMEOUT);
+    }
+}
@@ -0,0 +1,162 @@
+use warp::{Filter, Reply};
+
+use std::sync::{Arc, Mutex};
+
+use crate::{error_reply, unix_time, Database};
+use crate::error::lock;
+
+/// JSON body of `PUT /maintenance`: a time during which servers answer every request
+/// with 503, as if taken down for planned maintenance
+#[derive(Clone, Debug, PartialEq, serde_derive::Deserialize, serde_derive::Serialize)]
+pub struct MaintenanceWindow {
+    /// Unix time the window opens, by default when it's set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub starts_at: Option<u64>,
+    /// Unix time the window closes. Left out along with `duration_secs`, it stays open
+    /// until deleted.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ends_at: Option<u64>,
+    /// How long the window stays open, in place of `ends_at`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub duration_secs: Option<u64>,
+    /// `Retry-After` of the responses, by default the seconds left until the window
+    /// closes, if it does
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retry_after_secs: Option<u64>,
+    /// Body of the responses, by default a JSON error
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub body: Option<String>,
+    /// `Content-Type` of `body`
+    #[serde(default = "default_content_type")]
+    pub content_type: String,
+    /// Ports of the in-process HTTP servers taken down, by default every one but the
+    /// admin API's
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub ports: Vec<u16>,
+}
+
+fn default_content_type() -> String {
+    "text/plain; charset=utf-8".to_string()
+}
+
+/// The maintenance window set, shared by the registry with every HTTP server
+pub type SharedWindow = Arc<Mutex<Option<Window>>>;
+
+/// A maintenance window as set, with its times resolved
+#[derive(Debug)]
+pub struct Window {
+    config: MaintenanceWindow,
+    own_port: u16,
+}
+
+impl Window {
+    fn starts_at(&self) -> u64 {
+        self.config.starts_at.unwrap_or(0)
+    }
+
+    fn open(&self, now: u64) -> bool {
+        now >= self.starts_at() && self.config.ends_at.is_none_or(|ends_at| now < ends_at)
+    }
+
+    fn applies(&self, port: u16) -> bool {
+        if self.config.ports.is_empty() {
+            port != self.own_port
+        } else {
+            self.config.ports.contains(&port)
+        }
+    }
+
+    fn reply(&self, now: u64) -> warp::reply::Response {
+        let status = warp::http::StatusCode::SERVICE_UNAVAILABLE;
+        let mut response = match &self.config.body {
+            Some(body) => {
+                let reply = warp::reply::with_status(body.clone(), status);
+                warp::reply::with_header(reply, "content-type", self.config.content_type.as_str()).into_response()
+            }
+            None => error_reply(status, "down for maintenance"),
+        };
+        let retry_after = self.config.retry_after_secs
+            .or_else(|| self.config.ends_at.map(|ends_at| ends_at.saturating_sub(now).max(1)));
+        if let Some(retry_after) = retry_after {
+            response.headers_mut().insert("retry-after", retry_after.into());
+        }
+        response
+    }
+}
+
+/// `GET /maintenance` reply: the window, and whether it's open now
+#[derive(serde_derive::Serialize)]
+struct WindowStatus<'a> {
+    #[serde(flatten)]
+    config: &'a MaintenanceWindow,
+    open: bool,
+}
+
+/// Answer the requests of the server on `port` with 503 while a maintenance window
+/// taking it down is open. Other requests are rejected to be handled as usual.
+pub fn unavailable(
+    port: u16,
+    window: SharedWindow
+) -> impl Filter<Extract = (warp::reply::Response,), Error = warp::Rejection> + Clone {
+    warp::any().and_then(move || {
+        let now = unix_time();
+        match &*lock(&window) {
+            Some(window) if window.applies(port) && window.open(now) => Ok(window.reply(now)),
+            _ => Err(warp::reject::not_found()),
+        }
+    })
+}
+
+/// Set the maintenance window, replacing any set before
+pub fn set_window(
+    database: Database,
+    own_port: u16,
+    mut config: MaintenanceWindow
+) -> warp::reply::Response {
+    let starts_at = *config.starts_at.get_or_insert_with(unix_time);
+    if let Some(duration_secs) = config.duration_secs.take() {
+        if config.ends_at.is_some() {
+            let error = "a maintenance window has either ends_at or duration_secs";
+            return error_reply(warp::http::StatusCode::UNPROCESSABLE_ENTITY, error);
+        }
+        config.ends_at = Some(starts_at + duration_secs);
+    }
+    if config.ends_at.is_some_and(|ends_at| ends_at <= starts_at) {
+        let error = "a maintenance window has to end after it starts";
+        return error_reply(warp::http::StatusCode::UNPROCESSABLE_ENTITY, error);
+    }
+    if warp::http::header::HeaderValue::from_str(&config.content_type).is_err() {
+        let error = format!("invalid content type {:?}", config.content_type);
+        return error_reply(warp::http::StatusCode::UNPROCESSABLE_ENTITY, &error);
+    }
+
+    let window = Window { config, own_port };
+    let reply = warp::reply::json(&WindowStatus { config: &window.config, open: window.open(unix_time()) }).into_response();
+    let shared = lock(&database).maintenance.clone();
+    *lock(&shared) = Some(window);
+    reply
+}
+
+/// The maintenance window set, if any
+pub fn get_window(database: Database) -> warp::reply::Response {
+    let window = lock(&database).maintenance.clone();
+    let window = lock(&window);
+    match &*window {
+        Some(window) => warp::reply::json(&WindowStatus { config: &window.config, open: window.open(unix_time()) }).into_response(),
+        None => not_set(),
+    }
+}
+
+/// Remove the maintenance window, closing it if it's open
+pub fn delete_window(database: Database) -> warp::reply::Response {
+    let window = lock(&database).maintenance.clone();
+    let removed = lock(&window).take();
+    match removed {
+        Some(_) => warp::http::StatusCode::NO_CONTENT.into_response(),
+        None => not_set(),
+    }
+}
+
+fn not_set() -> warp::reply::Response {
+    error_reply(warp::http::StatusCode::NOT_FOUND, "no maintenance window is set")
+}
@@ -0,0 +1,79 @@
+use mdns_sd::{ServiceDaemon, ServiceInfo};
+
+use std::collections::{BTreeMap, HashMap};
+
+use crate::{store, ServerJsonBody, ServerKind, ServerStatus};
+
+/// What a server is advertised as
+#[derive(Debug, PartialEq)]
+struct Advertised {
+    service_type: &'static str,
+    instance: String,
+    labels: BTreeMap<String, String>,
+}
+
+/// Advertises the HTTP servers that are up on the local network over mDNS, as
+/// `_http._tcp` services or `_https._tcp` for those serving TLS, with their labels as
+/// TXT records. A server is advertised by its name, or as `server-{port}` without one.
+pub struct Advertiser {
+    daemon: ServiceDaemon,
+    host_name: String,
+    advertised: HashMap<u16, Advertised>,
+}
+
+impl Advertiser {
+    /// `MDNS=1` advertises the servers, under the host name `MDNS_HOSTNAME` if set and
+    /// otherwise that of the machine
+    pub fn from_env() -> Result<Option<Advertiser>, String> {
+        match std::env::var("MDNS").as_deref() {
+            Ok("1") | Ok("true") => {}
+            Ok("0") | Ok("false") | Err(_) => return Ok(None),
+            Ok(mdns) => return Err(format!("invalid MDNS {:?}, expected 1 or 0", mdns)),
+        }
+        let host_name = std::env::var("MDNS_HOSTNAME").unwrap_or_else(|_| store::host_name());
+        let daemon = ServiceDaemon::new().map_err(|e| format!("failed to start mDNS: {}", e))?;
+        let host_name = format!("{}.local.", host_name.trim_end_matches(".local."));
+        Ok(Some(Advertiser { daemon, host_name, advertised: HashMap::new() }))
+    }
+
+    /// Advertise `server` as it is now, withdrawing it if it isn't an HTTP server that's up
+    pub fn update(&mut self, server: &ServerJsonBody) {
+        let port = server.port;
+        let up = matches!(server.status, ServerStatus::Starting | ServerStatus::Running | ServerStatus::Paused);
+        if server.kind != ServerKind::Http || !up {
+            return self.withdraw(port);
+        }
+
+        let advertised = Advertised {
+            service_type: if server.tls.is_some() { "_https._tcp.local." } else { "_http._tcp.local." },
+            instance: server.name.clone().unwrap_or_else(|| format!("server-{}", port)),
+            labels: server.labels.clone(),
+        };
+        if self.advertised.get(&port) == Some(&advertised) {
+            return;
+        }
+        self.withdraw(port);
+
+        let labels: Vec<(&str, &str)> = advertised.labels.iter()
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+            .collect();
+        let info = ServiceInfo::new(advertised.service_type, &advertised.instance, &self.host_name, (), port, &labels[..])
+            .map(ServiceInfo::enable_addr_auto);
+        match info.and_then(|info| self.daemon.register(info)) {
+            Ok(()) => {
+                self.advertised.insert(port, advertised);
+            }
+            Err(e) => eprintln!("failed to advertise server {} over mDNS: {}", port, e),
+        }
+    }
+
+    /// Stop advertising the server on `port`, if it is
+    pub fn withdraw(&mut self, port: u16) {
+        if let Some(advertised) = self.advertised.remove(&port) {
+            let fullname = format!("{}.{}", advertised.instance, advertised.service_type);
+            if let Err(e) = self.daemon.unregister(&fullname) {
+                eprintln!("failed to withdraw server {} from mDNS: {}", port, e);
+            }
+        }
+    }
+}
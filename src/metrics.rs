@@ -0,0 +1,183 @@
+use warp::Reply;
+
+use std::fmt::Write;
+use std::time::Duration;
+
+use crate::Database;
+use crate::error::lock;
+use crate::journal::JournalStats;
+
+/// Upper bounds of the latency histogram buckets, in seconds
+const BUCKETS: [f64; 12] = [0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0];
+
+/// Response times of the requests served by an HTTP server
+#[derive(Clone, Debug, Default)]
+pub struct LatencyHistogram {
+    /// Requests per bucket, the last one counting those slower than every bound
+    counts: [u64; BUCKETS.len() + 1],
+    sum_secs: f64,
+}
+
+/// Latency percentiles reported in a server's JSON representation
+#[derive(Clone, Debug, Default, PartialEq, serde_derive::Deserialize, serde_derive::Serialize)]
+pub struct LatencySummary {
+    pub requests: u64,
+    pub p50_ms: f64,
+    pub p95_ms: f64,
+    pub p99_ms: f64,
+}
+
+impl LatencyHistogram {
+    /// Count the requests of `other` as well, as one histogram of the servers of both
+    pub fn merge(&mut self, other: &LatencyHistogram) {
+        for (count, other) in self.counts.iter_mut().zip(other.counts) {
+            *count += other;
+        }
+        self.sum_secs += other.sum_secs;
+    }
+
+    pub fn record(&mut self, elapsed: Duration) {
+        let secs = elapsed.as_secs_f64();
+        let bucket = BUCKETS.iter().position(|bound| secs <= *bound).unwrap_or(BUCKETS.len());
+        self.counts[bucket] += 1;
+        self.sum_secs += secs;
+    }
+
+    fn count(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    /// Estimate the `q` quantile in seconds by interpolating within its bucket, like
+    /// Prometheus' `histogram_quantile`. Requests slower than the last bound count as
+    /// taking exactly that long.
+    fn quantile(&self, q: f64) -> f64 {
+        let rank = q * self.count() as f64;
+        let mut below = 0;
+
+        for (bucket, count) in self.counts.iter().enumerate() {
+            if *count > 0 && (below + count) as f64 >= rank {
+                let lower = if bucket == 0 { 0.0 } else { BUCKETS[bucket - 1] };
+                let upper = match BUCKETS.get(bucket) {
+                    Some(upper) => *upper,
+                    None => return lower,
+                };
+                return lower + (upper - lower) * (rank - below as f64) / *count as f64;
+            }
+            below += count;
+        }
+        0.0
+    }
+
+    /// Percentiles of the recorded latencies, if any requests were recorded
+    pub fn summary(&self) -> Option<LatencySummary> {
+        let millis = |q| (self.quantile(q) * 1000.0 * 100.0).round() / 100.0;
+
+        Some(LatencySummary {
+            requests: self.count(),
+            p50_ms: millis(0.5),
+            p95_ms: millis(0.95),
+            p99_ms: millis(0.99),
+        })
+        .filter(|summary| summary.requests > 0)
+    }
+}
+
+/// Render the latency histograms of every HTTP server in the Prometheus text format
+pub fn metrics(database: Database) -> warp::reply::Response {
+    let registry = lock(&database);
+
+    let mut histograms: Vec<(u16, LatencyHistogram)> = registry.servers.values()
+        .filter_map(|server| {
+            let latency = lock(server.latency.as_ref()?).clone();
+            Some((server.config.port, latency))
+        })
+        .collect();
+    histograms.sort_by_key(|(port, _)| *port);
+
+    let mut body = String::new();
+    histogram_metric(&mut body, "mock_server_request_duration_seconds",
+        "Response times of the requests served by each HTTP server", &histograms);
+
+    let mut queues: Vec<(u16, LatencyHistogram, u64, usize)> = registry.servers.values()
+        .filter(|server| server.config.admission_queue.is_some())
+        .filter_map(|server| {
+            let usage = server.usage.as_ref()?;
+            let (waited, timeouts) = usage.queue_stats();
+            Some((server.config.port, waited, timeouts, usage.summary().queued))
+        })
+        .collect();
+    queues.sort_by_key(|(port, ..)| *port);
+
+    let waited: Vec<(u16, LatencyHistogram)> = queues.iter().map(|(port, waited, ..)| (*port, waited.clone())).collect();
+    histogram_metric(&mut body, "mock_server_request_queue_seconds",
+        "Time the requests let out of each HTTP server's admission queue waited in it", &waited);
+    let _ = writeln!(body, "# HELP mock_server_queue_timeouts_total Requests answered with 503 after waiting in each HTTP server's admission queue for as long as it lets them");
+    let _ = writeln!(body, "# TYPE mock_server_queue_timeouts_total counter");
+    for (port, _, timeouts, _) in &queues {
+        let _ = writeln!(body, "mock_server_queue_timeouts_total{{port=\"{}\"}} {}", port, timeouts);
+    }
+    let _ = writeln!(body, "# HELP mock_server_queued_requests Requests waiting in each HTTP server's admission queue");
+    let _ = writeln!(body, "# TYPE mock_server_queued_requests gauge");
+    for (port, _, _, queued) in &queues {
+        let _ = writeln!(body, "mock_server_queued_requests{{port=\"{}\"}} {}", port, queued);
+    }
+
+    let mut journals: Vec<(u16, JournalStats)> = registry.servers.values()
+        .filter_map(|server| Some((server.config.port, lock(server.journal.as_ref()?).stats())))
+        .collect();
+    journals.sort_by_key(|(port, _)| *port);
+
+    let mut journal_metric = |name: &str, kind: &str, help: &str, value: &dyn Fn(&JournalStats) -> u64| {
+        let _ = writeln!(body, "# HELP {} {}", name, help);
+        let _ = writeln!(body, "# TYPE {} {}", name, kind);
+        for (port, stats) in &journals {
+            let _ = writeln!(body, "{}{{port=\"{}\"}} {}", name, port, value(stats));
+        }
+    };
+    journal_metric("mock_server_journal_recorded_total", "counter",
+        "Requests recorded in each HTTP server's journal", &|stats| stats.recorded);
+    journal_metric("mock_server_journal_evicted_total", "counter",
+        "Requests evicted from each HTTP server's journal to stay within its limits", &|stats| stats.evicted);
+    journal_metric("mock_server_journal_truncated_total", "counter",
+        "Bodies cut short in each HTTP server's journal", &|stats| stats.truncated);
+    journal_metric("mock_server_journal_bytes", "gauge",
+        "Memory taken by each HTTP server's journal, roughly", &|stats| stats.bytes as u64);
+
+    if !registry.health.is_empty() {
+        let _ = writeln!(body, "# HELP mock_server_healthy Whether each server was healthy when last probed, with HEALTH_CHECK_INTERVAL_SECS");
+        let _ = writeln!(body, "# TYPE mock_server_healthy gauge");
+        for (port, healthy) in &registry.health {
+            let _ = writeln!(body, "mock_server_healthy{{port=\"{}\"}} {}", port, u8::from(*healthy));
+        }
+    }
+
+    let _ = writeln!(body, "# HELP mock_server_leaked_tasks Server futures that dropped their shutdown receiver, or didn't end once told to shut down");
+    let _ = writeln!(body, "# TYPE mock_server_leaked_tasks gauge");
+    for leak in registry.shutdowns.leaks(&registry.servers) {
+        let _ = writeln!(body, "mock_server_leaked_tasks{{port=\"{}\",reason=\"{}\"}} 1", leak.port, leak.reason.as_str());
+    }
+    let _ = writeln!(body, "# HELP mock_server_pending_shutdowns Server futures told to shut down that haven't ended yet");
+    let _ = writeln!(body, "# TYPE mock_server_pending_shutdowns gauge");
+    let _ = writeln!(body, "mock_server_pending_shutdowns {}", registry.shutdowns.pending());
+    let _ = writeln!(body, "# HELP mock_server_aborted_tasks_total Server futures aborted with DELETE /{{port}}?force=true");
+    let _ = writeln!(body, "# TYPE mock_server_aborted_tasks_total counter");
+    let _ = writeln!(body, "mock_server_aborted_tasks_total {}", registry.shutdowns.aborted());
+
+    warp::reply::with_header(body, "content-type", "text/plain; version=0.0.4").into_response()
+}
+
+fn histogram_metric(body: &mut String, name: &str, help: &str, histograms: &[(u16, LatencyHistogram)]) {
+    let _ = writeln!(body, "# HELP {} {}", name, help);
+    let _ = writeln!(body, "# TYPE {} histogram", name);
+
+    for (port, histogram) in histograms {
+        let mut cumulative = 0;
+        for (bound, count) in BUCKETS.iter().zip(&histogram.counts) {
+            cumulative += count;
+            let _ = writeln!(body, "{}_bucket{{port=\"{}\",le=\"{}\"}} {}", name, port, bound, cumulative);
+        }
+        let _ = writeln!(body, "{}_bucket{{port=\"{}\",le=\"+Inf\"}} {}", name, port, histogram.count());
+        let _ = writeln!(body, "{}_sum{{port=\"{}\"}} {}", name, port, histogram.sum_secs);
+        let _ = writeln!(body, "{}_count{{port=\"{}\"}} {}", name, port, histogram.count());
+    }
+}
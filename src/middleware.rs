@@ -0,0 +1,178 @@
+use futures::{Future, Stream};
+use warp::Filter;
+
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::{auth, metrics, mqtt, shared, statsd};
+use crate::error::{lock, Error};
+
+/// How long a `delay` layer holds requests back at most, an hour, well within what the
+/// timer can wait for
+const MAX_DELAY_MS: u64 = 60 * 60 * 1000;
+
+/// A behavior an in-process HTTP server applies to every request it answers, whatever
+/// route answers it. Layers run in the order of the pipeline listing them.
+#[derive(Clone, Debug, PartialEq, serde_derive::Deserialize, serde_derive::Serialize)]
+#[serde(tag = "layer", rename_all = "snake_case")]
+pub enum Layer {
+    /// Print a line for each request answered
+    Log,
+    /// Record response times, see `GET /metrics`, pushing them to statsd if
+    /// `STATSD_ADDR` is set
+    Metrics,
+    /// Hold requests back by `ms`, up to an hour, and by the delay `PUT /chaos` injects
+    Delay {
+        #[serde(default, skip_serializing_if = "is_zero")]
+        ms: u64,
+    },
+    /// Answer 401 unless the request carries `token` as `Authorization: Bearer`, by
+    /// default the admin token if one is set
+    Auth {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        token: Option<String>,
+    },
+    /// Keep requests and their responses in the journal, see `GET /{port}/requests`
+    Record,
+}
+
+impl Layer {
+    pub fn check(&self) -> io::Result<()> {
+        match self {
+            Layer::Delay { ms } if *ms > MAX_DELAY_MS => {
+                let error = format!("a delay layer holds requests back by {} ms at most", MAX_DELAY_MS);
+                Err(io::Error::new(io::ErrorKind::InvalidInput, error))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+fn is_zero(ms: &u64) -> bool {
+    *ms == 0
+}
+
+/// The pipeline of servers that name none, unless `MIDDLEWARE` sets another
+pub fn default_layers() -> Vec<Layer> {
+    vec![Layer::Metrics, Layer::Delay { ms: 0 }, Layer::Record]
+}
+
+/// `MIDDLEWARE` has the root server, and every server naming no pipeline of its own, use
+/// the one it lists. It's a JSON list of layers or, for those without options, a comma
+/// separated list of their names, like `log,metrics,delay,record`.
+pub fn from_env() -> Result<Option<Vec<Layer>>, String> {
+    let layers = match std::env::var("MIDDLEWARE") {
+        Ok(layers) => layers,
+        Err(_) => return Ok(None),
+    };
+    let parsed: Result<Vec<Layer>, _> = if layers.trim_start().starts_with('[') {
+        serde_json::from_str(&layers)
+    } else {
+        layers.split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .map(|name| serde_json::from_value(serde_json::json!({ "layer": name })))
+            .collect()
+    };
+    let parsed = parsed.map_err(|e| format!("invalid MIDDLEWARE {:?}: {}", layers, e))?;
+    for layer in &parsed {
+        layer.check().map_err(|e| format!("invalid MIDDLEWARE {:?}: {}", layers, e))?;
+    }
+    Ok(Some(parsed))
+}
+
+/// The layers of the server on `port`, with what they share with its `RunningServer`
+#[derive(Clone)]
+pub struct Pipeline {
+    layers: Arc<Vec<Layer>>,
+    port: u16,
+    shared: Arc<shared::Shared>,
+    delay_ms: Arc<AtomicU64>,
+    latency: Arc<Mutex<metrics::LatencyHistogram>>,
+    statsd: Option<statsd::Emitter>,
+    mqtt: Option<mqtt::Publisher>,
+}
+
+impl Pipeline {
+    pub fn new(
+        layers: Vec<Layer>,
+        port: u16,
+        shared: Arc<shared::Shared>,
+        delay_ms: Arc<AtomicU64>,
+        latency: Arc<Mutex<metrics::LatencyHistogram>>,
+        statsd: Option<statsd::Emitter>,
+        mqtt: Option<mqtt::Publisher>
+    ) -> Pipeline {
+        Pipeline { layers: Arc::new(layers), port, shared, delay_ms, latency, statsd, mqtt }
+    }
+
+    /// Whether the journal records the server's requests
+    pub fn records(&self) -> bool {
+        self.layers.contains(&Layer::Record)
+    }
+
+    /// The layers acting before a request is routed, rejecting it if one refuses it
+    pub fn before(&self) -> impl Filter<Extract = (), Error = warp::Rejection> + Clone {
+        let pipeline = self.clone();
+        warp::header::optional::<String>("authorization")
+            .and_then(move |authorization: Option<String>| {
+                let pipeline = pipeline.clone();
+                futures::stream::iter_ok::<_, warp::Rejection>(pipeline.layers.to_vec())
+                    .for_each(move |layer| pipeline.enter(&layer, authorization.as_deref()))
+            })
+            .untuple_one()
+    }
+
+    fn enter(&self, layer: &Layer, authorization: Option<&str>) -> Box<dyn Future<Item = (), Error = warp::Rejection> + Send> {
+        match layer {
+            Layer::Delay { ms } => {
+                let delay = Duration::from_millis(ms.saturating_add(self.delay_ms.load(Ordering::SeqCst)));
+                Box::new(tokio::timer::Delay::new(Instant::now() + delay).then(|delayed| {
+                    if let Err(e) = delayed {
+                        eprintln!("delay layer timer error: {}", e);
+                    }
+                    Ok(())
+                }))
+            }
+            Layer::Auth { token } => {
+                let token = token.clone().or_else(|| self.shared.admin_token.clone());
+                let bearer = authorization.and_then(|authorization| authorization.strip_prefix("Bearer "));
+                let authorized = match (&token, bearer) {
+                    (None, _) => true,
+                    (Some(token), Some(bearer)) => auth::tokens_equal(bearer.trim(), token),
+                    (Some(_), None) => false,
+                };
+                Box::new(futures::future::result(if authorized {
+                    Ok(())
+                } else {
+                    Err(warp::reject::custom(Error::Unauthorized))
+                }))
+            }
+            Layer::Log | Layer::Metrics | Layer::Record => Box::new(futures::future::ok(())),
+        }
+    }
+
+    /// The layers seeing a request once it's answered
+    pub fn after(&self, info: warp::log::Info) {
+        for layer in self.layers.iter() {
+            match layer {
+                Layer::Log => {
+                    let elapsed_ms = info.elapsed().as_secs_f64() * 1000.0;
+                    println!("server {}: {} {} {} {:.2}ms", self.port, info.method(), info.path(), info.status().as_u16(), elapsed_ms);
+                }
+                Layer::Metrics => {
+                    lock(&self.latency).record(info.elapsed());
+                    if let Some(statsd) = &self.statsd {
+                        statsd.request(info.status().as_u16(), info.elapsed());
+                    }
+                }
+                Layer::Delay { .. } | Layer::Auth { .. } | Layer::Record => {}
+            }
+        }
+        if let Some(mqtt) = &self.mqtt {
+            mqtt.request(self.port, &info);
+        }
+    }
+}
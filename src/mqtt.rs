@@ -0,0 +1,210 @@
+use futures::Stream;
+
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::sync::mpsc::{self, RecvTimeoutError, SyncSender, TrySendError};
+use std::time::Duration;
+
+use crate::Database;
+use crate::error::lock;
+
+/// How long the broker waits without hearing from the publisher before dropping it
+const KEEP_ALIVE: Duration = Duration::from_secs(60);
+
+/// How long to wait before connecting again after losing the broker
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// Events waiting to be published, beyond which new ones are dropped
+const QUEUE_LEN: usize = 1024;
+
+/// Where events are published, see `Publisher`
+#[derive(Clone, Debug)]
+pub struct MqttConfig {
+    broker: String,
+    topic: String,
+    client_id: String,
+    username: Option<String>,
+    password: Option<String>,
+}
+
+impl MqttConfig {
+    /// `MQTT_BROKER` is the `host:port` of the broker, the port being 1883 if left out,
+    /// and `MQTT_TOPIC` the prefix of the topics published to, `mock` by default. The
+    /// publisher connects as `MQTT_CLIENT_ID`, by default one of its own, with
+    /// `MQTT_USERNAME` and `MQTT_PASSWORD` if set.
+    pub fn from_env() -> Result<Option<MqttConfig>, String> {
+        let broker = match std::env::var("MQTT_BROKER") {
+            Ok(broker) => broker,
+            Err(_) => return Ok(None),
+        };
+        let broker = if broker.rsplit_once(':').is_some_and(|(_, port)| port.parse::<u16>().is_ok()) {
+            broker
+        } else {
+            format!("{}:1883", broker)
+        };
+        let topic = std::env::var("MQTT_TOPIC").unwrap_or_else(|_| "mock".to_string());
+        if topic.is_empty() || topic.contains(['+', '#']) {
+            return Err(format!("invalid MQTT_TOPIC {:?}, expected a topic without wildcards", topic));
+        }
+        Ok(Some(MqttConfig {
+            broker,
+            topic: topic.trim_end_matches('/').to_string(),
+            client_id: std::env::var("MQTT_CLIENT_ID").unwrap_or_else(|_| format!("mock-server-{}", std::process::id())),
+            username: std::env::var("MQTT_USERNAME").ok(),
+            password: std::env::var("MQTT_PASSWORD").ok(),
+        }))
+    }
+}
+
+/// Publishes events to an MQTT broker, at most once: `{topic}/servers/{port}` gets every
+/// change to the server on the port, as a line of `GET /watch`, and
+/// `{topic}/requests/{port}` every request an in-process HTTP server answers. Events
+/// are published from a thread of their own, and dropped while the broker can't be
+/// reached.
+#[derive(Clone, Debug)]
+pub struct Publisher {
+    topic: String,
+    events: SyncSender<(String, Vec<u8>)>,
+}
+
+/// `{topic}/requests/{port}` payload
+#[derive(serde_derive::Serialize)]
+struct RequestEvent<'a> {
+    port: u16,
+    method: &'a str,
+    path: &'a str,
+    status: u16,
+    duration_ms: f64,
+}
+
+impl Publisher {
+    pub fn start(config: MqttConfig) -> io::Result<Publisher> {
+        let (events, queued) = mpsc::sync_channel(QUEUE_LEN);
+        let topic = config.topic.clone();
+        std::thread::Builder::new().name("mqtt".to_string()).spawn(move || {
+            loop {
+                match publish(&config, &queued) {
+                    Ok(()) => return,
+                    Err(e) => eprintln!("MQTT broker {}: {}", config.broker, e),
+                }
+                std::thread::sleep(RECONNECT_DELAY);
+                // What happened while the broker was away is dropped
+                while queued.try_recv().is_ok() {}
+            }
+        })?;
+        Ok(Publisher { topic, events })
+    }
+
+    /// Publish every change to the servers, as long as the registry lasts
+    pub fn publish_changes(&self, database: &Database) -> impl futures::Future<Item = (), Error = ()> {
+        let publisher = self.clone();
+        lock(database).feed.subscribe().for_each(move |line| {
+            let port = serde_json::from_str::<serde_json::Value>(&line).ok()
+                .and_then(|change| change["server"]["port"].as_u64())
+                .unwrap_or_default();
+            publisher.send(format!("{}/servers/{}", publisher.topic, port), line.trim_end().as_bytes().to_vec());
+            Ok(())
+        })
+    }
+
+    /// Publish a request the server on `port` answered
+    pub fn request(&self, port: u16, info: &warp::log::Info) {
+        let event = RequestEvent {
+            port,
+            method: info.method().as_str(),
+            path: info.path(),
+            status: info.status().as_u16(),
+            duration_ms: (info.elapsed().as_secs_f64() * 1000.0 * 100.0).round() / 100.0,
+        };
+        let payload = serde_json::to_vec(&event).unwrap_or_default();
+        self.send(format!("{}/requests/{}", self.topic, port), payload);
+    }
+
+    fn send(&self, topic: String, payload: Vec<u8>) {
+        match self.events.try_send((topic, payload)) {
+            Ok(()) | Err(TrySendError::Full(_)) => {}
+            Err(TrySendError::Disconnected(_)) => eprintln!("the MQTT publisher stopped"),
+        }
+    }
+}
+
+/// Connect to the broker and publish the queued events until the connection fails, or
+/// every `Publisher` is dropped
+fn publish(config: &MqttConfig, queued: &mpsc::Receiver<(String, Vec<u8>)>) -> io::Result<()> {
+    let mut stream = TcpStream::connect(&config.broker)?;
+    stream.set_read_timeout(Some(Duration::from_secs(10)))?;
+    stream.write_all(&connect_packet(config))?;
+
+    let mut connack = [0; 4];
+    stream.read_exact(&mut connack)?;
+    match connack {
+        [0x20, 2, _, 0] => {}
+        [0x20, 2, _, code] => return Err(io::Error::other(format!("refused the connection with code {}", code))),
+        _ => return Err(io::Error::other("answered the connection with something other than CONNACK")),
+    }
+
+    loop {
+        match queued.recv_timeout(KEEP_ALIVE / 2) {
+            Ok((topic, payload)) => {
+                let mut packet = string(&topic);
+                packet.extend(payload);
+                stream.write_all(&packet_of(0x30, packet))?;
+            }
+            Err(RecvTimeoutError::Timeout) => {
+                stream.write_all(&[0xc0, 0])?;
+                let mut pingresp = [0; 2];
+                stream.read_exact(&mut pingresp)?;
+                if pingresp != [0xd0, 0] {
+                    return Err(io::Error::other("answered a ping with something other than PINGRESP"));
+                }
+            }
+            Err(RecvTimeoutError::Disconnected) => {
+                let _ = stream.write_all(&[0xe0, 0]);
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// An MQTT 3.1.1 CONNECT packet starting a clean session
+fn connect_packet(config: &MqttConfig) -> Vec<u8> {
+    let mut flags = 0x02;
+    if config.username.is_some() {
+        flags |= 0x80;
+    }
+    if config.password.is_some() {
+        flags |= 0x40;
+    }
+    let mut packet = string("MQTT");
+    packet.extend([4, flags]);
+    packet.extend((KEEP_ALIVE.as_secs() as u16).to_be_bytes());
+    packet.extend(string(&config.client_id));
+    for credential in config.username.iter().chain(&config.password) {
+        packet.extend(string(credential));
+    }
+    packet_of(0x10, packet)
+}
+
+/// A packet of `kind` with its remaining length
+fn packet_of(kind: u8, body: Vec<u8>) -> Vec<u8> {
+    let mut packet = vec![kind];
+    let mut length = body.len();
+    loop {
+        let byte = (length % 128) as u8;
+        length /= 128;
+        if length == 0 {
+            packet.push(byte);
+            break;
+        }
+        packet.push(byte | 0x80);
+    }
+    packet.extend(body);
+    packet
+}
+
+/// A length prefixed UTF-8 string
+fn string(value: &str) -> Vec<u8> {
+    let mut encoded = (value.len() as u16).to_be_bytes().to_vec();
+    encoded.extend(value.as_bytes());
+    encoded
+}
@@ -0,0 +1,41 @@
+use warp::Filter;
+
+use crate::{error_reply, headers, Database};
+use crate::error::lock;
+use crate::headers::Target;
+
+/// `MULTIPLEX` has the root server expose every other HTTP server at `/s/{port}/` of its
+/// own listener, for environments where clients can't reach any other port. The
+/// servers keep their ports, and the admin API manages them as usual.
+pub fn from_env() -> bool {
+    matches!(std::env::var("MULTIPLEX").as_deref(), Ok("1") | Ok("true"))
+}
+
+/// `/s/{port}/…` on the root server, when multiplexed: forwarded to the server on `port`
+/// without the prefix, which it's told of with `X-Forwarded-Prefix`. Other requests are
+/// rejected to be handled as usual.
+pub fn filter(
+    database: Database,
+    own_port: u16
+) -> impl Filter<Extract = (warp::reply::Response,), Error = warp::Rejection> + Clone {
+    let multiplexed = {
+        let database = database.clone();
+        warp::path("s")
+            .and(warp::path::param::<u16>())
+            .and_then(move |port: u16| {
+                let registry = lock(&database);
+                match registry.multiplexed && registry.root_port == Some(own_port) {
+                    true => Ok(port),
+                    false => Err(warp::reject::not_found()),
+                }
+            })
+    };
+    let unknown = multiplexed.clone().and_then(move |port: u16| {
+        match port != own_port && lock(&database).servers.contains_key(&port) {
+            true => Err(warp::reject::not_found()),
+            false => Ok(error_reply(warp::http::StatusCode::NOT_FOUND, &format!("no server on port {}", port))),
+        }
+    });
+    let known = headers::relay(multiplexed.map(|port| Target::server(port).stripping(format!("/s/{}", port))), None);
+    unknown.or(known).unify()
+}
@@ -0,0 +1,585 @@
+use warp::{Buf, Filter};
+use warp::http::{HeaderMap, Method, Response, StatusCode};
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::io;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::{clock, har};
+use crate::clock::SharedClock;
+use crate::error::lock;
+
+/// Keys a listing has at most unless `max-keys` asks for fewer, as S3's
+const MAX_KEYS: usize = 1000;
+
+/// Numbers the temporary directories of servers keeping their objects on disk
+static STORE_ID: AtomicUsize = AtomicUsize::new(0);
+
+fn invalid(error: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, error)
+}
+
+/// An HTTP server acting as S3-compatible object storage, for clients addressing it by
+/// path, like `http://localhost:{port}/{bucket}/{key}`, with any credentials:
+///
+/// - `GET /` lists the buckets, `PUT|HEAD|DELETE /{bucket}` creates, checks or removes
+///   an empty one, and `GET /{bucket}` lists its objects, by `prefix` and `delimiter`
+/// - `PUT|GET|HEAD|DELETE /{bucket}/{key}` stores, reads, checks or removes an object,
+///   copying it from `x-amz-copy-source` if given, and `POST /{bucket}?delete` removes
+///   several at once
+///
+/// Objects are kept across restarts, and dropped along with the server. Tried after
+/// feature flags and before the fallback.
+#[derive(Clone, Debug, Default, PartialEq, serde_derive::Deserialize, serde_derive::Serialize)]
+pub struct ObjectStorage {
+    /// Put in front of the paths, like `/s3`
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub prefix: String,
+    /// Keep the bytes of objects in a temporary directory rather than in memory
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub on_disk: bool,
+    /// The buckets there are to start with
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub buckets: Vec<String>,
+}
+
+impl ObjectStorage {
+    pub fn check(&self) -> io::Result<()> {
+        if !self.prefix.is_empty() && (!self.prefix.starts_with('/') || self.prefix.ends_with('/')) {
+            return Err(invalid(format!("object storage prefix {:?} doesn't start with / or ends with it", self.prefix)));
+        }
+        match self.buckets.iter().find(|bucket| !valid_bucket(bucket)) {
+            Some(bucket) => Err(invalid(format!("invalid bucket name {:?}", bucket))),
+            None => Ok(()),
+        }
+    }
+}
+
+/// As S3 has them: 3 to 63 lowercase letters, digits, dots and hyphens, starting and
+/// ending with a letter or digit
+fn valid_bucket(name: &str) -> bool {
+    (3..=63).contains(&name.len())
+        && name.bytes().all(|byte| byte.is_ascii_lowercase() || byte.is_ascii_digit() || byte == b'.' || byte == b'-')
+        && name.starts_with(|c: char| c.is_ascii_alphanumeric())
+        && name.ends_with(|c: char| c.is_ascii_alphanumeric())
+}
+
+/// The buckets and objects of a server, kept across restarts
+pub struct Store {
+    /// Where the bytes of objects are kept, if not in memory
+    dir: Option<PathBuf>,
+    next_file: u64,
+    buckets: BTreeMap<String, Bucket>,
+}
+
+pub type SharedStore = Arc<Mutex<Store>>;
+
+struct Bucket {
+    created_ms: u64,
+    objects: BTreeMap<String, Object>,
+}
+
+struct Object {
+    data: Data,
+    size: usize,
+    /// Quoted, as it's sent
+    etag: String,
+    modified_ms: u64,
+    content_type: String,
+    /// The `x-amz-meta-*` headers it was stored with
+    metadata: Vec<(String, String)>,
+}
+
+enum Data {
+    Memory(Arc<Vec<u8>>),
+    File(PathBuf),
+}
+
+impl Drop for Store {
+    fn drop(&mut self) {
+        if let Some(dir) = &self.dir {
+            let _ = std::fs::remove_dir_all(dir);
+        }
+    }
+}
+
+/// The store of the server on `port` acting as `storage`, with its buckets created
+pub fn share(port: u16, storage: &ObjectStorage, now_ms: u64) -> io::Result<SharedStore> {
+    storage.check()?;
+    let dir = match storage.on_disk {
+        true => {
+            let name = format!("objects-{}-{}-{}", std::process::id(), port, STORE_ID.fetch_add(1, Ordering::SeqCst));
+            let dir = std::env::temp_dir().join(name);
+            std::fs::create_dir_all(&dir)?;
+            Some(dir)
+        }
+        false => None,
+    };
+    let buckets = storage.buckets.iter()
+        .map(|name| (name.clone(), Bucket { created_ms: now_ms, objects: BTreeMap::new() }))
+        .collect();
+    Ok(Arc::new(Mutex::new(Store { dir, next_file: 0, buckets })))
+}
+
+impl Store {
+    fn read(&self, object: &Object) -> io::Result<Arc<Vec<u8>>> {
+        match &object.data {
+            Data::Memory(bytes) => Ok(bytes.clone()),
+            Data::File(path) => std::fs::read(path).map(Arc::new),
+        }
+    }
+
+    fn write(&mut self, bytes: Arc<Vec<u8>>) -> io::Result<Data> {
+        match &self.dir {
+            Some(dir) => {
+                let path = dir.join(self.next_file.to_string());
+                self.next_file += 1;
+                std::fs::write(&path, &*bytes)?;
+                Ok(Data::File(path))
+            }
+            None => Ok(Data::Memory(bytes)),
+        }
+    }
+
+    fn remove(&mut self, bucket: &str, key: &str) {
+        let removed = self.buckets.get_mut(bucket).and_then(|bucket| bucket.objects.remove(key));
+        if let Some(Object { data: Data::File(path), .. }) = removed {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+/// `text` with the characters XML has markup for escaped
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;").replace('\'', "&apos;")
+}
+
+/// `text` with the characters XML has markup for unescaped
+fn unescape(text: &str) -> String {
+    text.replace("&lt;", "<").replace("&gt;", ">").replace("&quot;", "\"").replace("&apos;", "'").replace("&amp;", "&")
+}
+
+/// A path segment with its `%XX` escapes decoded
+pub fn percent_decode(segment: &str) -> String {
+    let bytes = segment.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+    while index < bytes.len() {
+        let escaped = segment.get(index + 1..index + 3).and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (bytes[index], escaped) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                index += 3;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                index += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// The MD5 digest of `data`, which S3 tells objects by in their `ETag`
+pub fn md5(data: &[u8]) -> [u8; 16] {
+    const SHIFTS: [[u32; 4]; 4] = [[7, 12, 17, 22], [5, 9, 14, 20], [4, 11, 16, 23], [6, 10, 15, 21]];
+    let constants: Vec<u32> = (0..64).map(|i| ((i as f64 + 1.0).sin().abs() * 4_294_967_296.0) as u32).collect();
+
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&((data.len() as u64).wrapping_mul(8)).to_le_bytes());
+
+    let mut state = [0x6745_2301u32, 0xefcd_ab89, 0x98ba_dcfe, 0x1032_5476];
+    for block in message.chunks(64) {
+        let words: Vec<u32> = block.chunks(4).map(|word| u32::from_le_bytes([word[0], word[1], word[2], word[3]])).collect();
+        let [mut a, mut b, mut c, mut d] = state;
+        for i in 0..64 {
+            let (f, g) = match i / 16 {
+                0 => ((b & c) | (!b & d), i),
+                1 => ((d & b) | (!d & c), (5 * i + 1) % 16),
+                2 => (b ^ c ^ d, (3 * i + 5) % 16),
+                _ => (c ^ (b | !d), (7 * i) % 16),
+            };
+            let f = f.wrapping_add(a).wrapping_add(constants[i]).wrapping_add(words[g]);
+            a = d;
+            d = c;
+            c = b;
+            b = b.wrapping_add(f.rotate_left(SHIFTS[i / 16][i % 4]));
+        }
+        for (word, added) in state.iter_mut().zip([a, b, c, d]) {
+            *word = word.wrapping_add(added);
+        }
+    }
+    let mut digest = [0; 16];
+    for (bytes, word) in digest.chunks_mut(4).zip(state) {
+        bytes.copy_from_slice(&word.to_le_bytes());
+    }
+    digest
+}
+
+/// The payload of a body in the `aws-chunked` encoding SDKs stream signed uploads in,
+/// without its chunk signatures and trailing checksums
+fn decode_aws_chunked(mut body: &[u8]) -> Option<Vec<u8>> {
+    let mut decoded = Vec::new();
+    loop {
+        let line_end = body.windows(2).position(|window| window == b"\r\n")?;
+        let line = std::str::from_utf8(&body[..line_end]).ok()?;
+        let size = usize::from_str_radix(line.split(';').next()?.trim(), 16).ok()?;
+        body = &body[line_end + 2..];
+        if size == 0 {
+            return Some(decoded);
+        }
+        decoded.extend_from_slice(body.get(..size)?);
+        body = body.get(size + 2..)?;
+    }
+}
+
+fn xml(status: StatusCode, document: String) -> warp::reply::Response {
+    let body = format!("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n{}", document);
+    Response::builder()
+        .status(status)
+        .header("content-type", "application/xml")
+        .body(body.into())
+        .expect("the headers are valid")
+}
+
+/// An S3 error, with its code, like `NoSuchKey`, and the resource it's about
+fn error(status: StatusCode, code: &str, message: &str, resource: &str) -> warp::reply::Response {
+    xml(status, format!(
+        "<Error><Code>{}</Code><Message>{}</Message><Resource>{}</Resource></Error>",
+        code, escape(message), escape(resource)
+    ))
+}
+
+fn no_such_bucket(bucket: &str) -> warp::reply::Response {
+    error(StatusCode::NOT_FOUND, "NoSuchBucket", "The specified bucket does not exist", &format!("/{}", bucket))
+}
+
+fn empty(status: StatusCode) -> warp::reply::Response {
+    Response::builder().status(status).body(hyper::Body::empty()).expect("the status is valid")
+}
+
+/// What a request is for: the storage, a bucket or an object in one
+struct Request {
+    bucket: Option<String>,
+    key: Option<String>,
+    method: Method,
+    query: Vec<(String, String)>,
+    headers: HeaderMap,
+    body: Vec<u8>,
+}
+
+impl Request {
+    fn param(&self, name: &str) -> Option<&str> {
+        self.query.iter().find(|(param, _)| param == name).map(|(_, value)| value.as_str())
+    }
+}
+
+/// The bucket and key a request for `path` is for, if it's under `prefix`
+fn route(prefix: &str, path: &str) -> Option<(Option<String>, Option<String>)> {
+    let path = path.strip_prefix(prefix)?.strip_prefix('/')?;
+    match path.split_once('/') {
+        None if path.is_empty() => Some((None, None)),
+        None => Some((Some(percent_decode(path)), None)),
+        Some((bucket, "")) => Some((Some(percent_decode(bucket)), None)),
+        Some((bucket, key)) => Some((Some(percent_decode(bucket)), Some(percent_decode(key)))),
+    }
+}
+
+/// Answer the requests for the buckets and objects of `store` as the object storage
+/// `storage` has it, if the server acts as one, telling times by `clock`. Other requests
+/// are rejected to be handled as usual.
+pub fn filter(
+    storage: Option<&ObjectStorage>,
+    store: Option<SharedStore>,
+    clock: SharedClock
+) -> impl Filter<Extract = (warp::reply::Response,), Error = warp::Rejection> + Clone {
+    let served = storage.map(|storage| storage.prefix.clone()).zip(store);
+
+    // Finding the bucket first leaves the body to the usual routes if it's for none
+    let taken = warp::path::full().and_then(move |path: warp::path::FullPath| {
+        let (prefix, store) = served.as_ref().ok_or_else(warp::reject::not_found)?;
+        let (bucket, key) = route(prefix, path.as_str()).ok_or_else(warp::reject::not_found)?;
+        Ok::<_, warp::Rejection>((store.clone(), bucket, key))
+    });
+
+    taken
+        .and(warp::method())
+        .and(warp::query::<Vec<(String, String)>>())
+        .and(warp::header::headers_cloned())
+        .and(warp::body::concat())
+        .map(move |(store, bucket, key): (SharedStore, Option<String>, Option<String>), method, query, headers: HeaderMap, body: warp::body::FullBody| {
+            let streamed = headers.get("x-amz-content-sha256")
+                .and_then(|sha| sha.to_str().ok())
+                .is_some_and(|sha| sha.starts_with("STREAMING-"));
+            let body = match streamed {
+                true => match decode_aws_chunked(body.bytes()) {
+                    Some(body) => body,
+                    None => return error(StatusCode::BAD_REQUEST, "IncompleteBody", "The aws-chunked body is malformed", ""),
+                },
+                false => body.bytes().to_vec(),
+            };
+            let request = Request { bucket, key, method, query, headers, body };
+            let now_ms = lock(&clock).now_ms();
+            handle(&mut lock(&store), request, now_ms)
+        })
+}
+
+fn handle(store: &mut Store, request: Request, now_ms: u64) -> warp::reply::Response {
+    let bucket = match (&request.bucket, &request.method) {
+        (None, &Method::GET) => return list_buckets(store),
+        (None, _) => return error(StatusCode::METHOD_NOT_ALLOWED, "MethodNotAllowed", "The specified method is not allowed against this resource", "/"),
+        (Some(bucket), _) => bucket.clone(),
+    };
+    match (request.key.clone(), request.method.clone()) {
+        (None, Method::PUT) => create_bucket(store, &bucket, now_ms),
+        (None, Method::HEAD) if store.buckets.contains_key(&bucket) => empty(StatusCode::OK),
+        (None, Method::HEAD) => empty(StatusCode::NOT_FOUND),
+        (None, Method::DELETE) => delete_bucket(store, &bucket),
+        (None, Method::GET) if request.param("location").is_some() => match store.buckets.contains_key(&bucket) {
+            true => xml(StatusCode::OK, "<LocationConstraint xmlns=\"http://s3.amazonaws.com/doc/2006-03-01/\"/>".to_string()),
+            false => no_such_bucket(&bucket),
+        },
+        (None, Method::GET) => list_objects(store, &bucket, &request),
+        (None, Method::POST) if request.param("delete").is_some() => delete_objects(store, &bucket, &request.body),
+        (Some(key), Method::PUT) => put_object(store, &bucket, &key, request, now_ms),
+        (Some(key), Method::GET) => get_object(store, &bucket, &key, true),
+        (Some(key), Method::HEAD) => get_object(store, &bucket, &key, false),
+        (Some(key), Method::DELETE) => match store.buckets.contains_key(&bucket) {
+            true => {
+                store.remove(&bucket, &key);
+                empty(StatusCode::NO_CONTENT)
+            }
+            false => no_such_bucket(&bucket),
+        },
+        (_, _) => error(StatusCode::METHOD_NOT_ALLOWED, "MethodNotAllowed", "The specified method is not allowed against this resource", &format!("/{}", bucket)),
+    }
+}
+
+fn list_buckets(store: &Store) -> warp::reply::Response {
+    let mut buckets = String::new();
+    for (name, bucket) in &store.buckets {
+        let _ = write!(buckets, "<Bucket><Name>{}</Name><CreationDate>{}</CreationDate></Bucket>", name, har::iso8601(bucket.created_ms));
+    }
+    xml(StatusCode::OK, format!(
+        "<ListAllMyBucketsResult xmlns=\"http://s3.amazonaws.com/doc/2006-03-01/\"><Owner><ID>mock</ID><DisplayName>mock</DisplayName></Owner><Buckets>{}</Buckets></ListAllMyBucketsResult>",
+        buckets
+    ))
+}
+
+fn create_bucket(store: &mut Store, bucket: &str, now_ms: u64) -> warp::reply::Response {
+    if !valid_bucket(bucket) {
+        return error(StatusCode::BAD_REQUEST, "InvalidBucketName", "The specified bucket is not valid", &format!("/{}", bucket));
+    }
+    if store.buckets.contains_key(bucket) {
+        return error(StatusCode::CONFLICT, "BucketAlreadyOwnedByYou", "Your previous request to create the named bucket succeeded and you already own it", &format!("/{}", bucket));
+    }
+    store.buckets.insert(bucket.to_string(), Bucket { created_ms: now_ms, objects: BTreeMap::new() });
+    Response::builder()
+        .header("location", format!("/{}", bucket))
+        .body(hyper::Body::empty())
+        .expect("bucket names are valid header values")
+}
+
+fn delete_bucket(store: &mut Store, bucket: &str) -> warp::reply::Response {
+    match store.buckets.get(bucket) {
+        None => no_such_bucket(bucket),
+        Some(found) if !found.objects.is_empty() => {
+            error(StatusCode::CONFLICT, "BucketNotEmpty", "The bucket you tried to delete is not empty", &format!("/{}", bucket))
+        }
+        Some(_) => {
+            store.buckets.remove(bucket);
+            empty(StatusCode::NO_CONTENT)
+        }
+    }
+}
+
+/// `ListObjects`, or `ListObjectsV2` with `list-type=2`: the keys after the marker or
+/// continuation token starting with `prefix`, those with `delimiter` past the prefix
+/// rolled up into common prefixes
+fn list_objects(store: &Store, bucket: &str, request: &Request) -> warp::reply::Response {
+    let objects = match store.buckets.get(bucket) {
+        Some(found) => &found.objects,
+        None => return no_such_bucket(bucket),
+    };
+    let v2 = request.param("list-type") == Some("2");
+    let prefix = request.param("prefix").unwrap_or_default();
+    let delimiter = request.param("delimiter").filter(|delimiter| !delimiter.is_empty());
+    let max_keys = request.param("max-keys").and_then(|max| max.parse().ok()).unwrap_or(MAX_KEYS).min(MAX_KEYS);
+    let after = match v2 {
+        true => request.param("continuation-token").or(request.param("start-after")),
+        false => request.param("marker"),
+    }.unwrap_or_default();
+
+    let (mut contents, mut prefixes, mut last, mut truncated) = (String::new(), Vec::<String>::new(), None, false);
+    let mut listed = 0;
+    for (key, object) in objects.range::<str, _>((std::ops::Bound::Excluded(after), std::ops::Bound::Unbounded)) {
+        if !key.starts_with(prefix) {
+            continue;
+        }
+        let rolled_up = delimiter
+            .and_then(|delimiter| key[prefix.len()..].find(delimiter).map(|end| key[..prefix.len() + end + delimiter.len()].to_string()));
+        if let Some(common) = &rolled_up {
+            if prefixes.last() == Some(common) {
+                continue;
+            }
+        }
+        if listed == max_keys {
+            truncated = true;
+            break;
+        }
+        listed += 1;
+        match rolled_up {
+            Some(common) => {
+                // Continues past every key under the prefix
+                last = Some(format!("{}\u{10ffff}", common));
+                prefixes.push(common);
+            }
+            None => {
+                last = Some(key.clone());
+                let _ = write!(
+                    contents,
+                    "<Contents><Key>{}</Key><LastModified>{}</LastModified><ETag>{}</ETag><Size>{}</Size><StorageClass>STANDARD</StorageClass></Contents>",
+                    escape(key), har::iso8601(object.modified_ms), escape(&object.etag), object.size
+                );
+            }
+        }
+    }
+
+    let mut document = format!(
+        "<ListBucketResult xmlns=\"http://s3.amazonaws.com/doc/2006-03-01/\"><Name>{}</Name><Prefix>{}</Prefix><MaxKeys>{}</MaxKeys><IsTruncated>{}</IsTruncated>",
+        bucket, escape(prefix), max_keys, truncated
+    );
+    if let Some(delimiter) = delimiter {
+        let _ = write!(document, "<Delimiter>{}</Delimiter>", escape(delimiter));
+    }
+    match v2 {
+        true => {
+            let _ = write!(document, "<KeyCount>{}</KeyCount>", listed);
+            if let Some(token) = request.param("continuation-token") {
+                let _ = write!(document, "<ContinuationToken>{}</ContinuationToken>", escape(token));
+            }
+            if let Some(last) = last.as_ref().filter(|_| truncated) {
+                let _ = write!(document, "<NextContinuationToken>{}</NextContinuationToken>", escape(last));
+            }
+        }
+        false => {
+            let _ = write!(document, "<Marker>{}</Marker>", escape(after));
+            if let Some(last) = last.as_ref().filter(|_| truncated) {
+                let _ = write!(document, "<NextMarker>{}</NextMarker>", escape(last));
+            }
+        }
+    }
+    document.push_str(&contents);
+    for common in prefixes {
+        let _ = write!(document, "<CommonPrefixes><Prefix>{}</Prefix></CommonPrefixes>", escape(&common));
+    }
+    document.push_str("</ListBucketResult>");
+    xml(StatusCode::OK, document)
+}
+
+/// `DeleteObjects`: remove the objects of the `<Key>`s of `body`
+fn delete_objects(store: &mut Store, bucket: &str, body: &[u8]) -> warp::reply::Response {
+    if !store.buckets.contains_key(bucket) {
+        return no_such_bucket(bucket);
+    }
+    let body = String::from_utf8_lossy(body);
+    let quiet = body.contains("<Quiet>true</Quiet>");
+    let mut deleted = String::new();
+    for key in body.split("<Key>").skip(1).filter_map(|rest| rest.split_once("</Key>")).map(|(key, _)| unescape(key)) {
+        store.remove(bucket, &key);
+        if !quiet {
+            let _ = write!(deleted, "<Deleted><Key>{}</Key></Deleted>", escape(&key));
+        }
+    }
+    xml(StatusCode::OK, format!("<DeleteResult xmlns=\"http://s3.amazonaws.com/doc/2006-03-01/\">{}</DeleteResult>", deleted))
+}
+
+/// `PutObject`, or `CopyObject` with `x-amz-copy-source`
+fn put_object(store: &mut Store, bucket: &str, key: &str, request: Request, now_ms: u64) -> warp::reply::Response {
+    if !store.buckets.contains_key(bucket) {
+        return no_such_bucket(bucket);
+    }
+    let header = |name: &str| request.headers.get(name).and_then(|value| value.to_str().ok()).map(str::to_string);
+    let mut metadata: Vec<(String, String)> = request.headers.iter()
+        .filter(|(name, _)| name.as_str().starts_with("x-amz-meta-"))
+        .filter_map(|(name, value)| Some((name.as_str().to_string(), value.to_str().ok()?.to_string())))
+        .collect();
+    let mut content_type = header("content-type").unwrap_or_else(|| "binary/octet-stream".to_string());
+
+    let copied = header("x-amz-copy-source");
+    let bytes = match &copied {
+        Some(source) => {
+            let source = percent_decode(source.trim_start_matches('/'));
+            let found = source.split_once('/')
+                .and_then(|(bucket, key)| store.buckets.get(bucket)?.objects.get(key));
+            let object = match found {
+                Some(object) => object,
+                None => return error(StatusCode::NOT_FOUND, "NoSuchKey", "The specified key does not exist.", &format!("/{}", source)),
+            };
+            // Unless asked to replace them, as S3 copies
+            if header("x-amz-metadata-directive").as_deref() != Some("REPLACE") {
+                metadata = object.metadata.clone();
+                content_type = object.content_type.clone();
+            }
+            match store.read(object) {
+                Ok(bytes) => bytes,
+                Err(e) => return error(StatusCode::INTERNAL_SERVER_ERROR, "InternalError", &e.to_string(), &format!("/{}", source)),
+            }
+        }
+        None => Arc::new(request.body),
+    };
+
+    let etag = format!("\"{}\"", md5(&bytes).iter().map(|byte| format!("{:02x}", byte)).collect::<String>());
+    let size = bytes.len();
+    let data = match store.write(bytes) {
+        Ok(data) => data,
+        Err(e) => return error(StatusCode::INTERNAL_SERVER_ERROR, "InternalError", &e.to_string(), &format!("/{}/{}", bucket, key)),
+    };
+    store.remove(bucket, key);
+    let object = Object { data, size, etag: etag.clone(), modified_ms: now_ms, content_type, metadata };
+    if let Some(found) = store.buckets.get_mut(bucket) {
+        found.objects.insert(key.to_string(), object);
+    }
+
+    match copied {
+        Some(_) => xml(StatusCode::OK, format!(
+            "<CopyObjectResult><ETag>{}</ETag><LastModified>{}</LastModified></CopyObjectResult>",
+            escape(&etag), har::iso8601(now_ms)
+        )),
+        None => Response::builder().header("etag", etag).body(hyper::Body::empty()).expect("ETags are valid header values"),
+    }
+}
+
+/// `GetObject`, or `HeadObject` without the body
+fn get_object(store: &Store, bucket: &str, key: &str, with_body: bool) -> warp::reply::Response {
+    let object = match store.buckets.get(bucket).map(|found| found.objects.get(key)) {
+        Some(Some(object)) => object,
+        Some(None) if with_body => return error(StatusCode::NOT_FOUND, "NoSuchKey", "The specified key does not exist.", &format!("/{}/{}", bucket, key)),
+        None if with_body => return no_such_bucket(bucket),
+        _ => return empty(StatusCode::NOT_FOUND),
+    };
+    let mut response = Response::builder();
+    response
+        .header("content-type", object.content_type.as_str())
+        .header("etag", object.etag.as_str())
+        .header("last-modified", clock::http_date(object.modified_ms))
+        .header("content-length", object.size);
+    for (name, value) in &object.metadata {
+        response.header(name.as_str(), value.as_str());
+    }
+    let body = match with_body {
+        true => match store.read(object) {
+            Ok(bytes) => hyper::Body::from(bytes.to_vec()),
+            Err(e) => return error(StatusCode::INTERNAL_SERVER_ERROR, "InternalError", &e.to_string(), &format!("/{}/{}", bucket, key)),
+        },
+        false => hyper::Body::empty(),
+    };
+    response.body(body).unwrap_or_else(|_| empty(StatusCode::INTERNAL_SERVER_ERROR))
+}
@@ -0,0 +1,310 @@
+use serde_json::Value;
+use warp::{Buf, Filter, Reply};
+
+use std::io;
+use std::sync::Arc;
+
+use crate::schema::{self, Schema, Violation};
+use crate::stubs::Hits;
+use crate::synth::Synthesizer;
+
+/// Methods an OpenAPI path item can have operations for, in the order they're listed
+const METHODS: &[&str] = &["get", "put", "post", "delete", "options", "head", "patch", "trace"];
+
+/// How deep `$ref`s are followed into each other, past which a schema takes anything, as
+/// the schemas of recursive types refer to themselves
+const MAX_REF_DEPTH: usize = 16;
+
+fn invalid(error: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, error)
+}
+
+/// An operation of an OpenAPI document, by the requests it takes
+#[derive(Debug)]
+pub struct Endpoint {
+    /// Upper case, like `GET`
+    pub method: String,
+    /// The path template, with the path of the document's first server in front, like
+    /// `/v1/pets/{id}`
+    pub path: String,
+}
+
+impl Endpoint {
+    /// Segments of the path, `None` for the templated ones
+    fn segments(&self) -> Vec<Option<&str>> {
+        self.path.split('/')
+            .filter(|segment| !segment.is_empty())
+            .map(|segment| Some(segment).filter(|segment| !segment.contains('{')))
+            .collect()
+    }
+
+    fn matches(&self, method: &str, path: &str) -> bool {
+        let path = path.split_once('?').map_or(path, |(path, _)| path);
+        let actual: Vec<&str> = path.split('/').filter(|segment| !segment.is_empty()).collect();
+        let expected = self.segments();
+        self.method.eq_ignore_ascii_case(method)
+            && actual.len() == expected.len()
+            && expected.iter().zip(&actual).all(|(expected, actual)| expected.is_none_or(|expected| expected == *actual))
+    }
+}
+
+/// The path of the first of the `servers` of `document`, like `/v1` of
+/// `https://api.test/v1`, empty if there's none
+fn base_path(document: &Value) -> String {
+    let url = document.pointer("/servers/0/url").and_then(Value::as_str).unwrap_or("");
+    let path = match url.find("://") {
+        Some(scheme) => url[scheme + 3..].find('/').map_or("", |path| &url[scheme + 3 + path..]),
+        None => url,
+    };
+    path.trim_end_matches('/').to_string()
+}
+
+/// The operations of `document`, by path and then method
+fn operations_of(document: &Value) -> Vec<(Endpoint, &Value, &Value)> {
+    let base = base_path(document);
+    let paths = match document.get("paths").and_then(Value::as_object) {
+        Some(paths) => paths,
+        None => return Vec::new(),
+    };
+    let base = &base;
+    paths.iter()
+        .flat_map(|(path, item)| METHODS.iter().filter_map(move |method| {
+            let operation = item.get(*method)?;
+            Some((Endpoint { method: method.to_ascii_uppercase(), path: format!("{}{}", base, path) }, item, operation))
+        }))
+        .collect()
+}
+
+/// The operations of the OpenAPI document `document`, by path and then method, as they're
+/// told by in `GET /{port}/stubs`
+pub fn operations(document: &Value) -> Vec<Endpoint> {
+    operations_of(document).into_iter().map(|(endpoint, _, _)| endpoint).collect()
+}
+
+/// Which of `endpoints` takes a request with `method` for `path`, if one does. Of those
+/// matching, the one with the fewest templated segments takes it, as OpenAPI has it.
+pub fn find(endpoints: &[Endpoint], method: &str, path: &str) -> Option<usize> {
+    endpoints.iter().enumerate()
+        .filter(|(_, endpoint)| endpoint.matches(method, path))
+        .min_by_key(|(index, endpoint)| (endpoint.segments().iter().filter(|segment| segment.is_none()).count(), *index))
+        .map(|(index, _)| index)
+}
+
+/// `value` with the `$ref`s to other parts of `document` it has replaced by what they
+/// refer to
+fn resolve(document: &Value, value: &Value, depth: usize) -> Value {
+    match value {
+        Value::Object(object) => match object.get("$ref").and_then(Value::as_str) {
+            Some(_) if depth >= MAX_REF_DEPTH => Value::Object(Default::default()),
+            Some(reference) => {
+                let target = reference.strip_prefix('#').and_then(|pointer| document.pointer(pointer));
+                target.map_or(Value::Object(Default::default()), |target| resolve(document, target, depth + 1))
+            }
+            None => Value::Object(object.iter().map(|(key, value)| (key.clone(), resolve(document, value, depth))).collect()),
+        },
+        Value::Array(values) => Value::Array(values.iter().map(|value| resolve(document, value, depth)).collect()),
+        value => value.clone(),
+    }
+}
+
+/// The JSON `content` of a request body or response, if it has one
+fn json_content(content: &Value) -> Option<&Value> {
+    let content = content.as_object()?;
+    content.get("application/json")
+        .or_else(|| content.iter().find(|(media_type, _)| media_type.ends_with("+json")).map(|(_, content)| content))
+}
+
+/// A parameter a request must have
+#[derive(Debug)]
+struct Required {
+    /// `query` or `header`
+    location: &'static str,
+    name: String,
+}
+
+/// The body an operation takes
+struct RequestBody {
+    required: bool,
+    schema: Option<Schema>,
+}
+
+/// What a request for an operation must have, and what it's answered with
+struct Operation {
+    parameters: Vec<Required>,
+    body: Option<RequestBody>,
+    status: warp::http::StatusCode,
+    example: Option<Value>,
+}
+
+impl Operation {
+    fn new(
+        document: &Value,
+        endpoint: &Endpoint,
+        item: &Value,
+        operation: &Value,
+        draft4: bool,
+        mut synthesizer: Synthesizer
+    ) -> io::Result<Operation> {
+        let operation = resolve(document, operation, 0);
+        let item = resolve(document, item, 0);
+        let parameters = item.get("parameters").and_then(Value::as_array).into_iter().flatten()
+            .chain(operation.get("parameters").and_then(Value::as_array).into_iter().flatten())
+            .filter(|parameter| parameter.get("required").and_then(Value::as_bool).unwrap_or(false))
+            .filter_map(|parameter| {
+                let location = match parameter.get("in").and_then(Value::as_str)? {
+                    "query" => "query",
+                    "header" => "header",
+                    _ => return None,
+                };
+                Some(Required { location, name: parameter.get("name")?.as_str()?.to_string() })
+            })
+            .collect();
+
+        let body = match operation.get("requestBody") {
+            Some(body) => {
+                let schema = body.get("content").and_then(json_content).and_then(|content| content.get("schema"));
+                let schema = match schema {
+                    Some(schema) if draft4 => Some(Schema::compile_draft4(schema)),
+                    Some(schema) => Some(Schema::compile(schema)),
+                    None => None,
+                };
+                let schema = schema.transpose()
+                    .map_err(|e| invalid(format!("the request body of {} {}: {}", endpoint.method, endpoint.path, e)))?;
+                Some(RequestBody { required: body.get("required").and_then(Value::as_bool).unwrap_or(false), schema })
+            }
+            None => None,
+        };
+
+        // The first success it answers with, or else the default
+        let responses = operation.get("responses").and_then(Value::as_object);
+        let (code, response) = responses.into_iter().flatten()
+            .filter(|(code, _)| code.starts_with('2'))
+            .min_by_key(|(code, _)| code.as_str())
+            .or_else(|| responses?.get_key_value("default"))
+            .ok_or_else(|| invalid(format!("{} {} has no successful or default response", endpoint.method, endpoint.path)))?;
+        let status = match code.as_str() {
+            "default" => warp::http::StatusCode::OK,
+            code => code.replace('X', "0").parse().ok()
+                .and_then(|code| warp::http::StatusCode::from_u16(code).ok())
+                .ok_or_else(|| invalid(format!("invalid response status {:?} of {} {}", code, endpoint.method, endpoint.path)))?,
+        };
+        let example = response.get("content").and_then(json_content).and_then(|content| {
+            content.get("example").cloned()
+                .or_else(|| content.get("examples")?.as_object()?.values().next()?.get("value").cloned())
+                .or_else(|| content.get("schema").map(|schema| synthesizer.value(schema)))
+        });
+        Ok(Operation { parameters, body, status, example })
+    }
+
+    /// Where a request with `query` and `headers` and `body` departs from the operation
+    fn violations(&self, query: &str, headers: &warp::http::HeaderMap, body: &[u8]) -> Vec<Violation> {
+        let names: Vec<&str> = query.split('&')
+            .map(|pair| pair.split_once('=').map_or(pair, |(name, _)| name))
+            .collect();
+        let mut violations: Vec<Violation> = self.parameters.iter()
+            .filter(|parameter| match parameter.location {
+                "query" => !names.contains(&parameter.name.as_str()),
+                _ => !headers.contains_key(parameter.name.as_str()),
+            })
+            .map(|parameter| Violation {
+                location: Some(parameter.location),
+                path: parameter.name.clone(),
+                message: format!("the {} parameter is required", parameter.location),
+            })
+            .collect();
+        match &self.body {
+            Some(RequestBody { required: true, .. }) if body.is_empty() => {
+                violations.push(Violation { location: Some("body"), path: String::new(), message: "a body is required".to_string() });
+            }
+            Some(RequestBody { schema: Some(schema), .. }) if !body.is_empty() => {
+                violations.extend(schema.violations(body).into_iter().map(|violation| Violation { location: Some("body"), ..violation }));
+            }
+            _ => {}
+        }
+        violations
+    }
+
+    fn respond(&self) -> warp::reply::Response {
+        let mut reply = match &self.example {
+            Some(example) => warp::reply::json(example).into_response(),
+            None => warp::reply::Response::default(),
+        };
+        *reply.status_mut() = self.status;
+        reply
+    }
+}
+
+/// The operations of an OpenAPI 3 document an HTTP server answers as a contract testing
+/// server would. A request an operation takes is answered with the example of its first
+/// successful response, or one made up from the response's schema when the server starts,
+/// see `synth::Synthesizer`, if it has the parameters the operation requires and its body
+/// conforms to the operation's schema. Other requests it takes are answered with 422 and
+/// what they violate, flagged in the journal. Checking bodies needs the `schema` feature;
+/// parameters are checked to be there, but not what they are.
+pub struct Contract {
+    endpoints: Vec<Endpoint>,
+    operations: Vec<Operation>,
+}
+
+impl Contract {
+    /// The contract of `document`, making up examples from `seed`
+    pub fn new(document: &Value, seed: u64) -> io::Result<Contract> {
+        let version = document.get("openapi").and_then(Value::as_str)
+            .ok_or_else(|| invalid("an OpenAPI document names its `openapi` version".to_string()))?;
+        if !version.starts_with("3.") {
+            return Err(invalid(format!("OpenAPI {} isn't supported, only 3.0 and 3.1 are", version)));
+        }
+        if !document.get("paths").is_some_and(Value::is_object) {
+            return Err(invalid("an OpenAPI document has `paths`".to_string()));
+        }
+        let draft4 = version.starts_with("3.0");
+        let (endpoints, operations) = operations_of(document).into_iter()
+            .enumerate()
+            .map(|(index, (endpoint, item, operation))| {
+                let synthesizer = Synthesizer::new(seed, &format!("operation:{}", index));
+                let operation = Operation::new(document, &endpoint, item, operation, draft4, synthesizer)?;
+                Ok((endpoint, operation))
+            })
+            .collect::<io::Result<Vec<_>>>()?
+            .into_iter()
+            .unzip();
+        Ok(Contract { endpoints, operations })
+    }
+}
+
+/// Answer the requests an operation of the OpenAPI document `document` takes, if there's
+/// one, counting them in `hits`, see `Contract`. Other requests are rejected to be handled
+/// as usual.
+pub fn contract(
+    document: Option<&Value>,
+    seed: u64,
+    hits: Arc<Hits>
+) -> io::Result<impl Filter<Extract = (warp::reply::Response,), Error = warp::Rejection> + Clone> {
+    let contract = document.map(|document| Contract::new(document, seed)).transpose()?.map(Arc::new);
+
+    // Finding the operation first leaves the body to the usual routes if there's none
+    let taken = warp::method()
+        .and(warp::path::full())
+        .and_then(move |method: warp::http::Method, path: warp::path::FullPath| {
+            contract.as_ref()
+                .and_then(|contract| Some((contract.clone(), find(&contract.endpoints, method.as_str(), path.as_str())?)))
+                .ok_or_else(warp::reject::not_found)
+        });
+    let query = warp::query::raw().or(warp::any().map(String::new)).unify();
+
+    Ok(taken
+        .and(query)
+        .and(warp::header::headers_cloned())
+        .and(warp::body::concat())
+        .map(move |(contract, index): (Arc<Contract>, usize), query: String, headers, body: warp::body::FullBody| {
+            hits.operation(index);
+            let operation = &contract.operations[index];
+            let violations = operation.violations(&query, &headers, body.bytes());
+            if violations.is_empty() {
+                operation.respond()
+            } else {
+                let endpoint = &contract.endpoints[index];
+                schema::reply("openapi", &format!("the request doesn't conform to {} {} of the OpenAPI document", endpoint.method, endpoint.path), violations)
+            }
+        }))
+}
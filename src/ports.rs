@@ -0,0 +1,82 @@
+use std::io;
+use std::ops::RangeInclusive;
+
+/// Picks the port of a server created without one. Ports for which `taken` is true are
+/// registered to another server already.
+pub trait PortAllocator: Send {
+    fn allocate(&mut self, taken: &dyn Fn(u16) -> bool) -> io::Result<u16>;
+}
+
+/// Whatever free port the OS picks
+#[derive(Clone, Debug, Default)]
+pub struct EphemeralPorts;
+
+impl PortAllocator for EphemeralPorts {
+    fn allocate(&mut self, taken: &dyn Fn(u16) -> bool) -> io::Result<u16> {
+        loop {
+            let port = std::net::TcpListener::bind(("127.0.0.1", 0))?.local_addr()?.port();
+            if !taken(port) {
+                return Ok(port);
+            }
+        }
+    }
+}
+
+/// The lowest port from `base` up that isn't taken, so runs allocate the same ports
+#[derive(Clone, Debug)]
+pub struct SequentialPorts {
+    pub base: u16,
+}
+
+impl PortAllocator for SequentialPorts {
+    fn allocate(&mut self, taken: &dyn Fn(u16) -> bool) -> io::Result<u16> {
+        (self.base..=u16::MAX)
+            .find(|port| !taken(*port))
+            .ok_or_else(|| exhausted(format!("every port from {} up is taken", self.base)))
+    }
+}
+
+/// The lowest port of a fixed range that isn't taken
+#[derive(Clone, Debug)]
+pub struct PortPool {
+    pub ports: RangeInclusive<u16>,
+}
+
+impl PortAllocator for PortPool {
+    fn allocate(&mut self, taken: &dyn Fn(u16) -> bool) -> io::Result<u16> {
+        self.ports.clone()
+            .find(|port| !taken(*port))
+            .ok_or_else(|| exhausted(format!("every port in {:?} is taken", self.ports)))
+    }
+}
+
+fn exhausted(error: String) -> io::Error {
+    io::Error::new(io::ErrorKind::AddrNotAvailable, error)
+}
+
+impl Default for Box<dyn PortAllocator> {
+    fn default() -> Self {
+        Box::new(EphemeralPorts)
+    }
+}
+
+/// Parse an allocator given as `ephemeral`, `sequential:{base}` or `pool:{first}-{last}`
+pub fn parse_allocator(spec: &str) -> Result<Box<dyn PortAllocator>, String> {
+    let invalid = || format!("invalid port allocator {:?}", spec);
+    let (kind, arg) = spec.split_once(':').unwrap_or((spec, ""));
+
+    match kind {
+        "ephemeral" if arg.is_empty() => Ok(Box::new(EphemeralPorts)),
+        "sequential" => {
+            let base = arg.parse().map_err(|_| invalid())?;
+            Ok(Box::new(SequentialPorts { base }))
+        }
+        "pool" => {
+            let (first, last) = arg.split_once('-').ok_or_else(invalid)?;
+            let first = first.parse().map_err(|_| invalid())?;
+            let last = last.parse().map_err(|_| invalid())?;
+            Ok(Box::new(PortPool { ports: first..=last }))
+        }
+        _ => Err(invalid()),
+    }
+}
@@ -0,0 +1,224 @@
+use futures::Future;
+use warp::{Filter, Reply};
+use warp::http::StatusCode;
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::{error_reply, Database};
+use crate::chaos::Rng;
+use crate::error::{lock, Error};
+use crate::seed::{self, Reseeded, SharedSeed};
+
+/// The delay, jitter and spike length of a profile at most, an hour each, well within
+/// what the timer can hold a request back for
+const MAX_DELAY_MS: u64 = 60 * 60 * 1000;
+
+/// The named profiles, each with the network and upstream it stands for, and the
+/// profile it fills in: delay, jitter, spike rate and length, error rate and statuses
+const PRESETS: &[(&str, Preset)] = &[
+    // A wired local network
+    ("lan", Preset { delay_ms: 1, jitter_ms: 1, spike_rate: 0.0, spike_ms: 0, error_rate: 0.0, error_statuses: &[] }),
+    // A mobile connection, slow and now and then stalling
+    ("3g", Preset { delay_ms: 200, jitter_ms: 100, spike_rate: 0.05, spike_ms: 1500, error_rate: 0.01, error_statuses: &[503] }),
+    // A geostationary link, slow but steady
+    ("satellite", Preset { delay_ms: 600, jitter_ms: 50, spike_rate: 0.02, spike_ms: 2000, error_rate: 0.005, error_statuses: &[503] }),
+    // A fast network in front of an upstream that often times out or fails
+    ("flaky-upstream", Preset { delay_ms: 80, jitter_ms: 60, spike_rate: 0.1, spike_ms: 3000, error_rate: 0.15, error_statuses: &[502, 503, 504] }),
+];
+
+struct Preset {
+    delay_ms: u64,
+    jitter_ms: u64,
+    spike_rate: f64,
+    spike_ms: u64,
+    error_rate: f64,
+    error_statuses: &'static [u16],
+}
+
+/// JSON body of `PUT /{port}/profile`: how an in-process HTTP server holds back and fails
+/// the requests it answers, as a network or upstream would. `preset` names one of
+/// `lan`, `3g`, `satellite` or `flaky-upstream` to start from, and the other fields
+/// override what it sets, or leave out what isn't set.
+#[derive(Clone, Debug, Default, PartialEq, serde_derive::Deserialize, serde_derive::Serialize)]
+pub struct Profile {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub preset: Option<String>,
+    /// How long every request is held back, give or take `jitter_ms`, each up to an hour
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub delay_ms: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub jitter_ms: Option<u64>,
+    /// The share of requests held back by `spike_ms` more, up to an hour
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub spike_rate: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub spike_ms: Option<u64>,
+    /// The share of requests failed with one of `error_statuses`, picked at random, by
+    /// default 503
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error_rate: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error_statuses: Option<Vec<u16>>,
+    /// Seed of the random choices, to repeat a run. By default the server's seed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seed: Option<u64>,
+}
+
+impl Profile {
+    /// The profile with what its preset sets filled in, if it's valid
+    fn resolve(self) -> Result<Profile, String> {
+        let preset = match &self.preset {
+            Some(name) => match PRESETS.iter().find(|(preset, _)| preset == name) {
+                Some((_, preset)) => Some(preset),
+                None => {
+                    let names: Vec<&str> = PRESETS.iter().map(|(name, _)| *name).collect();
+                    return Err(format!("no profile preset {:?}, there are {}", name, names.join(", ")));
+                }
+            },
+            None => None,
+        };
+        let profile = Profile {
+            delay_ms: self.delay_ms.or(preset.map(|preset| preset.delay_ms)),
+            jitter_ms: self.jitter_ms.or(preset.map(|preset| preset.jitter_ms)),
+            spike_rate: self.spike_rate.or(preset.map(|preset| preset.spike_rate)),
+            spike_ms: self.spike_ms.or(preset.map(|preset| preset.spike_ms)),
+            error_rate: self.error_rate.or(preset.map(|preset| preset.error_rate)),
+            error_statuses: self.error_statuses.or(preset.map(|preset| preset.error_statuses.to_vec())),
+            ..self
+        };
+
+        let delays = [profile.delay_ms, profile.jitter_ms, profile.spike_ms];
+        if delays.iter().flatten().any(|ms| *ms > MAX_DELAY_MS) {
+            return Err(format!("delay_ms, jitter_ms and spike_ms must be at most {} each", MAX_DELAY_MS));
+        }
+        let rates = [profile.spike_rate, profile.error_rate];
+        if rates.iter().flatten().any(|rate| !(0.0..=1.0).contains(rate)) {
+            return Err("spike_rate and error_rate must be within 0 and 1".to_string());
+        }
+        match &profile.error_statuses {
+            Some(statuses) if statuses.is_empty() && profile.error_rate.unwrap_or(0.0) > 0.0 => {
+                return Err("a profile with an error rate needs error statuses".to_string());
+            }
+            Some(statuses) => {
+                if let Some(status) = statuses.iter().find(|status| !(400..600).contains(*status)) {
+                    return Err(format!("profile error status {} isn't an error", status));
+                }
+            }
+            None => {}
+        }
+        Ok(profile)
+    }
+}
+
+/// A profile applied to a server, with what it picks at random from
+pub struct Shaper {
+    profile: Profile,
+    rng: Reseeded<Rng>,
+}
+
+impl Shaper {
+    /// How long to hold the next request back, and the status to fail it with, if any
+    fn sample(&mut self) -> (Duration, Option<StatusCode>) {
+        let (profile, rng) = (&self.profile, self.rng.get());
+        let (delay, jitter) = (profile.delay_ms.unwrap_or(0), profile.jitter_ms.unwrap_or(0));
+        let spread = jitter.saturating_mul(2).saturating_add(1);
+        let mut ms = delay.saturating_sub(jitter).saturating_add((rng.fraction() * spread as f64) as u64);
+        if rng.fraction() < profile.spike_rate.unwrap_or(0.0) {
+            ms = ms.saturating_add(profile.spike_ms.unwrap_or(0));
+        }
+        let status = match &profile.error_statuses {
+            Some(statuses) if !statuses.is_empty() && rng.fraction() < profile.error_rate.unwrap_or(0.0) => {
+                let status = statuses[(rng.next() % statuses.len() as u64) as usize];
+                Some(StatusCode::from_u16(status).unwrap_or(StatusCode::SERVICE_UNAVAILABLE))
+            }
+            _ => None,
+        };
+        (Duration::from_millis(ms), status)
+    }
+}
+
+/// The profile applied to an in-process HTTP server, if any, kept across restarts
+pub type SharedProfile = Arc<Mutex<Option<Shaper>>>;
+
+/// Hold back and fail requests as the profile applied, if any, has it, failing them with
+/// `Error::Profiled`
+pub fn shape(profile: SharedProfile) -> impl Filter<Extract = (), Error = warp::Rejection> + Clone {
+    warp::any()
+        .and_then(move || {
+            let sampled = lock(&profile).as_mut().map(|shaper| (shaper.profile.preset.clone(), shaper.sample()));
+            let (preset, delay, status) = match sampled {
+                Some((preset, (delay, status))) => (preset, delay, status),
+                None => return futures::future::Either::A(futures::future::ok(())),
+            };
+            futures::future::Either::B(tokio::timer::Delay::new(Instant::now() + delay)
+                .then(move |delayed| {
+                    if let Err(e) = delayed {
+                        eprintln!("profile timer error: {}", e);
+                    }
+                    match status {
+                        Some(status) => Err(warp::reject::custom(Error::Profiled { preset, status })),
+                        None => Ok(()),
+                    }
+                }))
+        })
+        .untuple_one()
+}
+
+/// The profile of the server on `port`, or the status and error to answer with if it has none
+fn find_profile(database: &Database, port: u16) -> Result<SharedProfile, (StatusCode, String)> {
+    let registry = lock(database);
+    let server = registry.servers.get(&port)
+        .ok_or_else(|| (StatusCode::NOT_FOUND, format!("no server on port {}", port)))?;
+    server.profile.clone()
+        .ok_or_else(|| (StatusCode::CONFLICT, "only in-process HTTP servers take a profile".to_string()))
+}
+
+fn no_profile(port: u16) -> warp::reply::Response {
+    error_reply(StatusCode::NOT_FOUND, &format!("server {} has no profile", port))
+}
+
+/// `GET /{port}/profile`: the profile applied to the server on `port`, with what its
+/// preset sets filled in
+pub fn get_profile(database: Database, port: u16) -> warp::reply::Response {
+    match find_profile(&database, port) {
+        Ok(shared) => match &*lock(&shared) {
+            Some(shaper) => warp::reply::json(&shaper.profile).into_response(),
+            None => no_profile(port),
+        },
+        Err((status, error)) => error_reply(status, &error),
+    }
+}
+
+/// `PUT /{port}/profile`: apply `profile` to the server on `port`, in place of any other
+pub fn put_profile(database: Database, port: u16, profile: Profile) -> warp::reply::Response {
+    let shared = match find_profile(&database, port) {
+        Ok(shared) => shared,
+        Err((status, error)) => return error_reply(status, &error),
+    };
+    let profile = match profile.resolve() {
+        Ok(profile) => profile,
+        Err(error) => return error_reply(StatusCode::UNPROCESSABLE_ENTITY, &error),
+    };
+    let seed = match profile.seed {
+        Some(seed) => SharedSeed::new(seed),
+        None => match seed::find_seed(&database, port) {
+            Ok(seed) => seed,
+            Err((status, error)) => return error_reply(status, &error),
+        },
+    };
+    let reply = warp::reply::json(&profile).into_response();
+    *lock(&shared) = Some(Shaper { profile, rng: Reseeded::new(seed, Rng::new) });
+    reply
+}
+
+/// `DELETE /{port}/profile`: answer the requests of the server on `port` as they come again
+pub fn delete_profile(database: Database, port: u16) -> warp::reply::Response {
+    match find_profile(&database, port) {
+        Ok(shared) => match lock(&shared).take() {
+            Some(_) => StatusCode::NO_CONTENT.into_response(),
+            None => no_profile(port),
+        },
+        Err((status, error)) => error_reply(status, &error),
+    }
+}
@@ -0,0 +1,249 @@
+use futures::{Async, Future, Poll};
+use tokio::io::AsyncRead;
+
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+/// The signature a PROXY protocol v2 header starts with
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+/// The longest a v1 header is, its `\r\n` included
+const V1_MAX: usize = 107;
+
+fn invalid(error: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("invalid PROXY header: {}", error))
+}
+
+/// Read the PROXY protocol header, v1 or v2, a load balancer like haproxy starts each
+/// connection it passes on with, answering with the address of the client it came from
+/// and the connection to read the rest of from. A header telling no client, like v1's
+/// `UNKNOWN` or v2's `LOCAL` health checks send, leaves `peer`, the address the connection
+/// itself comes from. The header is read a few bytes at a time, so that none after it are.
+pub fn accept<T: AsyncRead>(stream: T, peer: Option<SocketAddr>) -> Accept<T> {
+    Accept { stream: Some(stream), peer, header: Vec::new() }
+}
+
+pub struct Accept<T> {
+    stream: Option<T>,
+    peer: Option<SocketAddr>,
+    header: Vec<u8>,
+}
+
+impl<T: AsyncRead> Future for Accept<T> {
+    type Item = (Option<SocketAddr>, T);
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, io::Error> {
+        loop {
+            let needed = match needed(&self.header)? {
+                0 => break,
+                needed => needed,
+            };
+            let stream = self.stream.as_mut().ok_or_else(|| invalid("polled after it was read"))?;
+            let read = self.header.len();
+            self.header.resize(read + needed, 0);
+            let poll = stream.poll_read(&mut self.header[read..]);
+            let count = match poll {
+                Ok(Async::Ready(count)) => count,
+                Ok(Async::NotReady) => 0,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => 0,
+                Err(e) => return Err(e),
+            };
+            self.header.truncate(read + count);
+            match poll {
+                Ok(Async::Ready(0)) => return Err(invalid("the connection closed before it was complete")),
+                Ok(Async::Ready(_)) => {}
+                _ => return Ok(Async::NotReady),
+            }
+        }
+        let client = parse(&self.header)?.or(self.peer);
+        let stream = self.stream.take().ok_or_else(|| invalid("polled after it was read"))?;
+        Ok(Async::Ready((client, stream)))
+    }
+}
+
+/// How many more bytes of `header` to read, 0 once it's complete
+fn needed(header: &[u8]) -> io::Result<usize> {
+    if header.len() < 5 {
+        return Ok(5 - header.len());
+    }
+    if header.starts_with(b"PROXY") {
+        return match header.ends_with(b"\r\n") {
+            true => Ok(0),
+            false if header.len() >= V1_MAX => Err(invalid("a v1 header is longer than 107 bytes")),
+            // The line's end is found a byte at a time
+            false => Ok(1),
+        };
+    }
+    if !V2_SIGNATURE.starts_with(&header[..header.len().min(V2_SIGNATURE.len())]) {
+        return Err(invalid("it starts with neither PROXY nor the v2 signature"));
+    }
+    if header.len() < 16 {
+        return Ok(16 - header.len());
+    }
+    let length = 16 + usize::from(u16::from_be_bytes([header[14], header[15]]));
+    Ok(length - header.len())
+}
+
+/// The client a complete `header` tells, if it tells one
+fn parse(header: &[u8]) -> io::Result<Option<SocketAddr>> {
+    if header.starts_with(b"PROXY") {
+        parse_v1(header)
+    } else {
+        parse_v2(header)
+    }
+}
+
+/// Like `PROXY TCP4 10.1.2.3 10.0.0.1 5000 80\r\n`
+fn parse_v1(header: &[u8]) -> io::Result<Option<SocketAddr>> {
+    let line = std::str::from_utf8(&header[..header.len() - 2]).map_err(|_| invalid("a v1 header isn't text"))?;
+    let words: Vec<&str> = line.split(' ').collect();
+    match words[..] {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", family @ ("TCP4" | "TCP6"), source, _, source_port, _] => {
+            let address: IpAddr = source.parse().map_err(|_| invalid("invalid source address"))?;
+            if address.is_ipv4() != (family == "TCP4") {
+                return Err(invalid("the source address isn't of its family"));
+            }
+            let port: u16 = source_port.parse().map_err(|_| invalid("invalid source port"))?;
+            Ok(Some(SocketAddr::new(address, port)))
+        }
+        _ => Err(invalid("expected PROXY, a family, two addresses and two ports")),
+    }
+}
+
+fn parse_v2(header: &[u8]) -> io::Result<Option<SocketAddr>> {
+    let (version, command, family) = (header[12] >> 4, header[12] & 0x0f, header[13] >> 4);
+    if version != 2 {
+        return Err(invalid("unknown version"));
+    }
+    let addresses = &header[16..];
+    match (command, family) {
+        // LOCAL: sent by the load balancer itself, not on a client's behalf
+        (0, _) => Ok(None),
+        (1, 1) if addresses.len() >= 12 => {
+            let ip = Ipv4Addr::new(addresses[0], addresses[1], addresses[2], addresses[3]);
+            Ok(Some(SocketAddr::new(ip.into(), u16::from_be_bytes([addresses[8], addresses[9]]))))
+        }
+        (1, 2) if addresses.len() >= 36 => {
+            let mut ip = [0; 16];
+            ip.copy_from_slice(&addresses[..16]);
+            Ok(Some(SocketAddr::new(Ipv6Addr::from(ip).into(), u16::from_be_bytes([addresses[32], addresses[33]]))))
+        }
+        // UNSPEC, or a Unix socket, tells no address of the client
+        (1, 0) | (1, 3) => Ok(None),
+        (1, _) => Err(invalid("its addresses are cut short")),
+        _ => Err(invalid("unknown command")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PEER: &str = "10.0.0.1:40000";
+
+    /// The client `bytes` start by telling, and what's left after the header
+    fn accepted(bytes: &[u8]) -> io::Result<(Option<SocketAddr>, &[u8])> {
+        accept(bytes, Some(PEER.parse().unwrap())).wait()
+    }
+
+    /// A v2 header of `command` and address `family`, over TCP, with `addresses` after it
+    fn v2(command: u8, family: u8, addresses: &[u8]) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend_from_slice(&[0x20 | command, family << 4 | 1]);
+        header.extend_from_slice(&(addresses.len() as u16).to_be_bytes());
+        header.extend_from_slice(addresses);
+        header
+    }
+
+    #[test]
+    fn reads_a_v1_header_and_nothing_after_it() {
+        let (client, rest) = accepted(b"PROXY TCP4 192.0.2.7 10.0.0.1 5000 80\r\nGET / HTTP/1.1\r\n").unwrap();
+        assert_eq!(client, Some("192.0.2.7:5000".parse().unwrap()));
+        assert_eq!(rest, b"GET / HTTP/1.1\r\n");
+
+        let (client, _) = accepted(b"PROXY TCP6 2001:db8::7 2001:db8::1 5000 443\r\n").unwrap();
+        assert_eq!(client, Some("[2001:db8::7]:5000".parse().unwrap()));
+    }
+
+    #[test]
+    fn keeps_the_peer_for_a_v1_unknown_header() {
+        let (client, rest) = accepted(b"PROXY UNKNOWN\r\nping").unwrap();
+        assert_eq!(client, Some(PEER.parse().unwrap()));
+        assert_eq!(rest, b"ping");
+    }
+
+    #[test]
+    fn refuses_malformed_v1_headers() {
+        for header in [
+            &b"PROXY TCP4 2001:db8::7 10.0.0.1 5000 80\r\n"[..],
+            b"PROXY TCP4 192.0.2.7 10.0.0.1 70000 80\r\n",
+            b"PROXY TCP4 192.0.2.7\r\n",
+            b"HELLO TCP4 192.0.2.7 10.0.0.1 5000 80\r\n",
+        ] {
+            assert_eq!(accepted(header).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        }
+    }
+
+    #[test]
+    fn refuses_a_v1_header_longer_than_107_bytes() {
+        let mut header = b"PROXY TCP4 ".to_vec();
+        header.resize(200, b'1');
+        header.extend_from_slice(b"\r\n");
+        let error = accepted(&header).unwrap_err();
+        assert!(error.to_string().contains("longer than 107 bytes"), "{}", error);
+    }
+
+    #[test]
+    fn refuses_truncated_headers() {
+        let v2_header = v2(1, 1, &[192, 0, 2, 7, 10, 0, 0, 1, 0x13, 0x88, 0, 80]);
+        for header in [&b"PROXY TCP4 192.0.2.7 10.0.0.1"[..], b"PRO", &v2_header[..10], &v2_header[..20]] {
+            let error = accepted(header).unwrap_err();
+            assert!(error.to_string().contains("closed before it was complete"), "{}", error);
+        }
+    }
+
+    #[test]
+    fn reads_a_v2_header_skipping_its_tlvs() {
+        let mut addresses = vec![192, 0, 2, 7, 10, 0, 0, 1, 0x13, 0x88, 0, 80];
+        // An ALPN TLV and an authority TLV, which tell nothing of the client
+        addresses.extend_from_slice(&[0x01, 0, 2, b'h', b'2', 0x02, 0, 8]);
+        addresses.extend_from_slice(b"api.test");
+        let mut bytes = v2(1, 1, &addresses);
+        bytes.extend_from_slice(b"GET /");
+
+        let (client, rest) = accepted(&bytes).unwrap();
+        assert_eq!(client, Some("192.0.2.7:5000".parse().unwrap()));
+        assert_eq!(rest, b"GET /");
+    }
+
+    #[test]
+    fn reads_a_v2_ipv6_header() {
+        let mut addresses = "2001:db8::7".parse::<Ipv6Addr>().unwrap().octets().to_vec();
+        addresses.extend_from_slice(&"2001:db8::1".parse::<Ipv6Addr>().unwrap().octets());
+        addresses.extend_from_slice(&[0x13, 0x88, 1, 0xbb]);
+        let (client, _) = accepted(&v2(1, 2, &addresses)).unwrap();
+        assert_eq!(client, Some("[2001:db8::7]:5000".parse().unwrap()));
+    }
+
+    #[test]
+    fn keeps_the_peer_for_v2_local_and_unspecified_headers() {
+        for bytes in [v2(0, 0, &[]), v2(0, 1, &[192, 0, 2, 7, 10, 0, 0, 1, 0x13, 0x88, 0, 80]), v2(1, 0, &[])] {
+            let (client, rest) = accepted(&bytes).unwrap();
+            assert_eq!(client, Some(PEER.parse().unwrap()));
+            assert!(rest.is_empty());
+        }
+    }
+
+    #[test]
+    fn refuses_malformed_v2_headers() {
+        let mut other_version = v2(1, 1, &[0; 12]);
+        other_version[12] = 0x11;
+        let mut other_signature = v2(1, 1, &[0; 12]);
+        other_signature[4] = b'X';
+        for bytes in [v2(1, 1, &[192, 0, 2, 7]), v2(1, 2, &[0; 12]), v2(2, 1, &[0; 12]), other_version, other_signature] {
+            assert_eq!(accepted(&bytes).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        }
+    }
+}
@@ -0,0 +1,311 @@
+use serde_derive::{Deserialize, Serialize};
+use warp::Reply;
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::{Database, Registry, ServerJsonBody};
+use crate::error::{lock, Error};
+
+/// The most a namespace, or all servers together, may use, without limit if left out
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Limits {
+    /// Servers registered, whether up or not
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_servers: Option<usize>,
+    /// Bytes the journals keep together. A journal that would exceed it evicts its oldest
+    /// entries first, and doesn't keep a request once it has none left to evict.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_recorded_bytes: Option<usize>,
+}
+
+/// The most each server may use on its own, without limit if left out
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerLimits {
+    /// Bytes of memory a server takes up, its configuration and the requests its journal
+    /// keeps together. The journal evicts its oldest entries to stay within it, and a
+    /// configuration that wouldn't fit on its own is refused. Captures take up none, being
+    /// written to files as they go.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_memory_bytes: Option<usize>,
+}
+
+/// The quotas guarding an instance shared by several users, by namespace. Servers
+/// without a namespace only count against the global quota.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct QuotaConfig {
+    /// Of all servers together, whatever their namespace
+    pub global: Limits,
+    /// Of each namespace not listed in `namespaces`
+    pub namespace: Limits,
+    pub namespaces: BTreeMap<String, Limits>,
+    /// Of every server on its own, whatever its namespace
+    pub server: ServerLimits,
+}
+
+impl QuotaConfig {
+    /// `QUOTAS` is JSON like `{"global": {"max_servers": 100}, "namespace": {"max_servers":
+    /// 10}, "namespaces": {"ci": {"max_servers": 50}}, "server": {"max_memory_bytes":
+    /// 1048576}}`. Left unset, nothing is limited.
+    pub fn from_env() -> Result<QuotaConfig, String> {
+        match std::env::var("QUOTAS") {
+            Ok(quotas) => serde_json::from_str(&quotas).map_err(|e| format!("invalid QUOTAS {:?}: {}", quotas, e)),
+            Err(_) => Ok(QuotaConfig::default()),
+        }
+    }
+
+    fn limits(&self, namespace: &str) -> &Limits {
+        self.namespaces.get(namespace).unwrap_or(&self.namespace)
+    }
+}
+
+/// Bytes recorded against a `max_recorded_bytes`
+#[derive(Debug, Default)]
+pub struct Budget {
+    max: Option<usize>,
+    used: AtomicUsize,
+}
+
+impl Budget {
+    fn new(max: Option<usize>) -> Budget {
+        Budget { max, used: AtomicUsize::new(0) }
+    }
+
+    fn exceeded(&self) -> bool {
+        self.max.is_some_and(|max| self.used.load(Ordering::SeqCst) > max)
+    }
+}
+
+/// The bytes a journal keeps, counted against the budgets of its server's namespace and
+/// of all servers until it's dropped
+#[derive(Debug, Default)]
+pub struct Charge {
+    budgets: Vec<Arc<Budget>>,
+    bytes: usize,
+}
+
+impl Charge {
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    pub fn add(&mut self, bytes: usize) {
+        self.bytes += bytes;
+        for budget in &self.budgets {
+            budget.used.fetch_add(bytes, Ordering::SeqCst);
+        }
+    }
+
+    pub fn remove(&mut self, bytes: usize) {
+        self.bytes -= bytes;
+        for budget in &self.budgets {
+            budget.used.fetch_sub(bytes, Ordering::SeqCst);
+        }
+    }
+
+    /// Whether a budget counted against holds more than it may
+    pub fn exceeded(&self) -> bool {
+        self.budgets.iter().any(|budget| budget.exceeded())
+    }
+
+    /// Count against `budgets` from now on
+    pub fn move_to(&mut self, budgets: Vec<Arc<Budget>>) {
+        let same = budgets.len() == self.budgets.len()
+            && budgets.iter().zip(&self.budgets).all(|(a, b)| Arc::ptr_eq(a, b));
+        if !same {
+            let bytes = self.bytes;
+            self.remove(bytes);
+            self.budgets = budgets;
+            self.add(bytes);
+        }
+    }
+}
+
+impl Drop for Charge {
+    fn drop(&mut self) {
+        let bytes = self.bytes;
+        self.remove(bytes);
+    }
+}
+
+/// The quotas in effect, with the bytes recorded against them
+#[derive(Debug, Default)]
+pub struct Quotas {
+    config: QuotaConfig,
+    global: Arc<Budget>,
+    namespaces: HashMap<String, Arc<Budget>>,
+}
+
+impl Quotas {
+    pub fn new(config: QuotaConfig) -> Quotas {
+        let global = Arc::new(Budget::new(config.global.max_recorded_bytes));
+        Quotas { config, global, namespaces: HashMap::new() }
+    }
+
+    /// The budgets the journal of a server in `namespace` is charged to
+    pub fn budgets(&mut self, namespace: Option<&str>) -> Vec<Arc<Budget>> {
+        let mut budgets = vec![self.global.clone()];
+        if let Some(namespace) = namespace {
+            let config = &self.config;
+            let budget = self.namespaces.entry(namespace.to_string())
+                .or_insert_with(|| Arc::new(Budget::new(config.limits(namespace).max_recorded_bytes)));
+            budgets.push(budget.clone());
+        }
+        budgets
+    }
+
+    /// The bytes the configuration of a server leaves its journal under the server
+    /// memory quota
+    pub fn journal_room(&self, config: &ServerJsonBody) -> Option<usize> {
+        self.config.server.max_memory_bytes.map(|max| max.saturating_sub(config_bytes(config)))
+    }
+}
+
+/// The memory a server's configuration is counted for, like the responses, rules and
+/// virtual hosts it's set up with
+fn config_bytes(config: &ServerJsonBody) -> usize {
+    serde_json::to_vec(config).map_or(0, |json| json.len())
+}
+
+/// A quota a request would have exceeded, reported along with its error
+#[derive(Debug, Serialize)]
+pub struct Exceeded {
+    /// None for the global quota
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub namespace: Option<String>,
+    /// The server a quota of every server on its own was exceeded by
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub port: Option<u16>,
+    pub resource: &'static str,
+    pub used: usize,
+    pub max: usize,
+}
+
+impl fmt::Display for Exceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (&self.namespace, self.port) {
+            (_, Some(port)) => write!(f, "server {} would take up {} {}, over its quota of {}", port, self.used, self.resource, self.max),
+            (Some(namespace), None) => write!(f, "namespace {} is at its quota of {} {}", namespace, self.max, self.resource),
+            (None, None) => write!(f, "the instance is at its quota of {} {}", self.max, self.resource),
+        }
+    }
+}
+
+/// Fail unless a server on `port` in `namespace` fits the server quotas, alongside every
+/// other server registered
+pub fn check_servers(registry: &Registry, port: u16, namespace: Option<&str>) -> Result<(), Error> {
+    if namespace == Some("") {
+        let error = std::io::Error::new(std::io::ErrorKind::InvalidInput, "the namespace can't be empty");
+        return Err(Error::StartServer { port, source: error });
+    }
+
+    let others = || registry.servers.values().filter(|server| server.config.port != port);
+    let exceeded = |namespace: Option<&str>, used: usize, max: Option<usize>| match max {
+        Some(max) if used >= max => Err(Error::QuotaExceeded(Exceeded {
+            namespace: namespace.map(str::to_string),
+            port: None,
+            resource: "servers",
+            used,
+            max,
+        })),
+        _ => Ok(()),
+    };
+
+    exceeded(None, others().count(), registry.quotas.config.global.max_servers)?;
+    if let Some(namespace) = namespace {
+        let used = others().filter(|server| server.config.namespace.as_deref() == Some(namespace)).count();
+        exceeded(Some(namespace), used, registry.quotas.config.limits(namespace).max_servers)?;
+    }
+    Ok(())
+}
+
+/// Fail unless `config` fits the memory quota of a server on its own, with nothing in its
+/// journal
+pub fn check_memory(registry: &Registry, config: &ServerJsonBody) -> Result<(), Error> {
+    match registry.quotas.config.server.max_memory_bytes {
+        Some(max) if config_bytes(config) > max => Err(Error::QuotaExceeded(Exceeded {
+            namespace: None,
+            port: Some(config.port),
+            resource: "memory bytes",
+            used: config_bytes(config),
+            max,
+        })),
+        _ => Ok(()),
+    }
+}
+
+/// How much of a quota is used
+#[derive(Debug, Default, Serialize)]
+struct Used {
+    used: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max: Option<usize>,
+}
+
+#[derive(Debug, Default, Serialize)]
+struct Usage {
+    servers: Used,
+    recorded_bytes: Used,
+}
+
+impl Usage {
+    fn new(limits: &Limits) -> Usage {
+        Usage {
+            servers: Used { used: 0, max: limits.max_servers },
+            recorded_bytes: Used { used: 0, max: limits.max_recorded_bytes },
+        }
+    }
+}
+
+/// What a server uses of the quotas of every server on its own
+#[derive(Debug, Serialize)]
+struct ServerUsage {
+    memory_bytes: Used,
+}
+
+#[derive(Debug, Serialize)]
+struct QuotaReply {
+    global: Usage,
+    namespaces: BTreeMap<String, Usage>,
+    servers: BTreeMap<u16, ServerUsage>,
+}
+
+/// `GET /quota`: what is used of the global quotas and those of each namespace that is
+/// configured or has servers, and what each server uses of its own
+pub fn quota(database: Database) -> warp::reply::Response {
+    let registry = lock(&database);
+    let quotas = &registry.quotas;
+
+    let mut global = Usage::new(&quotas.config.global);
+    global.servers.used = registry.servers.len();
+    global.recorded_bytes.used = quotas.global.used.load(Ordering::SeqCst);
+
+    let mut namespaces: BTreeMap<String, Usage> = quotas.config.namespaces.iter()
+        .map(|(namespace, limits)| (namespace.clone(), Usage::new(limits)))
+        .collect();
+    let usage = |namespace: &str| Usage::new(quotas.config.limits(namespace));
+    for namespace in registry.servers.values().filter_map(|server| server.config.namespace.as_ref()) {
+        namespaces.entry(namespace.clone()).or_insert_with(|| usage(namespace)).servers.used += 1;
+    }
+    for (namespace, budget) in &quotas.namespaces {
+        let used = budget.used.load(Ordering::SeqCst);
+        if used > 0 {
+            namespaces.entry(namespace.clone()).or_insert_with(|| usage(namespace)).recorded_bytes.used = used;
+        }
+    }
+
+    let servers = registry.servers.values()
+        .map(|server| {
+            let journal = server.journal.as_ref().map_or(0, |journal| lock(journal).stats().bytes);
+            let used = config_bytes(&server.config) + journal;
+            (server.config.port, ServerUsage { memory_bytes: Used { used, max: quotas.config.server.max_memory_bytes } })
+        })
+        .collect();
+
+    warp::reply::json(&QuotaReply { global, namespaces, servers }).into_response()
+}
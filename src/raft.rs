@@ -0,0 +1,700 @@
+use futures::Future;
+use futures::sync::mpsc;
+use openraft::error::{CheckIsLeaderError, ClientWriteError, ForwardToLeader, Infallible, InstallSnapshotError, RPCError, RaftError, RemoteError, Unreachable};
+use openraft::network::RPCOption;
+use openraft::raft::{AppendEntriesRequest, AppendEntriesResponse, InstallSnapshotRequest, InstallSnapshotResponse, VoteRequest, VoteResponse};
+use openraft::storage::Adaptor;
+use openraft::{BasicNode, Entry, EntryPayload, LogId, LogState, OptionalSend, RaftLogReader, RaftNetwork, RaftNetworkFactory, RaftSnapshotBuilder, RaftStorage, Snapshot, SnapshotMeta, StorageError, StorageIOError, StoredMembership, Vote};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use warp::{path, Filter, Reply};
+
+use std::collections::BTreeMap;
+use std::fmt::Debug;
+use std::io::Cursor;
+use std::ops::RangeBounds;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+
+use crate::{error_reply, ServerJsonBody};
+use crate::error::{lock, Error};
+use crate::store::{Store, StoreError};
+
+/// How long a change to the registry waits to be committed by a quorum before it's told
+/// to be waiting, and proposed again
+const WRITE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long an RPC to another node may take
+const RPC_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long to wait for a leader to be elected before asking it again
+const NO_LEADER_WAIT: Duration = Duration::from_millis(100);
+
+openraft::declare_raft_types!(
+    pub TypeConfig:
+        D = Command,
+        R = (),
+);
+
+type NodeError<E = Infallible> = RaftError<u64, E>;
+
+/// A change to the registry, replicated to every node before it's applied
+#[derive(Clone, Debug, serde_derive::Deserialize, serde_derive::Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Command {
+    Save { instance: String, server: Box<ServerJsonBody> },
+    Delete { instance: String, port: u16 },
+    SaveSecret { instance: String, name: String, value: String },
+}
+
+/// The registry as replicated: the servers and secrets of every instance, by instance,
+/// like a Redis keeps them
+#[derive(Clone, Debug, Default, serde_derive::Deserialize, serde_derive::Serialize)]
+struct Fleet {
+    servers: BTreeMap<String, BTreeMap<u16, ServerJsonBody>>,
+    secrets: BTreeMap<String, BTreeMap<String, String>>,
+}
+
+/// A change told to those watching the fleet, as `redis_store` tells them
+#[derive(Debug, serde_derive::Serialize)]
+struct FleetChange<'a> {
+    instance: &'a str,
+    #[serde(rename = "type")]
+    change: &'static str,
+    port: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    server: Option<&'a ServerJsonBody>,
+}
+
+impl Fleet {
+    /// Apply `command`, telling the change to `watchers` if it's one to a server
+    fn apply(&mut self, command: &Command, watchers: &mut Vec<mpsc::UnboundedSender<String>>) {
+        let change = match command {
+            Command::Save { instance, server } => {
+                self.servers.entry(instance.clone()).or_default().insert(server.port, (**server).clone());
+                FleetChange { instance, change: "saved", port: server.port, server: Some(server) }
+            }
+            Command::Delete { instance, port } => {
+                if let Some(servers) = self.servers.get_mut(instance) {
+                    servers.remove(port);
+                }
+                FleetChange { instance, change: "deleted", port: *port, server: None }
+            }
+            Command::SaveSecret { instance, name, value } => {
+                self.secrets.entry(instance.clone()).or_default().insert(name.clone(), value.clone());
+                return;
+            }
+        };
+        if let Ok(change) = serde_json::to_string(&change) {
+            watchers.retain(|watcher| watcher.unbounded_send(change.clone()).is_ok());
+        }
+    }
+}
+
+/// Where the nodes are and how this one keeps its log, from `RAFT_NODE_ID`, `RAFT_PEERS`
+/// and `RAFT_STATE_PATH`. `RAFT_PEERS` names every node of the cluster, this one too, by
+/// id and the address it listens for the others on, like
+/// `1=10.0.0.1:7000,2=10.0.0.2:7000,3=10.0.0.3:7000`, which is apart from the admin API
+/// as that's only listened for on the loopback. Without `RAFT_STATE_PATH` the log is only
+/// kept in memory, and a node that restarts catches up from the others.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RaftConfig {
+    pub id: u64,
+    pub peers: BTreeMap<u64, String>,
+    pub state_path: Option<PathBuf>,
+}
+
+impl RaftConfig {
+    pub fn from_env(peers: &str) -> Result<RaftConfig, String> {
+        let id = std::env::var("RAFT_NODE_ID")
+            .map_err(|_| "RAFT_PEERS needs RAFT_NODE_ID".to_string())?;
+        let id = id.parse::<u64>().map_err(|_| format!("invalid RAFT_NODE_ID {:?}", id))?;
+        let peers = peers.split(',')
+            .map(|peer| {
+                let (id, addr) = peer.trim().split_once('=')
+                    .filter(|(_, addr)| !addr.is_empty())
+                    .ok_or_else(|| format!("invalid peer {:?} in RAFT_PEERS, expected {{id}}={{host}}:{{port}}", peer))?;
+                let id = id.parse::<u64>().map_err(|_| format!("invalid peer id {:?} in RAFT_PEERS", id))?;
+                Ok((id, addr.to_string()))
+            })
+            .collect::<Result<BTreeMap<u64, String>, String>>()?;
+        if !peers.contains_key(&id) {
+            return Err(format!("RAFT_PEERS doesn't name this node, {}", id));
+        }
+        let state_path = std::env::var("RAFT_STATE_PATH").ok().filter(|path| !path.is_empty()).map(PathBuf::from);
+        Ok(RaftConfig { id, peers, state_path })
+    }
+}
+
+/// The Raft nodes run on tokio 1, on a runtime of their own
+fn runtime() -> &'static tokio1::runtime::Runtime {
+    static RUNTIME: OnceLock<tokio1::runtime::Runtime> = OnceLock::new();
+    RUNTIME.get_or_init(|| {
+        tokio1::runtime::Builder::new_multi_thread()
+            .thread_name("raft")
+            .enable_all()
+            .build()
+            .unwrap_or_else(|e| {
+                eprintln!("failed to start the Raft runtime: {}", e);
+                std::process::exit(1);
+            })
+    })
+}
+
+fn http() -> &'static reqwest::Client {
+    static CLIENT: OnceLock<reqwest::Client> = OnceLock::new();
+    CLIENT.get_or_init(|| reqwest::Client::builder().timeout(RPC_TIMEOUT).build().unwrap_or_else(|_| reqwest::Client::new()))
+}
+
+/// `POST` `body` to the node at `addr`, as the admin if there's a token
+fn post<B: Serialize, T: DeserializeOwned>(addr: &str, path: &str, token: Option<&str>, body: &B) -> Result<T, reqwest::Error> {
+    let mut request = http().post(&format!("http://{}{}", addr, path)).json(body);
+    if let Some(token) = token {
+        request = request.header(reqwest::header::AUTHORIZATION, format!("Bearer {}", token));
+    }
+    request.send()?.error_for_status()?.json()
+}
+
+fn get<T: DeserializeOwned>(addr: &str, path: &str, token: Option<&str>) -> Result<T, reqwest::Error> {
+    let mut request = http().get(&format!("http://{}{}", addr, path));
+    if let Some(token) = token {
+        request = request.header(reqwest::header::AUTHORIZATION, format!("Bearer {}", token));
+    }
+    request.send()?.error_for_status()?.json()
+}
+
+/// What a node keeps of the log and the registry, written to `RAFT_STATE_PATH` after
+/// every change if it's set
+#[derive(Default, serde_derive::Deserialize, serde_derive::Serialize)]
+struct Persisted {
+    vote: Option<Vote<u64>>,
+    committed: Option<LogId<u64>>,
+    log: BTreeMap<u64, Entry<TypeConfig>>,
+    last_purged: Option<LogId<u64>>,
+    last_applied: Option<LogId<u64>>,
+    membership: StoredMembership<u64, BasicNode>,
+    fleet: Fleet,
+    snapshot: Option<(SnapshotMeta<u64, BasicNode>, Vec<u8>)>,
+}
+
+/// The log and the state machine of a node, shared by openraft and the store
+#[derive(Clone, Default)]
+struct Storage {
+    state: Arc<Mutex<Persisted>>,
+    path: Option<Arc<PathBuf>>,
+    watchers: Arc<Mutex<Vec<mpsc::UnboundedSender<String>>>>,
+}
+
+fn write_error(e: std::io::Error) -> StorageError<u64> {
+    StorageIOError::write(&e).into()
+}
+
+impl Storage {
+    fn open(path: Option<PathBuf>) -> Result<Storage, String> {
+        let state = match &path {
+            Some(path) if path.exists() => {
+                let kept = std::fs::read(path).map_err(|e| format!("failed to read {}: {}", path.display(), e))?;
+                serde_json::from_slice(&kept).map_err(|e| format!("invalid Raft state in {}: {}", path.display(), e))?
+            }
+            _ => Persisted::default(),
+        };
+        Ok(Storage { state: Arc::new(Mutex::new(state)), path: path.map(Arc::new), ..Storage::default() })
+    }
+
+    /// Write `state` out, replacing what was written before at once
+    fn persist(&self, state: &Persisted) -> std::io::Result<()> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let json = serde_json::to_vec(state)?;
+        let partial = path.with_extension("partial");
+        std::fs::write(&partial, json)?;
+        std::fs::rename(&partial, path.as_ref())
+    }
+
+    fn snapshot(meta: &SnapshotMeta<u64, BasicNode>, data: &[u8]) -> Snapshot<TypeConfig> {
+        Snapshot { meta: meta.clone(), snapshot: Box::new(Cursor::new(data.to_vec())) }
+    }
+}
+
+impl RaftLogReader<TypeConfig> for Storage {
+    async fn try_get_log_entries<RB: RangeBounds<u64> + Clone + Debug + OptionalSend>(
+        &mut self,
+        range: RB
+    ) -> Result<Vec<Entry<TypeConfig>>, StorageError<u64>> {
+        Ok(lock(&self.state).log.range(range).map(|(_, entry)| entry.clone()).collect())
+    }
+}
+
+impl RaftSnapshotBuilder<TypeConfig> for Storage {
+    async fn build_snapshot(&mut self) -> Result<Snapshot<TypeConfig>, StorageError<u64>> {
+        let mut state = lock(&self.state);
+        let data = serde_json::to_vec(&state.fleet).map_err(|e| StorageIOError::read_state_machine(&e))?;
+        let built_at = crate::unix_time();
+        let meta = SnapshotMeta {
+            last_log_id: state.last_applied,
+            last_membership: state.membership.clone(),
+            snapshot_id: format!("{}-{}", state.last_applied.map_or(0, |log_id| log_id.index), built_at),
+        };
+        let snapshot = Storage::snapshot(&meta, &data);
+        state.snapshot = Some((meta, data));
+        self.persist(&state).map_err(write_error)?;
+        Ok(snapshot)
+    }
+}
+
+impl RaftStorage<TypeConfig> for Storage {
+    type LogReader = Storage;
+    type SnapshotBuilder = Storage;
+
+    async fn save_vote(&mut self, vote: &Vote<u64>) -> Result<(), StorageError<u64>> {
+        let mut state = lock(&self.state);
+        state.vote = Some(*vote);
+        self.persist(&state).map_err(write_error)
+    }
+
+    async fn read_vote(&mut self) -> Result<Option<Vote<u64>>, StorageError<u64>> {
+        Ok(lock(&self.state).vote)
+    }
+
+    async fn save_committed(&mut self, committed: Option<LogId<u64>>) -> Result<(), StorageError<u64>> {
+        let mut state = lock(&self.state);
+        state.committed = committed;
+        self.persist(&state).map_err(write_error)
+    }
+
+    async fn read_committed(&mut self) -> Result<Option<LogId<u64>>, StorageError<u64>> {
+        Ok(lock(&self.state).committed)
+    }
+
+    async fn get_log_state(&mut self) -> Result<LogState<TypeConfig>, StorageError<u64>> {
+        let state = lock(&self.state);
+        let last_log_id = state.log.values().next_back().map(|entry| entry.log_id).or(state.last_purged);
+        Ok(LogState { last_purged_log_id: state.last_purged, last_log_id })
+    }
+
+    async fn get_log_reader(&mut self) -> Storage {
+        self.clone()
+    }
+
+    async fn append_to_log<I>(&mut self, entries: I) -> Result<(), StorageError<u64>>
+    where
+        I: IntoIterator<Item = Entry<TypeConfig>> + OptionalSend
+    {
+        let mut state = lock(&self.state);
+        for entry in entries {
+            state.log.insert(entry.log_id.index, entry);
+        }
+        self.persist(&state).map_err(write_error)
+    }
+
+    async fn delete_conflict_logs_since(&mut self, log_id: LogId<u64>) -> Result<(), StorageError<u64>> {
+        let mut state = lock(&self.state);
+        state.log.split_off(&log_id.index);
+        self.persist(&state).map_err(write_error)
+    }
+
+    async fn purge_logs_upto(&mut self, log_id: LogId<u64>) -> Result<(), StorageError<u64>> {
+        let mut state = lock(&self.state);
+        state.log = state.log.split_off(&(log_id.index + 1));
+        state.last_purged = Some(log_id);
+        self.persist(&state).map_err(write_error)
+    }
+
+    async fn last_applied_state(&mut self) -> Result<(Option<LogId<u64>>, StoredMembership<u64, BasicNode>), StorageError<u64>> {
+        let state = lock(&self.state);
+        Ok((state.last_applied, state.membership.clone()))
+    }
+
+    async fn apply_to_state_machine(&mut self, entries: &[Entry<TypeConfig>]) -> Result<Vec<()>, StorageError<u64>> {
+        let mut state = lock(&self.state);
+        let mut watchers = lock(&self.watchers);
+        for entry in entries {
+            state.last_applied = Some(entry.log_id);
+            match &entry.payload {
+                EntryPayload::Blank => {}
+                EntryPayload::Normal(command) => state.fleet.apply(command, &mut watchers),
+                EntryPayload::Membership(membership) => {
+                    state.membership = StoredMembership::new(Some(entry.log_id), membership.clone());
+                }
+            }
+        }
+        self.persist(&state).map_err(write_error)?;
+        Ok(vec![(); entries.len()])
+    }
+
+    async fn get_snapshot_builder(&mut self) -> Storage {
+        self.clone()
+    }
+
+    async fn begin_receiving_snapshot(&mut self) -> Result<Box<Cursor<Vec<u8>>>, StorageError<u64>> {
+        Ok(Box::new(Cursor::new(Vec::new())))
+    }
+
+    async fn install_snapshot(
+        &mut self,
+        meta: &SnapshotMeta<u64, BasicNode>,
+        snapshot: Box<Cursor<Vec<u8>>>
+    ) -> Result<(), StorageError<u64>> {
+        let data = snapshot.into_inner();
+        let fleet = serde_json::from_slice(&data)
+            .map_err(|e| StorageIOError::read_snapshot(Some(meta.signature()), &e))?;
+        let mut state = lock(&self.state);
+        state.fleet = fleet;
+        state.last_applied = meta.last_log_id;
+        state.membership = meta.last_membership.clone();
+        state.snapshot = Some((meta.clone(), data));
+        self.persist(&state).map_err(write_error)
+    }
+
+    async fn get_current_snapshot(&mut self) -> Result<Option<Snapshot<TypeConfig>>, StorageError<u64>> {
+        Ok(lock(&self.state).snapshot.as_ref().map(|(meta, data)| Storage::snapshot(meta, data)))
+    }
+}
+
+/// Connects to the other nodes through their admin API
+struct Peers {
+    token: Option<String>,
+}
+
+impl RaftNetworkFactory<TypeConfig> for Peers {
+    type Network = Peer;
+
+    async fn new_client(&mut self, target: u64, node: &BasicNode) -> Peer {
+        Peer { target, addr: node.addr.clone(), token: self.token.clone() }
+    }
+}
+
+struct Peer {
+    target: u64,
+    addr: String,
+    token: Option<String>,
+}
+
+impl Peer {
+    /// `POST` `body` to `path` of the peer, which answers with what its node did with it
+    async fn call<B, T, E>(&self, path: &'static str, body: B) -> Result<T, RPCError<u64, BasicNode, NodeError<E>>>
+    where
+        B: Serialize + Send + 'static,
+        T: DeserializeOwned + Send + 'static,
+        E: std::error::Error + DeserializeOwned + Send + 'static,
+    {
+        let (addr, token) = (self.addr.clone(), self.token.clone());
+        let answer = tokio1::task::spawn_blocking(move || post::<_, Result<T, NodeError<E>>>(&addr, path, token.as_deref(), &body))
+            .await
+            .map_err(|e| RPCError::Unreachable(Unreachable::new(&e)))?;
+        match answer {
+            Ok(Ok(answer)) => Ok(answer),
+            Ok(Err(e)) => Err(RPCError::RemoteError(RemoteError::new(self.target, e))),
+            Err(e) => Err(RPCError::Unreachable(Unreachable::new(&e))),
+        }
+    }
+}
+
+impl RaftNetwork<TypeConfig> for Peer {
+    async fn append_entries(
+        &mut self,
+        rpc: AppendEntriesRequest<TypeConfig>,
+        _option: RPCOption
+    ) -> Result<AppendEntriesResponse<u64>, RPCError<u64, BasicNode, NodeError>> {
+        self.call("/raft/append", rpc).await
+    }
+
+    async fn install_snapshot(
+        &mut self,
+        rpc: InstallSnapshotRequest<TypeConfig>,
+        _option: RPCOption
+    ) -> Result<InstallSnapshotResponse<u64>, RPCError<u64, BasicNode, NodeError<InstallSnapshotError>>> {
+        self.call("/raft/snapshot", rpc).await
+    }
+
+    async fn vote(
+        &mut self,
+        rpc: VoteRequest<u64>,
+        _option: RPCOption
+    ) -> Result<VoteResponse<u64>, RPCError<u64, BasicNode, NodeError>> {
+        self.call("/raft/vote", rpc).await
+    }
+}
+
+/// The Raft node of this process, and the instance whose servers it saves
+#[derive(Clone)]
+struct Node {
+    id: u64,
+    raft: openraft::Raft<TypeConfig>,
+    storage: Storage,
+    instance: String,
+    peers: BTreeMap<u64, String>,
+    token: Option<String>,
+    /// Changes waiting to be proposed, see `propose_in_order`
+    writes: std::sync::mpsc::Sender<Command>,
+}
+
+impl Node {
+    /// Have `command` committed by a quorum, through the leader if it's another node
+    async fn propose(&self, command: Command) -> Result<(), String> {
+        loop {
+            let leader = match self.raft.client_write(command.clone()).await {
+                Ok(_) => return Ok(()),
+                Err(RaftError::APIError(ClientWriteError::ForwardToLeader(ForwardToLeader { leader_node, .. }))) => leader_node,
+                Err(e) => return Err(e.to_string()),
+            };
+            let leader = match leader {
+                Some(leader) => leader,
+                None => {
+                    tokio1::time::sleep(NO_LEADER_WAIT).await;
+                    continue;
+                }
+            };
+            let (addr, token, command) = (leader.addr.clone(), self.token.clone(), command.clone());
+            let forwarded = tokio1::task::spawn_blocking(move || {
+                post::<_, Result<(), NodeError<ClientWriteError<u64, BasicNode>>>>(&addr, "/raft/write", token.as_deref(), &command)
+            });
+            match forwarded.await.map_err(|e| e.to_string())? {
+                Ok(Ok(())) => return Ok(()),
+                // Leadership moved on meanwhile
+                Ok(Err(RaftError::APIError(ClientWriteError::ForwardToLeader(_)))) => tokio1::time::sleep(NO_LEADER_WAIT).await,
+                Ok(Err(e)) => return Err(e.to_string()),
+                // Gone, which the others notice soon enough to elect another
+                Err(_) => tokio1::time::sleep(NO_LEADER_WAIT).await,
+            }
+        }
+    }
+
+    fn write(&self, command: Command) -> Result<(), StoreError> {
+        self.writes.send(command).map_err(|_| StoreError::Raft("the Raft node is gone".to_string()))
+    }
+
+    fn servers(&self, instance: &str) -> Vec<ServerJsonBody> {
+        lock(&self.storage.state).fleet.servers.get(instance)
+            .map(|servers| servers.values().cloned().collect())
+            .unwrap_or_default()
+    }
+}
+
+/// Keeps the servers of several instances in a registry replicated across the root
+/// servers of a Raft cluster, each instance under its own name like a `RedisStore`. A
+/// change is saved once a quorum of the nodes has it, so the registry survives losing any
+/// fewer than half of them. Changes are replicated in the background, in order, see
+/// `propose_in_order`.
+pub struct RaftStore {
+    node: Node,
+}
+
+impl RaftStore {
+    pub fn open(config: RaftConfig, instance: String) -> Result<RaftStore, String> {
+        let RaftConfig { id, peers: addrs, state_path } = config;
+        let storage = Storage::open(state_path)?;
+        let (log_store, state_machine) = Adaptor::new(storage.clone());
+        let raft_config = openraft::Config {
+            cluster_name: "warp-self-replicating".to_string(),
+            heartbeat_interval: 250,
+            election_timeout_min: 1000,
+            election_timeout_max: 2000,
+            ..Default::default()
+        };
+        let raft_config = Arc::new(raft_config.validate().map_err(|e| e.to_string())?);
+        let token = crate::auth::admin_token_from_env();
+        let peers = Peers { token: token.clone() };
+        let raft = runtime().block_on(openraft::Raft::new(id, raft_config, peers, log_store, state_machine))
+            .map_err(|e| format!("failed to start Raft node {}: {}", id, e))?;
+
+        // The cluster is formed once, by the node with the lowest id
+        if addrs.keys().next() == Some(&id) {
+            let members: BTreeMap<u64, BasicNode> = addrs.iter()
+                .map(|(id, addr)| (*id, BasicNode::new(addr)))
+                .collect();
+            runtime().block_on(async {
+                if !raft.is_initialized().await.unwrap_or(true) {
+                    if let Err(e) = raft.initialize(members).await {
+                        eprintln!("failed to form the Raft cluster: {}", e);
+                    }
+                }
+            });
+        }
+
+        let (writes, proposed) = std::sync::mpsc::channel();
+        let node = Node { id, raft, storage, instance, peers: addrs, token, writes };
+        let writer = node.clone();
+        std::thread::Builder::new()
+            .name("raft-writes".to_string())
+            .spawn(move || propose_in_order(writer, proposed))
+            .map_err(|e| format!("failed to start proposing changes: {}", e))?;
+        listen(&node)?;
+        Ok(RaftStore { node })
+    }
+}
+
+impl Store for RaftStore {
+    fn save(&mut self, server: &ServerJsonBody) -> Result<(), StoreError> {
+        self.node.write(Command::Save { instance: self.node.instance.clone(), server: Box::new(server.clone()) })
+    }
+
+    fn delete(&mut self, port: u16) -> Result<(), StoreError> {
+        self.node.write(Command::Delete { instance: self.node.instance.clone(), port })
+    }
+
+    /// The servers as the leader has them, as a node that's been away may not have caught up
+    /// yet, once there's one to ask, this node too. Left without a leader for `WRITE_TIMEOUT`, they're
+    /// as this node has them.
+    fn load(&mut self) -> Result<Vec<ServerJsonBody>, StoreError> {
+        let path = format!("/raft/servers/{}", self.node.instance);
+        let token = self.node.token.as_deref();
+        let started = std::time::Instant::now();
+        while started.elapsed() < WRITE_TIMEOUT {
+            for addr in self.node.peers.values() {
+                if let Ok(Ok(servers)) = get::<Result<Vec<ServerJsonBody>, NodeError<CheckIsLeaderError<u64, BasicNode>>>>(addr, &path, token) {
+                    return Ok(servers);
+                }
+            }
+            std::thread::sleep(NO_LEADER_WAIT);
+        }
+        Ok(self.node.servers(&self.node.instance))
+    }
+
+    fn fleet(&mut self) -> Result<Option<BTreeMap<String, Vec<ServerJsonBody>>>, StoreError> {
+        let state = lock(&self.node.storage.state);
+        Ok(Some(state.fleet.servers.iter()
+            .map(|(instance, servers)| (instance.clone(), servers.values().cloned().collect()))
+            .collect()))
+    }
+
+    fn subscribe(&mut self) -> Result<Option<mpsc::UnboundedReceiver<String>>, StoreError> {
+        let (tx, rx) = mpsc::unbounded();
+        lock(&self.node.storage.watchers).push(tx);
+        Ok(Some(rx))
+    }
+
+    fn save_secret(&mut self, name: &str, value: &str) -> Result<(), StoreError> {
+        let instance = self.node.instance.clone();
+        self.node.write(Command::SaveSecret { instance, name: name.to_string(), value: value.to_string() })
+    }
+
+    fn load_secret(&mut self, name: &str) -> Result<Option<String>, StoreError> {
+        let state = lock(&self.node.storage.state);
+        Ok(state.fleet.secrets.get(&self.node.instance).and_then(|secrets| secrets.get(name)).cloned())
+    }
+}
+
+/// Propose the changes to the registry one at a time, in the order they were made, each
+/// until it's committed. The registry goes on meanwhile rather than wait for the cluster,
+/// which may not have a quorum yet, or ever, while the registry holds back the routes of
+/// its own node.
+fn propose_in_order(node: Node, proposed: std::sync::mpsc::Receiver<Command>) {
+    for command in proposed {
+        loop {
+            let proposed = runtime().block_on(async { tokio1::time::timeout(WRITE_TIMEOUT, node.propose(command.clone())).await });
+            match proposed {
+                Ok(Ok(())) => break,
+                Ok(Err(e)) => {
+                    eprintln!("failed to replicate a change to the registry: {}", e);
+                    break;
+                }
+                // Saving and deleting again is harmless, if the first try got through
+                Err(_) => eprintln!("still waiting for a quorum of the cluster to replicate a change to the registry"),
+            }
+        }
+    }
+}
+
+/// Run `future` on the Raft runtime, answering with what it returns as JSON
+fn answer<F>(future: F) -> impl Future<Item = warp::reply::Response, Error = warp::Rejection>
+where
+    F: std::future::Future + Send + 'static,
+    F::Output: Serialize + Send + 'static,
+{
+    let (tx, rx) = futures::sync::oneshot::channel();
+    runtime().spawn(async move {
+        let _ = tx.send(future.await);
+    });
+    rx.then(|answer| Ok(match answer {
+        Ok(answer) => warp::reply::json(&answer).into_response(),
+        Err(_) => error_reply(warp::http::StatusCode::INTERNAL_SERVER_ERROR, "the Raft node is gone"),
+    }))
+}
+
+/// Answer the other nodes on the address `RAFT_PEERS` gives this one, on a runtime of its
+/// own, so they don't wait on the admin API
+fn listen(node: &Node) -> Result<(), String> {
+    let addr = &node.peers[&node.id];
+    let listener = std::net::TcpListener::bind(addr.as_str())
+        .map_err(|e| format!("failed to listen for Raft on {}: {}", addr, e))?;
+    let handle = crate::runtime::spawn_dedicated("raft")
+        .map_err(|e| format!("failed to start the Raft listener: {}", e))?;
+    let routes = routes(node.clone());
+    handle.spawn(futures::future::lazy(move || {
+        match tokio::net::TcpListener::from_std(listener, &tokio::reactor::Handle::default()) {
+            Ok(listener) => futures::future::Either::A(warp::serve(routes).serve_incoming(listener.incoming())),
+            Err(e) => {
+                eprintln!("failed to listen for Raft: {}", e);
+                futures::future::Either::B(futures::future::ok(()))
+            }
+        }
+    }))
+    .map_err(|e| format!("failed to start the Raft listener: {:?}", e))
+}
+
+/// The routes the nodes of a Raft cluster call each other with, which need the admin token
+/// if one is set
+///
+/// - `GET /raft` tells how the node of this process sees the cluster
+/// - `POST /raft/append`, `/raft/vote` and `/raft/snapshot` are the RPCs of Raft
+/// - `POST /raft/write` proposes a change to the registry to the leader
+/// - `GET /raft/servers/{instance}` lists the servers of an instance as the leader has them
+fn routes(node: Node) -> warp::filters::BoxedFilter<(warp::reply::Response,)> {
+    let node = warp::any()
+        .and(warp::header::optional::<String>("authorization"))
+        .and_then(move |authorization: Option<String>| {
+            let bearer = authorization.as_deref().and_then(|authorization| authorization.strip_prefix("Bearer "));
+            match (&node.token, bearer) {
+                (None, _) => Ok(node.clone()),
+                (Some(token), Some(bearer)) if crate::auth::tokens_equal(bearer.trim(), token) => Ok(node.clone()),
+                _ => Err(warp::reject::custom(Error::Unauthorized)),
+            }
+        });
+
+    let status = node.clone()
+        .and(path!("raft"))
+        .and(warp::get2())
+        .and(warp::path::end())
+        .map(|node: Node| warp::reply::json(&*node.raft.metrics().borrow()).into_response());
+    let append = node.clone()
+        .and(path!("raft" / "append"))
+        .and(warp::post2())
+        .and(warp::body::json())
+        .and_then(|node: Node, rpc: AppendEntriesRequest<TypeConfig>| answer(async move { node.raft.append_entries(rpc).await }));
+    let vote = node.clone()
+        .and(path!("raft" / "vote"))
+        .and(warp::post2())
+        .and(warp::body::json())
+        .and_then(|node: Node, rpc: VoteRequest<u64>| answer(async move { node.raft.vote(rpc).await }));
+    let snapshot = node.clone()
+        .and(path!("raft" / "snapshot"))
+        .and(warp::post2())
+        .and(warp::body::json())
+        .and_then(|node: Node, rpc: InstallSnapshotRequest<TypeConfig>| answer(async move { node.raft.install_snapshot(rpc).await }));
+    let write = node.clone()
+        .and(path!("raft" / "write"))
+        .and(warp::post2())
+        .and(warp::body::json())
+        .and_then(|node: Node, command: Command| answer(async move { node.raft.client_write(command).await.map(|_| ()) }));
+    let servers = node
+        .and(path!("raft" / "servers" / String))
+        .and(warp::get2())
+        .and(warp::path::end())
+        .and_then(|node: Node, instance: String| answer(async move {
+            node.raft.ensure_linearizable().await.map(|_| node.servers(&instance))
+        }));
+
+    status.or(append).unify()
+        .or(vote).unify()
+        .or(snapshot).unify()
+        .or(write).unify()
+        .or(servers).unify()
+        .recover(crate::error::recover)
+        .unify()
+        .boxed()
+}
+
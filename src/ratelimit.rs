@@ -0,0 +1,121 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+/// Clients remembered before those whose buckets have refilled are forgotten
+const MAX_IDLE_CLIENTS: usize = 1024;
+
+/// The lowest rate limit, a request every 1000 seconds, so a client is never told to wait
+/// longer than that
+const MIN_RATE: f64 = 0.001;
+
+/// Requests per second the admin API serves, from `RATE_LIMIT` for all clients together
+/// and `CLIENT_RATE_LIMIT` for each client IP, at least `MIN_RATE`. Either allows bursts
+/// of a second's worth of requests. Left unset, there's no limit.
+#[derive(Clone, Debug, Default)]
+pub struct RateLimitConfig {
+    pub global: Option<f64>,
+    pub per_client: Option<f64>,
+}
+
+impl RateLimitConfig {
+    pub fn from_env() -> Result<RateLimitConfig, String> {
+        let rate = |var: &str| match std::env::var(var) {
+            Ok(rate) => rate.parse::<f64>().ok()
+                .filter(|rate| *rate >= MIN_RATE && rate.is_finite())
+                .map(Some)
+                .ok_or_else(|| format!("invalid {} {:?}, not a rate of at least {} per second", var, rate, MIN_RATE)),
+            Err(_) => Ok(None),
+        };
+
+        Ok(RateLimitConfig {
+            global: rate("RATE_LIMIT")?,
+            per_client: rate("CLIENT_RATE_LIMIT")?,
+        })
+    }
+}
+
+/// A token bucket refilling at `rate` tokens per second, holding `rate` at most
+#[derive(Clone, Copy, Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn full(rate: f64, now: Instant) -> Bucket {
+        Bucket { tokens: capacity(rate), updated: now }
+    }
+
+    fn refill(&mut self, rate: f64, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(capacity(rate));
+        self.updated = now;
+    }
+
+    /// How long until a token is available, if it isn't already
+    fn wait(&self, rate: f64) -> Option<Duration> {
+        if self.tokens < 1.0 {
+            Some(Duration::from_secs_f64((1.0 - self.tokens) / rate))
+        } else {
+            None
+        }
+    }
+}
+
+fn capacity(rate: f64) -> f64 {
+    rate.max(1.0)
+}
+
+/// The buckets of `RateLimitConfig`
+#[derive(Debug, Default)]
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    global: Option<Bucket>,
+    clients: HashMap<IpAddr, Bucket>,
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig) -> RateLimiter {
+        RateLimiter { config, ..RateLimiter::default() }
+    }
+
+    /// Take a request of `client` into account, or tell how long it has to wait if that
+    /// would exceed a limit. Requests of unknown clients only count towards the global
+    /// limit.
+    pub fn admit(&mut self, client: Option<IpAddr>) -> Result<(), Duration> {
+        let now = Instant::now();
+        let RateLimiter { config, global, clients } = self;
+
+        let global = config.global.map(|rate| {
+            let bucket = global.get_or_insert_with(|| Bucket::full(rate, now));
+            bucket.refill(rate, now);
+            (rate, bucket)
+        });
+        let client = match (config.per_client, client) {
+            (Some(rate), Some(client)) => {
+                if clients.len() >= MAX_IDLE_CLIENTS {
+                    clients.retain(|_, bucket| {
+                        bucket.refill(rate, now);
+                        bucket.tokens < capacity(rate)
+                    });
+                }
+                let bucket = clients.entry(client).or_insert_with(|| Bucket::full(rate, now));
+                bucket.refill(rate, now);
+                Some((rate, bucket))
+            }
+            _ => None,
+        };
+
+        let wait = global.iter().chain(client.iter())
+            .filter_map(|(rate, bucket)| bucket.wait(*rate))
+            .max();
+        if let Some(wait) = wait {
+            return Err(wait);
+        }
+        for (_, bucket) in global.into_iter().chain(client) {
+            bucket.tokens -= 1.0;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,64 @@
+use warp::http::Method;
+
+use std::sync::Arc;
+
+use crate::{error_reply, shared, Database};
+use crate::error::{lock, Error};
+
+/// JSON body of `GET|PUT /admin/readonly`
+#[derive(Clone, Copy, Debug, serde_derive::Deserialize, serde_derive::Serialize)]
+pub struct ReadOnlyMode {
+    /// Refuse every admin request that changes anything
+    pub enabled: bool,
+}
+
+/// Requests besides `GET` and `HEAD` that change nothing, and so are let through while
+/// read-only. `POST /rpc` is left to `jsonrpc` to tell by the method called.
+fn reads(method: &Method, path: &str) -> bool {
+    if method == Method::GET || method == Method::HEAD {
+        return true;
+    }
+    let port_verify = path.strip_prefix('/')
+        .and_then(|path| path.strip_suffix("/verify").or_else(|| path.strip_suffix("/requests/diff")))
+        .is_some_and(|port| port.parse::<u16>().is_ok());
+    path == "/admin/readonly" || (method == Method::POST && (path == "/rpc" || port_verify))
+}
+
+/// Refuse requests that would change the fleet with 503 while the admin API is
+/// read-only, so it stays frozen through a test run. Listing servers and their stats,
+/// and setting the mode itself, are let through.
+pub fn guard(
+    shared: Arc<shared::Shared>,
+    method: Method,
+    path: warp::path::FullPath
+) -> Result<(), warp::Rejection> {
+    if shared.read_only() && !reads(&method, path.as_str()) {
+        return Err(warp::reject::custom(Error::ReadOnly));
+    }
+    Ok(())
+}
+
+/// `GET /admin/readonly`
+pub fn get_mode(shared: Arc<shared::Shared>) -> warp::reply::Response {
+    let mode = ReadOnlyMode { enabled: shared.read_only() };
+    warp::reply::Reply::into_response(warp::reply::json(&mode))
+}
+
+/// `PUT /admin/readonly`: freeze the fleet, or thaw it
+pub fn set_mode(shared: Arc<shared::Shared>, mode: ReadOnlyMode) -> warp::reply::Response {
+    if shared.set_read_only(mode.enabled) != mode.enabled {
+        eprintln!("admin API is {}", if mode.enabled { "read-only" } else { "writable again" });
+    }
+    warp::reply::Reply::into_response(warp::reply::json(&mode))
+}
+
+/// Refuse a JSON-RPC call of `method` that would change the fleet while read-only, like
+/// `guard` does the routes it stands for
+pub fn check_call(database: &Database, method: &str) -> Option<warp::reply::Response> {
+    let reads = matches!(method, "list" | "get" | "watch");
+    if lock(database).shared.read_only() && !reads {
+        let error = Error::ReadOnly;
+        return Some(error_reply(warp::http::StatusCode::SERVICE_UNAVAILABLE, &error.to_string()));
+    }
+    None
+}
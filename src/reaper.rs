@@ -0,0 +1,31 @@
+use futures::{Future, Stream};
+use tokio::timer::Interval;
+
+use std::time::Duration;
+
+use crate::Database;
+use crate::error::lock;
+
+const REAP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Periodically delete servers whose lease has run out without a heartbeat, run as a
+/// `background::Background` task
+pub fn reap_expired_leases(database: Database) -> impl Future<Item = (), Error = ()> {
+    Interval::new_interval(REAP_INTERVAL)
+        .map_err(|e| eprintln!("lease reaper timer error: {}", e))
+        .for_each(move |_| {
+            let mut registry = lock(&database);
+
+            let expired: Vec<u16> = registry.servers.iter()
+                .filter(|(_, server)| server.lease_expired())
+                .map(|(port, _)| *port)
+                .collect();
+
+            for port in expired {
+                eprintln!("server {} lease expired", port);
+                registry.remove_server(port);
+            }
+
+            Ok(())
+        })
+}
@@ -0,0 +1,132 @@
+use futures::sync::mpsc;
+use redis::Commands;
+
+use std::collections::BTreeMap;
+
+use crate::ServerJsonBody;
+use crate::store::{Store, StoreError};
+
+/// Hashes of the servers of each instance, by port, are kept under this prefix
+const SERVERS_KEY_PREFIX: &str = "warp-self-replicating:servers:";
+
+/// Hashes of the secrets of each instance, by name, are kept under this prefix
+const SECRETS_KEY_PREFIX: &str = "warp-self-replicating:secrets:";
+
+/// Every saved and deleted server is published on this channel
+const CHANGES_CHANNEL: &str = "warp-self-replicating:changes";
+
+/// A change published to the other instances sharing the store
+#[derive(Debug, serde_derive::Serialize)]
+struct FleetChange<'a> {
+    instance: &'a str,
+    #[serde(rename = "type")]
+    change: &'static str,
+    port: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    server: Option<&'a ServerJsonBody>,
+}
+
+/// Keeps the servers of several instances in one Redis, each under its own `instance`
+/// name, so every one of them can see the whole fleet
+pub struct RedisStore {
+    client: redis::Client,
+    connection: redis::Connection,
+    instance: String,
+}
+
+impl RedisStore {
+    pub fn open(url: &str, instance: String) -> Result<RedisStore, StoreError> {
+        let client = redis::Client::open(url)?;
+        let connection = client.get_connection()?;
+        Ok(RedisStore { client, connection, instance })
+    }
+
+    fn key(&self) -> String {
+        format!("{}{}", SERVERS_KEY_PREFIX, self.instance)
+    }
+
+    fn publish(&mut self, change: &FleetChange) -> Result<(), StoreError> {
+        let message = serde_json::to_string(change)?;
+        self.connection.publish::<_, _, ()>(CHANGES_CHANNEL, message)?;
+        Ok(())
+    }
+}
+
+impl Store for RedisStore {
+    fn save(&mut self, server: &ServerJsonBody) -> Result<(), StoreError> {
+        let body = serde_json::to_string(server)?;
+        self.connection.hset::<_, _, _, ()>(self.key(), server.port, body)?;
+
+        let instance = self.instance.clone();
+        self.publish(&FleetChange { instance: &instance, change: "saved", port: server.port, server: Some(server) })
+    }
+
+    fn delete(&mut self, port: u16) -> Result<(), StoreError> {
+        self.connection.hdel::<_, _, ()>(self.key(), port)?;
+
+        let instance = self.instance.clone();
+        self.publish(&FleetChange { instance: &instance, change: "deleted", port, server: None })
+    }
+
+    fn load(&mut self) -> Result<Vec<ServerJsonBody>, StoreError> {
+        let bodies: BTreeMap<u16, String> = self.connection.hgetall(self.key())?;
+        bodies.values()
+            .map(|body| Ok(serde_json::from_str(body)?))
+            .collect()
+    }
+
+    fn fleet(&mut self) -> Result<Option<BTreeMap<String, Vec<ServerJsonBody>>>, StoreError> {
+        let keys: Vec<String> = self.connection.keys(format!("{}*", SERVERS_KEY_PREFIX))?;
+
+        let mut fleet = BTreeMap::new();
+        for key in keys {
+            let bodies: BTreeMap<u16, String> = self.connection.hgetall(&key)?;
+            let servers = bodies.values()
+                .map(|body| serde_json::from_str(body))
+                .collect::<Result<Vec<ServerJsonBody>, _>>()?;
+            fleet.insert(key[SERVERS_KEY_PREFIX.len()..].to_string(), servers);
+        }
+        Ok(Some(fleet))
+    }
+
+    fn save_secret(&mut self, name: &str, value: &str) -> Result<(), StoreError> {
+        let key = format!("{}{}", SECRETS_KEY_PREFIX, self.instance);
+        self.connection.hset::<_, _, _, ()>(key, name, value)?;
+        Ok(())
+    }
+
+    fn load_secret(&mut self, name: &str) -> Result<Option<String>, StoreError> {
+        let key = format!("{}{}", SECRETS_KEY_PREFIX, self.instance);
+        Ok(self.connection.hget(key, name)?)
+    }
+
+    /// Subscribing takes a connection and a thread of its own, which notices that the
+    /// receiver is gone on the next message after that
+    fn subscribe(&mut self) -> Result<Option<mpsc::UnboundedReceiver<String>>, StoreError> {
+        let mut connection = self.client.get_connection()?;
+        let (subscribed_tx, subscribed_rx) = std::sync::mpsc::channel();
+        let (tx, rx) = mpsc::unbounded();
+
+        std::thread::spawn(move || {
+            let mut pubsub = connection.as_pubsub();
+            if let Err(e) = pubsub.subscribe(CHANGES_CHANNEL) {
+                return subscribed_tx.send(Err(e)).unwrap_or(());
+            }
+            let _ = subscribed_tx.send(Ok(()));
+
+            loop {
+                let message = match pubsub.get_message().and_then(|message| message.get_payload::<String>()) {
+                    Ok(message) => message,
+                    Err(e) => return eprintln!("fleet subscription failed: {}", e),
+                };
+                if tx.unbounded_send(message).is_err() {
+                    return;
+                }
+            }
+        });
+
+        subscribed_rx.recv()
+            .unwrap_or_else(|_| Err(redis::RedisError::from((redis::ErrorKind::IoError, "subscriber thread exited"))))?;
+        Ok(Some(rx))
+    }
+}
@@ -0,0 +1,326 @@
+use warp::{Filter, Reply};
+use warp::http::header::{HeaderName, HeaderValue, CONTENT_TYPE};
+use warp::http::{HeaderMap, StatusCode};
+
+use std::collections::BTreeMap;
+use std::io;
+use std::net::IpAddr;
+use std::sync::Arc;
+
+use crate::{error_reply, objects, Database};
+use crate::error::lock;
+use crate::journal::{self, Entry, Header};
+
+/// Requests `GET /{port}/bin` lists unless `limit` asks for fewer
+const DEFAULT_LIMIT: usize = 100;
+
+fn invalid(error: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, error)
+}
+
+/// An HTTP server acting as a request bin, for webhooks and callbacks to be sent to and
+/// looked at afterwards: it takes every request, whatever its method and path, and
+/// answers it the same, with no stubs to define. Its requests are kept in the journal,
+/// to be searched with `GET /{port}/bin` and looked through at `GET /{port}/bin/ui`.
+/// Tried after the failure rules, so that they can still fail some of the requests.
+#[derive(Clone, Debug, PartialEq, serde_derive::Deserialize, serde_derive::Serialize)]
+pub struct RequestBin {
+    /// A 2xx status, by default 200
+    #[serde(default = "default_status")]
+    pub status: u16,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub headers: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub body: String,
+}
+
+fn default_status() -> u16 {
+    200
+}
+
+/// The answer of a `RequestBin`, checked to be valid
+#[derive(Clone, Debug)]
+struct Answer {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: String,
+}
+
+impl RequestBin {
+    fn answer(&self) -> io::Result<Answer> {
+        let status = StatusCode::from_u16(self.status).ok()
+            .filter(StatusCode::is_success)
+            .ok_or_else(|| invalid(format!("the request bin answers with {}, not a 2xx status", self.status)))?;
+        let mut headers = HeaderMap::new();
+        for (name, value) in &self.headers {
+            let name = HeaderName::from_bytes(name.as_bytes())
+                .map_err(|_| invalid(format!("invalid request bin header name {:?}", name)))?;
+            let value = HeaderValue::from_str(value)
+                .map_err(|_| invalid(format!("invalid value {:?} of request bin header {}", value, name)))?;
+            headers.insert(name, value);
+        }
+        Ok(Answer { status, headers, body: self.body.clone() })
+    }
+
+    pub fn check(&self) -> io::Result<()> {
+        self.answer().map(drop)
+    }
+}
+
+/// Answers every request the same if the server is a request bin, otherwise leaves them
+/// to the routes after it. The body is read through for the journal to have all of it.
+pub fn filter(
+    bin: Option<&RequestBin>
+) -> io::Result<impl Filter<Extract = (warp::reply::Response,), Error = warp::Rejection> + Clone> {
+    let answer = bin.map(RequestBin::answer).transpose()?.map(Arc::new);
+    Ok(warp::any()
+        .and_then(move || answer.clone().ok_or_else(warp::reject::not_found))
+        .and(warp::body::concat())
+        .map(|answer: Arc<Answer>, _body: warp::body::FullBody| {
+            let mut reply = warp::http::Response::new(answer.body.clone().into());
+            *reply.status_mut() = answer.status;
+            reply.headers_mut().extend(answer.headers.clone());
+            reply
+        }))
+}
+
+/// A request as `GET /{port}/bin` shows it, taken apart: its query string and form body
+/// decoded, and its body parsed if it's JSON
+#[derive(Debug, serde_derive::Serialize)]
+pub struct BinRequest<'a> {
+    pub id: u64,
+    pub received_at_ms: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client: Option<IpAddr>,
+    pub method: &'a str,
+    /// Without the query string
+    pub path: &'a str,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub query: Vec<Header>,
+    pub headers: &'a [Header],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<&'a str>,
+    pub body: &'a journal::Body,
+    /// The body, if it's whole and JSON
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub json: Option<serde_json::Value>,
+    /// The fields of an `application/x-www-form-urlencoded` body
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub form: Vec<Header>,
+}
+
+/// The `name=value` pairs of a query string or form body, decoded
+fn pairs(encoded: &str) -> Vec<Header> {
+    let decode = |text: &str| objects::percent_decode(&text.replace('+', " "));
+    encoded.split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            Header { name: decode(name), value: decode(value) }
+        })
+        .collect()
+}
+
+fn header<'a>(headers: &'a [Header], name: &str) -> Option<&'a str> {
+    headers.iter().find(|header| header.name.eq_ignore_ascii_case(name)).map(|header| header.value.as_str())
+}
+
+impl<'a> BinRequest<'a> {
+    fn new(entry: &'a Entry) -> BinRequest<'a> {
+        let request = &entry.request;
+        let (path, query) = request.path.split_once('?').unwrap_or((&request.path, ""));
+        let content_type = header(&request.headers, "content-type");
+        let mime = content_type.and_then(|content_type| content_type.split(';').next()).map(str::trim);
+        let whole = !request.body.truncated && request.body.size > 0;
+        let json = Some(&request.body.text)
+            .filter(|_| whole && mime.is_none_or(|mime| mime.ends_with("json")))
+            .and_then(|text| serde_json::from_str(text).ok());
+        let form = match mime {
+            Some(mime) if whole && mime.eq_ignore_ascii_case("application/x-www-form-urlencoded") => pairs(&request.body.text),
+            _ => vec![],
+        };
+        BinRequest {
+            id: entry.id,
+            received_at_ms: entry.started_at_ms,
+            client: entry.client,
+            method: &request.method,
+            path,
+            query: pairs(query),
+            headers: &request.headers,
+            content_type,
+            body: &request.body,
+            json,
+            form,
+        }
+    }
+}
+
+/// Query parameters of `GET /{port}/bin`, matching the requests listed. Text is matched
+/// without case.
+#[derive(Debug, Default, serde_derive::Deserialize)]
+pub struct BinQuery {
+    #[serde(default)]
+    method: Option<String>,
+    /// Part of the path, without the query string
+    #[serde(default)]
+    path: Option<String>,
+    /// A header the request has, as `name`, or as `name:value` for one with part of its
+    /// value being `value`
+    #[serde(default)]
+    header: Option<String>,
+    /// Text anywhere in the request: its path and query string, header values or body
+    #[serde(default)]
+    q: Option<String>,
+    /// Only those recorded after the request of this id, to poll for new ones
+    #[serde(default)]
+    since: Option<u64>,
+    /// The newest this many at most, by default `DEFAULT_LIMIT`
+    #[serde(default)]
+    limit: Option<usize>,
+}
+
+fn contains(text: &str, part: &str) -> bool {
+    text.to_lowercase().contains(&part.to_lowercase())
+}
+
+impl BinQuery {
+    fn matches(&self, entry: &Entry) -> bool {
+        let request = &entry.request;
+        let path = request.path.split('?').next().unwrap_or_default();
+        let header = self.header.as_deref().map(|header| match header.split_once(':') {
+            Some((name, value)) => (name.trim(), Some(value.trim())),
+            None => (header.trim(), None),
+        });
+        self.since.is_none_or(|since| entry.id > since)
+            && self.method.as_ref().is_none_or(|method| request.method.eq_ignore_ascii_case(method))
+            && self.path.as_ref().is_none_or(|part| contains(path, part))
+            && header.is_none_or(|(name, value)| {
+                request.headers.iter()
+                    .any(|header| header.name.eq_ignore_ascii_case(name) && value.is_none_or(|value| contains(&header.value, value)))
+            })
+            && self.q.as_ref().is_none_or(|text| {
+                contains(&request.path, text)
+                    || request.headers.iter().any(|header| contains(&header.value, text))
+                    || contains(&request.body.text, text)
+            })
+    }
+}
+
+/// JSON body of `GET /{port}/bin`
+#[derive(Debug, serde_derive::Serialize)]
+struct BinJsonBody<'a> {
+    /// Requests in the journal, matching or not
+    stored: usize,
+    /// Requests matching the query, listed or not
+    matched: usize,
+    /// Newest first
+    requests: Vec<BinRequest<'a>>,
+}
+
+/// `GET /{port}/bin`: the requests the HTTP server on `port` recorded that match `query`,
+/// newest first, taken apart for reading
+pub fn list_bin(database: Database, port: u16, query: BinQuery) -> warp::reply::Response {
+    let journal = match journal::find_journal(&database, port) {
+        Ok(journal) => journal,
+        Err((status, error)) => return error_reply(status, &error),
+    };
+    let journal = lock(&journal);
+    let matching: Vec<&Entry> = journal.entries().filter(|entry| query.matches(entry)).collect();
+    let requests = matching.iter().rev()
+        .take(query.limit.unwrap_or(DEFAULT_LIMIT))
+        .map(|entry| BinRequest::new(entry))
+        .collect();
+    let body = BinJsonBody { stored: journal.entries().count(), matched: matching.len(), requests };
+    warp::reply::json(&body).into_response()
+}
+
+/// `GET /{port}/bin/{id}`: the request of `id` the HTTP server on `port` recorded
+pub fn get_bin_request(database: Database, port: u16, id: u64) -> warp::reply::Response {
+    let journal = match journal::find_journal(&database, port) {
+        Ok(journal) => journal,
+        Err((status, error)) => return error_reply(status, &error),
+    };
+    let journal = lock(&journal);
+    let reply = match journal.entries().find(|entry| entry.id == id) {
+        Some(entry) => warp::reply::json(&BinRequest::new(entry)).into_response(),
+        None => error_reply(StatusCode::NOT_FOUND, &format!("server {} has no request {}, or no longer", port, id)),
+    };
+    reply
+}
+
+/// `GET /{port}/bin/ui`: a page listing the requests of the server on `port` as they
+/// come, from `GET /{port}/bin`, with a search box and each request's details on a
+/// click. It asks for no admin token, so it only works without one.
+pub fn bin_ui(database: Database, port: u16) -> warp::reply::Response {
+    if let Err((status, error)) = journal::find_journal(&database, port) {
+        return error_reply(status, &error);
+    }
+    let page = UI.replace("{port}", &port.to_string());
+    let mut reply = warp::http::Response::new(page.into());
+    reply.headers_mut().insert(CONTENT_TYPE, HeaderValue::from_static("text/html; charset=utf-8"));
+    reply
+}
+
+const UI: &str = r##"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>Request bin on port {port}</title>
+<style>
+body { font-family: sans-serif; margin: 0; display: flex; height: 100vh; }
+#list { width: 40%; overflow-y: auto; border-right: 1px solid #ccc; }
+#detail { flex: 1; overflow-y: auto; padding: 0 1em; }
+#search { width: 100%; box-sizing: border-box; padding: .5em; border: 0; border-bottom: 1px solid #ccc; }
+.request { padding: .4em .6em; border-bottom: 1px solid #eee; cursor: pointer; font-family: monospace; }
+.request:hover, .selected { background: #eef; }
+.time { color: #888; float: right; }
+pre { background: #f6f6f6; padding: .5em; white-space: pre-wrap; word-break: break-all; }
+</style>
+</head>
+<body>
+<div id="list"><input id="search" placeholder="Search paths, headers and bodies"><div id="requests"></div></div>
+<div id="detail"><p>Requests sent to port {port} show up on the left.</p></div>
+<script>
+const requests = document.getElementById("requests");
+const search = document.getElementById("search");
+let selected = null;
+function text(tag, content) {
+  const element = document.createElement(tag);
+  element.textContent = content;
+  return element;
+}
+function show(request) {
+  selected = request.id;
+  const detail = document.getElementById("detail");
+  detail.replaceChildren(text("h3", request.method + " " + request.path));
+  detail.append(text("p", "#" + request.id + " from " + (request.client || "?") + " at " + new Date(request.received_at_ms).toISOString()));
+  for (const [title, pairs] of [["Query", request.query], ["Headers", request.headers], ["Form", request.form]]) {
+    if (pairs && pairs.length) {
+      detail.append(text("h4", title), text("pre", pairs.map(pair => pair.name + ": " + pair.value).join("\n")));
+    }
+  }
+  const body = request.json !== undefined ? JSON.stringify(request.json, null, 2) : request.body.text;
+  detail.append(text("h4", "Body (" + request.body.size + " bytes" + (request.body.truncated ? ", cut short" : "") + ")"), text("pre", body));
+  refresh();
+}
+async function refresh() {
+  const query = search.value ? "?q=" + encodeURIComponent(search.value) : "";
+  const response = await fetch("/{port}/bin" + query);
+  if (!response.ok) return;
+  const bin = await response.json();
+  requests.replaceChildren(...bin.requests.map(request => {
+    const row = text("div", request.method + " " + request.path);
+    row.className = "request" + (request.id === selected ? " selected" : "");
+    row.prepend(text("span", new Date(request.received_at_ms).toLocaleTimeString()));
+    row.firstChild.className = "time";
+    row.onclick = () => show(request);
+    return row;
+  }));
+}
+search.oninput = refresh;
+refresh();
+setInterval(refresh, 2000);
+</script>
+</body>
+</html>
+"##;
@@ -0,0 +1,78 @@
+use futures::Future;
+use hyper::client::connect::dns::{GaiResolver, Name, Resolve};
+
+use std::collections::BTreeMap;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, OnceLock};
+
+use crate::{dns, vhosts};
+
+/// The system's resolver, shared by every `Resolver` rather than each starting threads
+/// of its own to resolve on
+static SYSTEM: OnceLock<GaiResolver> = OnceLock::new();
+
+/// How an HTTP server resolves the host name of the upstream it forwards to, in place of
+/// the system's resolver, so that a session recorded against `api.example.com` can be
+/// replayed against a lab without editing `/etc/hosts`
+#[derive(Clone, Debug, Default, PartialEq, serde_derive::Deserialize, serde_derive::Serialize)]
+#[serde(default)]
+pub struct UpstreamDns {
+    /// Addresses of host names, like `{"api.example.com": "10.0.0.7"}`, looked up first
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub hosts: BTreeMap<String, IpAddr>,
+    /// A DNS server resolving the other names, like `10.0.0.53:53` or a DNS server of
+    /// this host's, asked for their A records over UDP
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub server: Option<SocketAddr>,
+}
+
+impl UpstreamDns {
+    pub fn is_default(&self) -> bool {
+        *self == UpstreamDns::default()
+    }
+}
+
+/// An `UpstreamDns` checked to be valid, resolving names for hyper's clients
+#[derive(Clone)]
+pub struct Resolver {
+    hosts: Arc<BTreeMap<String, IpAddr>>,
+    server: Option<SocketAddr>,
+    system: GaiResolver,
+}
+
+impl Default for Resolver {
+    /// The system's resolver
+    fn default() -> Resolver {
+        resolver(&UpstreamDns::default()).expect("no host names to check")
+    }
+}
+
+pub fn resolver(dns: &UpstreamDns) -> io::Result<Resolver> {
+    let mut hosts = BTreeMap::new();
+    for (host, address) in &dns.hosts {
+        if host.starts_with("*.") || !vhosts::valid_host(host) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("invalid upstream host name {:?}", host)));
+        }
+        hosts.insert(host.trim_end_matches('.').to_ascii_lowercase(), *address);
+    }
+    // As many threads as the resolver of `hyper::Client::new`
+    let system = SYSTEM.get_or_init(|| GaiResolver::new(4)).clone();
+    Ok(Resolver { hosts: Arc::new(hosts), server: dns.server, system })
+}
+
+impl Resolve for Resolver {
+    type Addrs = std::vec::IntoIter<IpAddr>;
+    type Future = Box<dyn Future<Item = Self::Addrs, Error = io::Error> + Send>;
+
+    fn resolve(&self, name: Name) -> Self::Future {
+        let host = name.as_str().trim_end_matches('.').to_ascii_lowercase();
+        if let Some(address) = self.hosts.get(&host) {
+            return Box::new(futures::future::ok(vec![*address].into_iter()));
+        }
+        match self.server {
+            Some(server) => Box::new(dns::resolve(server, &host).map(Vec::into_iter)),
+            None => Box::new(self.system.resolve(name).map(|addresses| addresses.collect::<Vec<_>>().into_iter())),
+        }
+    }
+}
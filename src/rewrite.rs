@@ -0,0 +1,306 @@
+use futures::Poll;
+use tokio::io::{AsyncRead, AsyncWrite};
+use warp::Reply;
+use warp::http::header::{HeaderName, HeaderValue};
+
+use std::io::{self, Read, Write};
+use std::sync::Arc;
+
+/// A request head longer than this is passed on as it is, as is the rest of its connection
+const MAX_HEAD_BYTES: usize = 64 * 1024;
+
+/// Headers parsed per request head, at most
+const MAX_HEADERS: usize = 100;
+
+/// Headers that frame the messages of a connection, which rules leave alone
+const FRAMING: [&str; 3] = ["content-length", "transfer-encoding", "connection"];
+
+/// What a `HeaderRule` does to its header
+#[derive(Clone, Copy, Debug, PartialEq, serde_derive::Deserialize, serde_derive::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HeaderAction {
+    /// Add the value alongside any the message has
+    Add,
+    /// Replace any value the message has
+    Set,
+    /// Strip the header
+    Remove,
+}
+
+/// A rule of an HTTP server rewriting a header of every request before any route sees it,
+/// or of every response once answered, like a gateway in front of it decorating traffic
+/// with auth or tracing headers. Rules apply in order.
+#[derive(Clone, Debug, PartialEq, serde_derive::Deserialize, serde_derive::Serialize)]
+pub struct HeaderRule {
+    pub action: HeaderAction,
+    /// Name of the header, case insensitive
+    pub header: String,
+    /// Left out to remove the header
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub value: Option<String>,
+    /// Rewrite responses rather than requests
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub response: bool,
+}
+
+fn invalid(error: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, error)
+}
+
+/// The header rules of a server, checked to be valid
+#[derive(Clone, Debug, Default)]
+pub struct HeaderRules {
+    requests: Arc<Vec<(HeaderAction, HeaderName, Option<HeaderValue>)>>,
+    responses: Arc<Vec<(HeaderAction, HeaderName, Option<HeaderValue>)>>,
+}
+
+impl HeaderRules {
+    pub fn new(rules: &[HeaderRule]) -> io::Result<HeaderRules> {
+        let mut requests = Vec::new();
+        let mut responses = Vec::new();
+        for rule in rules {
+            let name = HeaderName::from_bytes(rule.header.as_bytes())
+                .map_err(|_| invalid(format!("invalid header rule name {:?}", rule.header)))?;
+            if FRAMING.contains(&name.as_str()) {
+                return Err(invalid(format!("header rules leave {} alone", name)));
+            }
+            let value = match (rule.action, &rule.value) {
+                (HeaderAction::Remove, None) => None,
+                (HeaderAction::Remove, Some(_)) => return Err(invalid(format!("a rule removing {} has no value", name))),
+                (_, None) => return Err(invalid(format!("a rule adding or setting {} needs a value", name))),
+                (_, Some(value)) => Some(HeaderValue::from_str(value)
+                    .map_err(|_| invalid(format!("invalid value of header rule {}", name)))?),
+            };
+            let rules = if rule.response { &mut responses } else { &mut requests };
+            rules.push((rule.action, name, value));
+        }
+        Ok(HeaderRules { requests: Arc::new(requests), responses: Arc::new(responses) })
+    }
+
+    /// Whether the requests are rewritten, which takes reading them off the connection
+    pub fn rewrites_requests(&self) -> bool {
+        !self.requests.is_empty()
+    }
+
+    /// `reply` rewritten by the rules for responses
+    pub fn response(&self, reply: impl Reply) -> warp::reply::Response {
+        let mut response = reply.into_response();
+        let headers = response.headers_mut();
+        for (action, name, value) in self.responses.iter() {
+            match (action, value) {
+                (HeaderAction::Add, Some(value)) => {
+                    headers.append(name.clone(), value.clone());
+                }
+                (HeaderAction::Set, Some(value)) => {
+                    headers.insert(name.clone(), value.clone());
+                }
+                _ => {
+                    headers.remove(name);
+                }
+            }
+        }
+        response
+    }
+
+    /// A request head rewritten by the rules for requests, and how its body is framed, or
+    /// `None` if it isn't a request head
+    fn request_head(&self, head: &[u8]) -> Option<(Vec<u8>, Framing)> {
+        let mut parsed = [httparse::EMPTY_HEADER; MAX_HEADERS];
+        let mut request = httparse::Request::new(&mut parsed);
+        match request.parse(head) {
+            Ok(httparse::Status::Complete(_)) => {}
+            _ => return None,
+        }
+        let mut headers: Vec<(&[u8], &[u8])> = request.headers.iter()
+            .map(|header| (header.name.as_bytes(), header.value))
+            .collect();
+
+        let header = |name: &str| headers.iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name.as_bytes()))
+            .map(|(_, value)| String::from_utf8_lossy(value).to_ascii_lowercase());
+        let framing = if request.method == Some("CONNECT") || header("upgrade").is_some() {
+            Framing::Raw
+        } else if header("transfer-encoding").is_some_and(|encoding| encoding.contains("chunked")) {
+            Framing::ChunkSize
+        } else {
+            match header("content-length").map(|length| length.trim().parse::<usize>()) {
+                Some(Ok(0)) | None => Framing::Head,
+                Some(Ok(remaining)) => Framing::Body { remaining },
+                Some(Err(_)) => Framing::Raw,
+            }
+        };
+
+        for (action, name, value) in self.requests.iter() {
+            if *action != HeaderAction::Add {
+                headers.retain(|(header, _)| !header.eq_ignore_ascii_case(name.as_str().as_bytes()));
+            }
+            if let Some(value) = value {
+                headers.push((name.as_str().as_bytes(), value.as_bytes()));
+            }
+        }
+
+        let mut rewritten = Vec::with_capacity(head.len());
+        let line = format!("{} {} HTTP/1.{}\r\n", request.method.unwrap_or_default(), request.path.unwrap_or_default(), request.version.unwrap_or(1));
+        rewritten.extend_from_slice(line.as_bytes());
+        for (name, value) in headers {
+            rewritten.extend_from_slice(name);
+            rewritten.extend_from_slice(b": ");
+            rewritten.extend_from_slice(value);
+            rewritten.extend_from_slice(b"\r\n");
+        }
+        rewritten.extend_from_slice(b"\r\n");
+        Some((rewritten, framing))
+    }
+}
+
+/// Where a connection is in the request it's reading
+#[derive(Clone, Copy, Debug)]
+enum Framing {
+    Head,
+    Body { remaining: usize },
+    ChunkSize,
+    ChunkData { remaining: usize },
+    ChunkEnd,
+    Trailers,
+    /// Lost track of the framing, or upgraded; the rest is passed on as it is
+    Raw,
+}
+
+/// A server's side of a connection whose requests have their heads rewritten by the
+/// rules for requests as they're read, see `HeaderRules`
+pub struct Rewritten<T> {
+    inner: T,
+    rules: HeaderRules,
+    framing: Framing,
+    /// Read off the connection and not yet passed on, a partial head or chunk line
+    pending: Vec<u8>,
+    /// Passed on and not yet read
+    out: Vec<u8>,
+}
+
+impl<T> Rewritten<T> {
+    pub fn new(inner: T, rules: HeaderRules) -> Rewritten<T> {
+        let framing = if rules.rewrites_requests() { Framing::Head } else { Framing::Raw };
+        Rewritten { inner, rules, framing, pending: Vec::new(), out: Vec::new() }
+    }
+
+    fn feed(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            match self.framing {
+                Framing::Head => {
+                    self.pending.extend_from_slice(data);
+                    let end = match self.pending.windows(4).position(|window| window == b"\r\n\r\n") {
+                        Some(end) => end + 4,
+                        None => {
+                            if self.pending.len() > MAX_HEAD_BYTES {
+                                self.out.append(&mut self.pending);
+                                self.framing = Framing::Raw;
+                            }
+                            return;
+                        }
+                    };
+                    let rest = self.pending.split_off(end);
+                    let head = std::mem::take(&mut self.pending);
+                    match self.rules.request_head(&head) {
+                        Some((rewritten, framing)) => {
+                            self.out.extend_from_slice(&rewritten);
+                            self.framing = framing;
+                        }
+                        None => {
+                            self.out.extend_from_slice(&head);
+                            self.framing = Framing::Raw;
+                        }
+                    }
+                    return self.feed(&rest);
+                }
+                Framing::Body { remaining } | Framing::ChunkData { remaining } => {
+                    let length = remaining.min(data.len());
+                    self.out.extend_from_slice(&data[..length]);
+                    data = &data[length..];
+                    let remaining = remaining - length;
+                    self.framing = match self.framing {
+                        Framing::Body { .. } if remaining == 0 => Framing::Head,
+                        Framing::Body { .. } => Framing::Body { remaining },
+                        _ if remaining == 0 => Framing::ChunkEnd,
+                        _ => Framing::ChunkData { remaining },
+                    };
+                }
+                Framing::ChunkSize | Framing::ChunkEnd | Framing::Trailers => {
+                    let end = match data.iter().position(|byte| *byte == b'\n') {
+                        Some(end) => end + 1,
+                        None => {
+                            self.pending.extend_from_slice(data);
+                            if self.pending.len() > MAX_HEAD_BYTES {
+                                self.out.append(&mut self.pending);
+                                self.framing = Framing::Raw;
+                            }
+                            return;
+                        }
+                    };
+                    self.pending.extend_from_slice(&data[..end]);
+                    data = &data[end..];
+                    let line = std::mem::take(&mut self.pending);
+                    let text = String::from_utf8_lossy(&line);
+                    let text = text.trim();
+                    self.framing = match self.framing {
+                        Framing::ChunkSize => match text.split(';').next().and_then(|size| usize::from_str_radix(size.trim(), 16).ok()) {
+                            Some(0) => Framing::Trailers,
+                            Some(remaining) => Framing::ChunkData { remaining },
+                            None => Framing::Raw,
+                        },
+                        Framing::ChunkEnd => Framing::ChunkSize,
+                        _ if text.is_empty() => Framing::Head,
+                        _ => Framing::Trailers,
+                    };
+                    self.out.extend_from_slice(&line);
+                }
+                Framing::Raw => {
+                    self.out.extend_from_slice(data);
+                    data = &[];
+                }
+            }
+        }
+    }
+}
+
+impl<T: Read> Read for Rewritten<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if let Framing::Raw = self.framing {
+            if self.out.is_empty() {
+                return self.inner.read(buf);
+            }
+        }
+        while self.out.is_empty() {
+            let mut read = [0; 8192];
+            let length = self.inner.read(&mut read)?;
+            if length == 0 {
+                // A head cut short by the connection closing is passed on as it is
+                self.out.append(&mut self.pending);
+                break;
+            }
+            self.feed(&read[..length]);
+        }
+        let length = buf.len().min(self.out.len());
+        buf[..length].copy_from_slice(&self.out[..length]);
+        self.out.drain(..length);
+        Ok(length)
+    }
+}
+
+impl<T: Write> Write for Rewritten<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<T: AsyncRead> AsyncRead for Rewritten<T> {}
+
+impl<T: AsyncWrite> AsyncWrite for Rewritten<T> {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        self.inner.shutdown()
+    }
+}
@@ -0,0 +1,172 @@
+use futures::{Future, Poll};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::runtime::current_thread;
+
+use std::io::{self, Read, Write};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Every task spawned with `spawn` or `spawn_for` that hasn't completed yet
+pub static TASKS: Tasks = Tasks(AtomicUsize::new(0));
+
+/// Settings of the shared tokio runtime, from `WORKER_THREADS` and `BLOCKING_THREADS`.
+/// Left unset, tokio's defaults apply.
+#[derive(Clone, Debug, Default)]
+pub struct RuntimeConfig {
+    /// Threads running tasks, by default one per CPU
+    pub worker_threads: Option<usize>,
+    /// Threads running blocking work like file I/O, by default 100
+    pub blocking_threads: Option<usize>,
+}
+
+impl RuntimeConfig {
+    pub fn from_env() -> Result<RuntimeConfig, String> {
+        let threads = |var: &str| match std::env::var(var) {
+            Ok(threads) => threads.parse().ok()
+                .filter(|threads| *threads > 0)
+                .map(Some)
+                .ok_or_else(|| format!("invalid {} {:?}", var, threads)),
+            Err(_) => Ok(None),
+        };
+
+        Ok(RuntimeConfig {
+            worker_threads: threads("WORKER_THREADS")?,
+            blocking_threads: threads("BLOCKING_THREADS")?,
+        })
+    }
+
+    fn build(&self) -> io::Result<tokio::runtime::Runtime> {
+        let mut builder = tokio::runtime::Builder::new();
+        if let Some(threads) = self.worker_threads {
+            builder.core_threads(threads);
+        }
+        if let Some(threads) = self.blocking_threads {
+            builder.blocking_threads(threads);
+        }
+        builder.build()
+    }
+}
+
+/// Like `tokio::run`, on a runtime configured from the environment
+pub fn run<F>(future: F)
+where
+    F: Future<Item = (), Error = ()> + Send + 'static,
+{
+    let mut runtime = RuntimeConfig::from_env()
+        .unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        })
+        .build()
+        .unwrap_or_else(|e| {
+            eprintln!("failed to start runtime: {}", e);
+            std::process::exit(1);
+        });
+
+    runtime.spawn(future);
+    runtime.shutdown_on_idle().wait().ok();
+}
+
+/// Start a single-threaded runtime on a thread of its own, named after `name`. It runs
+/// until the process exits.
+pub fn spawn_dedicated(name: &str) -> io::Result<current_thread::Handle> {
+    let (handle_tx, handle_rx) = std::sync::mpsc::channel();
+
+    std::thread::Builder::new()
+        .name(format!("runtime-{}", name))
+        .spawn(move || {
+            let mut runtime = match current_thread::Runtime::new() {
+                Ok(runtime) => runtime,
+                Err(e) => return handle_tx.send(Err(e)).unwrap_or(()),
+            };
+            let _ = handle_tx.send(Ok(runtime.handle()));
+            let _ = runtime.block_on(futures::future::empty::<(), ()>());
+        })?;
+
+    handle_rx.recv()
+        .map_err(|_| io::Error::other("runtime thread exited"))?
+}
+
+/// A count of running tasks, like those spawned for a server, see `spawn_for`
+#[derive(Debug, Default)]
+pub struct Tasks(AtomicUsize);
+
+impl Tasks {
+    pub fn active(&self) -> usize {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+/// `future`, counted in `TASKS` and in `tasks`, if given, until it completes or is dropped.
+/// A connection is counted until it's closed, standing for the task serving it.
+pub fn counted<F>(tasks: Option<Arc<Tasks>>, future: F) -> Counted<F> {
+    TASKS.0.fetch_add(1, Ordering::SeqCst);
+    if let Some(tasks) = &tasks {
+        tasks.0.fetch_add(1, Ordering::SeqCst);
+    }
+    Counted { inner: future, tasks }
+}
+
+/// Like `tokio::spawn`, counting the task in `TASKS`
+pub fn spawn<F>(future: F)
+where
+    F: Future<Item = (), Error = ()> + Send + 'static,
+{
+    tokio::spawn(counted(None, future));
+}
+
+/// Like `spawn`, also counting the task in `tasks`
+pub fn spawn_for<F>(tasks: &Arc<Tasks>, future: F)
+where
+    F: Future<Item = (), Error = ()> + Send + 'static,
+{
+    tokio::spawn(counted(Some(tasks.clone()), future));
+}
+
+/// A future or connection counted as a running task, see `counted`
+pub struct Counted<T> {
+    inner: T,
+    tasks: Option<Arc<Tasks>>,
+}
+
+impl<F: Future> Future for Counted<F> {
+    type Item = F::Item;
+    type Error = F::Error;
+
+    fn poll(&mut self) -> Poll<F::Item, F::Error> {
+        self.inner.poll()
+    }
+}
+
+impl<T> Drop for Counted<T> {
+    fn drop(&mut self) {
+        TASKS.0.fetch_sub(1, Ordering::SeqCst);
+        if let Some(tasks) = &self.tasks {
+            tasks.0.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+}
+
+impl<T: Read> Read for Counted<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+impl<T: Write> Write for Counted<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<T: AsyncRead> AsyncRead for Counted<T> {}
+
+impl<T: AsyncWrite> AsyncWrite for Counted<T> {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        self.inner.shutdown()
+    }
+}
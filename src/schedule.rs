@@ -0,0 +1,374 @@
+use futures::{Future, Stream};
+use tokio::timer::Interval;
+
+use std::io;
+use std::time::Duration;
+
+use crate::{start_server, unix_time, Database, Error, Registry, ServerStatus};
+use crate::error::lock;
+use crate::runtime;
+use crate::watch::ChangeType;
+
+const TICK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How often a server being restarted is checked for having stopped
+const RESTART_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Something done to a server on schedule
+#[derive(Clone, Copy, Debug, PartialEq, serde_derive::Deserialize, serde_derive::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ScheduledAction {
+    Start,
+    Stop,
+    /// Start the server anew, handing its listener over so no connection is refused
+    Restart,
+    /// Forget the requests an in-process HTTP server has recorded
+    ClearJournal,
+}
+
+/// An action taken on a server at the times a cron expression matches
+#[derive(Clone, Debug, PartialEq, serde_derive::Deserialize, serde_derive::Serialize)]
+pub struct Schedule {
+    /// Minute, hour, day of month, month and day of week, in UTC, like `0 3 * * *` for
+    /// every night at three, or one of `@hourly`, `@daily`, `@weekly` and `@monthly`
+    pub cron: String,
+    pub action: ScheduledAction,
+}
+
+/// The values a cron field matches, as bits
+type Field = u64;
+
+/// A parsed cron expression
+#[derive(Debug)]
+struct Cron {
+    minutes: Field,
+    hours: Field,
+    days: Field,
+    months: Field,
+    weekdays: Field,
+    /// Whether the day of month and of week were both restricted, either one matching
+    either_day: bool,
+}
+
+impl Cron {
+    fn parse(expression: &str) -> Result<Cron, String> {
+        let expression = match expression.trim() {
+            "@hourly" => "0 * * * *",
+            "@daily" | "@midnight" => "0 0 * * *",
+            "@weekly" => "0 0 * * 0",
+            "@monthly" => "0 0 1 * *",
+            expression => expression,
+        };
+        let fields: Vec<&str> = expression.split_whitespace().collect();
+        let [minutes, hours, days, months, weekdays] = fields.as_slice() else {
+            return Err(format!("cron expression {:?} doesn't have 5 fields", expression));
+        };
+
+        // Sunday is both 0 and 7
+        let weekdays_field = field(weekdays, 0, 7)?;
+        Ok(Cron {
+            minutes: field(minutes, 0, 59)?,
+            hours: field(hours, 0, 23)?,
+            days: field(days, 1, 31)?,
+            months: field(months, 1, 12)?,
+            weekdays: weekdays_field | (weekdays_field >> 7 & 1),
+            either_day: !days.starts_with('*') && !weekdays.starts_with('*'),
+        })
+    }
+
+    /// Whether the expression matches the minute starting `unix_time` seconds since the epoch
+    fn matches(&self, unix_time: u64) -> bool {
+        let minute = unix_time / 60;
+        let days = minute / (24 * 60);
+        let (_, month, day) = civil_from_days(days);
+        // The epoch was a Thursday
+        let weekday = (days + 4) % 7;
+
+        let bit = |field: Field, value: u64| field >> value & 1 == 1;
+        let day_matches = if self.either_day {
+            bit(self.days, day) || bit(self.weekdays, weekday)
+        } else {
+            bit(self.days, day) && bit(self.weekdays, weekday)
+        };
+        bit(self.minutes, minute % 60) && bit(self.hours, minute / 60 % 24) && bit(self.months, month) && day_matches
+    }
+}
+
+/// Parse a cron field: `*` or comma separated values or ranges, each optionally stepped,
+/// like `*/15` or `1-5,10`
+fn field(field: &str, min: u64, max: u64) -> Result<Field, String> {
+    let invalid = || format!("invalid cron field {:?}", field);
+    let number = |number: &str| number.parse::<u64>().ok().filter(|n| (min..=max).contains(n)).ok_or_else(invalid);
+
+    let mut bits = 0;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, step.parse::<u64>().ok().filter(|step| *step > 0).ok_or_else(invalid)?),
+            None => (part, 1),
+        };
+        let (first, last) = match range {
+            "*" => (min, max),
+            range => match range.split_once('-') {
+                Some((first, last)) => (number(first)?, number(last)?),
+                None if step > 1 => (number(range)?, max),
+                None => (number(range)?, number(range)?),
+            },
+        };
+        if first > last {
+            return Err(invalid());
+        }
+        for value in (first..=last).step_by(step as usize) {
+            bits |= 1 << value;
+        }
+    }
+    Ok(bits)
+}
+
+/// The year, month and day of `days` since the epoch, after Howard Hinnant's algorithm
+fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    let days = days + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days % 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 { shifted_month + 3 } else { shifted_month - 9 };
+    let year = era * 400 + year_of_era + u64::from(month <= 2);
+    (year, month, day)
+}
+
+/// Fail unless every schedule's cron expression is valid
+pub fn check(schedules: &[Schedule]) -> io::Result<()> {
+    for schedule in schedules {
+        Cron::parse(&schedule.cron).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    }
+    Ok(())
+}
+
+/// Take the scheduled actions of every server as each minute starts, run as a
+/// `background::Background` task
+pub fn run_schedules(database: Database) -> impl Future<Item = (), Error = ()> {
+    let mut last_minute = unix_time() / 60;
+    Interval::new_interval(TICK_INTERVAL)
+        .map_err(|e| eprintln!("scheduler timer error: {}", e))
+        .for_each(move |_| {
+            let minute = unix_time() / 60;
+            if minute == last_minute {
+                return Ok(());
+            }
+            last_minute = minute;
+
+            let mut registry = lock(&database);
+            let mut due: Vec<(u16, ScheduledAction)> = registry.servers.values()
+                .flat_map(|server| server.config.schedules.iter().map(move |schedule| (server.config.port, schedule)))
+                .filter(|(_, schedule)| Cron::parse(&schedule.cron).is_ok_and(|cron| cron.matches(minute * 60)))
+                .map(|(port, schedule)| (port, schedule.action))
+                .collect();
+            due.sort_by_key(|(port, _)| *port);
+
+            for (port, action) in due {
+                eprintln!("server {}: scheduled {:?}", port, action);
+                take_action(&database, &mut registry, port, action);
+            }
+            Ok(())
+        })
+}
+
+fn take_action(database: &Database, registry: &mut Registry, port: u16, action: ScheduledAction) {
+    let server = match registry.servers.get_mut(&port) {
+        Some(server) => server,
+        None => return,
+    };
+    let status = server.status;
+
+    match action {
+        ScheduledAction::Start | ScheduledAction::Restart if status.can_become(ServerStatus::Starting) => {
+            let config = server.config.clone();
+            let restarts = server.restarts;
+            if let Err(e) = start_server(database, registry, config, restarts) {
+                eprintln!("failed to start server {} on schedule: {}", port, e);
+            }
+        }
+        ScheduledAction::Stop if status.can_become(ServerStatus::Draining) => {
+            server.status = ServerStatus::Draining;
+            server.signal_shutdown();
+            registry.record_change(port, ChangeType::Modified);
+        }
+        ScheduledAction::Restart if status.can_become(ServerStatus::Draining) => {
+            if let Err(e) = restart(database, registry, port) {
+                eprintln!("failed to restart server {} on schedule: {}", port, e);
+            }
+        }
+        ScheduledAction::ClearJournal => {
+            if let Some(journal) = &server.journal {
+                lock(journal).clear();
+            }
+        }
+        _ => eprintln!("server {} is {:?}, skipping scheduled {:?}", port, status, action),
+    }
+}
+
+/// Start the server on `port` anew, handing its listener over so no connection is
+/// refused. A server listening in a subprocess or container has to let go of its port
+/// first, so it's started once it's stopped.
+pub fn restart(database: &Database, registry: &mut Registry, port: u16) -> Result<(), Error> {
+    let server = registry.servers.get_mut(&port).expect("restarted servers exist");
+    let config = server.config.clone();
+    let restarts = server.restarts;
+    server.signal_shutdown();
+    match server.listener.take() {
+        Some(listener) => {
+            registry.inherited_listeners.insert(port, listener);
+            start_server(database, registry, config, restarts)
+        }
+        None => {
+            server.status = ServerStatus::Draining;
+            let id = server.id;
+            registry.record_change(port, ChangeType::Modified);
+            start_once_stopped(database.clone(), port, id);
+            Ok(())
+        }
+    }
+}
+
+/// Start the server spawned as `id` on `port` once it's stopped, unless it's replaced or
+/// deleted before
+fn start_once_stopped(database: Database, port: u16, id: usize) {
+    let draining = database.clone();
+    runtime::spawn(Interval::new_interval(RESTART_POLL_INTERVAL)
+        .map_err(|e| eprintln!("scheduler timer error: {}", e))
+        .take_while(move |_| {
+            let registry = lock(&draining);
+            let draining = registry.servers.get(&port)
+                .is_some_and(|server| server.id == id && server.status == ServerStatus::Draining);
+            Ok(draining)
+        })
+        .for_each(|_| Ok(()))
+        .map(move |_| {
+            let mut registry = lock(&database);
+            let config = match registry.servers.get(&port) {
+                Some(server) if server.id == id && server.status == ServerStatus::Stopped => server.config.clone(),
+                _ => return,
+            };
+            let restarts = registry.servers[&port].restarts;
+            if let Err(e) = start_server(&database, &mut registry, config, restarts) {
+                eprintln!("failed to restart server {} on schedule: {}", port, e);
+            }
+        }));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Unix time of `hour`:`minute` UTC on the day of `date`
+    fn at(date: (u64, u64, u64), hour: u64, minute: u64) -> u64 {
+        let days = (0..).find(|days| civil_from_days(*days) == date).unwrap();
+        days * 24 * 60 * 60 + hour * 60 * 60 + minute * 60
+    }
+
+    // 2024-03-03 was a Sunday
+    const SUNDAY: (u64, u64, u64) = (2024, 3, 3);
+    const MONDAY: (u64, u64, u64) = (2024, 3, 4);
+    const FRIDAY: (u64, u64, u64) = (2024, 3, 8);
+    const SATURDAY: (u64, u64, u64) = (2024, 3, 9);
+
+    fn matching_minutes(expression: &str) -> Vec<u64> {
+        let cron = Cron::parse(expression).unwrap();
+        (0..60).filter(|minute| cron.matches(at(MONDAY, 10, *minute))).collect()
+    }
+
+    #[test]
+    fn steps_through_fields() {
+        assert_eq!(matching_minutes("*/15 * * * *"), [0, 15, 30, 45]);
+        assert_eq!(matching_minutes("5/20 * * * *"), [5, 25, 45]);
+        assert_eq!(matching_minutes("10-30/10 * * * *"), [10, 20, 30]);
+        assert_eq!(matching_minutes("0-2,58-59 * * * *"), [0, 1, 2, 58, 59]);
+        assert_eq!(matching_minutes("7 * * * *"), [7]);
+    }
+
+    #[test]
+    fn matches_ranges_of_hours_and_weekdays() {
+        let cron = Cron::parse("0 9-17 * * 1-5").unwrap();
+        assert!(cron.matches(at(MONDAY, 9, 0)));
+        assert!(cron.matches(at(FRIDAY, 17, 0)));
+        assert!(!cron.matches(at(FRIDAY, 18, 0)));
+        assert!(!cron.matches(at(MONDAY, 9, 1)));
+        assert!(!cron.matches(at(SATURDAY, 10, 0)));
+        assert!(!cron.matches(at(SUNDAY, 10, 0)));
+    }
+
+    #[test]
+    fn takes_sunday_as_7_too() {
+        for expression in ["0 0 * * 0", "0 0 * * 7", "0 0 * * 6-7"] {
+            let cron = Cron::parse(expression).unwrap();
+            assert!(cron.matches(at(SUNDAY, 0, 0)), "{}", expression);
+            assert!(!cron.matches(at(MONDAY, 0, 0)), "{}", expression);
+        }
+        assert!(Cron::parse("0 0 * * 6-7").unwrap().matches(at(SATURDAY, 0, 0)));
+    }
+
+    #[test]
+    fn matches_either_day_when_both_are_restricted() {
+        // The 13th, or any Friday
+        let cron = Cron::parse("0 0 13 * 5").unwrap();
+        assert!(cron.either_day);
+        assert!(cron.matches(at(FRIDAY, 0, 0)));
+        assert!(cron.matches(at((2024, 3, 13), 0, 0)));
+        assert!(!cron.matches(at((2024, 3, 14), 0, 0)));
+
+        // Only the day of month is restricted, so the day of week is no alternative
+        let cron = Cron::parse("0 0 13 * *").unwrap();
+        assert!(!cron.either_day);
+        assert!(cron.matches(at((2024, 3, 13), 0, 0)));
+        assert!(!cron.matches(at(FRIDAY, 0, 0)));
+
+        let cron = Cron::parse("0 0 * * 5").unwrap();
+        assert!(!cron.either_day);
+        assert!(cron.matches(at(FRIDAY, 0, 0)));
+        assert!(!cron.matches(at((2024, 3, 13), 0, 0)));
+    }
+
+    #[test]
+    fn expands_macros() {
+        for (macro_, expression) in [
+            ("@hourly", "0 * * * *"),
+            ("@daily", "0 0 * * *"),
+            ("@midnight", "0 0 * * *"),
+            ("@weekly", "0 0 * * 0"),
+            ("@monthly", "0 0 1 * *"),
+        ] {
+            let (expanded, parsed) = (Cron::parse(macro_).unwrap(), Cron::parse(expression).unwrap());
+            let fields = |cron: &Cron| (cron.minutes, cron.hours, cron.days, cron.months, cron.weekdays, cron.either_day);
+            assert_eq!(fields(&expanded), fields(&parsed), "{}", macro_);
+        }
+        assert!(Cron::parse("@hourly").unwrap().matches(at(MONDAY, 5, 0)));
+        assert!(!Cron::parse("@hourly").unwrap().matches(at(MONDAY, 5, 1)));
+        assert!(Cron::parse("@weekly").unwrap().matches(at(SUNDAY, 0, 0)));
+        assert!(Cron::parse("@monthly").unwrap().matches(at((2024, 3, 1), 0, 0)));
+        assert!(!Cron::parse("@monthly").unwrap().matches(at((2024, 3, 2), 0, 0)));
+    }
+
+    #[test]
+    fn refuses_invalid_expressions() {
+        for expression in ["60 * * * *", "* 24 * * *", "* * 0 * *", "* * * 13 *", "* * * * 8", "*/0 * * * *", "30-10 * * * *", "* * * *", "* * * * * *", "@yearly", "a * * * *"] {
+            assert!(Cron::parse(expression).is_err(), "{}", expression);
+        }
+    }
+
+    #[test]
+    fn converts_days_to_dates_across_leap_days() {
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(civil_from_days(19_782), (2024, 2, 29));
+        assert_eq!(civil_from_days(19_783), (2024, 3, 1));
+        // 2000 is a leap year, being divisible by 400, and 2100 isn't
+        assert_eq!(civil_from_days(11_016), (2000, 2, 29));
+        assert_eq!(civil_from_days(47_540), (2100, 2, 28));
+        assert_eq!(civil_from_days(47_541), (2100, 3, 1));
+
+        let leap_day = Cron::parse("0 12 29 2 *").unwrap();
+        assert!(leap_day.matches(at((2024, 2, 29), 12, 0)));
+        assert!(!leap_day.matches(at((2024, 3, 1), 12, 0)));
+    }
+}
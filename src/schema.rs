@@ -0,0 +1,108 @@
+use warp::Reply;
+
+use std::io;
+#[cfg(feature = "schema")]
+use std::sync::Arc;
+
+/// The header of the 422 answering a request that violates a schema, naming what kind of
+/// schema, by which the journal flags the request, see `journal::Entry::violation`
+pub const VIOLATION_HEADER: &str = "x-mock-violation";
+
+/// Where a request departs from a schema
+#[derive(Debug, serde_derive::Serialize)]
+pub struct Violation {
+    /// The part of the request, like `query` or `header`, if it's not the body
+    #[serde(rename = "in", skip_serializing_if = "Option::is_none")]
+    pub location: Option<&'static str>,
+    /// JSON Pointer to the offending value of the body, empty for the whole body, or the
+    /// name of the offending parameter
+    pub path: String,
+    pub message: String,
+}
+
+/// JSON body of the 422 answering a request whose body violates a schema
+#[derive(Debug, serde_derive::Serialize)]
+struct ViolationsJsonBody {
+    error: String,
+    violations: Vec<Violation>,
+}
+
+fn invalid(error: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, error)
+}
+
+/// A JSON Schema the bodies of the requests a header route, fallback or OpenAPI operation
+/// takes must conform to, compiled when the server starts. Other bodies are answered with
+/// 422 instead of being forwarded or answered as usual.
+#[cfg(feature = "schema")]
+#[derive(Clone)]
+pub struct Schema(Arc<jsonschema::Validator>);
+
+#[cfg(not(feature = "schema"))]
+#[derive(Clone)]
+pub enum Schema {}
+
+impl Schema {
+    #[cfg(feature = "schema")]
+    pub fn compile(schema: &serde_json::Value) -> io::Result<Schema> {
+        jsonschema::validator_for(schema)
+            .map(|validator| Schema(Arc::new(validator)))
+            .map_err(|e| invalid(format!("invalid JSON Schema: {}", e)))
+    }
+
+    /// `schema` as draft 4 has it, which the schemas of OpenAPI 3.0 documents follow
+    #[cfg(feature = "schema")]
+    pub fn compile_draft4(schema: &serde_json::Value) -> io::Result<Schema> {
+        jsonschema::draft4::new(schema)
+            .map(|validator| Schema(Arc::new(validator)))
+            .map_err(|e| invalid(format!("invalid JSON Schema: {}", e)))
+    }
+
+    #[cfg(not(feature = "schema"))]
+    pub fn compile(_schema: &serde_json::Value) -> io::Result<Schema> {
+        Err(invalid("checking request bodies needs this server to be built with the `schema` feature".to_string()))
+    }
+
+    #[cfg(not(feature = "schema"))]
+    pub fn compile_draft4(schema: &serde_json::Value) -> io::Result<Schema> {
+        Schema::compile(schema)
+    }
+
+    /// Where `body` departs from the schema
+    #[cfg(feature = "schema")]
+    pub fn violations(&self, body: &[u8]) -> Vec<Violation> {
+        match serde_json::from_slice::<serde_json::Value>(body) {
+            Ok(body) => self.0.iter_errors(&body)
+                .map(|error| Violation { location: None, path: error.instance_path.to_string(), message: error.to_string() })
+                .collect(),
+            Err(e) => vec![Violation { location: None, path: String::new(), message: format!("invalid JSON: {}", e) }],
+        }
+    }
+
+    #[cfg(not(feature = "schema"))]
+    pub fn violations(&self, _body: &[u8]) -> Vec<Violation> {
+        match *self {}
+    }
+
+    /// The 422 to answer a request with `body` with, unless it conforms
+    pub fn check(&self, body: &[u8]) -> Option<warp::reply::Response> {
+        let violations = self.violations(body);
+        if violations.is_empty() {
+            return None;
+        }
+        Some(reply("schema", "the request body doesn't conform to the schema", violations))
+    }
+}
+
+/// The 422 answering a request with `violations` of what `kind` of schema it's checked
+/// against, flagged with `kind` in the journal
+pub fn reply(kind: &'static str, error: &str, violations: Vec<Violation>) -> warp::reply::Response {
+    let body = ViolationsJsonBody { error: error.to_string(), violations };
+    let reply = warp::reply::with_status(warp::reply::json(&body), warp::http::StatusCode::UNPROCESSABLE_ENTITY);
+    warp::reply::with_header(reply, VIOLATION_HEADER, kind).into_response()
+}
+
+/// `schema` compiled, if there's one
+pub fn compile(schema: Option<&serde_json::Value>) -> io::Result<Option<Schema>> {
+    schema.map(Schema::compile).transpose()
+}
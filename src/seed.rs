@@ -0,0 +1,102 @@
+use warp::Reply;
+use warp::http::StatusCode;
+
+use std::sync::{Arc, Mutex};
+
+use crate::{error_reply, watch, Database};
+use crate::error::lock;
+
+/// JSON body of `GET|PUT /{port}/seed`
+#[derive(Clone, Copy, Debug, PartialEq, serde_derive::Deserialize, serde_derive::Serialize)]
+pub struct SeedBody {
+    pub seed: u64,
+}
+
+/// The seed of every random choice of an in-process HTTP server, kept across restarts:
+/// the delays and failures of its profile, and the bodies it makes up. Setting it starts
+/// what's made from it over, so that a run can be repeated exactly.
+#[derive(Clone, Debug, Default)]
+pub struct SharedSeed(Arc<Mutex<Seed>>);
+
+#[derive(Clone, Copy, Debug, Default)]
+struct Seed {
+    value: u64,
+    /// Times the seed was set, for what's made from it to tell it's been
+    generation: u64,
+}
+
+impl SharedSeed {
+    /// A seed of its own, never set again
+    pub fn new(value: u64) -> SharedSeed {
+        SharedSeed(Arc::new(Mutex::new(Seed { value, generation: 0 })))
+    }
+
+    pub fn get(&self) -> u64 {
+        lock(&self.0).value
+    }
+
+    pub fn set(&self, value: u64) {
+        let mut seed = lock(&self.0);
+        seed.value = value;
+        seed.generation += 1;
+    }
+}
+
+/// A generator made from a `SharedSeed`, and made again whenever the seed is set
+pub struct Reseeded<T> {
+    seed: SharedSeed,
+    generation: u64,
+    make: fn(u64) -> T,
+    value: T,
+}
+
+impl<T> Reseeded<T> {
+    pub fn new(seed: SharedSeed, make: fn(u64) -> T) -> Reseeded<T> {
+        let Seed { value, generation } = *lock(&seed.0);
+        Reseeded { seed, generation, make, value: make(value) }
+    }
+
+    pub fn get(&mut self) -> &mut T {
+        let seed = *lock(&self.seed.0);
+        if seed.generation != self.generation {
+            self.value = (self.make)(seed.value);
+            self.generation = seed.generation;
+        }
+        &mut self.value
+    }
+}
+
+/// The seed of the server on `port`, or the status and error to answer with if it has none
+pub fn find_seed(database: &Database, port: u16) -> Result<SharedSeed, (StatusCode, String)> {
+    let registry = lock(database);
+    let server = registry.servers.get(&port)
+        .ok_or_else(|| (StatusCode::NOT_FOUND, format!("no server on port {}", port)))?;
+    server.seed.clone()
+        .ok_or_else(|| (StatusCode::CONFLICT, "only in-process HTTP servers have a seed".to_string()))
+}
+
+/// `GET /{port}/seed`: the seed of the server on `port`, taken from the clock when it
+/// started unless it was given one, to repeat the run with
+pub fn get_seed(database: Database, port: u16) -> warp::reply::Response {
+    match find_seed(&database, port) {
+        Ok(seed) => warp::reply::json(&SeedBody { seed: seed.get() }).into_response(),
+        Err((status, error)) => error_reply(status, &error),
+    }
+}
+
+/// `PUT /{port}/seed`: start the random choices of the server on `port` over from `body`.
+/// The bodies it makes up from schemas when it starts are made up from the seed on its
+/// next start.
+pub fn put_seed(database: Database, port: u16, body: SeedBody) -> warp::reply::Response {
+    let shared = match find_seed(&database, port) {
+        Ok(seed) => seed,
+        Err((status, error)) => return error_reply(status, &error),
+    };
+    shared.set(body.seed);
+    let mut registry = lock(&database);
+    if let Some(server) = registry.servers.get_mut(&port) {
+        server.config.seed = Some(body.seed);
+        registry.record_change(port, watch::ChangeType::Modified);
+    }
+    warp::reply::json(&body).into_response()
+}
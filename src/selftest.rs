@@ -0,0 +1,264 @@
+use futures::Future;
+
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::time::{Duration, Instant};
+
+use crate::{ports, system, PortAllocator, TestInstance};
+
+/// Open files below which servers can't be had at all, each taking a listener and a
+/// descriptor per connection
+const MIN_FDS: u64 = 256;
+
+/// Open files below which a long suite may run out
+const RECOMMENDED_FDS: u64 = 4096;
+
+/// Ports the allocator is asked for, each of which must be free to bind
+const PORT_PROBES: usize = 16;
+
+/// How long a request of the self-test may take
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Outcome {
+    Ok,
+    Warning,
+    Failed,
+}
+
+/// Print the outcome of a check, like `ok    open files   soft limit 1024`
+fn report(outcome: Outcome, check: &str, detail: &str) {
+    let outcome = match outcome {
+        Outcome::Ok => "ok",
+        Outcome::Warning => "warn",
+        Outcome::Failed => "FAIL",
+    };
+    println!("{:<5} {:<14} {}", outcome, check, detail);
+}
+
+/// `--self-test`: check that the environment can hold servers, then start the admin API
+/// on a free port and have it create a server, stub it, serve it a request, verify that
+/// request and delete it, as a suite would. Prints the outcome of every check and exits
+/// nonzero if any failed, for CI to tell the tool works before a suite depends on it.
+pub fn run() {
+    let mut failed = !check_fds();
+    let port_allocator = match std::env::var("PORT_ALLOCATOR") {
+        Ok(spec) => ports::parse_allocator(&spec),
+        Err(_) => Ok(Box::default()),
+    };
+    match port_allocator {
+        Ok(mut port_allocator) => {
+            failed |= !check_ports(&mut *port_allocator);
+            failed |= !check_round_trip(port_allocator);
+        }
+        Err(e) => {
+            report(Outcome::Failed, "ports", &e);
+            failed = true;
+        }
+    }
+
+    if failed {
+        println!("self-test failed");
+        std::process::exit(1);
+    }
+    println!("self-test passed");
+}
+
+/// Whether the soft limit on open files leaves room for servers
+fn check_fds() -> bool {
+    match system::max_fds() {
+        None => report(Outcome::Ok, "open files", "no soft limit known"),
+        Some(limit) if limit < MIN_FDS => {
+            report(Outcome::Failed, "open files", &format!("soft limit {}, below {}", limit, MIN_FDS));
+            return false;
+        }
+        Some(limit) if limit < RECOMMENDED_FDS => {
+            let detail = format!("soft limit {}, below the {} a long suite may need, see ulimit -n", limit, RECOMMENDED_FDS);
+            report(Outcome::Warning, "open files", &detail);
+        }
+        Some(limit) => report(Outcome::Ok, "open files", &format!("soft limit {}", limit)),
+    }
+    true
+}
+
+/// Whether the ports the allocator hands out, with `PORT_ALLOCATOR`, are free to bind
+fn check_ports(port_allocator: &mut dyn PortAllocator) -> bool {
+    let mut allocated: Vec<u16> = Vec::new();
+    let mut error = None;
+    while allocated.len() < PORT_PROBES {
+        let port = match port_allocator.allocate(&|port| allocated.contains(&port)) {
+            Ok(port) => port,
+            Err(e) => {
+                error = Some(e.to_string());
+                break;
+            }
+        };
+        if let Err(e) = std::net::TcpListener::bind(("127.0.0.1", port)) {
+            error = Some(format!("allocated port {}, which can't be bound: {}", port, e));
+            break;
+        }
+        allocated.push(port);
+    }
+
+    let free = allocated.len();
+    match error {
+        Some(error) if free == 0 => {
+            report(Outcome::Failed, "ports", &error);
+            false
+        }
+        Some(error) => {
+            report(Outcome::Warning, "ports", &format!("{} ports free before the allocator ran out: {}", free, error));
+            true
+        }
+        None => {
+            report(Outcome::Ok, "ports", &format!("{} ports free, like {}", free, allocated[0]));
+            true
+        }
+    }
+}
+
+/// Whether a server could be run through a suite's steps on an admin API of its own
+fn check_round_trip(port_allocator: Box<dyn PortAllocator>) -> bool {
+    let mut runtime = match tokio::runtime::Runtime::new() {
+        Ok(runtime) => runtime,
+        Err(e) => {
+            report(Outcome::Failed, "runtime", &e.to_string());
+            return false;
+        }
+    };
+    let started = Instant::now();
+    let instance = runtime.block_on(futures::future::lazy(move || {
+        TestInstance::start_with_port_allocator(port_allocator)
+    }));
+    let passed = match instance {
+        Ok(instance) => {
+            report(Outcome::Ok, "admin API", &format!("on port {} in {:?}", instance.port(), started.elapsed()));
+            let passed = round_trip(instance.port());
+            drop(instance);
+            passed
+        }
+        Err(e) => {
+            report(Outcome::Failed, "admin API", &e.to_string());
+            false
+        }
+    };
+    runtime.shutdown_now().wait().ok();
+    passed
+}
+
+/// Run the steps on the admin API on `admin_port`, each depending on the last, stopping
+/// at the first that fails
+fn round_trip(admin_port: u16) -> bool {
+    let step = |check: &str, result: Result<String, String>, started: Instant| match result {
+        Ok(detail) => {
+            report(Outcome::Ok, check, &format!("{} in {:?}", detail, started.elapsed()));
+            true
+        }
+        Err(error) => {
+            report(Outcome::Failed, check, &error);
+            false
+        }
+    };
+
+    let started = Instant::now();
+    let created = request(admin_port, "POST", "/", Some(r#"{"labels":{"self-test":"true"}}"#))
+        .and_then(|response| response.expect(200))
+        .and_then(|body| {
+            serde_json::from_str::<serde_json::Value>(&body).ok()
+                .and_then(|server| server["port"].as_u64())
+                .map(|port| port as u16)
+                .ok_or_else(|| format!("no port in {}", body))
+        });
+    let port = match created {
+        Ok(port) => port,
+        Err(error) => return step("create", Err(error), started),
+    };
+    if !step("create", Ok(format!("server on port {}", port)), started) {
+        return false;
+    }
+
+    let started = Instant::now();
+    let stubbed = request(admin_port, "PUT", &format!("/{}/stubs", port), Some(r#"{"fallback":{"status":200,"body":"self-test"}}"#))
+        .and_then(|response| response.expect(200))
+        .map(|_| "fallback answering self-test".to_string());
+    if !step("stub", stubbed, started) {
+        return false;
+    }
+
+    let started = Instant::now();
+    let served = request(port, "GET", "/self-test", None)
+        .and_then(|response| response.expect(200))
+        .and_then(|body| match body.as_str() {
+            "self-test" => Ok("GET /self-test answered by the stub".to_string()),
+            _ => Err(format!("GET /self-test answered with {:?} rather than the stub", body)),
+        });
+    if !step("request", served, started) {
+        return false;
+    }
+
+    let started = Instant::now();
+    let assertions = r#"{"assertions":[{"method":"GET","path":"/self-test","count":1}]}"#;
+    let verified = request(admin_port, "POST", &format!("/{}/verify", port), Some(assertions))
+        .and_then(|response| response.expect(200))
+        .and_then(|body| {
+            let passed = serde_json::from_str::<serde_json::Value>(&body).ok()
+                .and_then(|report| report["passed"].as_bool());
+            match passed {
+                Some(true) => Ok("the request was journaled".to_string()),
+                _ => Err(format!("the request wasn't journaled: {}", body)),
+            }
+        });
+    if !step("verify", verified, started) {
+        return false;
+    }
+
+    let started = Instant::now();
+    let deleted = request(admin_port, "DELETE", &format!("/{}", port), None)
+        .and_then(|response| response.expect(204))
+        .map(|_| format!("server on port {}", port));
+    step("delete", deleted, started)
+}
+
+/// The status and body of a response
+struct Response {
+    status: u16,
+    body: String,
+}
+
+impl Response {
+    /// The body, if the status is `status`
+    fn expect(self, status: u16) -> Result<String, String> {
+        if self.status == status {
+            Ok(self.body)
+        } else {
+            Err(format!("answered {} rather than {}: {}", self.status, status, self.body))
+        }
+    }
+}
+
+/// Send a request to the server on `port` of this host over a connection of its own, with
+/// a JSON `body` if given
+fn request(port: u16, method: &str, path: &str, body: Option<&str>) -> Result<Response, String> {
+    let exchange = || -> std::io::Result<String> {
+        let mut stream = TcpStream::connect(("127.0.0.1", port))?;
+        stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+        stream.set_write_timeout(Some(REQUEST_TIMEOUT))?;
+        let body = body.unwrap_or_default();
+        write!(
+            stream,
+            "{} {} HTTP/1.1\r\nhost: 127.0.0.1:{}\r\nconnection: close\r\ncontent-type: application/json\r\ncontent-length: {}\r\n\r\n{}",
+            method, path, port, body.len(), body
+        )?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response)?;
+        Ok(response)
+    };
+    let response = exchange().map_err(|e| format!("{} {} on port {} failed: {}", method, path, port, e))?;
+
+    let (head, body) = response.split_once("\r\n\r\n")
+        .ok_or_else(|| format!("{} {} on port {} got an incomplete response", method, path, port))?;
+    let status = head.split_whitespace().nth(1)
+        .and_then(|status| status.parse().ok())
+        .ok_or_else(|| format!("{} {} on port {} got no status", method, path, port))?;
+    Ok(Response { status, body: body.to_string() })
+}
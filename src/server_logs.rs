@@ -0,0 +1,199 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use crate::error::lock;
+use crate::har;
+
+/// Size a log file is rotated at unless configured otherwise
+pub const DEFAULT_MAX_BYTES: u64 = 10 * 1024 * 1024;
+
+/// Rotated log files kept unless configured otherwise
+pub const DEFAULT_KEEP: usize = 5;
+
+/// A log file rotated once it's grown to `max_bytes` or is older than `max_age`, moved
+/// to `{path}.1`, the one there to `{path}.2` and on, keeping `keep` of them
+pub struct Rotating {
+    path: PathBuf,
+    max_bytes: u64,
+    max_age: Option<Duration>,
+    keep: usize,
+    file: File,
+    written: u64,
+    /// When the current file was started
+    started: SystemTime,
+}
+
+impl Rotating {
+    pub fn open(path: PathBuf, max_bytes: u64, max_age: Option<Duration>, keep: usize) -> io::Result<Rotating> {
+        let file = std::fs::OpenOptions::new().create(true).append(true).open(&path)?;
+        let metadata = file.metadata()?;
+        let started = metadata.created().unwrap_or_else(|_| SystemTime::now());
+        Ok(Rotating { path, max_bytes, max_age, keep, file, written: metadata.len(), started })
+    }
+
+    fn rotated(&self, index: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", index));
+        PathBuf::from(path)
+    }
+
+    pub fn write_line(&mut self, line: &str) -> io::Result<()> {
+        let length = line.len() as u64 + 1;
+        let too_old = self.max_age.is_some_and(|max_age| self.started.elapsed().is_ok_and(|age| age > max_age));
+        if self.written > 0 && (self.written + length > self.max_bytes || too_old) {
+            self.rotate()?;
+        }
+        writeln!(self.file, "{}", line)?;
+        self.written += length;
+        Ok(())
+    }
+
+    /// Move each file one up, the current one to `.1`, dropping the one past `keep`
+    fn rotate(&mut self) -> io::Result<()> {
+        if self.keep == 0 {
+            self.file.set_len(0)?;
+        } else {
+            for index in (1..self.keep).rev() {
+                rename_if_there(&self.rotated(index), &self.rotated(index + 1))?;
+            }
+            rename_if_there(&self.path, &self.rotated(1))?;
+            self.file = std::fs::OpenOptions::new().create(true).append(true).open(&self.path)?;
+        }
+        self.written = 0;
+        self.started = SystemTime::now();
+        Ok(())
+    }
+}
+
+fn rename_if_there(from: &Path, to: &Path) -> io::Result<()> {
+    match std::fs::rename(from, to) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// Where each in-process HTTP server writes the requests it answers and the connections
+/// it fails, rather than to the process's stderr, where those of many servers would be
+/// interleaved: `{dir}/{port}.log`
+#[derive(Debug)]
+pub struct ServerLogs {
+    dir: PathBuf,
+    max_bytes: u64,
+    max_age: Option<Duration>,
+    keep: usize,
+    /// Those opened, by port, so that a server restarted goes on with the same file
+    opened: Mutex<HashMap<u16, ServerLog>>,
+}
+
+impl ServerLogs {
+    /// `SERVER_LOG_DIR` is the directory the logs are written to, created if need be,
+    /// `SERVER_LOG_MAX_BYTES` the size each is rotated at, 10 MiB by default,
+    /// `SERVER_LOG_MAX_AGE_SECS` the age, if it should be, and `SERVER_LOG_KEEP` how many
+    /// rotated files are kept, 5 by default
+    pub fn from_env() -> Result<Option<Arc<ServerLogs>>, String> {
+        let dir = match std::env::var("SERVER_LOG_DIR") {
+            Ok(dir) if !dir.is_empty() => PathBuf::from(dir),
+            _ => return Ok(None),
+        };
+        let setting = |var: &str| match std::env::var(var) {
+            Ok(value) => value.parse::<u64>().map(Some).map_err(|_| format!("invalid {} {:?}", var, value)),
+            Err(_) => Ok(None),
+        };
+        let max_bytes = match setting("SERVER_LOG_MAX_BYTES")? {
+            Some(0) => return Err("invalid SERVER_LOG_MAX_BYTES \"0\"".to_string()),
+            max_bytes => max_bytes.unwrap_or(DEFAULT_MAX_BYTES),
+        };
+        let max_age = match setting("SERVER_LOG_MAX_AGE_SECS")? {
+            Some(0) => return Err("invalid SERVER_LOG_MAX_AGE_SECS \"0\"".to_string()),
+            max_age => max_age.map(Duration::from_secs),
+        };
+        let keep = setting("SERVER_LOG_KEEP")?.map_or(DEFAULT_KEEP, |keep| keep as usize);
+        std::fs::create_dir_all(&dir).map_err(|e| format!("failed to create SERVER_LOG_DIR {}: {}", dir.display(), e))?;
+        Ok(Some(Arc::new(ServerLogs { dir, max_bytes, max_age, keep, opened: Mutex::default() })))
+    }
+
+    /// The log of the server on `port`
+    pub fn open(&self, port: u16) -> io::Result<ServerLog> {
+        let mut opened = lock(&self.opened);
+        if let Some(log) = opened.get(&port) {
+            return Ok(log.clone());
+        }
+        let path = self.dir.join(format!("{}.log", port));
+        let file = Rotating::open(path, self.max_bytes, self.max_age, self.keep)?;
+        let log = ServerLog { port, file: Arc::new(Mutex::new((file, false))) };
+        opened.insert(port, log.clone());
+        Ok(log)
+    }
+}
+
+/// A server's log
+#[derive(Clone)]
+pub struct ServerLog {
+    port: u16,
+    /// The file, and whether the last line failed to be written to it
+    file: Arc<Mutex<(Rotating, bool)>>,
+}
+
+impl std::fmt::Debug for ServerLog {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("ServerLog").field("port", &self.port).finish()
+    }
+}
+
+impl ServerLog {
+    /// Log a request the server answered, with the client it came from, or `-` if that
+    /// isn't known, like `2024-05-01T12:00:00.000Z 10.1.2.3:5000 "GET /a HTTP/1.1" 200
+    /// 1.25ms "-" "curl/8.0"`
+    pub fn access(&self, client: Option<std::net::SocketAddr>, info: &warp::log::Info) {
+        let client = client.map_or_else(|| "-".to_string(), |client| client.to_string());
+        self.write(&format!(
+            "{} {} \"{} {} {:?}\" {} {:.2}ms \"{}\" \"{}\"",
+            har::iso8601(now_ms()),
+            client,
+            info.method(),
+            info.path(),
+            info.version(),
+            info.status().as_u16(),
+            info.elapsed().as_secs_f64() * 1000.0,
+            info.referer().unwrap_or("-"),
+            info.user_agent().unwrap_or("-"),
+        ));
+    }
+
+    /// Log something that went wrong, like a connection failing
+    pub fn error(&self, message: &str) {
+        self.write(&format!("{} error: {}", har::iso8601(now_ms()), message));
+    }
+
+    /// A line that can't be written is told of on stderr, once until one can be again
+    fn write(&self, line: &str) {
+        let mut file = lock(&self.file);
+        let (rotating, failing) = &mut *file;
+        match rotating.write_line(line) {
+            Ok(()) => *failing = false,
+            Err(e) if !*failing => {
+                *failing = true;
+                eprintln!("failed to write the log of server {}: {}", self.port, e);
+            }
+            Err(_) => {}
+        }
+    }
+}
+
+/// Tell of an error of a server, in its log if it has one or else on stderr
+pub fn error(log: Option<&ServerLog>, message: &str) {
+    match log {
+        Some(log) => log.error(message),
+        None => eprintln!("{}", message),
+    }
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_millis() as u64)
+        .unwrap_or(0)
+}
@@ -0,0 +1,251 @@
+use futures::Future;
+use futures::sync::oneshot;
+use windows_service::service::{
+    ServiceAccess, ServiceAction, ServiceActionType, ServiceControl, ServiceControlAccept, ServiceErrorControl,
+    ServiceExitCode, ServiceFailureActions, ServiceFailureResetPeriod, ServiceInfo, ServiceStartType, ServiceState,
+    ServiceStatus, ServiceType,
+};
+use windows_service::service_control_handler::{self, ServiceControlHandlerResult, ServiceStatusHandle};
+use windows_service::service_manager::{ServiceManager, ServiceManagerAccess};
+use windows_sys::Win32::System::Console::{SetStdHandle, STD_ERROR_HANDLE, STD_OUTPUT_HANDLE};
+use windows_sys::Win32::System::EventLog::{RegisterEventSourceW, ReportEventW, EVENTLOG_INFORMATION_TYPE, EVENTLOG_WARNING_TYPE};
+
+use std::ffi::OsString;
+use std::io::{self, BufRead, BufReader};
+use std::os::windows::io::IntoRawHandle;
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+
+use crate::{background, daemon, Database};
+use crate::error::lock;
+use crate::runtime;
+
+/// What the service is installed as, and the source of its events in the event log
+const SERVICE_NAME: &str = "warp_self_replicating_server";
+const DISPLAY_NAME: &str = "Warp self-replicating mock server";
+
+/// What the command line asks of the service control manager
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Command {
+    /// `--service`: run as the service the manager started
+    Run,
+    /// `--install-service`: install the service, started along with Windows
+    Install,
+    /// `--uninstall-service`: stop and remove the service
+    Uninstall,
+}
+
+/// The options of the process, until the manager calls `service_main` with them
+static OPTIONS: Mutex<Option<daemon::Options>> = Mutex::new(None);
+
+/// What the process reports its state to, while running as the service
+static STATUS: OnceLock<ServiceStatusHandle> = OnceLock::new();
+
+/// Fires when the manager asks the service to stop, until `watch_for_stop` takes it
+static STOP: Mutex<Option<oneshot::Receiver<()>>> = Mutex::new(None);
+
+windows_service::define_windows_service!(ffi_service_main, service_main);
+
+/// Run as the service the manager started, with `options`. The admin API and its servers
+/// come up as they would from a console, writing to the Application event log instead
+/// of stderr. Stopping the service drains every server before the process exits, and
+/// leaves them stored as they were, so that they're back once it's started again.
+pub fn run(options: daemon::Options) {
+    *lock(&OPTIONS) = Some(options);
+    // Returns once the service has stopped, or straight away if this process wasn't
+    // started by the manager
+    if let Err(e) = windows_service::service_dispatcher::start(SERVICE_NAME, ffi_service_main) {
+        eprintln!("failed to run as a service, which only the service control manager can start: {}", e);
+        std::process::exit(1);
+    }
+}
+
+fn service_main(_arguments: Vec<OsString>) {
+    if let Err(e) = log_to_event_log() {
+        eprintln!("failed to log to the event log: {}", e);
+    }
+
+    let (stop, stopped) = oneshot::channel();
+    let stop = Mutex::new(Some(stop));
+    let handler = move |control| match control {
+        ServiceControl::Stop | ServiceControl::Shutdown => {
+            if let Some(stop) = lock(&stop).take() {
+                let _ = stop.send(());
+            }
+            ServiceControlHandlerResult::NoError
+        }
+        ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
+        _ => ServiceControlHandlerResult::NotImplemented,
+    };
+    let status = match service_control_handler::register(SERVICE_NAME, handler) {
+        Ok(status) => status,
+        Err(e) => return eprintln!("failed to register with the service control manager: {}", e),
+    };
+    let _ = STATUS.set(status);
+    *lock(&STOP) = Some(stopped);
+    report(ServiceState::StartPending, Duration::from_secs(30));
+
+    let options = lock(&OPTIONS).take().unwrap_or_default();
+    crate::serve(options);
+}
+
+/// Tell the manager the service is running, if it started the process, see
+/// `daemon::Readiness::ready`
+pub fn running() {
+    report(ServiceState::Running, Duration::ZERO);
+    log(EVENTLOG_INFORMATION_TYPE, "started");
+}
+
+/// Shut down and exit once the manager asks the service to stop, if it started the
+/// process, see `background::shut_down`
+pub fn watch_for_stop(database: &Database) {
+    let stopped = match lock(&STOP).take() {
+        Some(stopped) => stopped,
+        None => return,
+    };
+    let database = database.clone();
+    runtime::spawn(stopped
+        .map_err(|_| ())
+        .and_then(move |()| {
+            report(ServiceState::StopPending, background::DRAIN_TIMEOUT);
+            background::shut_down(database)
+        })
+        .then(|_| -> Result<(), ()> {
+            log(EVENTLOG_INFORMATION_TYPE, "stopped");
+            report(ServiceState::Stopped, Duration::ZERO);
+            std::process::exit(0)
+        }));
+}
+
+fn report(state: ServiceState, wait_hint: Duration) {
+    let status = match STATUS.get() {
+        Some(status) => status,
+        None => return,
+    };
+    let controls_accepted = match state {
+        ServiceState::StartPending | ServiceState::StopPending | ServiceState::Stopped => ServiceControlAccept::empty(),
+        _ => ServiceControlAccept::STOP | ServiceControlAccept::SHUTDOWN,
+    };
+    let result = status.set_service_status(ServiceStatus {
+        service_type: ServiceType::OWN_PROCESS,
+        current_state: state,
+        controls_accepted,
+        exit_code: ServiceExitCode::Win32(0),
+        checkpoint: 0,
+        wait_hint,
+        process_id: None,
+    });
+    if let Err(e) = result {
+        eprintln!("failed to report {:?} to the service control manager: {}", state, e);
+    }
+}
+
+/// The event log source of the service, once it's registered
+static EVENT_SOURCE: OnceLock<isize> = OnceLock::new();
+
+/// Have every line written to stdout and stderr be a warning in the Application event
+/// log, as a service has no console to write to
+fn log_to_event_log() -> io::Result<()> {
+    let name = wide(SERVICE_NAME);
+    // The name outlives the call
+    let source = unsafe { RegisterEventSourceW(std::ptr::null(), name.as_ptr()) };
+    if source == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let _ = EVENT_SOURCE.set(source);
+
+    let (output, writer) = io::pipe()?;
+    // The write end is never closed, as the standard handles keep pointing at it
+    let writer = writer.into_raw_handle() as isize;
+    if unsafe { SetStdHandle(STD_OUTPUT_HANDLE, writer) == 0 || SetStdHandle(STD_ERROR_HANDLE, writer) == 0 } {
+        return Err(io::Error::last_os_error());
+    }
+
+    std::thread::Builder::new().name("eventlog".to_string()).spawn(move || {
+        for line in BufReader::new(output).lines().map_while(Result::ok) {
+            log(EVENTLOG_WARNING_TYPE, &line);
+        }
+    })?;
+    Ok(())
+}
+
+fn log(kind: u16, message: &str) {
+    let source = match EVENT_SOURCE.get() {
+        Some(source) => *source,
+        None => return,
+    };
+    let message = wide(message);
+    let strings = [message.as_ptr()];
+    // The message outlives the call, which copies it
+    unsafe {
+        ReportEventW(source, kind, 0, 0, std::ptr::null_mut(), 1, 0, strings.as_ptr(), std::ptr::null());
+    }
+}
+
+/// `value` as a NUL terminated UTF-16 string
+fn wide(value: &str) -> Vec<u16> {
+    value.encode_utf16().chain(std::iter::once(0)).collect()
+}
+
+/// Install the service, to run this executable with `arguments` and `--service` as
+/// LocalSystem, started along with Windows and again whenever it fails. The environment
+/// it's configured by is the `Environment` value of its key in the registry, under
+/// `HKLM\SYSTEM\CurrentControlSet\Services`.
+pub fn install(arguments: Vec<OsString>) -> Result<(), String> {
+    let manager = connect(ServiceManagerAccess::CONNECT | ServiceManagerAccess::CREATE_SERVICE)?;
+    let executable_path = std::env::current_exe()
+        .map_err(|e| format!("failed to find this executable: {}", e))?;
+    let info = ServiceInfo {
+        name: SERVICE_NAME.into(),
+        display_name: DISPLAY_NAME.into(),
+        service_type: ServiceType::OWN_PROCESS,
+        start_type: ServiceStartType::AutoStart,
+        error_control: ServiceErrorControl::Normal,
+        executable_path,
+        launch_arguments: std::iter::once("--service".into()).chain(arguments).collect(),
+        dependencies: Vec::new(),
+        account_name: None,
+        account_password: None,
+    };
+    let service = manager.create_service(&info, ServiceAccess::CHANGE_CONFIG | ServiceAccess::START)
+        .map_err(|e| format!("failed to install service {}: {}", SERVICE_NAME, e))?;
+
+    let restart = ServiceAction { action_type: ServiceActionType::Restart, delay: Duration::from_secs(5) };
+    let failure_actions = ServiceFailureActions {
+        reset_period: ServiceFailureResetPeriod::After(Duration::from_secs(24 * 60 * 60)),
+        reboot_msg: None,
+        command: None,
+        actions: Some(vec![restart.clone(), restart.clone(), restart]),
+    };
+    let configured = service.set_description("Mock servers, managed through an admin API on port 8080")
+        .and_then(|()| service.update_failure_actions(failure_actions))
+        .and_then(|()| service.set_failure_actions_on_non_crash_failures(true));
+    if let Err(e) = configured {
+        eprintln!("failed to configure service {}: {}", SERVICE_NAME, e);
+    }
+    println!("installed service {}, start it with `sc start {}`", SERVICE_NAME, SERVICE_NAME);
+    Ok(())
+}
+
+/// Stop the service if it's running, and remove it
+pub fn uninstall() -> Result<(), String> {
+    let manager = connect(ServiceManagerAccess::CONNECT)?;
+    let service = manager.open_service(SERVICE_NAME, ServiceAccess::QUERY_STATUS | ServiceAccess::STOP | ServiceAccess::DELETE)
+        .map_err(|e| format!("failed to open service {}: {}", SERVICE_NAME, e))?;
+    let stopped = service.query_status()
+        .is_ok_and(|status| status.current_state == ServiceState::Stopped);
+    if !stopped {
+        if let Err(e) = service.stop() {
+            eprintln!("failed to stop service {}: {}", SERVICE_NAME, e);
+        }
+    }
+    // Removed once it has stopped, and every handle on it is closed
+    service.delete().map_err(|e| format!("failed to uninstall service {}: {}", SERVICE_NAME, e))?;
+    println!("uninstalled service {}", SERVICE_NAME);
+    Ok(())
+}
+
+fn connect(access: ServiceManagerAccess) -> Result<ServiceManager, String> {
+    ServiceManager::local_computer(None::<&str>, access)
+        .map_err(|e| format!("failed to connect to the service control manager: {}", e))
+}
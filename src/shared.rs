@@ -0,0 +1,95 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Instant;
+
+use crate::{admin_faults, limits, metrics, ratelimit, RunningServer, ServerJsonBody};
+use crate::error::lock;
+
+/// What the admin API reads on every request, kept apart from the registry's lock so that
+/// listing servers, checking the admin token and the like don't wait on changes holding
+/// it, such as creates binding their sockets
+#[derive(Debug, Default)]
+pub struct Shared {
+    /// The servers as of the last change to any of them, replaced rather than changed, so
+    /// each read takes the lock only long enough to clone an `Arc`
+    servers: RwLock<Arc<BTreeMap<u16, Listed>>>,
+    /// Required of admin API requests if set, see `auth::authorize`
+    pub admin_token: Option<String>,
+    /// Set with `PUT /admin/readonly` to refuse changes, see `readonly::guard`
+    read_only: AtomicBool,
+    /// Limits the rate of admin API requests, see `ratelimit::RateLimitConfig`
+    pub rate_limiter: Mutex<ratelimit::RateLimiter>,
+    /// Set with `PUT /admin/faults` to hold back and fail admin API requests, see
+    /// `admin_faults::inject`
+    pub admin_faults: Mutex<Option<admin_faults::Injector>>,
+}
+
+impl Shared {
+    pub fn new(admin_token: Option<String>, rate_limiter: ratelimit::RateLimiter) -> Shared {
+        Shared { admin_token, rate_limiter: Mutex::new(rate_limiter), ..Shared::default() }
+    }
+
+    /// The servers as of the last change
+    pub fn servers(&self) -> Arc<BTreeMap<u16, Listed>> {
+        self.servers.read().unwrap_or_else(|poisoned| poisoned.into_inner()).clone()
+    }
+
+    /// List `server` as it is now, or stop listing the one on `port` if there's none
+    pub fn list(&self, port: u16, server: Option<&RunningServer>) {
+        let listed = server.map(Listed::new);
+        let mut servers = self.servers.write().unwrap_or_else(|poisoned| poisoned.into_inner());
+        // Copied only if a read still holds the servers as they were
+        let servers = Arc::make_mut(&mut servers);
+        match listed {
+            Some(listed) => servers.insert(port, listed),
+            None => servers.remove(&port),
+        };
+    }
+
+    pub fn read_only(&self) -> bool {
+        self.read_only.load(Ordering::SeqCst)
+    }
+
+    /// Set whether the admin API is read-only, answering whether it was
+    pub fn set_read_only(&self, read_only: bool) -> bool {
+        self.read_only.swap(read_only, Ordering::SeqCst)
+    }
+}
+
+/// A server as it was listed, along with what tells its uptime, lease, latency and usage
+/// as they are when it's read
+#[derive(Clone, Debug)]
+pub struct Listed {
+    server: ServerJsonBody,
+    up: bool,
+    started_at: Instant,
+    lease_renewed_at: Instant,
+    latency: Option<Arc<Mutex<metrics::LatencyHistogram>>>,
+    usage: Option<Arc<limits::Usage>>,
+}
+
+impl Listed {
+    fn new(server: &RunningServer) -> Listed {
+        Listed {
+            server: server.json_body(),
+            up: server.is_up(),
+            started_at: server.started_at,
+            lease_renewed_at: server.lease_renewed_at,
+            latency: server.latency.clone(),
+            usage: server.usage.clone(),
+        }
+    }
+
+    /// Like `RunningServer::json_body`
+    pub fn json_body(&self) -> ServerJsonBody {
+        ServerJsonBody {
+            uptime_secs: Some(self.started_at.elapsed().as_secs()).filter(|_| self.up),
+            lease_remaining_secs: self.server.lease_secs
+                .map(|lease| lease.saturating_sub(self.lease_renewed_at.elapsed().as_secs())),
+            latency: self.latency.as_ref().and_then(|latency| lock(latency).summary()),
+            usage: self.usage.as_ref().map(|usage| usage.summary()),
+            ..self.server.clone()
+        }
+    }
+}
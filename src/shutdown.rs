@@ -0,0 +1,287 @@
+use futures::future::Shared;
+use futures::sync::oneshot;
+use futures::{Future, Stream};
+use tokio::timer::{Interval, Timeout};
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::{Database, RunningServer, ServerFuture};
+use crate::error::lock;
+
+/// How long a server future is given to end once told to shut down, before it's taken to
+/// have leaked
+pub const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(30);
+
+const SWEEP_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Why a server future is taken to have leaked
+#[derive(Clone, Copy, Debug, PartialEq, serde_derive::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LeakReason {
+    /// It dropped its end of the shutdown channel without ending, so it can't be told to
+    ReceiverDropped,
+    /// It was told to shut down `SHUTDOWN_TIMEOUT` ago or more, and hasn't
+    Stuck,
+}
+
+impl LeakReason {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            LeakReason::ReceiverDropped => "receiver_dropped",
+            LeakReason::Stuck => "stuck",
+        }
+    }
+}
+
+/// Where the task running a server future is at
+#[derive(Clone, Copy, Debug, PartialEq, serde_derive::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TaskStatus {
+    Running,
+    /// Told to shut down, and yet to end
+    ShuttingDown,
+    Ended,
+    /// Won't end by itself, see `Leak`
+    Leaked,
+}
+
+/// How a server future ended once its server was deleted
+#[derive(Clone, Copy, Debug, PartialEq, serde_derive::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    /// It ended by itself once told to shut down
+    Graceful,
+    /// It was aborted, having been given too long to end or the delete being forced
+    Forced,
+}
+
+impl Outcome {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Outcome::Graceful => "graceful",
+            Outcome::Forced => "forced",
+        }
+    }
+}
+
+/// A server future that has leaked, holding its port and whatever it serves until it's
+/// aborted with `DELETE /{port}?force=true`
+#[derive(Clone, Debug, serde_derive::Serialize)]
+pub struct Leak {
+    pub port: u16,
+    pub reason: LeakReason,
+    /// Whether the server is still registered, or was deleted while its future went on
+    pub registered: bool,
+    /// Since the future was told to shut down, if it has been
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub signalled_secs: Option<u64>,
+}
+
+/// The task running a server future, shared by its `RunningServer` and `Shutdowns`, which
+/// keeps it until the future ends, or is dropped, however long after its server is gone
+#[derive(Debug)]
+pub struct Handle {
+    port: u16,
+    id: usize,
+    signalled_at: Mutex<Option<Instant>>,
+    receiver_dropped: AtomicBool,
+    ended: AtomicBool,
+    /// Resolves, with an error, once the future has ended
+    ended_signal: Shared<oneshot::Receiver<()>>,
+    /// Logged once it has leaked, so it's only logged once
+    reported: AtomicBool,
+    /// Ends the future at once, taken once it's been sent
+    abort: Mutex<Option<oneshot::Sender<()>>>,
+}
+
+impl Handle {
+    /// Record that the future was told to shut down, and whether it could be, its end of
+    /// the shutdown channel being there to be told
+    pub fn signalled(&self, delivered: bool) {
+        lock(&self.signalled_at).get_or_insert_with(Instant::now);
+        if !delivered {
+            self.receiver_dropped();
+        }
+    }
+
+    /// Record that the future dropped its end of the shutdown channel, which is a leak
+    /// unless it has ended
+    pub fn receiver_dropped(&self) {
+        self.receiver_dropped.store(true, Ordering::SeqCst);
+    }
+
+    pub fn ended(&self) -> bool {
+        self.ended.load(Ordering::SeqCst)
+    }
+
+    pub fn status(&self) -> TaskStatus {
+        if self.ended() {
+            TaskStatus::Ended
+        } else if self.leak(true).is_some() {
+            TaskStatus::Leaked
+        } else if lock(&self.signalled_at).is_some() {
+            TaskStatus::ShuttingDown
+        } else {
+            TaskStatus::Running
+        }
+    }
+
+    /// Resolves once the future has ended, or `timeout` is up, with whether it had
+    pub fn ended_within(&self, timeout: Duration) -> impl Future<Item = bool, Error = ()> {
+        let ended = self.ended_signal.clone().then(|_| Ok::<_, ()>(()));
+        Timeout::new(ended, timeout).then(|ended| Ok(ended.is_ok()))
+    }
+
+    /// End the future where it stands, dropping it along with the listeners it holds.
+    /// Connections it accepted are served on tasks of their own, and left be. Answers
+    /// whether there was a future to abort.
+    pub fn abort(&self) -> bool {
+        match lock(&self.abort).take() {
+            Some(abort) if !self.ended() => abort.send(()).is_ok(),
+            _ => false,
+        }
+    }
+
+    /// Why the future has leaked, if it has
+    fn leak(&self, registered: bool) -> Option<Leak> {
+        if self.ended() {
+            return None;
+        }
+        let signalled_at = *lock(&self.signalled_at);
+        let reason = if self.receiver_dropped.load(Ordering::SeqCst) {
+            LeakReason::ReceiverDropped
+        } else if signalled_at.is_some_and(|signalled_at| signalled_at.elapsed() >= SHUTDOWN_TIMEOUT) {
+            LeakReason::Stuck
+        } else {
+            return None;
+        };
+        Some(Leak {
+            port: self.port,
+            reason,
+            registered,
+            signalled_secs: signalled_at.map(|signalled_at| signalled_at.elapsed().as_secs()),
+        })
+    }
+}
+
+/// Marks the future ended once it completes or is dropped, whichever comes first, and
+/// resolves the `ended_signal` of its `Handle` by dropping the other end
+struct Ended {
+    handle: Arc<Handle>,
+    _signal: oneshot::Sender<()>,
+}
+
+impl Drop for Ended {
+    fn drop(&mut self) {
+        self.handle.ended.store(true, Ordering::SeqCst);
+    }
+}
+
+/// `future` of the server spawned as `id` on `port`, ended early by `Handle::abort`
+pub fn abortable(port: u16, id: usize, future: ServerFuture) -> (Arc<Handle>, ServerFuture) {
+    let (abort, aborted) = oneshot::channel();
+    let (ended, ended_signal) = oneshot::channel();
+    let handle = Arc::new(Handle {
+        port,
+        id,
+        signalled_at: Mutex::new(None),
+        receiver_dropped: AtomicBool::new(false),
+        ended: AtomicBool::new(false),
+        ended_signal: ended_signal.shared(),
+        reported: AtomicBool::new(false),
+        abort: Mutex::new(Some(abort)),
+    });
+    let ended = Ended { handle: handle.clone(), _signal: ended };
+    let aborted = aborted.then(move |_| {
+        eprintln!("aborted server {}", port);
+        Ok(())
+    });
+    let future = future.select(aborted).then(move |_| {
+        drop(ended);
+        Ok(())
+    });
+    (handle, Box::new(future))
+}
+
+/// The tasks of every server future that hasn't ended, registered or not, see `Handle`
+#[derive(Debug, Default)]
+pub struct Shutdowns {
+    handles: Vec<Arc<Handle>>,
+    /// Server futures aborted so far
+    aborted: u64,
+}
+
+impl Shutdowns {
+    pub fn track(&mut self, handle: Arc<Handle>) {
+        self.handles.push(handle);
+    }
+
+    /// Let go of the tasks whose futures ended
+    fn collect(&mut self) {
+        self.handles.retain(|handle| !handle.ended());
+    }
+
+    /// The server futures that have leaked, of `servers` and of those deleted
+    pub fn leaks(&self, servers: &HashMap<u16, RunningServer>) -> Vec<Leak> {
+        let mut leaks: Vec<Leak> = self.handles.iter()
+            .filter_map(|handle| {
+                let registered = servers.get(&handle.port).is_some_and(|server| server.id == handle.id);
+                handle.leak(registered)
+            })
+            .collect();
+        leaks.sort_by_key(|leak| leak.port);
+        leaks
+    }
+
+    /// Abort the futures of servers on `port` besides the one spawned as `keep`,
+    /// answering how many there were
+    pub fn abort(&mut self, port: u16, keep: Option<usize>) -> usize {
+        let aborted = self.handles.iter()
+            .filter(|handle| handle.port == port && Some(handle.id) != keep)
+            .filter(|handle| handle.abort())
+            .count();
+        self.aborted += aborted as u64;
+        aborted
+    }
+
+    /// Server futures told to shut down that haven't ended yet, leaked or not
+    pub fn pending(&self) -> usize {
+        self.handles.iter()
+            .filter(|handle| !handle.ended() && lock(&handle.signalled_at).is_some())
+            .count()
+    }
+
+    pub fn aborted(&self) -> u64 {
+        self.aborted
+    }
+}
+
+/// Periodically let go of the tasks of server futures that have ended, and find those
+/// that have leaked, logging each once, run as a `background::Background` task. A server
+/// whose future dropped its end of the shutdown channel while it's up is found here, as
+/// it's only told otherwise once it's stopped.
+pub fn sweep(database: Database) -> impl Future<Item = (), Error = ()> {
+    Interval::new_interval(SWEEP_INTERVAL)
+        .map_err(|e| eprintln!("shutdown sweeper timer error: {}", e))
+        .for_each(move |_| {
+            let mut registry = lock(&database);
+            for server in registry.servers.values() {
+                if server.shutdown.as_ref().is_some_and(|shutdown| shutdown.is_canceled()) {
+                    server.task.receiver_dropped();
+                }
+            }
+
+            registry.shutdowns.collect();
+            for handle in &registry.shutdowns.handles {
+                let leaked = handle.leak(true).map(|leak| leak.reason);
+                if let Some(reason) = leaked.filter(|_| !handle.reported.swap(true, Ordering::SeqCst)) {
+                    let port = handle.port;
+                    eprintln!("server {} leaked its task ({}), see DELETE /{}?force=true", port, reason.as_str(), port);
+                }
+            }
+            Ok(())
+        })
+}
@@ -0,0 +1,449 @@
+use futures::{Future, Stream};
+use futures::future::{self, Either, Loop, Shared};
+use futures::sync::oneshot;
+use tokio::codec::{BytesCodec, FramedRead};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use warp::Reply;
+
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::{error_reply, unix_time, Database, ServerKind};
+use crate::error::lock;
+use crate::runtime::{self, Tasks};
+
+/// Longest command line taken, as RFC 5321 has it, before the connection is closed
+const MAX_LINE_BYTES: usize = 512;
+
+/// What an SMTP server accepts and keeps the messages of, for tests of code sending
+/// email to check what it sent with `GET /{port}/emails`. It takes any sender, any
+/// recipient and any credentials, and delivers nothing.
+#[derive(Clone, Debug, PartialEq, serde_derive::Deserialize, serde_derive::Serialize)]
+#[serde(default)]
+pub struct Smtp {
+    /// The name it greets clients with
+    pub hostname: String,
+    /// Messages kept, the oldest dropped first
+    pub max_messages: usize,
+    /// Larger messages are refused
+    pub max_message_bytes: usize,
+}
+
+impl Default for Smtp {
+    fn default() -> Smtp {
+        Smtp { hostname: "localhost".to_string(), max_messages: 1000, max_message_bytes: 10 * 1024 * 1024 }
+    }
+}
+
+/// A message an SMTP server accepted
+#[derive(Clone, Debug, serde_derive::Serialize)]
+pub struct Email {
+    pub id: u64,
+    pub received_at: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client: Option<SocketAddr>,
+    /// The name the client gave with `HELO` or `EHLO`
+    pub helo: String,
+    /// The envelope's sender and recipients, which the headers needn't agree with
+    pub from: String,
+    pub to: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub subject: Option<String>,
+    /// In the order they were sent, continuation lines unfolded
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+    pub size: usize,
+}
+
+impl Email {
+    /// Taken apart from `data`, the message as it was sent after `DATA`
+    fn parse(id: u64, client: Option<SocketAddr>, helo: String, from: String, to: Vec<String>, data: &[u8]) -> Email {
+        let text = String::from_utf8_lossy(data);
+        let (head, body) = match text.find("\r\n\r\n") {
+            Some(end) => (&text[..end], &text[end + 4..]),
+            None if text.starts_with("\r\n") => ("", &text[2..]),
+            None => (&text[..], ""),
+        };
+        let mut headers: Vec<(String, String)> = Vec::new();
+        for line in head.split("\r\n").filter(|line| !line.is_empty()) {
+            match (line.starts_with([' ', '\t']), headers.last_mut()) {
+                (true, Some((_, value))) => {
+                    value.push(' ');
+                    value.push_str(line.trim());
+                }
+                _ => {
+                    if let Some((name, value)) = line.split_once(':') {
+                        headers.push((name.trim().to_string(), value.trim().to_string()));
+                    }
+                }
+            }
+        }
+        let subject = headers.iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case("subject"))
+            .map(|(_, subject)| subject.clone());
+        Email { id, received_at: unix_time(), client, helo, from, to, subject, headers, body: body.to_string(), size: data.len() }
+    }
+}
+
+/// The messages an SMTP server accepted, kept across restarts
+#[derive(Debug, Default)]
+pub struct Mailbox {
+    emails: VecDeque<Email>,
+    /// Messages accepted, including those dropped since
+    received: u64,
+}
+
+pub type SharedMailbox = Arc<Mutex<Mailbox>>;
+
+impl Mailbox {
+    fn store(&mut self, email: Email, max_messages: usize) {
+        self.emails.push_back(email);
+        while self.emails.len() > max_messages {
+            self.emails.pop_front();
+        }
+    }
+}
+
+/// Serve SMTP on `incoming` connections, keeping the messages in `mailbox`. The returned
+/// future accepts connections until `shutdown` fires, which also closes every connection
+/// still open. Connections accepted while `paused` are closed straight away. Each
+/// connection is served by a task counted in `tasks`.
+pub fn create_smtp_server<S>(
+    config: Smtp,
+    incoming: S,
+    mailbox: SharedMailbox,
+    paused: Arc<AtomicBool>,
+    tasks: Arc<Tasks>,
+    shutdown: oneshot::Receiver<()>
+) -> impl Future<Item = (), Error = ()>
+where
+    S: Stream<Item = crate::limits::Connection<TcpStream>, Error = std::io::Error>,
+{
+    let shutdown = shutdown.shared();
+    let config = Arc::new(config);
+
+    let accept_shutdown = shutdown.clone();
+    let server = incoming
+        .map_err(|e| eprintln!("smtp accept error: {}", e))
+        .for_each(move |socket| {
+            if paused.load(Ordering::SeqCst) {
+                return Ok(());
+            }
+            let client = socket.get_ref().peer_addr().ok();
+            let session = Session::new(config.clone(), mailbox.clone(), client);
+            runtime::spawn_for(&tasks, handle_connection(session, socket, shutdown.clone()));
+            Ok(())
+        });
+
+    server.select2(accept_shutdown).then(|_| Ok(()))
+}
+
+/// Serve one connection with `session`, giving up when `shutdown` fires
+fn handle_connection(
+    session: Session,
+    socket: impl AsyncRead + AsyncWrite + Send + 'static,
+    shutdown: Shared<oneshot::Receiver<()>>
+) -> impl Future<Item = (), Error = ()> {
+    let (read, write) = AsyncRead::split(socket);
+    let greeting = format!("220 {} ESMTP ready\r\n", session.config.hostname);
+    let connection = tokio::io::write_all(write, greeting.into_bytes()).and_then(move |(write, _)| {
+        future::loop_fn((session, FramedRead::new(read, BytesCodec::new()), write), |(mut session, chunks, write)| {
+            chunks.into_future().map_err(|(e, _)| e).and_then(move |(chunk, chunks)| match chunk {
+                None => Either::A(future::ok(Loop::Break(()))),
+                Some(chunk) => {
+                    let replies = session.feed(&chunk);
+                    Either::B(tokio::io::write_all(write, replies).map(move |(write, _)| match session.closed {
+                        true => Loop::Break(()),
+                        false => Loop::Continue((session, chunks, write)),
+                    }))
+                }
+            })
+        })
+    });
+
+    connection
+        .map_err(|e| eprintln!("smtp connection error: {}", e))
+        .select2(shutdown)
+        .then(|_| Ok(()))
+}
+
+/// Where a connection is in the conversation
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum State {
+    Command,
+    Data,
+    /// Awaiting the user name, then the password, of `AUTH LOGIN`
+    AuthLogin(u8),
+    /// Awaiting the credentials of `AUTH PLAIN` sent without them
+    AuthPlain,
+}
+
+struct Session {
+    config: Arc<Smtp>,
+    mailbox: SharedMailbox,
+    client: Option<SocketAddr>,
+    /// What was read past the last complete line
+    buffer: Vec<u8>,
+    state: State,
+    helo: Option<String>,
+    from: Option<String>,
+    to: Vec<String>,
+    data: Vec<u8>,
+    /// Whether the message being sent grew larger than accepted
+    oversized: bool,
+    closed: bool,
+}
+
+/// The address of `MAIL FROM:<...>` or `RCPT TO:<...>`, without its parameters
+fn path_argument(argument: &str) -> Option<String> {
+    let argument = argument.trim_start();
+    match argument.strip_prefix('<') {
+        Some(rest) => rest.split_once('>').map(|(address, _)| address.to_string()),
+        None => argument.split_whitespace().next().map(str::to_string),
+    }
+}
+
+impl Session {
+    fn new(config: Arc<Smtp>, mailbox: SharedMailbox, client: Option<SocketAddr>) -> Session {
+        Session {
+            config,
+            mailbox,
+            client,
+            buffer: Vec::new(),
+            state: State::Command,
+            helo: None,
+            from: None,
+            to: Vec::new(),
+            data: Vec::new(),
+            oversized: false,
+            closed: false,
+        }
+    }
+
+    /// The replies to the lines completed by `bytes`
+    fn feed(&mut self, bytes: &[u8]) -> Vec<u8> {
+        self.buffer.extend_from_slice(bytes);
+        let mut replies = Vec::new();
+        while let Some(end) = self.buffer.iter().position(|byte| *byte == b'\n') {
+            let mut line: Vec<u8> = self.buffer.drain(..=end).collect();
+            line.pop();
+            if line.last() == Some(&b'\r') {
+                line.pop();
+            }
+            if let Some(reply) = self.line(&line) {
+                replies.extend_from_slice(reply.as_bytes());
+                replies.extend_from_slice(b"\r\n");
+            }
+            if self.closed {
+                return replies;
+            }
+        }
+        if self.state != State::Data && self.buffer.len() > MAX_LINE_BYTES {
+            replies.extend_from_slice(b"500 Line too long\r\n");
+            self.closed = true;
+        }
+        replies
+    }
+
+    fn reset(&mut self) {
+        self.from = None;
+        self.to.clear();
+        self.data.clear();
+        self.oversized = false;
+    }
+
+    /// The reply to `line`, if there's one to it
+    fn line(&mut self, line: &[u8]) -> Option<String> {
+        match self.state {
+            State::Data if line == b"." => {
+                self.state = State::Command;
+                Some(self.deliver())
+            }
+            State::Data => {
+                // Dot-stuffed, see RFC 5321 4.5.2
+                let line = line.strip_prefix(b".").unwrap_or(line);
+                if self.data.len() + line.len() + 2 > self.config.max_message_bytes {
+                    self.oversized = true;
+                } else if !self.oversized {
+                    self.data.extend_from_slice(line);
+                    self.data.extend_from_slice(b"\r\n");
+                }
+                None
+            }
+            State::AuthLogin(0) => {
+                self.state = State::AuthLogin(1);
+                // "Password:"
+                Some("334 UGFzc3dvcmQ6".to_string())
+            }
+            State::AuthLogin(_) | State::AuthPlain => {
+                self.state = State::Command;
+                Some("235 2.7.0 Authentication successful".to_string())
+            }
+            State::Command => Some(self.command(&String::from_utf8_lossy(line))),
+        }
+    }
+
+    fn command(&mut self, line: &str) -> String {
+        let (verb, argument) = line.split_once(' ').unwrap_or((line, ""));
+        match verb.to_ascii_uppercase().as_str() {
+            "HELO" => {
+                self.helo = Some(argument.trim().to_string());
+                self.reset();
+                format!("250 {}", self.config.hostname)
+            }
+            "EHLO" => {
+                self.helo = Some(argument.trim().to_string());
+                self.reset();
+                format!(
+                    "250-{}\r\n250-SIZE {}\r\n250-8BITMIME\r\n250-PIPELINING\r\n250-AUTH PLAIN LOGIN\r\n250 SMTPUTF8",
+                    self.config.hostname, self.config.max_message_bytes
+                )
+            }
+            "AUTH" => {
+                let mut words = argument.split_whitespace();
+                match (words.next().map(str::to_ascii_uppercase).as_deref(), words.next()) {
+                    (Some("PLAIN"), Some(_)) => "235 2.7.0 Authentication successful".to_string(),
+                    (Some("PLAIN"), None) => {
+                        self.state = State::AuthPlain;
+                        "334 ".to_string()
+                    }
+                    (Some("LOGIN"), Some(_)) => {
+                        self.state = State::AuthLogin(1);
+                        "334 UGFzc3dvcmQ6".to_string()
+                    }
+                    (Some("LOGIN"), None) => {
+                        self.state = State::AuthLogin(0);
+                        // "Username:"
+                        "334 VXNlcm5hbWU6".to_string()
+                    }
+                    _ => "504 5.5.4 Unrecognized authentication type".to_string(),
+                }
+            }
+            "MAIL" => match argument.get(..5).filter(|from| from.eq_ignore_ascii_case("FROM:")) {
+                _ if self.helo.is_none() => "503 5.5.1 Say HELO or EHLO first".to_string(),
+                Some(_) => match path_argument(&argument[5..]) {
+                    Some(from) => {
+                        self.reset();
+                        self.from = Some(from);
+                        "250 2.1.0 OK".to_string()
+                    }
+                    None => "501 5.5.4 Syntax: MAIL FROM:<address>".to_string(),
+                },
+                None => "501 5.5.4 Syntax: MAIL FROM:<address>".to_string(),
+            },
+            "RCPT" => match argument.get(..3).filter(|to| to.eq_ignore_ascii_case("TO:")) {
+                _ if self.from.is_none() => "503 5.5.1 Need MAIL before RCPT".to_string(),
+                Some(_) => match path_argument(&argument[3..]).filter(|to| !to.is_empty()) {
+                    Some(to) => {
+                        self.to.push(to);
+                        "250 2.1.5 OK".to_string()
+                    }
+                    None => "501 5.5.4 Syntax: RCPT TO:<address>".to_string(),
+                },
+                None => "501 5.5.4 Syntax: RCPT TO:<address>".to_string(),
+            },
+            "DATA" if self.to.is_empty() => "503 5.5.1 Need RCPT before DATA".to_string(),
+            "DATA" => {
+                self.state = State::Data;
+                "354 End data with <CR><LF>.<CR><LF>".to_string()
+            }
+            "RSET" => {
+                self.reset();
+                "250 2.0.0 OK".to_string()
+            }
+            "NOOP" => "250 2.0.0 OK".to_string(),
+            "VRFY" => "252 2.1.5 Cannot verify, but will accept".to_string(),
+            "QUIT" => {
+                self.closed = true;
+                format!("221 2.0.0 {} closing", self.config.hostname)
+            }
+            _ => "500 5.5.2 Command not recognized".to_string(),
+        }
+    }
+
+    /// Keep the message just sent, if it isn't too large
+    fn deliver(&mut self) -> String {
+        if self.oversized {
+            self.reset();
+            return "552 5.3.4 Message too big".to_string();
+        }
+        let mut mailbox = lock(&self.mailbox);
+        mailbox.received += 1;
+        let id = mailbox.received;
+        let (helo, from, to) = (self.helo.clone().unwrap_or_default(), self.from.take().unwrap_or_default(), std::mem::take(&mut self.to));
+        let email = Email::parse(id, self.client, helo, from, to, &self.data);
+        mailbox.store(email, self.config.max_messages);
+        drop(mailbox);
+        self.reset();
+        format!("250 2.0.0 OK queued as {}", id)
+    }
+}
+
+/// Query parameters of `GET /{port}/emails`, matching the messages listed
+#[derive(Debug, Default, serde_derive::Deserialize)]
+pub struct EmailQuery {
+    /// Part of the subject, without case
+    #[serde(default)]
+    subject: Option<String>,
+    /// One of the recipients, without case
+    #[serde(default)]
+    to: Option<String>,
+    /// The sender, without case
+    #[serde(default)]
+    from: Option<String>,
+}
+
+impl EmailQuery {
+    fn matches(&self, email: &Email) -> bool {
+        let subject = self.subject.as_ref().is_none_or(|part| {
+            email.subject.as_ref().is_some_and(|subject| subject.to_lowercase().contains(&part.to_lowercase()))
+        });
+        let to = self.to.as_ref().is_none_or(|to| email.to.iter().any(|recipient| recipient.eq_ignore_ascii_case(to)));
+        let from = self.from.as_ref().is_none_or(|from| email.from.eq_ignore_ascii_case(from));
+        subject && to && from
+    }
+}
+
+/// Body of `GET /{port}/emails`
+#[derive(serde_derive::Serialize)]
+struct EmailsJsonBody<'a> {
+    stored: usize,
+    received: u64,
+    emails: Vec<&'a Email>,
+}
+
+fn find_mailbox(database: &Database, port: u16) -> Result<SharedMailbox, (warp::http::StatusCode, String)> {
+    let registry = lock(database);
+    let server = registry.servers.get(&port)
+        .ok_or_else(|| (warp::http::StatusCode::NOT_FOUND, format!("no server on port {}", port)))?;
+    match (&server.config.kind, &server.emails) {
+        (ServerKind::Smtp(_), Some(emails)) => Ok(emails.clone()),
+        (kind, _) => Err((warp::http::StatusCode::CONFLICT, format!("a {} server keeps no emails", kind.name()))),
+    }
+}
+
+/// The messages the SMTP server on `port` accepted and still keeps, oldest first, those
+/// matching `query`
+pub fn list_emails(database: Database, port: u16, query: EmailQuery) -> warp::reply::Response {
+    let mailbox = match find_mailbox(&database, port) {
+        Ok(mailbox) => mailbox,
+        Err((status, error)) => return error_reply(status, &error),
+    };
+    let mailbox = lock(&mailbox);
+    let emails = mailbox.emails.iter().filter(|email| query.matches(email)).collect();
+    warp::reply::json(&EmailsJsonBody { stored: mailbox.emails.len(), received: mailbox.received, emails }).into_response()
+}
+
+/// Forget the messages the SMTP server on `port` accepted. It keeps counting them.
+pub fn clear_emails(database: Database, port: u16) -> warp::reply::Response {
+    match find_mailbox(&database, port) {
+        Ok(mailbox) => {
+            lock(&mailbox).emails.clear();
+            warp::http::StatusCode::NO_CONTENT.into_response()
+        }
+        Err((status, error)) => error_reply(status, &error),
+    }
+}
@@ -0,0 +1,134 @@
+use warp::Reply;
+
+use std::collections::BTreeMap;
+use std::sync::atomic::Ordering;
+
+use crate::{converge, error_reply, start_server, unix_time, ApplySummary, Database, Registry, ServerJsonBody, ServerStatus};
+use crate::error::lock;
+use crate::templates::TemplateJsonBody;
+use crate::watch::ChangeType;
+
+/// Everything needed to rebuild the fleet elsewhere: the body of `GET /snapshot` and
+/// `POST /restore`
+#[derive(Debug, serde_derive::Deserialize, serde_derive::Serialize)]
+pub struct Snapshot {
+    /// Unix time the snapshot was taken
+    #[serde(default)]
+    pub taken_at: u64,
+    /// Every server but the admin API, with its status
+    #[serde(default)]
+    servers: Vec<ServerJsonBody>,
+    #[serde(default)]
+    templates: Vec<TemplateJsonBody>,
+}
+
+/// Capture every server and template. The admin API on `own_port` is left out, so a
+/// snapshot can be restored into an instance listening elsewhere.
+pub fn snapshot(database: Database, own_port: u16) -> warp::reply::Response {
+    warp::reply::json(&capture(&lock(&database), own_port)).into_response()
+}
+
+/// The snapshot of `GET /snapshot`
+pub fn capture(registry: &Registry, own_port: u16) -> Snapshot {
+    let mut servers: Vec<ServerJsonBody> = registry.servers.values()
+        .filter(|server| server.config.port != own_port)
+        .map(|server| server.json_body())
+        .collect();
+    servers.sort_by_key(|server| server.port);
+
+    Snapshot {
+        taken_at: unix_time(),
+        servers,
+        templates: registry.templates.values().map(|template| template.json_body()).collect(),
+    }
+}
+
+/// Rebuild the fleet captured in `snapshot`, answering with the summary of
+/// `restore_servers`. The registered templates are replaced with those of the snapshot.
+pub fn restore(
+    database: Database,
+    own_port: u16,
+    snapshot: Snapshot
+) -> warp::reply::Response {
+    let mut templates = BTreeMap::new();
+    for template in snapshot.templates {
+        match template.parse() {
+            Ok(template) => templates.insert(template.name().to_string(), template),
+            Err(error) => return error_reply(warp::http::StatusCode::UNPROCESSABLE_ENTITY, &error),
+        };
+    }
+
+    let mut registry = lock(&database);
+    let summary = match restore_servers(&database, &mut registry, own_port, snapshot.servers) {
+        Ok(summary) => summary,
+        Err(error) => return error_reply(warp::http::StatusCode::UNPROCESSABLE_ENTITY, &error),
+    };
+    registry.templates = templates;
+
+    warp::reply::json(&summary).into_response()
+}
+
+/// Converge on the `captured` servers like `POST /apply` does, then bring them to their
+/// captured status: stopped and crashed servers end up `stopped`, and paused ones
+/// `paused`. The server on `own_port` is left alone.
+pub fn restore_servers(
+    database: &Database,
+    registry: &mut Registry,
+    own_port: u16,
+    captured: Vec<ServerJsonBody>
+) -> Result<ApplySummary, String> {
+    let captured: Vec<ServerJsonBody> = captured.into_iter()
+        .filter(|server| server.port != own_port)
+        .collect();
+    let summary = converge(database, registry, own_port, captured.clone(), false)?;
+
+    for server in captured {
+        if summary.created.contains(&server.port) {
+            if let Some(created) = registry.servers.get_mut(&server.port) {
+                created.restarts = server.restarts;
+                created.created_at = server.created_at;
+            }
+        }
+        restore_status(database, registry, server.port, server.status);
+    }
+
+    Ok(summary)
+}
+
+/// Bring the server on `port` to the captured `status`, as far as a handler could
+fn restore_status(database: &Database, registry: &mut Registry, port: u16, status: ServerStatus) {
+    let down = |status| matches!(status, ServerStatus::Stopped | ServerStatus::Crashed);
+    let server = match registry.servers.get(&port) {
+        Some(server) => server,
+        None => return,
+    };
+
+    // A server that's down but shouldn't be is started, and paused below if need be
+    if down(server.status) && !down(status) {
+        let config = server.config.clone();
+        let restarts = server.restarts;
+        if let Err(e) = start_server(database, registry, config, restarts) {
+            return eprintln!("failed to restore server {}: {}", port, e);
+        }
+    }
+
+    let server = registry.servers.get_mut(&port).unwrap();
+    match status {
+        ServerStatus::Stopped | ServerStatus::Crashed if server.status.can_become(ServerStatus::Draining) => {
+            server.status = ServerStatus::Draining;
+            server.signal_shutdown();
+        }
+        ServerStatus::Paused if server.config.pausable() && matches!(server.status, ServerStatus::Starting | ServerStatus::Running) => {
+            server.paused.store(true, Ordering::SeqCst);
+            server.status = ServerStatus::Paused;
+        }
+        ServerStatus::Starting | ServerStatus::Running | ServerStatus::Draining
+            if server.status == ServerStatus::Paused =>
+        {
+            server.paused.store(false, Ordering::SeqCst);
+            server.status = ServerStatus::Running;
+        }
+        _ => return,
+    }
+    registry.record_change(port, ChangeType::Modified);
+}
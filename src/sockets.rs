@@ -0,0 +1,117 @@
+use futures::Async;
+use net2::TcpBuilder;
+#[cfg(unix)]
+use net2::unix::UnixTcpBuilderExt;
+
+use std::io;
+use std::net::{IpAddr, SocketAddr, TcpListener, ToSocketAddrs};
+
+/// Options of an HTTP or TCP server's listening socket, set before it's bound. A listener
+/// inherited from systemd or a previous process is used as it is.
+#[derive(Clone, Debug, PartialEq, serde_derive::Deserialize, serde_derive::Serialize)]
+#[serde(default)]
+pub struct SocketOptions {
+    /// The address listened on, or a host name resolved to it each time the server
+    /// starts, like `lab-eth1.local`
+    pub bind_addr: String,
+    /// Listen on every address `bind_addr` resolves to, rather than the first
+    pub bind_all_addresses: bool,
+    /// `SO_REUSEADDR`, letting the port be bound again while old connections are in
+    /// `TIME_WAIT`. Windows always lets it be, and its `SO_REUSEADDR` would let another
+    /// socket take over the port while it's listened on, so it's left unset there.
+    pub reuse_address: bool,
+    /// `SO_REUSEPORT`, letting other sockets with the option listen on the same port. Only
+    /// supported on Unix.
+    pub reuse_port: bool,
+    /// `TCP_NODELAY` on accepted connections
+    pub nodelay: bool,
+    /// Length of the queue of connections waiting to be accepted
+    pub backlog: i32,
+}
+
+impl Default for SocketOptions {
+    fn default() -> Self {
+        SocketOptions {
+            bind_addr: "127.0.0.1".to_string(),
+            bind_all_addresses: false,
+            reuse_address: true,
+            reuse_port: false,
+            nodelay: false,
+            backlog: 128,
+        }
+    }
+}
+
+impl SocketOptions {
+    pub fn is_default(&self) -> bool {
+        *self == SocketOptions::default()
+    }
+
+    /// The addresses `bind_addr` resolves to on `port`, or only the first of them unless
+    /// `bind_all_addresses`
+    pub fn addresses(&self, port: u16) -> io::Result<Vec<SocketAddr>> {
+        let mut addresses: Vec<SocketAddr> = Vec::new();
+        let resolved = resolve(&self.bind_addr, port)
+            .map_err(|e| io::Error::new(e.kind(), format!("can't resolve bind_addr {:?}: {}", self.bind_addr, e)))?;
+        for address in resolved {
+            if !addresses.contains(&address) {
+                addresses.push(address);
+            }
+        }
+        if addresses.is_empty() {
+            let error = format!("bind_addr {:?} resolves to no address", self.bind_addr);
+            return Err(io::Error::new(io::ErrorKind::AddrNotAvailable, error));
+        }
+        if !self.bind_all_addresses {
+            addresses.truncate(1);
+        }
+        Ok(addresses)
+    }
+
+    /// Bind a listener on every address of `addresses` with these options
+    pub fn bind(&self, port: u16) -> io::Result<Vec<TcpListener>> {
+        self.addresses(port)?.into_iter().map(|address| self.bind_address(address)).collect()
+    }
+
+    /// Bind a listener on `address` with these options
+    pub fn bind_address(&self, address: SocketAddr) -> io::Result<TcpListener> {
+        let builder = match address {
+            SocketAddr::V4(_) => TcpBuilder::new_v4()?,
+            SocketAddr::V6(_) => {
+                // Left to take IPv4 too, it would clash with a listener on the same port
+                // of an IPv4 address resolved alongside
+                let builder = TcpBuilder::new_v6()?;
+                builder.only_v6(true)?;
+                builder
+            }
+        };
+        #[cfg(unix)]
+        {
+            builder.reuse_address(self.reuse_address)?;
+            builder.reuse_port(self.reuse_port)?;
+        }
+        #[cfg(windows)]
+        if self.reuse_port {
+            return Err(io::Error::new(io::ErrorKind::Unsupported, "reuse_port is only supported on Unix"));
+        }
+        builder.bind(address)
+            .map_err(|e| io::Error::new(e.kind(), format!("can't bind {}: {}", address, e)))?;
+        builder.listen(self.backlog)
+    }
+}
+
+/// What `host` resolves to on `port`. Looking a host name up blocks, so on a worker of the
+/// runtime it's done as blocking work, for the pool to hand the worker's other tasks to
+/// another thread meanwhile. Off the runtime, or with no blocking thread to spare, it's
+/// looked up right here.
+fn resolve(host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+    if let Ok(ip) = host.parse::<IpAddr>() {
+        return Ok(vec![SocketAddr::new(ip, port)]);
+    }
+    let mut resolved = None;
+    let blocking = tokio_threadpool::blocking(|| resolved = Some((host, port).to_socket_addrs()));
+    match (blocking, resolved) {
+        (Ok(Async::Ready(())), Some(resolved)) => Ok(resolved?.collect()),
+        _ => Ok((host, port).to_socket_addrs()?.collect()),
+    }
+}
@@ -0,0 +1,67 @@
+use rusqlite::{params, Connection};
+
+use crate::ServerJsonBody;
+use crate::store::{Store, StoreError};
+
+/// Keeps the registry's servers in an SQLite database, as the JSON they're reported as
+pub struct SqliteStore {
+    connection: Connection,
+}
+
+impl SqliteStore {
+    /// Open the database at `path`, creating it if need be
+    pub fn open(path: &str) -> Result<SqliteStore, StoreError> {
+        let connection = Connection::open(path)?;
+        connection.execute_batch(
+            "CREATE TABLE IF NOT EXISTS servers (
+                port INTEGER PRIMARY KEY,
+                body TEXT NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS secrets (
+                name TEXT PRIMARY KEY,
+                value TEXT NOT NULL
+            )",
+        )?;
+        Ok(SqliteStore { connection })
+    }
+}
+
+impl Store for SqliteStore {
+    fn save(&mut self, server: &ServerJsonBody) -> Result<(), StoreError> {
+        let body = serde_json::to_string(server)?;
+        self.connection.execute(
+            "INSERT OR REPLACE INTO servers (port, body) VALUES (?1, ?2)",
+            params![server.port, body],
+        )?;
+        Ok(())
+    }
+
+    fn delete(&mut self, port: u16) -> Result<(), StoreError> {
+        self.connection.execute("DELETE FROM servers WHERE port = ?1", params![port])?;
+        Ok(())
+    }
+
+    fn load(&mut self) -> Result<Vec<ServerJsonBody>, StoreError> {
+        let mut statement = self.connection.prepare("SELECT body FROM servers ORDER BY port")?;
+        let bodies = statement.query_map([], |row| row.get::<_, String>(0))?
+            .collect::<Result<Vec<String>, _>>()?;
+
+        bodies.iter()
+            .map(|body| Ok(serde_json::from_str(body)?))
+            .collect()
+    }
+
+    fn save_secret(&mut self, name: &str, value: &str) -> Result<(), StoreError> {
+        self.connection.execute(
+            "INSERT OR REPLACE INTO secrets (name, value) VALUES (?1, ?2)",
+            params![name, value],
+        )?;
+        Ok(())
+    }
+
+    fn load_secret(&mut self, name: &str) -> Result<Option<String>, StoreError> {
+        let mut statement = self.connection.prepare("SELECT value FROM secrets WHERE name = ?1")?;
+        let mut values = statement.query_map(params![name], |row| row.get::<_, String>(0))?;
+        Ok(values.next().transpose()?)
+    }
+}
@@ -0,0 +1,45 @@
+use warp::Reply;
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+use crate::{error_reply, Database};
+use crate::error::lock;
+
+/// What an in-process HTTP server keeps between requests, by key, for stateful mocks like
+/// a fake cart. Read and counted up by the placeholders of templated bodies, see
+/// `faker::BodyTemplate`, and set with `PUT /{port}/state`. Empty whenever the server
+/// (re)starts.
+pub type SharedState = Arc<Mutex<BTreeMap<String, serde_json::Value>>>;
+
+/// The state of the server on `port`, or the status and error to answer with if it has none
+fn find_state(database: &Database, port: u16) -> Result<SharedState, (warp::http::StatusCode, String)> {
+    let registry = lock(database);
+    let server = registry.servers.get(&port)
+        .ok_or_else(|| (warp::http::StatusCode::NOT_FOUND, format!("no server on port {}", port)))?;
+    server.state.clone()
+        .ok_or_else(|| (warp::http::StatusCode::CONFLICT, "only in-process HTTP servers keep state".to_string()))
+}
+
+/// `GET /{port}/state`: what the HTTP server on `port` keeps, by key
+pub fn get_state(database: Database, port: u16) -> warp::reply::Response {
+    match find_state(&database, port) {
+        Ok(state) => warp::reply::json(&*lock(&state)).into_response(),
+        Err((status, error)) => error_reply(status, &error),
+    }
+}
+
+/// `PUT /{port}/state`: replace what the HTTP server on `port` keeps with `state`
+pub fn put_state(
+    database: Database,
+    port: u16,
+    state: BTreeMap<String, serde_json::Value>
+) -> warp::reply::Response {
+    match find_state(&database, port) {
+        Ok(shared) => {
+            *lock(&shared) = state;
+            warp::reply::json(&*lock(&shared)).into_response()
+        }
+        Err((status, error)) => error_reply(status, &error),
+    }
+}
@@ -0,0 +1,95 @@
+use std::collections::BTreeMap;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// How metrics are written to the statsd endpoint
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    /// Plain statsd, without tags, the port being part of each metric's name
+    Statsd,
+    /// statsd with DogStatsD tags, the port, labels and status being tags
+    DogStatsd,
+}
+
+/// Pushes a counter and a timer of every request an in-process HTTP server answers to a
+/// statsd endpoint over UDP, beside the histograms `GET /metrics` renders. Like those,
+/// they're recorded by the `metrics` layer.
+#[derive(Debug)]
+pub struct Statsd {
+    socket: UdpSocket,
+    addr: SocketAddr,
+    format: Format,
+    prefix: String,
+}
+
+impl Statsd {
+    /// `STATSD_ADDR` is the `host:port` metrics are sent to. `STATSD_FORMAT` is
+    /// `dogstatsd`, the default, or `statsd` for an endpoint not taking tags, and
+    /// `STATSD_PREFIX` starts the name of every metric, `mock_server` by default.
+    pub fn from_env() -> Result<Option<Arc<Statsd>>, String> {
+        let addr = match std::env::var("STATSD_ADDR") {
+            Ok(addr) => addr,
+            Err(_) => return Ok(None),
+        };
+        let resolved = addr.to_socket_addrs().ok()
+            .and_then(|mut addrs| addrs.next())
+            .ok_or_else(|| format!("invalid STATSD_ADDR {:?}, expected host:port", addr))?;
+        let format = match std::env::var("STATSD_FORMAT").as_deref() {
+            Ok("dogstatsd") | Err(_) => Format::DogStatsd,
+            Ok("statsd") => Format::Statsd,
+            Ok(format) => return Err(format!("invalid STATSD_FORMAT {:?}, expected dogstatsd or statsd", format)),
+        };
+        let prefix = std::env::var("STATSD_PREFIX").unwrap_or_else(|_| "mock_server".to_string());
+
+        let local: SocketAddr = if resolved.is_ipv4() { ([0, 0, 0, 0], 0).into() } else { ([0u16; 8], 0).into() };
+        let socket = UdpSocket::bind(local)
+            .and_then(|socket| socket.set_nonblocking(true).map(|_| socket))
+            .map_err(|e| format!("failed to open a socket for statsd: {}", e))?;
+        Ok(Some(Arc::new(Statsd { socket, addr: resolved, format, prefix })))
+    }
+
+    /// What the server on `port` labelled `labels` sends its metrics with
+    pub fn emitter(self: &Arc<Statsd>, port: u16, labels: &BTreeMap<String, String>) -> Emitter {
+        let tags = std::iter::once(format!("port:{}", port))
+            .chain(labels.iter().map(|(name, value)| format!("{}:{}", tag(name), tag(value))))
+            .collect::<Vec<_>>()
+            .join(",");
+        Emitter { statsd: self.clone(), port, tags }
+    }
+}
+
+/// A server's handle on the statsd endpoint
+#[derive(Clone, Debug)]
+pub struct Emitter {
+    statsd: Arc<Statsd>,
+    port: u16,
+    /// The DogStatsD tags of each of the server's metrics
+    tags: String,
+}
+
+impl Emitter {
+    /// Count a request answered with `status` after `elapsed`
+    pub fn request(&self, status: u16, elapsed: Duration) {
+        let millis = elapsed.as_secs_f64() * 1000.0;
+        let prefix = &self.statsd.prefix;
+        let payload = match self.statsd.format {
+            Format::DogStatsd => format!(
+                "{prefix}.requests:1|c|#{tags},status:{status}\n{prefix}.request_duration:{millis:.3}|ms|#{tags},status:{status}",
+                prefix = prefix, tags = self.tags, status = status, millis = millis,
+            ),
+            Format::Statsd => format!(
+                "{prefix}.{port}.requests:1|c\n{prefix}.{port}.request_duration:{millis:.3}|ms",
+                prefix = prefix, port = self.port, millis = millis,
+            ),
+        };
+        // Metrics are dropped rather than held up when the socket's buffer is full, as
+        // they would be on the wire
+        let _ = self.statsd.socket.send_to(payload.as_bytes(), self.statsd.addr);
+    }
+}
+
+/// `value` with the characters DogStatsD separates tags and fields by replaced
+fn tag(value: &str) -> String {
+    value.replace([',', '|', '#', ':', '\n'], "_")
+}
@@ -0,0 +1,146 @@
+use futures::sync::mpsc;
+
+use std::collections::BTreeMap;
+
+use crate::ServerJsonBody;
+
+/// Failures of a `Store`
+#[derive(Debug, thiserror::Error)]
+pub enum StoreError {
+    #[cfg(feature = "sqlite")]
+    #[error("sqlite error: {0}")]
+    Sqlite(#[from] rusqlite::Error),
+    #[cfg(feature = "redis")]
+    #[error("redis error: {0}")]
+    Redis(#[from] redis::RedisError),
+    #[cfg(feature = "raft")]
+    #[error("raft error: {0}")]
+    Raft(String),
+    #[error("invalid stored server: {0}")]
+    Json(#[from] serde_json::Error),
+}
+
+/// Where the registry keeps its servers beyond the process. The registry saves a
+/// server after every change to it and deletes it once removed, so a store always holds
+/// the latest state of every registered server.
+pub trait Store: Send {
+    fn save(&mut self, server: &ServerJsonBody) -> Result<(), StoreError>;
+    fn delete(&mut self, port: u16) -> Result<(), StoreError>;
+    /// Every stored server, as last saved
+    fn load(&mut self) -> Result<Vec<ServerJsonBody>, StoreError>;
+
+    /// The servers of every instance sharing the store, by instance, if it's shared
+    fn fleet(&mut self) -> Result<Option<BTreeMap<String, Vec<ServerJsonBody>>>, StoreError> {
+        Ok(None)
+    }
+
+    /// Changes to the servers of every instance sharing the store, as JSON, if it's shared
+    fn subscribe(&mut self) -> Result<Option<mpsc::UnboundedReceiver<String>>, StoreError> {
+        Ok(None)
+    }
+
+    /// Keep `value` under `name` for this instance, like its ACME account key. Stores that
+    /// keep nothing forget it.
+    fn save_secret(&mut self, _name: &str, _value: &str) -> Result<(), StoreError> {
+        Ok(())
+    }
+
+    fn load_secret(&mut self, _name: &str) -> Result<Option<String>, StoreError> {
+        Ok(None)
+    }
+}
+
+/// Keeps nothing, leaving the registry in memory only
+#[derive(Clone, Debug, Default)]
+pub struct MemoryStore;
+
+impl Store for MemoryStore {
+    fn save(&mut self, _server: &ServerJsonBody) -> Result<(), StoreError> {
+        Ok(())
+    }
+
+    fn delete(&mut self, _port: u16) -> Result<(), StoreError> {
+        Ok(())
+    }
+
+    fn load(&mut self) -> Result<Vec<ServerJsonBody>, StoreError> {
+        Ok(Vec::new())
+    }
+}
+
+impl Default for Box<dyn Store> {
+    fn default() -> Self {
+        Box::new(MemoryStore)
+    }
+}
+
+/// The store selected with `SQLITE_PATH`, `REDIS_URL` or `RAFT_PEERS`, or else a
+/// `MemoryStore`. A Redis or a Raft cluster is shared by instances with different
+/// `INSTANCE_ID`s, by default the host name.
+pub fn from_env() -> Result<Box<dyn Store>, String> {
+    let selected = ["SQLITE_PATH", "REDIS_URL", "RAFT_PEERS"].map(|var| std::env::var(var).ok());
+    match selected {
+        [Some(path), None, None] => open_sqlite(&path),
+        [None, Some(url), None] => open_redis(&url),
+        [None, None, Some(peers)] => open_raft(&peers),
+        [None, None, None] => Ok(Box::default()),
+        _ => Err("only one of SQLITE_PATH, REDIS_URL and RAFT_PEERS can be set".to_string()),
+    }
+}
+
+fn open_sqlite(path: &str) -> Result<Box<dyn Store>, String> {
+    #[cfg(feature = "sqlite")]
+    {
+        crate::sqlite::SqliteStore::open(path)
+            .map(|store| Box::new(store) as Box<dyn Store>)
+            .map_err(|e| format!("failed to open {}: {}", path, e))
+    }
+    #[cfg(not(feature = "sqlite"))]
+    {
+        Err(format!("can't open {}: built without the sqlite feature", path))
+    }
+}
+
+fn open_redis(url: &str) -> Result<Box<dyn Store>, String> {
+    #[cfg(feature = "redis")]
+    {
+        let instance = std::env::var("INSTANCE_ID").unwrap_or_else(|_| host_name());
+        crate::redis_store::RedisStore::open(url, instance)
+            .map(|store| Box::new(store) as Box<dyn Store>)
+            .map_err(|e| format!("failed to connect to {}: {}", url, e))
+    }
+    #[cfg(not(feature = "redis"))]
+    {
+        Err(format!("can't connect to {}: built without the redis feature", url))
+    }
+}
+
+fn open_raft(peers: &str) -> Result<Box<dyn Store>, String> {
+    #[cfg(feature = "raft")]
+    {
+        let config = crate::raft::RaftConfig::from_env(peers)?;
+        let instance = std::env::var("INSTANCE_ID").unwrap_or_else(|_| host_name());
+        crate::raft::RaftStore::open(config, instance).map(|store| Box::new(store) as Box<dyn Store>)
+    }
+    #[cfg(not(feature = "raft"))]
+    {
+        Err(format!("can't join {}: built without the raft feature", peers))
+    }
+}
+
+/// The name of the machine, or `localhost` if it can't be told
+#[cfg(unix)]
+pub fn host_name() -> String {
+    let mut name = [0u8; 256];
+    // The buffer outlives the call, and its last byte stays 0 whatever the name's length
+    if unsafe { libc::gethostname(name.as_mut_ptr() as *mut libc::c_char, name.len() - 1) } != 0 {
+        return "localhost".to_string();
+    }
+    let len = name.iter().position(|byte| *byte == 0).unwrap_or(0);
+    String::from_utf8_lossy(&name[..len]).into_owned()
+}
+
+#[cfg(all(windows, any(feature = "redis", feature = "raft", feature = "mdns")))]
+pub fn host_name() -> String {
+    std::env::var("COMPUTERNAME").unwrap_or_else(|_| "localhost".to_string())
+}
@@ -0,0 +1,632 @@
+use warp::Reply;
+use warp::http::header::{HeaderMap, HeaderName, HeaderValue};
+
+use std::collections::BTreeMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::{clients, error_reply, headers, objects, openapi, server_reply, unix_time, unmatched, Database, Isolation, ServerJsonBody, ServerKind, ServerStatus};
+use crate::activity::Activity;
+use crate::crud::Crud;
+use crate::downloads::Download;
+use crate::error::lock;
+use crate::failures::FailureRule;
+use crate::fallback::Fallback;
+use crate::headers::HeaderRoute;
+use crate::requestbin::RequestBin;
+use crate::throttling::{RateLimitRule, Windows};
+use crate::vhosts::{self, VirtualHost};
+
+/// What of an HTTP server's configuration takes requests before its routes do
+#[derive(Clone, Debug, serde_derive::Deserialize, serde_derive::Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Stub {
+    RateLimitRule {
+        index: usize,
+        #[serde(flatten)]
+        rule: RateLimitRule,
+    },
+    FailureRule {
+        index: usize,
+        #[serde(flatten)]
+        rule: FailureRule,
+    },
+    /// Taking every request the failure rules let through, see `requestbin::RequestBin`
+    RequestBin {
+        #[serde(flatten)]
+        request_bin: RequestBin,
+    },
+    VirtualHost {
+        host: String,
+        port: u16,
+    },
+    HeaderRoute {
+        index: usize,
+        #[serde(flatten)]
+        route: HeaderRoute,
+    },
+    Download {
+        path: String,
+        #[serde(flatten)]
+        download: Download,
+    },
+    /// An operation of the server's OpenAPI document
+    Operation {
+        index: usize,
+        method: String,
+        path: String,
+    },
+    /// A CRUD collection, see `crud::Crud`
+    Collection {
+        name: String,
+        /// Items it started with
+        seeded: usize,
+    },
+    Fallback {
+        #[serde(flatten)]
+        fallback: Fallback,
+    },
+}
+
+impl Stub {
+    /// What the stub is told by in `POST /{port}/stubs/{id}/reset`, like `header_route:1`
+    fn id(&self) -> String {
+        match self {
+            Stub::RateLimitRule { index, .. } => format!("rate_limit_rule:{}", index),
+            Stub::FailureRule { index, .. } => format!("failure_rule:{}", index),
+            Stub::RequestBin { .. } => "request_bin".to_string(),
+            Stub::VirtualHost { host, .. } => format!("virtual_host:{}", host),
+            Stub::HeaderRoute { index, .. } => format!("header_route:{}", index),
+            Stub::Download { path, .. } => format!("download:{}", path),
+            Stub::Operation { index, .. } => format!("operation:{}", index),
+            Stub::Collection { name, .. } => format!("collection:{}", name),
+            Stub::Fallback { .. } => "fallback".to_string(),
+        }
+    }
+
+    fn activity(&self) -> Option<&Activity> {
+        match self {
+            Stub::RateLimitRule { rule, .. } => Some(&rule.activity),
+            Stub::FailureRule { rule, .. } => Some(&rule.activity),
+            Stub::HeaderRoute { route, .. } => Some(&route.activity),
+            Stub::Fallback { fallback } => Some(&fallback.activity),
+            _ => None,
+        }
+    }
+}
+
+/// A stub in the `GET /{port}/stubs` listing
+#[derive(Clone, Debug, serde_derive::Deserialize, serde_derive::Serialize)]
+pub struct ListedStub {
+    pub id: String,
+    /// Requests the stub matched since the server (re)started or the stub was reset.
+    /// Those of a server that isn't an in-process HTTP server aren't counted, nor are those
+    /// of the request bin, which keeps them in the journal, and of downloads.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub matched: Option<u64>,
+    /// Whether a stub active only for a while is now, see `activity::Activity`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub active: Option<bool>,
+    #[serde(flatten)]
+    pub stub: Stub,
+}
+
+/// How many requests each stub of an in-process HTTP server matched
+#[derive(Debug, Default)]
+pub struct Hits {
+    /// Along with the windows of the clients they limit, see `throttling::throttle`
+    pub rate_limit_rules: Vec<Windows>,
+    /// Those of the failure rules are what they count failures by, see `failures::fail`
+    pub failure_rules: Vec<AtomicU64>,
+    header_routes: Vec<AtomicU64>,
+    operations: Vec<AtomicU64>,
+    collections: BTreeMap<String, AtomicU64>,
+    /// By the name the virtual host is kept under, as they come and go while the server runs
+    virtual_hosts: Mutex<BTreeMap<String, u64>>,
+    fallback: AtomicU64,
+    /// When the server (re)started by its clock, that `ttl_secs` of a stub counts from, see
+    /// `activity::Activity`
+    pub started_ms: u64,
+}
+
+impl Hits {
+    pub fn new(config: &ServerJsonBody, started_ms: u64) -> Hits {
+        Hits {
+            started_ms,
+            rate_limit_rules: config.rate_limit_rules.iter().map(|_| Windows::default()).collect(),
+            failure_rules: config.failure_rules.iter().map(|_| AtomicU64::new(0)).collect(),
+            header_routes: config.header_routes.iter().map(|_| AtomicU64::new(0)).collect(),
+            operations: config.openapi.iter().flat_map(openapi::operations).map(|_| AtomicU64::new(0)).collect(),
+            collections: config.crud.iter()
+                .flat_map(|crud| crud.collections.keys())
+                .map(|name| (name.clone(), AtomicU64::new(0)))
+                .collect(),
+            ..Hits::default()
+        }
+    }
+
+    pub fn header_route(&self, index: usize) {
+        if let Some(hits) = self.header_routes.get(index) {
+            hits.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn operation(&self, index: usize) {
+        if let Some(hits) = self.operations.get(index) {
+            hits.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn collection(&self, name: &str) {
+        if let Some(hits) = self.collections.get(name) {
+            hits.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn virtual_host(&self, host: &str) {
+        *lock(&self.virtual_hosts).entry(host.to_string()).or_insert(0) += 1;
+    }
+
+    /// Count a request the fallback takes, answering how many it took before
+    pub fn fallback(&self) -> u64 {
+        self.fallback.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// The counter of the stub `id`, if the server has the stub
+    fn counter(&self, id: &str) -> Option<&AtomicU64> {
+        let (kind, key) = id.split_once(':').unwrap_or((id, ""));
+        match kind {
+            "rate_limit_rule" => self.rate_limit_rules.get(key.parse::<usize>().ok()?).map(|windows| &windows.matched),
+            "failure_rule" => self.failure_rules.get(key.parse::<usize>().ok()?),
+            "header_route" => self.header_routes.get(key.parse::<usize>().ok()?),
+            "operation" => self.operations.get(key.parse::<usize>().ok()?),
+            "collection" => self.collections.get(key),
+            "fallback" if key.is_empty() => Some(&self.fallback),
+            _ => None,
+        }
+    }
+
+    fn matched(&self, stub: &Stub) -> Option<u64> {
+        match stub {
+            Stub::VirtualHost { host, .. } => Some(lock(&self.virtual_hosts).get(host).copied().unwrap_or(0)),
+            stub => self.counter(&stub.id()).map(|hits| hits.load(Ordering::Relaxed)),
+        }
+    }
+
+    /// Start counting the stub `id` over, if the server has it
+    fn reset(&self, id: &str, config: &ServerJsonBody) -> bool {
+        if let Some(host) = id.strip_prefix("virtual_host:") {
+            let host = host.to_ascii_lowercase();
+            lock(&self.virtual_hosts).remove(&host);
+            return config.virtual_hosts.contains_key(&host);
+        }
+        let rule = id.strip_prefix("rate_limit_rule:").and_then(|index| index.parse::<usize>().ok());
+        if let Some(windows) = rule.and_then(|index| self.rate_limit_rules.get(index)) {
+            windows.clear();
+        }
+        match self.counter(id) {
+            Some(hits) => {
+                hits.store(0, Ordering::Relaxed);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// `GET /{port}/stubs` report, also what `PUT` and `PATCH /{port}/stubs` answer with
+#[derive(Clone, Debug, serde_derive::Deserialize, serde_derive::Serialize)]
+pub struct StubsReport {
+    /// In the order they're tried
+    pub stubs: Vec<ListedStub>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub resolution: Option<Resolution>,
+}
+
+/// How the hypothetical request of `?resolve=` would be taken
+#[derive(Clone, Debug, serde_derive::Deserialize, serde_derive::Serialize)]
+pub struct Resolution {
+    pub method: String,
+    pub path: String,
+    /// What answers the request
+    pub taken_by: String,
+    /// The stubs tried before
+    pub steps: Vec<ResolutionStep>,
+}
+
+#[derive(Clone, Debug, serde_derive::Deserialize, serde_derive::Serialize)]
+pub struct ResolutionStep {
+    pub stub: String,
+    pub matched: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub note: Option<String>,
+}
+
+/// A request to resolve, from the query of `GET /{port}/stubs`: `resolve` is its path,
+/// `method` its method, GET by default, every `header` one of its headers, like
+/// `header=Host: api.test`, and `client` the address it comes from, like `10.1.2.3` or
+/// `10.1.2.3:5000`
+struct Hypothetical {
+    method: warp::http::Method,
+    path: String,
+    headers: HeaderMap,
+    client: Option<SocketAddr>,
+}
+
+impl Hypothetical {
+    fn from_query(query: Vec<(String, String)>) -> Result<Option<Hypothetical>, String> {
+        let mut path = None;
+        let mut method = warp::http::Method::GET;
+        let mut headers = HeaderMap::new();
+        let mut client = None;
+        for (key, value) in query {
+            match key.as_str() {
+                "resolve" => path = Some(value),
+                "method" => {
+                    method = value.to_ascii_uppercase().parse()
+                        .map_err(|_| format!("invalid method {:?}", value))?;
+                }
+                "header" => {
+                    let (name, value) = value.split_once(':')
+                        .ok_or_else(|| format!("invalid header {:?}, expected `name: value`", value))?;
+                    let name = HeaderName::from_bytes(name.trim().as_bytes())
+                        .map_err(|_| format!("invalid header name {:?}", name))?;
+                    let value = HeaderValue::from_str(value.trim())
+                        .map_err(|_| format!("invalid value of header {}", name))?;
+                    headers.append(name, value);
+                }
+                "client" => {
+                    let address = value.parse::<SocketAddr>()
+                        .or_else(|_| value.parse::<IpAddr>().map(|ip| SocketAddr::new(ip, 0)))
+                        .map_err(|_| format!("invalid client {:?}, expected an address or an address and port", value))?;
+                    client = Some(address);
+                }
+                key => return Err(format!("unknown query parameter {:?}", key)),
+            }
+        }
+        match path {
+            Some(path) if !path.starts_with('/') => Err(format!("path {:?} doesn't start with /", path)),
+            Some(path) => Ok(Some(Hypothetical { method, path, headers, client })),
+            None if headers.is_empty() && client.is_none() => Ok(None),
+            None => Err("headers and client are only given along with resolve".to_string()),
+        }
+    }
+
+    /// How the request would be taken, by the stubs `active` says are
+    fn resolve(&self, config: &ServerJsonBody, active: &dyn Fn(&Activity) -> bool) -> Resolution {
+        let path = self.path.split_once('?').map_or(self.path.as_str(), |(path, _)| path);
+        let mut steps = Vec::new();
+        let resolution = |steps, taken_by| Resolution {
+            method: self.method.to_string(),
+            path: self.path.clone(),
+            taken_by,
+            steps,
+        };
+
+        if !clients::matches(&config.allowed_clients, self.client) {
+            steps.push(ResolutionStep { stub: "allowed clients".to_string(), matched: false, note: None });
+            return resolution(steps, "nothing, answering with 403 as the client isn't allowed".to_string());
+        }
+
+        // Every rate-limit rule matching counts the request, the first one with none left
+        // for its client answering it
+        for (index, rule) in config.rate_limit_rules.iter().enumerate() {
+            if !active(&rule.activity) {
+                steps.push(ResolutionStep { stub: format!("rate-limit rule {}", index), matched: false, note: inactive() });
+                continue;
+            }
+            let matched = rule.matches(&self.method, path, self.client);
+            let note = Some(format!("answers with 429 if the client has used up its {} requests every {} seconds", rule.limit, rule.window_secs))
+                .filter(|_| matched);
+            steps.push(ResolutionStep { stub: format!("rate-limit rule {}", index), matched, note });
+        }
+
+        // Every failure rule matching counts the request, failing it or letting it through
+        for (index, rule) in config.failure_rules.iter().enumerate() {
+            if !active(&rule.activity) {
+                steps.push(ResolutionStep { stub: format!("failure rule {}", index), matched: false, note: inactive() });
+                continue;
+            }
+            let matched = rule.matches(&self.method, path, self.client);
+            let note = Some(format!("fails it with {} if it's one of those the rule fails", rule.status)).filter(|_| matched);
+            steps.push(ResolutionStep { stub: format!("failure rule {}", index), matched, note });
+        }
+
+        if let Some(bin) = &config.request_bin {
+            steps.push(ResolutionStep { stub: "request bin".to_string(), matched: true, note: None });
+            return resolution(steps, format!("request bin, answering with {}", bin.status));
+        }
+
+        if !config.virtual_hosts.is_empty() {
+            let host = self.headers.get(warp::http::header::HOST).and_then(|host| host.to_str().ok());
+            match host.and_then(|host| vhosts::lookup(&config.virtual_hosts, host)) {
+                Some((host, virtual_host)) => {
+                    steps.push(ResolutionStep { stub: format!("virtual host {}", host), matched: true, note: None });
+                    return resolution(steps, format!("virtual host {}, forwarding to server {}", host, virtual_host.port));
+                }
+                None => steps.push(ResolutionStep { stub: "virtual hosts".to_string(), matched: false, note: None }),
+            }
+        }
+
+        for (index, route) in headers::ordered(&config.header_routes) {
+            if !active(&route.activity) {
+                steps.push(ResolutionStep { stub: format!("header route {}", index), matched: false, note: inactive() });
+                continue;
+            }
+            let matched = route.matches(&self.headers, self.client);
+            let note = Some("answers with 422 unless the body conforms to the route's schema".to_string())
+                .filter(|_| matched && route.schema.is_some());
+            steps.push(ResolutionStep { stub: format!("header route {}", index), matched, note });
+            if matched {
+                return resolution(steps, format!("header route {}, forwarding to server {}", index, route.port));
+            }
+        }
+
+        if !config.downloads.is_empty() {
+            match config.downloads.get(path).filter(|_| self.method == warp::http::Method::GET || self.method == warp::http::Method::HEAD) {
+                Some(_) => {
+                    steps.push(ResolutionStep { stub: format!("download {}", path), matched: true, note: None });
+                    return resolution(steps, format!("download {}", path));
+                }
+                None => steps.push(ResolutionStep { stub: "downloads".to_string(), matched: false, note: None }),
+            }
+        }
+
+        if let Some(document) = &config.openapi {
+            let endpoints = openapi::operations(document);
+            match openapi::find(&endpoints, self.method.as_str(), path) {
+                Some(index) => {
+                    let note = Some("answers with 422 unless the request conforms to the OpenAPI document".to_string());
+                    steps.push(ResolutionStep { stub: format!("operation {}", index), matched: true, note });
+                    let endpoint = &endpoints[index];
+                    return resolution(steps, format!("operation {} {} {}, answering with its example", index, endpoint.method, endpoint.path));
+                }
+                None => steps.push(ResolutionStep { stub: "operations".to_string(), matched: false, note: None }),
+            }
+        }
+
+        if let Some(crud) = &config.crud {
+            match crud.route(path) {
+                Some((name, _)) => {
+                    steps.push(ResolutionStep { stub: format!("collection {}", name), matched: true, note: None });
+                    return resolution(steps, format!("collection {}", name));
+                }
+                None => steps.push(ResolutionStep { stub: "collections".to_string(), matched: false, note: None }),
+            }
+        }
+
+        let route = unmatched::route_for(self.method.as_str(), path);
+        if config.fallback.as_ref().is_some_and(|fallback| !active(&fallback.activity)) {
+            steps.push(ResolutionStep { stub: "fallback".to_string(), matched: false, note: inactive() });
+        } else if let Some(fallback) = &config.fallback {
+            let note = Some("answers with 422 unless the body conforms to the fallback's schema".to_string())
+                .filter(|_| route.is_none() && fallback.schema.is_some());
+            steps.push(ResolutionStep { stub: "fallback".to_string(), matched: route.is_none(), note });
+            if route.is_none() {
+                let taken_by = match &fallback.upstream {
+                    Some(upstream) => format!("fallback, forwarding to {}", upstream),
+                    None => format!("fallback, answering with {}", fallback.status),
+                };
+                return resolution(steps, taken_by);
+            }
+        }
+        let taken_by = match route {
+            Some((method, path)) => format!("route {} {}", method, path),
+            None => "nothing, answering with 404 or 405".to_string(),
+        };
+        resolution(steps, taken_by)
+    }
+}
+
+fn inactive() -> Option<String> {
+    Some("not active now".to_string())
+}
+
+/// `GET /{port}/stubs?resolve={path}&method=&header=&client=`: what takes requests on the HTTP
+/// server on `port` before its routes do, in the order they're tried with how many each
+/// matched, and which would take a request for `path`, with how it got there
+pub fn stubs(database: Database, port: u16, query: Vec<(String, String)>) -> warp::reply::Response {
+    let hypothetical = match Hypothetical::from_query(query) {
+        Ok(hypothetical) => hypothetical,
+        Err(error) => return error_reply(warp::http::StatusCode::BAD_REQUEST, &error),
+    };
+    let registry = lock(&database);
+    let server = match registry.servers.get(&port) {
+        Some(server) => server,
+        None => return error_reply(warp::http::StatusCode::NOT_FOUND, &format!("no server on port {}", port)),
+    };
+    let config = &server.config;
+    let started_ms = server.stub_hits.as_ref().map_or(0, |hits| hits.started_ms);
+    let now_ms = server.clock.as_ref().map_or(unix_time() * 1000, |clock| lock(clock).now_ms());
+    let active = |activity: &Activity| activity.is_active(started_ms, now_ms);
+
+    let mut stubs: Vec<Stub> = config.rate_limit_rules.iter().cloned().enumerate()
+        .map(|(index, rule)| Stub::RateLimitRule { index, rule })
+        .collect();
+    stubs.extend(config.failure_rules.iter().cloned().enumerate().map(|(index, rule)| Stub::FailureRule { index, rule }));
+    stubs.extend(config.request_bin.iter().cloned().map(|request_bin| Stub::RequestBin { request_bin }));
+    // Named hosts are tried before wildcards
+    let (wildcards, named): (Vec<_>, Vec<_>) = config.virtual_hosts.iter().partition(|(host, _)| host.starts_with("*."));
+    let virtual_hosts = named.into_iter().chain(wildcards);
+    stubs.extend(virtual_hosts.map(|(host, virtual_host)| Stub::VirtualHost { host: host.clone(), port: virtual_host.port }));
+    stubs.extend(headers::ordered(&config.header_routes).into_iter().map(|(index, route)| Stub::HeaderRoute { index, route: route.clone() }));
+    stubs.extend(config.downloads.iter().map(|(path, download)| Stub::Download { path: path.clone(), download: download.clone() }));
+    let operations = config.openapi.iter().flat_map(openapi::operations).enumerate();
+    stubs.extend(operations.map(|(index, endpoint)| Stub::Operation { index, method: endpoint.method, path: endpoint.path }));
+    let collections = config.crud.iter().flat_map(|crud| &crud.collections);
+    stubs.extend(collections.map(|(name, items)| Stub::Collection { name: name.clone(), seeded: items.len() }));
+    stubs.extend(config.fallback.iter().cloned().map(|fallback| Stub::Fallback { fallback }));
+
+    let stubs = stubs.into_iter()
+        .map(|stub| ListedStub {
+            id: stub.id(),
+            matched: server.stub_hits.as_ref().and_then(|hits| hits.matched(&stub)),
+            active: stub.activity().filter(|activity| !activity.is_default()).map(active),
+            stub,
+        })
+        .collect();
+    let report = StubsReport { stubs, resolution: hypothetical.map(|hypothetical| hypothetical.resolve(config, &active)) };
+    warp::reply::json(&report).into_response()
+}
+
+/// `POST /{port}/stubs/{id}/reset`: start counting the requests the stub `id` of the HTTP
+/// server on `port` matches over from zero, `id` percent-encoded where it has to be, like
+/// `virtual_host:%2A.example.com`. A failure rule starts over failing requests
+/// as it did when the server started, and a rate-limit rule gives every client its whole
+/// limit again.
+pub fn reset(database: Database, port: u16, id: String) -> warp::reply::Response {
+    let id = objects::percent_decode(&id);
+    let registry = lock(&database);
+    let server = match registry.servers.get(&port) {
+        Some(server) => server,
+        None => return error_reply(warp::http::StatusCode::NOT_FOUND, &format!("no server on port {}", port)),
+    };
+    let hits = match &server.stub_hits {
+        Some(hits) => hits,
+        None => return error_reply(warp::http::StatusCode::CONFLICT, "only in-process HTTP servers count what their stubs match"),
+    };
+    if hits.reset(&id, &server.config) {
+        warp::http::StatusCode::NO_CONTENT.into_response()
+    } else {
+        error_reply(warp::http::StatusCode::NOT_FOUND, &format!("server {} has no stub {}", port, id))
+    }
+}
+
+/// Every stub of an HTTP server, the part of its configuration `PUT /{port}/stubs`
+/// replaces. What's left out of it is cleared.
+#[derive(Clone, Debug, Default, serde_derive::Deserialize, serde_derive::Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct StubSet {
+    #[serde(default)]
+    pub rate_limit_rules: Vec<RateLimitRule>,
+    #[serde(default)]
+    pub failure_rules: Vec<FailureRule>,
+    #[serde(default)]
+    pub request_bin: Option<RequestBin>,
+    #[serde(default)]
+    pub virtual_hosts: BTreeMap<String, VirtualHost>,
+    #[serde(default)]
+    pub header_routes: Vec<HeaderRoute>,
+    #[serde(default)]
+    pub downloads: BTreeMap<String, Download>,
+    #[serde(default)]
+    pub openapi: Option<serde_json::Value>,
+    #[serde(default)]
+    pub crud: Option<Crud>,
+    #[serde(default)]
+    pub fallback: Option<Fallback>,
+}
+
+impl StubSet {
+    fn of(config: &ServerJsonBody) -> StubSet {
+        StubSet {
+            rate_limit_rules: config.rate_limit_rules.clone(),
+            failure_rules: config.failure_rules.clone(),
+            request_bin: config.request_bin.clone(),
+            virtual_hosts: config.virtual_hosts.clone(),
+            header_routes: config.header_routes.clone(),
+            downloads: config.downloads.clone(),
+            openapi: config.openapi.clone(),
+            crud: config.crud.clone(),
+            fallback: config.fallback.clone(),
+        }
+    }
+
+    /// `config` with these stubs in place of its own
+    fn config(self, config: &ServerJsonBody) -> ServerJsonBody {
+        ServerJsonBody {
+            rate_limit_rules: self.rate_limit_rules,
+            failure_rules: self.failure_rules,
+            request_bin: self.request_bin,
+            virtual_hosts: self.virtual_hosts,
+            header_routes: self.header_routes,
+            downloads: self.downloads,
+            openapi: self.openapi,
+            crud: self.crud,
+            fallback: self.fallback,
+            ..config.clone()
+        }
+    }
+}
+
+/// `PUT /{port}/stubs`: replace every stub of the HTTP server on `port` at once, see
+/// `swap`
+pub fn replace_stubs(
+    database: Database,
+    port: u16,
+    if_match: Option<String>,
+    stubs: StubSet
+) -> Result<warp::reply::Response, warp::Rejection> {
+    swap(database, port, if_match, |_| Ok(stubs))
+}
+
+/// `PATCH /{port}/stubs`: replace the kinds of stub of the HTTP server on `port` the body
+/// has, like `header_routes`, keeping the others. `null` clears them. See `swap`.
+pub fn patch_stubs(
+    database: Database,
+    port: u16,
+    if_match: Option<String>,
+    patch: serde_json::Map<String, serde_json::Value>
+) -> Result<warp::reply::Response, warp::Rejection> {
+    swap(database, port, if_match, |config| {
+        let mut stubs = match serde_json::to_value(StubSet::of(config)) {
+            Ok(serde_json::Value::Object(stubs)) => stubs,
+            _ => serde_json::Map::new(),
+        };
+        for (kind, value) in patch {
+            if value.is_null() {
+                stubs.remove(&kind);
+            } else {
+                stubs.insert(kind, value);
+            }
+        }
+        serde_json::from_value(serde_json::Value::Object(stubs)).map_err(|e| e.to_string())
+    })
+}
+
+/// Give the HTTP server on `port` the stubs `stubs` makes of its config, all or none of
+/// them: the config they make is checked whole before anything changes. A server that's
+/// up is started over with them on its listener, the server it replaces taking requests
+/// until then, and one that's stopped has them once it's started. Answered with the
+/// stubs as `GET /{port}/stubs` lists them. `if_match` is held to the server's ETag as
+/// for any other change, see `RunningServer::precondition`.
+fn swap(
+    database: Database,
+    port: u16,
+    if_match: Option<String>,
+    stubs: impl FnOnce(&ServerJsonBody) -> Result<StubSet, String>
+) -> Result<warp::reply::Response, warp::Rejection> {
+    // Listed once the registry is let go of
+    {
+        let mut registry = lock(&database);
+        let registry = &mut *registry;
+        let server = match registry.servers.get(&port) {
+            Some(server) => server,
+            None => return Ok(error_reply(warp::http::StatusCode::NOT_FOUND, &format!("no server on port {}", port))),
+        };
+        if let Some(refused) = server.precondition(if_match.as_deref(), registry.require_if_match) {
+            return Ok(refused);
+        }
+        if server.config.kind != ServerKind::Http || server.config.isolation != Isolation::InProcess {
+            return Ok(error_reply(warp::http::StatusCode::CONFLICT, "only in-process HTTP servers have stubs"));
+        }
+        if matches!(server.status, ServerStatus::Paused | ServerStatus::Draining) {
+            return Ok(server_reply(server, warp::http::StatusCode::CONFLICT));
+        }
+
+        let config = match stubs(&server.config) {
+            Ok(stubs) => stubs.config(&server.config),
+            Err(error) => return Ok(error_reply(warp::http::StatusCode::BAD_REQUEST, &error)),
+        };
+        crate::check_config(registry, &config).map_err(warp::reject::custom)?;
+        match server.status {
+            ServerStatus::Stopped | ServerStatus::Crashed => {
+                registry.servers.get_mut(&port).unwrap().config = config;
+                registry.record_change(port, crate::watch::ChangeType::Modified);
+            }
+            _ => crate::reconfigure(&database, registry, config).map_err(warp::reject::custom)?,
+        }
+    }
+    Ok(self::stubs(database, port, Vec::new()))
+}
@@ -0,0 +1,103 @@
+use futures::Future;
+use tokio::timer::Delay;
+
+use std::time::{Duration, Instant};
+
+use crate::{start_server, Database, ServerFuture, ServerStatus};
+use crate::error::lock;
+use crate::runtime;
+use crate::watch::ChangeType;
+
+// Respawn backoff doubles per restart, up to 2^6 = 64 seconds
+const MAX_BACKOFF_EXPONENT: u32 = 6;
+
+/// Run the server future spawned as `id` on `port`, tracking its state in the registry.
+///
+/// The server is `running` once the runtime first polls it. When it finishes, a server
+/// that was `draining` has stopped. Servers are told to shut down once they're draining,
+/// like those `delete_server` waits on, or once they're removed from the registry, so any
+/// other server still registered when its future ends has crashed. It is marked as such
+/// and, if configured to `respawn`, restarted after a backoff.
+pub fn supervise(
+    database: Database,
+    port: u16,
+    id: usize,
+    future: ServerFuture
+) -> impl Future<Item = (), Error = ()> {
+    let started = database.clone();
+    let running = futures::future::lazy(move || {
+        let mut registry = lock(&started);
+        if let Some(server) = registry.servers.get_mut(&port) {
+            if server.id == id && server.status == ServerStatus::Starting {
+                server.status = ServerStatus::Running;
+                registry.record_change(port, ChangeType::Modified);
+            }
+        }
+        drop(registry);
+        future
+    });
+
+    running.then(move |_| {
+        let respawn_after = {
+            let mut registry = lock(&database);
+
+            let server = match registry.servers.get_mut(&port) {
+                Some(server) if server.id == id => server,
+                _ => return Ok(()),
+            };
+
+            // Stop holding the port open once the server no longer listens on it
+            server.listener = None;
+            server.extra_listeners.clear();
+
+            let respawn_after = if server.status == ServerStatus::Draining {
+                server.status = ServerStatus::Stopped;
+                None
+            } else {
+                eprintln!("server {} crashed", port);
+                server.status = ServerStatus::Crashed;
+                Some(server.restarts).filter(|_| server.config.respawn)
+            };
+            registry.record_change(port, ChangeType::Modified);
+            respawn_after
+        };
+
+        if let Some(restarts) = respawn_after {
+            schedule_respawn(database, port, id, restarts);
+        }
+        Ok(())
+    })
+}
+
+/// Respawn crashed server `id` on `port` after a backoff that doubles with each restart
+fn schedule_respawn(database: Database, port: u16, id: usize, restarts: u32) {
+    let backoff = Duration::from_secs(1 << restarts.min(MAX_BACKOFF_EXPONENT));
+    eprintln!("respawning server {} in {}s", port, backoff.as_secs());
+
+    runtime::spawn(Delay::new(Instant::now() + backoff)
+        .map_err(|e| eprintln!("respawn timer error: {}", e))
+        .map(move |_| respawn(database, port, id, restarts + 1)));
+}
+
+fn respawn(database: Database, port: u16, id: usize, restarts: u32) {
+    let mut registry = lock(&database);
+
+    // The crashed server may have been deleted or replaced in the meantime
+    let config = match registry.servers.get(&port) {
+        Some(server) if server.id == id => server.config.clone(),
+        _ => return,
+    };
+
+    match start_server(&database, &mut registry, config, restarts) {
+        Ok(()) => eprintln!("server {} respawned", port),
+        Err(e) => {
+            eprintln!("failed to respawn server {}: {}", port, e);
+            if let Some(server) = registry.servers.get_mut(&port) {
+                server.restarts = restarts;
+            }
+            registry.record_change(port, ChangeType::Modified);
+            drop(registry);
+            schedule_respawn(database, port, id, restarts);
+        }
+    }
+}
@@ -0,0 +1,251 @@
+use serde_json::Value;
+
+use crate::chaos::Rng;
+use crate::har;
+
+/// How deep objects and arrays are made up in each other, past which only what a schema
+/// requires is, as the schemas of recursive types refer to themselves
+const MAX_DEPTH: usize = 6;
+
+const WORDS: &[&str] = &[
+    "amber", "birch", "cobalt", "delta", "ember", "fjord", "granite", "harbor", "indigo", "juniper",
+    "kestrel", "lumen", "meadow", "nimbus", "orchid", "pebble", "quartz", "ridge", "sierra", "tundra",
+];
+
+const NAMES: &[&str] = &[
+    "Ada", "Bo", "Chidi", "Dana", "Emil", "Farah", "Goran", "Hana", "Ines", "Jonas", "Kaito", "Lena",
+];
+
+const LAST_NAMES: &[&str] = &[
+    "Andersen", "Baptiste", "Costa", "Dubois", "Eriksen", "Fischer", "Garcia", "Haddad", "Ivanova", "Jensen",
+    "Kowalski", "Larsen", "Moreau", "Nakamura", "Okafor", "Park",
+];
+
+const LOREM: &[&str] = &[
+    "lorem", "ipsum", "dolor", "sit", "amet", "consectetur", "adipiscing", "elit", "sed", "do", "eiusmod",
+    "tempor", "incididunt", "ut", "labore", "et", "dolore", "magna", "aliqua", "enim", "minim", "veniam",
+];
+
+/// The seconds since the epoch made up date-times are between, 2000 to 2030
+const TIMES: (u64, u64) = (946_684_800, 1_893_456_000);
+
+/// Makes up plausible values conforming to JSON Schemas, for the response bodies of stubs
+/// that have a schema but no example. The values are random, but the same for the same
+/// stub, seed and schema.
+pub struct Synthesizer(Rng);
+
+impl Synthesizer {
+    /// Making up the values of the stub `id`, like `operation:3`, from `seed`. Each stub
+    /// has a generator of its own, so that its values stay the same as others come and go.
+    pub fn new(seed: u64, id: &str) -> Synthesizer {
+        // FNV-1a of the id
+        let id = id.bytes().fold(0xcbf2_9ce4_8422_2325u64, |hash, byte| (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3));
+        Synthesizer(Rng::new(seed ^ id))
+    }
+
+    /// Uniform in `[0, n)`, zero if `n` is
+    fn below(&mut self, n: u64) -> u64 {
+        if n == 0 { 0 } else { self.0.next() % n }
+    }
+
+    fn pick<'a, T>(&mut self, values: &'a [T]) -> &'a T {
+        &values[self.below(values.len() as u64) as usize]
+    }
+
+    /// A value conforming to `schema`, its example if it has one. `$ref`s are expected to
+    /// have been resolved.
+    pub fn value(&mut self, schema: &Value) -> Value {
+        self.named(schema, None, 0)
+    }
+
+    /// A value conforming to `schema` for the property `name`, whose name hints at what
+    /// it is, like `email`
+    fn named(&mut self, schema: &Value, name: Option<&str>, depth: usize) -> Value {
+        for key in ["example", "default", "const"] {
+            if let Some(example) = schema.get(key) {
+                return example.clone();
+            }
+        }
+        if let Some(examples) = schema.get("examples").and_then(Value::as_array).filter(|examples| !examples.is_empty()) {
+            return self.pick(examples).clone();
+        }
+        if let Some(values) = schema.get("enum").and_then(Value::as_array).filter(|values| !values.is_empty()) {
+            return self.pick(values).clone();
+        }
+        if let Some(choices) = ["oneOf", "anyOf"].iter().find_map(|key| schema.get(*key)?.as_array().filter(|choices| !choices.is_empty())) {
+            let choice = self.pick(choices);
+            return self.named(choice, name, depth);
+        }
+        if let Some(all) = schema.get("allOf").and_then(Value::as_array) {
+            let mut merged = serde_json::Map::new();
+            for part in all {
+                match self.named(part, name, depth) {
+                    Value::Object(object) => merged.extend(object),
+                    value => return value,
+                }
+            }
+            return Value::Object(merged);
+        }
+
+        // A list of types, as OpenAPI 3.1 has it, is taken by its first that isn't `null`
+        let type_name = match schema.get("type") {
+            Some(Value::String(type_name)) => Some(type_name.as_str()),
+            Some(Value::Array(types)) => types.iter().filter_map(Value::as_str).find(|type_name| *type_name != "null"),
+            _ => None,
+        };
+        match type_name {
+            Some("object") => self.object(schema, depth),
+            None if schema.get("properties").is_some() => self.object(schema, depth),
+            Some("array") => self.array(schema, depth),
+            Some("string") => Value::from(self.string(schema, name)),
+            Some("integer") => Value::from(self.integer(schema)),
+            Some("number") => Value::from(self.number(schema)),
+            Some("boolean") => Value::Bool(self.below(2) == 1),
+            _ => Value::Null,
+        }
+    }
+
+    /// Every required property, and most of the others
+    fn object(&mut self, schema: &Value, depth: usize) -> Value {
+        let required: Vec<&str> = schema.get("required").and_then(Value::as_array).into_iter().flatten()
+            .filter_map(Value::as_str)
+            .collect();
+        let properties = schema.get("properties").and_then(Value::as_object).into_iter().flatten();
+        let mut object = serde_json::Map::new();
+        for (name, property) in properties {
+            if required.contains(&name.as_str()) || (depth < MAX_DEPTH && self.below(4) != 0) {
+                let value = self.named(property, Some(name), depth + 1);
+                object.insert(name.clone(), value);
+            }
+        }
+        Value::Object(object)
+    }
+
+    /// One to three items, or as many as the schema takes
+    fn array(&mut self, schema: &Value, depth: usize) -> Value {
+        let min = schema.get("minItems").and_then(Value::as_u64).unwrap_or(1);
+        let max = schema.get("maxItems").and_then(Value::as_u64).unwrap_or(min + 2).max(min);
+        let count = if depth < MAX_DEPTH { min + self.below(max - min + 1) } else { min };
+        let items = schema.get("items").cloned().unwrap_or(Value::Null);
+        Value::Array((0..count).map(|_| self.named(&items, None, depth + 1)).collect())
+    }
+
+    /// Milliseconds since the epoch within `TIMES`
+    fn time_ms(&mut self) -> u64 {
+        TIMES.0 * 1000 + self.below((TIMES.1 - TIMES.0) * 1000)
+    }
+
+    pub fn first_name(&mut self) -> &'static str {
+        NAMES[self.below(NAMES.len() as u64) as usize]
+    }
+
+    pub fn last_name(&mut self) -> &'static str {
+        LAST_NAMES[self.below(LAST_NAMES.len() as u64) as usize]
+    }
+
+    pub fn word(&mut self) -> &'static str {
+        WORDS[self.below(WORDS.len() as u64) as usize]
+    }
+
+    pub fn email(&mut self) -> String {
+        format!("{}.{}@example.com", self.first_name().to_ascii_lowercase(), self.last_name().to_ascii_lowercase())
+    }
+
+    /// A version 4 UUID
+    pub fn uuid(&mut self) -> String {
+        let (high, low) = (self.0.next(), self.0.next());
+        format!(
+            "{:08x}-{:04x}-4{:03x}-{:04x}-{:012x}",
+            high >> 32, (high >> 16) & 0xffff, high & 0xfff, 0x8000 | (low >> 48) & 0x3fff, low & 0xffff_ffff_ffff
+        )
+    }
+
+    /// An ISO 8601 date-time in UTC, between 2000 and 2030
+    pub fn timestamp(&mut self) -> String {
+        har::iso8601(self.time_ms())
+    }
+
+    /// `words` words of lorem ipsum, the first capitalized, ending with a full stop
+    pub fn lorem(&mut self, words: usize) -> String {
+        let mut lorem = (0..words).map(|_| *self.pick(LOREM)).collect::<Vec<_>>().join(" ");
+        if let Some(first) = lorem.get_mut(..1) {
+            first.make_ascii_uppercase();
+        }
+        if !lorem.is_empty() {
+            lorem.push('.');
+        }
+        lorem
+    }
+
+    /// Within `[min, max]`
+    pub fn integer_within(&mut self, min: i64, max: i64) -> i64 {
+        min.saturating_add(self.below(max.abs_diff(min).saturating_add(1)) as i64)
+    }
+
+    fn string(&mut self, schema: &Value, name: Option<&str>) -> String {
+        let name = name.unwrap_or("").to_ascii_lowercase();
+        let format = schema.get("format").and_then(Value::as_str).unwrap_or("");
+        let string = match format {
+            "date-time" => self.timestamp(),
+            "date" => self.timestamp()[..10].to_string(),
+            "uuid" => self.uuid(),
+            "email" => self.email(),
+            "uri" | "url" => format!("https://example.com/{}", self.pick(WORDS)),
+            "hostname" => format!("{}.example.com", self.pick(WORDS)),
+            "ipv4" => format!("192.0.2.{}", 1 + self.below(254)),
+            "ipv6" => format!("2001:db8::{:x}", 1 + self.below(0xfffe)),
+            _ if name.contains("email") => self.email(),
+            _ if name.contains("url") || name.contains("uri") => format!("https://example.com/{}", self.pick(WORDS)),
+            _ if name.contains("name") => self.pick(NAMES).to_string(),
+            _ => format!("{} {}", self.pick(WORDS), self.pick(WORDS)),
+        };
+
+        let min = schema.get("minLength").and_then(Value::as_u64).unwrap_or(0) as usize;
+        let max = schema.get("maxLength").and_then(Value::as_u64).map_or(usize::MAX, |max| max as usize);
+        let mut string: String = string.chars().take(max).collect();
+        while string.chars().count() < min {
+            string.push('x');
+        }
+        string
+    }
+
+    /// The bounds of a number schema, with an exclusive bound as OpenAPI 3.0 has it, a
+    /// flag beside `minimum`, or as 3.1 has it, the bound itself
+    fn bounds(schema: &Value) -> (Option<f64>, bool, Option<f64>, bool) {
+        let bound = |inclusive: &str, exclusive: &str| match (schema.get(inclusive).and_then(Value::as_f64), schema.get(exclusive)) {
+            (Some(bound), Some(Value::Bool(exclusive))) => (Some(bound), *exclusive),
+            (_, Some(bound)) if bound.is_number() => (bound.as_f64(), true),
+            (bound, _) => (bound, false),
+        };
+        let (min, min_exclusive) = bound("minimum", "exclusiveMinimum");
+        let (max, max_exclusive) = bound("maximum", "exclusiveMaximum");
+        (min, min_exclusive, max, max_exclusive)
+    }
+
+    fn integer(&mut self, schema: &Value) -> i64 {
+        let (min, min_exclusive, max, max_exclusive) = Synthesizer::bounds(schema);
+        let min = min.map(|min| min.ceil() as i64 + i64::from(min_exclusive && min.fract() == 0.0));
+        let max = max.map(|max| max.floor() as i64 - i64::from(max_exclusive && max.fract() == 0.0));
+        let (min, max) = match (min, max) {
+            (Some(min), Some(max)) => (min, max.max(min)),
+            (Some(min), None) => (min, min.saturating_add(1000)),
+            (None, Some(max)) => (max.saturating_sub(1000), max),
+            (None, None) => (1, 1000),
+        };
+        self.integer_within(min, max)
+    }
+
+    /// With two decimals
+    fn number(&mut self, schema: &Value) -> f64 {
+        let (min, _, max, _) = Synthesizer::bounds(schema);
+        let (min, max) = match (min, max) {
+            (Some(min), Some(max)) => (min, max.max(min)),
+            (Some(min), None) => (min, min + 1000.0),
+            (None, Some(max)) => (max - 1000.0, max),
+            (None, None) => (0.0, 1000.0),
+        };
+        let value = min + self.0.fraction() * (max - min);
+        // Rounding may take it past a bound
+        ((value * 100.0).round() / 100.0).clamp(min, max)
+    }
+}
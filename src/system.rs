@@ -0,0 +1,110 @@
+use crate::{Database, ServerStatus};
+use crate::error::lock;
+use crate::{runtime, shutdown};
+
+/// `GET /system` report. What the platform doesn't tell is `null`.
+#[derive(Debug, serde_derive::Serialize)]
+struct SystemReport {
+    /// Resident set size of the process, in bytes
+    rss_bytes: Option<u64>,
+    threads: Option<u64>,
+    open_fds: Option<u64>,
+    /// The soft limit on open file descriptors, past which sockets can't be opened
+    max_fds: Option<u64>,
+    /// Tasks running on the runtime, see `runtime::TASKS`
+    tasks: usize,
+    servers: Vec<ServerTasks>,
+    /// Server futures told to shut down that haven't ended yet
+    pending_shutdowns: usize,
+    /// Server futures that won't end by themselves, see `shutdown::Leak`
+    leaked_tasks: Vec<shutdown::Leak>,
+    /// Server futures aborted with `DELETE /{port}?force=true`
+    aborted_tasks: u64,
+}
+
+/// The tasks of a single server in the `GET /system` report
+#[derive(Debug, serde_derive::Serialize)]
+struct ServerTasks {
+    port: u16,
+    status: ServerStatus,
+    /// The server's own task and those serving its connections. Those of a server running
+    /// in a subprocess or container are its own.
+    tasks: usize,
+    /// Where the server's own task is at
+    task_status: shutdown::TaskStatus,
+}
+
+/// `GET /system`: what the process uses of the system's resources, so that what's left
+/// can be told before a limit is reached. Tasks are those the admin API and the servers
+/// spawn, each connection to an HTTP server counting as the task serving it.
+pub fn system(database: Database) -> impl warp::Reply {
+    let registry = lock(&database);
+    let mut servers: Vec<ServerTasks> = registry.servers.iter()
+        .map(|(port, server)| ServerTasks {
+            port: *port,
+            status: server.status,
+            tasks: server.tasks.active(),
+            task_status: server.task.status(),
+        })
+        .collect();
+    let pending_shutdowns = registry.shutdowns.pending();
+    let leaked_tasks = registry.shutdowns.leaks(&registry.servers);
+    let aborted_tasks = registry.shutdowns.aborted();
+    drop(registry);
+    servers.sort_by_key(|server| server.port);
+
+    let status = proc_status();
+    warp::reply::json(&SystemReport {
+        rss_bytes: status_field(&status, "VmRSS:").map(|kb| kb * 1024),
+        threads: status_field(&status, "Threads:"),
+        open_fds: open_fds(),
+        max_fds: max_fds(),
+        tasks: runtime::TASKS.active(),
+        servers,
+        pending_shutdowns,
+        leaked_tasks,
+        aborted_tasks,
+    })
+}
+
+/// `/proc/self/status`, where there's one
+fn proc_status() -> Option<String> {
+    std::fs::read_to_string("/proc/self/status").ok()
+}
+
+/// The number in the line of `status` starting with `name`, like `VmRSS:   1024 kB`
+fn status_field(status: &Option<String>, name: &str) -> Option<u64> {
+    status.as_ref()?.lines()
+        .find_map(|line| line.strip_prefix(name))?
+        .split_whitespace()
+        .next()?
+        .parse()
+        .ok()
+}
+
+#[cfg(unix)]
+fn open_fds() -> Option<u64> {
+    // Listing the directory takes a descriptor of its own
+    let count = std::fs::read_dir("/dev/fd").ok()?.count();
+    Some(count.saturating_sub(1) as u64)
+}
+
+#[cfg(not(unix))]
+fn open_fds() -> Option<u64> {
+    None
+}
+
+#[cfg(unix)]
+pub fn max_fds() -> Option<u64> {
+    let mut limit = libc::rlimit { rlim_cur: 0, rlim_max: 0 };
+    // The limit outlives the call
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } != 0 || limit.rlim_cur == libc::RLIM_INFINITY {
+        return None;
+    }
+    Some(limit.rlim_cur)
+}
+
+#[cfg(not(unix))]
+pub fn max_fds() -> Option<u64> {
+    None
+}
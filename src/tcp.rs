@@ -0,0 +1,110 @@
+use futures::{Future, Stream};
+use futures::future::Shared;
+use futures::sync::oneshot;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream};
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::SocketOptions;
+use crate::runtime::{self, Tasks};
+
+/// What a raw TCP server does with each accepted connection
+#[derive(Clone, Debug, PartialEq, serde_derive::Deserialize, serde_derive::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TcpMode {
+    /// Write every received byte back to the client
+    Echo,
+    /// Read and discard everything the client sends
+    Sink,
+    /// Write a canned byte sequence, then close the connection
+    Replay(String),
+}
+
+/// Connections accepted on `listeners` if given, or else on new listeners bound to `port`,
+/// with the socket `options`
+pub fn bind(
+    port: u16,
+    listeners: Vec<std::net::TcpListener>,
+    options: &SocketOptions
+) -> std::io::Result<impl Stream<Item = TcpStream, Error = std::io::Error>> {
+    let listeners = if listeners.is_empty() { options.bind(port)? } else { listeners };
+    let mut incoming: Option<Box<dyn Stream<Item = TcpStream, Error = std::io::Error> + Send>> = None;
+    for listener in listeners {
+        let accepted = TcpListener::from_std(listener, &tokio::reactor::Handle::default())?.incoming();
+        incoming = Some(match incoming {
+            Some(incoming) => Box::new(incoming.select(accepted)),
+            None => Box::new(accepted),
+        });
+    }
+    let incoming = incoming.expect("bound to at least one address");
+
+    let nodelay = options.nodelay;
+    Ok(incoming.map(move |socket| {
+        if nodelay {
+            if let Err(e) = socket.set_nodelay(true) {
+                eprintln!("failed to set TCP_NODELAY: {}", e);
+            }
+        }
+        socket
+    }))
+}
+
+/// Serve a raw TCP server on `incoming` connections. The returned future accepts
+/// connections until `shutdown` fires, which also closes every connection still open.
+/// Connections accepted while `paused` are closed straight away. Each connection is served
+/// by a task counted in `tasks`.
+pub fn create_tcp_server<S>(
+    mode: TcpMode,
+    incoming: S,
+    paused: Arc<AtomicBool>,
+    tasks: Arc<Tasks>,
+    shutdown: oneshot::Receiver<()>
+) -> impl Future<Item = (), Error = ()>
+where
+    S: Stream<Error = std::io::Error>,
+    S::Item: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let shutdown = shutdown.shared();
+
+    let accept_shutdown = shutdown.clone();
+    let server = incoming
+        .map_err(|e| eprintln!("tcp accept error: {}", e))
+        .for_each(move |socket| {
+            if paused.load(Ordering::SeqCst) {
+                return Ok(());
+            }
+            runtime::spawn_for(&tasks, handle_connection(&mode, socket, shutdown.clone()));
+            Ok(())
+        });
+
+    server.select2(accept_shutdown).then(|_| Ok(()))
+}
+
+/// Serve one connection according to `mode`, giving up when `shutdown` fires
+fn handle_connection(
+    mode: &TcpMode,
+    socket: impl AsyncRead + AsyncWrite + Send + 'static,
+    shutdown: Shared<oneshot::Receiver<()>>
+) -> impl Future<Item = (), Error = ()> {
+    let connection: Box<dyn Future<Item = (), Error = std::io::Error> + Send> = match mode {
+        TcpMode::Echo => {
+            let (reader, writer) = AsyncRead::split(socket);
+            Box::new(tokio::io::copy(reader, writer).map(|_| ()))
+        }
+        TcpMode::Sink => {
+            Box::new(tokio::io::copy(socket, std::io::sink()).map(|_| ()))
+        }
+        TcpMode::Replay(data) => {
+            Box::new(tokio::io::write_all(socket, data.clone().into_bytes())
+                .and_then(|(socket, _)| tokio::io::shutdown(socket))
+                .map(|_| ()))
+        }
+    };
+
+    connection
+        .map_err(|e| eprintln!("tcp connection error: {}", e))
+        .select2(shutdown)
+        .then(|_| Ok(()))
+}
@@ -0,0 +1,145 @@
+use warp::Reply;
+
+use crate::{error_reply, server_reply, start_server, watch, Database, ServerJsonBody};
+use crate::error::lock;
+
+/// Body of `POST /templates`
+#[derive(Debug, serde_derive::Deserialize, serde_derive::Serialize)]
+pub struct TemplateJsonBody {
+    name: String,
+    /// Also apply the new configuration to the servers instantiated from an earlier
+    /// version of the template
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    propagate: bool,
+    /// A server configuration without a port, which is given when instantiating
+    server: serde_json::Value,
+}
+
+/// A named server configuration, instantiated with `POST /?template={name}&port={port}`
+#[derive(Clone, Debug, serde_derive::Serialize)]
+pub struct Template {
+    name: String,
+    #[serde(skip)]
+    config: ServerJsonBody,
+    /// The configuration as registered, for listing
+    server: serde_json::Value,
+}
+
+impl Template {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The template as it would be registered again
+    pub fn json_body(&self) -> TemplateJsonBody {
+        TemplateJsonBody { name: self.name.clone(), propagate: false, server: self.server.clone() }
+    }
+}
+
+impl TemplateJsonBody {
+    /// Parse the template's server configuration
+    pub fn parse(self) -> Result<Template, String> {
+        let mut config = self.server.clone();
+        if let Some(config) = config.as_object_mut() {
+            config.entry("port").or_insert(0.into());
+        }
+        let mut config = serde_json::from_value(config)
+            .map(ServerJsonBody::into_config)
+            .map_err(|e| format!("invalid template server: {}", e))?;
+        config.template = Some(self.name.clone());
+
+        Ok(Template { name: self.name, config, server: self.server })
+    }
+}
+
+/// Response of `POST /templates`
+#[derive(Debug, serde_derive::Serialize)]
+struct RegisteredTemplate<'a> {
+    #[serde(flatten)]
+    template: &'a Template,
+    /// Ports of the instances the template was propagated to
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    propagated_to: Vec<u16>,
+}
+
+/// Query parameters of `POST /?template=`
+#[derive(Debug, serde_derive::Deserialize)]
+pub struct InstantiateQuery {
+    template: String,
+    /// Allocated if left out
+    port: Option<u16>,
+}
+
+/// Register a template, replacing any with the same name. With `propagate`, instances of
+/// the template are updated in place, keeping their port. Instances whose kind or
+/// isolation would change are left alone, as that would take a different listener.
+pub fn register_template(
+    database: Database,
+    body: TemplateJsonBody
+) -> warp::reply::Response {
+    let propagate = body.propagate;
+    let template = match body.parse() {
+        Ok(template) => template,
+        Err(error) => return error_reply(warp::http::StatusCode::UNPROCESSABLE_ENTITY, &error),
+    };
+
+    let mut registry = lock(&database);
+
+    let mut propagated_to = Vec::new();
+    if propagate {
+        let mut instances: Vec<u16> = registry.servers.values()
+            .filter(|server| server.config.template.as_ref() == Some(&template.name))
+            .filter(|server| {
+                server.config.kind == template.config.kind && server.config.isolation == template.config.isolation
+            })
+            .map(|server| server.config.port)
+            .collect();
+        instances.sort();
+
+        for port in instances {
+            if let Some(server) = registry.servers.get_mut(&port) {
+                server.config = ServerJsonBody { port, ..template.config.clone() };
+            }
+            registry.record_change(port, watch::ChangeType::Modified);
+            propagated_to.push(port);
+        }
+    }
+
+    let reply = warp::reply::json(&RegisteredTemplate { template: &template, propagated_to }).into_response();
+    registry.templates.insert(template.name.clone(), template);
+    reply
+}
+
+/// List the registered templates by name
+pub fn list_templates(database: Database) -> warp::reply::Response {
+    let registry = lock(&database);
+    let templates: Vec<&Template> = registry.templates.values().collect();
+    warp::reply::json(&templates).into_response()
+}
+
+/// Create a server on `port` from a template
+pub fn instantiate(
+    database: Database,
+    query: InstantiateQuery
+) -> Result<warp::reply::Response, warp::Rejection> {
+    let mut registry = lock(&database);
+
+    let config = match registry.templates.get(&query.template) {
+        Some(template) => template.config.clone(),
+        None => {
+            let error = format!("no template named {}", query.template);
+            return Ok(error_reply(warp::http::StatusCode::NOT_FOUND, &error));
+        }
+    };
+
+    let port = match query.port {
+        Some(port) if registry.servers.contains_key(&port) => return Err(warp::reject::not_found()),
+        Some(port) => port,
+        None => registry.allocate_port().map_err(warp::reject::custom)?,
+    };
+
+    start_server(&database, &mut registry, ServerJsonBody { port, ..config }, 0)
+        .map_err(warp::reject::custom)?;
+
+    Ok(server_reply(&registry.servers[&port], warp::http::StatusCode::OK))
+}
@@ -0,0 +1,73 @@
+use std::sync::{Arc, Mutex};
+
+use crate::{reaper, start_server, Database, Error, PortAllocator, Registry, ServerJsonBody};
+use crate::error::lock;
+
+/// The admin API running on a free port, for integration tests.
+///
+/// `start` must be called from a task on a tokio 0.1 runtime, such as in
+/// `tokio::runtime::Runtime::block_on`, and the servers are spawned on that runtime, which
+/// has to keep running for as long as the instance is used. Runtimes of tokio 0.2 and
+/// later, and of other crates, aren't supported. `tests/test_instance.rs` shows how it's
+/// used. Dropping the instance shuts down every server it started, including those created
+/// through its API. Servers isolated in a subprocess re-exec the current executable, so
+/// they can't be used from a test binary.
+pub struct TestInstance {
+    port: u16,
+    database: Database,
+}
+
+impl TestInstance {
+    pub fn start() -> Result<TestInstance, Error> {
+        TestInstance::start_with_port_allocator(Box::default())
+    }
+
+    /// Start with servers created without a port allocated by `port_allocator`. The
+    /// admin API itself is always on a port picked by the OS.
+    pub fn start_with_port_allocator(port_allocator: Box<dyn PortAllocator>) -> Result<TestInstance, Error> {
+        let listener = std::net::TcpListener::bind(("127.0.0.1", 0)).map_err(Error::AllocatePort)?;
+        let port = listener.local_addr().map_err(Error::AllocatePort)?.port();
+
+        let mut registry = Registry { port_allocator, ..Registry::default() };
+        registry.inherited_listeners.insert(port, listener);
+        let database: Database = Arc::new(Mutex::new(registry));
+
+        let body = ServerJsonBody { port, ..Default::default() };
+        let mut registry = lock(&database);
+        start_server(&database, &mut registry, body, 0)?;
+
+        let reaped = database.clone();
+        registry.background.start("lease reaper", move || reaper::reap_expired_leases(reaped.clone()));
+        drop(registry);
+
+        Ok(TestInstance { port, database })
+    }
+
+    /// The port the admin API listens on
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// `http://127.0.0.1:{port}`, without a trailing slash
+    pub fn base_url(&self) -> String {
+        format!("http://127.0.0.1:{}", self.port)
+    }
+
+    /// A client for the instance's admin API
+    #[cfg(feature = "client")]
+    pub fn client(&self) -> crate::client::Client {
+        crate::client::Client::new(self.base_url())
+    }
+}
+
+impl Drop for TestInstance {
+    fn drop(&mut self) {
+        let mut registry = lock(&self.database);
+        // The reaper holds on to the registry, so it's stopped for the registry to be dropped
+        registry.background = Default::default();
+        let ports: Vec<u16> = registry.servers.keys().cloned().collect();
+        for port in ports {
+            registry.remove_server(port);
+        }
+    }
+}
@@ -0,0 +1,258 @@
+use warp::Filter;
+use warp::http::header::{HeaderMap, HeaderName, HeaderValue};
+
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::activity::Activity;
+use crate::clients::{self, ClientPattern};
+use crate::clock::SharedClock;
+use crate::error::{self, lock};
+use crate::stubs::Hits;
+
+/// Clients a rule keeps windows of before those whose windows have ended are forgotten
+const MAX_IDLE_KEYS: usize = 1024;
+
+/// A rule of an HTTP server limiting the requests it matches to `limit` every
+/// `window_secs` for each client, telling clients how many they have left in the
+/// rate-limit headers of its responses, and answering 429 once they've none, as an API
+/// gateway would. A client's window starts with its first request and the count starts
+/// over when it ends, by the server's clock.
+#[derive(Clone, Debug, PartialEq, serde_derive::Deserialize, serde_derive::Serialize)]
+pub struct RateLimitRule {
+    /// The method of the requests limited, any by default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub method: Option<String>,
+    /// The path of the requests limited, without the query string, any by default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    /// The clients whose requests are limited, any by default
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub clients: Vec<ClientPattern>,
+    pub limit: u64,
+    #[serde(default = "default_window_secs")]
+    pub window_secs: u64,
+    /// The header telling clients apart, like `x-api-key`, by default their IP address.
+    /// Requests without it are told apart by their IP address.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key_header: Option<String>,
+    #[serde(default)]
+    pub headers: HeaderStyle,
+    #[serde(flatten)]
+    pub activity: Activity,
+}
+
+fn default_window_secs() -> u64 {
+    60
+}
+
+/// The rate-limit headers a rule answers with
+#[derive(Clone, Copy, Debug, Default, PartialEq, serde_derive::Deserialize, serde_derive::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HeaderStyle {
+    /// `X-RateLimit-Limit`, `X-RateLimit-Remaining` and `X-RateLimit-Reset`, the Unix time
+    /// in seconds the window ends, as GitHub's
+    #[default]
+    XRateLimit,
+    /// `RateLimit-Limit`, `RateLimit-Remaining`, `RateLimit-Reset`, the seconds until the
+    /// window ends, and `RateLimit-Policy`, as the early drafts of the IETF's
+    Draft,
+    /// `RateLimit-Policy` and `RateLimit` as structured fields, as the later drafts of the
+    /// IETF's
+    Structured,
+}
+
+impl RateLimitRule {
+    pub fn matches(&self, method: &warp::http::Method, path: &str, client: Option<SocketAddr>) -> bool {
+        self.method.as_ref().is_none_or(|expected| expected.eq_ignore_ascii_case(method.as_str()))
+            && self.path.as_ref().is_none_or(|expected| expected == path)
+            && clients::matches(&self.clients, client)
+    }
+
+    /// What the request's client is counted by
+    fn key(&self, headers: &HeaderMap, client: Option<SocketAddr>) -> String {
+        let header = self.key_header.as_ref()
+            .and_then(|name| headers.get(name.as_str()))
+            .and_then(|value| value.to_str().ok());
+        match (header, client) {
+            (Some(value), _) => format!("header:{}", value),
+            (None, Some(client)) => client.ip().to_string(),
+            (None, None) => String::new(),
+        }
+    }
+}
+
+/// The windows of the clients of a rate-limit rule, and how many requests it matched,
+/// kept with the counts of the server's other stubs, see `stubs::Hits`
+#[derive(Debug, Default)]
+pub struct Windows {
+    pub matched: AtomicU64,
+    keys: Mutex<HashMap<String, Window>>,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Window {
+    started_ms: u64,
+    used: u64,
+}
+
+/// Where a client stands with a rule after a request
+struct Standing {
+    limit: u64,
+    remaining: u64,
+    window_secs: u64,
+    /// When the window ends
+    reset_ms: u64,
+    exhausted: bool,
+}
+
+impl Windows {
+    /// Count a request of `key` at `now_ms`, unless it has none left
+    fn take(&self, rule: &RateLimitRule, key: String, now_ms: u64) -> Standing {
+        let window_ms = rule.window_secs.saturating_mul(1000);
+        let mut keys = lock(&self.keys);
+        if keys.len() >= MAX_IDLE_KEYS {
+            keys.retain(|_, window| now_ms < window.started_ms.saturating_add(window_ms));
+        }
+        let window = keys.entry(key).or_insert(Window { started_ms: now_ms, used: 0 });
+        if now_ms >= window.started_ms.saturating_add(window_ms) {
+            *window = Window { started_ms: now_ms, used: 0 };
+        }
+        let exhausted = window.used >= rule.limit;
+        if !exhausted {
+            window.used += 1;
+        }
+        Standing {
+            limit: rule.limit,
+            remaining: rule.limit - window.used,
+            window_secs: rule.window_secs,
+            reset_ms: window.started_ms.saturating_add(window_ms),
+            exhausted,
+        }
+    }
+
+    /// Forget the clients' windows, for each to have the whole limit again
+    pub fn clear(&self) {
+        lock(&self.keys).clear();
+    }
+}
+
+impl Standing {
+    /// Whole seconds until the window ends
+    fn reset_secs(&self, now_ms: u64) -> u64 {
+        self.reset_ms.saturating_sub(now_ms).div_ceil(1000)
+    }
+
+    fn headers(&self, index: usize, style: HeaderStyle, now_ms: u64) -> HeaderMap {
+        let reset_secs = self.reset_secs(now_ms);
+        let fields = match style {
+            HeaderStyle::XRateLimit => vec![
+                ("x-ratelimit-limit", self.limit.to_string()),
+                ("x-ratelimit-remaining", self.remaining.to_string()),
+                ("x-ratelimit-reset", self.reset_ms.div_ceil(1000).to_string()),
+            ],
+            HeaderStyle::Draft => vec![
+                ("ratelimit-limit", self.limit.to_string()),
+                ("ratelimit-remaining", self.remaining.to_string()),
+                ("ratelimit-reset", reset_secs.to_string()),
+                ("ratelimit-policy", format!("{};w={}", self.limit, self.window_secs)),
+            ],
+            HeaderStyle::Structured => vec![
+                ("ratelimit-policy", format!("\"rule-{}\";q={};w={}", index, self.limit, self.window_secs)),
+                ("ratelimit", format!("\"rule-{}\";r={};t={}", index, self.remaining, reset_secs)),
+            ],
+        };
+        let mut headers = HeaderMap::new();
+        for (name, value) in fields {
+            if let Ok(value) = HeaderValue::from_str(&value) {
+                headers.insert(HeaderName::from_static(name), value);
+            }
+        }
+        if self.exhausted {
+            headers.insert(warp::http::header::RETRY_AFTER, HeaderValue::from(reset_secs));
+        }
+        headers
+    }
+}
+
+/// The rate-limit rules of a server, checked to be valid, see `Throttling::filter`
+#[derive(Clone)]
+pub struct Throttling {
+    rules: Arc<Vec<RateLimitRule>>,
+    clock: SharedClock,
+    hits: Arc<Hits>,
+}
+
+/// Check `rules`, to limit requests with once they're served, while they're active by
+/// `clock`, counting them in `hits`
+pub fn throttle(rules: &[RateLimitRule], clock: SharedClock, hits: Arc<Hits>) -> io::Result<Throttling> {
+    for rule in rules {
+        if rule.window_secs == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "a rate-limit rule needs a non-zero window_secs"));
+        }
+        if let Some(name) = &rule.key_header {
+            HeaderName::from_bytes(name.as_bytes())
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, format!("invalid rate-limit key header {:?}", name)))?;
+        }
+        rule.activity.check()?;
+    }
+    Ok(Throttling { rules: Arc::new(rules.to_vec()), clock, hits })
+}
+
+/// The rate-limit headers to answer a request with
+#[derive(Debug, Default)]
+pub struct Limits(HeaderMap);
+
+impl Limits {
+    pub fn apply(self, reply: impl warp::Reply) -> warp::reply::Response {
+        let mut response = reply.into_response();
+        response.headers_mut().extend(self.0);
+        response
+    }
+}
+
+impl Throttling {
+    /// Count the requests of the connection from `client` against every rule they match,
+    /// with the headers of the rule leaving the fewest. A request one of them has none
+    /// left for is answered with 429 before the routes see it.
+    pub fn filter(
+        &self,
+        client: Option<SocketAddr>
+    ) -> impl Filter<Extract = (Limits,), Error = warp::Rejection> + Clone {
+        let Throttling { rules, clock, hits } = self.clone();
+        warp::method()
+            .and(warp::path::full())
+            .and(warp::header::headers_cloned())
+            .and_then(move |method: warp::http::Method, path: warp::path::FullPath, headers: HeaderMap| {
+                let now_ms = lock(&clock).now_ms();
+                let mut tightest: Option<(usize, Standing)> = None;
+                for (index, rule) in rules.iter().enumerate() {
+                    if !rule.activity.is_active(hits.started_ms, now_ms) || !rule.matches(&method, path.as_str(), client) {
+                        continue;
+                    }
+                    let windows = &hits.rate_limit_rules[index];
+                    windows.matched.fetch_add(1, Ordering::Relaxed);
+                    let standing = windows.take(rule, rule.key(&headers, client), now_ms);
+                    let tighter = tightest.as_ref().is_none_or(|(_, tightest)| {
+                        (standing.exhausted, u64::MAX - standing.remaining) > (tightest.exhausted, u64::MAX - tightest.remaining)
+                    });
+                    if tighter {
+                        tightest = Some((index, standing));
+                    }
+                }
+
+                match tightest {
+                    Some((index, standing)) if standing.exhausted => {
+                        let headers = standing.headers(index, rules[index].headers, now_ms);
+                        let (limit, window_secs) = (standing.limit, standing.window_secs);
+                        Err(warp::reject::custom(error::Error::LimitExceeded { rule: index, limit, window_secs, headers }))
+                    }
+                    Some((index, standing)) => Ok(Limits(standing.headers(index, rules[index].headers, now_ms))),
+                    None => Ok(Limits::default()),
+                }
+            })
+    }
+}
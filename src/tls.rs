@@ -0,0 +1,529 @@
+use futures::Poll;
+use tokio::io::{AsyncRead, AsyncWrite};
+use warp::Reply;
+
+use std::collections::BTreeMap;
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+
+use crate::{error_reply, vhosts, Database};
+use crate::error::lock;
+use crate::journal::ClientCert;
+
+/// TLS of an in-process HTTP server, which needs the `tls` feature
+#[derive(Clone, Debug, PartialEq, serde_derive::Deserialize, serde_derive::Serialize)]
+pub struct TlsConfig {
+    /// Generate a CA, and a certificate it signs for `hostnames`, when the server starts.
+    /// Clients trust the server by trusting the CA, see `GET /{port}/ca.pem`.
+    #[serde(default)]
+    pub self_signed: bool,
+    /// DNS names and IP addresses the self-signed certificate is for
+    #[serde(default = "default_hostnames")]
+    pub hostnames: Vec<String>,
+    /// PEM certificate chain to serve unless `self_signed`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cert_file: Option<PathBuf>,
+    /// PEM key of the certificate in `cert_file`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key_file: Option<PathBuf>,
+    /// PEM CA certificates that client certificates must be signed by. Clients are then
+    /// required to present one, and the journal records which they did.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_ca_file: Option<PathBuf>,
+    /// Let clients without a certificate in, still validating those presented
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub client_cert_optional: bool,
+    /// Certificates and routes for the server names clients ask for with SNI, of which
+    /// `*.api.test` is every subdomain of `api.test`. Other connections are served as usual.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub sni: BTreeMap<String, SniRoute>,
+    /// Also serve HTTP/3 over QUIC, on the UDP port of the same number, which responses
+    /// over TCP advertise with `Alt-Svc`. Needs the `http3` feature.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub http3: bool,
+}
+
+impl TlsConfig {
+    /// The port of the server answering the connections for `server_name`, if not this
+    /// one. A name given exactly is preferred to a `*.` wildcard matching it.
+    pub fn sni_port(&self, server_name: &str) -> Option<u16> {
+        self.sni.iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(server_name))
+            .or_else(|| self.sni.iter().find(|(name, _)| name.starts_with("*.") && vhosts::host_matches(name, server_name)))
+            .and_then(|(_, route)| route.port)
+    }
+}
+
+/// What a TLS server does with the connections for one SNI server name
+#[derive(Clone, Debug, PartialEq, serde_derive::Deserialize, serde_derive::Serialize)]
+pub struct SniRoute {
+    /// Port of the server on this host answering the requests of these connections, this
+    /// server by default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub port: Option<u16>,
+    /// PEM certificate chain served for the name unless `self_signed`, which has the CA
+    /// sign one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cert_file: Option<PathBuf>,
+    /// PEM key of the certificate in `cert_file`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key_file: Option<PathBuf>,
+}
+
+fn default_hostnames() -> Vec<String> {
+    vec!["localhost".to_string(), "127.0.0.1".to_string()]
+}
+
+fn invalid(error: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, error)
+}
+
+/// What a TLS server is set up with, kept when it restarts with the same `config` so the
+/// CA clients trust stays the same
+#[derive(Clone)]
+pub struct Tls {
+    pub config: TlsConfig,
+    pub acceptor: Acceptor,
+    /// The CA that signed a self-signed certificate
+    pub ca_pem: Option<String>,
+    /// PEM certificate chain served, leaf first, and its key, for HTTP/3 to serve too
+    #[cfg(feature = "http3")]
+    pub identity_pem: (String, String),
+}
+
+#[cfg(feature = "tls")]
+pub use self::openssl_tls::{setup, Acceptor};
+
+/// Servers can't be set up for TLS without the `tls` feature
+#[cfg(not(feature = "tls"))]
+#[derive(Clone)]
+pub enum Acceptor {}
+
+#[cfg(not(feature = "tls"))]
+pub fn setup(_port: u16, _config: &TlsConfig) -> io::Result<Tls> {
+    Err(invalid("TLS needs this server to be built with the `tls` feature".to_string()))
+}
+
+#[cfg(not(feature = "tls"))]
+impl Acceptor {
+    pub fn accept<T>(&self, _stream: T) -> Box<dyn futures::Future<Item = Stream<T>, Error = io::Error> + Send> {
+        match *self {}
+    }
+}
+
+#[cfg(feature = "tls")]
+mod openssl_tls {
+    use futures::{Async, Future, Poll};
+    use openssl::asn1::Asn1Time;
+    use openssl::bn::{BigNum, MsbOption};
+    use openssl::ec::{EcGroup, EcKey};
+    use openssl::error::ErrorStack;
+    use openssl::hash::MessageDigest;
+    use openssl::nid::Nid;
+    use openssl::pkey::{PKey, Private};
+    use openssl::ssl::{
+        HandshakeError, MidHandshakeSslStream, NameType, SniError, SslAcceptor, SslAcceptorBuilder, SslContext,
+        SslFiletype, SslMethod, SslVerifyMode,
+    };
+    use openssl::x509::{X509, X509Name, X509NameBuilder, X509NameRef, X509Ref};
+    use openssl::x509::extension::{
+        AuthorityKeyIdentifier, BasicConstraints, ExtendedKeyUsage, KeyUsage, SubjectAlternativeName,
+        SubjectKeyIdentifier,
+    };
+
+    use std::collections::HashMap;
+    use std::io::{self, Read, Write};
+    use std::path::Path;
+    use std::sync::{Arc, Mutex};
+
+    use crate::error::lock;
+    use super::{invalid, ClientCert, Stream, Tls, TlsConfig};
+
+    /// Takes connections through the TLS handshake. The certificate can be replaced while
+    /// the server runs, for connections accepted from then on.
+    #[derive(Clone)]
+    pub struct Acceptor(Arc<Mutex<Arc<SslAcceptor>>>);
+
+    impl Acceptor {
+        pub fn accept<T>(&self, stream: T) -> Box<dyn Future<Item = Stream<T>, Error = io::Error> + Send>
+        where
+            T: Read + Write + Send + 'static,
+        {
+            let acceptor = lock(&self.0).clone();
+            Box::new(Handshake::Start(acceptor, stream).map(Stream::Tls))
+        }
+
+        /// Serve the PEM certificate chain `chain`, leaf first, with the PEM key `key`,
+        /// no longer asking clients for certificates
+        #[cfg(feature = "acme")]
+        pub fn replace_certificate(&self, chain: &str, key: &str) -> Result<(), ErrorStack> {
+            let mut certificates = X509::stack_from_pem(chain.as_bytes())?.into_iter();
+            let mut acceptor = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls())?;
+            let key = PKey::private_key_from_pem(key.as_bytes())?;
+            acceptor.set_private_key(&key)?;
+            if let Some(leaf) = certificates.next() {
+                acceptor.set_certificate(&leaf)?;
+            }
+            for certificate in certificates {
+                acceptor.add_extra_chain_cert(certificate)?;
+            }
+            acceptor.check_private_key()?;
+            *lock(&self.0) = Arc::new(acceptor.build());
+            Ok(())
+        }
+    }
+
+    /// A TLS handshake over a non-blocking stream, resumed whenever it would block
+    enum Handshake<T> {
+        Start(Arc<SslAcceptor>, T),
+        Mid(MidHandshakeSslStream<T>),
+        Done,
+    }
+
+    impl<T: Read + Write> Future for Handshake<T> {
+        type Item = openssl::ssl::SslStream<T>;
+        type Error = io::Error;
+
+        fn poll(&mut self) -> Poll<Self::Item, io::Error> {
+            let result = match std::mem::replace(self, Handshake::Done) {
+                Handshake::Start(acceptor, stream) => acceptor.accept(stream),
+                Handshake::Mid(handshake) => handshake.handshake(),
+                Handshake::Done => panic!("TLS handshake polled after it finished"),
+            };
+            match result {
+                Ok(stream) => Ok(Async::Ready(stream)),
+                Err(HandshakeError::WouldBlock(handshake)) => {
+                    *self = Handshake::Mid(handshake);
+                    Ok(Async::NotReady)
+                }
+                Err(HandshakeError::Failure(handshake)) => Err(io::Error::other(handshake.into_error())),
+                Err(HandshakeError::SetupFailure(e)) => Err(io::Error::other(e)),
+            }
+        }
+    }
+
+    fn key() -> Result<PKey<Private>, ErrorStack> {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)?;
+        PKey::from_ec_key(EcKey::generate(&group)?)
+    }
+
+    fn name(common_name: &str) -> Result<X509Name, ErrorStack> {
+        let mut name = X509NameBuilder::new()?;
+        name.append_entry_by_nid(Nid::COMMONNAME, common_name)?;
+        Ok(name.build())
+    }
+
+    fn certificate(
+        subject: &X509Name,
+        key: &PKey<Private>,
+        issuer: Option<(&X509, &PKey<Private>)>,
+        hostnames: &[String]
+    ) -> Result<X509, ErrorStack> {
+        let mut serial = BigNum::new()?;
+        serial.rand(127, MsbOption::MAYBE_ZERO, false)?;
+
+        let mut builder = X509::builder()?;
+        builder.set_version(2)?;
+        let serial = serial.to_asn1_integer()?;
+        builder.set_serial_number(&serial)?;
+        builder.set_subject_name(subject)?;
+        builder.set_issuer_name(issuer.map_or(subject, |(ca, _)| ca.subject_name()))?;
+        builder.set_pubkey(key)?;
+        let (not_before, not_after) = (Asn1Time::days_from_now(0)?, Asn1Time::days_from_now(365)?);
+        builder.set_not_before(&not_before)?;
+        builder.set_not_after(&not_after)?;
+
+        let subject_key_id = SubjectKeyIdentifier::new().build(&builder.x509v3_context(None, None))?;
+        builder.append_extension(subject_key_id)?;
+        match issuer {
+            None => {
+                builder.append_extension(BasicConstraints::new().critical().ca().build()?)?;
+                builder.append_extension(KeyUsage::new().critical().key_cert_sign().crl_sign().build()?)?;
+            }
+            Some((ca, _)) => {
+                let authority_key_id = AuthorityKeyIdentifier::new()
+                    .keyid(false)
+                    .build(&builder.x509v3_context(Some(ca), None))?;
+                builder.append_extension(authority_key_id)?;
+                builder.append_extension(BasicConstraints::new().build()?)?;
+                builder.append_extension(KeyUsage::new().critical().digital_signature().key_encipherment().build()?)?;
+                builder.append_extension(ExtendedKeyUsage::new().server_auth().build()?)?;
+
+                let mut names = SubjectAlternativeName::new();
+                for hostname in hostnames {
+                    if hostname.parse::<std::net::IpAddr>().is_ok() {
+                        names.ip(hostname);
+                    } else {
+                        names.dns(hostname);
+                    }
+                }
+                let names = names.build(&builder.x509v3_context(Some(ca), None))?;
+                builder.append_extension(names)?;
+            }
+        }
+
+        builder.sign(issuer.map_or(key, |(_, ca_key)| ca_key), MessageDigest::sha256())?;
+        Ok(builder.build())
+    }
+
+    /// Have clients present a certificate signed by a CA in `client_ca_file`
+    fn require_client_certs(
+        acceptor: &mut SslAcceptorBuilder,
+        port: u16,
+        client_ca_file: &std::path::Path,
+        optional: bool
+    ) -> Result<(), ErrorStack> {
+        acceptor.set_ca_file(client_ca_file)?;
+        // Tells clients which CAs they may present a certificate of
+        acceptor.set_client_ca_list(X509Name::load_client_ca_file(client_ca_file)?);
+        // Sessions are only resumed by the server that verified them
+        acceptor.set_session_id_context(&port.to_be_bytes())?;
+        let mode = if optional { SslVerifyMode::PEER } else { SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT };
+        acceptor.set_verify(mode);
+        Ok(())
+    }
+
+    fn one_line(name: &X509NameRef) -> String {
+        name.entries()
+            .map(|entry| {
+                let key = entry.object().nid().short_name().unwrap_or("?");
+                let value = entry.data().to_string().unwrap_or_default();
+                format!("{}={}", key, value)
+            })
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    pub fn client_cert(cert: &X509Ref) -> ClientCert {
+        ClientCert {
+            subject: one_line(cert.subject_name()),
+            issuer: one_line(cert.issuer_name()),
+            serial: cert.serial_number().to_bn().map_or_else(|_| String::new(), |serial| hex(&serial.to_vec())),
+            sha256: cert.digest(MessageDigest::sha256()).map_or_else(|_| String::new(), |digest| hex(&digest)),
+        }
+    }
+
+    /// An acceptor of the server on `port`, asking clients for certificates if `config`
+    /// says so
+    fn builder(port: u16, config: &TlsConfig) -> io::Result<SslAcceptorBuilder> {
+        let mut acceptor = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls()).map_err(io::Error::other)?;
+        if let Some(client_ca_file) = &config.client_ca_file {
+            require_client_certs(&mut acceptor, port, client_ca_file, config.client_cert_optional)
+                .map_err(|e| invalid(format!("failed to load client CA: {}", e)))?;
+        }
+        Ok(acceptor)
+    }
+
+    /// Serve a certificate for `hostnames` that `ca` signs, returning the PEM chain and key
+    fn serve_generated(
+        acceptor: &mut SslAcceptorBuilder,
+        ca: &X509,
+        ca_key: &PKey<Private>,
+        hostnames: &[String]
+    ) -> Result<(String, String), ErrorStack> {
+        let server_key = key()?;
+        let server = certificate(&name(&hostnames[0])?, &server_key, Some((ca, ca_key)), hostnames)?;
+        acceptor.set_private_key(&server_key)?;
+        acceptor.set_certificate(&server)?;
+        acceptor.add_extra_chain_cert(ca.clone())?;
+
+        let chain = [server.to_pem()?, ca.to_pem()?].concat();
+        let key = server_key.private_key_to_pem_pkcs8()?;
+        Ok((String::from_utf8_lossy(&chain).into_owned(), String::from_utf8_lossy(&key).into_owned()))
+    }
+
+    /// Serve the certificate in `cert_file`, returning the PEM chain and key
+    fn serve_files(acceptor: &mut SslAcceptorBuilder, cert_file: &Path, key_file: &Path) -> io::Result<(String, String)> {
+        let loaded = acceptor.set_certificate_chain_file(cert_file)
+            .and_then(|_| acceptor.set_private_key_file(key_file, SslFiletype::PEM));
+        loaded.map_err(|e| invalid(format!("failed to load TLS certificate {}: {}", cert_file.display(), e)))?;
+        acceptor.check_private_key().map_err(|e| invalid(format!("TLS key doesn't match the certificate: {}", e)))?;
+        Ok((std::fs::read_to_string(cert_file)?, std::fs::read_to_string(key_file)?))
+    }
+
+    /// Set the server on `port` up with `config`, generating its certificates if
+    /// self-signed
+    pub fn setup(port: u16, config: &TlsConfig) -> io::Result<Tls> {
+        if config.http3 {
+            if !cfg!(feature = "http3") {
+                return Err(invalid("HTTP/3 needs this server to be built with the `http3` feature".to_string()));
+            }
+            if config.client_ca_file.is_some() || !config.sni.is_empty() {
+                return Err(invalid("HTTP/3 can't ask for client certificates or serve SNI names".to_string()));
+            }
+        }
+        let mut acceptor = builder(port, config)?;
+        let (ca, identity_pem) = if config.self_signed {
+            if config.hostnames.is_empty() {
+                return Err(invalid("a self-signed certificate needs hostnames".to_string()));
+            }
+            let mut generate = || -> Result<_, ErrorStack> {
+                let ca_key = key()?;
+                let ca_name = name(&format!("{} CA of port {}", env!("CARGO_PKG_NAME"), port))?;
+                let ca = certificate(&ca_name, &ca_key, None, &[])?;
+                let identity_pem = serve_generated(&mut acceptor, &ca, &ca_key, &config.hostnames)?;
+                Ok((Some((ca, ca_key)), identity_pem))
+            };
+            generate().map_err(io::Error::other)?
+        } else {
+            match (&config.cert_file, &config.key_file) {
+                (Some(cert_file), Some(key_file)) => (None, serve_files(&mut acceptor, cert_file, key_file)?),
+                _ => return Err(invalid("TLS needs self_signed, or cert_file and key_file".to_string())),
+            }
+        };
+
+        // Each server name has a context of its own, switched to once the client names it
+        let mut contexts = HashMap::new();
+        for (server_name, route) in &config.sni {
+            if !crate::vhosts::valid_host(server_name) {
+                return Err(invalid(format!("invalid SNI server name {:?}", server_name)));
+            }
+            if route.port == Some(port) {
+                return Err(invalid(format!("SNI server name {} can't be routed to its own server", server_name)));
+            }
+            let mut context = builder(port, config)?;
+            match (&ca, &route.cert_file, &route.key_file) {
+                (_, Some(cert_file), Some(key_file)) => {
+                    serve_files(&mut context, cert_file, key_file)?;
+                }
+                (Some((ca, ca_key)), None, None) => {
+                    serve_generated(&mut context, ca, ca_key, std::slice::from_ref(server_name)).map_err(io::Error::other)?;
+                }
+                _ => {
+                    let error = format!("SNI server name {} needs cert_file and key_file, or self_signed", server_name);
+                    return Err(invalid(error));
+                }
+            }
+            contexts.insert(server_name.to_ascii_lowercase(), context.build().into_context());
+        }
+        if !contexts.is_empty() {
+            acceptor.set_servername_callback(move |ssl, _alert| {
+                let context: Option<&SslContext> = ssl.servername(NameType::HOST_NAME).and_then(|server_name| {
+                    let server_name = server_name.to_ascii_lowercase();
+                    contexts.get(&server_name)
+                        .or_else(|| contexts.get(&format!("*.{}", server_name.split_once('.')?.1)))
+                });
+                match context {
+                    Some(context) => ssl.set_ssl_context(context).map_err(|_| SniError::ALERT_FATAL),
+                    None => Ok(()),
+                }
+            });
+        }
+
+        let ca_pem = match ca {
+            Some((ca, _)) => Some(String::from_utf8_lossy(&ca.to_pem().map_err(io::Error::other)?).into_owned()),
+            None => None,
+        };
+        #[cfg(not(feature = "http3"))]
+        let _ = identity_pem;
+        Ok(Tls {
+            config: config.clone(),
+            acceptor: Acceptor(Arc::new(Mutex::new(Arc::new(acceptor.build())))),
+            ca_pem,
+            #[cfg(feature = "http3")]
+            identity_pem,
+        })
+    }
+}
+
+/// A connection of an HTTP server, decrypted if it serves TLS. The journal and capture
+/// see what's inside the TLS.
+pub enum Stream<T> {
+    Plain(T),
+    #[cfg(feature = "tls")]
+    Tls(openssl::ssl::SslStream<T>),
+}
+
+impl<T> Stream<T> {
+    /// The certificate the client presented, if any
+    pub fn client_cert(&self) -> Option<ClientCert> {
+        match self {
+            Stream::Plain(_) => None,
+            #[cfg(feature = "tls")]
+            Stream::Tls(stream) => stream.ssl().peer_certificate().map(|cert| openssl_tls::client_cert(&cert)),
+        }
+    }
+
+    /// The server name the client asked for with SNI, if any
+    pub fn server_name(&self) -> Option<String> {
+        match self {
+            Stream::Plain(_) => None,
+            #[cfg(feature = "tls")]
+            Stream::Tls(stream) => stream.ssl().servername(openssl::ssl::NameType::HOST_NAME).map(str::to_string),
+        }
+    }
+}
+
+impl<T: Read + Write> Read for Stream<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(stream) => stream.read(buf),
+            #[cfg(feature = "tls")]
+            Stream::Tls(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl<T: Read + Write> Write for Stream<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(stream) => stream.write(buf),
+            #[cfg(feature = "tls")]
+            Stream::Tls(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Plain(stream) => stream.flush(),
+            #[cfg(feature = "tls")]
+            Stream::Tls(stream) => stream.flush(),
+        }
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite> AsyncRead for Stream<T> {}
+
+impl<T: AsyncRead + AsyncWrite> AsyncWrite for Stream<T> {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        match self {
+            Stream::Plain(stream) => stream.shutdown(),
+            #[cfg(feature = "tls")]
+            Stream::Tls(stream) => {
+                // Sends the close_notify alert before closing the connection itself
+                match stream.shutdown() {
+                    Ok(_) => {}
+                    Err(e) if e.code() == openssl::ssl::ErrorCode::ZERO_RETURN => {}
+                    Err(e) => match e.into_io_error() {
+                        Ok(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(futures::Async::NotReady),
+                        Ok(e) => return Err(e),
+                        Err(e) => return Err(io::Error::other(e)),
+                    },
+                }
+                stream.get_mut().shutdown()
+            }
+        }
+    }
+}
+
+/// The CA certificate that signed the self-signed one of the HTTP server on `port`, for
+/// clients to trust
+pub fn ca_pem(database: Database, port: u16) -> warp::reply::Response {
+    let registry = lock(&database);
+    let server = match registry.servers.get(&port) {
+        Some(server) => server,
+        None => return error_reply(warp::http::StatusCode::NOT_FOUND, &format!("no server on port {}", port)),
+    };
+
+    match server.tls.as_ref().and_then(|tls| tls.ca_pem.clone()) {
+        Some(pem) => warp::reply::with_header(pem, "content-type", "application/x-pem-file").into_response(),
+        None => {
+            let error = format!("server on port {} has no self-signed certificate", port);
+            error_reply(warp::http::StatusCode::NOT_FOUND, &error)
+        }
+    }
+}
@@ -0,0 +1,91 @@
+use warp::Reply;
+
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use crate::{unix_time, Database, FinalStats};
+use crate::error::lock;
+
+/// How long deleted servers are kept unless `TOMBSTONE_RETENTION_SECS` says otherwise
+const DEFAULT_RETENTION: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Tombstones kept at most, the oldest making way for new ones
+const MAX_TOMBSTONES: usize = 10_000;
+
+/// A deleted server as it was just before it was deleted, like `DELETE /{port}?stats=true`
+/// answers with
+#[derive(Debug, serde_derive::Serialize)]
+pub struct Tombstone {
+    pub port: u16,
+    /// Unix time it was deleted
+    pub deleted_at: u64,
+    #[serde(flatten)]
+    pub stats: FinalStats,
+}
+
+/// The servers deleted within the retention window of `TOMBSTONE_RETENTION_SECS`, a day
+/// by default or none kept at all if 0, oldest first, for `GET /deleted` to tell what was
+/// running when
+#[derive(Debug)]
+pub struct Tombstones {
+    tombstones: VecDeque<Tombstone>,
+    retention: Duration,
+}
+
+impl Default for Tombstones {
+    fn default() -> Tombstones {
+        Tombstones { tombstones: VecDeque::new(), retention: DEFAULT_RETENTION }
+    }
+}
+
+impl Tombstones {
+    pub fn from_env() -> Result<Tombstones, String> {
+        let retention = match std::env::var("TOMBSTONE_RETENTION_SECS") {
+            Ok(secs) => match secs.parse::<u64>() {
+                Ok(secs) => Duration::from_secs(secs),
+                Err(_) => return Err(format!("invalid TOMBSTONE_RETENTION_SECS {:?}, expected a number of seconds", secs)),
+            },
+            Err(_) => DEFAULT_RETENTION,
+        };
+        Ok(Tombstones { retention, ..Tombstones::default() })
+    }
+
+    pub fn bury(&mut self, stats: FinalStats) {
+        if self.retention.is_zero() {
+            return;
+        }
+        let tombstone = Tombstone { port: stats.server.port, deleted_at: unix_time(), stats };
+        self.tombstones.push_back(tombstone);
+        if self.tombstones.len() > MAX_TOMBSTONES {
+            self.tombstones.pop_front();
+        }
+        self.expire();
+    }
+
+    fn expire(&mut self) {
+        let oldest = unix_time().saturating_sub(self.retention.as_secs());
+        while self.tombstones.front().is_some_and(|tombstone| tombstone.deleted_at < oldest) {
+            self.tombstones.pop_front();
+        }
+    }
+}
+
+/// Query parameters of `GET /deleted`
+#[derive(Debug, serde_derive::Deserialize)]
+pub struct DeletedQuery {
+    port: Option<u16>,
+    /// Unix time the servers were running at: created by then and deleted after
+    at: Option<u64>,
+}
+
+/// The servers deleted within the retention window, oldest first
+pub fn list_deleted(database: Database, query: DeletedQuery) -> warp::reply::Response {
+    let mut registry = lock(&database);
+    registry.tombstones.expire();
+
+    let tombstones: Vec<&Tombstone> = registry.tombstones.tombstones.iter()
+        .filter(|tombstone| query.port.is_none_or(|port| tombstone.port == port))
+        .filter(|tombstone| query.at.is_none_or(|at| tombstone.stats.server.created_at <= at && at < tombstone.deleted_at))
+        .collect();
+    warp::reply::json(&tombstones).into_response()
+}
@@ -0,0 +1,264 @@
+use futures::{Future, Stream};
+use futures::sync::mpsc as futures_mpsc;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Modifier, Style};
+use ratatui::widgets::{Block, List, Paragraph, Row, Table, TableState};
+use serde_json::Value;
+
+use std::collections::{BTreeMap, VecDeque};
+use std::io;
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+
+use crate::{jsonrpc, runtime, Database, Registry, ServerJsonBody, ServerStatus};
+
+/// How often the servers are listed anew
+const REFRESH: Duration = Duration::from_millis(500);
+
+/// How long a call may take before the dashboard gives up on it
+const CALL_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Changes kept for the list of recent events
+const EVENTS: usize = 100;
+
+const HELP: &str = "n new  d delete  p pause/resume  s stop/start  ↑↓ select  q quit";
+
+/// A call of the admin API, made on the runtime, with where its result goes
+struct Call {
+    method: &'static str,
+    params: Value,
+    result: mpsc::Sender<Result<Value, String>>,
+}
+
+/// Show a dashboard of the servers in the terminal, taking it over until `q` quits the
+/// process: a table of the servers and the rate they answer requests at, the latest
+/// changes to them, and keys to create, delete, pause and stop servers. Everything it
+/// does is a JSON-RPC call, see `jsonrpc::call`, made as the admin API on `own_port`.
+/// What the servers print goes over the dashboard until it's next drawn.
+pub fn run(database: Database, registry: &mut Registry, own_port: u16) {
+    let (calls, queued) = futures_mpsc::unbounded::<Call>();
+    runtime::spawn(queued.for_each(move |Call { method, params, result }| {
+        jsonrpc::call(&database, own_port, method, params).then(move |answer| {
+            let _ = result.send(answer);
+            Ok(())
+        })
+    }));
+
+    let (publish, changes) = mpsc::channel();
+    runtime::spawn(registry.feed.subscribe().for_each(move |line| publish.send(line).map_err(|_| ())));
+
+    let spawned = std::thread::Builder::new()
+        .name("tui".to_string())
+        .spawn(move || {
+            let shown = Dashboard::new(own_port, calls, changes).show();
+            ratatui::restore();
+            match shown {
+                Ok(()) => std::process::exit(0),
+                Err(e) => {
+                    eprintln!("dashboard failed: {}", e);
+                    std::process::exit(1);
+                }
+            }
+        });
+    if let Err(e) = spawned {
+        eprintln!("failed to start the dashboard: {}", e);
+        std::process::exit(1);
+    }
+}
+
+struct Dashboard {
+    own_port: u16,
+    calls: futures_mpsc::UnboundedSender<Call>,
+    changes: mpsc::Receiver<String>,
+    servers: Vec<ServerJsonBody>,
+    /// Requests each server had answered when last listed, and when that was
+    answered: BTreeMap<u16, (u64, Instant)>,
+    /// Requests a second each server answered between the last two listings
+    rates: BTreeMap<u16, f64>,
+    events: VecDeque<String>,
+    table: TableState,
+    /// What the last key did
+    message: String,
+}
+
+impl Dashboard {
+    fn new(own_port: u16, calls: futures_mpsc::UnboundedSender<Call>, changes: mpsc::Receiver<String>) -> Dashboard {
+        Dashboard {
+            own_port,
+            calls,
+            changes,
+            servers: Vec::new(),
+            answered: BTreeMap::new(),
+            rates: BTreeMap::new(),
+            events: VecDeque::new(),
+            table: TableState::default().with_selected(Some(0)),
+            message: String::new(),
+        }
+    }
+
+    fn call(&self, method: &'static str, params: Value) -> Result<Value, String> {
+        let (result, answer) = mpsc::channel();
+        self.calls.unbounded_send(Call { method, params, result })
+            .map_err(|_| "the runtime is gone".to_string())?;
+        answer.recv_timeout(CALL_TIMEOUT).map_err(|_| format!("{} took too long", method))?
+    }
+
+    fn show(mut self) -> io::Result<()> {
+        let mut terminal = ratatui::try_init()?;
+        let mut refreshed = Instant::now() - REFRESH;
+        loop {
+            if refreshed.elapsed() >= REFRESH {
+                self.refresh();
+                refreshed = Instant::now();
+            }
+            while let Ok(line) = self.changes.try_recv() {
+                if self.events.len() == EVENTS {
+                    self.events.pop_front();
+                }
+                self.events.push_back(describe(&line));
+            }
+            terminal.draw(|frame| self.draw(frame))?;
+
+            if !event::poll(REFRESH.saturating_sub(refreshed.elapsed()))? {
+                continue;
+            }
+            let key = match event::read()? {
+                Event::Key(key) if key.kind == KeyEventKind::Press => key.code,
+                _ => continue,
+            };
+            match key {
+                KeyCode::Char('q') | KeyCode::Esc => return Ok(()),
+                KeyCode::Up | KeyCode::Char('k') => self.table.select_previous(),
+                KeyCode::Down | KeyCode::Char('j') => self.table.select_next(),
+                KeyCode::Char('n') => {
+                    self.message = match self.call("create", serde_json::json!({})) {
+                        Ok(server) => format!("created server {}", server["port"]),
+                        Err(error) => error,
+                    };
+                    refreshed -= REFRESH;
+                }
+                KeyCode::Char(key @ ('d' | 'p' | 's')) => {
+                    if let Some(server) = self.table.selected().and_then(|selected| self.servers.get(selected)) {
+                        let (method, done) = match (key, server.status) {
+                            ('d', _) => ("delete", "deleted"),
+                            ('p', ServerStatus::Paused) => ("resume", "resumed"),
+                            ('p', _) => ("pause", "paused"),
+                            ('s', ServerStatus::Stopped | ServerStatus::Crashed) => ("start", "started"),
+                            _ => ("stop", "stopped"),
+                        };
+                        let port = server.port;
+                        self.message = match self.call(method, serde_json::json!({ "port": port })) {
+                            Ok(_) => format!("{} server {}", done, port),
+                            Err(error) => error,
+                        };
+                        refreshed -= REFRESH;
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// List the servers anew, and tell the rates they answer requests at
+    fn refresh(&mut self) {
+        let listed = self.call("list", Value::Null)
+            .and_then(|servers| serde_json::from_value::<Vec<ServerJsonBody>>(servers).map_err(|e| e.to_string()));
+        let servers = match listed {
+            Ok(servers) => servers,
+            Err(error) => {
+                self.message = error;
+                return;
+            }
+        };
+        let now = Instant::now();
+        for server in &servers {
+            let requests = server.latency.as_ref().map_or(0, |latency| latency.requests);
+            if let Some((before, at)) = self.answered.insert(server.port, (requests, now)) {
+                let secs = now.duration_since(at).as_secs_f64();
+                self.rates.insert(server.port, requests.saturating_sub(before) as f64 / secs.max(0.001));
+            }
+        }
+        self.answered.retain(|port, _| servers.iter().any(|server| server.port == *port));
+        let answered = &self.answered;
+        self.rates.retain(|port, _| answered.contains_key(port));
+        self.servers = servers;
+        if self.table.selected().is_none_or(|selected| selected >= self.servers.len()) {
+            self.table.select(self.servers.len().checked_sub(1));
+        }
+    }
+
+    fn draw(&mut self, frame: &mut ratatui::Frame) {
+        let [title, table, events, status] = Layout::vertical([
+            Constraint::Length(1),
+            Constraint::Min(5),
+            Constraint::Length(10),
+            Constraint::Length(1),
+        ])
+        .areas(frame.area());
+
+        let heading = format!("Servers of the admin API on port {}   {}", self.own_port, HELP);
+        frame.render_widget(Paragraph::new(heading).style(Style::new().add_modifier(Modifier::BOLD)), title);
+
+        let rows = self.servers.iter().map(|server| {
+            let latency = server.latency.as_ref();
+            Row::new([
+                server.port.to_string(),
+                server.kind.name().to_string(),
+                server.status.name().to_string(),
+                server.uptime_secs.map_or_else(String::new, uptime),
+                latency.map_or(0, |latency| latency.requests).to_string(),
+                self.rates.get(&server.port).map_or_else(String::new, |rate| format!("{:.1}", rate)),
+                latency.map_or_else(String::new, |latency| format!("{:.2}", latency.p95_ms)),
+            ])
+        });
+        let widths = [
+            Constraint::Length(6),
+            Constraint::Length(7),
+            Constraint::Length(9),
+            Constraint::Length(9),
+            Constraint::Length(9),
+            Constraint::Length(8),
+            Constraint::Length(8),
+        ];
+        let servers = Table::new(rows, widths)
+            .header(Row::new(["PORT", "KIND", "STATUS", "UPTIME", "REQUESTS", "REQ/S", "P95 MS"])
+                .style(Style::new().add_modifier(Modifier::BOLD)))
+            .block(Block::bordered().title(format!(" {} servers ", self.servers.len())))
+            .row_highlight_style(Style::new().add_modifier(Modifier::REVERSED));
+        frame.render_stateful_widget(servers, table, &mut self.table);
+
+        // The latest that fit, latest last
+        let fit = usize::from(events.height.saturating_sub(2));
+        let recent: Vec<&str> = self.events.iter().skip(self.events.len().saturating_sub(fit)).map(String::as_str).collect();
+        frame.render_widget(List::new(recent).block(Block::bordered().title(" Recent events ")), events);
+
+        frame.render_widget(Paragraph::new(self.message.as_str()), status);
+    }
+}
+
+/// A line of `GET /watch` as a line of the recent events
+fn describe(line: &str) -> String {
+    let change: Value = serde_json::from_str(line).unwrap_or_default();
+    let server = &change["server"];
+    let mut event = format!(
+        "#{:<6} {:<8} {:<6} {}",
+        change["revision"].to_string(),
+        change["type"].as_str().unwrap_or(""),
+        server["port"].to_string(),
+        server["status"].as_str().unwrap_or("")
+    );
+    if let Some(chaos) = change["chaos"].as_str() {
+        event.push_str(&format!(" (chaos: {})", chaos));
+    }
+    event
+}
+
+/// Like `1h02m`, `3m07s` or `12s`
+fn uptime(secs: u64) -> String {
+    match secs {
+        0..=59 => format!("{}s", secs),
+        60..=3599 => format!("{}m{:02}s", secs / 60, secs % 60),
+        _ => format!("{}h{:02}m", secs / 3600, secs % 3600 / 60),
+    }
+}
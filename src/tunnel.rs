@@ -0,0 +1,52 @@
+use futures::{Future, Sink, Stream};
+use tokio::codec::{BytesCodec, FramedRead};
+use tokio::io::AsyncRead;
+use tokio::net::TcpStream;
+use warp::Reply;
+use warp::ws::{Message, Ws2};
+
+use std::io;
+
+use crate::{error_reply, Database, ServerKind};
+use crate::error::lock;
+
+/// `GET /{port}/tunnel` upgraded to a WebSocket: a TCP connection to the listener of the
+/// server on `port`, for clients that can only reach the admin API's port. The bytes
+/// of every binary or text message are written to the connection, and what it reads is
+/// sent back in binary messages. Closing either end closes the other.
+pub fn tunnel(database: Database, port: u16, ws: Ws2) -> warp::reply::Response {
+    let kind = match lock(&database).servers.get(&port) {
+        Some(server) => server.config.kind.clone(),
+        None => return error_reply(warp::http::StatusCode::NOT_FOUND, &format!("no server on port {}", port)),
+    };
+    if let ServerKind::Udp(_) | ServerKind::Dns(_) = kind {
+        let error = format!("a {} server has no TCP listener to tunnel to", kind.name());
+        return error_reply(warp::http::StatusCode::CONFLICT, &error);
+    }
+
+    ws.on_upgrade(move |socket| {
+        TcpStream::connect(&([127, 0, 0, 1], port).into())
+            .map_err(move |e| eprintln!("failed to tunnel to server {}: {}", port, e))
+            .and_then(move |connection| {
+                let (read, write) = AsyncRead::split(connection);
+                let (sink, incoming) = socket.split();
+                let sent = incoming
+                    .map_err(io::Error::other)
+                    .take_while(|message| Ok(!message.is_close()))
+                    .filter(|message| message.is_binary() || message.is_text())
+                    .fold(write, |write, message| tokio::io::write_all(write, message.as_bytes().to_vec()).map(|(write, _)| write))
+                    .and_then(tokio::io::shutdown)
+                    .map(drop);
+                let received = FramedRead::new(read, BytesCodec::new())
+                    .map(|bytes| Message::binary(bytes.to_vec()))
+                    .forward(sink.sink_map_err(io::Error::other))
+                    .map(drop);
+                sent.select2(received).then(move |ended| {
+                    if let Err(e) = ended.map_err(|e| e.split().0) {
+                        eprintln!("tunnel to server {} failed: {}", port, e);
+                    }
+                    Ok(())
+                })
+            })
+    }).into_response()
+}
@@ -0,0 +1,51 @@
+use futures::{Future, Stream};
+use futures::sync::oneshot;
+use tokio::codec::BytesCodec;
+use tokio::net::{UdpFramed, UdpSocket};
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// How a UDP server answers incoming datagrams
+#[derive(Clone, Debug, PartialEq, serde_derive::Deserialize, serde_derive::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UdpMode {
+    /// Send every datagram back to its sender
+    Echo,
+    /// Answer each datagram with the next response in the script, starting over
+    /// after the last one. An empty script never answers.
+    Script(Vec<String>),
+}
+
+/// Bind a UDP server on `port`, answering datagrams until `shutdown` fires.
+/// Datagrams received while `paused` are dropped.
+pub fn create_udp_server(
+    mode: UdpMode,
+    port: u16,
+    paused: Arc<AtomicBool>,
+    shutdown: oneshot::Receiver<()>
+) -> std::io::Result<impl Future<Item = (), Error = ()>> {
+    let socket = UdpSocket::bind(&([127, 0, 0, 1], port).into())?;
+    let (sink, stream) = UdpFramed::new(socket, BytesCodec::new()).split();
+
+    let mut received = 0;
+    let replies = stream.filter_map(move |(datagram, addr)| {
+        if paused.load(Ordering::SeqCst) {
+            return None;
+        }
+        let reply = match mode {
+            UdpMode::Echo => Some(datagram.freeze()),
+            UdpMode::Script(ref responses) if responses.is_empty() => None,
+            UdpMode::Script(ref responses) => {
+                Some(responses[received % responses.len()].clone().into())
+            }
+        };
+        received += 1;
+        reply.map(|reply| (reply, addr))
+    });
+
+    let server = replies.forward(sink)
+        .map_err(|e| eprintln!("udp server error: {}", e));
+
+    Ok(server.select2(shutdown).then(|_| Ok(())))
+}
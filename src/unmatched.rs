@@ -0,0 +1,232 @@
+use warp::Reply;
+
+use crate::{error_reply, Database};
+use crate::error::lock;
+use crate::journal::{self, Entry};
+
+/// Candidate routes shown for an unmatched request
+const MAX_CANDIDATES: usize = 3;
+
+/// The routes of the admin API an HTTP server replicates, by method and path, kept in
+/// step with `app_filter`. `{port}` stands for a port; `{name}` for any segment.
+const ROUTES: &[(&str, &str)] = &[
+    ("GET", "/"),
+    ("POST", "/"),
+    ("GET", "/watch"),
+    ("GET", "/templates"),
+    ("POST", "/templates"),
+    ("GET", "/diff"),
+    ("GET", "/quota"),
+    ("GET", "/metrics"),
+    ("GET", "/system"),
+    ("GET", "/healthz"),
+    ("POST", "/apply"),
+    ("POST", "/rpc"),
+    ("GET", "/rpc"),
+    ("POST", "/upgrade"),
+    ("GET", "/fleet"),
+    ("GET", "/fleet/watch"),
+    ("GET", "/history"),
+    ("GET", "/history/{port}"),
+    ("GET", "/deleted"),
+    ("POST", "/duplicate"),
+    ("GET", "/snapshot"),
+    ("POST", "/restore"),
+    ("GET", "/chaos"),
+    ("PUT", "/chaos"),
+    ("DELETE", "/chaos"),
+    ("GET", "/maintenance"),
+    ("PUT", "/maintenance"),
+    ("DELETE", "/maintenance"),
+    ("GET", "/admin/readonly"),
+    ("PUT", "/admin/readonly"),
+    ("GET", "/{port}"),
+    ("PUT", "/{port}"),
+    ("DELETE", "/{port}"),
+    ("POST", "/{port}/heartbeat"),
+    ("POST", "/{port}/clone"),
+    ("POST", "/{port}/load"),
+    ("GET", "/{port}/requests"),
+    ("DELETE", "/{port}/requests"),
+    ("GET", "/{port}/requests/stream"),
+    ("GET", "/{port}/requests/export"),
+    ("POST", "/{port}/requests/diff"),
+    ("GET", "/{port}/unmatched"),
+    ("GET", "/{port}/stubs"),
+    ("PUT", "/{port}/stubs"),
+    ("PATCH", "/{port}/stubs"),
+    ("POST", "/{port}/stubs/{name}/reset"),
+    ("GET", "/{port}/state"),
+    ("PUT", "/{port}/state"),
+    ("GET", "/{port}/profile"),
+    ("PUT", "/{port}/profile"),
+    ("DELETE", "/{port}/profile"),
+    ("GET", "/{port}/config"),
+    ("GET", "/{port}/clock"),
+    ("PUT", "/{port}/clock"),
+    ("DELETE", "/{port}/clock"),
+    ("GET", "/{port}/capture"),
+    ("POST", "/{port}/capture"),
+    ("GET", "/{port}/hosts"),
+    ("PUT", "/{port}/hosts/{name}"),
+    ("DELETE", "/{port}/hosts/{name}"),
+    ("GET", "/{port}/ca.pem"),
+    ("POST", "/{port}/verify"),
+    ("POST", "/{port}/pause"),
+    ("POST", "/{port}/resume"),
+    ("POST", "/{port}/stop"),
+    ("POST", "/{port}/start"),
+];
+
+/// What `GET /{port}/unmatched` found
+#[derive(Debug, serde_derive::Serialize)]
+struct UnmatchedReport {
+    /// Requests evicted from the journal, which may have been unmatched
+    evicted: u64,
+    requests: Vec<UnmatchedRequest>,
+}
+
+#[derive(Debug, serde_derive::Serialize)]
+struct UnmatchedRequest {
+    id: u64,
+    started_at_ms: u64,
+    method: String,
+    path: String,
+    status: u16,
+    /// The routes closest to matching, with how the request differs from them
+    candidates: Vec<Candidate>,
+}
+
+#[derive(Debug, serde_derive::Serialize)]
+struct Candidate {
+    method: &'static str,
+    path: &'static str,
+    diffs: Vec<Diff>,
+}
+
+/// What a request has that a route doesn't take
+#[derive(Debug, serde_derive::Serialize)]
+struct Diff {
+    /// `method`, or `segment {n}` of the path counting from 1
+    field: String,
+    expected: String,
+    /// Left out if the request has no such segment
+    #[serde(skip_serializing_if = "Option::is_none")]
+    actual: Option<String>,
+}
+
+/// The segments of the path of a request, without its query string
+fn segments(path: &str) -> Vec<&str> {
+    let path = path.split_once('?').map_or(path, |(path, _)| path);
+    path.split('/').filter(|segment| !segment.is_empty()).collect()
+}
+
+/// How a request differs from a route, and how far it is from it: a wrong method
+/// counting one, and a wrong segment the characters it takes to fix it. No diffs if it
+/// matches.
+fn compare(method: &str, path: &[&str], route: (&str, &str)) -> (Vec<Diff>, usize) {
+    let (route_method, route_path) = route;
+    let mut diffs = Vec::new();
+    let mut distance = 0;
+    if !route_method.eq_ignore_ascii_case(method) {
+        distance += 1;
+        diffs.push(Diff { field: "method".to_string(), expected: route_method.to_string(), actual: Some(method.to_string()) });
+    }
+
+    let expected = segments(route_path);
+    for index in 0..expected.len().max(path.len()) {
+        let field = format!("segment {}", index + 1);
+        match (expected.get(index).copied(), path.get(index).copied()) {
+            (Some("{port}"), Some(actual)) if actual.parse::<u16>().is_err() => {
+                distance += actual.len();
+                diffs.push(Diff { field, expected: "a port".to_string(), actual: Some(actual.to_string()) });
+            }
+            (Some("{port}" | "{name}"), Some(_)) => {}
+            (Some(expected), Some(actual)) if expected != actual => {
+                distance += edit_distance(expected, actual);
+                diffs.push(Diff { field, expected: expected.to_string(), actual: Some(actual.to_string()) });
+            }
+            (Some(_), Some(_)) => {}
+            (Some(expected), None) => {
+                distance += expected.len();
+                diffs.push(Diff { field, expected: expected.to_string(), actual: None });
+            }
+            (None, Some(actual)) => {
+                distance += actual.len();
+                diffs.push(Diff { field, expected: "nothing".to_string(), actual: Some(actual.to_string()) });
+            }
+            (None, None) => unreachable!(),
+        }
+    }
+    (diffs, distance)
+}
+
+/// Characters to insert, delete or substitute to turn `a` into `b`
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, a) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, b) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(a != *b);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+/// The route taking a request with `method` for `path`, if one does
+pub fn route_for(method: &str, path: &str) -> Option<(&'static str, &'static str)> {
+    let path = segments(path);
+    ROUTES.iter().copied().find(|route| compare(method, &path, *route).0.is_empty())
+}
+
+/// Whether one of the routes takes a request with `method` for `path`
+pub fn is_routed(method: &str, path: &str) -> bool {
+    route_for(method, path).is_some()
+}
+
+/// Whether no route took the request `entry` recorded, which was answered with 404 or
+/// 405 for it
+pub fn is_unmatched(entry: &Entry) -> bool {
+    let answered = entry.response.as_ref().is_some_and(|response| matches!(response.status, 404 | 405));
+    answered && !is_routed(&entry.request.method, &entry.request.path)
+}
+
+/// `GET /{port}/unmatched`: the requests recorded by the HTTP server on `port` that no
+/// route took, oldest first, each with the routes closest to taking it and how it
+/// differs from them
+pub fn unmatched(database: Database, port: u16) -> warp::reply::Response {
+    let journal = match journal::find_journal(&database, port) {
+        Ok(journal) => journal,
+        Err((status, error)) => return error_reply(status, &error),
+    };
+    let journal = lock(&journal);
+
+    let requests = journal.entries()
+        .filter(|entry| is_unmatched(entry))
+        .map(|entry| {
+            let path = segments(&entry.request.path);
+            let mut candidates: Vec<(Candidate, usize)> = ROUTES.iter()
+                .map(|&(method, route_path)| {
+                    let (diffs, distance) = compare(&entry.request.method, &path, (method, route_path));
+                    (Candidate { method, path: route_path, diffs }, distance)
+                })
+                .collect();
+            // Closest first, and of those the ones differing in the fewest places
+            candidates.sort_by_key(|(candidate, distance)| (*distance, candidate.diffs.len()));
+            UnmatchedRequest {
+                id: entry.id,
+                started_at_ms: entry.started_at_ms,
+                method: entry.request.method.clone(),
+                path: entry.request.path.clone(),
+                status: entry.response.as_ref().map_or(0, |response| response.status),
+                candidates: candidates.into_iter().take(MAX_CANDIDATES).map(|(candidate, _)| candidate).collect(),
+            }
+        })
+        .collect();
+
+    let report = UnmatchedReport { evicted: journal.stats().evicted, requests };
+    warp::reply::json(&report).into_response()
+}
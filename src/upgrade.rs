@@ -0,0 +1,170 @@
+use futures::Future;
+use tokio::timer::Delay;
+
+use std::collections::HashMap;
+use std::net::TcpListener;
+#[cfg(unix)]
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+#[cfg(unix)]
+use std::os::unix::process::CommandExt;
+#[cfg(unix)]
+use std::process::Command;
+use std::time::{Duration, Instant};
+
+use crate::{error_reply, start_server, Database, Isolation, Registry, ServerJsonBody, ServerStatus};
+use crate::error::lock;
+use crate::runtime;
+use crate::watch::ChangeType;
+
+/// Carries the registry from the process being upgraded to the new one
+const UPGRADE_STATE_VAR: &str = "WARP_SELF_REPLICATING_UPGRADE";
+
+/// Listeners are only handed over where there's `exec`, which Windows lacks
+#[cfg(not(unix))]
+type RawFd = i32;
+
+/// Time for the `POST /upgrade` response to go out before the process is replaced
+const EXEC_DELAY: Duration = Duration::from_millis(100);
+
+/// Query parameters of `POST /upgrade`
+#[derive(Debug, serde_derive::Deserialize)]
+pub struct UpgradeQuery {
+    /// Path of the binary to exec, by default the one this process was started as
+    binary: Option<String>,
+}
+
+/// A server as handed over to the upgraded process
+#[derive(Debug, serde_derive::Deserialize, serde_derive::Serialize)]
+pub struct HandedOverServer {
+    config: ServerJsonBody,
+    status: ServerStatus,
+    restarts: u32,
+    created_at: u64,
+    /// The server's listening socket, left open across the exec
+    fd: Option<RawFd>,
+}
+
+/// Replace this process with a new binary, handing over the registry and the listeners
+/// of the servers that are up, so they keep accepting connections throughout. Servers in
+/// a subprocess or container can't be handed over, so their presence refuses the upgrade.
+/// Without `exec`, on Windows, it's refused with 501.
+pub fn upgrade(
+    database: Database,
+    query: UpgradeQuery
+) -> warp::reply::Response {
+    if cfg!(not(unix)) {
+        let error = "upgrading in place is only supported on Unix";
+        return error_reply(warp::http::StatusCode::NOT_IMPLEMENTED, error);
+    }
+
+    let registry = lock(&database);
+
+    let external = registry.servers.values().any(|server| {
+        server.config.isolation == Isolation::Subprocess || !server.config.kind.backend().in_process()
+    });
+    if external {
+        let error = "servers running in a subprocess or container can't be handed over";
+        return error_reply(warp::http::StatusCode::CONFLICT, error);
+    }
+    drop(registry);
+
+    let binary = query.binary
+        .or_else(|| std::env::args().next())
+        .unwrap_or_default();
+
+    runtime::spawn(Delay::new(Instant::now() + EXEC_DELAY)
+        .map_err(|e| eprintln!("upgrade timer error: {}", e))
+        .map(move |_| exec(&database, &binary)));
+
+    warp::reply::Reply::into_response(warp::http::StatusCode::ACCEPTED)
+}
+
+#[cfg(not(unix))]
+fn exec(_database: &Database, _binary: &str) {}
+
+#[cfg(unix)]
+fn exec(database: &Database, binary: &str) {
+    // Held until the exec, so nothing changes after the registry has been serialized
+    let registry = lock(database);
+
+    let servers: Vec<HandedOverServer> = registry.servers.values()
+        .map(|server| HandedOverServer {
+            config: server.config.clone(),
+            status: server.status,
+            restarts: server.restarts,
+            created_at: server.created_at,
+            fd: server.listener.as_ref().map(TcpListener::as_raw_fd),
+        })
+        .collect();
+
+    for fd in servers.iter().filter_map(|server| server.fd) {
+        // Listeners are opened close-on-exec
+        unsafe {
+            libc::fcntl(fd, libc::F_SETFD, 0);
+        }
+    }
+
+    let state = match serde_json::to_string(&servers) {
+        Ok(state) => state,
+        Err(e) => return eprintln!("failed to serialize registry for upgrade: {}", e),
+    };
+
+    eprintln!("upgrading to {}", binary);
+    // The new process takes the same `--log` sinks itself, its stderr the one this started with
+    crate::logging::finish();
+    let e = Command::new(binary)
+        .args(std::env::args().skip(1))
+        .env(UPGRADE_STATE_VAR, state)
+        .exec();
+    eprintln!("failed to exec {}: {}", binary, e);
+}
+
+/// The servers handed over by the process this one was upgraded from, if any
+pub fn handed_over_servers() -> Option<Vec<HandedOverServer>> {
+    let state = std::env::var(UPGRADE_STATE_VAR).ok()?;
+    std::env::remove_var(UPGRADE_STATE_VAR);
+
+    match serde_json::from_str(&state) {
+        Ok(servers) => Some(servers),
+        Err(e) => {
+            eprintln!("ignoring invalid upgrade state: {}", e);
+            None
+        }
+    }
+}
+
+/// Take ownership of the listeners of handed over servers, by port
+#[cfg(unix)]
+pub fn handed_over_listeners(servers: &[HandedOverServer]) -> HashMap<u16, TcpListener> {
+    servers.iter()
+        .filter_map(|server| server.fd.map(|fd| (server.config.port, fd)))
+        // The previous process left these descriptors open for this one, and nothing
+        // else owns them
+        .map(|(port, fd)| (port, unsafe { TcpListener::from_raw_fd(fd) }))
+        .collect()
+}
+
+#[cfg(not(unix))]
+pub fn handed_over_listeners(_servers: &[HandedOverServer]) -> HashMap<u16, TcpListener> {
+    HashMap::new()
+}
+
+/// Register and start the handed over servers. Those that were stopped or crashed are
+/// shut down again straight away, ending up `stopped`.
+pub fn restore(database: &Database, registry: &mut Registry, servers: Vec<HandedOverServer>) {
+    for server in servers {
+        let port = server.config.port;
+        if let Err(e) = start_server(database, registry, server.config, server.restarts) {
+            eprintln!("failed to restore server {}: {}", port, e);
+            continue;
+        }
+
+        let restored = registry.servers.get_mut(&port).unwrap();
+        restored.created_at = server.created_at;
+        if matches!(server.status, ServerStatus::Stopped | ServerStatus::Crashed) {
+            restored.status = ServerStatus::Draining;
+            restored.signal_shutdown();
+        }
+        registry.record_change(port, ChangeType::Modified);
+    }
+}
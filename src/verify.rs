@@ -0,0 +1,406 @@
+use warp::Reply;
+
+use std::collections::BTreeMap;
+
+use crate::{error_reply, Database};
+use crate::error::lock;
+use crate::journal::{self, Entry, Header, RecordedRequest};
+
+/// Near misses shown for an assertion that failed
+const MAX_NEAR_MISSES: usize = 3;
+
+/// JSON body of `POST /{port}/verify`
+#[derive(Debug, serde_derive::Deserialize)]
+pub struct VerifyJsonBody {
+    assertions: Vec<Assertion>,
+}
+
+/// How many of the recorded requests must match, like "exactly 3 requests matched
+/// method=POST path=/orders with header X". Without a count, at least one must.
+#[derive(Debug, serde_derive::Deserialize)]
+pub struct Assertion {
+    /// Names the assertion in the results, by default its position
+    #[serde(default)]
+    name: Option<String>,
+    #[serde(flatten)]
+    pattern: RequestPattern,
+    #[serde(default)]
+    count: Option<usize>,
+    #[serde(default)]
+    at_least: Option<usize>,
+    #[serde(default)]
+    at_most: Option<usize>,
+}
+
+/// What a recorded request is matched by, any request if nothing is given
+#[derive(Debug, serde_derive::Deserialize)]
+struct RequestPattern {
+    #[serde(default)]
+    method: Option<String>,
+    /// The path without the query string
+    #[serde(default)]
+    path: Option<String>,
+    /// Query parameters the request must have, with these values
+    #[serde(default)]
+    query: BTreeMap<String, String>,
+    /// Headers the request must have, with these values. Names are case insensitive.
+    /// The pseudo headers of HTTP/2 stand for the request line and host: `:method`,
+    /// `:path`, with the query string, and `:authority`.
+    #[serde(default)]
+    headers: BTreeMap<String, String>,
+    /// Trailers the chunked request body must end with, with these values
+    #[serde(default)]
+    trailers: BTreeMap<String, String>,
+    /// Trailers the chunked response body must end with, with these values, like
+    /// `grpc-status`
+    #[serde(default)]
+    response_trailers: BTreeMap<String, String>,
+    /// Text the captured request body must contain
+    #[serde(default)]
+    body_contains: Option<String>,
+}
+
+/// What `POST /{port}/verify` found
+#[derive(Debug, serde_derive::Serialize)]
+struct VerifyReport {
+    passed: bool,
+    /// Requests evicted from the journal before verification, which may have matched
+    evicted: u64,
+    results: Vec<AssertionResult>,
+}
+
+#[derive(Debug, serde_derive::Serialize)]
+struct AssertionResult {
+    name: String,
+    passed: bool,
+    /// The count expected, like "exactly 3" or "at least 1"
+    expected: String,
+    matched: usize,
+    /// Journal ids of the matching requests
+    matched_ids: Vec<u64>,
+    /// The recorded requests closest to matching, with how they differ, if too few matched
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    near_misses: Vec<NearMiss>,
+}
+
+#[derive(Debug, serde_derive::Serialize)]
+struct NearMiss {
+    id: u64,
+    method: String,
+    path: String,
+    diffs: Vec<Diff>,
+}
+
+/// A criterion of an assertion a request doesn't meet
+#[derive(Debug, serde_derive::Serialize)]
+struct Diff {
+    field: String,
+    expected: String,
+    /// Left out if the request has no such field
+    #[serde(skip_serializing_if = "Option::is_none")]
+    actual: Option<String>,
+}
+
+impl Assertion {
+    /// The count bounds, checking they're possible
+    fn bounds(&self) -> Result<(usize, usize), String> {
+        let (min, max) = match (self.count, self.at_least, self.at_most) {
+            (Some(count), None, None) => (count, count),
+            (Some(_), ..) => return Err("count can't be combined with at_least or at_most".to_string()),
+            (None, None, None) => (1, usize::MAX),
+            (None, at_least, at_most) => (at_least.unwrap_or(0), at_most.unwrap_or(usize::MAX)),
+        };
+        if min > max {
+            return Err(format!("at_least {} is above at_most {}", min, max));
+        }
+        Ok((min, max))
+    }
+}
+
+impl RequestPattern {
+    /// How `entry` differs from what the assertion matches; empty if it matches
+    fn diffs(&self, entry: &Entry) -> Vec<Diff> {
+        let request = &entry.request;
+        let (path, query) = match request.path.split_once('?') {
+            Some((path, query)) => (path, query),
+            None => (request.path.as_str(), ""),
+        };
+        let mut diffs = Vec::new();
+
+        if let Some(method) = &self.method {
+            if !method.eq_ignore_ascii_case(&request.method) {
+                diffs.push(Diff { field: "method".to_string(), expected: method.clone(), actual: Some(request.method.clone()) });
+            }
+        }
+        if let Some(expected) = &self.path {
+            if expected != path {
+                diffs.push(Diff { field: "path".to_string(), expected: expected.clone(), actual: Some(path.to_string()) });
+            }
+        }
+        for (name, expected) in &self.query {
+            let actual = query.split('&')
+                .filter_map(|pair| pair.split_once('=').or(Some((pair, ""))))
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value.to_string());
+            if actual.as_ref() != Some(expected) {
+                diffs.push(Diff { field: format!("query {}", name), expected: expected.clone(), actual });
+            }
+        }
+        for (name, expected) in &self.headers {
+            let actual = header_value(request, name);
+            if actual.as_ref() != Some(expected) {
+                diffs.push(Diff { field: format!("header {}", name), expected: expected.clone(), actual });
+            }
+        }
+        let response_trailers = entry.response.as_ref().map_or(&[][..], |response| &response.trailers);
+        let trailers = [("trailer", &self.trailers, &request.trailers[..]), ("response trailer", &self.response_trailers, response_trailers)];
+        for (field, expected_trailers, trailers) in trailers {
+            for (name, expected) in expected_trailers {
+                let actual = find(trailers, name);
+                if actual.as_ref() != Some(expected) {
+                    diffs.push(Diff { field: format!("{} {}", field, name), expected: expected.clone(), actual });
+                }
+            }
+        }
+        if let Some(expected) = &self.body_contains {
+            if !request.body.text.contains(expected.as_str()) {
+                let actual = Some(request.body.text.clone()).filter(|body| !body.is_empty());
+                diffs.push(Diff { field: "body".to_string(), expected: format!("containing {:?}", expected), actual });
+            }
+        }
+        diffs
+    }
+}
+
+fn find(headers: &[Header], name: &str) -> Option<String> {
+    headers.iter()
+        .find(|header| header.name.eq_ignore_ascii_case(name))
+        .map(|header| header.value.clone())
+}
+
+/// The value of the header `name` of `request`, or of the pseudo header it is
+fn header_value(request: &RecordedRequest, name: &str) -> Option<String> {
+    match name {
+        ":method" => Some(request.method.clone()),
+        ":path" => Some(request.path.clone()),
+        ":authority" => find(&request.headers, "host"),
+        name => find(&request.headers, name),
+    }
+}
+
+fn describe(min: usize, max: usize) -> String {
+    match (min, max) {
+        (min, max) if min == max => format!("exactly {}", min),
+        (min, usize::MAX) => format!("at least {}", min),
+        (0, max) => format!("at most {}", max),
+        (min, max) => format!("between {} and {}", min, max),
+    }
+}
+
+/// Evaluate `assertions` against the requests recorded by the HTTP server on `port`,
+/// reporting every assertion with near misses for those that failed
+pub fn verify(
+    database: Database,
+    port: u16,
+    body: VerifyJsonBody
+) -> warp::reply::Response {
+    let bounds = match body.assertions.iter().map(Assertion::bounds).collect::<Result<Vec<_>, _>>() {
+        Ok(bounds) => bounds,
+        Err(error) => return error_reply(warp::http::StatusCode::UNPROCESSABLE_ENTITY, &error),
+    };
+    let journal = match journal::find_journal(&database, port) {
+        Ok(journal) => journal,
+        Err((status, error)) => return error_reply(status, &error),
+    };
+    let journal = lock(&journal);
+
+    let results: Vec<AssertionResult> = body.assertions.iter().zip(bounds).enumerate()
+        .map(|(index, (assertion, (min, max)))| {
+            let mut misses: Vec<(&Entry, Vec<Diff>)> = Vec::new();
+            let mut matched_ids = Vec::new();
+            for entry in journal.entries() {
+                let diffs = assertion.pattern.diffs(entry);
+                if diffs.is_empty() {
+                    matched_ids.push(entry.id);
+                } else {
+                    misses.push((entry, diffs));
+                }
+            }
+
+            let matched = matched_ids.len();
+            let passed = (min..=max).contains(&matched);
+            let near_misses = if matched < min {
+                // Fewest differences first, and the latest of those
+                misses.sort_by_key(|(entry, diffs)| (diffs.len(), std::cmp::Reverse(entry.id)));
+                misses.into_iter()
+                    .take(MAX_NEAR_MISSES)
+                    .map(|(entry, diffs)| NearMiss {
+                        id: entry.id,
+                        method: entry.request.method.clone(),
+                        path: entry.request.path.clone(),
+                        diffs,
+                    })
+                    .collect()
+            } else {
+                Vec::new()
+            };
+
+            AssertionResult {
+                name: assertion.name.clone().unwrap_or_else(|| index.to_string()),
+                passed,
+                expected: describe(min, max),
+                matched,
+                matched_ids,
+                near_misses,
+            }
+        })
+        .collect();
+
+    let report = VerifyReport {
+        passed: results.iter().all(|result| result.passed),
+        evicted: journal.stats().evicted,
+        results,
+    };
+    warp::reply::json(&report).into_response()
+}
+
+/// JSON body of `POST /{port}/requests/diff`
+#[derive(Debug, serde_derive::Deserialize)]
+pub struct SequenceJsonBody {
+    /// The requests expected, in the order they're expected in
+    expected: Vec<ExpectedRequest>,
+    /// Compare only the recorded requests from the one with this journal id on
+    #[serde(default)]
+    from_id: Option<u64>,
+    /// Pass even with recorded requests that weren't expected, like health checks
+    #[serde(default)]
+    allow_unexpected: bool,
+}
+
+#[derive(Debug, serde_derive::Deserialize)]
+struct ExpectedRequest {
+    /// Names the request in the diff, by default its position
+    #[serde(default)]
+    name: Option<String>,
+    #[serde(flatten)]
+    pattern: RequestPattern,
+}
+
+/// What `POST /{port}/requests/diff` found
+#[derive(Debug, serde_derive::Serialize)]
+struct SequenceReport {
+    passed: bool,
+    /// Requests evicted from the journal before the comparison, which may have been expected
+    evicted: u64,
+    /// In the order of the recorded requests, with those missing where they were expected
+    diff: Vec<SequenceStep>,
+}
+
+#[derive(Debug, serde_derive::Serialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum SequenceStep {
+    /// An expected request recorded in its place
+    Matched { expected: String, id: u64 },
+    /// An expected request recorded, but before or after others than expected
+    OutOfOrder { expected: String, id: u64, method: String, path: String },
+    /// An expected request not recorded at all
+    Missing { expected: String },
+    /// A recorded request that wasn't expected
+    Unexpected { id: u64, method: String, path: String },
+}
+
+/// Comparing more pairs of expected and recorded requests than this is refused
+const MAX_COMPARED: usize = 4_000_000;
+
+/// Compare the requests recorded by the HTTP server on `port`, in order, with the
+/// sequence `body` expects. The expected requests recorded in the same order as expected,
+/// as many as can be, are matched; of the others, those recorded elsewhere are out of order
+/// and the rest are missing. Recorded requests neither matched nor out of order are
+/// unexpected.
+pub fn diff_sequence(
+    database: Database,
+    port: u16,
+    body: SequenceJsonBody
+) -> warp::reply::Response {
+    let journal = match journal::find_journal(&database, port) {
+        Ok(journal) => journal,
+        Err((status, error)) => return error_reply(status, &error),
+    };
+    let journal = lock(&journal);
+    let recorded: Vec<&Entry> = journal.entries()
+        .filter(|entry| body.from_id.is_none_or(|from_id| entry.id >= from_id))
+        .collect();
+    let expected = &body.expected;
+    if expected.len().saturating_mul(recorded.len()) > MAX_COMPARED {
+        let error = format!("{} expected and {} recorded requests are too many to compare, narrow them down with from_id", expected.len(), recorded.len());
+        return error_reply(warp::http::StatusCode::UNPROCESSABLE_ENTITY, &error);
+    }
+    let matches = |i: usize, j: usize| expected[i].pattern.diffs(recorded[j]).is_empty();
+
+    // The longest common subsequence: `longest[i][j]` of `expected[i..]` and `recorded[j..]`
+    let (n, m) = (expected.len(), recorded.len());
+    let mut longest = vec![vec![0usize; m + 1]; n + 1];
+    for i in (0..n).rev() {
+        for j in (0..m).rev() {
+            longest[i][j] = if matches(i, j) {
+                longest[i + 1][j + 1] + 1
+            } else {
+                longest[i + 1][j].max(longest[i][j + 1])
+            };
+        }
+    }
+    // Aligned as `(expected, recorded)`, either missing from the other side
+    let mut aligned: Vec<(Option<usize>, Option<usize>)> = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < n || j < m {
+        if i < n && j < m && matches(i, j) && longest[i][j] == longest[i + 1][j + 1] + 1 {
+            aligned.push((Some(i), Some(j)));
+            i += 1;
+            j += 1;
+        } else if j < m && (i == n || longest[i][j + 1] >= longest[i + 1][j]) {
+            aligned.push((None, Some(j)));
+            j += 1;
+        } else {
+            aligned.push((Some(i), None));
+            i += 1;
+        }
+    }
+
+    // Each expected request left over takes the first recorded one left over it matches
+    let mut out_of_order: BTreeMap<usize, usize> = BTreeMap::new();
+    for i in aligned.iter().filter_map(|&(i, j)| i.filter(|_| j.is_none())) {
+        let taken = aligned.iter()
+            .filter_map(|&(other, j)| j.filter(|_| other.is_none()))
+            .find(|j| !out_of_order.contains_key(j) && matches(i, *j));
+        if let Some(j) = taken {
+            out_of_order.insert(j, i);
+        }
+    }
+    let paired: Vec<usize> = out_of_order.values().copied().collect();
+
+    let name = |i: usize| expected[i].name.clone().unwrap_or_else(|| i.to_string());
+    let diff: Vec<SequenceStep> = aligned.into_iter()
+        .filter_map(|aligned| Some(match aligned {
+            (Some(i), Some(j)) => SequenceStep::Matched { expected: name(i), id: recorded[j].id },
+            (None, Some(j)) => {
+                let entry = recorded[j];
+                let (id, method, path) = (entry.id, entry.request.method.clone(), entry.request.path.clone());
+                match out_of_order.get(&j) {
+                    Some(&i) => SequenceStep::OutOfOrder { expected: name(i), id, method, path },
+                    None => SequenceStep::Unexpected { id, method, path },
+                }
+            }
+            (Some(i), None) if paired.contains(&i) => return None,
+            (Some(i), None) => SequenceStep::Missing { expected: name(i) },
+            (None, None) => return None,
+        }))
+        .collect();
+
+    let passed = diff.iter().all(|step| match step {
+        SequenceStep::Matched { .. } => true,
+        SequenceStep::Unexpected { .. } => body.allow_unexpected,
+        SequenceStep::OutOfOrder { .. } | SequenceStep::Missing { .. } => false,
+    });
+    let report = SequenceReport { passed, evicted: journal.stats().evicted, diff };
+    warp::reply::json(&report).into_response()
+}
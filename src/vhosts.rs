@@ -0,0 +1,174 @@
+use warp::{Filter, Reply};
+
+use std::collections::BTreeMap;
+use std::io;
+use std::sync::{Arc, Mutex};
+
+use crate::{error_reply, headers, quota, Database, Registry, ServerKind};
+use crate::error::lock;
+use crate::journal::SharedJournal;
+use crate::stubs::Hits;
+use crate::watch::ChangeType;
+
+/// A logical server sharing the listener of an HTTP server, which hands it the requests
+/// for its host name
+#[derive(Clone, Debug, PartialEq, serde_derive::Deserialize, serde_derive::Serialize)]
+pub struct VirtualHost {
+    /// Port of the server on this host answering the requests
+    pub port: u16,
+}
+
+/// The virtual hosts of an HTTP server by host name, shared with its listener so they
+/// can change while it runs
+pub type SharedHosts = Arc<Mutex<BTreeMap<String, VirtualHost>>>;
+
+/// Host names are matched without case. `*.example.com` matches the subdomains of
+/// `example.com` without a host of their own.
+pub fn valid_host(host: &str) -> bool {
+    let name = host.strip_prefix("*.").unwrap_or(host);
+    !name.is_empty() && name.split('.').all(|label| {
+        !label.is_empty() && label.bytes().all(|byte| byte.is_ascii_alphanumeric() || byte == b'-')
+    })
+}
+
+fn check(own_port: u16, host: &str, virtual_host: &VirtualHost) -> Result<(), String> {
+    if !valid_host(host) {
+        return Err(format!("invalid virtual host name {:?}", host));
+    }
+    if virtual_host.port == own_port {
+        return Err(format!("virtual host {} can't be served by its own listener", host));
+    }
+    Ok(())
+}
+
+/// The virtual hosts of the server on `own_port` to share with its listener, checked
+/// to be valid
+pub fn share(own_port: u16, hosts: &BTreeMap<String, VirtualHost>) -> io::Result<SharedHosts> {
+    let mut shared = BTreeMap::new();
+    for (host, virtual_host) in hosts {
+        check(own_port, host, virtual_host).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        shared.insert(host.to_ascii_lowercase(), virtual_host.clone());
+    }
+    Ok(Arc::new(Mutex::new(shared)))
+}
+
+/// `host`, a host name or `Host` header value, without its port. Past a closing bracket,
+/// an IPv6 address has no port to strip.
+fn strip_port(host: &str) -> &str {
+    match host.rfind(':') {
+        Some(colon) if !host[colon..].contains(']') => &host[..colon],
+        _ => host,
+    }
+}
+
+/// Whether `host`, a host name or `Host` header value, is one `pattern` names. A `*.`
+/// wildcard stands for any single label, as in certificates, so `*.api.test` matches
+/// `tenant.api.test` but neither `api.test` nor `a.tenant.api.test`.
+pub fn host_matches(pattern: &str, host: &str) -> bool {
+    let name = strip_port(host);
+    match pattern.strip_prefix("*.") {
+        Some(parent) => name.split_once('.')
+            .is_some_and(|(label, rest)| !label.is_empty() && rest.eq_ignore_ascii_case(parent)),
+        None => name.eq_ignore_ascii_case(pattern),
+    }
+}
+
+/// The virtual host serving the `Host` header value `host`, with the name it's kept
+/// under, one named exactly before a wildcard
+pub fn lookup<'a>(hosts: &'a BTreeMap<String, VirtualHost>, host: &str) -> Option<(&'a str, &'a VirtualHost)> {
+    hosts.get_key_value(&strip_port(host).to_ascii_lowercase())
+        .or_else(|| hosts.iter().find(|(pattern, _)| pattern.starts_with("*.") && host_matches(pattern, host)))
+        .map(|(pattern, virtual_host)| (pattern.as_str(), virtual_host))
+}
+
+/// Forward the requests for one of `hosts` to the server that serves it, counting them
+/// in `hits` and keeping them in `journal`. Other requests are rejected to be handled as
+/// usual.
+pub fn dispatch(
+    hosts: SharedHosts,
+    hits: Arc<Hits>,
+    journal: Option<SharedJournal>
+) -> impl Filter<Extract = (warp::reply::Response,), Error = warp::Rejection> + Clone {
+    headers::proxy(warp::header::optional::<String>("host").and_then(move |host: Option<String>| {
+        let hosts = lock(&hosts);
+        let (host, virtual_host) = host.and_then(|host| lookup(&hosts, &host).map(|(host, virtual_host)| (host.to_string(), virtual_host.port)))
+            .ok_or_else(warp::reject::not_found)?;
+        hits.virtual_host(&host);
+        Ok::<_, warp::Rejection>(virtual_host)
+    }), journal)
+}
+
+fn find_hosts(registry: &Registry, port: u16) -> Result<SharedHosts, (warp::http::StatusCode, String)> {
+    let server = registry.servers.get(&port)
+        .ok_or_else(|| (warp::http::StatusCode::NOT_FOUND, format!("no server on port {}", port)))?;
+
+    match &server.virtual_hosts {
+        Some(hosts) => Ok(hosts.clone()),
+        None if server.config.kind == ServerKind::Http => {
+            Err((warp::http::StatusCode::CONFLICT, "only in-process servers have virtual hosts".to_string()))
+        }
+        None => {
+            let error = format!("a {} server has no virtual hosts", server.config.kind.name());
+            Err((warp::http::StatusCode::CONFLICT, error))
+        }
+    }
+}
+
+/// The virtual hosts sharing the listener of the HTTP server on `port`
+pub fn list_hosts(database: Database, port: u16) -> warp::reply::Response {
+    match find_hosts(&lock(&database), port) {
+        Ok(hosts) => warp::reply::json(&*lock(&hosts)).into_response(),
+        Err((status, error)) => error_reply(status, &error),
+    }
+}
+
+/// Add or replace the virtual host `host` of the HTTP server on `port`, taking effect on
+/// its next request. Refused if the server would exceed its memory quota.
+pub fn put_host(
+    database: Database,
+    port: u16,
+    host: String,
+    virtual_host: VirtualHost
+) -> Result<warp::reply::Response, warp::Rejection> {
+    let host = host.to_ascii_lowercase();
+    if let Err(error) = check(port, &host, &virtual_host) {
+        return Ok(error_reply(warp::http::StatusCode::UNPROCESSABLE_ENTITY, &error));
+    }
+    let mut registry = lock(&database);
+    let hosts = match find_hosts(&registry, port) {
+        Ok(hosts) => hosts,
+        Err((status, error)) => return Ok(error_reply(status, &error)),
+    };
+    if let Some(server) = registry.servers.get(&port) {
+        let mut config = server.config.clone();
+        config.virtual_hosts.insert(host.clone(), virtual_host.clone());
+        quota::check_memory(&registry, &config).map_err(warp::reject::custom)?;
+    }
+    let replaced = lock(&hosts).insert(host.clone(), virtual_host.clone()).is_some();
+    if let Some(server) = registry.servers.get_mut(&port) {
+        server.config.virtual_hosts.insert(host, virtual_host.clone());
+    }
+    registry.record_change(port, ChangeType::Modified);
+
+    let status = if replaced { warp::http::StatusCode::OK } else { warp::http::StatusCode::CREATED };
+    Ok(warp::reply::with_status(warp::reply::json(&virtual_host), status).into_response())
+}
+
+/// Remove the virtual host `host` of the HTTP server on `port`
+pub fn delete_host(database: Database, port: u16, host: String) -> warp::reply::Response {
+    let host = host.to_ascii_lowercase();
+    let mut registry = lock(&database);
+    let hosts = match find_hosts(&registry, port) {
+        Ok(hosts) => hosts,
+        Err((status, error)) => return error_reply(status, &error),
+    };
+    if lock(&hosts).remove(&host).is_none() {
+        let error = format!("server {} has no virtual host {}", port, host);
+        return error_reply(warp::http::StatusCode::NOT_FOUND, &error);
+    }
+    if let Some(server) = registry.servers.get_mut(&port) {
+        server.config.virtual_hosts.remove(&host);
+    }
+    registry.record_change(port, ChangeType::Modified);
+    warp::http::StatusCode::NO_CONTENT.into_response()
+}
@@ -0,0 +1,159 @@
+use futures::Stream;
+use futures::sync::mpsc;
+
+use std::collections::VecDeque;
+
+use crate::{error_reply, Database, ServerJsonBody};
+use crate::chaos::ChaosAction;
+use crate::history::EventLog;
+use crate::error::lock;
+
+/// How many past changes are kept for watchers resuming with `since`
+const HISTORY_LEN: usize = 1024;
+
+/// What happened to a server in a `Change`
+#[derive(Clone, Copy, Debug, serde_derive::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChangeType {
+    Added,
+    Modified,
+    Deleted,
+}
+
+/// One line of the `GET /watch` stream
+#[derive(Clone, Debug, serde_derive::Serialize)]
+pub struct Change {
+    pub revision: u64,
+    #[serde(rename = "type")]
+    pub change: ChangeType,
+    /// The fault injected by the chaos schedule, if that's what changed the server
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub chaos: Option<ChaosAction>,
+    pub server: ServerJsonBody,
+}
+
+/// The registry's change feed. Every change gets the next revision, so the retained
+/// history is a contiguous run of revisions.
+#[derive(Default)]
+pub struct Feed {
+    revision: u64,
+    history: VecDeque<Change>,
+    log: EventLog,
+    watchers: Vec<mpsc::UnboundedSender<String>>,
+}
+
+impl Feed {
+    /// The revision of the latest change
+    pub fn revision(&self) -> u64 {
+        self.revision
+    }
+
+    /// Every change published so far
+    pub fn log(&self) -> &EventLog {
+        &self.log
+    }
+
+    /// Publish a change to `server` to every watcher, returning the revision it was
+    /// assigned. The published server carries that revision as its `resource_version`.
+    pub fn publish(&mut self, change: ChangeType, server: ServerJsonBody) -> u64 {
+        self.publish_change(change, None, server)
+    }
+
+    /// Publish a fault injected into `server` by the chaos schedule, like `publish`
+    pub fn publish_chaos(&mut self, action: ChaosAction, server: ServerJsonBody) -> u64 {
+        self.publish_change(ChangeType::Modified, Some(action), server)
+    }
+
+    /// Receive every change published from now on, as a line of the `GET /watch` stream
+    pub fn subscribe(&mut self) -> mpsc::UnboundedReceiver<String> {
+        let (watcher, changes) = mpsc::unbounded();
+        self.watchers.push(watcher);
+        changes
+    }
+
+    fn publish_change(&mut self, change: ChangeType, chaos: Option<ChaosAction>, mut server: ServerJsonBody) -> u64 {
+        self.revision += 1;
+        server.resource_version = self.revision;
+
+        let change = Change { revision: self.revision, change, chaos, server };
+        let line = ndjson_line(&change);
+        self.watchers.retain(|watcher| watcher.unbounded_send(line.clone()).is_ok());
+
+        if self.history.len() == HISTORY_LEN {
+            self.history.pop_front();
+        }
+        self.history.push_back(change.clone());
+        self.log.append(change);
+
+        self.revision
+    }
+
+    /// Changes after revision `since`, or `None` if some of them are no longer retained
+    fn since(&self, since: u64) -> Option<Vec<Change>> {
+        match self.history.front() {
+            Some(oldest) if oldest.revision > since + 1 => None,
+            _ => Some(self.history.iter().filter(|change| change.revision > since).cloned().collect()),
+        }
+    }
+}
+
+/// Query parameters of `GET /watch`
+#[derive(Debug, serde_derive::Deserialize)]
+pub struct WatchQuery {
+    since: Option<u64>,
+}
+
+/// Stream registry changes as newline delimited JSON. Without `since`, the stream starts
+/// with an `added` change for every current server. With it, the stream replays every
+/// change after that revision, or fails with 410 if those have been forgotten.
+pub fn watch(
+    database: Database,
+    query: WatchQuery
+) -> warp::reply::Response {
+    let mut registry = lock(&database);
+
+    let backlog = match query.since {
+        Some(since) => match registry.feed.since(since) {
+            Some(changes) => changes,
+            None => {
+                let error = "changes since that revision are no longer available";
+                return error_reply(warp::http::StatusCode::GONE, error);
+            }
+        },
+        None => {
+            let mut changes: Vec<Change> = registry.servers.values()
+                .map(|server| Change {
+                    revision: server.resource_version,
+                    change: ChangeType::Added,
+                    chaos: None,
+                    server: server.json_body(),
+                })
+                .collect();
+            changes.sort_by_key(|change| change.revision);
+            changes
+        }
+    };
+
+    // Registering while holding the lock means no change can slip in between the
+    // backlog and the live feed
+    let (watcher, changes) = mpsc::unbounded();
+    for change in &backlog {
+        let _ = watcher.unbounded_send(ndjson_line(change));
+    }
+    registry.feed.watchers.push(watcher);
+
+    let body = changes.map_err(|()| -> std::io::Error { unreachable!("unbounded receivers never fail") });
+
+    let mut response = warp::http::Response::new(hyper::Body::wrap_stream(body));
+    response.headers_mut().insert(
+        warp::http::header::CONTENT_TYPE,
+        warp::http::header::HeaderValue::from_static("application/x-ndjson"),
+    );
+    response
+}
+
+fn ndjson_line(change: &Change) -> String {
+    let mut line = serde_json::to_string(change).unwrap_or_default();
+    line.push('\n');
+    line
+}
@@ -0,0 +1,150 @@
+use std::collections::BTreeMap;
+use std::io;
+#[cfg(feature = "xpath")]
+use std::sync::Arc;
+
+/// The namespace of SOAP 1.1 envelopes, which are sent as `text/xml`
+const SOAP_1_1_NAMESPACE: &str = "http://schemas.xmlsoap.org/soap/envelope/";
+
+/// The namespace of SOAP 1.2 envelopes, which are sent as `application/soap+xml`
+const SOAP_1_2_NAMESPACE: &str = "http://www.w3.org/2003/05/soap-envelope";
+
+/// XPath expressions the XML bodies of the requests a header route or fallback takes must
+/// match, every one of them, like the SOAP envelopes a legacy service is called with. An
+/// expression matches if it's true, like `//GetPrice/Item = 'Apple'`, or selects a node,
+/// like `/s:Envelope/s:Body/GetPrice`. Bodies that aren't XML match none.
+#[derive(Clone, Debug, Default, PartialEq, serde_derive::Deserialize, serde_derive::Serialize)]
+pub struct XPathMatchers {
+    pub expressions: Vec<String>,
+    /// The namespaces the prefixes of the expressions stand for, like `s` for
+    /// `http://schemas.xmlsoap.org/soap/envelope/`
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub namespaces: BTreeMap<String, String>,
+}
+
+fn invalid(error: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, error)
+}
+
+/// `XPathMatchers` checked to be valid when the server starts. The expressions are parsed
+/// anew for every body, as what they're parsed to can't be shared between threads.
+#[cfg(feature = "xpath")]
+#[derive(Clone)]
+pub struct Matcher(Arc<XPathMatchers>);
+
+#[cfg(not(feature = "xpath"))]
+#[derive(Clone)]
+pub enum Matcher {}
+
+impl Matcher {
+    #[cfg(feature = "xpath")]
+    pub fn compile(matchers: &XPathMatchers) -> io::Result<Matcher> {
+        if matchers.expressions.is_empty() {
+            return Err(invalid("XPath matchers have at least one expression".to_string()));
+        }
+        let matcher = Matcher(Arc::new(matchers.clone()));
+        // Evaluated against an empty document, to tell of unknown functions
+        let package = sxd_document::Package::new();
+        let context = matcher.context();
+        let factory = sxd_xpath::Factory::new();
+        for expression in &matchers.expressions {
+            let xpath = factory.build(expression)
+                .map_err(|e| invalid(format!("invalid XPath expression {:?}: {}", expression, e)))?
+                .ok_or_else(|| invalid("empty XPath expression".to_string()))?;
+            // Which sxd-xpath panics on as it comes across them
+            if let Some(prefix) = prefixes(expression).into_iter().find(|prefix| !matchers.namespaces.contains_key(*prefix)) {
+                return Err(invalid(format!("XPath expression {:?} has prefix {:?} without a namespace", expression, prefix)));
+            }
+            xpath.evaluate(&context, package.as_document().root())
+                .map_err(|e| invalid(format!("invalid XPath expression {:?}: {}", expression, e)))?;
+        }
+        Ok(matcher)
+    }
+
+    #[cfg(not(feature = "xpath"))]
+    pub fn compile(_matchers: &XPathMatchers) -> io::Result<Matcher> {
+        Err(invalid("matching request bodies with XPath needs this server to be built with the `xpath` feature".to_string()))
+    }
+
+    #[cfg(feature = "xpath")]
+    fn context(&self) -> sxd_xpath::Context<'static> {
+        let mut context = sxd_xpath::Context::new();
+        for (prefix, namespace) in &self.0.namespaces {
+            context.set_namespace(prefix, namespace);
+        }
+        context
+    }
+
+    /// Whether `body` is an XML document every expression matches
+    #[cfg(feature = "xpath")]
+    pub fn matches(&self, body: &[u8]) -> bool {
+        let package = match std::str::from_utf8(body).ok().and_then(|body| sxd_document::parser::parse(body).ok()) {
+            Some(package) => package,
+            None => return false,
+        };
+        let document = package.as_document();
+        let context = self.context();
+        let factory = sxd_xpath::Factory::new();
+        self.0.expressions.iter().all(|expression| {
+            factory.build(expression).ok().flatten()
+                .and_then(|xpath| xpath.evaluate(&context, document.root()).ok())
+                .is_some_and(|value| value.boolean())
+        })
+    }
+
+    #[cfg(not(feature = "xpath"))]
+    pub fn matches(&self, _body: &[u8]) -> bool {
+        match *self {}
+    }
+}
+
+/// The prefixes of the names in `expression`, like `s` of `s:Envelope`, leaving out axes
+/// like `child::` and what's quoted
+#[cfg(feature = "xpath")]
+fn prefixes(expression: &str) -> Vec<&str> {
+    let bytes = expression.as_bytes();
+    let mut prefixes = Vec::new();
+    let mut position = 0;
+    while position < bytes.len() {
+        let start = position;
+        match bytes[position] {
+            quote @ (b'\'' | b'"') => {
+                position = expression[start + 1..].find(quote as char).map_or(bytes.len(), |end| start + end + 2);
+            }
+            byte if byte.is_ascii_alphabetic() || byte == b'_' || byte.is_ascii_digit() => {
+                while position < bytes.len() && (bytes[position].is_ascii_alphanumeric() || matches!(bytes[position], b'_' | b'-' | b'.')) {
+                    position += 1;
+                }
+                let prefixed = bytes.get(position) == Some(&b':') && bytes.get(position + 1) != Some(&b':');
+                if prefixed && !byte.is_ascii_digit() {
+                    prefixes.push(&expression[start..position]);
+                }
+            }
+            _ => position += 1,
+        }
+    }
+    prefixes
+}
+
+/// `matchers` compiled, if there are any
+pub fn compile(matchers: Option<&XPathMatchers>) -> io::Result<Option<Matcher>> {
+    matchers.map(Matcher::compile).transpose()
+}
+
+/// The content type to answer with `body` as, if it's XML: that of SOAP 1.2 or SOAP 1.1
+/// for their envelopes, and `application/xml` for other documents starting with an XML
+/// declaration
+pub fn content_type(body: &str) -> Option<&'static str> {
+    let body = body.trim_start();
+    if !body.starts_with('<') {
+        None
+    } else if body.contains(SOAP_1_2_NAMESPACE) {
+        Some("application/soap+xml; charset=utf-8")
+    } else if body.contains(SOAP_1_1_NAMESPACE) {
+        Some("text/xml; charset=utf-8")
+    } else if body.starts_with("<?xml") {
+        Some("application/xml")
+    } else {
+        None
+    }
+}
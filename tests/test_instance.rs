@@ -0,0 +1,210 @@
+use futures::{Future, Stream};
+use hyper::{Body, Client, HeaderMap, Method, Request, StatusCode};
+use tokio::runtime::Runtime;
+
+use std::time::{Duration, Instant};
+
+use warp_self_replicating_server::TestInstance;
+
+/// Send `request`, answering its status, headers and JSON body, if any
+fn send(runtime: &mut Runtime, request: Request<Body>) -> (StatusCode, HeaderMap, serde_json::Value) {
+    // Connections aren't kept, so nothing is left running on the runtime once it's done
+    let client = Client::builder().keep_alive(false).build_http::<Body>();
+    let response = client.request(request)
+        .and_then(|response| {
+            let (parts, body) = response.into_parts();
+            body.concat2().map(move |body| (parts.status, parts.headers, body))
+        });
+    let (status, headers, body) = runtime.block_on(response).unwrap();
+    (status, headers, serde_json::from_slice(&body).unwrap_or(serde_json::Value::Null))
+}
+
+/// Make a request of the instance's admin API, answering its status and JSON body, if any
+fn request(
+    runtime: &mut Runtime,
+    instance: &TestInstance,
+    method: Method,
+    path: &str,
+    body: &str
+) -> (StatusCode, serde_json::Value) {
+    let request = Request::builder()
+        .method(method)
+        .uri(format!("{}{}", instance.base_url(), path))
+        .header("content-type", "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap();
+    let (status, _, body) = send(runtime, request);
+    (status, body)
+}
+
+/// Make a request of the instance's admin API with an `If-Match` of `etag`
+fn request_if_match(
+    runtime: &mut Runtime,
+    instance: &TestInstance,
+    method: Method,
+    path: &str,
+    etag: &str,
+    body: &str
+) -> (StatusCode, serde_json::Value) {
+    let request = Request::builder()
+        .method(method)
+        .uri(format!("{}{}", instance.base_url(), path))
+        .header("content-type", "application/json")
+        .header("if-match", etag)
+        .body(Body::from(body.to_string()))
+        .unwrap();
+    let (status, _, body) = send(runtime, request);
+    (status, body)
+}
+
+/// `GET http://127.0.0.1:{port}{path}` of a server the instance started, answering its
+/// status and how long it took
+fn get_from(runtime: &mut Runtime, port: u64, path: &str) -> (StatusCode, Duration) {
+    let request = Request::get(format!("http://127.0.0.1:{}{}", port, path)).body(Body::empty()).unwrap();
+    let started = Instant::now();
+    let (status, _, _) = send(runtime, request);
+    (status, started.elapsed())
+}
+
+#[test]
+fn creates_and_deletes_a_server() {
+    let mut runtime = Runtime::new().unwrap();
+    let instance = runtime.block_on(futures::future::lazy(TestInstance::start)).unwrap();
+
+    let (status, server) = request(&mut runtime, &instance, Method::POST, "/", "{}");
+    assert_eq!(status, StatusCode::OK);
+    let port = server["port"].as_u64().unwrap();
+    assert_ne!(port, u64::from(instance.port()));
+
+    let (status, _) = request(&mut runtime, &instance, Method::GET, &format!("/{}", port), "");
+    assert_eq!(status, StatusCode::OK);
+
+    let (status, _) = request(&mut runtime, &instance, Method::DELETE, &format!("/{}", port), "");
+    assert_eq!(status, StatusCode::NO_CONTENT);
+
+    let (status, servers) = request(&mut runtime, &instance, Method::GET, "/", "");
+    assert_eq!(status, StatusCode::OK);
+    let ports: Vec<u64> = servers.as_array().unwrap().iter().filter_map(|server| server["port"].as_u64()).collect();
+    assert_eq!(ports, vec![u64::from(instance.port())]);
+
+    // Every server the instance started is shut down with it, so the runtime goes idle
+    drop(instance);
+    runtime.shutdown_on_idle().wait().unwrap();
+}
+
+#[test]
+fn injects_faults_into_the_admin_api() {
+    let mut runtime = Runtime::new().unwrap();
+    let instance = runtime.block_on(futures::future::lazy(TestInstance::start)).unwrap();
+
+    let faults = r#"{"operations": ["list"], "error_rate": 1.0, "error_status": 503, "retry_after_secs": 2}"#;
+    let (status, _) = request(&mut runtime, &instance, Method::PUT, "/admin/faults", faults);
+    assert_eq!(status, StatusCode::OK);
+
+    let list = Request::get(format!("{}/", instance.base_url())).body(Body::empty()).unwrap();
+    let (status, headers, _) = send(&mut runtime, list);
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(headers["retry-after"], "2");
+    // Getting a server isn't among the operations
+    let (status, _) = request(&mut runtime, &instance, Method::GET, &format!("/{}", instance.port()), "");
+    assert_eq!(status, StatusCode::OK);
+    let (_, injected) = request(&mut runtime, &instance, Method::GET, "/admin/faults", "");
+    assert_eq!(injected["failed"], 1);
+
+    // Held back for an hour at most
+    let faults = r#"{"operations": ["get"], "latency_ms": 3600001}"#;
+    let (status, _) = request(&mut runtime, &instance, Method::PUT, "/admin/faults", faults);
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+
+    let faults = r#"{"operations": ["get"], "latency_ms": 300}"#;
+    request(&mut runtime, &instance, Method::PUT, "/admin/faults", faults);
+    let started = Instant::now();
+    let (status, _) = request(&mut runtime, &instance, Method::GET, &format!("/{}", instance.port()), "");
+    assert_eq!(status, StatusCode::OK);
+    assert!(started.elapsed() >= Duration::from_millis(300));
+
+    let (status, _) = request(&mut runtime, &instance, Method::DELETE, "/admin/faults", "");
+    assert_eq!(status, StatusCode::NO_CONTENT);
+    let (status, _) = request(&mut runtime, &instance, Method::GET, "/", "");
+    assert_eq!(status, StatusCode::OK);
+
+    drop(instance);
+    runtime.shutdown_on_idle().wait().unwrap();
+}
+
+#[test]
+fn shapes_the_requests_of_a_server_by_its_profile() {
+    let mut runtime = Runtime::new().unwrap();
+    let instance = runtime.block_on(futures::future::lazy(TestInstance::start)).unwrap();
+    let (_, server) = request(&mut runtime, &instance, Method::POST, "/", "{}");
+    let port = server["port"].as_u64().unwrap();
+    let profile = format!("/{}/profile", port);
+    let (unshaped, _) = get_from(&mut runtime, port, "/anything");
+
+    let (status, _) = request(&mut runtime, &instance, Method::PUT, &profile, r#"{"error_rate": 1.0, "error_statuses": [502]}"#);
+    assert_eq!(status, StatusCode::OK);
+    let (status, _) = get_from(&mut runtime, port, "/anything");
+    assert_eq!(status, StatusCode::BAD_GATEWAY);
+
+    let (status, _) = request(&mut runtime, &instance, Method::PUT, &profile, r#"{"delay_ms": 300}"#);
+    assert_eq!(status, StatusCode::OK);
+    let (status, elapsed) = get_from(&mut runtime, port, "/anything");
+    assert_eq!(status, unshaped);
+    assert!(elapsed >= Duration::from_millis(300));
+
+    // Delays are an hour at most, and presets have to exist
+    for invalid in [r#"{"delay_ms": 3600001}"#, r#"{"spike_rate": 0.5, "spike_ms": 3600001}"#, r#"{"preset": "dial-up"}"#] {
+        let (status, _) = request(&mut runtime, &instance, Method::PUT, &profile, invalid);
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY, "{}", invalid);
+    }
+
+    let (status, _) = request(&mut runtime, &instance, Method::DELETE, &profile, "");
+    assert_eq!(status, StatusCode::NO_CONTENT);
+    let (status, elapsed) = get_from(&mut runtime, port, "/anything");
+    assert_eq!(status, unshaped);
+    assert!(elapsed < Duration::from_millis(300));
+
+    drop(instance);
+    runtime.shutdown_on_idle().wait().unwrap();
+}
+
+#[test]
+fn holds_changes_to_the_if_match_etag() {
+    let mut runtime = Runtime::new().unwrap();
+    let instance = runtime.block_on(futures::future::lazy(TestInstance::start)).unwrap();
+    request(&mut runtime, &instance, Method::POST, "/", "{}");
+    let (_, servers) = request(&mut runtime, &instance, Method::GET, "/", "");
+    let port = servers.as_array().unwrap().iter()
+        .filter_map(|server| server["port"].as_u64())
+        .find(|port| *port != u64::from(instance.port()))
+        .unwrap();
+    let path = format!("/{}", port);
+
+    let get = Request::get(format!("{}{}", instance.base_url(), path)).body(Body::empty()).unwrap();
+    let (status, headers, server) = send(&mut runtime, get);
+    assert_eq!(status, StatusCode::OK);
+    let etag = headers["etag"].to_str().unwrap().to_string();
+    let stale = "\"0\"";
+    let body = server.to_string();
+
+    let (status, current) = request_if_match(&mut runtime, &instance, Method::PUT, &path, stale, &body);
+    assert_eq!(status, StatusCode::PRECONDITION_FAILED);
+    // Answered with the server as it is, to retry the change with
+    assert_eq!(current["port"], port);
+    let (status, _) = request_if_match(&mut runtime, &instance, Method::PUT, &format!("{}/stubs", path), stale, "{}");
+    assert_eq!(status, StatusCode::PRECONDITION_FAILED);
+    let (status, _) = request_if_match(&mut runtime, &instance, Method::DELETE, &path, stale, "");
+    assert_eq!(status, StatusCode::PRECONDITION_FAILED);
+
+    let (status, updated) = request_if_match(&mut runtime, &instance, Method::PUT, &path, &etag, &body);
+    assert_eq!(status, StatusCode::OK);
+    // The change made the ETag stale
+    let (status, _) = request_if_match(&mut runtime, &instance, Method::DELETE, &path, &etag, "");
+    assert_eq!(status, StatusCode::PRECONDITION_FAILED);
+    let etag = format!("\"{}\"", updated["resource_version"]);
+    let (status, _) = request_if_match(&mut runtime, &instance, Method::DELETE, &path, &etag, "");
+    assert_eq!(status, StatusCode::NO_CONTENT);
+
+    drop(instance);
+    runtime.shutdown_on_idle().wait().unwrap();
+}